@@ -0,0 +1,67 @@
+// Compares the single element fast path of `gen_token_response` with batched issuance
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kagippcore::PrivacyPass;
+use privacypass::batched_tokens_ristretto255::{
+    client::Client, server::deserialize_public_key, TokenRequest,
+};
+use privacypass::Nonce;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::ExposeSecret;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use voprf::{Group, Ristretto255};
+
+fn token_request_bytes(public_key: &[u8], nr: u16) -> Vec<u8> {
+    let client = Client::new(deserialize_public_key(public_key).expect("invalid public key"));
+    let token_challenge = PrivacyPass::gen_token_challenge();
+    let nonces = (0..nr)
+        .map(|_| {
+            let mut nonce: Nonce = [0u8; 32];
+            OsRng.fill_bytes(&mut nonce);
+            nonce
+        })
+        .collect::<Vec<_>>();
+    let blinds = (0..nr)
+        .map(|_| <Ristretto255 as Group>::Scalar::random(&mut OsRng))
+        .collect::<Vec<_>>();
+    let (token_request, _) = client
+        .issue_token_request_with_params(&token_challenge, nonces, blinds)
+        .expect("failed to generate token request");
+    token_request
+        .tls_serialize_detached()
+        .expect("failed to serialize token request")
+}
+
+fn bench_gen_token_response(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("failed to create tokio runtime");
+    let pp = PrivacyPass::new();
+    let keypair = rt.block_on(pp.gen_keys()).expect("failed to generate keys");
+
+    let mut group = c.benchmark_group("gen_token_response");
+    for nr in [1u16, 2, 8] {
+        let request_bytes = token_request_bytes(&keypair.public_key, nr);
+        group.throughput(Throughput::Elements(u64::from(nr)));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(nr),
+            &request_bytes,
+            |b, bytes| {
+                b.iter_batched(
+                    || TokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap(),
+                    |token_request| {
+                        rt.block_on(pp.gen_token_response(
+                            keypair.secret_key.expose_secret(),
+                            token_request,
+                            usize::from(nr),
+                        ))
+                        .unwrap()
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_gen_token_response);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+fn main() {
+    // protobuf types and service trait of the grpc module
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/privacypass.proto"], &["proto"])
+        .expect("Unable to generate gRPC bindings, is protoc installed?");
+}
@@ -0,0 +1,8 @@
+# builds the issuer side of the core crate for serverless WebAssembly runtimes, see the
+# serverless module
+# needs the targets: rustup target add wasm32-unknown-unknown wasm32-wasip1
+
+# Cloudflare Workers (and other JS-hosted runtimes)
+cargo build --profile serverless --target wasm32-unknown-unknown --no-default-features --features serverless
+# WASI hosts
+cargo build --profile serverless --target wasm32-wasip1 --no-default-features --features serverless
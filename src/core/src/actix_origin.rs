@@ -0,0 +1,209 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  actix origin  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9577 origin side for actix-web, the equivalent of `tower_origin`. The `Origin` is
+// registered as app data (`web::Data<Origin>`), and then either:
+// - scopes are protected with `middleware::from_fn(require_private_token)`, or
+// - handlers take a `PrivateToken` argument, which is only extracted from requests whose
+//   token the origin redeemed.
+// Other requests get a 401 with the `WWW-Authenticate` challenge of the origin, so that
+// clients can fetch a token and retry. Requests of apps without an `Origin` get a 500.
+// NOTE: actix guards are synchronous, so can't redeem tokens, hence the middleware.
+
+use crate::origin::{AuthorizationError, Origin};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use std::future::Future;
+use std::pin::Pin;
+use thiserror::Error;
+
+/// Refusal of a request, answered with a 401 and the challenge of the origin
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct PrivateTokenRejection {
+    pub error: AuthorizationError,
+    challenge: Option<String>,
+}
+
+impl ResponseError for PrivateTokenRejection {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::Unauthorized();
+        if let Some(challenge) = &self.challenge {
+            response.insert_header((WWW_AUTHENTICATE, challenge.as_str()));
+        }
+        response.finish()
+    }
+}
+
+/// Redeems the token of the `Authorization` header of a request with the origin of the app
+async fn redeem(
+    origin: Option<web::Data<Origin>>,
+    headers: &HeaderMap,
+) -> Result<(), actix_web::Error> {
+    let origin =
+        origin.ok_or_else(|| actix_web::error::ErrorInternalServerError("no Origin app data"))?;
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .map(|value| value.to_str().unwrap_or_default());
+    origin.redeem(authorization).await.map_err(|error| {
+        PrivateTokenRejection {
+            error,
+            challenge: origin
+                .challenge()
+                .and_then(|challenge| challenge.to_str().ok().map(str::to_string)),
+        }
+        .into()
+    })
+}
+
+/// Middleware (for `middleware::from_fn`) letting through only requests with a valid,
+/// unspent token
+pub async fn require_private_token<B: MessageBody>(
+    request: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    redeem(
+        request.app_data::<web::Data<Origin>>().cloned(),
+        request.headers(),
+    )
+    .await?;
+    next.call(request).await
+}
+
+/// Extractor of requests whose token was redeemed
+#[derive(Debug, Clone, Copy)]
+pub struct PrivateToken;
+
+impl FromRequest for PrivateToken {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let origin = request.app_data::<web::Data<Origin>>().cloned();
+        let headers = request.headers().clone();
+        Box::pin(async move {
+            redeem(origin, &headers).await?;
+            Ok(PrivateToken)
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batched_memory_stores::{MemoryKeyStoreRistretto255, MemoryNonceStore};
+    use crate::config::{batched_tokens_mod, GroupTokenType, VoprfGroup};
+    use crate::origin::OriginConfig;
+    use crate::NONCE_BYTES;
+    use actix_web::{middleware::from_fn, test, App};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use batched_tokens_mod::client::Client;
+    use batched_tokens_mod::server::{serialize_public_key, Server};
+    use privacypass::{auth::authenticate::TokenChallenge, Nonce};
+    use rand::{rngs::OsRng, RngCore};
+    use std::sync::Arc;
+    use tls_codec::Serialize as TlsSerializeTrait;
+    use voprf::Group;
+
+    #[actix_web::test]
+    async fn test_actix_origin() {
+        let key_store = Arc::new(MemoryKeyStoreRistretto255::default());
+        let public_key = Server::new()
+            .create_keypair_with_params(&*key_store, &[7u8; 32], b"actix")
+            .await
+            .unwrap();
+        let config = OriginConfig {
+            issuer_name: "issuer.example".to_string(),
+            origin_info: vec!["origin.example".to_string()],
+            token_key: serialize_public_key(public_key).to_vec(),
+        };
+        let token_challenge = TokenChallenge::new(
+            GroupTokenType,
+            &config.issuer_name,
+            None,
+            &config.origin_info,
+        );
+        // a token per protected route
+        let nonces = (0..2)
+            .map(|_| {
+                let mut nonce: Nonce = [0u8; NONCE_BYTES];
+                OsRng.fill_bytes(&mut nonce);
+                nonce
+            })
+            .collect();
+        let blinds = (0..2)
+            .map(|_| <VoprfGroup as Group>::Scalar::random(&mut OsRng))
+            .collect();
+        let client = Client::new(public_key);
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(&token_challenge, nonces, blinds)
+            .unwrap();
+        let token_response = Server::new()
+            .issue_token_response(&*key_store, token_request)
+            .await
+            .unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+
+        let origin = Origin::new(config, key_store, Arc::new(MemoryNonceStore::default())).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(origin))
+                .service(
+                    web::scope("/scope")
+                        .wrap(from_fn(require_private_token))
+                        .route("", web::get().to(|| async { "protected" })),
+                )
+                .route(
+                    "/handler",
+                    web::get().to(|_: PrivateToken| async { "protected" }),
+                ),
+        )
+        .await;
+
+        for uri in ["/scope", "/handler"] {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header((AUTHORIZATION, "PrivateToken token=AAAA"))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+            assert!(response.headers()[WWW_AUTHENTICATE]
+                .to_str()
+                .unwrap()
+                .starts_with("PrivateToken"));
+        }
+
+        for (uri, token) in ["/scope", "/handler"].into_iter().zip(&tokens) {
+            let authorization = format!(
+                "PrivateToken token={}",
+                URL_SAFE.encode(token.tls_serialize_detached().unwrap())
+            );
+            let request = || {
+                test::TestRequest::get()
+                    .uri(uri)
+                    .insert_header((AUTHORIZATION, authorization.as_str()))
+                    .to_request()
+            };
+            let admitted = test::call_service(&app, request()).await;
+            assert_eq!(admitted.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(test::read_body(admitted).await, "protected", "{}", uri);
+
+            let replayed = test::call_service(&app, request()).await;
+            assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+            assert!(replayed.headers().contains_key(WWW_AUTHENTICATE), "{}", uri);
+        }
+    }
+}
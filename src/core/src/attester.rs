@@ -0,0 +1,462 @@
+// -----------------------------------------------------------------------------
+// ---------------------  rate-limited tokens (attester)  ----------------------
+// -----------------------------------------------------------------------------
+//
+// Attester side of rate-limited token issuance (draft-ietf-privacypass-rate-limit-tokens,
+// token type 0x0003). Clients send an AccessTokenRequest, whose token request and origin name
+// are encrypted to the issuer: the attester never learns the origin, it only checks the
+// request is well formed and signed with the request key it pinned for the client, forwards
+// the encrypted part to the issuer as is, and counts issued tokens per client and per
+// anonymous issuer origin id, the stable per client and origin pseudonym returned by the
+// issuer.
+//   AccessTokenRequest = token_type (3) || request_key[Npk]
+//                        || encrypted_token_request<1..2^16-1> || request_signature[Nsig]
+// request_signature is an ECDSA P-384 (SHA-384) signature by request_key over every byte
+// preceding it. Clients encrypt the token request and origin name to the issuer, and issuers
+// decrypt them along with the request key, see origin_name.rs.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    crystal_error, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, Base64Json, JSONRetVal, JSONRetValRef,
+};
+use crate::limits::InputKind;
+use p384::ecdsa::signature::Verifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+pub const TOKEN_TYPE_RATE_LIMITED: u16 = 0x0003;
+/// Size of request keys, compressed P-384 points
+pub const REQUEST_KEY_LEN: usize = 49;
+/// Size of request signatures, ECDSA P-384 (r || s)
+pub const REQUEST_SIGNATURE_LEN: usize = 96;
+/// Size of anonymous issuer origin ids
+pub const ANON_ORIGIN_ID_LEN: usize = 32;
+
+/// Attester-side identity of a client, e.g. the SHA256 of its account id
+pub type ClientId = [u8; 32];
+pub type AnonOriginId = [u8; ANON_ORIGIN_ID_LEN];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AttesterError {
+    #[error("malformed access token request")]
+    Malformed,
+    #[error("invalid token type {0:#06x} for a rate-limited token request")]
+    InvalidTokenType(u16),
+    #[error("no access token request was checked for the client")]
+    UnknownClient,
+    #[error("request key differs from the one pinned for the client")]
+    RequestKeyMismatch,
+    #[error("invalid request key")]
+    InvalidRequestKey,
+    #[error("invalid request signature")]
+    InvalidSignature,
+    #[error("incorrect number of bytes ({0}) for an anonymous issuer origin id")]
+    WrongOriginIdSize(usize),
+    #[error("rate limit of {0} tokens per origin reached")]
+    RateLimited(u32),
+}
+
+/// Serialized AccessTokenRequest, borrowed from the request buffer
+#[derive(Debug)]
+pub struct AccessTokenRequest<'a> {
+    pub request_key: &'a [u8; REQUEST_KEY_LEN],
+    /// token request and origin name, encrypted to the issuer and forwarded as is
+    pub encrypted_token_request: &'a [u8],
+    pub request_signature: &'a [u8; REQUEST_SIGNATURE_LEN],
+    /// every byte preceding request_signature, which it signs
+    signed: &'a [u8],
+}
+
+impl<'a> AccessTokenRequest<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AttesterError> {
+        let (token_type, rest) = bytes
+            .split_first_chunk::<2>()
+            .ok_or(AttesterError::Malformed)?;
+        let token_type = u16::from_be_bytes(*token_type);
+        if token_type != TOKEN_TYPE_RATE_LIMITED {
+            return Err(AttesterError::InvalidTokenType(token_type));
+        }
+        let (request_key, rest) = rest
+            .split_first_chunk::<REQUEST_KEY_LEN>()
+            .ok_or(AttesterError::Malformed)?;
+        let (len_bytes, rest) = rest
+            .split_first_chunk::<2>()
+            .ok_or(AttesterError::Malformed)?;
+        let len = usize::from(u16::from_be_bytes(*len_bytes));
+        if len == 0 || rest.len() != len + REQUEST_SIGNATURE_LEN {
+            return Err(AttesterError::Malformed);
+        }
+        let encrypted_token_request = rest.get(..len).ok_or(AttesterError::Malformed)?;
+        let (signed, request_signature) = bytes
+            .split_last_chunk::<REQUEST_SIGNATURE_LEN>()
+            .ok_or(AttesterError::Malformed)?;
+        Ok(AccessTokenRequest {
+            request_key,
+            encrypted_token_request,
+            request_signature,
+            signed,
+        })
+    }
+
+    /// Checks request_signature is a signature of the request by request_key
+    pub fn verify_signature(&self) -> Result<(), AttesterError> {
+        let request_key = VerifyingKey::from_sec1_bytes(self.request_key)
+            .map_err(|_| AttesterError::InvalidRequestKey)?;
+        let signature = Signature::from_slice(self.request_signature)
+            .map_err(|_| AttesterError::InvalidSignature)?;
+        request_key
+            .verify(self.signed, &signature)
+            .map_err(|_| AttesterError::InvalidSignature)
+    }
+}
+
+/// Number of tokens a client can get per origin within a window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    pub max_tokens: u32,
+    pub window: Duration,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        RateLimitPolicy {
+            max_tokens: 100,
+            window: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+struct Window {
+    started: u64,
+    issued: u32,
+}
+
+struct ClientState {
+    request_key: [u8; REQUEST_KEY_LEN],
+    origins: HashMap<AnonOriginId, Window>,
+}
+
+pub struct Attester {
+    policy: RateLimitPolicy,
+    clients: Mutex<HashMap<ClientId, ClientState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Attester {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        Self::with_clock(policy, global_clock())
+    }
+
+    pub fn with_clock(policy: RateLimitPolicy, clock: Arc<dyn Clock>) -> Self {
+        Attester {
+            policy,
+            clients: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.policy
+    }
+
+    fn with_clients<T>(&self, f: impl FnOnce(&mut HashMap<ClientId, ClientState>) -> T) -> T {
+        // a poisoned lock still holds valid counters, as each update is a single assignment
+        f(&mut self.clients.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Checks an AccessTokenRequest of `client_id`, returning the encrypted token request to
+    /// forward to the issuer, along with the request key it is bound to. The first request key
+    /// seen for a client is pinned, so that clients can't spread their requests over several
+    /// keys.
+    pub fn check_request<'a>(
+        &self,
+        client_id: &ClientId,
+        access_token_request: &'a [u8],
+    ) -> Result<&'a [u8], AttesterError> {
+        let request = AccessTokenRequest::parse(access_token_request)?;
+        // checked before pinning, so that only the holder of a key can get it pinned
+        request.verify_signature()?;
+        self.with_clients(|clients| {
+            let client = clients.entry(*client_id).or_insert_with(|| ClientState {
+                request_key: *request.request_key,
+                origins: HashMap::new(),
+            });
+            match client.request_key == *request.request_key {
+                true => Ok(request.encrypted_token_request),
+                false => Err(AttesterError::RequestKeyMismatch),
+            }
+        })
+    }
+
+    /// Counts a token issued to `client_id` for the origin behind `anon_origin_id`, returning
+    /// how many more it can get in the current window, or an error once the limit is reached.
+    /// NOTE: the issuer response must not be relayed to the client on error
+    pub fn record_issuance(
+        &self,
+        client_id: &ClientId,
+        anon_origin_id: &[u8],
+    ) -> Result<u32, AttesterError> {
+        let anon_origin_id: AnonOriginId = anon_origin_id
+            .try_into()
+            .map_err(|_| AttesterError::WrongOriginIdSize(anon_origin_id.len()))?;
+        let now = self.clock.unix_seconds();
+        let window_seconds = self.policy.window.as_secs();
+        let max_tokens = self.policy.max_tokens;
+        self.with_clients(|clients| {
+            // clients are known from check_request, which pins their request key
+            let client = clients
+                .get_mut(client_id)
+                .ok_or(AttesterError::UnknownClient)?;
+            let window = client.origins.entry(anon_origin_id).or_insert(Window {
+                started: now,
+                issued: 0,
+            });
+            if now.saturating_sub(window.started) >= window_seconds {
+                *window = Window {
+                    started: now,
+                    issued: 0,
+                };
+            }
+            if window.issued >= max_tokens {
+                return Err(AttesterError::RateLimited(max_tokens));
+            }
+            window.issued += 1;
+            Ok(max_tokens - window.issued)
+        })
+    }
+
+    /// Forgets windows that ended, and clients left without any, returning the number of
+    /// forgotten windows
+    pub fn prune_expired(&self) -> usize {
+        let now = self.clock.unix_seconds();
+        let window_seconds = self.policy.window.as_secs();
+        self.with_clients(|clients| {
+            let mut pruned = 0;
+            clients.retain(|_, client| {
+                let before = client.origins.len();
+                client
+                    .origins
+                    .retain(|_, window| now.saturating_sub(window.started) < window_seconds);
+                pruned += before - client.origins.len();
+                !client.origins.is_empty()
+            });
+            pruned
+        })
+    }
+}
+
+static GLOBAL_ATTESTER: RwLock<Option<Arc<Attester>>> = RwLock::new(None);
+
+fn global_attester() -> Arc<Attester> {
+    // a poisoned lock still holds a valid attester, as it is only ever overwritten whole
+    if let Some(attester) = GLOBAL_ATTESTER
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        return attester.clone();
+    }
+    GLOBAL_ATTESTER
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .get_or_insert_with(|| Arc::new(Attester::new(RateLimitPolicy::default())))
+        .clone()
+}
+
+/// Decodes a base64 client id, hashed so that Crystal can pass identifiers of any length
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+unsafe fn client_id_from_crystal(
+    client_id_cstr: *const i8,
+) -> Result<ClientId, Box<dyn std::error::Error>> {
+    let client_id = unsafe { decode_untrusted_bytes_from_crystal(client_id_cstr, InputKind::Key)? };
+    if client_id.is_empty() {
+        Err(crystal_error("empty client id"))?;
+    }
+    Ok(Sha256::digest(client_id).into())
+}
+
+/// Sets the per client and origin limit of the attester, resetting its state.
+/// NOTE: pass 0 as `window_seconds` for a daily window
+#[no_mangle]
+pub extern "C" fn set_attester_policy(max_tokens: u32, window_seconds: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let window = match window_seconds {
+            0 => RateLimitPolicy::default().window,
+            _ => Duration::from_secs(window_seconds.into()),
+        };
+        let attester = Attester::new(RateLimitPolicy { max_tokens, window });
+        *GLOBAL_ATTESTER
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(attester));
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Checks a (base64 encoded) AccessTokenRequest of a (base64 encoded) client id, returning
+/// the encrypted token request to forward to the issuer
+#[no_mangle]
+pub extern "C" fn attester_check_request(
+    client_id_cstr: *const i8,
+    access_token_request_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let client_id = unsafe { client_id_from_crystal(client_id_cstr)? };
+        let access_token_request = unsafe {
+            decode_untrusted_bytes_from_crystal(access_token_request_cstr, InputKind::TokenRequest)?
+        };
+        let encrypted_token_request =
+            global_attester().check_request(&client_id, &access_token_request)?;
+
+        let rv = JSONRetValRef {
+            retval: Base64Json(encrypted_token_request),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Counts a token issued to a client for the (base64 encoded) anonymous issuer origin id
+/// returned by the issuer, returning the number of tokens left in the window.
+/// Errors once the limit is reached, in which case the token must not be relayed.
+#[no_mangle]
+pub extern "C" fn attester_record_issuance(
+    client_id_cstr: *const i8,
+    anon_origin_id_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let client_id = unsafe { client_id_from_crystal(client_id_cstr)? };
+        let anon_origin_id =
+            unsafe { decode_untrusted_bytes_from_crystal(anon_origin_id_cstr, InputKind::Key)? };
+        let remaining = global_attester().record_issuance(&client_id, &anon_origin_id)?;
+
+        let rv = JSONRetVal {
+            retval: remaining.to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use p384::ecdsa::signature::Signer;
+    use p384::ecdsa::SigningKey;
+
+    /// AccessTokenRequest signed by the request key of `seed`
+    fn access_token_request(seed: u8) -> Vec<u8> {
+        let signing_key = SigningKey::from_bytes(&[seed; 48].into()).unwrap();
+        let request_key = signing_key.verifying_key().to_encoded_point(true);
+        let mut bytes = TOKEN_TYPE_RATE_LIMITED.to_be_bytes().to_vec();
+        bytes.extend(request_key.as_bytes());
+        bytes.extend(3u16.to_be_bytes());
+        bytes.extend([7u8; 3]);
+        let signature: Signature = signing_key.sign(&bytes);
+        bytes.extend(signature.to_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_rate_limited_issuance() {
+        let clock = Arc::new(MockClock::at_unix_seconds(1_000));
+        let policy = RateLimitPolicy {
+            max_tokens: 2,
+            window: Duration::from_secs(60),
+        };
+        let attester = Attester::with_clock(policy, clock.clone());
+        let client_id = [1u8; 32];
+
+        let request = access_token_request(2);
+        assert_eq!(
+            attester.check_request(&client_id, &request).unwrap(),
+            [7u8; 3]
+        );
+        assert_eq!(
+            attester.check_request(&client_id, &access_token_request(3)),
+            Err(AttesterError::RequestKeyMismatch)
+        );
+        assert_eq!(
+            attester.check_request(&client_id, &request[..request.len() - 1]),
+            Err(AttesterError::Malformed)
+        );
+        // requests must be signed by their request key
+        let mut forged = request.clone();
+        forged[REQUEST_KEY_LEN + 4] ^= 1;
+        assert_eq!(
+            attester.check_request(&client_id, &forged),
+            Err(AttesterError::InvalidSignature)
+        );
+        let mut unsigned = access_token_request(4);
+        let signature_start = unsigned.len() - REQUEST_SIGNATURE_LEN;
+        unsigned[signature_start..].fill(0);
+        assert_eq!(
+            attester.check_request(&[9u8; 32], &unsigned),
+            Err(AttesterError::InvalidSignature)
+        );
+        let mut invalid_key = request.clone();
+        invalid_key[2] = 0x05;
+        assert_eq!(
+            attester.check_request(&client_id, &invalid_key),
+            Err(AttesterError::InvalidRequestKey)
+        );
+
+        let origin = [5u8; ANON_ORIGIN_ID_LEN];
+        assert_eq!(attester.record_issuance(&client_id, &origin), Ok(1));
+        assert_eq!(attester.record_issuance(&client_id, &origin), Ok(0));
+        assert_eq!(
+            attester.record_issuance(&client_id, &origin),
+            Err(AttesterError::RateLimited(2))
+        );
+        // limits are per origin
+        assert_eq!(
+            attester.record_issuance(&client_id, &[6u8; ANON_ORIGIN_ID_LEN]),
+            Ok(1)
+        );
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(attester.record_issuance(&client_id, &origin), Ok(1));
+        assert_eq!(attester.prune_expired(), 1);
+    }
+}
@@ -0,0 +1,273 @@
+// -----------------------------------------------------------------------------
+// -----------------------  redemption audit log  ------------------------------
+// -----------------------------------------------------------------------------
+//
+// Optional, disabled by default. Each redemption is recorded as its key id, a coarse
+// timestamp bucket (an hour or longer) and its outcome, and nothing else: never nonces,
+// tokens or challenges, which could link a redemption to an issuance. Events are kept as
+// append-only counters per (key id, bucket, outcome), so that not even their order within a
+// bucket is retained.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use privacypass::TruncatedTokenKeyId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum RedemptionOutcome {
+    Valid,
+    Invalid,
+    DoubleSpent,
+    KeyRevoked,
+    KeyNotYetValid,
+    KeyExpired,
+}
+
+impl RedemptionOutcome {
+    pub fn from_validity(valid: bool) -> Self {
+        match valid {
+            true => RedemptionOutcome::Valid,
+            false => RedemptionOutcome::Invalid,
+        }
+    }
+}
+
+/// Number of redemptions with the same key id and outcome within a timestamp bucket
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditAggregate {
+    pub truncated_token_key_id: TruncatedTokenKeyId,
+    /// unix time (in seconds) at which the bucket starts
+    pub timestamp_bucket: u64,
+    pub outcome: RedemptionOutcome,
+    pub count: u64,
+}
+
+type AuditKey = (TruncatedTokenKeyId, u64, RedemptionOutcome);
+
+/// Shortest timestamp bucket: finer ones would tell redemptions apart by their time
+pub const MIN_AUDIT_BUCKET_SECONDS: u64 = 3600;
+
+#[derive(Error, Debug)]
+#[error("audit log buckets of {0} seconds are shorter than {MIN_AUDIT_BUCKET_SECONDS} seconds")]
+pub struct AuditBucketTooShortError(pub u64);
+
+/// Redemption counters per (key id, timestamp bucket, outcome). The process-wide log fed by
+/// the redemption paths is behind `configure_audit_log` and `audit_aggregates`.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    bucket_seconds: u64, // 0 when disabled
+    counts: BTreeMap<AuditKey, u64>,
+}
+
+impl AuditLog {
+    /// Disabled log, recording nothing until configured
+    pub const fn new() -> Self {
+        AuditLog {
+            bucket_seconds: 0,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Starts recording redemptions, with timestamps rounded down to multiples of
+    /// `bucket_seconds`, at least MIN_AUDIT_BUCKET_SECONDS.
+    /// NOTE: pass 0 to stop recording, already recorded events are kept
+    pub fn configure(&mut self, bucket_seconds: u64) -> Result<(), AuditBucketTooShortError> {
+        if bucket_seconds != 0 && bucket_seconds < MIN_AUDIT_BUCKET_SECONDS {
+            return Err(AuditBucketTooShortError(bucket_seconds));
+        }
+        self.bucket_seconds = bucket_seconds;
+        Ok(())
+    }
+
+    pub fn record(
+        &mut self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+        outcome: RedemptionOutcome,
+        clock: &dyn Clock,
+    ) {
+        if self.bucket_seconds == 0 {
+            return;
+        }
+        let now = clock.unix_seconds();
+        let timestamp_bucket = now - now % self.bucket_seconds;
+        *self
+            .counts
+            .entry((truncated_token_key_id, timestamp_bucket, outcome))
+            .or_insert(0) += 1;
+    }
+
+    /// Drops the buckets starting more than `horizon_seconds` before `now`, returning how many
+    pub fn prune(&mut self, horizon_seconds: u64, now: u64) -> usize {
+        let before = self.counts.len();
+        self.counts.retain(|&(_, timestamp_bucket, _), _| {
+            now.saturating_sub(timestamp_bucket) <= horizon_seconds
+        });
+        before - self.counts.len()
+    }
+
+    /// Returns the recorded redemptions, ordered by key id, bucket and outcome
+    pub fn aggregates(&self) -> Vec<AuditAggregate> {
+        self.counts
+            .iter()
+            .map(
+                |(&(truncated_token_key_id, timestamp_bucket, outcome), &count)| AuditAggregate {
+                    truncated_token_key_id,
+                    timestamp_bucket,
+                    outcome,
+                    count,
+                },
+            )
+            .collect()
+    }
+}
+
+static AUDIT_LOG: Mutex<AuditLog> = Mutex::new(AuditLog::new());
+
+fn with_audit_log<T>(f: impl FnOnce(&mut AuditLog) -> T) -> T {
+    // a poisoned log only ever misses the event being recorded when the panic happened
+    let mut audit_log = AUDIT_LOG.lock().unwrap_or_else(|err| err.into_inner());
+    f(&mut audit_log)
+}
+
+/// Configures the process-wide audit log, see `AuditLog::configure`
+pub fn configure_audit_log(bucket_seconds: u64) -> Result<(), AuditBucketTooShortError> {
+    with_audit_log(|audit_log| audit_log.configure(bucket_seconds))
+}
+
+pub fn record_redemption(truncated_token_key_id: TruncatedTokenKeyId, outcome: RedemptionOutcome) {
+    record_redemption_with_clock(truncated_token_key_id, outcome, &global_clock())
+}
+
+pub fn record_redemption_with_clock(
+    truncated_token_key_id: TruncatedTokenKeyId,
+    outcome: RedemptionOutcome,
+    clock: &dyn Clock,
+) {
+    with_audit_log(|audit_log| audit_log.record(truncated_token_key_id, outcome, clock));
+}
+
+/// Prunes the process-wide audit log, see `AuditLog::prune`
+pub fn prune_audit_log(horizon_seconds: u64, now: u64) -> usize {
+    with_audit_log(|audit_log| audit_log.prune(horizon_seconds, now))
+}
+
+/// Returns the redemptions recorded by the process-wide audit log
+pub fn audit_aggregates() -> Vec<AuditAggregate> {
+    with_audit_log(|audit_log| audit_log.aggregates())
+}
+
+/// Enables the redemption audit log, with timestamps bucketed to `bucket_seconds`, at least
+/// 3600. NOTE: pass 0 to disable it again
+#[no_mangle]
+pub extern "C" fn set_audit_log(bucket_seconds: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        configure_audit_log(u64::from(bucket_seconds))?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns the audit log aggregates as a JSON array
+#[no_mangle]
+pub extern "C" fn get_audit_log() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&audit_aggregates())?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_audit_log_aggregates() {
+        let mut audit_log = AuditLog::new();
+        let clock = MockClock::at_unix_seconds(7_200 + 3_599);
+        // disabled by default
+        audit_log.record(201, RedemptionOutcome::Valid, &clock);
+        assert!(audit_log.aggregates().is_empty());
+
+        audit_log.configure(3600).unwrap();
+        audit_log.record(201, RedemptionOutcome::Valid, &clock);
+        audit_log.record(201, RedemptionOutcome::Valid, &clock);
+        clock.advance(Duration::from_secs(1));
+        audit_log.record(201, RedemptionOutcome::Invalid, &clock);
+        audit_log.configure(0).unwrap();
+        audit_log.record(201, RedemptionOutcome::Valid, &clock);
+
+        assert_eq!(
+            audit_log.aggregates(),
+            vec![
+                AuditAggregate {
+                    truncated_token_key_id: 201,
+                    timestamp_bucket: 7_200,
+                    outcome: RedemptionOutcome::Valid,
+                    count: 2,
+                },
+                AuditAggregate {
+                    truncated_token_key_id: 201,
+                    timestamp_bucket: 10_800,
+                    outcome: RedemptionOutcome::Invalid,
+                    count: 1,
+                },
+            ]
+        );
+
+        assert_eq!(audit_log.prune(3_600, 10_801), 1);
+        let aggregates = audit_log.aggregates();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].timestamp_bucket, 10_800);
+    }
+
+    #[test]
+    fn test_audit_log_refuses_fine_buckets() {
+        let mut audit_log = AuditLog::new();
+        assert!(matches!(
+            audit_log.configure(60),
+            Err(AuditBucketTooShortError(60))
+        ));
+        audit_log.record(
+            201,
+            RedemptionOutcome::Valid,
+            &MockClock::at_unix_seconds(60),
+        );
+        assert!(audit_log.aggregates().is_empty());
+        audit_log.configure(MIN_AUDIT_BUCKET_SECONDS).unwrap();
+    }
+}
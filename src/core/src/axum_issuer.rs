@@ -0,0 +1,559 @@
+// -----------------------------------------------------------------------------
+// ----------------------------  axum issuer  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9578 HTTP issuer, so that the library can be deployed without the Crystal host (see
+// the pp-issuer binary). `router` serves:
+// - GET /.well-known/private-token-issuer-directory, the issuer directory of the keys
+//   accepted for redemption (application/private-token-issuer-directory);
+// - POST <path of the issuer request URI>, taking a TokenRequest
+//   (application/private-token-request) and returning its TokenResponse
+//   (application/private-token-response). Malformed requests, requests for too many tokens
+//   and requests naming a key the issuer does not hold get a 400;
+// - GET /.well-known/private-token-transparency-log, the process-wide transparency log every
+//   key the issuer installed is logged in before being listed (application/json), kept in
+//   `transparency_log_file` if set, see the transparency module.
+// Keys are held by a `KeyManager` and persisted through an `IssuerKeyStore`, along with the
+// time they became the current key, so that a restarted issuer keeps issuing with the keys
+// clients already know, and previous keys only stay listed for what is left of their grace
+// period: `MemoryIssuerKeyStore` keeps none across restarts, and with the `file-key-store`
+// feature a `FileKeyStore` (behind a Mutex) keeps them encrypted on disk. Keys are generated
+// and persisted before being installed, and rotate on schedule when `spawn_rotation` runs,
+// the previous keys staying accepted for redemption during the grace period of the key
+// manager. Keys the key manager dropped are deleted from the key store.
+// NOTE: redemption is up to origins, the issuer never sees tokens.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::secret_from_slice;
+#[cfg(feature = "file-key-store")]
+use crate::file_key_store::FileKeyStore;
+use crate::issuer_directory::{IssuerDirectory, IssuerDirectoryError};
+use crate::key_id::TokenKeyIds;
+use crate::key_manager::{KeyManager, KeyManagerConfig, KeyManagerError};
+use crate::server::{GenKeysError, GenTokenResponseError, TokenRequestView};
+use crate::server_sync::PrivacyPassSync;
+use crate::transparency::{global_log_entries, persist_transparency_log, TransparencyLogFileError};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use privacypass::TruncatedTokenKeyId;
+use secrecy::{ExposeSecret, SecretSlice};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tls_codec::Serialize as TlsSerializeTrait;
+
+pub const ISSUER_DIRECTORY_PATH: &str = "/.well-known/private-token-issuer-directory";
+pub const ISSUER_DIRECTORY_MEDIA_TYPE: &str = "application/private-token-issuer-directory";
+pub const TOKEN_REQUEST_MEDIA_TYPE: &str = "application/private-token-request";
+pub const TOKEN_RESPONSE_MEDIA_TYPE: &str = "application/private-token-response";
+pub const TRANSPARENCY_LOG_PATH: &str = "/.well-known/private-token-transparency-log";
+
+/// Error of an `IssuerKeyStore` implementation
+pub type IssuerKeyStoreError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Error, Debug)]
+pub enum IssuerServerError {
+    #[error("invalid issuer request URI")]
+    InvalidUri(#[from] http::uri::InvalidUri),
+    #[error("failed to generate key")]
+    GenKeys(#[from] GenKeysError),
+    #[error("failed to install key")]
+    KeyManager(#[from] KeyManagerError),
+    #[error("failed to load or persist keys")]
+    KeyStore(IssuerKeyStoreError),
+    #[error("malformed token request")]
+    MalformedRequest(tls_codec::Error),
+    #[error("failed to issue token response")]
+    Issuance(#[from] GenTokenResponseError),
+    #[error("failed to serialize token response")]
+    Serialize(tls_codec::Error),
+    #[error("failed to build issuer directory")]
+    Directory(#[from] IssuerDirectoryError),
+    #[error("failed to load or persist the transparency log")]
+    TransparencyLog(#[from] TransparencyLogFileError),
+}
+
+impl IssuerServerError {
+    /// HTTP status of the error, 400 for errors of the client
+    pub fn status(&self) -> StatusCode {
+        match self {
+            IssuerServerError::MalformedRequest(_)
+            | IssuerServerError::Issuance(
+                GenTokenResponseError::RequestedTooManyTokens(..)
+                | GenTokenResponseError::InvalidTokenType
+                | GenTokenResponseError::KeyIdNotFound
+                | GenTokenResponseError::KeyRevoked(_)
+                | GenTokenResponseError::KeyValidity(_)
+                | GenTokenResponseError::DuplicateBlindedElement(_)
+                | GenTokenResponseError::InvalidBlindedElement(_),
+            ) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Secret key persisted by an `IssuerKeyStore`
+pub struct StoredKey {
+    pub secret_key: SecretSlice<u8>,
+    /// when the key became the current key, in unix seconds
+    pub installed_at: u64,
+}
+
+impl StoredKey {
+    fn new(secret_key: &[u8], installed_at: u64) -> Self {
+        StoredKey {
+            secret_key: secret_from_slice(secret_key),
+            installed_at,
+        }
+    }
+}
+
+/// Where the issuer keeps its secret keys across restarts
+pub trait IssuerKeyStore: Send + Sync {
+    /// Secret keys, oldest first
+    fn load_keys(&self) -> Result<Vec<StoredKey>, IssuerKeyStoreError>;
+    /// Persists a newly generated key, before it becomes the current key at `installed_at`
+    fn store_key(&self, secret_key: &[u8], installed_at: u64) -> Result<(), IssuerKeyStoreError>;
+    /// Deletes a key the issuer dropped, doing nothing if it isn't stored
+    fn delete_key(&self, secret_key: &[u8]) -> Result<(), IssuerKeyStoreError>;
+}
+
+impl<S: IssuerKeyStore + ?Sized> IssuerKeyStore for Arc<S> {
+    fn load_keys(&self) -> Result<Vec<StoredKey>, IssuerKeyStoreError> {
+        (**self).load_keys()
+    }
+
+    fn store_key(&self, secret_key: &[u8], installed_at: u64) -> Result<(), IssuerKeyStoreError> {
+        (**self).store_key(secret_key, installed_at)
+    }
+
+    fn delete_key(&self, secret_key: &[u8]) -> Result<(), IssuerKeyStoreError> {
+        (**self).delete_key(secret_key)
+    }
+}
+
+/// Keeps keys in memory only, so that a restarted issuer starts over with a new key
+#[derive(Default)]
+pub struct MemoryIssuerKeyStore {
+    keys: Mutex<Vec<StoredKey>>,
+}
+
+impl MemoryIssuerKeyStore {
+    fn keys(&self) -> std::sync::MutexGuard<'_, Vec<StoredKey>> {
+        // a poisoned store still holds whole keys, as they are only pushed and removed whole
+        self.keys.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl IssuerKeyStore for MemoryIssuerKeyStore {
+    fn load_keys(&self) -> Result<Vec<StoredKey>, IssuerKeyStoreError> {
+        Ok(self
+            .keys()
+            .iter()
+            .map(|key| StoredKey::new(key.secret_key.expose_secret(), key.installed_at))
+            .collect())
+    }
+
+    fn store_key(&self, secret_key: &[u8], installed_at: u64) -> Result<(), IssuerKeyStoreError> {
+        self.keys().push(StoredKey::new(secret_key, installed_at));
+        Ok(())
+    }
+
+    fn delete_key(&self, secret_key: &[u8]) -> Result<(), IssuerKeyStoreError> {
+        self.keys()
+            .retain(|key| key.secret_key.expose_secret() != secret_key);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "file-key-store")]
+impl IssuerKeyStore for Mutex<FileKeyStore> {
+    fn load_keys(&self) -> Result<Vec<StoredKey>, IssuerKeyStoreError> {
+        // a poisoned store still holds the keys of the file, which is rewritten whole
+        let key_store = self.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(key_store
+            .keys()
+            .iter()
+            .map(|key| StoredKey::new(key.secret_key.expose_secret(), key.added_at))
+            .collect())
+    }
+
+    fn store_key(&self, secret_key: &[u8], installed_at: u64) -> Result<(), IssuerKeyStoreError> {
+        let mut key_store = self.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(key_store.add_key_at(secret_key, installed_at)?)
+    }
+
+    fn delete_key(&self, secret_key: &[u8]) -> Result<(), IssuerKeyStoreError> {
+        let mut key_store = self.lock().unwrap_or_else(|err| err.into_inner());
+        key_store.remove_key(secret_key)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IssuerConfig {
+    /// URI clients send TokenRequests to, listed in the issuer directory. Its path is
+    /// the path of the token request endpoint.
+    pub issuer_request_uri: String,
+    /// previous keys and grace period. Its `rotation_interval` is how often
+    /// `spawn_rotation` rotates keys, the key manager itself never rotates them.
+    pub key_manager: KeyManagerConfig,
+    /// tokens a single TokenRequest may ask for
+    pub max_tokens: usize,
+    /// file the process-wide transparency log is kept in, see `persist_transparency_log`.
+    /// The log is kept in memory only if not set.
+    pub transparency_log_file: Option<PathBuf>,
+}
+
+impl IssuerConfig {
+    pub fn new(issuer_request_uri: &str) -> Self {
+        IssuerConfig {
+            issuer_request_uri: issuer_request_uri.to_string(),
+            key_manager: KeyManagerConfig::default(),
+            max_tokens: 100,
+            transparency_log_file: None,
+        }
+    }
+}
+
+pub struct Issuer {
+    config: IssuerConfig,
+    key_manager: KeyManager,
+    key_store: Box<dyn IssuerKeyStore>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Issuer {
+    /// Issuer holding the keys of `key_store`, the newest one being the current key, or a
+    /// new (persisted) key if it holds none. Previous keys whose grace period ended while the
+    /// issuer was down are deleted from the key store rather than installed again. The
+    /// transparency log is loaded from `transparency_log_file` first, if set, so that keys
+    /// installed again keep their entries.
+    pub fn new(
+        config: IssuerConfig,
+        key_store: Box<dyn IssuerKeyStore>,
+    ) -> Result<Self, IssuerServerError> {
+        Self::with_clock(config, key_store, global_clock())
+    }
+
+    pub fn with_clock(
+        config: IssuerConfig,
+        key_store: Box<dyn IssuerKeyStore>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, IssuerServerError> {
+        config.issuer_request_uri.parse::<http::Uri>()?;
+        if let Some(transparency_log_file) = &config.transparency_log_file {
+            persist_transparency_log(transparency_log_file)?;
+        }
+        let key_manager = KeyManager::with_clock(
+            KeyManagerConfig {
+                rotation_interval: None,
+                ..config.key_manager.clone()
+            },
+            Arc::clone(&clock),
+        );
+        let keys = key_store.load_keys().map_err(IssuerServerError::KeyStore)?;
+        for key in &keys {
+            key_manager.install_key_at(key.secret_key.expose_secret(), key.installed_at)?;
+        }
+        let issuer = Issuer {
+            config,
+            key_manager,
+            key_store,
+            clock,
+        };
+        issuer.delete_dropped_keys()?;
+        if keys.is_empty() {
+            issuer.rotate()?;
+        }
+        Ok(issuer)
+    }
+
+    pub fn config(&self) -> &IssuerConfig {
+        &self.config
+    }
+
+    pub fn key_manager(&self) -> &KeyManager {
+        &self.key_manager
+    }
+
+    /// Generates a new current key, persists it, then installs it. Returns its public key.
+    pub fn rotate(&self) -> Result<Vec<u8>, IssuerServerError> {
+        let taken: Vec<TruncatedTokenKeyId> = self
+            .key_manager
+            .public_keys()
+            .iter()
+            .map(|public_key| TokenKeyIds::from_serialized_public_key(public_key))
+            .map(|key_ids| key_ids.truncated_token_key_id)
+            .collect();
+        let keypair = PrivacyPassSync::new().gen_keys_avoiding(&taken)?;
+        let installed_at = self.clock.unix_seconds();
+        self.key_store
+            .store_key(keypair.secret_key.expose_secret(), installed_at)
+            .map_err(IssuerServerError::KeyStore)?;
+        let public_key = self
+            .key_manager
+            .install_key_at(keypair.secret_key.expose_secret(), installed_at)?;
+        self.delete_dropped_keys()?;
+        Ok(public_key)
+    }
+
+    /// Deletes the persisted keys the key manager dropped, so that they are never installed
+    /// again
+    fn delete_dropped_keys(&self) -> Result<(), IssuerServerError> {
+        let keys = self
+            .key_store
+            .load_keys()
+            .map_err(IssuerServerError::KeyStore)?;
+        for key in keys {
+            if !self.key_manager.holds_key(key.secret_key.expose_secret())? {
+                self.key_store
+                    .delete_key(key.secret_key.expose_secret())
+                    .map_err(IssuerServerError::KeyStore)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rotates keys every `rotation_interval` of the config, if set, until the task is
+    /// aborted. Must be called from within a tokio runtime.
+    pub fn spawn_rotation(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let rotation_interval = self.config.key_manager.rotation_interval?;
+        let issuer = Arc::clone(self);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(rotation_interval);
+            // the first tick completes immediately, the current key is fresh
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let issuer = Arc::clone(&issuer);
+                // failing to rotate keeps issuing with the current key, retried on the next tick
+                let _ = tokio::task::spawn_blocking(move || issuer.rotate()).await;
+            }
+        }))
+    }
+
+    pub fn directory(&self) -> Result<IssuerDirectory, IssuerServerError> {
+        Ok(self
+            .key_manager
+            .issuer_directory(&self.config.issuer_request_uri)?)
+    }
+
+    /// Issuer directory, serialized
+    pub fn directory_json(&self) -> Result<String, IssuerServerError> {
+        Ok(serde_json::to_string(&self.directory()?).map_err(IssuerDirectoryError::from)?)
+    }
+
+    /// Serialized TokenResponse to a serialized TokenRequest
+    pub fn issue(&self, token_request: &[u8]) -> Result<Vec<u8>, IssuerServerError> {
+        let token_request = TokenRequestView::try_from_bytes(token_request)
+            .map_err(IssuerServerError::MalformedRequest)?;
+        if token_request.nr() > self.config.max_tokens {
+            return Err(GenTokenResponseError::RequestedTooManyTokens(
+                token_request.nr(),
+                self.config.max_tokens,
+            )
+            .into());
+        }
+        self.key_manager
+            .issue_token_response(&token_request)?
+            .tls_serialize_detached()
+            .map_err(IssuerServerError::Serialize)
+    }
+}
+
+/// Whether the Content-Type of a request is `media_type`, ignoring parameters
+fn has_media_type(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|content_type| content_type.trim().eq_ignore_ascii_case(media_type))
+}
+
+async fn issuer_directory(State(issuer): State<Arc<Issuer>>) -> Response {
+    match issuer.directory_json() {
+        Ok(directory_json) => (
+            [(header::CONTENT_TYPE, ISSUER_DIRECTORY_MEDIA_TYPE)],
+            directory_json,
+        )
+            .into_response(),
+        Err(err) => err.status().into_response(),
+    }
+}
+
+async fn transparency_log() -> Response {
+    match serde_json::to_string(&global_log_entries()) {
+        Ok(log_json) => ([(header::CONTENT_TYPE, "application/json")], log_json).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn token_request(
+    State(issuer): State<Arc<Issuer>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !has_media_type(&headers, TOKEN_REQUEST_MEDIA_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    // VOPRF evaluation is CPU-bound, it is kept off the async workers
+    match tokio::task::spawn_blocking(move || issuer.issue(&body)).await {
+        Ok(Ok(token_response)) => (
+            [(header::CONTENT_TYPE, TOKEN_RESPONSE_MEDIA_TYPE)],
+            token_response,
+        )
+            .into_response(),
+        Ok(Err(err)) => err.status().into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Router serving the issuer directory, the transparency log and the token request endpoint
+/// of `issuer`
+pub fn router(issuer: Arc<Issuer>) -> Result<Router, IssuerServerError> {
+    let issuer_request_uri: http::Uri = issuer.config.issuer_request_uri.parse()?;
+    Ok(Router::new()
+        .route(ISSUER_DIRECTORY_PATH, get(issuer_directory))
+        .route(TRANSPARENCY_LOG_PATH, get(transparency_log))
+        .route(issuer_request_uri.path(), post(token_request))
+        .with_state(issuer))
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::transparency::{verify_key_in_log, LogEntry, GENESIS_HASH};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use std::time::{Duration, SystemTime};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_issuer_keys_and_errors() {
+        let config = IssuerConfig::new("https://issuer.example/token-request");
+        let key_store = Arc::new(MemoryIssuerKeyStore::default());
+        let issuer = Issuer::new(config.clone(), Box::new(Arc::clone(&key_store))).unwrap();
+        let public_key = issuer.key_manager().current_public_key().unwrap();
+        let rotated = issuer.rotate().unwrap();
+        assert_ne!(rotated, public_key);
+
+        // both keys were persisted, and a restarted issuer gets them back in order
+        assert_eq!(key_store.load_keys().unwrap().len(), 2);
+        let restarted = Issuer::new(config, Box::new(key_store)).unwrap();
+        assert_eq!(
+            restarted.key_manager().current_public_key().unwrap(),
+            rotated
+        );
+        assert_eq!(restarted.directory().unwrap().token_keys.len(), 2);
+
+        assert_eq!(
+            restarted
+                .issue(b"not a token request")
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    /// Serves the router of `issuer` on a local port, returning its address
+    async fn serve(issuer: Issuer) -> std::net::SocketAddr {
+        let app = router(Arc::new(issuer)).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// Status and body of the response to an HTTP/1.1 request to `addr`
+    async fn request(
+        addr: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "{method} {path} HTTP/1.1\r\nhost: issuer.example\r\nconnection: close\r\n\
+             content-type: {content_type}\r\ncontent-length: {}\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let body_offset = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        let status = std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap();
+        (status, response[body_offset..].to_vec())
+    }
+
+    /// Public keys the issuer directory served at `addr` lists, current key first
+    async fn directory_keys(addr: std::net::SocketAddr) -> Vec<Vec<u8>> {
+        let (status, body) = request(addr, "GET", ISSUER_DIRECTORY_PATH, "", b"").await;
+        assert_eq!(status, 200);
+        let directory: IssuerDirectory = serde_json::from_slice(&body).unwrap();
+        directory
+            .token_keys
+            .iter()
+            .map(|token_key| URL_SAFE.decode(&token_key.token_key).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_restarted_issuer_over_http() {
+        // starting now, so that the nonce lifetimes set by rotations are in the future of
+        // the other tests, which share the process-wide nonce store
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let mut config = IssuerConfig::new("https://issuer.example/token-request");
+        config.key_manager.grace_period = Duration::from_secs(600);
+        let key_store = Arc::new(MemoryIssuerKeyStore::default());
+        let restart = || {
+            let key_store = Box::new(Arc::clone(&key_store));
+            Issuer::with_clock(config.clone(), key_store, clock.clone()).unwrap()
+        };
+        let issuer = restart();
+        let first = issuer.key_manager().current_public_key().unwrap();
+        clock.advance(Duration::from_secs(60));
+        let second = issuer.rotate().unwrap();
+        drop(issuer);
+
+        // restarted during the grace period of the first key, which is still listed
+        clock.advance(Duration::from_secs(60));
+        let addr = serve(restart()).await;
+        assert_eq!(directory_keys(addr).await, vec![second.clone(), first]);
+
+        // restarted after it ended, the first key stays retired and is deleted
+        clock.advance(Duration::from_secs(540));
+        let addr = serve(restart()).await;
+        assert_eq!(directory_keys(addr).await, vec![second.clone()]);
+        assert_eq!(key_store.load_keys().unwrap().len(), 1);
+
+        // the keys it lists are in the transparency log it serves
+        let (status, body) = request(addr, "GET", TRANSPARENCY_LOG_PATH, "", b"").await;
+        assert_eq!(status, 200);
+        let entries: Vec<LogEntry> = serde_json::from_slice(&body).unwrap();
+        assert!(verify_key_in_log(&entries, &second, 0, &GENESIS_HASH).is_ok());
+
+        let path = "/token-request";
+        let (status, _) = request(addr, "POST", path, "text/plain", b"").await;
+        assert_eq!(status, 415);
+        let (status, _) = request(addr, "POST", path, TOKEN_REQUEST_MEDIA_TYPE, b"\x00").await;
+        assert_eq!(status, 400);
+    }
+}
@@ -4,17 +4,437 @@
 // In this context, a thread that panics durinc access to the Mutex (and hence makes .lock() fail)
 // is the only thread, and a further panic in this file will never be triggered.
 // Just in case, we add a message to the panic.
+#![allow(clippy::expect_used)]
 
+use crate::clock::{global_clock, Clock};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::nonce_store::NonceStore;
+use crate::revocation::RevocationStore;
 use async_trait::async_trait;
 use p384::NistP384;
-use privacypass::{Nonce, NonceStore, TruncatedTokenKeyId};
-use std::collections::{HashMap, HashSet};
-use std::sync::Mutex;
+use privacypass::batched_tokens_ristretto255::server::serialize_public_key;
+use privacypass::{Nonce, TruncatedTokenKeyId};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use thiserror::Error;
 use voprf::{Ristretto255, VoprfServer};
 
+/// What to do when inserting into a store that already holds `max_entries` entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Evict the oldest entry to make room for the new one
+    #[default]
+    EvictOldest,
+    /// Refuse to store the new entry
+    RejectNew,
+}
+
+/// Size cap for the in-memory stores. `max_entries: None` means unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreLimits {
+    pub max_entries: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+}
+
+/// Counters exposed so that operators can see when store limits are hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreStats {
+    pub entries: usize,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub rejections: u64,
+}
+
+/// HashMap remembering insertion order, so that the oldest entry can be evicted
+struct BoundedMap<K, V> {
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+    limits: StoreLimits,
+    stats: StoreStats,
+}
+
+impl<K, V> Default for BoundedMap<K, V> {
+    fn default() -> Self {
+        BoundedMap {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            limits: StoreLimits::default(),
+            stats: StoreStats::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> BoundedMap<K, V> {
+    fn with_limits(limits: StoreLimits) -> Self {
+        BoundedMap {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            limits,
+            stats: StoreStats::default(),
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if let Some(existing) = self.entries.get_mut(&key) {
+            *existing = value;
+            self.stats.inserts += 1;
+            return;
+        }
+        if !self.make_room() {
+            return;
+        }
+        self.order.push_back(key.clone());
+        self.entries.insert(key, value);
+        self.stats.inserts += 1;
+    }
+
+    /// Inserts a new entry where `sort_key` puts it among entries inserted in ascending
+    /// `sort_key` order, after those with an equal key, so that older entries stay first
+    fn insert_ordered<T: Ord>(&mut self, key: K, value: V, sort_key: impl Fn(&V) -> T) {
+        if self.entries.contains_key(&key) || !self.make_room() {
+            return;
+        }
+        let position = sort_key(&value);
+        let entries = &self.entries;
+        let index = self.order.partition_point(|stored| {
+            !entries
+                .get(stored)
+                .is_some_and(|stored| sort_key(stored) > position)
+        });
+        self.order.insert(index, key.clone());
+        self.entries.insert(key, value);
+        self.stats.inserts += 1;
+    }
+
+    /// Makes room for a new entry according to the limits, returning false if it is rejected
+    fn make_room(&mut self) -> bool {
+        let Some(max_entries) = self.limits.max_entries else {
+            return true;
+        };
+        if self.entries.len() < max_entries {
+            return true;
+        }
+        match self.limits.eviction_policy {
+            EvictionPolicy::RejectNew => {
+                self.stats.rejections += 1;
+                false
+            }
+            EvictionPolicy::EvictOldest => {
+                while self.entries.len() >= max_entries {
+                    match self.order.pop_front() {
+                        Some(oldest) => {
+                            self.entries.remove(&oldest);
+                            self.stats.evictions += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if max_entries == 0 {
+                    self.stats.rejections += 1;
+                    return false;
+                }
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.remove(key)?;
+        self.order.retain(|stored| stored != key);
+        Some(value)
+    }
+
+    /// Removes the entries `remove` holds for, returning how many were removed
+    fn remove_where(&mut self, mut remove: impl FnMut(&V) -> bool) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, value| !remove(value));
+        let entries = &self.entries;
+        self.order.retain(|key| entries.contains_key(key));
+        before - self.entries.len()
+    }
+
+    /// Removes entries, oldest first, for as long as `expired` holds for them.
+    /// Returns the number of removed entries.
+    fn prune_oldest(&mut self, mut expired: impl FnMut(&V) -> bool) -> usize {
+        let mut pruned = 0;
+        while let Some(oldest) = self.order.front() {
+            if self
+                .entries
+                .get(oldest)
+                .is_some_and(|value| !expired(value))
+            {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                pruned += usize::from(self.entries.remove(&oldest).is_some());
+            }
+        }
+        pruned
+    }
+
+    /// Removes the oldest entries until at most `keep` are left, returning how many were removed
+    fn truncate_oldest(&mut self, keep: usize) -> usize {
+        let mut pruned = 0;
+        while self.entries.len() > keep {
+            match self.order.pop_front() {
+                Some(oldest) => pruned += usize::from(self.entries.remove(&oldest).is_some()),
+                None => break,
+            }
+        }
+        pruned
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NonceRecord {
+    // unix time (in seconds)
+    recorded_at: u64,
+    // (full) id of the key the token was issued with, if known
+    token_key_id: Option<[u8; 32]>,
+}
+
+/// Unix times (in seconds) keys retire at, past which the nonces of their tokens are forgotten.
+/// Keys are told apart by their full token key id, as truncated ones are shared by 1 in 256
+/// keys. Retired keys are remembered, so that their tokens can't be redeemed again.
+struct KeyLifetimes {
+    retires_at: Mutex<HashMap<[u8; 32], u64>>,
+    // earliest of retires_at, so that stores only take the lock once a key is due
+    next_retirement: AtomicU64,
+    // unix time (in seconds) each retired key retired at
+    retired: RwLock<HashMap<[u8; 32], u64>>,
+}
+
+impl Default for KeyLifetimes {
+    fn default() -> Self {
+        KeyLifetimes {
+            retires_at: Mutex::new(HashMap::new()),
+            next_retirement: AtomicU64::new(u64::MAX),
+            retired: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl KeyLifetimes {
+    /// Returns false, leaving the lifetimes untouched, if the key already retired
+    fn set(&self, token_key_id: [u8; 32], retires_at: u64) -> bool {
+        let mut lifetimes = self
+            .retires_at
+            .lock()
+            .expect("KeyLifetimes .lock() failed on .set()");
+        if self.retired_at(&token_key_id).is_some() {
+            return false;
+        }
+        lifetimes.insert(token_key_id, retires_at);
+        self.next_retirement
+            .fetch_min(retires_at, Ordering::Relaxed);
+        true
+    }
+
+    fn retired_at(&self, token_key_id: &[u8; 32]) -> Option<u64> {
+        self.retired
+            .read()
+            .expect("KeyLifetimes .read() failed on .retired_at()")
+            .get(token_key_id)
+            .copied()
+    }
+
+    /// Keys retired at `now`, which are moved from the lifetimes to the retired keys
+    fn take_retired(&self, now: u64) -> Vec<[u8; 32]> {
+        if self.next_retirement.load(Ordering::Relaxed) > now {
+            return Vec::new();
+        }
+        let mut lifetimes = self
+            .retires_at
+            .lock()
+            .expect("KeyLifetimes .lock() failed on .take_retired()");
+        let retired: Vec<_> = lifetimes
+            .iter()
+            .filter(|(_, retires_at)| **retires_at <= now)
+            .map(|(token_key_id, retires_at)| (*token_key_id, *retires_at))
+            .collect();
+        let mut retired_keys = self
+            .retired
+            .write()
+            .expect("KeyLifetimes .write() failed on .take_retired()");
+        for (token_key_id, retires_at) in &retired {
+            lifetimes.remove(token_key_id);
+            retired_keys.insert(*token_key_id, *retires_at);
+        }
+        let next_retirement = lifetimes.values().copied().min().unwrap_or(u64::MAX);
+        self.next_retirement
+            .store(next_retirement, Ordering::Relaxed);
+        retired
+            .into_iter()
+            .map(|(token_key_id, _)| token_key_id)
+            .collect()
+    }
+}
+
 #[derive(Default)]
 pub struct MemoryNonceStore {
-    nonces: Mutex<HashSet<Nonce>>,
+    nonces: Mutex<BoundedMap<Nonce, NonceRecord>>,
+    key_lifetimes: KeyLifetimes,
+}
+
+impl MemoryNonceStore {
+    /// NOTE: evicting nonces (or refusing to store them) allows the corresponding tokens
+    ///       to be redeemed again, so limits should be chosen generously.
+    pub fn with_limits(limits: StoreLimits) -> Self {
+        MemoryNonceStore {
+            nonces: Mutex::new(BoundedMap::with_limits(limits)),
+            key_lifetimes: KeyLifetimes::default(),
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .stats()")
+            .stats()
+    }
+
+    /// Inserts `nonce` unless already present, returning whether it was inserted.
+    /// Unlike `exists` followed by `insert`, two concurrent callers can't both succeed.
+    /// NOTE: with `EvictionPolicy::RejectNew`, a full store can't record the nonce, which is
+    ///       reported as it not being inserted.
+    pub fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        self.record(
+            nonce,
+            NonceRecord {
+                recorded_at: global_clock().unix_seconds(),
+                token_key_id: None,
+            },
+        )
+    }
+
+    /// Like `insert_if_absent`, but remembers the (full) id of the key of the token, so that
+    /// the nonce is forgotten once that key retires, see `set_key_lifetime`. Nonces of retired
+    /// keys are never inserted, as those of their tokens redeemed so far were forgotten.
+    pub fn insert_if_absent_for_key(&self, nonce: Nonce, token_key_id: [u8; 32]) -> bool {
+        let now = global_clock().unix_seconds();
+        self.prune_retired_keys(now);
+        if self.key_lifetimes.retired_at(&token_key_id).is_some() {
+            return false;
+        }
+        self.record(
+            nonce,
+            NonceRecord {
+                recorded_at: now,
+                token_key_id: Some(token_key_id),
+            },
+        )
+    }
+
+    fn record(&self, nonce: Nonce, record: NonceRecord) -> bool {
+        let mut nonces = self
+            .nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .record()");
+        if nonces.entries.contains_key(&nonce) {
+            return false;
+        }
+        nonces.insert(nonce, record);
+        nonces.entries.contains_key(&nonce)
+    }
+
+    /// Forgets the nonces recorded for the key of `token_key_id` once `retires_at` (unix time,
+    /// in seconds) is reached, bounding how long they're kept to the lifetime of their key.
+    /// Returns false if the key already retired, as its lifetime can't be extended anymore.
+    /// NOTE: a forgotten nonce can be redeemed again, so past `retires_at` the nonces of the
+    ///       key are refused by `insert_if_absent_for_key`, and the key must be refused for
+    ///       redemption, see `key_retired_at`.
+    pub fn set_key_lifetime(&self, token_key_id: [u8; 32], retires_at: u64) -> bool {
+        self.key_lifetimes.set(token_key_id, retires_at)
+    }
+
+    /// Unix time (in seconds) the key of `token_key_id` retired at, None if it didn't retire
+    pub fn key_retired_at(&self, token_key_id: &[u8; 32]) -> Option<u64> {
+        self.key_lifetimes.retired_at(token_key_id)
+    }
+
+    /// Forgets the nonces recorded for the key of `token_key_id`, returning how many
+    pub fn forget_key(&self, token_key_id: &[u8; 32]) -> usize {
+        self.nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .forget_key()")
+            .remove_where(|record| record.token_key_id.as_ref() == Some(token_key_id))
+    }
+
+    /// Forgets the nonces of keys retired at `now`, returning how many
+    pub fn prune_retired_keys(&self, now: u64) -> usize {
+        self.key_lifetimes
+            .take_retired(now)
+            .iter()
+            .map(|token_key_id| self.forget_key(token_key_id))
+            .sum()
+    }
+
+    /// Forgets nonces recorded more than `max_age_seconds` before `now`, returning how many.
+    /// NOTE: a forgotten nonce can be redeemed again, so `max_age_seconds` must exceed how
+    ///       long its token is accepted for.
+    pub fn prune_older_than(&self, max_age_seconds: u64, now: u64) -> usize {
+        self.nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .prune_older_than()")
+            .prune_oldest(|record| now.saturating_sub(record.recorded_at) > max_age_seconds)
+    }
+
+    /// The recorded nonces and the unix time they were recorded at, oldest first
+    pub fn snapshot(&self) -> Vec<(Nonce, u64)> {
+        let nonces = self
+            .nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .snapshot()");
+        nonces
+            .order
+            .iter()
+            .filter_map(|nonce| Some((*nonce, nonces.entries.get(nonce)?.recorded_at)))
+            .collect()
+    }
+
+    /// Records `nonce` as if recorded at `recorded_at`, unless already present, returning
+    /// whether it was inserted. Used to restore snapshots: the nonce is put among the recorded
+    /// ones by the time it was recorded at, so that it expires in turn.
+    pub fn restore(&self, nonce: Nonce, recorded_at: u64) -> bool {
+        let mut nonces = self
+            .nonces
+            .lock()
+            .expect("MemoryNonceStore .lock() failed on .restore()");
+        if nonces.entries.contains_key(&nonce) {
+            return false;
+        }
+        let record = NonceRecord {
+            recorded_at,
+            token_key_id: None,
+        };
+        nonces.insert_ordered(nonce, record, |record| record.recorded_at);
+        nonces.entries.contains_key(&nonce)
+    }
+}
+
+/// Nonce stores able to check for and record a nonce in a single atomic step
+#[async_trait]
+pub trait AtomicNonceStore: NonceStore {
+    /// Records `nonce`, returning false if it was already recorded, or could not be recorded
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool;
+}
+
+#[async_trait]
+impl AtomicNonceStore for MemoryNonceStore {
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        MemoryNonceStore::insert_if_absent(self, nonce)
+    }
 }
 
 #[async_trait]
@@ -24,7 +444,7 @@ impl NonceStore for MemoryNonceStore {
             .nonces
             .lock()
             .expect("MemoryNonceStore .lock() failed on .exists()");
-        nonces.contains(nonce)
+        nonces.entries.contains_key(nonce)
     }
 
     async fn insert(&self, nonce: Nonce) {
@@ -32,13 +452,292 @@ impl NonceStore for MemoryNonceStore {
             .nonces
             .lock()
             .expect("MemoryNonceStore .lock() failed on .insert()");
-        nonces.insert(nonce);
+        let record = NonceRecord {
+            recorded_at: global_clock().unix_seconds(),
+            token_key_id: None,
+        };
+        nonces.insert(nonce, record);
+    }
+}
+
+/// Default number of shards of a `ShardedMemoryNonceStore`
+pub const DEFAULT_NONCE_SHARDS: usize = 16;
+
+/// MemoryNonceStore split into shards, each behind a lock of its own, so that concurrent
+/// redemptions mostly don't wait on each other. Nonces are chosen by clients, so shards are
+/// picked by a hash of the nonce keyed with a per-store random key: clients can't aim their
+/// nonces at one shard to evict the nonces of other users from it.
+pub struct ShardedMemoryNonceStore {
+    shards: Vec<MemoryNonceStore>,
+    shard_hasher: RandomState,
+    key_lifetimes: KeyLifetimes,
+}
+
+impl Default for ShardedMemoryNonceStore {
+    fn default() -> Self {
+        Self::with_limits(DEFAULT_NONCE_SHARDS, StoreLimits::default())
+    }
+}
+
+impl ShardedMemoryNonceStore {
+    /// Splits `limits.max_entries` evenly across `shards` shards (at least 1, at most 256),
+    /// so that eviction and rejection happen per shard.
+    /// NOTE: as with MemoryNonceStore, evicted nonces can be redeemed again.
+    pub fn with_limits(shards: usize, limits: StoreLimits) -> Self {
+        let shards = shards.clamp(1, 256);
+        let shard_limits = StoreLimits {
+            max_entries: limits
+                .max_entries
+                .map(|max_entries| max_entries.div_ceil(shards)),
+            ..limits
+        };
+        ShardedMemoryNonceStore {
+            shards: (0..shards)
+                .map(|_| MemoryNonceStore::with_limits(shard_limits))
+                .collect(),
+            shard_hasher: RandomState::new(),
+            key_lifetimes: KeyLifetimes::default(),
+        }
+    }
+
+    fn shard_index(&self, nonce: &Nonce) -> usize {
+        // shards are at most 256, the cast can't truncate the remainder
+        (self.shard_hasher.hash_one(nonce) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, nonce: &Nonce) -> &MemoryNonceStore {
+        self.shards
+            .get(self.shard_index(nonce))
+            .expect("ShardedNonceStore has no shard for .shard()")
     }
+
+    /// Counters summed over all shards
+    pub fn stats(&self) -> StoreStats {
+        self.shards.iter().map(MemoryNonceStore::stats).fold(
+            StoreStats::default(),
+            |total, shard| StoreStats {
+                entries: total.entries + shard.entries,
+                inserts: total.inserts + shard.inserts,
+                evictions: total.evictions + shard.evictions,
+                rejections: total.rejections + shard.rejections,
+            },
+        )
+    }
+
+    /// See `MemoryNonceStore::insert_if_absent`, only the shard of `nonce` is locked
+    pub fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        self.shard(&nonce).insert_if_absent(nonce)
+    }
+
+    /// The recorded nonces of all shards, oldest first
+    pub fn snapshot(&self) -> Vec<(Nonce, u64)> {
+        let mut snapshot: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(MemoryNonceStore::snapshot)
+            .collect();
+        snapshot.sort_by_key(|(_, recorded_at)| *recorded_at);
+        snapshot
+    }
+
+    /// See `MemoryNonceStore::restore`
+    pub fn restore(&self, nonce: Nonce, recorded_at: u64) -> bool {
+        self.shard(&nonce).restore(nonce, recorded_at)
+    }
+
+    /// See `MemoryNonceStore::insert_if_absent_for_key`, lifetimes are kept for all shards
+    pub fn insert_if_absent_for_key(&self, nonce: Nonce, token_key_id: [u8; 32]) -> bool {
+        let now = global_clock().unix_seconds();
+        self.prune_retired_keys(now);
+        if self.key_lifetimes.retired_at(&token_key_id).is_some() {
+            return false;
+        }
+        self.shard(&nonce).record(
+            nonce,
+            NonceRecord {
+                recorded_at: now,
+                token_key_id: Some(token_key_id),
+            },
+        )
+    }
+
+    /// See `MemoryNonceStore::set_key_lifetime`
+    pub fn set_key_lifetime(&self, token_key_id: [u8; 32], retires_at: u64) -> bool {
+        self.key_lifetimes.set(token_key_id, retires_at)
+    }
+
+    /// See `MemoryNonceStore::key_retired_at`
+    pub fn key_retired_at(&self, token_key_id: &[u8; 32]) -> Option<u64> {
+        self.key_lifetimes.retired_at(token_key_id)
+    }
+
+    /// See `MemoryNonceStore::forget_key`
+    pub fn forget_key(&self, token_key_id: &[u8; 32]) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.forget_key(token_key_id))
+            .sum()
+    }
+
+    /// See `MemoryNonceStore::prune_retired_keys`
+    pub fn prune_retired_keys(&self, now: u64) -> usize {
+        self.key_lifetimes
+            .take_retired(now)
+            .iter()
+            .map(|token_key_id| self.forget_key(token_key_id))
+            .sum()
+    }
+
+    /// See `MemoryNonceStore::prune_older_than`, shards are pruned one after the other
+    pub fn prune_older_than(&self, max_age_seconds: u64, now: u64) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.prune_older_than(max_age_seconds, now))
+            .sum()
+    }
+}
+
+#[async_trait]
+impl AtomicNonceStore for ShardedMemoryNonceStore {
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        ShardedMemoryNonceStore::insert_if_absent(self, nonce)
+    }
+}
+
+#[async_trait]
+impl NonceStore for ShardedMemoryNonceStore {
+    async fn exists(&self, nonce: &Nonce) -> bool {
+        self.shard(nonce).exists(nonce).await
+    }
+
+    async fn insert(&self, nonce: Nonce) {
+        self.shard(&nonce).insert(nonce).await
+    }
+}
+
+// NOTE: VoprfServer wipes its secret key when dropped, so keys evicted from or overwritten
+//       in the key stores below don't linger in memory.
+/// What to do when installing a key whose truncated key id is already used by another key.
+/// NOTE: truncated key ids are a single byte, so rotated keys collide sooner or later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyCollisionPolicy {
+    /// Keep both keys. Lookups by truncated key id get the newest one (e.g. for issuance),
+    /// while redemption tells them apart through the full token key id carried by tokens
+    #[default]
+    KeepBoth,
+    /// Replace the older key, so that tokens issued with it stop validating
+    ReplaceOld,
+    /// Refuse the new key
+    RefuseNew,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("truncated key id {0} is already used by another key")]
+pub struct KeyCollisionError(pub TruncatedTokenKeyId);
+
+/// Key stores able to tell apart keys sharing a truncated key id, using the full token key id
+/// (i.e. SHA256 of the serialized public key) carried by tokens
+#[async_trait]
+pub trait TokenKeyIdLookup {
+    async fn get_by_token_key_id(&self, token_key_id: &[u8]) -> Option<VoprfServer<Ristretto255>>;
 }
 
 #[derive(Default)]
 pub struct MemoryKeyStoreRistretto255 {
-    keys: Mutex<HashMap<TruncatedTokenKeyId, VoprfServer<Ristretto255>>>,
+    // keys sharing a truncated key id, oldest first
+    keys: Mutex<BoundedMap<TruncatedTokenKeyId, Vec<VoprfServer<Ristretto255>>>>,
+    collision_policy: KeyCollisionPolicy,
+}
+
+impl MemoryKeyStoreRistretto255 {
+    pub fn with_limits(limits: StoreLimits) -> Self {
+        MemoryKeyStoreRistretto255 {
+            keys: Mutex::new(BoundedMap::with_limits(limits)),
+            collision_policy: KeyCollisionPolicy::default(),
+        }
+    }
+
+    pub fn with_collision_policy(mut self, collision_policy: KeyCollisionPolicy) -> Self {
+        self.collision_policy = collision_policy;
+        self
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreRistretto255 .lock() failed on .stats()")
+            .stats()
+    }
+
+    /// Installs a key, following the collision policy if its truncated key id is already used
+    /// by a different key. Installing a key again makes it the newest one for its id.
+    pub fn insert_checked(
+        &self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+        server: VoprfServer<Ristretto255>,
+    ) -> Result<(), KeyCollisionError> {
+        let mut keys = self
+            .keys
+            .lock()
+            .expect("MemoryKeyStoreRistretto255 .lock() failed on .insert_checked()");
+        let public_key = server.get_public_key();
+        let mut servers = keys
+            .entries
+            .get(&truncated_token_key_id)
+            .cloned()
+            .unwrap_or_default();
+        servers.retain(|stored| stored.get_public_key() != public_key);
+        if !servers.is_empty() {
+            match self.collision_policy {
+                KeyCollisionPolicy::KeepBoth => {}
+                KeyCollisionPolicy::ReplaceOld => servers.clear(),
+                KeyCollisionPolicy::RefuseNew => {
+                    return Err(KeyCollisionError(truncated_token_key_id))
+                }
+            }
+        }
+        servers.push(server);
+        // moved to the back, so that keys are ordered by their latest installation
+        keys.remove(&truncated_token_key_id);
+        keys.insert(truncated_token_key_id, servers);
+        Ok(())
+    }
+
+    /// Drops all but the `depth` most recently installed keys, returning how many were dropped.
+    /// Keys sharing a truncated key id count as installed when the newest of them was.
+    pub fn retain_newest_keys(&self, depth: usize) -> usize {
+        let mut keys = self
+            .keys
+            .lock()
+            .expect("MemoryKeyStoreRistretto255 .lock() failed on .retain_newest_keys()");
+        let newest_first: Vec<_> = keys.order.iter().rev().copied().collect();
+        let (mut kept, mut dropped) = (0, 0);
+        for truncated_token_key_id in newest_first {
+            let Some(servers) = keys.entries.get_mut(&truncated_token_key_id) else {
+                continue;
+            };
+            let keep = servers.len().min(depth - kept);
+            dropped += servers.len() - keep;
+            servers.drain(..servers.len() - keep);
+            kept += keep;
+            if servers.is_empty() {
+                keys.remove(&truncated_token_key_id);
+            }
+        }
+        dropped
+    }
+
+    /// Truncated key ids currently shared by more than one key
+    pub fn collisions(&self) -> Vec<TruncatedTokenKeyId> {
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreRistretto255 .lock() failed on .collisions()")
+            .entries
+            .iter()
+            .filter(|(_, servers)| servers.len() > 1)
+            .map(|(truncated_token_key_id, _)| *truncated_token_key_id)
+            .collect()
+    }
 }
 
 #[async_trait]
@@ -50,11 +749,8 @@ impl privacypass::batched_tokens_ristretto255::server::BatchedKeyStore
         truncated_token_key_id: TruncatedTokenKeyId,
         server: VoprfServer<Ristretto255>,
     ) {
-        let mut keys = self
-            .keys
-            .lock()
-            .expect("MemoryKeyStoreRistretto255 .lock() failed on .insert()");
-        keys.insert(truncated_token_key_id, server);
+        // BatchedKeyStore::insert can't report errors, a refused key is simply not installed
+        let _ = self.insert_checked(truncated_token_key_id, server);
     }
 
     async fn get(
@@ -64,14 +760,57 @@ impl privacypass::batched_tokens_ristretto255::server::BatchedKeyStore
         self.keys
             .lock()
             .expect("MemoryKeyStoreRistretto255 .lock() failed on .get()")
+            .entries
             .get(truncated_token_key_id)
+            .and_then(|servers| servers.last())
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl TokenKeyIdLookup for MemoryKeyStoreRistretto255 {
+    async fn get_by_token_key_id(&self, token_key_id: &[u8]) -> Option<VoprfServer<Ristretto255>> {
+        let truncated_token_key_id = *token_key_id.last()?;
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreRistretto255 .lock() failed on .get_by_token_key_id()")
+            .entries
+            .get(&truncated_token_key_id)?
+            .iter()
+            .find(|server| {
+                Sha256::digest(serialize_public_key(server.get_public_key())).as_slice()
+                    == token_key_id
+            })
             .cloned()
     }
 }
 
 #[derive(Default)]
 pub struct MemoryKeyStoreP384 {
-    keys: Mutex<HashMap<TruncatedTokenKeyId, VoprfServer<NistP384>>>,
+    keys: Mutex<BoundedMap<TruncatedTokenKeyId, VoprfServer<NistP384>>>,
+}
+
+impl MemoryKeyStoreP384 {
+    pub fn with_limits(limits: StoreLimits) -> Self {
+        MemoryKeyStoreP384 {
+            keys: Mutex::new(BoundedMap::with_limits(limits)),
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreP384 .lock() failed on .stats()")
+            .stats()
+    }
+
+    /// Drops all but the `depth` most recently installed keys, returning how many were dropped
+    pub fn retain_newest_keys(&self, depth: usize) -> usize {
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreP384 .lock() failed on .retain_newest_keys()")
+            .truncate_oldest(depth)
+    }
 }
 
 #[async_trait]
@@ -95,7 +834,337 @@ impl privacypass::batched_tokens_p384::server::BatchedKeyStore for MemoryKeyStor
         self.keys
             .lock()
             .expect("MemoryKeyStoreP384 .lock() failed on .get()")
+            .entries
+            .get(truncated_token_key_id)
+            .cloned()
+    }
+}
+
+// the single token P-384 flow (token type 0x0001) looks keys up the same way
+#[async_trait]
+impl privacypass::private_tokens::server::PrivateKeyStore for MemoryKeyStoreP384 {
+    async fn insert(
+        &self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+        server: VoprfServer<NistP384>,
+    ) {
+        let mut keys = self
+            .keys
+            .lock()
+            .expect("MemoryKeyStoreP384 .lock() failed on .insert()");
+        keys.insert(truncated_token_key_id, server);
+    }
+
+    async fn get(
+        &self,
+        truncated_token_key_id: &TruncatedTokenKeyId,
+    ) -> Option<VoprfServer<NistP384>> {
+        self.keys
+            .lock()
+            .expect("MemoryKeyStoreP384 .lock() failed on .get()")
+            .entries
             .get(truncated_token_key_id)
             .cloned()
     }
 }
+
+pub struct MemoryRevocationStore {
+    revoked: Mutex<BTreeSet<TruncatedTokenKeyId>>,
+}
+
+impl MemoryRevocationStore {
+    pub const fn new() -> Self {
+        MemoryRevocationStore {
+            revoked: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn contains(&self, truncated_token_key_id: &TruncatedTokenKeyId) -> bool {
+        self.revoked
+            .lock()
+            .expect("MemoryRevocationStore .lock() failed on .contains()")
+            .contains(truncated_token_key_id)
+    }
+
+    pub fn insert(&self, truncated_token_key_id: TruncatedTokenKeyId) {
+        self.revoked
+            .lock()
+            .expect("MemoryRevocationStore .lock() failed on .insert()")
+            .insert(truncated_token_key_id);
+    }
+
+    /// Replaces the whole revocation list, e.g. with one read from a store shared by replicas
+    pub fn replace(&self, truncated_token_key_ids: impl IntoIterator<Item = TruncatedTokenKeyId>) {
+        *self
+            .revoked
+            .lock()
+            .expect("MemoryRevocationStore .lock() failed on .replace()") =
+            truncated_token_key_ids.into_iter().collect();
+    }
+
+    pub fn revoked_key_ids(&self) -> Vec<TruncatedTokenKeyId> {
+        self.revoked
+            .lock()
+            .expect("MemoryRevocationStore .lock() failed on .revoked_key_ids()")
+            .iter()
+            .copied()
+            .collect()
+    }
+}
+
+impl Default for MemoryRevocationStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl RevocationStore for MemoryRevocationStore {
+    async fn is_revoked(&self, truncated_token_key_id: &TruncatedTokenKeyId) -> bool {
+        self.contains(truncated_token_key_id)
+    }
+
+    async fn revoke(&self, truncated_token_key_id: TruncatedTokenKeyId) {
+        self.insert(truncated_token_key_id)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_oldest() {
+        let mut map = BoundedMap::with_limits(StoreLimits {
+            max_entries: Some(2),
+            eviction_policy: EvictionPolicy::EvictOldest,
+        });
+        map.insert(1u8, ());
+        map.insert(2u8, ());
+        map.insert(3u8, ());
+
+        assert!(!map.entries.contains_key(&1));
+        assert!(map.entries.contains_key(&2));
+        assert!(map.entries.contains_key(&3));
+        assert_eq!(
+            map.stats(),
+            StoreStats {
+                entries: 2,
+                inserts: 3,
+                evictions: 1,
+                rejections: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_key_collision_policies() {
+        use privacypass::batched_tokens_ristretto255::server::BatchedKeyStore;
+
+        let token_key_id = |server: &VoprfServer<Ristretto255>| {
+            Sha256::digest(serialize_public_key(server.get_public_key())).to_vec()
+        };
+        // search for two keys whose truncated key ids collide
+        let mut seen = HashMap::new();
+        let (old_key, new_key) = (0u8..=255)
+            .find_map(|seed| {
+                let server = VoprfServer::<Ristretto255>::new_from_seed(&[seed; 32], b"").unwrap();
+                let truncated_token_key_id = *token_key_id(&server).last().unwrap();
+                match seen.insert(truncated_token_key_id, server.clone()) {
+                    Some(old_key) => Some((old_key, server)),
+                    None => None,
+                }
+            })
+            .unwrap();
+        let id = *token_key_id(&old_key).last().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let key_store = MemoryKeyStoreRistretto255::default();
+        key_store.insert_checked(id, old_key.clone()).unwrap();
+        key_store.insert_checked(id, new_key.clone()).unwrap();
+        assert_eq!(key_store.collisions(), vec![id]);
+        rt.block_on(async {
+            let newest = key_store.get(&id).await.unwrap();
+            assert_eq!(newest.get_public_key(), new_key.get_public_key());
+            let resolved = key_store
+                .get_by_token_key_id(&token_key_id(&old_key))
+                .await
+                .unwrap();
+            assert_eq!(resolved.get_public_key(), old_key.get_public_key());
+        });
+
+        let key_store = MemoryKeyStoreRistretto255::default()
+            .with_collision_policy(KeyCollisionPolicy::RefuseNew);
+        key_store.insert_checked(id, old_key.clone()).unwrap();
+        assert_eq!(
+            key_store.insert_checked(id, new_key.clone()),
+            Err(KeyCollisionError(id))
+        );
+        // installing the same key again isn't a collision
+        key_store.insert_checked(id, old_key.clone()).unwrap();
+
+        let key_store = MemoryKeyStoreRistretto255::default()
+            .with_collision_policy(KeyCollisionPolicy::ReplaceOld);
+        key_store.insert_checked(id, old_key).unwrap();
+        key_store.insert_checked(id, new_key).unwrap();
+        assert!(key_store.collisions().is_empty());
+    }
+
+    #[test]
+    fn test_reject_new() {
+        let mut map = BoundedMap::with_limits(StoreLimits {
+            max_entries: Some(1),
+            eviction_policy: EvictionPolicy::RejectNew,
+        });
+        map.insert(1u8, ());
+        map.insert(2u8, ());
+
+        assert!(map.entries.contains_key(&1));
+        assert!(!map.entries.contains_key(&2));
+        assert_eq!(map.stats().rejections, 1);
+    }
+
+    #[test]
+    fn test_sharded_nonce_store() {
+        let nonce_store = ShardedMemoryNonceStore::with_limits(
+            4,
+            StoreLimits {
+                max_entries: Some(8),
+                eviction_policy: EvictionPolicy::RejectNew,
+            },
+        );
+        // three nonces sharing a shard holding at most 2 of them, and one of another shard
+        let nonces: Vec<Nonce> = (0..=u8::MAX).map(|i| [i; 32]).collect();
+        let shard = nonce_store.shard_index(&nonces[0]);
+        let same_shard: Vec<_> = nonces
+            .iter()
+            .filter(|nonce| nonce_store.shard_index(nonce) == shard)
+            .take(3)
+            .collect();
+        let other_shard = nonces
+            .iter()
+            .find(|nonce| nonce_store.shard_index(nonce) != shard)
+            .unwrap();
+        assert!(nonce_store.insert_if_absent(*same_shard[0]));
+        assert!(!nonce_store.insert_if_absent(*same_shard[0]));
+        assert!(nonce_store.insert_if_absent(*same_shard[1]));
+        assert!(!nonce_store.insert_if_absent(*same_shard[2]));
+        assert!(nonce_store.insert_if_absent(*other_shard));
+        let stats = nonce_store.stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.rejections, 1);
+
+        let now = global_clock().unix_seconds();
+        assert_eq!(nonce_store.prune_older_than(60, now + 61), 3);
+        assert!(nonce_store.insert_if_absent(*same_shard[2]));
+    }
+
+    #[test]
+    fn test_nonces_sharing_a_prefix_spread_over_shards() {
+        let nonce_store = ShardedMemoryNonceStore::default();
+        // nonces a client picked to share their first bytes
+        let shards: BTreeSet<_> = (0..=u8::MAX)
+            .map(|i| {
+                let mut nonce = [0u8; 32];
+                nonce[31] = i;
+                nonce_store.shard_index(&nonce)
+            })
+            .collect();
+        // all 256 landing in one of 16 shards has a probability of 16^-255
+        assert!(shards.len() > 1);
+    }
+
+    #[test]
+    fn test_nonces_are_forgotten_with_their_key() {
+        let nonce_store = ShardedMemoryNonceStore::default();
+        let now = global_clock().unix_seconds();
+        assert!(nonce_store.insert_if_absent_for_key([1u8; 32], [7u8; 32]));
+        assert!(nonce_store.insert_if_absent_for_key([2u8; 32], [8u8; 32]));
+        assert!(nonce_store.insert_if_absent([3u8; 32]));
+        assert!(nonce_store.set_key_lifetime([7u8; 32], now + 60));
+        assert_eq!(nonce_store.prune_retired_keys(now), 0);
+        assert!(!nonce_store.insert_if_absent_for_key([1u8; 32], [7u8; 32]));
+
+        assert_eq!(nonce_store.prune_retired_keys(now + 60), 1);
+        assert_eq!(nonce_store.stats().entries, 2);
+        assert_eq!(nonce_store.key_retired_at(&[7u8; 32]), Some(now + 60));
+        // already retired keys are not forgotten twice, nor revived
+        assert_eq!(nonce_store.prune_retired_keys(now + 120), 0);
+        assert!(!nonce_store.set_key_lifetime([7u8; 32], u64::MAX));
+        assert_eq!(nonce_store.forget_key(&[8u8; 32]), 1);
+        assert!(!nonce_store.insert_if_absent([3u8; 32]));
+    }
+
+    #[test]
+    fn test_key_lifetimes_of_colliding_truncated_ids() {
+        // two keys sharing their truncated key id (the last byte)
+        let mut retiring_key_id = [1u8; 32];
+        let mut live_key_id = [2u8; 32];
+        retiring_key_id[31] = 9;
+        live_key_id[31] = 9;
+
+        let nonce_store = ShardedMemoryNonceStore::default();
+        let now = global_clock().unix_seconds();
+        assert!(nonce_store.insert_if_absent_for_key([1u8; 32], retiring_key_id));
+        assert!(nonce_store.insert_if_absent_for_key([2u8; 32], live_key_id));
+        assert!(nonce_store.set_key_lifetime(retiring_key_id, now));
+        assert_eq!(nonce_store.prune_retired_keys(now), 1);
+
+        // the tokens of the retired key are refused, those of the live key still recorded
+        assert!(!nonce_store.insert_if_absent_for_key([1u8; 32], retiring_key_id));
+        assert!(!nonce_store.insert_if_absent_for_key([3u8; 32], retiring_key_id));
+        assert!(!nonce_store.insert_if_absent_for_key([2u8; 32], live_key_id));
+        assert!(nonce_store.insert_if_absent_for_key([3u8; 32], live_key_id));
+        assert_eq!(nonce_store.key_retired_at(&live_key_id), None);
+    }
+
+    #[test]
+    fn test_retention_pruning() {
+        let mut map = BoundedMap::default();
+        map.insert(1u8, 100u64);
+        map.insert(2u8, 200u64);
+        map.insert(3u8, 300u64);
+        assert_eq!(map.prune_oldest(|recorded_at| *recorded_at < 250), 2);
+        assert!(map.entries.contains_key(&3));
+        assert_eq!(map.truncate_oldest(0), 1);
+        assert!(map.entries.is_empty());
+
+        let nonce_store = MemoryNonceStore::default();
+        assert!(nonce_store.insert_if_absent([1u8; 32]));
+        let now = global_clock().unix_seconds();
+        assert_eq!(nonce_store.prune_older_than(60, now), 0);
+        assert_eq!(nonce_store.prune_older_than(60, now + 61), 1);
+        assert!(nonce_store.insert_if_absent([1u8; 32]));
+
+        // restored nonces expire by the time they were recorded at, whatever the restore order
+        let nonce_store = MemoryNonceStore::default();
+        assert!(nonce_store.restore([3u8; 32], 300));
+        assert!(nonce_store.restore([1u8; 32], 100));
+        assert!(nonce_store.restore([2u8; 32], 200));
+        assert!(!nonce_store.restore([2u8; 32], 50));
+        assert_eq!(nonce_store.prune_older_than(100, 350), 2);
+        assert_eq!(nonce_store.snapshot(), vec![([3u8; 32], 300)]);
+
+        let key_store = MemoryKeyStoreRistretto255::default();
+        let servers: Vec<_> = (0u8..3)
+            .map(|seed| VoprfServer::<Ristretto255>::new_from_seed(&[seed; 32], b"").unwrap())
+            .collect();
+        for (truncated_token_key_id, server) in (10u8..).zip(&servers) {
+            key_store
+                .insert_checked(truncated_token_key_id, server.clone())
+                .unwrap();
+        }
+        // reinstalling the oldest key makes it the newest
+        key_store.insert_checked(10, servers[0].clone()).unwrap();
+        assert_eq!(key_store.retain_newest_keys(2), 1);
+        let keys = key_store.keys.lock().unwrap();
+        assert!(keys.entries.contains_key(&10));
+        assert!(!keys.entries.contains_key(&11));
+        assert!(keys.entries.contains_key(&12));
+    }
+}
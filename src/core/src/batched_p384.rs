@@ -0,0 +1,566 @@
+// -----------------------------------------------------------------------------
+// ------------------------  batched P-384 tokens  -----------------------------
+// -----------------------------------------------------------------------------
+//
+// The batched flow of server.rs, over P-384 instead of VoprfGroup (ristretto255), for
+// deployments whose clients only do NIST curves. Issuance runs through the batched_tokens_p384
+// module of privacypass-rust; redemption shares `verify_p384_token_uniformly` with the type
+// 0x0001 tokens, for which keys are serialized the same way. The group is picked at runtime:
+// the gen_keys, gen_token_response and validate_token FFI go by the token type they are given,
+// `PrivacyPass` by its batched group, see `BatchedGroup`.
+
+use crate::audit::{record_redemption, RedemptionOutcome};
+use crate::challenge_authentication::ChallengeAuthenticationError;
+use crate::challenge_freshness::ChallengeFreshnessError;
+use crate::config::{batched_tokens_p384_mod, BatchedP384TokenType, MemoryKeyStoreBatchedP384};
+use crate::crypto_pool::{run_blocking, CryptoPoolError};
+use crate::crystal::{
+    encode_json_for_crystal, encode_secret_json_for_crystal, secret_from_slice, JSONRetVal,
+    JSONRetValRef,
+};
+use crate::issuance::first_occurrences;
+use crate::key_encoding::KeyEncoding;
+use crate::key_validity::KeyValidityError;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::private_tokens::{
+    public_key_to_token_key_id, public_key_to_truncated_token_key_id, serialize_public_key,
+    verify_p384_token_uniformly, P384Keypair,
+};
+use crate::replay::redeem_nonce;
+use crate::revocation::KeyRevokedError;
+use crate::server::{
+    check_key, check_redemption_challenge, duplicate_element_policy, token_response_for_crystal,
+    DuplicateBlindedElementError, DuplicateElementPolicy, KeyPair, KeyUse, PrivacyPass,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use batched_tokens_p384_mod::server::{CreateKeypairError, IssueTokenResponseError, Server};
+use batched_tokens_p384_mod::{TokenRequest, TokenResponse};
+use generic_array::GenericArray;
+use kagippverify::token::token_nonce;
+use p384::NistP384;
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::TruncatedTokenKeyId;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::ExposeSecret;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use voprf::{derive_key, Group, Mode, VoprfServer};
+use zeroize::Zeroizing;
+
+/// Size of a serialized (compressed) P-384 blinded element
+pub(crate) const NE: usize = 49;
+
+/// How many seeds `gen_batched_p384_keys` tries before giving up on a free truncated key id
+const MAX_KEY_ID_ATTEMPTS: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum BatchedP384Error {
+    #[error("failed to construct keypair")]
+    CreateKeypair(#[from] CreateKeypairError),
+    #[error("failed generating secret key")]
+    DeriveKey(voprf::Error),
+    #[error("no free truncated key id")]
+    NoFreeKeyId,
+    #[error("failed to load secret key")]
+    InvalidKey(voprf::Error),
+    #[error("requested {0} tokens, max is {1}")]
+    RequestedTooManyTokens(usize, usize),
+    #[error("token request repeats a blinded element")]
+    DuplicateBlindedElement(#[from] DuplicateBlindedElementError),
+    #[error("failed to issue token response")]
+    IssueTokenResponse(#[from] IssueTokenResponseError),
+    #[error("failed to (de)serialize token request")]
+    Tls(#[from] tls_codec::Error),
+    #[error("failed to run the P-384 VOPRF")]
+    CryptoPool(#[from] CryptoPoolError),
+    #[error("key was revoked")]
+    KeyRevoked(#[from] KeyRevokedError),
+    #[error("key is outside of its validity window")]
+    KeyValidity(#[from] KeyValidityError),
+    #[error("token challenge is not fresh")]
+    ChallengeFreshness(#[from] ChallengeFreshnessError),
+    #[error("token challenge is not authenticated")]
+    ChallengeAuthentication(#[from] ChallengeAuthenticationError),
+}
+
+/// Derives a fresh batched P-384 keypair under `info`, whose truncated key id is not in `taken`
+pub fn gen_batched_p384_keys(
+    rt: &tokio::runtime::Handle,
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+) -> Result<P384Keypair, BatchedP384Error> {
+    if (0..=TruncatedTokenKeyId::MAX).all(|id| taken.contains(&id)) {
+        return Err(BatchedP384Error::NoFreeKeyId);
+    }
+    let mut seed = Zeroizing::new(GenericArray::<_, <NistP384 as Group>::ScalarLen>::default());
+    let mut attempts = 0;
+    loop {
+        OsRng.fill_bytes(&mut seed);
+        let server = VoprfServer::<NistP384>::new_from_seed(&seed, info)
+            .map_err(BatchedP384Error::DeriveKey)?;
+        if !taken.contains(&public_key_to_truncated_token_key_id(
+            server.get_public_key(),
+        )) {
+            break;
+        }
+        attempts += 1;
+        if attempts == MAX_KEY_ID_ATTEMPTS {
+            return Err(BatchedP384Error::NoFreeKeyId);
+        }
+    }
+
+    let server = Server::new();
+    let key_store = MemoryKeyStoreBatchedP384::default();
+    let public_key = rt.block_on(server.create_keypair_with_params(&key_store, &seed, info))?;
+    let secret_key =
+        derive_key::<NistP384>(&seed, info, Mode::Voprf).map_err(BatchedP384Error::DeriveKey)?;
+
+    Ok(P384Keypair {
+        public_key: serialize_public_key(public_key),
+        secret_key: secret_from_slice(&NistP384::serialize_scalar(secret_key)),
+        info: info.to_vec(),
+    })
+}
+
+/// Drops the blinded elements past the first `max_nr` of a serialized TokenRequest
+/// (`token_type || truncated_token_key_id || blinded_elements<0..2^16-1>`), returning
+/// whether any were dropped
+fn truncate_token_request(
+    token_request_bytes: &mut Vec<u8>,
+    max_nr: usize,
+) -> Result<bool, tls_codec::Error> {
+    let Some(len_bytes) = token_request_bytes
+        .get(3..)
+        .and_then(|rest| rest.first_chunk::<2>())
+    else {
+        return Err(tls_codec::Error::EndOfStream);
+    };
+    let len = usize::from(u16::from_be_bytes(*len_bytes));
+    let Some(max_len) = max_nr.checked_mul(NE).filter(|max_len| *max_len < len) else {
+        return Ok(false);
+    };
+    token_request_bytes.truncate(5 + max_len);
+    set_blinded_elements_len(token_request_bytes, max_len)?;
+    Ok(true)
+}
+
+/// Overwrites the length prefix of the blinded elements of a serialized TokenRequest
+fn set_blinded_elements_len(
+    token_request_bytes: &mut [u8],
+    len: usize,
+) -> Result<(), tls_codec::Error> {
+    let len = u16::try_from(len).map_err(|_| tls_codec::Error::InvalidVectorLength)?;
+    token_request_bytes
+        .get_mut(3..5)
+        .ok_or(tls_codec::Error::EndOfStream)?
+        .copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Applies `policy` to the blinded elements of a serialized TokenRequest, dropping repeated
+/// ones in place. Returns the indices of the blinded elements kept, in order, when any were
+/// dropped.
+fn deduplicate_token_request(
+    token_request_bytes: &mut Vec<u8>,
+    policy: DuplicateElementPolicy,
+) -> Result<Option<Vec<usize>>, BatchedP384Error> {
+    let Some(blinded_elements) = token_request_bytes.get(5..) else {
+        return Err(tls_codec::Error::EndOfStream.into());
+    };
+    let blinded_elements: Vec<&[u8]> = blinded_elements.chunks_exact(NE).collect();
+    let kept = first_occurrences(blinded_elements.iter().copied());
+    if kept.len() == blinded_elements.len() {
+        return Ok(None);
+    }
+    if policy == DuplicateElementPolicy::Reject {
+        // the first blinded element not kept repeats an earlier one
+        let index = kept
+            .iter()
+            .zip(0..)
+            .position(|(kept, index)| *kept != index)
+            .unwrap_or(kept.len());
+        return Err(DuplicateBlindedElementError(index).into());
+    }
+    let deduplicated: Vec<u8> = kept
+        .iter()
+        .filter_map(|index| blinded_elements.get(*index))
+        .flat_map(|blinded_element| blinded_element.iter())
+        .copied()
+        .collect();
+    token_request_bytes.truncate(5);
+    set_blinded_elements_len(token_request_bytes, deduplicated.len())?;
+    token_request_bytes.extend(deduplicated);
+    Ok(Some(kept))
+}
+
+/// Issues the TokenResponse for a serialized batched P-384 TokenRequest, truncated to its
+/// first `max_nr` blinded elements and with repeated ones handled like the ristretto255 FFI
+/// does
+pub fn issue_batched_p384_token_response(
+    rt: &tokio::runtime::Handle,
+    private_key: &[u8],
+    token_request_bytes: Vec<u8>,
+    max_nr: usize,
+) -> Result<TokenResponse, BatchedP384Error> {
+    issue_deduplicated_token_response(rt, private_key, token_request_bytes, max_nr)
+        .map(|(token_response, _, _)| token_response)
+}
+
+/// Body of `issue_batched_p384_token_response`, also returning the number of blinded elements
+/// dropped, past the first `max_nr` or repeated, and the indices of the blinded elements
+/// answered when repeated ones were dropped
+fn issue_deduplicated_token_response(
+    rt: &tokio::runtime::Handle,
+    private_key: &[u8],
+    mut token_request_bytes: Vec<u8>,
+    max_nr: usize,
+) -> Result<(TokenResponse, usize, Option<Vec<usize>>), BatchedP384Error> {
+    // 5 bytes of token_type || truncated_token_key_id || blinded_elements length
+    let blinded_elements_nr =
+        |token_request_bytes: &[u8]| token_request_bytes.len().saturating_sub(5) / NE;
+    let requested_nr = blinded_elements_nr(&token_request_bytes);
+    truncate_token_request(&mut token_request_bytes, max_nr)?;
+    let kept = deduplicate_token_request(&mut token_request_bytes, duplicate_element_policy())?;
+    let token_request = TokenRequest::tls_deserialize(&mut token_request_bytes.as_slice())?;
+    let dropped = requested_nr - blinded_elements_nr(&token_request_bytes);
+
+    let server = Server::new();
+    let key_store = MemoryKeyStoreBatchedP384::default();
+    let token_response = rt.block_on(async {
+        let public_key = server.set_key(&key_store, private_key).await?;
+        check_key::<BatchedP384Error>(&public_key_to_token_key_id(public_key), KeyUse::Issuance)?;
+        Ok::<TokenResponse, BatchedP384Error>(
+            server
+                .issue_token_response(&key_store, token_request)
+                .await?,
+        )
+    })?;
+    Ok((token_response, dropped, kept))
+}
+
+/// Loads a batched P-384 secret key
+pub(crate) fn load_batched_p384_key(
+    private_key: &[u8],
+) -> Result<VoprfServer<NistP384>, BatchedP384Error> {
+    VoprfServer::<NistP384>::new_with_key(private_key).map_err(BatchedP384Error::InvalidKey)
+}
+
+/// Loads `private_key` and checks a serialized batched P-384 token against it, returning the
+/// token key id of the key along with the validity of the token
+fn check_batched_p384_token(
+    private_key: &[u8],
+    token: &[u8],
+    challenge_digest: Option<&[u8]>,
+) -> Result<([u8; 32], bool), BatchedP384Error> {
+    check_batched_p384_token_with(
+        &load_batched_p384_key(private_key)?,
+        token,
+        challenge_digest,
+    )
+}
+
+/// Like `check_batched_p384_token`, against the loaded key of `server`
+fn check_batched_p384_token_with(
+    server: &VoprfServer<NistP384>,
+    token: &[u8],
+    challenge_digest: Option<&[u8]>,
+) -> Result<([u8; 32], bool), BatchedP384Error> {
+    let token_key_id = public_key_to_token_key_id(server.get_public_key());
+    check_key::<BatchedP384Error>(&token_key_id, KeyUse::Redemption)?;
+    let valid = bool::from(verify_p384_token_uniformly(
+        server,
+        token,
+        BatchedP384TokenType as u16,
+        challenge_digest,
+    ));
+    Ok((token_key_id, valid))
+}
+
+impl PrivacyPass {
+    /// Like `gen_keys`, for batched P-384 tokens
+    pub async fn gen_keys_batched_p384(&self) -> Result<P384Keypair, BatchedP384Error> {
+        let info = self.info().to_vec();
+        run_blocking(move || gen_batched_p384_keys(&tokio::runtime::Handle::current(), &info, &[]))
+            .await?
+    }
+
+    /// Like `gen_token_response`, for batched P-384 tokens
+    pub async fn gen_token_response_batched_p384(
+        &self,
+        private_key: &[u8],
+        token_request: TokenRequest,
+        max_requests: usize,
+    ) -> Result<TokenResponse, BatchedP384Error> {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        if token_request.nr() > max_requests {
+            return Err(BatchedP384Error::RequestedTooManyTokens(
+                token_request.nr(),
+                max_requests,
+            ));
+        }
+        let token_request_bytes = token_request.tls_serialize_detached()?;
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || {
+            issue_batched_p384_token_response(
+                &tokio::runtime::Handle::current(),
+                private_key.expose_secret(),
+                token_request_bytes,
+                max_requests,
+            )
+        })
+        .await?
+    }
+
+    /// Like `validate_token`, for batched P-384 tokens
+    pub async fn validate_token_batched_p384(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+    ) -> Result<bool, BatchedP384Error> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        check_redemption_challenge::<BatchedP384Error>(None)?;
+        let token = token.to_vec();
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || {
+            let (token_key_id, valid) =
+                check_batched_p384_token(private_key.expose_secret(), &token, None)?;
+            let [.., truncated_token_key_id] = token_key_id;
+            record_redemption(
+                truncated_token_key_id,
+                RedemptionOutcome::from_validity(valid),
+            );
+            Ok(valid)
+        })
+        .await?
+    }
+}
+
+/// Body of `gen_keys` when the batched flow runs over P-384, deriving the key under `info`
+/// and returning it in `encoding`
+pub(crate) fn gen_keys_for_crystal(
+    rt: &tokio::runtime::Runtime,
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let keypair = gen_batched_p384_keys(rt.handle(), info, taken)?;
+    let keypair = KeyPair {
+        pk: encoding.encode(&keypair.public_key),
+        sk: encoding.encode(keypair.secret_key.expose_secret()),
+        token_type: BatchedP384TokenType as u16,
+        error: "".to_string(),
+    };
+    let keypair_json = Zeroizing::new(serde_json::to_string(&keypair)?);
+
+    let rv = JSONRetValRef {
+        retval: keypair_json.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Body of `gen_token_response` when the batched flow runs over P-384
+pub(crate) fn issue_for_crystal(
+    rt: &tokio::runtime::Runtime,
+    private_key: &[u8],
+    token_request_bytes: Vec<u8>,
+    max_nr: u16,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let (token_response, dropped, kept) = issue_deduplicated_token_response(
+        rt.handle(),
+        private_key,
+        token_request_bytes,
+        usize::from(max_nr),
+    )?;
+
+    token_response_for_crystal(
+        &token_response.tls_serialize_detached()?,
+        dropped,
+        kept.as_deref(),
+    )
+}
+
+/// Redeems the base64 `token_encoded` against `server` and `challenge_digest`, returning
+/// whether it is valid and wasn't redeemed before
+pub(crate) fn redeem_encoded_token(
+    server: &VoprfServer<NistP384>,
+    token_encoded: &[u8],
+    challenge_digest: &[u8],
+) -> Result<bool, BatchedP384Error> {
+    // malformed (or non canonical) base64 is rejected like any other invalid token
+    let token = URL_SAFE
+        .decode(token_encoded)
+        .ok()
+        .filter(|token| bool::from(URL_SAFE.encode(token).as_bytes().ct_eq(token_encoded)))
+        .unwrap_or_default();
+    let (token_key_id, valid) =
+        check_batched_p384_token_with(server, &token, Some(challenge_digest))?;
+    let [.., truncated_token_key_id] = token_key_id;
+
+    // refuse replays, only recording nonces of valid tokens
+    let mut outcome = RedemptionOutcome::from_validity(valid);
+    if valid && !token_nonce(&token).is_some_and(|nonce| redeem_nonce(nonce, token_key_id)) {
+        outcome = RedemptionOutcome::DoubleSpent;
+    }
+    record_redemption(truncated_token_key_id, outcome);
+    Ok(outcome == RedemptionOutcome::Valid)
+}
+
+/// Body of `validate_token` when the batched flow runs over P-384, returning "1" for valid
+/// tokens
+pub(crate) fn validate_token_for_crystal(
+    private_key: &[u8],
+    token_encoded: &[u8],
+    token_challenge_s: &str,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let challenge_digest = TokenChallenge::from_base64(token_challenge_s)?.digest()?;
+    check_redemption_challenge::<BatchedP384Error>(Some(token_challenge_s))?;
+    let server = load_batched_p384_key(private_key)?;
+    let valid_s = match redeem_encoded_token(&server, token_encoded, &challenge_digest)? {
+        true => "1",
+        false => "0",
+    };
+
+    let rv = JSONRetVal {
+        retval: valid_s.to_string(),
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BatchedGroup;
+    use batched_tokens_p384_mod::client::Client;
+    use p384::elliptic_curve::Field;
+
+    #[test]
+    fn test_truncate_token_request() {
+        let mut token_request_bytes = vec![0xF9, 0x01, 7];
+        token_request_bytes.extend(((3 * NE) as u16).to_be_bytes());
+        token_request_bytes.extend([1u8; 3 * NE]);
+
+        assert!(!truncate_token_request(&mut token_request_bytes, 3).unwrap());
+        assert_eq!(token_request_bytes.len(), 5 + 3 * NE);
+        assert!(truncate_token_request(&mut token_request_bytes, 2).unwrap());
+        assert_eq!(token_request_bytes.len(), 5 + 2 * NE);
+        assert_eq!(token_request_bytes[3..5], ((2 * NE) as u16).to_be_bytes());
+        assert!(truncate_token_request(&mut vec![0xF9, 0x01], 2).is_err());
+    }
+
+    #[test]
+    fn test_deduplicate_token_request() {
+        let [a, b, c] = [[1u8; NE], [2u8; NE], [3u8; NE]];
+        let token_request_bytes = |blinded_elements: &[[u8; NE]]| {
+            let mut token_request_bytes = vec![0xF9, 0x01, 7];
+            token_request_bytes.extend(((blinded_elements.len() * NE) as u16).to_be_bytes());
+            token_request_bytes.extend(blinded_elements.concat());
+            token_request_bytes
+        };
+
+        let mut repeating = token_request_bytes(&[a, b, a, c, b]);
+        assert!(matches!(
+            deduplicate_token_request(&mut repeating, DuplicateElementPolicy::Reject),
+            Err(BatchedP384Error::DuplicateBlindedElement(
+                DuplicateBlindedElementError(2)
+            ))
+        ));
+        // first occurrences are kept, in order
+        assert_eq!(
+            deduplicate_token_request(&mut repeating, DuplicateElementPolicy::Deduplicate).unwrap(),
+            Some(vec![0, 1, 3])
+        );
+        assert_eq!(repeating, token_request_bytes(&[a, b, c]));
+        assert_eq!(
+            deduplicate_token_request(&mut repeating, DuplicateElementPolicy::Reject).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_keys_avoid_taken_key_ids() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let taken: Vec<TruncatedTokenKeyId> = (0..128).collect();
+        for _ in 0..8 {
+            let keypair =
+                gen_batched_p384_keys(rt.handle(), crate::server::DEFAULT_KEY_INFO, &taken)
+                    .unwrap();
+            let public_key = NistP384::deserialize_elem(&keypair.public_key).unwrap();
+            assert!(public_key_to_truncated_token_key_id(public_key) >= 128);
+        }
+
+        let taken: Vec<TruncatedTokenKeyId> = (0..=TruncatedTokenKeyId::MAX).collect();
+        assert!(matches!(
+            gen_batched_p384_keys(rt.handle(), crate::server::DEFAULT_KEY_INFO, &taken),
+            Err(BatchedP384Error::NoFreeKeyId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batched_p384_issuance_and_redemption() {
+        let privacy_pass = PrivacyPass::new().with_batched_group(BatchedGroup::P384);
+        let keypair = privacy_pass.gen_keys_batched_p384().await.unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let public_key = NistP384::deserialize_elem(&keypair.public_key).unwrap();
+        let token_challenge = privacy_pass.token_challenge();
+        assert_eq!(
+            token_challenge.token_type() as u16,
+            BatchedP384TokenType as u16
+        );
+
+        let nonces = (0..3u8).map(|i| [i; 32]).collect();
+        let blinds = (0..3)
+            .map(|_| <NistP384 as Group>::Scalar::random(&mut OsRng))
+            .collect();
+        let client = Client::new(public_key);
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(&token_challenge, nonces, blinds)
+            .unwrap();
+        let token_response = privacy_pass
+            .gen_token_response_batched_p384(sk, token_request, 3)
+            .await
+            .unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+        assert_eq!(tokens.len(), 3);
+
+        let challenge_digest = token_challenge.digest().unwrap();
+        for token in tokens {
+            let mut token = token.tls_serialize_detached().unwrap();
+            assert!(privacy_pass
+                .validate_token_batched_p384(&token, sk)
+                .await
+                .unwrap());
+            assert!(
+                check_batched_p384_token(sk, &token, Some(&challenge_digest))
+                    .unwrap()
+                    .1
+            );
+            assert!(
+                !check_batched_p384_token(sk, &token, Some(&[0u8; 32]))
+                    .unwrap()
+                    .1
+            );
+            let last = token.len() - 1;
+            token[last] ^= 1;
+            assert!(!privacy_pass
+                .validate_token_batched_p384(&token, sk)
+                .await
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_batched_group_selection() {
+        assert_eq!(
+            BatchedGroup::from_token_type(BatchedP384TokenType as u16),
+            Some(BatchedGroup::P384)
+        );
+        assert_eq!(BatchedGroup::from_token_type(0x0002), None);
+    }
+}
@@ -0,0 +1,98 @@
+// -----------------------------------------------------------------------------
+// ---------------------  key generation ceremony CLI  -------------------------
+// -----------------------------------------------------------------------------
+//
+// pp-ceremony contribute
+//     samples a contribution, prints it (keep it secret until the reveal) and its commitment
+// pp-ceremony combine <info> <commitments.json> <contributions.json>
+//     derives the issuer keypair from all contributions and prints it with the transcript
+// pp-ceremony verify <transcript.json> <contributions.json>
+//     re-derives the public key from the contributions and checks it matches the transcript
+//
+// commitments.json and contributions.json are JSON arrays of hex strings, in the same order.
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::ceremony::{
+    combine_contributions, verify_transcript, CeremonyTranscript, Commitment, Contribution,
+    CONTRIBUTION_LEN,
+};
+use secrecy::ExposeSecret;
+use serde_json::json;
+use std::error::Error;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "usage:
+  pp-ceremony contribute
+  pp-ceremony combine <info> <commitments.json> <contributions.json>
+  pp-ceremony verify <transcript.json> <contributions.json>";
+
+fn read_hex_array<const N: usize>(path: &str) -> Result<Vec<Zeroizing<[u8; N]>>, Box<dyn Error>> {
+    let contents = Zeroizing::new(std::fs::read_to_string(path)?);
+    let hex_strings: Vec<String> = serde_json::from_str(&contents)?;
+    hex_strings
+        .iter()
+        .map(|hex_string| {
+            let mut bytes = Zeroizing::new([0u8; N]);
+            hex::decode_to_slice(hex_string, bytes.as_mut())?;
+            Ok(bytes)
+        })
+        .collect()
+}
+
+fn read_contributions(path: &str) -> Result<Vec<Contribution>, Box<dyn Error>> {
+    Ok(read_hex_array::<CONTRIBUTION_LEN>(path)?
+        .iter()
+        .map(|bytes| Contribution::from_bytes(**bytes))
+        .collect())
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args {
+        [command] if command == "contribute" => {
+            let contribution = Contribution::generate();
+            let out = Zeroizing::new(
+                json!({
+                    "contribution": hex::encode(contribution.expose()),
+                    "commitment": hex::encode(contribution.commitment()),
+                })
+                .to_string(),
+            );
+            println!("{}", *out);
+        }
+        [command, info, commitments_path, contributions_path] if command == "combine" => {
+            let commitments: Vec<Commitment> = read_hex_array::<32>(commitments_path)?
+                .iter()
+                .map(|bytes| **bytes)
+                .collect();
+            let contributions = read_contributions(contributions_path)?;
+            let (keypair, transcript) =
+                combine_contributions(info.as_bytes(), &commitments, &contributions)?;
+            let out = Zeroizing::new(
+                json!({
+                    "pk": URL_SAFE.encode(&keypair.public_key),
+                    "sk": URL_SAFE.encode(keypair.secret_key.expose_secret()),
+                    "transcript": transcript,
+                })
+                .to_string(),
+            );
+            println!("{}", *out);
+        }
+        [command, transcript_path, contributions_path] if command == "verify" => {
+            let transcript: CeremonyTranscript =
+                serde_json::from_str(&std::fs::read_to_string(transcript_path)?)?;
+            let contributions = read_contributions(contributions_path)?;
+            verify_transcript(&transcript, &contributions)?;
+            println!("ok: public key {}", URL_SAFE.encode(&transcript.public_key));
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
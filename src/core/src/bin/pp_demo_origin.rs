@@ -0,0 +1,122 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  demo origin  -----------------------------------
+// -----------------------------------------------------------------------------
+//
+// pp-demo-origin <listen addr> <secret key file> [options]
+//     reference origin, as an end-to-end smoke target for the Crystal and WebAssembly sides,
+//     redeeming batched ristretto255 tokens of the (base64) secret key in the key file, e.g.
+//     pp-demo-origin 127.0.0.1:8081 sk.txt
+//
+// routes:
+//   GET /           what to request
+//   GET /protected  challenges clients with a WWW-Authenticate: PrivateToken header until
+//                   they send an unspent token (see the tower_origin module)
+//   GET /stats      number of tokens redeemed so far, as JSON
+//
+// options:
+//   --issuer-name <name>  issuer name of the challenge, privacy-pass-issuer.kagi.com by
+//                         default
+//   --origin <name>       origin info of the challenge, repeatable,
+//                         privacy-pass-origin.kagi.com by default
+//
+// The defaults are those of `gen_token_challenge`, so tokens of the FFI and WebAssembly
+// flows are accepted as they are. Spent nonces are kept in memory, a restarted demo origin
+// accepts them again.
+
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::batched_memory_stores::{MemoryKeyStoreRistretto255, MemoryNonceStore};
+use kagippcore::origin::{Origin, OriginConfig};
+use kagippcore::server::{DEFAULT_ISSUER_NAME, DEFAULT_ORIGIN_INFO};
+use kagippcore::tower_origin::PrivateTokenLayer;
+use privacypass::batched_tokens_ristretto255::server::{serialize_public_key, Server};
+use std::error::Error;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "usage:
+  pp-demo-origin <listen addr> <secret key file> [--issuer-name <name>] [--origin <name>]...";
+
+struct Args {
+    listen_addr: String,
+    secret_key: Zeroizing<Vec<u8>>,
+    issuer_name: String,
+    origin_info: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, Box<dyn Error>> {
+    let [listen_addr, secret_key_path, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+    let encoded = Zeroizing::new(std::fs::read_to_string(secret_key_path)?);
+    let mut parsed = Args {
+        listen_addr: listen_addr.to_string(),
+        secret_key: Zeroizing::new(URL_SAFE.decode(encoded.trim())?),
+        issuer_name: DEFAULT_ISSUER_NAME.to_string(),
+        origin_info: vec![],
+    };
+    for option in options.chunks(2) {
+        match option {
+            [name, issuer_name] if name == "--issuer-name" => {
+                parsed.issuer_name = issuer_name.to_string()
+            }
+            [name, origin] if name == "--origin" => parsed.origin_info.push(origin.to_string()),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    if parsed.origin_info.is_empty() {
+        parsed.origin_info.push(DEFAULT_ORIGIN_INFO.to_string());
+    }
+    Ok(parsed)
+}
+
+async fn stats(State(nonce_store): State<Arc<MemoryNonceStore>>) -> String {
+    format!("{{\"redeemed\":{}}}\n", nonce_store.stats().entries)
+}
+
+async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = parse_args(args)?;
+    let key_store = Arc::new(MemoryKeyStoreRistretto255::default());
+    let public_key = Server::new()
+        .set_key(&*key_store, args.secret_key.as_slice())
+        .await?;
+    let nonce_store = Arc::new(MemoryNonceStore::default());
+    let origin = Origin::new(
+        OriginConfig {
+            issuer_name: args.issuer_name,
+            origin_info: args.origin_info,
+            token_key: serialize_public_key(public_key).to_vec(),
+        },
+        key_store,
+        nonce_store.clone(),
+    )?;
+
+    let app = Router::new()
+        .route("/protected", get(|| async { "token redeemed\n" }))
+        .route_layer(PrivateTokenLayer::new(Arc::new(origin)))
+        .route(
+            "/",
+            get(|| async { "GET /protected with an Authorization: PrivateToken header\n" }),
+        )
+        .route("/stats", get(stats))
+        .with_state(nonce_store);
+    let listener = tokio::net::TcpListener::bind(&args.listen_addr).await?;
+    eprintln!(
+        "redeeming tokens of {} on {}",
+        URL_SAFE.encode(serialize_public_key(public_key)),
+        args.listen_addr
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,103 @@
+// -----------------------------------------------------------------------------
+// -------------------------  standalone HTTP issuer  --------------------------
+// -----------------------------------------------------------------------------
+//
+// pp-issuer <listen addr> <issuer request uri> [options]
+//     serves the issuer directory and the token request endpoint of the issuer request URI
+//     (see the axum_issuer module), e.g.
+//     pp-issuer 0.0.0.0:8080 https://issuer.example/token-request --rotate-every 604800
+//
+// options:
+//   --key-file <path>         keeps keys in an encrypted key file (file-key-store feature),
+//                             created if missing, the passphrase being read from
+//                             PP_ISSUER_KEY_PASSPHRASE. Keys are kept in memory otherwise.
+//   --rotate-every <seconds>  rotates keys on schedule, never by default
+//   --grace-period <seconds>  time previous keys stay listed after a rotation, one day by
+//                             default
+//   --previous-keys <n>       previous keys kept, 1 by default
+//   --max-tokens <n>          tokens a single request may ask for, 100 by default
+//   --transparency-log <path> keeps the transparency log served at
+//                             /.well-known/private-token-transparency-log in a file,
+//                             created if missing. It is kept in memory otherwise.
+
+use kagippcore::axum_issuer::{router, Issuer, IssuerConfig, IssuerKeyStore, MemoryIssuerKeyStore};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "usage:
+  pp-issuer <listen addr> <issuer request uri> [--key-file <path>] [--rotate-every <seconds>]
+            [--grace-period <seconds>] [--previous-keys <n>] [--max-tokens <n>]
+            [--transparency-log <path>]";
+
+#[cfg(feature = "file-key-store")]
+fn open_key_file(path: &str) -> Result<Box<dyn IssuerKeyStore>, Box<dyn Error>> {
+    use kagippcore::file_key_store::{FileKeyStore, KdfParams};
+    use zeroize::Zeroizing;
+
+    let passphrase = Zeroizing::new(std::env::var("PP_ISSUER_KEY_PASSPHRASE")?);
+    let key_store = match std::path::Path::new(path).exists() {
+        true => FileKeyStore::open(path, passphrase.as_bytes())?,
+        false => FileKeyStore::create(path, passphrase.as_bytes(), KdfParams::default())?,
+    };
+    Ok(Box::new(std::sync::Mutex::new(key_store)))
+}
+
+#[cfg(not(feature = "file-key-store"))]
+fn open_key_file(_path: &str) -> Result<Box<dyn IssuerKeyStore>, Box<dyn Error>> {
+    Err("--key-file needs pp-issuer to be built with the file-key-store feature".into())
+}
+
+fn parse_args(
+    args: &[String],
+) -> Result<(String, IssuerConfig, Box<dyn IssuerKeyStore>), Box<dyn Error>> {
+    let [listen_addr, issuer_request_uri, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+    let mut config = IssuerConfig::new(issuer_request_uri);
+    let mut key_store: Box<dyn IssuerKeyStore> = Box::new(MemoryIssuerKeyStore::default());
+    for option in options.chunks(2) {
+        match option {
+            [name, path] if name == "--key-file" => key_store = open_key_file(path)?,
+            [name, seconds] if name == "--rotate-every" => {
+                config.key_manager.rotation_interval = Some(Duration::from_secs(seconds.parse()?));
+            }
+            [name, seconds] if name == "--grace-period" => {
+                config.key_manager.grace_period = Duration::from_secs(seconds.parse()?);
+            }
+            [name, n] if name == "--previous-keys" => {
+                config.key_manager.max_previous_keys = n.parse()?
+            }
+            [name, n] if name == "--max-tokens" => config.max_tokens = n.parse()?,
+            [name, path] if name == "--transparency-log" => {
+                config.transparency_log_file = Some(path.into());
+            }
+            _ => return Err(USAGE.into()),
+        }
+    }
+    Ok((listen_addr.to_string(), config, key_store))
+}
+
+async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (listen_addr, config, key_store) = parse_args(args)?;
+    let issuer = Arc::new(Issuer::new(config, key_store)?);
+    let _rotation = issuer.spawn_rotation();
+    let app = router(Arc::clone(&issuer))?;
+    let listener = tokio::net::TcpListener::bind(&listen_addr).await?;
+    eprintln!(
+        "issuing for {} on {}",
+        issuer.config().issuer_request_uri,
+        listen_addr
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,152 @@
+// -----------------------------------------------------------------------------
+// -------------------------  key and token CLI  -------------------------------
+// -----------------------------------------------------------------------------
+//
+// privacypass-cli gen-keys [<info>]
+//     generates an issuer keypair, printing the public key then the secret key
+// privacypass-cli gen-challenge [<issuer name> <origin>...]
+//     prints a TokenChallenge, Kagi's by default
+// privacypass-cli issue <secret key file> [<max tokens>]
+//     reads a TokenRequest from stdin and prints its TokenResponse
+// privacypass-cli validate <secret key file> [<token challenge>]
+//     reads a token from stdin, prints "valid" or "invalid" (exiting with 2) depending on
+//     whether it was issued with the secret key, for the token challenge if given
+// privacypass-cli directory <issuer request uri>
+//     reads public keys from stdin, one per line and preferred key first, and prints the
+//     issuer directory JSON
+//
+// Keys, challenges, token requests, token responses and tokens are read and printed in
+// base64, as the FFI functions take and return them. Secret keys are read from files so
+// that they don't show up in process listings. Only batched ristretto255 keys and tokens
+// are supported.
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::issuer_directory::IssuerDirectory;
+use kagippcore::server::PrivacyPass;
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::batched_tokens_ristretto255::{TokenRequest, TokenResponse};
+use privacypass::TokenType;
+use secrecy::ExposeSecret;
+use std::error::Error;
+use std::io::Read;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use zeroize::Zeroizing;
+
+const USAGE: &str = "usage:
+  privacypass-cli gen-keys [<info>]
+  privacypass-cli gen-challenge [<issuer name> <origin>...]
+  privacypass-cli issue <secret key file> [<max tokens>]
+  privacypass-cli validate <secret key file> [<token challenge>]
+  privacypass-cli directory <issuer request uri>";
+
+const DEFAULT_MAX_TOKENS: usize = 100;
+
+fn read_stdin() -> Result<String, Box<dyn Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    Ok(input)
+}
+
+fn decode_stdin() -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(URL_SAFE.decode(read_stdin()?.trim())?)
+}
+
+fn read_secret_key(path: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let encoded = Zeroizing::new(std::fs::read_to_string(path)?);
+    Ok(Zeroizing::new(URL_SAFE.decode(encoded.trim())?))
+}
+
+async fn run(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    match args {
+        [command, info @ ..] if command == "gen-keys" && info.len() <= 1 => {
+            let privacy_pass = match info {
+                [info] => PrivacyPass::with_info(info.as_bytes()),
+                _ => PrivacyPass::new(),
+            };
+            let keypair = privacy_pass.gen_keys().await?;
+            let out = Zeroizing::new(format!(
+                "{}\n{}",
+                URL_SAFE.encode(&keypair.public_key),
+                URL_SAFE.encode(keypair.secret_key.expose_secret())
+            ));
+            println!("{}", *out);
+        }
+        [command] if command == "gen-challenge" => {
+            println!("{}", PrivacyPass::gen_token_challenge().to_base64()?);
+        }
+        [command, issuer_name, origins @ ..] if command == "gen-challenge" => {
+            let token_challenge = TokenChallenge::new(
+                TokenType::BatchedTokenRistretto255,
+                issuer_name,
+                None, /* redemption_context */
+                origins,
+            );
+            println!("{}", token_challenge.to_base64()?);
+        }
+        [command, secret_key_path, max_tokens @ ..]
+            if command == "issue" && max_tokens.len() <= 1 =>
+        {
+            let max_tokens = match max_tokens {
+                [max_tokens] => max_tokens.parse()?,
+                _ => DEFAULT_MAX_TOKENS,
+            };
+            let secret_key = read_secret_key(secret_key_path)?;
+            let token_request = TokenRequest::tls_deserialize(&mut decode_stdin()?.as_slice())?;
+            let token_response: TokenResponse = PrivacyPass::new()
+                .gen_token_response(&secret_key, token_request, max_tokens)
+                .await?;
+            println!(
+                "{}",
+                URL_SAFE.encode(token_response.tls_serialize_detached()?)
+            );
+        }
+        [command, secret_key_path, token_challenge @ ..]
+            if command == "validate" && token_challenge.len() <= 1 =>
+        {
+            let secret_key = read_secret_key(secret_key_path)?;
+            let token = decode_stdin()?;
+            let valid = PrivacyPass::new()
+                .redeem_token(
+                    &token,
+                    &secret_key,
+                    token_challenge.first().map(String::as_str),
+                )
+                .await?;
+            println!("{}", if valid { "valid" } else { "invalid" });
+            return Ok(valid);
+        }
+        [command, issuer_request_uri] if command == "directory" => {
+            let public_keys = read_stdin()?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| URL_SAFE.decode(line))
+                .collect::<Result<Vec<_>, _>>()?;
+            let directory = IssuerDirectory::from_public_keys(
+                issuer_request_uri,
+                public_keys.iter().map(|public_key| {
+                    (
+                        TokenType::BatchedTokenRistretto255 as u16,
+                        public_key.as_slice(),
+                    )
+                }),
+            )?;
+            println!("{}", serde_json::to_string(&directory)?);
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(true)
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args).await {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(2),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+// -----------------------------------------------------------------------------
+// ------------------------  Bloom filter nonce store  -------------------------
+// -----------------------------------------------------------------------------
+//
+// Remembering every redeemed nonce exactly costs 32 bytes (plus map overhead) per token,
+// which adds up for high-volume issuers. `BloomNonceStore` records nonces in Bloom filters
+// instead, sized for a number of nonces per epoch and a false positive rate, i.e. about
+// 1.44 * log2(1 / rate) bits per nonce, up to `MAX_FILTER_BITS` per filter. A false positive
+// refuses a valid token as already spent, it never accepts a spent one.
+// Filters are only allocated on their first insertion, and freed when cleared, so that a store
+// only takes memory for the epochs it records nonces in.
+// Filters rotate by epoch: nonces go to the filter of the current epoch, and are looked up
+// in it and in the filter of the previous epoch, so a nonce is remembered for one to two
+// epochs. Epochs must therefore exceed how long tokens are accepted for.
+// Filter positions are derived from a random per-store key, so that clients can't craft
+// nonces colliding with the tokens of others.
+
+use crate::batched_memory_stores::AtomicNonceStore;
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use crate::nonce_store::NonceStore;
+use crate::replay::set_shared_nonce_store;
+use async_trait::async_trait;
+use privacypass::Nonce;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Largest filter size (1 GiB), past which parameters are refused rather than allocated
+pub const MAX_FILTER_BITS: usize = 1 << 33;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BloomParamsError {
+    #[error("expected number of nonces per epoch must be positive")]
+    NoExpectedNonces,
+    #[error("false positive rate must be within (0, 1), got {0}")]
+    InvalidFalsePositiveRate(f64),
+    #[error("epoch must last at least a second")]
+    EpochTooShort,
+    #[error("a filter of {0} bits exceeds the maximum of {MAX_FILTER_BITS} bits")]
+    TooLarge(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BloomParams {
+    /// nonces expected to be redeemed per epoch, past which the false positive rate degrades
+    pub expected_nonces: usize,
+    /// probability of refusing a fresh nonce, with `expected_nonces` nonces recorded
+    pub false_positive_rate: f64,
+    /// how long each filter records nonces for, see the module comment
+    pub epoch: Duration,
+}
+
+struct BloomFilter {
+    /// empty until the first insertion, `words` long after
+    bits: Vec<u64>,
+    words: usize,
+    insertions: usize,
+}
+
+impl BloomFilter {
+    fn new(words: usize) -> Self {
+        BloomFilter {
+            bits: Vec::new(),
+            words,
+            insertions: 0,
+        }
+    }
+
+    fn contains(&self, positions: &[usize]) -> bool {
+        !self.bits.is_empty()
+            && positions.iter().all(|&position| {
+                self.bits
+                    .get(position / 64)
+                    .is_some_and(|word| word & (1 << (position % 64)) != 0)
+            })
+    }
+
+    fn insert(&mut self, positions: &[usize]) {
+        if self.bits.is_empty() {
+            self.bits = vec![0; self.words];
+        }
+        for &position in positions {
+            if let Some(word) = self.bits.get_mut(position / 64) {
+                *word |= 1 << (position % 64);
+            }
+        }
+        self.insertions += 1;
+    }
+
+    fn clear(&mut self) {
+        self.bits = Vec::new();
+        self.insertions = 0;
+    }
+}
+
+struct Filters {
+    epoch: u64,
+    current: BloomFilter,
+    previous: BloomFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BloomStats {
+    /// bits per filter, two filters are kept
+    pub bits: usize,
+    /// filter positions set per nonce
+    pub hashes: usize,
+    /// nonces recorded in the current and previous epochs
+    pub current_nonces: usize,
+    pub previous_nonces: usize,
+}
+
+pub struct BloomNonceStore {
+    filters: Mutex<Filters>,
+    key: Zeroizing<[u8; 32]>,
+    bits: usize,
+    hashes: usize,
+    epoch_seconds: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl BloomNonceStore {
+    pub fn new(params: BloomParams) -> Result<Self, BloomParamsError> {
+        Self::with_clock(params, global_clock())
+    }
+
+    pub fn with_clock(
+        params: BloomParams,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, BloomParamsError> {
+        if params.expected_nonces == 0 {
+            return Err(BloomParamsError::NoExpectedNonces);
+        }
+        let rate = params.false_positive_rate;
+        if !(rate > 0.0 && rate < 1.0) {
+            return Err(BloomParamsError::InvalidFalsePositiveRate(rate));
+        }
+        let epoch_seconds = params.epoch.as_secs();
+        if epoch_seconds == 0 {
+            return Err(BloomParamsError::EpochTooShort);
+        }
+
+        // optimal sizes: m = -n ln(p) / ln(2)^2 bits and k = m / n ln(2) hashes
+        let ln2 = std::f64::consts::LN_2;
+        let ideal_bits = -(params.expected_nonces as f64) * rate.ln() / (ln2 * ln2);
+        if ideal_bits > MAX_FILTER_BITS as f64 {
+            return Err(BloomParamsError::TooLarge(ideal_bits));
+        }
+        let words = (ideal_bits.ceil() as usize).div_ceil(64).max(1);
+        let bits = words * 64;
+        let hashes = ((bits as f64 / params.expected_nonces as f64) * ln2)
+            .round()
+            .max(1.0) as usize;
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        let epoch = clock.unix_seconds() / epoch_seconds;
+        Ok(BloomNonceStore {
+            filters: Mutex::new(Filters {
+                epoch,
+                current: BloomFilter::new(words),
+                previous: BloomFilter::new(words),
+            }),
+            key,
+            bits,
+            hashes,
+            epoch_seconds,
+            clock,
+        })
+    }
+
+    pub fn stats(&self) -> BloomStats {
+        let filters = self.rotated_filters();
+        BloomStats {
+            bits: self.bits,
+            hashes: self.hashes,
+            current_nonces: filters.current.insertions,
+            previous_nonces: filters.previous.insertions,
+        }
+    }
+
+    /// Inserts `nonce` unless (probably) already present, returning whether it was inserted
+    pub fn insert_if_absent(&self, nonce: &Nonce) -> bool {
+        let positions = self.positions(nonce);
+        let mut filters = self.rotated_filters();
+        if filters.current.contains(&positions) || filters.previous.contains(&positions) {
+            return false;
+        }
+        filters.current.insert(&positions);
+        true
+    }
+
+    /// Filter positions of `nonce`, by double hashing a keyed digest of it
+    fn positions(&self, nonce: &Nonce) -> Vec<usize> {
+        let digest = Sha256::new()
+            .chain_update(self.key.as_slice())
+            .chain_update(nonce)
+            .finalize();
+        let h1 = digest
+            .first_chunk::<8>()
+            .map_or(0, |h1| u64::from_le_bytes(*h1));
+        // odd, so that successive positions don't cycle early
+        let h2 = digest
+            .get(8..)
+            .and_then(|rest| rest.first_chunk::<8>())
+            .map_or(0, |h2| u64::from_le_bytes(*h2))
+            | 1;
+        (0..self.hashes as u64)
+            .map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % self.bits as u64) as usize)
+            .collect()
+    }
+
+    /// Forgets the nonces of the previous epoch once they are all older than `max_age_seconds`
+    /// at `now`, returning how many. Nonces of the current epoch are only forgotten by rotation.
+    pub fn prune_older_than(&self, max_age_seconds: u64, now: u64) -> usize {
+        let mut filters = self.rotated_filters();
+        // the previous epoch ended as the current one started
+        let previous_end = filters.epoch.saturating_mul(self.epoch_seconds);
+        if previous_end > now.saturating_sub(max_age_seconds) {
+            return 0;
+        }
+        let pruned = filters.previous.insertions;
+        filters.previous.clear();
+        pruned
+    }
+
+    /// Locks the filters, first rotating them if epochs went by since the last access
+    fn rotated_filters(&self) -> std::sync::MutexGuard<'_, Filters> {
+        // a poisoned lock still holds valid filters, bits only ever being set or cleared
+        let mut filters = self.filters.lock().unwrap_or_else(|err| err.into_inner());
+        let epoch = self.clock.unix_seconds() / self.epoch_seconds;
+        match epoch.saturating_sub(filters.epoch) {
+            0 => {}
+            1 => {
+                let filters = &mut *filters;
+                std::mem::swap(&mut filters.current, &mut filters.previous);
+                filters.current.clear();
+            }
+            _ => {
+                filters.current.clear();
+                filters.previous.clear();
+            }
+        }
+        // a clock going backwards doesn't rotate filters back
+        filters.epoch = filters.epoch.max(epoch);
+        filters
+    }
+}
+
+#[async_trait]
+impl NonceStore for BloomNonceStore {
+    async fn exists(&self, nonce: &Nonce) -> bool {
+        let positions = self.positions(nonce);
+        let filters = self.rotated_filters();
+        filters.current.contains(&positions) || filters.previous.contains(&positions)
+    }
+
+    async fn insert(&self, nonce: Nonce) {
+        let positions = self.positions(&nonce);
+        self.rotated_filters().current.insert(&positions);
+    }
+}
+
+#[async_trait]
+impl AtomicNonceStore for BloomNonceStore {
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        BloomNonceStore::insert_if_absent(self, &nonce)
+    }
+}
+
+/// Makes `validate_token` (and the other redemption FFI calls) record redeemed nonces in a
+/// Bloom filter nonce store, sized for `expected_nonces` per epoch of `epoch_seconds` at
+/// `false_positive_rate`. NOTE: `set_replay_protection` goes back to an exact nonce store.
+#[no_mangle]
+pub extern "C" fn set_bloom_nonce_store(
+    expected_nonces: u32,
+    false_positive_rate: f64,
+    epoch_seconds: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let nonce_store = BloomNonceStore::new(BloomParams {
+            expected_nonces: usize::try_from(expected_nonces)?,
+            false_positive_rate,
+            epoch: Duration::from_secs(epoch_seconds.into()),
+        })?;
+        set_shared_nonce_store(Arc::new(nonce_store));
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn nonce(i: u64) -> Nonce {
+        let mut nonce = [0u8; 32];
+        nonce[..8].copy_from_slice(&i.to_be_bytes());
+        nonce
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let params = BloomParams {
+            expected_nonces: 10_000,
+            false_positive_rate: 0.01,
+            epoch: Duration::from_secs(3600),
+        };
+        let nonce_store = BloomNonceStore::new(params).unwrap();
+        for i in 0..10_000 {
+            nonce_store.insert_if_absent(&nonce(i));
+        }
+        for i in 0..10_000 {
+            assert!(!nonce_store.insert_if_absent(&nonce(i)));
+        }
+        let false_positives = (10_000..20_000)
+            .filter(|&i| !nonce_store.insert_if_absent(&nonce(i)))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_filters_rotate_by_epoch() {
+        let clock = Arc::new(MockClock::at_unix_seconds(1_000));
+        let params = BloomParams {
+            expected_nonces: 100,
+            false_positive_rate: 1e-6,
+            epoch: Duration::from_secs(100),
+        };
+        let nonce_store = BloomNonceStore::with_clock(params, clock.clone()).unwrap();
+        assert!(nonce_store.insert_if_absent(&nonce(1)));
+
+        // still remembered during the next epoch
+        clock.advance(Duration::from_secs(100));
+        assert!(!nonce_store.insert_if_absent(&nonce(1)));
+        assert!(nonce_store.insert_if_absent(&nonce(2)));
+        assert_eq!(nonce_store.stats().previous_nonces, 1);
+
+        // forgotten after two
+        clock.advance(Duration::from_secs(100));
+        assert!(nonce_store.insert_if_absent(&nonce(1)));
+        assert!(!nonce_store.insert_if_absent(&nonce(2)));
+        clock.advance(Duration::from_secs(1_000));
+        assert_eq!(nonce_store.stats().current_nonces, 0);
+        assert_eq!(nonce_store.stats().previous_nonces, 0);
+    }
+
+    #[test]
+    fn test_prune_older_than() {
+        let clock = Arc::new(MockClock::at_unix_seconds(1_000));
+        let params = BloomParams {
+            expected_nonces: 1_000,
+            false_positive_rate: 0.01,
+            epoch: Duration::from_secs(100),
+        };
+        let nonce_store = BloomNonceStore::with_clock(params, clock.clone()).unwrap();
+        assert!(nonce_store.insert_if_absent(&nonce(1)));
+        clock.advance(Duration::from_secs(100));
+        assert!(nonce_store.insert_if_absent(&nonce(2)));
+
+        // nonce 1 may have been recorded as late as 1_099
+        assert_eq!(nonce_store.prune_older_than(50, 1_100), 0);
+        assert!(!nonce_store.insert_if_absent(&nonce(1)));
+        assert_eq!(nonce_store.prune_older_than(50, 1_150), 1);
+        assert!(nonce_store.insert_if_absent(&nonce(1)));
+        // the current epoch is kept whatever the age
+        assert_eq!(nonce_store.prune_older_than(0, 1_199), 0);
+        assert!(!nonce_store.insert_if_absent(&nonce(2)));
+    }
+
+    #[test]
+    fn test_filters_allocated_on_first_insertion() {
+        let clock = Arc::new(MockClock::at_unix_seconds(1_000));
+        let params = BloomParams {
+            expected_nonces: 1_000_000,
+            false_positive_rate: 1e-6,
+            epoch: Duration::from_secs(100),
+        };
+        let nonce_store = BloomNonceStore::with_clock(params, clock.clone()).unwrap();
+        let allocated_words = |nonce_store: &BloomNonceStore| {
+            let filters = nonce_store.rotated_filters();
+            (filters.current.bits.len(), filters.previous.bits.len())
+        };
+        assert_eq!(allocated_words(&nonce_store), (0, 0));
+        assert!(nonce_store.insert_if_absent(&nonce(1)));
+        let words = nonce_store.stats().bits / 64;
+        assert_eq!(allocated_words(&nonce_store), (words, 0));
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(allocated_words(&nonce_store), (0, words));
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(allocated_words(&nonce_store), (0, 0));
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let params = BloomParams {
+            expected_nonces: 100,
+            false_positive_rate: 1.0,
+            epoch: Duration::from_secs(100),
+        };
+        assert_eq!(
+            BloomNonceStore::new(params).err(),
+            Some(BloomParamsError::InvalidFalsePositiveRate(1.0))
+        );
+        let params = BloomParams {
+            false_positive_rate: 0.01,
+            epoch: Duration::ZERO,
+            ..params
+        };
+        assert_eq!(
+            BloomNonceStore::new(params).err(),
+            Some(BloomParamsError::EpochTooShort)
+        );
+        let params = BloomParams {
+            expected_nonces: u32::MAX as usize,
+            false_positive_rate: 1e-9,
+            epoch: Duration::from_secs(100),
+        };
+        assert!(matches!(
+            BloomNonceStore::new(params).err(),
+            Some(BloomParamsError::TooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+// -----------------------------------------------------------------------------
+// ---------------------  runtime crypto capabilities  -------------------------
+// -----------------------------------------------------------------------------
+//
+// Reports what the library is actually running on, to help diagnosing
+// "slow on customer hardware" reports.
+
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CryptoCapabilities {
+    pub version: String,
+    pub group: String,
+    pub curve_backend: String,
+    /// false when running on targets where the engine may not preserve constant-time code
+    /// (e.g. wasm, which gets JIT-compiled by the browser)
+    pub constant_time: bool,
+    pub wasm_simd: bool,
+    pub target_arch: String,
+    pub target_os: String,
+    pub target_pointer_width: usize,
+    pub features: Vec<String>,
+}
+
+/// Best guess of the curve25519-dalek backend in use.
+/// On x86_64 dalek picks its AVX2 backend at runtime when the CPU supports it.
+fn curve_backend() -> String {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if std::is_x86_feature_detected!("avx2") {
+            return "simd (avx2)".to_string();
+        }
+    }
+    match cfg!(target_pointer_width = "64") {
+        true => "serial (u64)".to_string(),
+        false => "serial (u32)".to_string(),
+    }
+}
+
+fn enabled_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(feature = "server") {
+        features.push("server".to_string());
+    }
+    if cfg!(feature = "client") {
+        features.push("client".to_string());
+    }
+    if cfg!(feature = "injectable-rng") {
+        features.push("injectable-rng".to_string());
+    }
+    if cfg!(feature = "parallel") {
+        features.push("parallel".to_string());
+    }
+    features
+}
+
+pub fn crypto_capabilities() -> CryptoCapabilities {
+    CryptoCapabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        group: "ristretto255".to_string(),
+        curve_backend: curve_backend(),
+        constant_time: !cfg!(target_arch = "wasm32"),
+        wasm_simd: cfg!(all(target_arch = "wasm32", target_feature = "simd128")),
+        target_arch: std::env::consts::ARCH.to_string(),
+        target_os: std::env::consts::OS.to_string(),
+        target_pointer_width: usize::BITS as usize,
+        features: enabled_features(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_crypto_capabilities() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let capabilities_s = serde_json::to_string(&crypto_capabilities())?;
+
+        let rv = JSONRetVal {
+            retval: capabilities_s,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
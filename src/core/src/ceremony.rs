@@ -0,0 +1,214 @@
+// -----------------------------------------------------------------------------
+// ----------------------  key generation ceremony  ----------------------------
+// -----------------------------------------------------------------------------
+//
+// A commit-then-reveal ceremony for issuer keys, so that no single operator picks the seed.
+// 1. every operator samples a Contribution and publishes only its commitment;
+// 2. once all commitments are collected, operators reveal their contributions, and the seed
+//    is hashed from all of them, in commitment order;
+// 3. the transcript (info, commitments, public key) is published, and every operator holding
+//    the revealed contributions re-derives the public key with `verify_transcript`.
+// Committing first stops the last operator to reveal from grinding the seed, and every
+// operator ends up able to re-derive the key, so no single machine holds its only copy.
+
+use crate::config::VoprfGroup;
+use crate::server::RustKeypair;
+use privacypass::batched_tokens_ristretto255::server::serialize_public_key;
+use privacypass::TokenType;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::SecretBox;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use thiserror::Error;
+use voprf::{derive_key, Mode, VoprfServer};
+use zeroize::Zeroizing;
+
+pub const CONTRIBUTION_LEN: usize = 32;
+pub type Commitment = [u8; 32];
+
+const COMMITMENT_DOMAIN: &[u8] = b"kagipp ceremony commitment";
+const SEED_DOMAIN: &[u8] = b"kagipp ceremony seed";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CeremonyError {
+    #[error("a ceremony needs at least 2 participants, got {0}")]
+    TooFewParticipants(usize),
+    #[error("got {0} contributions for {1} commitments")]
+    CountMismatch(usize, usize),
+    #[error("commitment {0} appears more than once")]
+    DuplicateCommitment(usize),
+    #[error("contribution {0} does not match its commitment")]
+    CommitmentMismatch(usize),
+    #[error("failed to derive key from the ceremony seed")]
+    DeriveKey(voprf::Error),
+    #[error("derived public key does not match the transcript")]
+    PublicKeyMismatch,
+}
+
+/// An operator's secret share of entropy, wiped on drop
+pub struct Contribution(Zeroizing<[u8; CONTRIBUTION_LEN]>);
+
+impl Contribution {
+    pub fn generate() -> Self {
+        let mut contribution = Zeroizing::new([0u8; CONTRIBUTION_LEN]);
+        OsRng.fill_bytes(contribution.as_mut());
+        Contribution(contribution)
+    }
+
+    pub fn from_bytes(bytes: [u8; CONTRIBUTION_LEN]) -> Self {
+        Contribution(Zeroizing::new(bytes))
+    }
+
+    pub fn expose(&self) -> &[u8; CONTRIBUTION_LEN] {
+        &self.0
+    }
+
+    pub fn commitment(&self) -> Commitment {
+        let mut hasher = Sha256::new();
+        hasher.update(COMMITMENT_DOMAIN);
+        hasher.update(self.0.as_slice());
+        hasher.finalize().into()
+    }
+}
+
+/// Public record of a ceremony, enough to re-derive and check its key from the contributions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CeremonyTranscript {
+    #[serde(with = "hex")]
+    pub info: Vec<u8>,
+    pub commitments: Vec<HexCommitment>,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexCommitment(#[serde(with = "hex")] pub Commitment);
+
+fn check_contributions(
+    commitments: &[Commitment],
+    contributions: &[Contribution],
+) -> Result<(), CeremonyError> {
+    if commitments.len() < 2 {
+        return Err(CeremonyError::TooFewParticipants(commitments.len()));
+    }
+    if contributions.len() != commitments.len() {
+        return Err(CeremonyError::CountMismatch(
+            contributions.len(),
+            commitments.len(),
+        ));
+    }
+    let mut seen = BTreeSet::new();
+    for (index, commitment) in commitments.iter().enumerate() {
+        if !seen.insert(commitment) {
+            return Err(CeremonyError::DuplicateCommitment(index));
+        }
+    }
+    for (index, (commitment, contribution)) in commitments.iter().zip(contributions).enumerate() {
+        if contribution.commitment() != *commitment {
+            return Err(CeremonyError::CommitmentMismatch(index));
+        }
+    }
+    Ok(())
+}
+
+fn ceremony_seed(contributions: &[Contribution]) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(SEED_DOMAIN);
+    hasher.update((contributions.len() as u64).to_be_bytes());
+    for contribution in contributions {
+        hasher.update(contribution.expose());
+    }
+    Zeroizing::new(hasher.finalize().into())
+}
+
+/// Derives the ceremony key from all revealed contributions, given in commitment order
+pub fn combine_contributions(
+    info: &[u8],
+    commitments: &[Commitment],
+    contributions: &[Contribution],
+) -> Result<(RustKeypair, CeremonyTranscript), CeremonyError> {
+    check_contributions(commitments, contributions)?;
+    let seed = ceremony_seed(contributions);
+
+    let server = VoprfServer::<VoprfGroup>::new_from_seed(seed.as_slice(), info)
+        .map_err(CeremonyError::DeriveKey)?;
+    let secret_key = derive_key::<VoprfGroup>(seed.as_slice(), info, Mode::Voprf)
+        .map_err(CeremonyError::DeriveKey)?;
+    let public_key = serialize_public_key(server.get_public_key());
+
+    let transcript = CeremonyTranscript {
+        info: info.to_vec(),
+        commitments: commitments.iter().copied().map(HexCommitment).collect(),
+        public_key: public_key.clone(),
+    };
+    let keypair = RustKeypair {
+        public_key,
+        secret_key: SecretBox::init_with(|| secret_key.to_bytes()),
+        token_type: TokenType::BatchedTokenRistretto255,
+        info: info.to_vec(),
+    };
+    Ok((keypair, transcript))
+}
+
+/// Re-derives the ceremony key from the revealed contributions and checks it matches the
+/// published transcript
+pub fn verify_transcript(
+    transcript: &CeremonyTranscript,
+    contributions: &[Contribution],
+) -> Result<(), CeremonyError> {
+    let commitments: Vec<Commitment> = transcript.commitments.iter().map(|c| c.0).collect();
+    let (keypair, _) = combine_contributions(&transcript.info, &commitments, contributions)?;
+    match keypair.public_key == transcript.public_key {
+        true => Ok(()),
+        false => Err(CeremonyError::PublicKeyMismatch),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ceremony_roundtrip() {
+        let contributions = vec![Contribution::generate(), Contribution::generate()];
+        let commitments: Vec<_> = contributions.iter().map(|c| c.commitment()).collect();
+        let (keypair, mut transcript) =
+            combine_contributions(b"PrivacyPass", &commitments, &contributions).unwrap();
+        assert_eq!(keypair.public_key, transcript.public_key);
+        assert_eq!(verify_transcript(&transcript, &contributions), Ok(()));
+
+        transcript.public_key[0] ^= 1;
+        assert_eq!(
+            verify_transcript(&transcript, &contributions),
+            Err(CeremonyError::PublicKeyMismatch)
+        );
+    }
+
+    #[test]
+    fn test_ceremony_rejects_bad_contributions() {
+        let contributions = vec![Contribution::generate(), Contribution::generate()];
+        let commitments: Vec<_> = contributions.iter().map(|c| c.commitment()).collect();
+
+        let swapped = vec![
+            Contribution::from_bytes(*contributions[1].expose()),
+            Contribution::from_bytes(*contributions[0].expose()),
+        ];
+        assert_eq!(
+            combine_contributions(b"PrivacyPass", &commitments, &swapped).err(),
+            Some(CeremonyError::CommitmentMismatch(0))
+        );
+        assert_eq!(
+            combine_contributions(b"PrivacyPass", &commitments[..1], &contributions[..1]).err(),
+            Some(CeremonyError::TooFewParticipants(1))
+        );
+        assert_eq!(
+            combine_contributions(b"PrivacyPass", &[commitments[0]; 2], &contributions).err(),
+            Some(CeremonyError::DuplicateCommitment(1))
+        );
+    }
+}
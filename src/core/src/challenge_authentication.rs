@@ -0,0 +1,360 @@
+// -----------------------------------------------------------------------------
+// ----------------------  challenge authentication  ---------------------------
+// -----------------------------------------------------------------------------
+//
+// Tokens carry the digest of the challenge they answer, and origins validate them against a
+// challenge they have to trust. Origins that don't keep the challenges they issued, and
+// rebuild them from what clients send instead, can have them authenticated: with a local
+// HMAC key set through `set_challenge_mac_key`, `authenticated_token_challenge` issues
+// challenges whose redemption context is
+//   issued_at (8, unix seconds, big endian) || HMAC-SHA256(key, challenge fields)[..24]
+// and every redemption path refuses tokens whose challenge doesn't carry a valid MAC, or
+// that are redeemed without their challenge, with a `ChallengeAuthenticationError`. The MAC covers
+// the token type, issuer name and origin info of the challenge along with issued_at, which
+// is laid out like the timestamp of the challenge freshness module, so that both can be
+// checked together.
+// NOTE: the key authenticates challenges, not tokens: anyone holding it can issue them, so it
+//       belongs with the origin, and origin processes validating each other's challenges
+//       share it.
+
+use crate::challenge_freshness::redemption_context_timestamp;
+use crate::clock::{global_clock, Clock};
+use crate::config::BatchedGroup;
+use crate::crystal::{
+    decode_secret_bytes_from_crystal, decode_string_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use crate::limits::InputKind;
+use crate::server::UnsupportedTokenTypeError;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use hmac::{Hmac, Mac};
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use privacypass::auth::authenticate::{RedemptionContext, TokenChallenge};
+use privacypass::TokenType;
+use secrecy::ExposeSecret;
+use sha2::Sha256;
+use std::sync::RwLock;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shortest challenge MAC key accepted
+pub const MIN_CHALLENGE_MAC_KEY_LEN: usize = 32;
+
+const TIMESTAMP_LEN: usize = 8;
+
+// consulted by the validate_token paths, None when challenges aren't authenticated
+static CHALLENGE_MAC_KEY: RwLock<Option<Zeroizing<Vec<u8>>>> = RwLock::new(None);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ChallengeAuthenticationError {
+    #[error("challenge MAC key too short ({0} < {MIN_CHALLENGE_MAC_KEY_LEN} bytes)")]
+    KeyTooShort(usize),
+    #[error("no challenge MAC key set")]
+    NoKey,
+    #[error("token challenge carries no MAC")]
+    NotAuthenticated,
+    #[error("token challenge MAC is invalid")]
+    InvalidMac,
+}
+
+impl ChallengeAuthenticationError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChallengeAuthenticationError::KeyTooShort(_) => "challenge_mac_key_too_short",
+            ChallengeAuthenticationError::NoKey => "challenge_mac_key_not_set",
+            ChallengeAuthenticationError::NotAuthenticated => "challenge_not_authenticated",
+            ChallengeAuthenticationError::InvalidMac => "challenge_invalid_mac",
+        }
+    }
+}
+
+fn challenge_mac(
+    key: &[u8],
+    token_type: u16,
+    issuer_name: &str,
+    origin_info: &str,
+    issued_at: &[u8],
+) -> Result<HmacSha256, ChallengeAuthenticationError> {
+    if key.len() < MIN_CHALLENGE_MAC_KEY_LEN {
+        return Err(ChallengeAuthenticationError::KeyTooShort(key.len()));
+    }
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|_| ChallengeAuthenticationError::KeyTooShort(key.len()))?;
+    mac.update(&token_type.to_be_bytes());
+    // names are length prefixed, as in the challenge itself
+    for name in [issuer_name, origin_info] {
+        mac.update(&(name.len() as u64).to_be_bytes());
+        mac.update(name.as_bytes());
+    }
+    mac.update(issued_at);
+    Ok(mac)
+}
+
+/// Redemption context authenticating a challenge of `token_type`, `issuer_name` and
+/// `origin_info` issued at `issued_at` (unix seconds) with `key`
+pub fn authenticated_redemption_context(
+    key: &[u8],
+    issued_at: u64,
+    token_type: u16,
+    issuer_name: &str,
+    origin_info: &[String],
+) -> Result<RedemptionContext, ChallengeAuthenticationError> {
+    let issued_at = issued_at.to_be_bytes();
+    let mac = challenge_mac(
+        key,
+        token_type,
+        issuer_name,
+        &origin_info.join(","),
+        &issued_at,
+    )?
+    .finalize()
+    .into_bytes();
+    let mut redemption_context = RedemptionContext::default();
+    let (timestamp, tag) = redemption_context.split_at_mut(TIMESTAMP_LEN);
+    timestamp.copy_from_slice(&issued_at);
+    let mac = mac
+        .get(..tag.len())
+        .ok_or(ChallengeAuthenticationError::InvalidMac)?;
+    tag.copy_from_slice(mac);
+    Ok(redemption_context)
+}
+
+/// Errors out unless the serialized `token_challenge` carries a MAC of its fields with `key`
+pub fn verify_challenge_mac(
+    key: &[u8],
+    token_challenge: &[u8],
+) -> Result<(), ChallengeAuthenticationError> {
+    let token_challenge = kagippverify::TokenChallenge::parse(token_challenge)
+        .map_err(|_| ChallengeAuthenticationError::NotAuthenticated)?;
+    let redemption_context = token_challenge.redemption_context;
+    if redemption_context_timestamp(redemption_context).is_none() {
+        return Err(ChallengeAuthenticationError::NotAuthenticated);
+    }
+    let (issued_at, tag) = redemption_context.split_at(TIMESTAMP_LEN);
+    challenge_mac(
+        key,
+        token_challenge.token_type,
+        token_challenge.issuer_name,
+        &token_challenge.origin_info.join(","),
+        issued_at,
+    )?
+    .verify_truncated_left(tag)
+    .map_err(|_| ChallengeAuthenticationError::InvalidMac)
+}
+
+/// Authenticates challenges with `key` from now on, refusing tokens of other challenges.
+/// NOTE: pass None to stop authenticating challenges, the default
+pub fn set_challenge_mac_key(key: Option<&[u8]>) -> Result<(), ChallengeAuthenticationError> {
+    if let Some(key) = key.filter(|key| key.len() < MIN_CHALLENGE_MAC_KEY_LEN) {
+        return Err(ChallengeAuthenticationError::KeyTooShort(key.len()));
+    }
+    // a poisoned lock still holds a valid key, as it is only ever overwritten whole
+    *CHALLENGE_MAC_KEY
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = key.map(|key| Zeroizing::new(key.to_vec()));
+    Ok(())
+}
+
+fn challenge_mac_key() -> Option<Zeroizing<Vec<u8>>> {
+    CHALLENGE_MAC_KEY
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// Whether a challenge MAC key is set, in which case tokens redeemed without their challenge
+/// are refused as well
+pub fn challenges_authenticated() -> bool {
+    CHALLENGE_MAC_KEY
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .is_some()
+}
+
+/// Challenge of `token_type` for `issuer_name` and `origin_info`, authenticated with the key
+/// of `set_challenge_mac_key` and stamped with the current time
+pub fn authenticated_token_challenge(
+    token_type: TokenType,
+    issuer_name: &str,
+    origin_info: &[String],
+) -> Result<TokenChallenge, ChallengeAuthenticationError> {
+    let key = challenge_mac_key().ok_or(ChallengeAuthenticationError::NoKey)?;
+    let redemption_context = authenticated_redemption_context(
+        &key,
+        global_clock().unix_seconds(),
+        token_type as u16,
+        issuer_name,
+        origin_info,
+    )?;
+    Ok(TokenChallenge::new(
+        token_type,
+        issuer_name,
+        Some(redemption_context),
+        origin_info,
+    ))
+}
+
+/// Errors out if challenges are authenticated and the base64 `token_challenge_s` carries no
+/// valid MAC
+pub fn check_challenge_authentication(
+    token_challenge_s: &str,
+) -> Result<(), ChallengeAuthenticationError> {
+    let Some(key) = challenge_mac_key() else {
+        return Ok(());
+    };
+    let token_challenge = URL_SAFE.decode(token_challenge_s).unwrap_or_default();
+    verify_challenge_mac(&key, &token_challenge)
+}
+
+/// Sets the (base64) HMAC key challenges are authenticated with, at least 32 bytes long, see
+/// `set_challenge_mac_key`.
+/// NOTE: pass an empty key to stop authenticating challenges
+#[no_mangle]
+pub extern "C" fn set_challenge_hmac_key(key_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let key = unsafe { decode_secret_bytes_from_crystal(key_cstr, InputKind::Key)? };
+        let key = Some(key.expose_secret()).filter(|key| !key.is_empty());
+        set_challenge_mac_key(key)?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Token type of a challenge, as passed over FFI: 0 for batched ristretto255 (0x0005), as in
+/// `gen_keys`
+fn challenge_token_type(token_type: u16) -> Result<TokenType, UnsupportedTokenTypeError> {
+    match token_type {
+        0 => Ok(BatchedGroup::default().token_type()),
+        TOKEN_TYPE_PRIVATE_P384 => Ok(TokenType::PrivateToken),
+        TOKEN_TYPE_PUBLIC_RSA => Ok(TokenType::PublicToken),
+        _ => BatchedGroup::from_token_type(token_type)
+            .map(BatchedGroup::token_type)
+            .ok_or(UnsupportedTokenTypeError(token_type)),
+    }
+}
+
+/// Like `gen_token_challenge`, for a challenge of `token_type` (as for `gen_keys`)
+/// authenticated with the key of `set_challenge_hmac_key`
+#[no_mangle]
+pub extern "C" fn gen_authenticated_token_challenge(
+    token_type: u16,
+    issuer_name_cstr: *const i8,
+    origin_info_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let issuer_name_s = unsafe { decode_string_from_crystal(issuer_name_cstr)? };
+        let origin_info_s = unsafe { decode_string_from_crystal(origin_info_cstr)? };
+        let token_type = challenge_token_type(token_type)?;
+        let token_challenge =
+            authenticated_token_challenge(token_type, &issuer_name_s, &[origin_info_s])?;
+
+        let rv = JSONRetVal {
+            retval: token_challenge.to_base64()?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BatchedP384TokenType, GroupTokenType};
+
+    fn serialized(token_challenge: &TokenChallenge) -> Vec<u8> {
+        URL_SAFE
+            .decode(token_challenge.to_base64().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_challenge_mac() {
+        let key = [7u8; 32];
+        let origin_info = ["origin.example".to_string()];
+        let redemption_context = authenticated_redemption_context(
+            &key,
+            1_000,
+            GroupTokenType as u16,
+            "issuer.example",
+            &origin_info,
+        )
+        .unwrap();
+        assert_eq!(
+            redemption_context_timestamp(&redemption_context),
+            Some(1_000)
+        );
+        let challenge = |issuer_name: &str, redemption_context| {
+            serialized(&TokenChallenge::new(
+                GroupTokenType,
+                issuer_name,
+                redemption_context,
+                &origin_info,
+            ))
+        };
+
+        let authenticated = challenge("issuer.example", Some(redemption_context));
+        assert!(verify_challenge_mac(&key, &authenticated).is_ok());
+        assert_eq!(
+            verify_challenge_mac(&[8u8; 32], &authenticated),
+            Err(ChallengeAuthenticationError::InvalidMac)
+        );
+        // the MAC covers the challenge fields and the timestamp
+        assert_eq!(
+            verify_challenge_mac(&key, &challenge("other.example", Some(redemption_context))),
+            Err(ChallengeAuthenticationError::InvalidMac)
+        );
+        assert_eq!(
+            verify_challenge_mac(
+                &key,
+                &serialized(&TokenChallenge::new(
+                    BatchedP384TokenType,
+                    "issuer.example",
+                    Some(redemption_context),
+                    &origin_info,
+                ))
+            ),
+            Err(ChallengeAuthenticationError::InvalidMac)
+        );
+        let mut restamped = redemption_context;
+        restamped[7] ^= 1;
+        assert_eq!(
+            verify_challenge_mac(&key, &challenge("issuer.example", Some(restamped))),
+            Err(ChallengeAuthenticationError::InvalidMac)
+        );
+        assert_eq!(
+            verify_challenge_mac(&key, &challenge("issuer.example", None)),
+            Err(ChallengeAuthenticationError::NotAuthenticated)
+        );
+        assert_eq!(
+            authenticated_redemption_context(&key[..16], 0, 0, "", &[]),
+            Err(ChallengeAuthenticationError::KeyTooShort(16))
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// -----------------------------------------------------------------------------
+// ------------------------  challenge freshness  ------------------------------
+// -----------------------------------------------------------------------------
+//
+// Tokens can be hoarded: one issued for a challenge stays redeemable for as long as its key
+// is. Origins limiting this issue challenges whose redemption context carries the time they
+// were issued at, and set a maximum challenge age with `set_challenge_max_age`. The
+// validate_token FFI functions and `PrivacyPass::redeem_token` then refuse tokens whose
+// challenge is older than that, or carries no timestamp, with a `ChallengeFreshnessError`
+// whose error code (challenge_stale or challenge_not_timestamped) tells both apart.
+// Timestamped redemption contexts are laid out as
+//   issued_at (8, unix seconds, big endian) || random (24)
+// so that two challenges issued within the same second still differ.
+// NOTE: the timestamp is only as trustworthy as the challenge validated against: origins
+//       rebuilding the challenge from what the client sent should authenticate it too.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use privacypass::auth::authenticate::RedemptionContext;
+use rand::{rngs::OsRng, RngCore};
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+const TIMESTAMP_LEN: usize = 8;
+
+// maximum challenge age in seconds, 0 when challenges aren't checked for freshness
+static CHALLENGE_MAX_AGE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ChallengeFreshnessError {
+    #[error("token challenge carries no issuance timestamp")]
+    NotTimestamped,
+    #[error("token challenge issued at {issued_at} is older than {max_age} seconds")]
+    Stale { issued_at: u64, max_age: u64 },
+}
+
+impl ChallengeFreshnessError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChallengeFreshnessError::NotTimestamped => "challenge_not_timestamped",
+            ChallengeFreshnessError::Stale { .. } => "challenge_stale",
+        }
+    }
+}
+
+/// Redemption context stamped with `issued_at`, in unix seconds
+pub fn timestamped_redemption_context(issued_at: u64) -> RedemptionContext {
+    let mut redemption_context = RedemptionContext::default();
+    let (timestamp, random) = redemption_context.split_at_mut(TIMESTAMP_LEN);
+    timestamp.copy_from_slice(&issued_at.to_be_bytes());
+    OsRng.fill_bytes(random);
+    redemption_context
+}
+
+/// Unix seconds a timestamped redemption context was issued at, None for an empty one
+pub fn redemption_context_timestamp(redemption_context: &[u8]) -> Option<u64> {
+    let timestamp = redemption_context.get(..TIMESTAMP_LEN)?;
+    Some(u64::from_be_bytes(timestamp.try_into().ok()?))
+}
+
+/// Errors out if the serialized `token_challenge` was issued more than `max_age` seconds away
+/// from `now`, either way, so that clock skew between origin processes can't make a challenge
+/// outlive its window. Malformed challenges are left to the token validation to refuse.
+pub fn check_freshness(
+    token_challenge: &[u8],
+    max_age: u64,
+    now: u64,
+) -> Result<(), ChallengeFreshnessError> {
+    let Ok(token_challenge) = kagippverify::TokenChallenge::parse(token_challenge) else {
+        return Ok(());
+    };
+    let issued_at = redemption_context_timestamp(token_challenge.redemption_context)
+        .ok_or(ChallengeFreshnessError::NotTimestamped)?;
+    if now.abs_diff(issued_at) > max_age {
+        return Err(ChallengeFreshnessError::Stale { issued_at, max_age });
+    }
+    Ok(())
+}
+
+/// Maximum age of the challenges tokens are validated against, refusing older ones.
+/// NOTE: pass 0 to stop checking challenges for freshness, the default
+pub fn set_challenge_max_age(max_age: u64) {
+    CHALLENGE_MAX_AGE.store(max_age, Ordering::Relaxed);
+}
+
+pub fn challenge_max_age() -> Option<u64> {
+    Some(CHALLENGE_MAX_AGE.load(Ordering::Relaxed)).filter(|max_age| *max_age != 0)
+}
+
+/// Errors out if tokens are checked for freshness and the base64 `token_challenge_s` is stale
+pub fn check_challenge_freshness(token_challenge_s: &str) -> Result<(), ChallengeFreshnessError> {
+    let Some(max_age) = challenge_max_age() else {
+        return Ok(());
+    };
+    let token_challenge = URL_SAFE.decode(token_challenge_s).unwrap_or_default();
+    check_freshness(&token_challenge, max_age, global_clock().unix_seconds())
+}
+
+/// Sets the maximum age in seconds of the challenges tokens are validated against, see
+/// `set_challenge_max_age`
+#[no_mangle]
+pub extern "C" fn set_challenge_freshness(max_age: u64) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        set_challenge_max_age(max_age);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Base64 redemption context stamped with the current time, for
+/// `gen_token_challenge_with_context`
+#[no_mangle]
+pub extern "C" fn gen_timestamped_redemption_context() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let redemption_context = timestamped_redemption_context(global_clock().unix_seconds());
+        let rv = JSONRetVal {
+            retval: URL_SAFE.encode(redemption_context),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use privacypass::auth::authenticate::TokenChallenge;
+    use privacypass::TokenType;
+
+    fn serialized_challenge(redemption_context: Option<RedemptionContext>) -> Vec<u8> {
+        let token_challenge = TokenChallenge::new(
+            TokenType::BatchedTokenRistretto255,
+            "issuer.example",
+            redemption_context,
+            &["origin.example".to_string()],
+        );
+        URL_SAFE
+            .decode(token_challenge.to_base64().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_freshness() {
+        let redemption_context = timestamped_redemption_context(1_000);
+        assert_eq!(
+            redemption_context_timestamp(&redemption_context),
+            Some(1_000)
+        );
+        assert_ne!(redemption_context, timestamped_redemption_context(1_000));
+
+        let token_challenge = serialized_challenge(Some(redemption_context));
+        assert!(check_freshness(&token_challenge, 60, 1_000).is_ok());
+        assert!(check_freshness(&token_challenge, 60, 1_060).is_ok());
+        assert!(check_freshness(&token_challenge, 60, 940).is_ok());
+        let stale = check_freshness(&token_challenge, 60, 1_061).unwrap_err();
+        assert_eq!(
+            stale,
+            ChallengeFreshnessError::Stale {
+                issued_at: 1_000,
+                max_age: 60
+            }
+        );
+        assert_eq!(stale.code(), "challenge_stale");
+        assert!(check_freshness(&token_challenge, 60, 939).is_err());
+
+        assert_eq!(
+            check_freshness(&serialized_challenge(None), 60, 1_000),
+            Err(ChallengeFreshnessError::NotTimestamped)
+        );
+    }
+}
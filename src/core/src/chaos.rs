@@ -0,0 +1,411 @@
+// -----------------------------------------------------------------------------
+// ----------------------  chaos / soak test harness  --------------------------
+// -----------------------------------------------------------------------------
+//
+// Only built with the `chaos` feature. Runs sustained issuance and redemption against key and
+// nonce stores injecting latency, failures and restarts, and reports whether any token got
+// redeemed more than once or any installed key went missing.
+// Injected store failures are handled fail-closed: a nonce store that can't answer makes the
+// redemption fail, as accepting it could let a double spend through, and a key store that can't
+// answer fails the issuance or redemption looking the key up.
+// Restarts replace the in-memory state of the stores with what they persisted, as a restarted
+// process would, so the harness checks nothing is lost in between.
+
+use crate::batched_memory_stores::{
+    AtomicNonceStore, MemoryKeyStoreRistretto255, MemoryNonceStore, TokenKeyIdLookup,
+};
+use crate::config::{batched_tokens_mod, GroupTokenType, VoprfGroup};
+use crate::nonce_store::NonceStore;
+use crate::server::redeem_token_concurrently;
+use crate::server::{ValidateTokenError, DEFAULT_KEY_INFO};
+use crate::NONCE_BYTES;
+use async_trait::async_trait;
+use batched_tokens_mod::{
+    client::Client,
+    server::{serialize_public_key, BatchedKeyStore, RedeemTokenError, Server},
+    BatchedToken,
+};
+use generic_array::GenericArray;
+use privacypass::{auth::authenticate::TokenChallenge, Nonce, TruncatedTokenKeyId};
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use voprf::{Group, VoprfServer};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FaultConfig {
+    /// probability of each store operation failing, below 1 as key store inserts are retried
+    /// until they succeed
+    pub failure_rate: f64,
+    /// each store operation is delayed by up to this long
+    pub max_latency: Duration,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            failure_rate: 0.05,
+            max_latency: Duration::from_millis(2),
+        }
+    }
+}
+
+struct FaultInjector {
+    config: FaultConfig,
+    rng: Mutex<StdRng>,
+    injected_failures: AtomicU64,
+}
+
+impl FaultInjector {
+    fn new(config: FaultConfig, seed: u64) -> Self {
+        FaultInjector {
+            config,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            injected_failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Sleeps for a random latency, then returns whether the operation should fail
+    async fn inject(&self) -> bool {
+        let (latency, fail) = {
+            let mut rng = self.rng.lock().unwrap_or_else(|err| err.into_inner());
+            let max_micros = u64::try_from(self.config.max_latency.as_micros()).unwrap_or(u64::MAX);
+            (
+                Duration::from_micros(rng.gen_range(0..=max_micros)),
+                rng.gen_bool(self.config.failure_rate.clamp(0.0, 1.0)),
+            )
+        };
+        tokio::time::sleep(latency).await;
+        if fail {
+            self.injected_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+}
+
+/// Nonce store injecting faults, persisting every recorded nonce before acknowledging it
+pub struct FaultyNonceStore {
+    memory: RwLock<Arc<MemoryNonceStore>>,
+    persisted: Mutex<HashSet<Nonce>>,
+    faults: FaultInjector,
+}
+
+impl FaultyNonceStore {
+    pub fn new(config: FaultConfig, seed: u64) -> Self {
+        FaultyNonceStore {
+            memory: RwLock::new(Arc::new(MemoryNonceStore::default())),
+            persisted: Mutex::new(HashSet::new()),
+            faults: FaultInjector::new(config, seed),
+        }
+    }
+
+    fn memory(&self) -> Arc<MemoryNonceStore> {
+        self.memory
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Drops the in-memory state and reloads it from what was persisted
+    pub fn restart(&self) {
+        let memory = MemoryNonceStore::default();
+        for nonce in self
+            .persisted
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+        {
+            memory.insert_if_absent(*nonce);
+        }
+        *self.memory.write().unwrap_or_else(|err| err.into_inner()) = Arc::new(memory);
+    }
+
+    pub fn injected_failures(&self) -> u64 {
+        self.faults.injected_failures.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl NonceStore for FaultyNonceStore {
+    async fn exists(&self, nonce: &Nonce) -> bool {
+        // fail closed: a store that can't answer reports the nonce as spent
+        self.faults.inject().await || self.memory().exists(nonce).await
+    }
+
+    async fn insert(&self, nonce: Nonce) {
+        AtomicNonceStore::insert_if_absent(self, nonce).await;
+    }
+}
+
+#[async_trait]
+impl AtomicNonceStore for FaultyNonceStore {
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        if self.faults.inject().await {
+            return false;
+        }
+        let mut persisted = self.persisted.lock().unwrap_or_else(|err| err.into_inner());
+        if !self.memory().insert_if_absent(nonce) {
+            return false;
+        }
+        persisted.insert(nonce);
+        true
+    }
+}
+
+/// Key store injecting faults, persisting every installed key
+pub struct FaultyKeyStore {
+    memory: RwLock<Arc<MemoryKeyStoreRistretto255>>,
+    persisted: Mutex<Vec<(TruncatedTokenKeyId, VoprfServer<VoprfGroup>)>>,
+    faults: FaultInjector,
+}
+
+impl FaultyKeyStore {
+    pub fn new(config: FaultConfig, seed: u64) -> Self {
+        FaultyKeyStore {
+            memory: RwLock::new(Arc::new(MemoryKeyStoreRistretto255::default())),
+            persisted: Mutex::new(Vec::new()),
+            faults: FaultInjector::new(config, seed),
+        }
+    }
+
+    fn memory(&self) -> Arc<MemoryKeyStoreRistretto255> {
+        self.memory
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    /// Drops the in-memory state and reloads it from what was persisted
+    pub async fn restart(&self) {
+        let memory = MemoryKeyStoreRistretto255::default();
+        let persisted = self
+            .persisted
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        for (truncated_token_key_id, server) in persisted {
+            memory.insert(truncated_token_key_id, server).await;
+        }
+        *self.memory.write().unwrap_or_else(|err| err.into_inner()) = Arc::new(memory);
+    }
+
+    pub fn injected_failures(&self) -> u64 {
+        self.faults.injected_failures.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl BatchedKeyStore for FaultyKeyStore {
+    async fn insert(
+        &self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+        server: VoprfServer<VoprfGroup>,
+    ) {
+        // an insert can't report failing, so it is retried until the store answers, as dropping
+        // it would lose the key
+        while self.faults.inject().await {}
+        self.persisted
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push((truncated_token_key_id, server.clone()));
+        self.memory().insert(truncated_token_key_id, server).await;
+    }
+
+    async fn get(
+        &self,
+        truncated_token_key_id: &TruncatedTokenKeyId,
+    ) -> Option<VoprfServer<VoprfGroup>> {
+        if self.faults.inject().await {
+            return None;
+        }
+        self.memory().get(truncated_token_key_id).await
+    }
+}
+
+#[async_trait]
+impl TokenKeyIdLookup for FaultyKeyStore {
+    async fn get_by_token_key_id(&self, token_key_id: &[u8]) -> Option<VoprfServer<VoprfGroup>> {
+        if self.faults.inject().await {
+            return None;
+        }
+        self.memory().get_by_token_key_id(token_key_id).await
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SoakConfig {
+    pub rounds: usize,
+    /// tokens issued per round
+    pub tokens_per_round: u16,
+    /// every token is redeemed this many times concurrently
+    pub redemptions_per_token: usize,
+    /// a new key is installed every this many rounds
+    pub rotate_every: usize,
+    /// stores are restarted every this many rounds
+    pub restart_every: usize,
+    pub faults: FaultConfig,
+    pub seed: u64,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        SoakConfig {
+            rounds: 20,
+            tokens_per_round: 8,
+            redemptions_per_token: 3,
+            rotate_every: 5,
+            restart_every: 4,
+            faults: FaultConfig::default(),
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SoakReport {
+    pub issued: u64,
+    /// issuances failed by an injected key store failure
+    pub failed_issuances: u64,
+    pub accepted: u64,
+    pub rejected_double_spends: u64,
+    pub failed: u64,
+    pub injected_failures: u64,
+    pub restarts: u64,
+    /// tokens accepted more than once, must be 0
+    pub double_spends_accepted: u64,
+    /// installed keys missing from the key store, must be 0
+    pub keys_lost: u64,
+}
+
+/// Challenge every token of the harness is issued for and redeemed against
+fn token_challenge() -> TokenChallenge {
+    TokenChallenge::new(
+        GroupTokenType,
+        "chaos.issuer",
+        None,
+        &["chaos.origin".to_string()],
+    )
+}
+
+async fn issue_tokens(
+    key_store: &FaultyKeyStore,
+    public_key: batched_tokens_mod::PublicKey,
+    nr: u16,
+    rng: &mut StdRng,
+) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let token_challenge = token_challenge();
+    let nonces: Vec<Nonce> = (0..nr)
+        .map(|_| {
+            let mut nonce = [0u8; NONCE_BYTES];
+            rng.fill_bytes(&mut nonce);
+            nonce
+        })
+        .collect();
+    let blinds = (0..nr)
+        .map(|_| <VoprfGroup as Group>::Scalar::random(&mut *rng))
+        .collect();
+
+    let client = Client::new(public_key);
+    let (token_request, token_states) =
+        client.issue_token_request_with_params(&token_challenge, nonces, blinds)?;
+    let token_response = Server::new()
+        .issue_token_response(key_store, token_request)
+        .await?;
+    let tokens = client.issue_tokens(&token_response, &token_states)?;
+    Ok(tokens
+        .iter()
+        .map(|token| token.tls_serialize_detached())
+        .collect::<Result<_, _>>()?)
+}
+
+/// Runs sustained issuance and redemption under injected store faults.
+/// Must be called from within a tokio runtime.
+pub async fn run_soak(config: SoakConfig) -> Result<SoakReport, Box<dyn std::error::Error>> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let key_store = Arc::new(FaultyKeyStore::new(config.faults, rng.next_u64()));
+    let nonce_store = Arc::new(FaultyNonceStore::new(config.faults, rng.next_u64()));
+    let server = Server::new();
+    let mut report = SoakReport::default();
+    let mut installed_keys = BTreeSet::new();
+    let mut public_key = None;
+    let token_challenge = token_challenge().to_base64()?;
+
+    for round in 0..config.rounds {
+        if public_key.is_none() || round % config.rotate_every.max(1) == 0 {
+            let mut seed = GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default();
+            rng.fill_bytes(&mut seed);
+            let new_public_key = server
+                .create_keypair_with_params(&*key_store, &seed, DEFAULT_KEY_INFO)
+                .await?;
+            installed_keys.insert(Sha256::digest(serialize_public_key(new_public_key)).to_vec());
+            public_key = Some(new_public_key);
+        }
+        if round > 0 && round % config.restart_every.max(1) == 0 {
+            key_store.restart().await;
+            nonce_store.restart();
+            report.restarts += 1;
+            for token_key_id in &installed_keys {
+                if key_store
+                    .memory()
+                    .get_by_token_key_id(token_key_id)
+                    .await
+                    .is_none()
+                {
+                    report.keys_lost += 1;
+                }
+            }
+        }
+
+        let Some(public_key) = public_key else {
+            continue;
+        };
+        let tokens =
+            match issue_tokens(&key_store, public_key, config.tokens_per_round, &mut rng).await {
+                Ok(tokens) => tokens,
+                Err(_) => {
+                    report.failed_issuances += 1;
+                    continue;
+                }
+            };
+        report.issued += tokens.len() as u64;
+
+        for token in tokens {
+            let redemptions: Vec<_> = (0..config.redemptions_per_token)
+                .map(|_| {
+                    let key_store = key_store.clone();
+                    let nonce_store = nonce_store.clone();
+                    let token = token.clone();
+                    let token_challenge = token_challenge.clone();
+                    tokio::spawn(async move {
+                        let token = BatchedToken::tls_deserialize(&mut token.as_slice())?;
+                        redeem_token_concurrently(
+                            &*key_store,
+                            &*nonce_store,
+                            token,
+                            &token_challenge,
+                        )
+                        .await
+                    })
+                })
+                .collect();
+            let mut accepted = 0;
+            for redemption in redemptions {
+                match redemption.await? {
+                    Ok(()) => accepted += 1,
+                    Err(ValidateTokenError::RedeemToken(RedeemTokenError::DoubleSpending)) => {
+                        report.rejected_double_spends += 1
+                    }
+                    Err(_) => report.failed += 1,
+                }
+            }
+            report.accepted += accepted;
+            report.double_spends_accepted += accepted.saturating_sub(1);
+        }
+    }
+    report.injected_failures = key_store.injected_failures() + nonce_store.injected_failures();
+    Ok(report)
+}
@@ -1,40 +1,54 @@
-#![allow(unreachable_patterns)] // used to catch possible error types not yet defined by dependencies
-
+#![allow(unreachable_patterns)]
+// used to catch possible error types not yet defined by dependencies
 use crate::config::{batched_tokens_mod, VoprfGroup};
 use crate::crystal::{
-    crystal_error, decode_bytes_from_crystal, decode_string_from_crystal,
-    encode_string_for_crystal, error_json_retval, CrystalErrorType, JSONRetVal,
+    crystal_error, decode_string_from_crystal, decode_untrusted_bytes_from_crystal,
+    decode_untrusted_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, Base64Json, CrystalErrorType, JSONRetVal,
 };
+use crate::issuance::first_occurrences;
+use crate::limits::InputKind;
 use crate::NONCE_BYTES;
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use batched_tokens_mod::{
     client::{Client, IssueTokenError, IssueTokenRequestError},
     server::deserialize_public_key,
-    EvaluatedElement, SerializationError, TokenResponse, NS,
+    EvaluatedElement, PublicKey, SerializationError, NE, NS,
 };
-use rand::{rngs::OsRng, RngCore};
+// wire types of the Rust client API, so that consumers can (de)serialize them
+pub use batched_tokens_mod::{BatchedToken, TokenRequest, TokenResponse};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "parallel")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
 use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait, TlsVecU16};
 use tls_codec_derive::{TlsDeserialize, TlsSerialize, TlsSize};
+use zeroize::{Zeroize, Zeroizing};
 
-#[derive(Serialize, Deserialize)]
-struct KeyPair {
-    sk: String,
-    pk: String,
-    token_type: u16,
-    error: String,
-}
 #[derive(Serialize, Deserialize)]
 pub struct JSONTokens {
     pub tokens: Vec<String>,
     pub error: String,
 }
+#[derive(Serialize)]
+struct JSONTokensRef<'a> {
+    tokens: Vec<Base64Json<'a>>,
+    error: &'a str,
+}
 #[derive(Serialize, Deserialize)]
 struct HexNonce(#[serde(with = "hex")] Vec<u8>);
 
 #[derive(Serialize, Deserialize)]
 struct HexBlind(#[serde(with = "hex")] Vec<u8>);
 
+// blinding factors are secret until the tokens are finalized
+impl Drop for HexBlind {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct StateTokenRequestRetval {
     pub token_request: String,
@@ -48,7 +62,7 @@ struct MyTokenReqState {
     blinds_s: Vec<HexBlind>,
 }
 
-use privacypass::auth::authenticate::parse_www_authenticate_header;
+use privacypass::auth::authenticate::{parse_www_authenticate_header, TokenChallenge};
 use voprf::{Error as voprfError, Group};
 
 use http::header::HeaderValue;
@@ -80,94 +94,257 @@ pub fn token_response_nr(bytes: &[u8]) -> Result<usize, CrystalErrorType> {
     }
 }
 
-/// # Safety
-///
-/// Callers must provide a valid NUL terminated string pointer.
-#[no_mangle]
-pub unsafe extern "C" fn gen_token_request(
-    www_authenticate_header_cstr: *const i8,
-    nr: u16,
-) -> *const i8 {
-    // NOTE: the value of result below would not be *const i8
-    //       if the begin_panic_handling and end_panic_handling macros where not there
-    begin_panic_handling!();
-    let result = panic::catch_unwind(|| {
-        let www_authenticate_header_s =
-            unsafe { decode_string_from_crystal(www_authenticate_header_cstr) }?;
-        let header_value: HeaderValue = HeaderValue::from_str(&www_authenticate_header_s)?;
-        let challenges = parse_www_authenticate_header(&header_value)?;
-        match challenges.len() {
-            1 => Ok(()),
-            _ => Err(crystal_error("more than one TokenChallenge in header")), // currently not as planned
-        }?;
-        let challenge = &challenges[0];
+/// Smallest number of tokens worth blinding on a separate worker
+#[cfg(feature = "parallel")]
+const MIN_PARALLEL_CHUNK: usize = 8;
 
-        // parse issuer public key
-        let public_key = match deserialize_public_key(challenge.token_key()) {
+// on wasm, rayon only has workers once `initThreadPool` resolved, so it starts disabled there
+#[cfg(feature = "parallel")]
+static PARALLEL_BLINDING: AtomicBool = AtomicBool::new(!cfg!(target_arch = "wasm32"));
+
+/// Enables or disables splitting blinding and finalization across the rayon thread pool
+#[cfg(feature = "parallel")]
+pub fn set_parallel_blinding(enabled: bool) {
+    PARALLEL_BLINDING.store(enabled, Ordering::Relaxed);
+}
+
+/// Concatenates the blinded elements of TokenRequests built for the same key
+fn merge_token_requests(
+    token_requests: Vec<TokenRequest>,
+) -> Result<TokenRequest, tls_codec::Error> {
+    // TokenRequest = token_type (2) || truncated_token_key_id (1) || blinded_elements<0..2^16-1>
+    let mut header = Vec::new();
+    let mut blinded_elements = Vec::new();
+    for token_request in token_requests {
+        let token_request_bytes = token_request.tls_serialize_detached()?;
+        let chunk_elements = token_request_bytes
+            .get(5..)
+            .ok_or(tls_codec::Error::EndOfStream)?;
+        if header.is_empty() {
+            header.extend_from_slice(
+                token_request_bytes
+                    .get(..3)
+                    .ok_or(tls_codec::Error::EndOfStream)?,
+            );
+        }
+        blinded_elements.extend_from_slice(chunk_elements);
+    }
+    let blinded_elements_len =
+        u16::try_from(blinded_elements.len()).map_err(|_| tls_codec::Error::InvalidVectorLength)?;
+
+    let mut merged_bytes = header;
+    merged_bytes.extend_from_slice(&blinded_elements_len.to_be_bytes());
+    merged_bytes.extend_from_slice(&blinded_elements);
+    TokenRequest::tls_deserialize(&mut merged_bytes.as_slice())
+}
+
+/// Blinds `nonces` with `blinds` through `blind_chunk`, splitting large batches across the
+/// rayon thread pool when the `parallel` feature is enabled. Blinding is deterministic once
+/// nonces and blinding factors are fixed, so the chunk TokenRequests are stitched back into
+/// the same TokenRequest that a single call would have returned.
+/// NOTE: `blind_chunk` gets its own copy of the blinding factors, as privacypass takes them
+/// by value; callers keep theirs in a `Zeroizing`
+fn blind_in_chunks<S, F>(
+    nonces: Vec<[u8; NONCE_BYTES]>,
+    blinds: &[<VoprfGroup as Group>::Scalar],
+    blind_chunk: F,
+) -> Result<(TokenRequest, Vec<S>), IssueTokenRequestError>
+where
+    S: Send,
+    F: Fn(
+            Vec<[u8; NONCE_BYTES]>,
+            Vec<<VoprfGroup as Group>::Scalar>,
+        ) -> Result<(TokenRequest, Vec<S>), IssueTokenRequestError>
+        + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if PARALLEL_BLINDING.load(Ordering::Relaxed)
+        && nonces.len() == blinds.len()
+        && nonces.len() >= 2 * MIN_PARALLEL_CHUNK
+    {
+        use rayon::prelude::*;
+
+        let chunk_len = nonces
+            .len()
+            .div_ceil(rayon::current_num_threads())
+            .max(MIN_PARALLEL_CHUNK);
+        let chunks = nonces
+            .par_chunks(chunk_len)
+            .zip(blinds.par_chunks(chunk_len))
+            .map(|(nonces, blinds)| blind_chunk(nonces.to_vec(), blinds.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut token_requests = Vec::with_capacity(chunks.len());
+        let mut token_states = Vec::with_capacity(nonces.len());
+        for (token_request, chunk_states) in chunks {
+            token_requests.push(token_request);
+            token_states.extend(chunk_states);
+        }
+        // the chunks share token type and key, so merging can't fail on a well formed request
+        let token_request = merge_token_requests(token_requests)
+            .map_err(|_| IssueTokenRequestError::BlindingError)?;
+        return Ok((token_request, token_states));
+    }
+    blind_chunk(nonces, blinds.to_vec())
+}
+
+/// Finalizes each TokenResponse with the states of the elements it answers through
+/// `finalize_chunk`, verifying the proofs and unblinding the responses across the rayon thread
+/// pool when the `parallel` feature is enabled. A TokenResponse carries a single batched DLEQ
+/// proof over all of its elements, so each response is finalized as a whole, and the tokens
+/// are returned in the order of the responses.
+fn finalize_in_chunks<S, F>(
+    chunks: &[(&TokenResponse, &[S])],
+    finalize_chunk: F,
+) -> Result<Vec<BatchedToken>, IssueTokenError>
+where
+    S: Sync,
+    F: Fn(&TokenResponse, &[S]) -> Result<Vec<BatchedToken>, IssueTokenError> + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if PARALLEL_BLINDING.load(Ordering::Relaxed) && chunks.len() > 1 {
+        use rayon::prelude::*;
+
+        let chunks_tokens = chunks
+            .par_iter()
+            .map(|(token_response, token_states)| finalize_chunk(token_response, token_states))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(chunks_tokens.into_iter().flatten().collect());
+    }
+    let mut tokens = Vec::new();
+    for (token_response, token_states) in chunks {
+        tokens.extend(finalize_chunk(token_response, token_states)?);
+    }
+    Ok(tokens)
+}
+
+/// Builds a TokenRequest for `nr` tokens and the client state needed to finalize them,
+/// sampling nonces and blinding factors from `rng`
+fn token_request_with_rng<R: RngCore + CryptoRng>(
+    www_authenticate_header_s: &str,
+    nr: u16,
+    rng: &mut R,
+) -> Result<StateTokenRequestRetval, Box<dyn std::error::Error>> {
+    let header_value: HeaderValue = HeaderValue::from_str(www_authenticate_header_s)?;
+    let challenges = parse_www_authenticate_header(&header_value)?;
+    let challenge = match challenges.as_slice() {
+        [challenge] => Ok(challenge),
+        _ => Err(crystal_error("more than one TokenChallenge in header")), // currently not as planned
+    }?;
+
+    // parse issuer public key
+    let public_key = match deserialize_public_key(challenge.token_key()) {
         Ok(res) => Ok(res),
         Err(err) => match err {
             voprfError::Info => Err(crystal_error("Size of info is longer then [`u16::MAX`]")),
-            voprfError::Input => Err(crystal_error("Size of input is empty or longer then [`u16::MAX`].")),
-            voprfError::DeriveKeyPair => Err(crystal_error("Size of info and seed together are longer then `u16::MAX - 3`.")),
+            voprfError::Input => Err(crystal_error(
+                "Size of input is empty or longer then [`u16::MAX`].",
+            )),
+            voprfError::DeriveKeyPair => Err(crystal_error(
+                "Size of info and seed together are longer then `u16::MAX - 3`.",
+            )),
             voprfError::Deserialization => Err(crystal_error("Failure to deserialize bytes")),
-            voprfError::Batch => Err(crystal_error("Batched items are more then [`u16::MAX`] or length don't match.")),
-            voprfError::ProofVerification => Err(crystal_error("In verifiable mode, occurs when the proof failed to verify")),
-            voprfError::Protocol => Err(crystal_error("The protocol has failed and can't be completed.")),
-            _ => Err(crystal_error("unrecognized voprf::Error, was the the voprf-rust library updated with a new one?"))
-        }}?;
-        let token_challenge = challenge.token_challenge();
-        // let max_age = challenge.max_age(); // currently unused
+            voprfError::Batch => Err(crystal_error(
+                "Batched items are more then [`u16::MAX`] or length don't match.",
+            )),
+            voprfError::ProofVerification => Err(crystal_error(
+                "In verifiable mode, occurs when the proof failed to verify",
+            )),
+            voprfError::Protocol => Err(crystal_error(
+                "The protocol has failed and can't be completed.",
+            )),
+            _ => Err(crystal_error(
+                "unrecognized voprf::Error, was the the voprf-rust library updated with a new one?",
+            )),
+        },
+    }?;
+    let token_challenge = challenge.token_challenge();
+    // let max_age = challenge.max_age(); // currently unused
 
-        let client = Client::new(public_key);
+    let client = Client::new(public_key);
 
-        // generate token nonces
+    // generate token nonces
 
-        let mut nonces = Vec::with_capacity(nr as usize);
+    let mut nonces = Vec::with_capacity(nr as usize);
 
-        for _ in 0..nr {
-            let mut nonce = [0u8; NONCE_BYTES];
-            OsRng.fill_bytes(&mut nonce);
-            nonces.push(nonce);
-        }
+    for _ in 0..nr {
+        let mut nonce = [0u8; NONCE_BYTES];
+        rng.fill_bytes(&mut nonce);
+        nonces.push(nonce);
+    }
 
-        // serialise token nonces
+    // serialise token nonces
 
-        let nonces_s: Vec<_> = nonces
-            .iter()
-            .map(|nonce| HexNonce(nonce.clone().to_vec()))
-            .collect();
+    let nonces_s: Vec<_> = nonces
+        .iter()
+        .map(|nonce| HexNonce(nonce.clone().to_vec()))
+        .collect();
 
-        // generate blinding factors
+    // generate blinding factors
 
-        let blinds = (0..nr)
-            .map(|_| <VoprfGroup as Group>::Scalar::random(&mut OsRng))
-            .collect::<Vec<_>>();
+    let blinds = Zeroizing::new(
+        (0..nr)
+            .map(|_| <VoprfGroup as Group>::Scalar::random(&mut *rng))
+            .collect::<Vec<_>>(),
+    );
 
-        // serialise blinding factors
+    // serialise blinding factors
 
-        let blinds_s = blinds
-            .iter()
-            .map(|blind| HexBlind(blind.to_bytes().to_vec()))
-            .collect::<Vec<_>>();
+    let blinds_s = blinds
+        .iter()
+        .map(|blind| HexBlind(blind.to_bytes().to_vec()))
+        .collect::<Vec<_>>();
 
-        // create a token request corresponding to the challenge, nonces and blinding factors
+    // create a token request corresponding to the challenge, nonces and blinding factors
 
-        let (token_request, _) =
-            client.issue_token_request_with_params(token_challenge, nonces, blinds)?;
+    let (token_request, _) = blind_in_chunks(nonces, &blinds, |nonces, blinds| {
+        client.issue_token_request_with_params(token_challenge, nonces, blinds)
+    })?;
 
-        // serialise token request
+    // serialise token request
 
-        let token_request_byes = token_request.tls_serialize_detached()?;
-        let token_request_s = URL_SAFE.encode(token_request_byes);
+    let token_request_byes = token_request.tls_serialize_detached()?;
+    let token_request_s = URL_SAFE.encode(token_request_byes);
 
-        let state_vector = MyTokenReqState { nonces_s, blinds_s };
-        let state_vector_s = serde_json::to_string_pretty(&state_vector)?;
+    let state_vector = MyTokenReqState { nonces_s, blinds_s };
+    let state_vector_s = serde_json::to_string_pretty(&state_vector)?;
 
-        let state_token_request_rv = StateTokenRequestRetval {
-            token_request: token_request_s,
-            state: state_vector_s,
-            error: "".to_string(),
-        };
+    Ok(StateTokenRequestRetval {
+        token_request: token_request_s,
+        state: state_vector_s,
+        error: "".to_string(),
+    })
+}
+
+/// Like `gen_token_request`, but with nonces and blinding factors sampled from a caller
+/// supplied RNG, so that tests can be seeded and fuzzers can inject edge-case scalars.
+/// NOTE: only for testing, production code must use the OsRng-backed `gen_token_request`
+#[cfg(feature = "injectable-rng")]
+pub fn gen_token_request_with_rng<R: RngCore + CryptoRng>(
+    www_authenticate_header_s: &str,
+    nr: u16,
+    rng: &mut R,
+) -> Result<StateTokenRequestRetval, Box<dyn std::error::Error>> {
+    token_request_with_rng(www_authenticate_header_s, nr, rng)
+}
+
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn gen_token_request(
+    www_authenticate_header_cstr: *const i8,
+    nr: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let www_authenticate_header_s = unsafe {
+            decode_untrusted_string_from_crystal(www_authenticate_header_cstr, InputKind::Header)
+        }?;
+        let state_token_request_rv =
+            token_request_with_rng(&www_authenticate_header_s, nr, &mut OsRng)?;
 
         let state_token_request_rv_s = serde_json::to_string(&state_token_request_rv)?;
         let rv = JSONRetVal {
@@ -175,8 +352,7 @@ pub unsafe extern "C" fn gen_token_request(
             error: "".to_string(),
         };
 
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let out = encode_json_for_crystal(&rv)?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -196,16 +372,18 @@ pub unsafe extern "C" fn gen_token(
     begin_panic_handling!();
     let result = panic::catch_unwind(|| {
         // parse inputs
-        let www_authenticate_header_s =
-            unsafe { decode_string_from_crystal(www_authenticate_header_cstr) }?;
+        let www_authenticate_header_s = unsafe {
+            decode_untrusted_string_from_crystal(www_authenticate_header_cstr, InputKind::Header)
+        }?;
         let header_value: HeaderValue = HeaderValue::from_str(&www_authenticate_header_s)?;
         let challenges = parse_www_authenticate_header(&header_value)?;
-        match challenges.len() {
-            1 => Ok(()),
+        let challenge = match challenges.as_slice() {
+            [challenge] => Ok(challenge),
             _ => Err(crystal_error("more than one TokenChallenge in header")), // currently not as planned
         }?;
-        let challenge = &challenges[0];
-        let token_response_bytes = unsafe { decode_bytes_from_crystal(token_response_cstr) }?;
+        let token_response_bytes = unsafe {
+            decode_untrusted_bytes_from_crystal(token_response_cstr, InputKind::TokenResponse)
+        }?;
         let client_state_s = unsafe { decode_string_from_crystal(client_state_cstr) }?;
 
         // parse issuer public key
@@ -245,7 +423,7 @@ pub unsafe extern "C" fn gen_token(
         .iter()
         .map(|blind| VoprfGroup::deserialize_scalar(&blind.0))
         .collect::<Result<Vec<_>, _>>() {
-            Ok(res) => Ok(res),
+            Ok(res) => Ok(Zeroizing::new(res)),
             Err(err) => match err {
                 voprfError::Info => Err(crystal_error("Size of info is longer then [`u16::MAX`]")),
                 voprfError::Input => Err(crystal_error("Size of input is empty or longer then [`u16::MAX`].")),
@@ -262,8 +440,9 @@ pub unsafe extern "C" fn gen_token(
         let token_challenge = challenge.token_challenge();
 
         // regenerate original token request sent to issuer
-        let (_, mut token_states) = match client
-        .issue_token_request_with_params(token_challenge, nonces, blinds) {
+        let (token_request, token_states) = match blind_in_chunks(nonces, &blinds, |nonces, blinds| {
+            client.issue_token_request_with_params(token_challenge, nonces, blinds)
+        }) {
             Ok(res) => Ok(res),
             Err(err) => match err {
                 IssueTokenRequestError::BlindingError => Err(crystal_error("failed to blind token")),
@@ -274,11 +453,7 @@ pub unsafe extern "C" fn gen_token(
 
         // count how many tokens can be generated with the TokenResponse, and get rid of state if got too much
         let nr: usize = token_response_nr(token_response_bytes.as_slice())?;
-        if token_states.len() > nr {
-            for _ in nr..token_states.len() {
-                token_states.pop();
-            }
-        }
+        let token_states = answered_token_states(&token_request, token_states, nr)?;
 
         // unblind token (this is where Finalize happens)
         let raw_tokens = match client.issue_tokens(&token_response, &token_states) {
@@ -295,14 +470,13 @@ pub unsafe extern "C" fn gen_token(
             .map(|token| token.tls_serialize_detached())
             .collect::<Result<Vec<Vec<u8>>, _>>()?;
 
-        let tokens_s = tokens_buf
-            .into_iter()
-            .map(|token_buf| URL_SAFE.encode(token_buf))
-            .collect::<Vec<_>>();
-
-        let tokens_rv = JSONTokens {
-            tokens: tokens_s,
-            error: "".to_string(),
+        // same layout as JSONTokens, with tokens base64url-encoded while serializing
+        let tokens_rv = JSONTokensRef {
+            tokens: tokens_buf
+                .iter()
+                .map(|token_buf| Base64Json(token_buf))
+                .collect(),
+            error: "",
         };
 
         let tokens_rv_s = serde_json::to_string(&tokens_rv)?;
@@ -311,8 +485,7 @@ pub unsafe extern "C" fn gen_token(
             error: "".to_string(),
         };
 
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let out = encode_json_for_crystal(&rv)?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -320,3 +493,460 @@ pub unsafe extern "C" fn gen_token(
     end_panic_handling!();
     result
 }
+
+/// Authorization header value presenting a (base64) token returned by `gen_token`, see
+/// `build_authorization_header`
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn gen_authorization_header(token_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token = unsafe { decode_untrusted_bytes_from_crystal(token_cstr, InputKind::Token) }?;
+        let rv = JSONRetVal {
+            retval: build_authorization_header(&token)?,
+            error: "".to_string(),
+        };
+
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// Rust API of the client side, for consumers running the whole protocol without the FFI or
+// WebAssembly builds
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("invalid WWW-Authenticate header")]
+    InvalidHeader,
+    #[error("expected a single TokenChallenge in header, got {0}")]
+    ChallengeCount(usize),
+    #[error("invalid issuer public key")]
+    InvalidPublicKey,
+    #[error("invalid client state")]
+    InvalidState,
+    #[error("failed to build token request")]
+    TokenRequest(#[from] IssueTokenRequestError),
+    #[error("invalid token response")]
+    TokenResponse,
+    #[error("malformed token")]
+    InvalidToken,
+}
+
+/// Keeps the states of the blinded elements of `token_request` answered by a TokenResponse of
+/// `nr` elements. Issuers drop the blinded elements past their limit, and may drop repeated
+/// ones (see `DuplicateElementPolicy`), answering the first occurrence of each in order.
+fn answered_token_states<S>(
+    token_request: &TokenRequest,
+    token_states: Vec<S>,
+    nr: usize,
+) -> Result<Vec<S>, ClientError> {
+    if token_states.len() <= nr {
+        return Ok(token_states);
+    }
+    let token_request_bytes = token_request
+        .tls_serialize_detached()
+        .map_err(|_| ClientError::InvalidState)?;
+    // token_type (2) || truncated_token_key_id (1) || blinded_elements<0..2^16-1>
+    let blinded_elements = token_request_bytes.get(5..).unwrap_or_default();
+    let mut token_states: Vec<Option<S>> = token_states.into_iter().map(Some).collect();
+    Ok(first_occurrences(blinded_elements.chunks_exact(NE))
+        .into_iter()
+        .take(nr)
+        .filter_map(|index| token_states.get_mut(index).and_then(Option::take))
+        .collect())
+}
+
+/// Nonces and blinding factors of a TokenRequest, needed to finalize its tokens.
+/// Serialized as the state returned by `gen_token_request`, so that tokens requested through
+/// the FFI can be finalized in Rust and conversely.
+pub struct TokenRequestState {
+    nonces: Vec<[u8; NONCE_BYTES]>,
+    /// secret until the tokens are finalized, wiped on drop
+    blinds: Zeroizing<Vec<<VoprfGroup as Group>::Scalar>>,
+}
+
+impl TokenRequestState {
+    /// Number of tokens requested
+    pub fn nr(&self) -> usize {
+        self.nonces.len()
+    }
+
+    /// NOTE: the blinding factors are secret until the tokens are finalized
+    pub fn to_json(&self) -> Result<Zeroizing<String>, ClientError> {
+        let state_vector = MyTokenReqState {
+            nonces_s: self
+                .nonces
+                .iter()
+                .map(|nonce| HexNonce(nonce.to_vec()))
+                .collect(),
+            blinds_s: self
+                .blinds
+                .iter()
+                .map(|blind| HexBlind(blind.to_bytes().to_vec()))
+                .collect(),
+        };
+        serde_json::to_string(&state_vector)
+            .map(Zeroizing::new)
+            .map_err(|_| ClientError::InvalidState)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ClientError> {
+        let state_vector: MyTokenReqState =
+            serde_json::from_str(json).map_err(|_| ClientError::InvalidState)?;
+        let nonces = state_vector
+            .nonces_s
+            .iter()
+            .map(|nonce| <[u8; NONCE_BYTES]>::try_from(nonce.0.as_slice()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ClientError::InvalidState)?;
+        let blinds = state_vector
+            .blinds_s
+            .iter()
+            .map(|blind| VoprfGroup::deserialize_scalar(&blind.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Zeroizing::new)
+            .map_err(|_| ClientError::InvalidState)?;
+        if nonces.len() != blinds.len() {
+            return Err(ClientError::InvalidState);
+        }
+        Ok(TokenRequestState { nonces, blinds })
+    }
+}
+
+/// Client side of issuance, for a token challenge and the issuer key it names
+pub struct PrivacyPassClient {
+    token_challenge: TokenChallenge,
+    public_key: PublicKey,
+}
+
+impl PrivacyPassClient {
+    pub fn new(token_challenge: TokenChallenge, public_key: PublicKey) -> Self {
+        PrivacyPassClient {
+            token_challenge,
+            public_key,
+        }
+    }
+
+    /// Client for the challenge of a `WWW-Authenticate: PrivateToken challenge=...,
+    /// token-key=...` header value, which must hold a single challenge
+    pub fn from_www_authenticate_header(header: &str) -> Result<Self, ClientError> {
+        let header_value = HeaderValue::from_str(header).map_err(|_| ClientError::InvalidHeader)?;
+        let challenges =
+            parse_www_authenticate_header(&header_value).map_err(|_| ClientError::InvalidHeader)?;
+        let [challenge] = challenges.as_slice() else {
+            return Err(ClientError::ChallengeCount(challenges.len()));
+        };
+        let public_key = deserialize_public_key(challenge.token_key())
+            .map_err(|_| ClientError::InvalidPublicKey)?;
+        Ok(Self::new(challenge.token_challenge().clone(), public_key))
+    }
+
+    pub fn token_challenge(&self) -> &TokenChallenge {
+        &self.token_challenge
+    }
+
+    /// Builds a TokenRequest for `nr` tokens, to be sent to the issuer, and the state needed
+    /// to finalize its TokenResponse
+    pub fn token_request(&self, nr: u16) -> Result<(TokenRequest, TokenRequestState), ClientError> {
+        self.token_request_from_rng(nr, &mut OsRng)
+    }
+
+    /// Like `token_request`, but with nonces and blinding factors sampled from a caller
+    /// supplied RNG, so that tests can be seeded and fuzzers can inject edge-case scalars.
+    /// NOTE: only for testing, production code must use the OsRng-backed `token_request`
+    #[cfg(feature = "injectable-rng")]
+    pub fn token_request_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        nr: u16,
+        rng: &mut R,
+    ) -> Result<(TokenRequest, TokenRequestState), ClientError> {
+        self.token_request_from_rng(nr, rng)
+    }
+
+    fn token_request_from_rng<R: RngCore + CryptoRng>(
+        &self,
+        nr: u16,
+        rng: &mut R,
+    ) -> Result<(TokenRequest, TokenRequestState), ClientError> {
+        let nonces = (0..nr)
+            .map(|_| {
+                let mut nonce = [0u8; NONCE_BYTES];
+                rng.fill_bytes(&mut nonce);
+                nonce
+            })
+            .collect::<Vec<_>>();
+        let blinds = Zeroizing::new(
+            (0..nr)
+                .map(|_| <VoprfGroup as Group>::Scalar::random(&mut *rng))
+                .collect::<Vec<_>>(),
+        );
+        let client = Client::new(self.public_key);
+        let (token_request, _) = blind_in_chunks(nonces.clone(), &blinds, |nonces, blinds| {
+            client.issue_token_request_with_params(&self.token_challenge, nonces, blinds)
+        })?;
+        Ok((token_request, TokenRequestState { nonces, blinds }))
+    }
+
+    /// Finalizes the tokens of `token_response`, the issuer's answer to the TokenRequest of
+    /// `state`. Issuers may answer with fewer tokens than requested, dropping the blinded
+    /// elements past their limit or repeated ones, the tokens of those answered are then
+    /// finalized.
+    pub fn finalize(
+        &self,
+        state: &TokenRequestState,
+        token_response: &TokenResponse,
+    ) -> Result<Vec<BatchedToken>, ClientError> {
+        self.finalize_chunks(state, std::slice::from_ref(token_response))
+    }
+
+    /// Like `finalize`, for the TokenResponses of an issuer answering the TokenRequest of
+    /// `state` in chunks, in order, as `PrivacyPass::gen_token_response_chunks` does
+    pub fn finalize_chunks(
+        &self,
+        state: &TokenRequestState,
+        token_responses: &[TokenResponse],
+    ) -> Result<Vec<BatchedToken>, ClientError> {
+        let nrs = token_responses
+            .iter()
+            .map(|token_response| {
+                let token_response_bytes = token_response
+                    .tls_serialize_detached()
+                    .map_err(|_| ClientError::TokenResponse)?;
+                MyTokenResponse::try_from_bytes(&token_response_bytes)
+                    .map(|token_response| token_response.nr())
+                    .map_err(|_| ClientError::TokenResponse)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // regenerate the states of the original token request
+        let client = Client::new(self.public_key);
+        let (token_request, token_states) =
+            blind_in_chunks(state.nonces.clone(), &state.blinds, |nonces, blinds| {
+                client.issue_token_request_with_params(&self.token_challenge, nonces, blinds)
+            })?;
+        let token_states = answered_token_states(&token_request, token_states, nrs.iter().sum())?;
+
+        // each response answers the elements following those of the previous one
+        let mut chunks = Vec::with_capacity(token_responses.len());
+        let mut remaining = token_states.as_slice();
+        for (token_response, nr) in token_responses.iter().zip(nrs) {
+            let (chunk, rest) = remaining
+                .split_at_checked(nr)
+                .ok_or(ClientError::TokenResponse)?;
+            chunks.push((token_response, chunk));
+            remaining = rest;
+        }
+        finalize_in_chunks(&chunks, |token_response, token_states| {
+            client.issue_tokens(token_response, token_states)
+        })
+        .map_err(|_| ClientError::TokenResponse)
+    }
+}
+
+/// RFC 9577 `Authorization` header value presenting a serialized token,
+/// `PrivateToken token="<base64url>"`
+pub fn build_authorization_header(token: &[u8]) -> Result<String, ClientError> {
+    kagippverify::Token::parse(token).map_err(|_| ClientError::InvalidToken)?;
+    Ok(format!("PrivateToken token=\"{}\"", URL_SAFE.encode(token)))
+}
+
+/// Fields of one challenge of a `WWW-Authenticate: PrivateToken` header value
+pub struct ChallengeFields {
+    pub token_type: u16,
+    pub issuer_name: String,
+    pub origin_info: Vec<String>,
+    /// seconds the challenge may be cached for, if the header sets a max-age
+    pub max_age: Option<usize>,
+    /// serialized issuer public key
+    pub token_key: Vec<u8>,
+    pub token_challenge: TokenChallenge,
+}
+
+impl ChallengeFields {
+    /// Client requesting tokens for this challenge, from the issuer key it names
+    pub fn client(&self) -> Result<PrivacyPassClient, ClientError> {
+        let public_key =
+            deserialize_public_key(&self.token_key).map_err(|_| ClientError::InvalidPublicKey)?;
+        Ok(PrivacyPassClient::new(
+            self.token_challenge.clone(),
+            public_key,
+        ))
+    }
+}
+
+/// Fields of every challenge of a `WWW-Authenticate: PrivateToken challenge=...,
+/// token-key=...[, max-age=...]` header value, in header order
+pub fn parse_challenges(header: &str) -> Result<Vec<ChallengeFields>, ClientError> {
+    let header_value = HeaderValue::from_str(header).map_err(|_| ClientError::InvalidHeader)?;
+    let challenges =
+        parse_www_authenticate_header(&header_value).map_err(|_| ClientError::InvalidHeader)?;
+    Ok(challenges
+        .iter()
+        .map(|challenge| {
+            let token_challenge = challenge.token_challenge().clone();
+            ChallengeFields {
+                token_type: token_challenge.token_type() as u16,
+                issuer_name: token_challenge.issuer_name(),
+                origin_info: token_challenge.origin_info(),
+                max_age: challenge.max_age(),
+                token_key: challenge.token_key().to_vec(),
+                token_challenge,
+            }
+        })
+        .collect())
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(all(test, feature = "server", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::server::{MyTokenRequest, PrivacyPass};
+    use crate::server_sync::PrivacyPassSync;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn test_privacy_pass_client() {
+        let privacy_pass = PrivacyPassSync::new();
+        let keypair = privacy_pass.gen_keys().unwrap();
+        let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+        let client =
+            PrivacyPassClient::from_www_authenticate_header(header.to_str().unwrap()).unwrap();
+
+        let (token_request, state) = client.token_request(3).unwrap();
+        let state = TokenRequestState::from_json(&state.to_json().unwrap()).unwrap();
+        assert_eq!(state.nr(), 3);
+        let token_response = privacy_pass
+            .gen_token_response(keypair.secret_key.expose_secret(), token_request, 3)
+            .unwrap();
+
+        let tokens = client.finalize(&state, &token_response).unwrap();
+        assert_eq!(tokens.len(), 3);
+        for token in tokens {
+            let token_bytes = token.tls_serialize_detached().unwrap();
+            assert!(privacy_pass
+                .validate_token(&token_bytes, keypair.secret_key.expose_secret())
+                .unwrap());
+        }
+        assert!(matches!(
+            PrivacyPassClient::from_www_authenticate_header("Basic realm=x"),
+            Err(ClientError::InvalidHeader)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_finalize_chunks() {
+        let privacy_pass = PrivacyPass::new();
+        let keypair = privacy_pass.gen_keys().await.unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+        let client =
+            PrivacyPassClient::from_www_authenticate_header(header.to_str().unwrap()).unwrap();
+
+        let (token_request, state) = client.token_request(5).unwrap();
+        let token_request = MyTokenRequest::tls_deserialize(
+            &mut token_request.tls_serialize_detached().unwrap().as_slice(),
+        )
+        .unwrap();
+        let token_responses = privacy_pass
+            .gen_token_response_chunks(sk, token_request, 2, 5)
+            .await
+            .unwrap();
+        assert_eq!(token_responses.len(), 3);
+
+        let tokens = client.finalize_chunks(&state, &token_responses).unwrap();
+        assert_eq!(tokens.len(), 5);
+        for token in tokens {
+            let token_bytes = token.tls_serialize_detached().unwrap();
+            assert!(privacy_pass.validate_token(&token_bytes, sk).await.unwrap());
+        }
+        // responses are finalized against the elements they answer, in order
+        assert!(matches!(
+            client.finalize_chunks(&state, &token_responses[1..]),
+            Err(ClientError::TokenResponse)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_finalize_chunks_rejects_bad_chunk() {
+        let privacy_pass = PrivacyPass::new();
+        let keypair = privacy_pass.gen_keys().await.unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+        let client =
+            PrivacyPassClient::from_www_authenticate_header(header.to_str().unwrap()).unwrap();
+
+        let (token_request, state) = client.token_request(40).unwrap();
+        let token_request = MyTokenRequest::tls_deserialize(
+            &mut token_request.tls_serialize_detached().unwrap().as_slice(),
+        )
+        .unwrap();
+        let token_responses = privacy_pass
+            .gen_token_response_chunks(sk, token_request, 10, 40)
+            .await
+            .unwrap();
+        assert_eq!(token_responses.len(), 4);
+
+        // the third chunk answers the elements of the second one, its proof can't verify
+        let token_response_bytes = token_responses
+            .iter()
+            .map(|token_response| token_response.tls_serialize_detached().unwrap())
+            .collect::<Vec<_>>();
+        let bad_token_responses = [0, 1, 1, 3]
+            .map(|index| TokenResponse::try_from_bytes(&token_response_bytes[index]).unwrap());
+        assert!(matches!(
+            client.finalize_chunks(&state, &bad_token_responses),
+            Err(ClientError::TokenResponse)
+        ));
+        assert_eq!(
+            client
+                .finalize_chunks(&state, &token_responses)
+                .unwrap()
+                .len(),
+            40
+        );
+    }
+
+    #[test]
+    fn test_parse_challenges() {
+        let keypair = PrivacyPassSync::new().gen_keys().unwrap();
+        let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+        let challenges = parse_challenges(header.to_str().unwrap()).unwrap();
+        let [challenge] = challenges.as_slice() else {
+            panic!("expected a single challenge, got {}", challenges.len());
+        };
+        assert_eq!(challenge.token_type, 5);
+        assert_eq!(challenge.issuer_name, "privacy-pass-issuer.kagi.com");
+        assert_eq!(challenge.origin_info, ["privacy-pass-origin.kagi.com"]);
+        assert_eq!(challenge.max_age, None);
+        assert_eq!(challenge.token_key, keypair.public_key);
+        assert!(parse_challenges("Basic realm=x").is_err());
+    }
+
+    #[test]
+    fn test_build_authorization_header() {
+        let mut token = vec![0x00, 0x05];
+        token.extend([7u8; 32 + 32 + 32 + 64]);
+        let header = build_authorization_header(&token).unwrap();
+        assert_eq!(
+            header,
+            format!("PrivateToken token=\"{}\"", URL_SAFE.encode(&token))
+        );
+        assert!(matches!(
+            build_authorization_header(&token[1..]),
+            Err(ClientError::InvalidToken)
+        ));
+    }
+}
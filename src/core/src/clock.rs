@@ -0,0 +1,118 @@
+// -----------------------------------------------------------------------------
+// ------------------------------  clock  --------------------------------------
+// -----------------------------------------------------------------------------
+//
+// Time source for wall-clock dependent logic (audit buckets, key publication times, and
+// key validity or nonce expiry). Functions taking a `&dyn Clock` can be tested with a
+// MockClock, while the process-wide entry points read `global_clock()`, which is the system
+// clock unless replaced with `set_global_clock`.
+// NOTE: deadlines and latencies measure elapsed time, and keep using the monotonic Instant.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Seconds since the unix epoch, 0 for times before it
+    fn unix_seconds(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> SystemTime {
+        (**self).now()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    // SystemTime::now panics on wasm32-unknown-unknown, so wasm builds read the epoch
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH
+    }
+}
+
+/// Clock that only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        MockClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn at_unix_seconds(seconds: u64) -> Self {
+        Self::new(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap_or_else(|err| err.into_inner()) = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|err| err.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        // a poisoned lock still holds a valid time, as it is only ever overwritten whole
+        *self.now.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+static GLOBAL_CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Clock read by the process-wide entry points (FFI functions and global stores)
+pub fn global_clock() -> Arc<dyn Clock> {
+    match GLOBAL_CLOCK
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        Some(clock) => clock.clone(),
+        None => Arc::new(SystemClock),
+    }
+}
+
+/// Replaces the process-wide clock. NOTE: pass None to go back to the system clock
+pub fn set_global_clock(clock: Option<Arc<dyn Clock>>) {
+    // a poisoned lock still holds a valid clock, as it is only ever overwritten whole
+    *GLOBAL_CLOCK.write().unwrap_or_else(|err| err.into_inner()) = clock;
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::at_unix_seconds(1_000);
+        assert_eq!(clock.unix_seconds(), 1_000);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.unix_seconds(), 1_060);
+        clock.set(UNIX_EPOCH - Duration::from_secs(1));
+        assert_eq!(clock.unix_seconds(), 0);
+    }
+}
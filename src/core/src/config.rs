@@ -3,15 +3,50 @@
 use voprf::Ristretto255;
 pub type VoprfGroup = Ristretto255;
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 use crate::batched_memory_stores::MemoryKeyStoreRistretto255;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub type MemoryKeyStore = MemoryKeyStoreRistretto255;
 
 pub use privacypass::batched_tokens_ristretto255 as batched_tokens_mod;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub use privacypass::TokenType::BatchedTokenRistretto255 as GroupTokenType;
 
 // if true, debug messages are printed to stdout
-#[cfg(not(target_arch = "wasm32"))]
-pub const VERBOSE: bool = false;
\ No newline at end of file
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub const VERBOSE: bool = false;
+
+// batched tokens over P-384, issued by the batched_p384 module
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::batched_memory_stores::MemoryKeyStoreP384;
+pub use privacypass::batched_tokens_p384 as batched_tokens_p384_mod;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub type MemoryKeyStoreBatchedP384 = MemoryKeyStoreP384;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use privacypass::TokenType::BatchedTokenP384 as BatchedP384TokenType;
+
+/// Group batched tokens run over, VoprfGroup by default: the FFI picks it per call from the
+/// token types it is given (0 meaning the default), `PrivacyPass` per instance
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BatchedGroup {
+    #[default]
+    Ristretto255,
+    P384,
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+impl BatchedGroup {
+    pub fn token_type(self) -> privacypass::TokenType {
+        match self {
+            BatchedGroup::Ristretto255 => GroupTokenType,
+            BatchedGroup::P384 => BatchedP384TokenType,
+        }
+    }
+
+    pub fn from_token_type(token_type: u16) -> Option<Self> {
+        [BatchedGroup::Ristretto255, BatchedGroup::P384]
+            .into_iter()
+            .find(|group| group.token_type() as u16 == token_type)
+    }
+}
@@ -0,0 +1,164 @@
+// -----------------------------------------------------------------------------
+// ------------------------  blocking crypto pool  -----------------------------
+// -----------------------------------------------------------------------------
+//
+// VOPRF evaluation and verification are CPU-bound. When the async API is used inside
+// an existing async server, running them inline would stall the I/O executor, so
+// `PrivacyPass` moves them onto tokio's blocking threads, bounded by a semaphore.
+
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+#[derive(Error, Debug)]
+pub enum CryptoPoolError {
+    #[error("crypto pool was closed")]
+    Closed(#[from] tokio::sync::AcquireError),
+    #[error("crypto pool task failed")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+struct CryptoPool {
+    semaphore: Arc<Semaphore>,
+    size: usize,
+}
+
+impl CryptoPool {
+    fn new(size: usize) -> Self {
+        CryptoPool {
+            semaphore: Arc::new(Semaphore::new(size)),
+            size,
+        }
+    }
+}
+
+static CRYPTO_POOL: RwLock<Option<CryptoPool>> = RwLock::new(None);
+
+/// Default number of crypto operations allowed to run at the same time
+pub fn default_crypto_pool_size() -> usize {
+    std::thread::available_parallelism()
+        .map(|parallelism| parallelism.get())
+        .unwrap_or(1)
+}
+
+/// Caps the number of crypto operations running at the same time on blocking threads.
+/// NOTE: pass 0 to go back to the default. Operations already running keep their slot.
+pub fn configure_crypto_pool(max_concurrency: usize) {
+    let max_concurrency = match max_concurrency {
+        0 => default_crypto_pool_size(),
+        _ => max_concurrency,
+    };
+    // a poisoned lock still holds a valid pool, as it is only ever overwritten whole
+    *CRYPTO_POOL.write().unwrap_or_else(|err| err.into_inner()) =
+        Some(CryptoPool::new(max_concurrency));
+}
+
+fn crypto_pool() -> (Arc<Semaphore>, usize) {
+    if let Some(pool) = CRYPTO_POOL
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        return (pool.semaphore.clone(), pool.size);
+    }
+    let mut pool = CRYPTO_POOL.write().unwrap_or_else(|err| err.into_inner());
+    let pool = pool.get_or_insert_with(|| CryptoPool::new(default_crypto_pool_size()));
+    (pool.semaphore.clone(), pool.size)
+}
+
+/// Runs `f` on a blocking thread once a slot in the crypto pool is free.
+/// Must be called from within a tokio runtime.
+pub async fn run_blocking<F, T>(f: F) -> Result<T, CryptoPoolError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (semaphore, _) = crypto_pool();
+    run_blocking_bounded(semaphore, f).await
+}
+
+/// Runs `f` on a blocking thread once a permit of `semaphore` is free
+async fn run_blocking_bounded<F, T>(semaphore: Arc<Semaphore>, f: F) -> Result<T, CryptoPoolError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let permit = semaphore.acquire_owned().await?;
+    Ok(tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    })
+    .await?)
+}
+
+/// Waits for the crypto operations already running to complete.
+/// NOTE: operations started meanwhile are waited for too, in the order they were queued
+pub async fn drain_crypto_pool() -> Result<(), CryptoPoolError> {
+    let (semaphore, size) = crypto_pool();
+    let size = u32::try_from(size).unwrap_or(u32::MAX);
+    drop(semaphore.acquire_many(size).await?);
+    Ok(())
+}
+
+/// Sets the maximum number of crypto operations run at the same time by the async API.
+/// NOTE: pass 0 to keep the default (the number of available CPUs)
+#[no_mangle]
+pub extern "C" fn set_crypto_pool_size(max_concurrency: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        configure_crypto_pool(usize::try_from(max_concurrency)?);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pool_bounds_concurrency() {
+        // a pool of its own, as resizing the process-wide one would throttle other tests
+        let pool = CryptoPool::new(1);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                tokio::spawn(run_blocking_bounded(pool.semaphore.clone(), move || {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }))
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
+}
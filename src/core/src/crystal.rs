@@ -2,16 +2,116 @@
 // --------------------  interfacing with crystal  -----------------------------
 // -----------------------------------------------------------------------------
 
+use crate::limits::{check_input_len, InputKind, InputTooLongError};
 use anyhow::{Context, Result};
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use base64::{display::Base64Display, engine::general_purpose::URL_SAFE, Engine as _};
+use secrecy::{ExposeSecretMut, SecretSlice};
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
+use std::io::Write as _;
 use std::os::raw::c_char;
+use zeroize::{Zeroize, Zeroizing};
+
+// returned when even a JSON error value can't be encoded, so that error paths never panic
+const FALLBACK_ERROR_RETVAL: &CStr = c"{\"retval\":\"\",\"error\":\"failed to encode error\"}";
+
+/// Borrows a C string coming from Crystal, refusing null pointers
+///
+/// # Safety
+///
+/// Callers must provide either a null pointer or a valid NUL terminated string pointer,
+/// outliving the returned CStr.
+unsafe fn cstr_from_crystal<'a>(cstr: *const i8) -> Result<&'a CStr> {
+    match cstr.is_null() {
+        true => Err(anyhow::anyhow!("null pointer passed from Crystal")),
+        false => Ok(unsafe { CStr::from_ptr(cstr as *const c_char) }),
+    }
+}
 
 pub fn encode_string_for_crystal(data: String) -> Result<*const i8> {
     let c_string = CString::new(data).with_context(|| "encode_string_for_crystal".to_string())?;
     Ok(c_string.into_raw() as *const i8) // Move ownership to C, cast to i8 for cross-platform compatibility
 }
 
+// Scratch buffer JSON gets serialized into before being copied into the output C string.
+// Reusing it means each encode_json_for_crystal call performs a single, exactly sized
+// allocation (the one whose ownership moves to C), instead of growing a String and then
+// reallocating it into a CString.
+const MAX_POOLED_BUFFER_BYTES: usize = 1 << 20;
+thread_local! {
+    static ENCODE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Serializes `value` as JSON directly into a C string to be passed to Crystal
+pub fn encode_json_for_crystal<T: seSerialize>(value: &T) -> Result<*const i8> {
+    encode_json_into_c_string(value, false)
+}
+
+/// Like `encode_json_for_crystal`, for values carrying secrets such as secret keys:
+/// the pooled scratch buffer gets wiped before each time it grows, and once the output C
+/// string was created
+pub fn encode_secret_json_for_crystal<T: seSerialize>(value: &T) -> Result<*const i8> {
+    encode_json_into_c_string(value, true)
+}
+
+/// Writer appending to the pooled scratch buffer. With `wipe`, it grows the buffer by hand,
+/// wiping the previous allocation, as Vec would free it with the bytes written so far.
+struct ScratchWriter<'a> {
+    buffer: &'a mut Vec<u8>,
+    wipe: bool,
+}
+
+impl std::io::Write for ScratchWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        let needed = self.buffer.len().saturating_add(bytes.len());
+        if self.wipe && needed > self.buffer.capacity() {
+            let mut grown =
+                Vec::with_capacity(needed.max(self.buffer.capacity().saturating_mul(2)));
+            grown.extend_from_slice(self.buffer);
+            self.buffer.zeroize();
+            *self.buffer = grown;
+        }
+        self.buffer.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn encode_json_into_c_string<T: seSerialize>(value: &T, wipe: bool) -> Result<*const i8> {
+    ENCODE_BUFFER
+        .try_with(|buffer| -> Result<*const i8> {
+            let mut buffer = buffer
+                .try_borrow_mut()
+                .with_context(|| "encode_json_for_crystal 0".to_string())?;
+            buffer.clear();
+            let mut writer = ScratchWriter {
+                buffer: &mut *buffer,
+                wipe,
+            };
+            let written = serde_json::to_writer(&mut writer, value)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| Ok(writer.write_all(&[0])?));
+            let c_string = written
+                .with_context(|| "encode_json_for_crystal 1".to_string())
+                .and_then(|_| {
+                    // to_vec allocates exactly buffer.len() bytes, so into_raw won't need to shrink it
+                    CString::from_vec_with_nul(buffer.to_vec())
+                        .with_context(|| "encode_json_for_crystal 2".to_string())
+                });
+            if wipe {
+                buffer.zeroize();
+            }
+            if buffer.capacity() > MAX_POOLED_BUFFER_BYTES {
+                *buffer = Vec::new();
+            }
+            Ok(c_string?.into_raw() as *const i8) // Move ownership to C, cast to i8 for cross-platform compatibility
+        })
+        .with_context(|| "encode_json_for_crystal 3".to_string())?
+}
+
 pub fn encode_bytes_for_crystal(data: Vec<u8>) -> Result<*const i8> {
     let encoded_data: String = URL_SAFE.encode(&data);
     let encoded_s = encode_string_for_crystal(encoded_data)
@@ -23,7 +123,7 @@ pub fn encode_bytes_for_crystal(data: Vec<u8>) -> Result<*const i8> {
 ///
 /// Callers must provide a valid NUL terminated string pointer.
 pub unsafe fn decode_string_from_crystal(cstr: *const i8) -> Result<String> {
-    let c_str: &CStr = unsafe { CStr::from_ptr(cstr as *const c_char) };
+    let c_str: &CStr = unsafe { cstr_from_crystal(cstr)? };
     let rust_s = c_str
         .to_str()
         .with_context(|| "decode_string_from_crystal".to_string())?
@@ -35,11 +135,145 @@ pub unsafe fn decode_string_from_crystal(cstr: *const i8) -> Result<String> {
 ///
 /// Callers must provide a valid NUL terminated string pointer.
 pub unsafe fn decode_bytes_from_crystal(cstr: *const i8) -> Result<Vec<u8>> {
-    let decoded_s = decode_string_from_crystal(cstr)
-        .with_context(|| "decode_bytes_from_crystal 1".to_string())?;
+    // decode straight from the borrowed C string, base64 decoding rejects non-ASCII input anyway
+    let c_str: &CStr = unsafe { cstr_from_crystal(cstr)? };
     let decoded_bytes = URL_SAFE
-        .decode(decoded_s)
-        .with_context(|| "decode_bytes_from_crystal 2".to_string())?;
+        .decode(c_str.to_bytes())
+        .with_context(|| "decode_bytes_from_crystal".to_string())?;
+    Ok(decoded_bytes)
+}
+
+/// Borrows the bytes of a C string coming from Crystal, rejecting inputs longer than the limit
+/// for `kind` without copying them
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer, outliving the returned slice.
+pub unsafe fn borrow_untrusted_bytes_from_crystal<'a>(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<&'a [u8]> {
+    let c_str: &CStr = unsafe { cstr_from_crystal(cstr)? };
+    let bytes = c_str.to_bytes();
+    check_input_len(kind, bytes.len())?;
+    Ok(bytes)
+}
+
+/// Like `borrow_untrusted_bytes_from_crystal`, but also checks the input is valid UTF-8
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer, outliving the returned slice.
+pub unsafe fn borrow_untrusted_str_from_crystal<'a>(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<&'a str> {
+    let bytes = unsafe { borrow_untrusted_bytes_from_crystal(cstr, kind)? };
+    std::str::from_utf8(bytes).with_context(|| "borrow_untrusted_str_from_crystal".to_string())
+}
+
+/// Base64url-decodes a C string coming from Crystal straight into `buffer`, without copying
+/// it into an intermediate String. `buffer` is cleared first, so that it can be reused.
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+pub unsafe fn decode_untrusted_base64_from_crystal_into(
+    cstr: *const i8,
+    kind: InputKind,
+    buffer: &mut Vec<u8>,
+) -> Result<()> {
+    let encoded = unsafe { borrow_untrusted_bytes_from_crystal(cstr, kind)? };
+    buffer.clear();
+    URL_SAFE
+        .decode_vec(encoded, buffer)
+        .with_context(|| "decode_untrusted_base64_from_crystal_into".to_string())?;
+    Ok(())
+}
+
+/// Like `decode_untrusted_bytes_from_crystal`, for inputs carrying secrets such as secret keys.
+/// The decoded bytes are wiped when the returned SecretSlice is dropped.
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+pub unsafe fn decode_secret_bytes_from_crystal(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<SecretSlice<u8>> {
+    let encoded = unsafe { borrow_untrusted_bytes_from_crystal(cstr, kind)? };
+    decode_secret_base64(encoded).with_context(|| "decode_secret_bytes_from_crystal".to_string())
+}
+
+/// Like `decode_secret_bytes_from_crystal`, for a JSON array of secrets such as secret keys,
+/// e.g. "[\"<base64 key>\", \"<base64 key>\"]". The whole array must fit the limit for `kind`.
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+pub unsafe fn decode_secret_bytes_array_from_crystal(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<Vec<SecretSlice<u8>>> {
+    let json = unsafe { borrow_untrusted_str_from_crystal(cstr, kind)? };
+    // borrowed from the C string, so that parsing leaves no copies of the secrets behind
+    let encoded: Vec<&str> = serde_json::from_str(json)
+        .with_context(|| "decode_secret_bytes_array_from_crystal".to_string())?;
+    encoded
+        .iter()
+        .map(|encoded| decode_secret_base64(encoded.as_bytes()))
+        .collect::<Result<_, _>>()
+        .with_context(|| "decode_secret_bytes_array_from_crystal".to_string())
+}
+
+fn decode_secret_base64(encoded: &[u8]) -> Result<SecretSlice<u8>, base64::DecodeError> {
+    // reserve upfront, so that decoding never reallocates and leaves copies behind
+    let mut decoded_bytes = Zeroizing::new(Vec::with_capacity(base64::decoded_len_estimate(
+        encoded.len(),
+    )));
+    URL_SAFE.decode_vec(encoded, &mut decoded_bytes)?;
+    Ok(secret_from_slice(&decoded_bytes))
+}
+
+/// Copies `bytes` into a secret allocated at their exact length upfront, since converting a
+/// `Vec` whose capacity exceeds its length reallocates and leaves an unwiped copy behind
+pub(crate) fn secret_from_slice(bytes: &[u8]) -> SecretSlice<u8> {
+    let mut secret = SecretSlice::from(vec![0u8; bytes.len()]);
+    secret.expose_secret_mut().copy_from_slice(bytes);
+    secret
+}
+
+/// Like `decode_string_from_crystal`, but rejects inputs longer than the limit for `kind`
+/// before copying them
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+pub unsafe fn decode_untrusted_string_from_crystal(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<String> {
+    let c_str: &CStr = unsafe { cstr_from_crystal(cstr)? };
+    check_input_len(kind, c_str.to_bytes().len())?;
+    let rust_s = c_str
+        .to_str()
+        .with_context(|| "decode_untrusted_string_from_crystal".to_string())?
+        .to_string();
+    Ok(rust_s)
+}
+
+/// Like `decode_bytes_from_crystal`, but rejects inputs longer than the limit for `kind`
+/// before decoding them
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+pub unsafe fn decode_untrusted_bytes_from_crystal(
+    cstr: *const i8,
+    kind: InputKind,
+) -> Result<Vec<u8>> {
+    let mut decoded_bytes = Vec::new();
+    unsafe { decode_untrusted_base64_from_crystal_into(cstr, kind, &mut decoded_bytes)? };
     Ok(decoded_bytes)
 }
 
@@ -56,14 +290,195 @@ pub struct JSONRetVal {
     pub error: String,
 }
 
+/// Borrowed bytes that serialize as a base64url JSON string, streamed straight into
+/// the serializer instead of being encoded into an intermediate String first
+pub struct Base64Json<'a>(pub &'a [u8]);
+
+impl seSerialize for Base64Json<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&Base64Display::new(self.0, &URL_SAFE))
+    }
+}
+
+/// Same JSON layout as JSONRetVal, but borrowing its contents
+#[derive(seSerialize)]
+pub struct JSONRetValRef<'a, T: seSerialize> {
+    pub retval: T,
+    pub error: &'a str,
+}
+
 pub fn error_json_retval(message: &str) -> *const i8 {
+    set_last_error(JSONErrorRetVal {
+        retval: "".to_string(),
+        error: message.to_string(),
+        // only called by end_panic_handling for caught panics
+        code: "panic".to_string(),
+        causes: vec![message.to_string()],
+    });
     let error_obj = JSONRetVal {
         retval: "".to_string(),
         error: message.to_string(),
     };
-    let error_s =
-        serde_json::to_string(&error_obj).expect("failed to encode JSONRetVal into string"); // this should be unable to fail
-    encode_string_for_crystal(error_s).expect("failed to pass encoded JSONRetVal to Crystal")
+    encode_json_for_crystal(&error_obj).unwrap_or_else(|_| fallback_error_retval())
+}
+
+/// Error return value, carrying alongside the error message a stable code and the message of
+/// every error in its `source()` chain (outermost first), so that nested errors (e.g. tls_codec
+/// inside privacypass inside our wrappers) are still debuggable from the Crystal logs
+#[derive(seSerialize, seDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JSONErrorRetVal {
+    pub retval: String,
+    pub error: String,
+    pub code: String,
+    pub causes: Vec<String>,
+}
+
+impl JSONErrorRetVal {
+    pub fn from_error(err: &(dyn std::error::Error + 'static)) -> Self {
+        JSONErrorRetVal {
+            retval: "".to_string(),
+            error: format!("{:?}", err),
+            code: error_code(err).to_string(),
+            causes: error_chain(err).map(|cause| cause.to_string()).collect(),
+        }
+    }
+}
+
+/// Iterates over `err` and its sources, outermost first
+pub fn error_chain<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+    std::iter::successors(Some(err), |cause| cause.source())
+}
+
+/// Stable code of the innermost error in the chain of `err` we have a code for
+pub fn error_code(err: &(dyn std::error::Error + 'static)) -> &'static str {
+    let mut code = "error";
+    for cause in error_chain(err) {
+        if let Some(too_long) = cause.downcast_ref::<InputTooLongError>() {
+            code = too_long.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if cause.is::<crate::revocation::KeyRevokedError>() {
+            code = "key_revoked";
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(validity) = cause.downcast_ref::<crate::key_validity::KeyValidityError>() {
+            code = validity.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if cause.is::<crate::audit::AuditBucketTooShortError>() {
+            code = "audit_bucket_too_short";
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if cause.is::<crate::server::UnsupportedTokenTypeError>() {
+            code = "unsupported_token_type";
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if cause.is::<crate::server::UnknownKeyIdError>()
+            || matches!(
+                cause.downcast_ref::<crate::server::GenTokenResponseError>(),
+                Some(crate::server::GenTokenResponseError::KeyIdNotFound)
+            )
+        {
+            code = "unknown_key_id";
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if cause.is::<crate::server::DuplicateBlindedElementError>() {
+            code = "duplicate_blinded_element";
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(blinded_element) =
+            cause.downcast_ref::<crate::server::InvalidBlindedElementError>()
+        {
+            code = blinded_element.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(prevalidation) = cause.downcast_ref::<crate::server::PrevalidateTokenError>() {
+            code = prevalidation.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(origin) = cause.downcast_ref::<crate::server::ChallengeOriginError>() {
+            code = origin.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(authentication) =
+            cause.downcast_ref::<crate::challenge_authentication::ChallengeAuthenticationError>()
+        {
+            code = authentication.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(freshness) =
+            cause.downcast_ref::<crate::challenge_freshness::ChallengeFreshnessError>()
+        {
+            code = freshness.code();
+        }
+        #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+        if let Some(authorization) = cause.downcast_ref::<crate::origin::AuthorizationHeaderError>()
+        {
+            code = authorization.code();
+        }
+    }
+    code
+}
+
+pub fn error_chain_json_retval(err: &(dyn std::error::Error + 'static)) -> *const i8 {
+    let error_obj = JSONErrorRetVal::from_error(err);
+    let out = encode_json_for_crystal(&error_obj).unwrap_or_else(|_| fallback_error_retval());
+    set_last_error(error_obj);
+    out
+}
+
+// Error of the most recent failed FFI call on each thread, kept until the next failure, so
+// that callers can fetch diagnostics after the fact instead of parsing every return value
+thread_local! {
+    static LAST_ERROR: RefCell<Option<JSONErrorRetVal>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(error: JSONErrorRetVal) {
+    // a thread being torn down has no caller left to ask for its last error
+    let _ = LAST_ERROR.try_with(|last_error| {
+        if let Ok(mut last_error) = last_error.try_borrow_mut() {
+            *last_error = Some(error);
+        }
+    });
+}
+
+/// Returns the error of the most recent failed FFI call on the current thread, if any
+pub fn last_error() -> Option<JSONErrorRetVal> {
+    LAST_ERROR
+        .try_with(|last_error| last_error.try_borrow().ok().and_then(|e| e.clone()))
+        .ok()
+        .flatten()
+}
+
+/// Returns the error (message, code and source chain) of the most recent failed call on the
+/// current thread as a JSON encoded retval, or an empty retval if no call failed yet
+#[no_mangle]
+pub extern "C" fn pp_last_error() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let retval = match last_error() {
+            Some(last_error) => serde_json::to_string(&last_error)?,
+            None => "".to_string(),
+        };
+        let rv = JSONRetVal {
+            retval,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+fn fallback_error_retval() -> *const i8 {
+    FALLBACK_ERROR_RETVAL.to_owned().into_raw() as *const i8
 }
 
 /// # Safety
@@ -76,3 +491,47 @@ pub extern "C" fn free_string(ptr: *const i8) {
     // Take the ownership back to rust and drop the owner
     let _ = unsafe { CString::from_raw(ptr as *mut c_char) };
 }
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_retval_keeps_source_chain() {
+        let err: Box<dyn std::error::Error> =
+            anyhow::Error::from(InputTooLongError::Token(300, 256))
+                .context("decoding token")
+                .into();
+        let rv = JSONErrorRetVal::from_error(&*err);
+
+        assert_eq!(rv.code, "token_too_long");
+        assert_eq!(
+            rv.causes,
+            vec![
+                "decoding token".to_string(),
+                "token input too long (300 > 256 bytes)".to_string(),
+            ]
+        );
+
+        free_string(error_chain_json_retval(&*err));
+        assert_eq!(last_error(), Some(rv));
+    }
+
+    #[test]
+    fn test_scratch_writer_grows_wiping() {
+        let mut buffer = Vec::with_capacity(4);
+        let mut writer = ScratchWriter {
+            buffer: &mut buffer,
+            wipe: true,
+        };
+        for chunk in [b"sec".as_slice(), b"ret ", b"key bytes"] {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(buffer, b"secret key bytes");
+        assert!(buffer.capacity() >= 16);
+    }
+}
@@ -0,0 +1,555 @@
+// -----------------------------------------------------------------------------
+// ------------------------  encrypted file key store  -------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuer secret keys used to live as raw base64 strings in the app config. `FileKeyStore`
+// keeps them in a file instead, encrypted with AES-256-GCM under a key derived from a
+// passphrase with argon2id, so that only the passphrase has to be handed to the issuer.
+// `pp_server_new_from_key_file` loads the newest key of such a file into a server handle at
+// startup, and `add_key_to_key_file` adds keys to it (creating it if needed).
+// File layout (integers big endian), the header being authenticated along with the keys:
+//   magic "KPPK" || version (1 byte) || argon2 m_cost, t_cost, p_cost (4 bytes each)
+//   || salt (16 bytes) || AES-GCM nonce (12 bytes) || ciphertext
+// with the plaintext being the secret keys, oldest first, each prefixed by the time it was
+// added (unix time, in seconds, 8 bytes) and its length (2 bytes). Version 1 files, without
+// the times, are still opened, their keys counting as added at 0. Files are rewritten whole
+// (as version 2), through a temporary file renamed over the old one. The argon2 parameters
+// are read before anything is authenticated, so files asking for more than the bounds below
+// are refused rather than derived from.
+// Behind a Mutex, the store implements `Retain`, keeping only the newest keys.
+
+use crate::clock::global_clock;
+use crate::crystal::{
+    crystal_error, decode_secret_bytes_from_crystal, decode_string_from_crystal,
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, secret_from_slice,
+    JSONRetVal,
+};
+use crate::limits::InputKind;
+use crate::retention::{Retain, RetentionPolicy};
+use crate::server_handle::ServerHandle;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce as AesGcmNonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use async_trait::async_trait;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::{ExposeSecret, SecretSlice};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 4] = b"KPPK";
+const VERSION: u8 = 2;
+/// version of the files whose keys are not prefixed by the time they were added
+const VERSION_WITHOUT_TIMES: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+/// most memory a key file may ask argon2 for, in KiB (1 GiB)
+const MAX_M_COST: u32 = 1024 * 1024;
+/// most passes a key file may ask argon2 for
+const MAX_T_COST: u32 = 16;
+/// most lanes a key file may ask argon2 for
+const MAX_P_COST: u32 = 16;
+
+#[derive(Error, Debug)]
+pub enum FileKeyStoreError {
+    #[error("failed to access key file")]
+    Io(#[from] std::io::Error),
+    #[error("malformed key file")]
+    Malformed,
+    #[error("unsupported key file version {0}")]
+    UnsupportedVersion(u8),
+    #[error("wrong passphrase, or key file was tampered with")]
+    Decryption,
+    #[error("invalid key derivation parameters: {0}")]
+    Kdf(String),
+    #[error("key derivation parameters {0:?} exceed the bounds of key files")]
+    KdfTooCostly(KdfParams),
+    #[error("key file holds no key")]
+    NoKey,
+    #[error("too many or too large keys for a key file")]
+    TooLarge,
+}
+
+/// argon2id cost parameters, stored in the file so that they can be raised for new files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// memory cost, in KiB
+    pub m_cost: u32,
+    /// number of passes
+    pub t_cost: u32,
+    /// degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB and 3 passes, within the argon2id recommendations of RFC 9106
+    fn default() -> Self {
+        KdfParams {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Refuses costs over MAX_M_COST, MAX_T_COST and MAX_P_COST
+    fn check_bounds(&self) -> Result<(), FileKeyStoreError> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(FileKeyStoreError::KdfTooCostly(*self));
+        }
+        Ok(())
+    }
+}
+
+fn derive_file_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, FileKeyStoreError> {
+    params.check_bounds()?;
+    let argon2_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|err| FileKeyStoreError::Kdf(err.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase, salt, key.as_mut())
+        .map_err(|err| FileKeyStoreError::Kdf(err.to_string()))?;
+    Ok(key)
+}
+
+/// Whether `secret_key` is `other`, compared in constant time
+fn same_secret_key(secret_key: &SecretSlice<u8>, other: &[u8]) -> bool {
+    secret_key.expose_secret().ct_eq(other).into()
+}
+
+/// Secret key of a key file
+pub struct FileKey {
+    pub secret_key: SecretSlice<u8>,
+    /// when the key was added, in unix seconds (0 for keys of version 1 files)
+    pub added_at: u64,
+}
+
+/// Secret keys persisted to an encrypted file, see the module comment
+pub struct FileKeyStore {
+    path: PathBuf,
+    params: KdfParams,
+    salt: [u8; SALT_LEN],
+    file_key: Zeroizing<[u8; 32]>,
+    // oldest first
+    keys: Vec<FileKey>,
+}
+
+impl FileKeyStore {
+    /// Creates an empty key file at `path`, replacing any file there
+    pub fn create(
+        path: impl AsRef<Path>,
+        passphrase: &[u8],
+        params: KdfParams,
+    ) -> Result<Self, FileKeyStoreError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let file_key = derive_file_key(passphrase, &salt, params)?;
+        let key_store = FileKeyStore {
+            path: path.as_ref().to_path_buf(),
+            params,
+            salt,
+            file_key,
+            keys: Vec::new(),
+        };
+        key_store.save()?;
+        Ok(key_store)
+    }
+
+    /// Decrypts the key file at `path`
+    pub fn open(path: impl AsRef<Path>, passphrase: &[u8]) -> Result<Self, FileKeyStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let bytes = std::fs::read(&path)?;
+        if !bytes.starts_with(MAGIC) {
+            return Err(FileKeyStoreError::Malformed);
+        }
+        let (header, ciphertext) = bytes
+            .split_at_checked(HEADER_LEN)
+            .ok_or(FileKeyStoreError::Malformed)?;
+        let version = *header
+            .get(MAGIC.len())
+            .ok_or(FileKeyStoreError::Malformed)?;
+        if version != VERSION && version != VERSION_WITHOUT_TIMES {
+            return Err(FileKeyStoreError::UnsupportedVersion(version));
+        }
+        let u32_at = |offset: usize| {
+            header
+                .get(offset..offset + 4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or(FileKeyStoreError::Malformed)
+        };
+        let params_offset = MAGIC.len() + 1;
+        let params = KdfParams {
+            m_cost: u32_at(params_offset)?,
+            t_cost: u32_at(params_offset + 4)?,
+            p_cost: u32_at(params_offset + 8)?,
+        };
+        let salt_offset = params_offset + 12;
+        let salt: [u8; SALT_LEN] = header
+            .get(salt_offset..salt_offset + SALT_LEN)
+            .and_then(|salt| salt.try_into().ok())
+            .ok_or(FileKeyStoreError::Malformed)?;
+        let nonce = header
+            .get(salt_offset + SALT_LEN..)
+            .ok_or(FileKeyStoreError::Malformed)?;
+
+        let file_key = derive_file_key(passphrase, &salt, params)?;
+        let cipher = Aes256Gcm::new_from_slice(file_key.as_slice())
+            .map_err(|_| FileKeyStoreError::Decryption)?;
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(
+                    AesGcmNonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| FileKeyStoreError::Decryption)?,
+        );
+        Ok(FileKeyStore {
+            path,
+            params,
+            salt,
+            file_key,
+            keys: decode_keys(&plaintext, version == VERSION)?,
+        })
+    }
+
+    /// Secret keys of the file, oldest first
+    pub fn keys(&self) -> &[FileKey] {
+        &self.keys
+    }
+
+    /// Most recently added secret key
+    pub fn newest_key(&self) -> Option<&SecretSlice<u8>> {
+        self.keys.last().map(|key| &key.secret_key)
+    }
+
+    /// Adds `secret_key` as the newest key (moving it there if already in the file), and saves
+    /// the file
+    pub fn add_key(&mut self, secret_key: &[u8]) -> Result<(), FileKeyStoreError> {
+        self.add_key_at(secret_key, global_clock().unix_seconds())
+    }
+
+    /// Like `add_key`, recording the key as added at `added_at` (unix time, in seconds)
+    pub fn add_key_at(
+        &mut self,
+        secret_key: &[u8],
+        added_at: u64,
+    ) -> Result<(), FileKeyStoreError> {
+        self.keys
+            .retain(|existing| !same_secret_key(&existing.secret_key, secret_key));
+        self.keys.push(FileKey {
+            secret_key: secret_from_slice(secret_key),
+            added_at,
+        });
+        self.save()
+    }
+
+    /// Removes `secret_key` from the file, returning whether it was there
+    pub fn remove_key(&mut self, secret_key: &[u8]) -> Result<bool, FileKeyStoreError> {
+        let before = self.keys.len();
+        self.keys
+            .retain(|existing| !same_secret_key(&existing.secret_key, secret_key));
+        if self.keys.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Removes all but the `depth` newest keys from the file, returning how many were removed
+    pub fn retain_newest_keys(&mut self, depth: usize) -> Result<usize, FileKeyStoreError> {
+        let removed = self.keys.len().saturating_sub(depth);
+        if removed == 0 {
+            return Ok(0);
+        }
+        let kept = self.keys.split_off(removed);
+        let dropped = std::mem::replace(&mut self.keys, kept);
+        if let Err(err) = self.save() {
+            // put the keys back, so that they are removed from the file on the next attempt
+            let kept = std::mem::replace(&mut self.keys, dropped);
+            self.keys.extend(kept);
+            return Err(err);
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), FileKeyStoreError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&self.params.m_cost.to_be_bytes());
+        header.extend_from_slice(&self.params.t_cost.to_be_bytes());
+        header.extend_from_slice(&self.params.p_cost.to_be_bytes());
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&nonce);
+
+        let plaintext = encode_keys(&self.keys)?;
+        let cipher = Aes256Gcm::new_from_slice(self.file_key.as_slice())
+            .map_err(|_| FileKeyStoreError::Decryption)?;
+        let ciphertext = cipher
+            .encrypt(
+                AesGcmNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| FileKeyStoreError::TooLarge)?;
+        header.extend_from_slice(&ciphertext);
+        write_private_file(&self.path, &header)
+    }
+}
+
+// NOTE: a file that can't be saved keeps its keys, the next pass tries again
+#[async_trait]
+impl Retain for Mutex<FileKeyStore> {
+    async fn apply_retention(&self, policy: &RetentionPolicy, _now: u64) -> usize {
+        match policy.key_history_depth {
+            // a poisoned lock still holds valid keys, as the file is only ever rewritten whole
+            Some(depth) => self
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .retain_newest_keys(depth)
+                .unwrap_or(0),
+            None => 0,
+        }
+    }
+}
+
+fn encode_keys(keys: &[FileKey]) -> Result<Zeroizing<Vec<u8>>, FileKeyStoreError> {
+    let mut plaintext = Zeroizing::new(Vec::new());
+    for key in keys {
+        plaintext.extend_from_slice(&key.added_at.to_be_bytes());
+        let key = key.secret_key.expose_secret();
+        let len = u16::try_from(key.len()).map_err(|_| FileKeyStoreError::TooLarge)?;
+        plaintext.extend_from_slice(&len.to_be_bytes());
+        plaintext.extend_from_slice(key);
+    }
+    Ok(plaintext)
+}
+
+/// Keys of a plaintext, whose keys are prefixed by the time they were added if `with_times`
+fn decode_keys(mut plaintext: &[u8], with_times: bool) -> Result<Vec<FileKey>, FileKeyStoreError> {
+    let mut keys = Vec::new();
+    while !plaintext.is_empty() {
+        let mut added_at = 0;
+        if with_times {
+            let (time, rest) = plaintext
+                .split_first_chunk::<8>()
+                .ok_or(FileKeyStoreError::Malformed)?;
+            added_at = u64::from_be_bytes(*time);
+            plaintext = rest;
+        }
+        let Some((len, rest)) = plaintext.split_first_chunk::<2>() else {
+            return Err(FileKeyStoreError::Malformed);
+        };
+        let len = usize::from(u16::from_be_bytes(*len));
+        if rest.len() < len {
+            return Err(FileKeyStoreError::Malformed);
+        }
+        let (key, rest) = rest.split_at(len);
+        keys.push(FileKey {
+            secret_key: secret_from_slice(key),
+            added_at,
+        });
+        plaintext = rest;
+    }
+    Ok(keys)
+}
+
+/// Writes `bytes` to a temporary file only readable by the current user, then renames it
+/// to `path`, so that a crash mid-write never leaves a truncated key file behind
+fn write_private_file(path: &Path, bytes: &[u8]) -> Result<(), FileKeyStoreError> {
+    use std::io::Write;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Loads the newest key of the key file at `path`, decrypted with `passphrase`, into a
+/// handle written to `handle_out`, like `pp_server_new`
+#[no_mangle]
+pub extern "C" fn pp_server_new_from_key_file(
+    path_cstr: *const i8,
+    passphrase_cstr: *const i8,
+    handle_out: *mut *mut ServerHandle,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let path = unsafe { decode_string_from_crystal(path_cstr)? };
+        let passphrase = Zeroizing::new(unsafe { decode_string_from_crystal(passphrase_cstr)? });
+        let key_store = FileKeyStore::open(&path, passphrase.as_bytes())?;
+        let private_key = key_store.newest_key().ok_or(FileKeyStoreError::NoKey)?;
+        let private_key = secret_from_slice(private_key.expose_secret());
+        let handle = Box::new(ServerHandle::new(private_key)?);
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Adds a (base64 encoded) secret key, as returned by `gen_keys`, to the key file at `path`,
+/// creating the file, encrypted with `passphrase`, if there is none yet
+#[no_mangle]
+pub extern "C" fn add_key_to_key_file(
+    path_cstr: *const i8,
+    passphrase_cstr: *const i8,
+    sk_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let path = unsafe { decode_string_from_crystal(path_cstr)? };
+        let passphrase = Zeroizing::new(unsafe { decode_string_from_crystal(passphrase_cstr)? });
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let mut key_store = match Path::new(&path).exists() {
+            true => FileKeyStore::open(&path, passphrase.as_bytes())?,
+            false => FileKeyStore::create(&path, passphrase.as_bytes(), KdfParams::default())?,
+        };
+        key_store.add_key(private_key.expose_secret())?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cheap parameters, tests don't need to resist brute force
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_key_file_roundtrip() {
+        let path = std::env::temp_dir().join(format!("kagipp-keys-{}", std::process::id()));
+        let mut key_store = FileKeyStore::create(&path, b"passphrase", TEST_PARAMS).unwrap();
+        key_store.add_key_at(&[1u8; 32], 10).unwrap();
+        key_store.add_key_at(&[2u8; 32], 20).unwrap();
+        // re-adding a key makes it the newest
+        key_store.add_key_at(&[1u8; 32], 30).unwrap();
+
+        let reopened = FileKeyStore::open(&path, b"passphrase").unwrap();
+        let keys: Vec<_> = reopened
+            .keys()
+            .iter()
+            .map(|key| (key.secret_key.expose_secret(), key.added_at))
+            .collect();
+        assert_eq!(keys, vec![(&[2u8; 32][..], 20), (&[1u8; 32][..], 30)]);
+        assert_eq!(reopened.params, TEST_PARAMS);
+        assert!(matches!(
+            FileKeyStore::open(&path, b"wrong passphrase"),
+            Err(FileKeyStoreError::Decryption)
+        ));
+
+        // the header is authenticated too
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN - 1] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            FileKeyStore::open(&path, b"passphrase"),
+            Err(FileKeyStoreError::Decryption)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_key_file_retention() {
+        let path = std::env::temp_dir().join(format!("kagipp-retain-{}", std::process::id()));
+        let mut key_store = FileKeyStore::create(&path, b"passphrase", TEST_PARAMS).unwrap();
+        for (i, added_at) in [(1u8, 10), (2, 20), (3, 30)] {
+            key_store.add_key_at(&[i; 32], added_at).unwrap();
+        }
+        assert_eq!(key_store.retain_newest_keys(5).unwrap(), 0);
+        assert_eq!(key_store.retain_newest_keys(1).unwrap(), 2);
+
+        let reopened = FileKeyStore::open(&path, b"passphrase").unwrap();
+        assert_eq!(reopened.keys().len(), 1);
+        assert_eq!(
+            reopened.newest_key().unwrap().expose_secret(),
+            &[3u8; 32][..]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_key_file_kdf_bounds() {
+        let path = std::env::temp_dir().join(format!("kagipp-kdf-{}", std::process::id()));
+        FileKeyStore::create(&path, b"passphrase", TEST_PARAMS).unwrap();
+
+        // an unauthenticated header asking for 4 TiB is refused before deriving anything
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            FileKeyStore::open(&path, b"passphrase"),
+            Err(FileKeyStoreError::KdfTooCostly(KdfParams {
+                m_cost: u32::MAX,
+                ..
+            }))
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        let too_many_passes = KdfParams {
+            t_cost: MAX_T_COST + 1,
+            ..TEST_PARAMS
+        };
+        assert!(matches!(
+            FileKeyStore::create(&path, b"passphrase", too_many_passes),
+            Err(FileKeyStoreError::KdfTooCostly(_))
+        ));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,349 @@
+// -----------------------------------------------------------------------------
+// ------------------------  generic batched tokens  ---------------------------
+// -----------------------------------------------------------------------------
+//
+// Generic batched issuance (draft-ietf-privacypass-batched-tokens): a single request carries
+// TokenRequests of any token type, here 0x0001 (P-384) and 0x0002 (blind RSA), each answered by
+// the issuance path of its type. Individual TokenResponses may be left empty, e.g. for token
+// types the issuer has no key for, the rest of the batch is still answered.
+//   BatchTokenRequest     = token_requests<0..2^16-1>, of TokenRequest of any type
+//   OptionalTokenResponse = token_response<0..2^16-1>
+//   BatchTokenResponse    = token_responses<0..2^16-1>, of OptionalTokenResponse
+// The issued tokens are ordinary tokens of their type, redeemed with `validate_token_p384`
+// and `validate_token_rsa`.
+
+use crate::crystal::{
+    decode_secret_bytes_from_crystal, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, Base64Json, JSONRetValRef,
+};
+use crate::limits::InputKind;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::private_tokens::issue_p384_token_response;
+use crate::public_tokens::issue_rsa_token_response;
+use crate::runtime::ffi_runtime;
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use privacypass::private_tokens::TokenRequest as P384TokenRequest;
+use secrecy::ExposeSecret;
+use thiserror::Error;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+
+#[derive(Error, Debug)]
+pub enum GenericBatchedError {
+    #[error("failed to deserialize batch token request")]
+    Tls(#[from] tls_codec::Error),
+    #[error("unsupported token type {0:#06x} in batch token request")]
+    UnsupportedTokenType(u16),
+    #[error("requested {0} tokens, max is {1}")]
+    RequestedTooManyTokens(usize, usize),
+}
+
+/// Secret keys of the token types a batch can be answered for, None for unsupported types
+#[derive(Default, Clone, Copy)]
+pub struct IssuerKeys<'a> {
+    /// serialized P-384 scalar, see `gen_keys_p384`
+    pub p384: Option<&'a [u8]>,
+    /// PKCS#1 DER RSA secret key, see `gen_keys_rsa`
+    pub rsa: Option<&'a [u8]>,
+}
+
+fn split_vec_u16(bytes: &[u8]) -> Result<(&[u8], &[u8]), tls_codec::Error> {
+    let Some((len_bytes, rest)) = bytes.split_first_chunk::<2>() else {
+        return Err(tls_codec::Error::EndOfStream);
+    };
+    let len = usize::from(u16::from_be_bytes(*len_bytes));
+    if rest.len() < len {
+        return Err(tls_codec::Error::EndOfStream);
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Splits a serialized BatchTokenRequest into its serialized TokenRequests. TokenRequests
+/// aren't length prefixed, so each one's size follows from its token type.
+pub fn parse_batch_token_request(bytes: &[u8]) -> Result<Vec<&[u8]>, GenericBatchedError> {
+    let (mut token_requests, rest) = split_vec_u16(bytes)?;
+    if !rest.is_empty() {
+        return Err(tls_codec::Error::TrailingData.into());
+    }
+    let mut parsed = Vec::new();
+    while let Some(token_type) = token_requests.first_chunk::<2>() {
+        let token_type = u16::from_be_bytes(*token_type);
+        let len = match token_type {
+            TOKEN_TYPE_PRIVATE_P384 => crate::private_tokens::TOKEN_REQUEST_LEN,
+            TOKEN_TYPE_PUBLIC_RSA => crate::public_tokens::TOKEN_REQUEST_LEN,
+            _ => return Err(GenericBatchedError::UnsupportedTokenType(token_type)),
+        };
+        if token_requests.len() < len {
+            return Err(tls_codec::Error::EndOfStream.into());
+        }
+        let (token_request, rest) = token_requests.split_at(len);
+        parsed.push(token_request);
+        token_requests = rest;
+    }
+    if !token_requests.is_empty() {
+        return Err(tls_codec::Error::EndOfStream.into());
+    }
+    Ok(parsed)
+}
+
+/// Answers a single TokenRequest of a batch, None if it can't be answered
+fn issue_one(
+    rt: &tokio::runtime::Handle,
+    keys: IssuerKeys,
+    token_request: &[u8],
+) -> Option<Vec<u8>> {
+    match u16::from_be_bytes(*token_request.first_chunk::<2>()?) {
+        TOKEN_TYPE_PRIVATE_P384 => {
+            let token_request = P384TokenRequest::tls_deserialize(&mut &token_request[..]).ok()?;
+            issue_p384_token_response(rt, keys.p384?, token_request)
+                .ok()?
+                .tls_serialize_detached()
+                .ok()
+        }
+        TOKEN_TYPE_PUBLIC_RSA => issue_rsa_token_response(keys.rsa?, token_request).ok(),
+        _ => None,
+    }
+}
+
+/// Issues the serialized BatchTokenResponse for a serialized BatchTokenRequest of at most
+/// `max_nr` TokenRequests. TokenRequests that can't be answered (no key for their token type,
+/// wrong key id, revoked key, malformed blinded message) get an empty TokenResponse.
+pub fn issue_batch_token_response(
+    rt: &tokio::runtime::Handle,
+    keys: IssuerKeys,
+    batch_token_request: &[u8],
+    max_nr: usize,
+) -> Result<Vec<u8>, GenericBatchedError> {
+    let token_requests = parse_batch_token_request(batch_token_request)?;
+    if token_requests.len() > max_nr {
+        return Err(GenericBatchedError::RequestedTooManyTokens(
+            token_requests.len(),
+            max_nr,
+        ));
+    }
+
+    let mut token_responses = Vec::new();
+    for token_request in token_requests {
+        let token_response = issue_one(rt, keys, token_request).unwrap_or_default();
+        token_responses.extend_from_slice(&(token_response.len() as u16).to_be_bytes());
+        token_responses.extend_from_slice(&token_response);
+    }
+    let len = u16::try_from(token_responses.len()).map_err(|_| {
+        tls_codec::Error::EncodingError("batch token response too large".to_string())
+    })?;
+    let mut batch_token_response = len.to_be_bytes().to_vec();
+    batch_token_response.extend_from_slice(&token_responses);
+    Ok(batch_token_response)
+}
+
+/// Splits a serialized BatchTokenResponse into its TokenResponses, in request order, None for
+/// TokenRequests the issuer left unanswered
+pub fn parse_batch_token_response(bytes: &[u8]) -> Result<Vec<Option<&[u8]>>, tls_codec::Error> {
+    let (mut token_responses, rest) = split_vec_u16(bytes)?;
+    if !rest.is_empty() {
+        return Err(tls_codec::Error::TrailingData);
+    }
+    let mut parsed = Vec::new();
+    while !token_responses.is_empty() {
+        let (token_response, rest) = split_vec_u16(token_responses)?;
+        parsed.push((!token_response.is_empty()).then_some(token_response));
+        token_responses = rest;
+    }
+    Ok(parsed)
+}
+
+/// Issues a (base64 encoded) BatchTokenResponse for a BatchTokenRequest of type 0x0001 and
+/// 0x0002 TokenRequests. NOTE: pass an empty string for the secret key of a token type that
+/// isn't issued, its TokenRequests get empty TokenResponses
+#[no_mangle]
+pub extern "C" fn gen_batch_token_response(
+    p384_sk_cstr: *const i8,
+    rsa_sk_cstr: *const i8,
+    batch_token_request_cstr: *const i8,
+    max_nr: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let rt = ffi_runtime()?;
+        let p384_sk = unsafe { decode_secret_bytes_from_crystal(p384_sk_cstr, InputKind::Key)? };
+        let rsa_sk = unsafe { decode_secret_bytes_from_crystal(rsa_sk_cstr, InputKind::RsaKey)? };
+        let batch_token_request = unsafe {
+            decode_untrusted_bytes_from_crystal(batch_token_request_cstr, InputKind::TokenRequest)?
+        };
+        let keys = IssuerKeys {
+            p384: Some(p384_sk.expose_secret()).filter(|sk| !sk.is_empty()),
+            rsa: Some(rsa_sk.expose_secret()).filter(|sk| !sk.is_empty()),
+        };
+        let batch_token_response =
+            issue_batch_token_response(rt.handle(), keys, &batch_token_request, max_nr.into())?;
+
+        let rv = JSONRetValRef {
+            retval: Base64Json(&batch_token_response),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::private_tokens::{serialize_public_key, validate_p384_token};
+    use crate::public_tokens::{rsa_options, verify_rsa_token, NK};
+    use crate::test_fixtures::rsa_keypair;
+    use blind_rsa_signatures::{BlindSignature, PublicKey};
+    use p384::NistP384;
+    use rand::rngs::OsRng;
+    use sha2::{Digest, Sha256};
+    use voprf::{derive_key, EvaluationElement, Group, Mode, Proof, VoprfClient, VoprfServer};
+
+    fn batch(token_requests: &[Vec<u8>]) -> Vec<u8> {
+        let token_requests = token_requests.concat();
+        let mut bytes = (token_requests.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(token_requests);
+        bytes
+    }
+
+    #[test]
+    fn test_batch_token_request_parsing() {
+        let mut rsa_request = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        rsa_request.extend([0u8; 1 + NK]);
+        let mut p384_request = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        p384_request.extend([0u8; 1 + 49]);
+        let bytes = batch(&[rsa_request.clone(), p384_request.clone()]);
+        assert_eq!(
+            parse_batch_token_request(&bytes).unwrap(),
+            vec![&rsa_request[..], &p384_request[..]]
+        );
+
+        assert!(parse_batch_token_request(&bytes[..bytes.len() - 1]).is_err());
+        assert!(matches!(
+            parse_batch_token_request(&batch(&[vec![0, 5, 0]])),
+            Err(GenericBatchedError::UnsupportedTokenType(5))
+        ));
+    }
+
+    #[test]
+    fn test_batch_issuance_and_redemption() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let p384_sk = NistP384::serialize_scalar(
+            derive_key::<NistP384>(&[3u8; 48], b"", Mode::Voprf).unwrap(),
+        );
+        let p384_pk = serialize_public_key(
+            VoprfServer::<NistP384>::new_with_key(&p384_sk)
+                .unwrap()
+                .get_public_key(),
+        );
+        let rsa_keypair = rsa_keypair();
+        let rsa_pk = PublicKey::from_spki(&rsa_keypair.public_key, Some(&rsa_options())).unwrap();
+        let challenge_digest = [9u8; 32];
+        // token_input = token_type || nonce || challenge_digest || token_key_id
+        let token_input = |token_type: u16, public_key: &[u8]| {
+            let mut token_input = token_type.to_be_bytes().to_vec();
+            token_input.extend([5u8; 32]);
+            token_input.extend(challenge_digest);
+            token_input.extend(Sha256::digest(public_key));
+            token_input
+        };
+        let token_request = |token_type: u16, public_key: &[u8], blinded: &[u8]| {
+            let mut token_request = token_type.to_be_bytes().to_vec();
+            token_request.push(*Sha256::digest(public_key).last().unwrap());
+            token_request.extend(blinded);
+            token_request
+        };
+
+        let p384_input = token_input(TOKEN_TYPE_PRIVATE_P384, &p384_pk);
+        let p384_blind = VoprfClient::<NistP384>::blind(&p384_input, &mut OsRng).unwrap();
+        let rsa_input = token_input(TOKEN_TYPE_PUBLIC_RSA, &rsa_keypair.public_key);
+        let rsa_blinding = rsa_pk
+            .blind(&mut OsRng, &rsa_input, false, &rsa_options())
+            .unwrap();
+        let bytes = batch(&[
+            token_request(
+                TOKEN_TYPE_PRIVATE_P384,
+                &p384_pk,
+                &p384_blind.message.serialize(),
+            ),
+            token_request(
+                TOKEN_TYPE_PUBLIC_RSA,
+                &rsa_keypair.public_key,
+                &rsa_blinding.blind_msg.0,
+            ),
+        ]);
+        let keys = IssuerKeys {
+            p384: Some(p384_sk.as_slice()),
+            rsa: Some(rsa_keypair.secret_key.expose_secret()),
+        };
+        let batch_token_response =
+            issue_batch_token_response(rt.handle(), keys, &bytes, 2).unwrap();
+        let token_responses = parse_batch_token_response(&batch_token_response).unwrap();
+        let [Some(p384_response), Some(rsa_response)] = token_responses[..] else {
+            panic!("a TokenRequest was left unanswered");
+        };
+
+        // each token is an ordinary token of its type
+        let (evaluated_element, proof) = p384_response.split_at(49);
+        let authenticator = p384_blind
+            .state
+            .finalize(
+                &p384_input,
+                &EvaluationElement::deserialize(evaluated_element).unwrap(),
+                &Proof::deserialize(proof).unwrap(),
+                NistP384::deserialize_elem(&p384_pk).unwrap(),
+            )
+            .unwrap();
+        let p384_token = [p384_input.as_slice(), authenticator.as_slice()].concat();
+        assert!(validate_p384_token(&p384_sk, &p384_token, Some(&challenge_digest)).unwrap());
+        let signature = rsa_pk
+            .finalize(
+                &BlindSignature::new(rsa_response.to_vec()),
+                &rsa_blinding.secret,
+                None,
+                &rsa_input,
+                &rsa_options(),
+            )
+            .unwrap();
+        let rsa_token = [rsa_input.as_slice(), signature.0.as_slice()].concat();
+        assert!(verify_rsa_token(&rsa_keypair.public_key, &rsa_token, &challenge_digest).unwrap());
+    }
+
+    #[test]
+    fn test_unanswered_token_requests() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let keypair = rsa_keypair();
+        // wrong key id and no P-384 key: both TokenRequests are left unanswered
+        let key_id = *Sha256::digest(&keypair.public_key).last().unwrap();
+        let mut rsa_request = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        rsa_request.push(key_id ^ 1);
+        rsa_request.extend([1u8; NK]);
+        let mut p384_request = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        p384_request.extend([0u8; 1 + 49]);
+        let keys = IssuerKeys {
+            p384: None,
+            rsa: Some(keypair.secret_key.expose_secret()),
+        };
+
+        let bytes = batch(&[rsa_request, p384_request]);
+        let batch_token_response =
+            issue_batch_token_response(rt.handle(), keys, &bytes, 2).unwrap();
+        assert_eq!(
+            parse_batch_token_response(&batch_token_response).unwrap(),
+            vec![None, None]
+        );
+        assert!(matches!(
+            issue_batch_token_response(rt.handle(), keys, &bytes, 1),
+            Err(GenericBatchedError::RequestedTooManyTokens(2, 1))
+        ));
+    }
+}
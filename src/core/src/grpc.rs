@@ -0,0 +1,222 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  gRPC service  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuance and redemption over gRPC (proto/privacypass.proto), for deployments where the
+// issuer runs as a sidecar rather than being linked in through the FFI. The RPCs mirror
+// the FFI functions: keys are held by the caller and passed along with every request, and
+// `RedeemToken` refuses replays like `validate_token`, see `set_replay_protection`.
+// Malformed inputs are reported as INVALID_ARGUMENT, revoked keys and keys outside of their
+// validity window as FAILED_PRECONDITION.
+// NOTE: only batched ristretto255 tokens are issued and redeemed.
+
+use crate::config::batched_tokens_mod::TokenRequest;
+use crate::server::{GenKeysError, GenTokenResponseError, PrivacyPass, ValidateTokenError};
+use proto::privacy_pass_issuer_server::{PrivacyPassIssuer, PrivacyPassIssuerServer};
+use proto::{
+    GenerateKeysRequest, GenerateKeysResponse, IssueTokenResponseRequest,
+    IssueTokenResponseResponse, RedeemTokenRequest, RedeemTokenResponse,
+};
+use secrecy::ExposeSecret;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use tonic::{Request, Response, Status};
+
+/// Protobuf wire types and service trait, generated from proto/privacypass.proto
+pub mod proto {
+    tonic::include_proto!("privacypass.v1");
+}
+
+/// Default of `GrpcIssuer::max_tokens`
+pub const DEFAULT_MAX_TOKENS: usize = 100;
+
+fn gen_keys_status(err: GenKeysError) -> Status {
+    Status::internal(err.to_string())
+}
+
+fn gen_token_response_status(err: GenTokenResponseError) -> Status {
+    match err {
+        GenTokenResponseError::RequestedTooManyTokens(..)
+        | GenTokenResponseError::InvalidTokenType
+        | GenTokenResponseError::KeyIdNotFound
+        | GenTokenResponseError::InvalidKey(_)
+        | GenTokenResponseError::Tls(_)
+        | GenTokenResponseError::DuplicateBlindedElement(_)
+        | GenTokenResponseError::InvalidBlindedElement(_) => {
+            Status::invalid_argument(err.to_string())
+        }
+        GenTokenResponseError::KeyRevoked(_) | GenTokenResponseError::KeyValidity(_) => {
+            Status::failed_precondition(err.to_string())
+        }
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+fn validate_token_status(err: ValidateTokenError) -> Status {
+    match err {
+        ValidateTokenError::InvalidKey(_) | ValidateTokenError::ChallengeDigest => {
+            Status::invalid_argument(err.to_string())
+        }
+        ValidateTokenError::KeyRevoked(_)
+        | ValidateTokenError::KeyValidity(_)
+        | ValidateTokenError::ChallengeFreshness(_)
+        | ValidateTokenError::ChallengeAuthentication(_)
+        | ValidateTokenError::ChallengeOrigin(_) => Status::failed_precondition(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+pub struct GrpcIssuer {
+    privacy_pass: PrivacyPass,
+    /// tokens a single request may ask for, unless it asks for less
+    pub max_tokens: usize,
+}
+
+impl Default for GrpcIssuer {
+    fn default() -> Self {
+        Self::new(PrivacyPass::new())
+    }
+}
+
+impl GrpcIssuer {
+    pub fn new(privacy_pass: PrivacyPass) -> Self {
+        GrpcIssuer {
+            privacy_pass,
+            max_tokens: DEFAULT_MAX_TOKENS,
+        }
+    }
+
+    /// The tonic service of this issuer, to be added to a `tonic::transport::Server`
+    pub fn into_service(self) -> PrivacyPassIssuerServer<Self> {
+        PrivacyPassIssuerServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl PrivacyPassIssuer for GrpcIssuer {
+    async fn generate_keys(
+        &self,
+        request: Request<GenerateKeysRequest>,
+    ) -> Result<Response<GenerateKeysResponse>, Status> {
+        let info = request.into_inner().info;
+        let keypair = match info.is_empty() {
+            true => self.privacy_pass.gen_keys().await,
+            false => PrivacyPass::with_info(&info).gen_keys().await,
+        }
+        .map_err(gen_keys_status)?;
+        Ok(Response::new(GenerateKeysResponse {
+            public_key: keypair.public_key.clone(),
+            secret_key: keypair.secret_key.expose_secret().to_vec(),
+            token_type: u32::from(keypair.token_type as u16),
+        }))
+    }
+
+    async fn issue_token_response(
+        &self,
+        request: Request<IssueTokenResponseRequest>,
+    ) -> Result<Response<IssueTokenResponseResponse>, Status> {
+        let request = request.into_inner();
+        let max_tokens = match usize::try_from(request.max_tokens) {
+            Ok(0) | Err(_) => self.max_tokens,
+            Ok(max_tokens) => max_tokens.min(self.max_tokens),
+        };
+        let token_request = TokenRequest::tls_deserialize(&mut request.token_request.as_slice())
+            .map_err(|_| Status::invalid_argument("malformed token request"))?;
+        let token_response = self
+            .privacy_pass
+            .gen_token_response(&request.secret_key, token_request, max_tokens)
+            .await
+            .map_err(gen_token_response_status)?;
+        let token_response = token_response
+            .tls_serialize_detached()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(IssueTokenResponseResponse { token_response }))
+    }
+
+    async fn redeem_token(
+        &self,
+        request: Request<RedeemTokenRequest>,
+    ) -> Result<Response<RedeemTokenResponse>, Status> {
+        let request = request.into_inner();
+        let token_challenge = Some(request.token_challenge.as_str()).filter(|s| !s.is_empty());
+        let valid = self
+            .privacy_pass
+            .redeem_token(&request.token, &request.secret_key, token_challenge)
+            .await
+            .map_err(validate_token_status)?;
+        Ok(Response::new(RedeemTokenResponse { valid }))
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{batched_tokens_mod, VoprfGroup};
+    use batched_tokens_mod::client::Client;
+    use batched_tokens_mod::server::deserialize_public_key;
+    use batched_tokens_mod::TokenResponse;
+    use rand::rngs::OsRng;
+    use voprf::Group;
+
+    #[tokio::test]
+    async fn test_grpc_issuer() {
+        let issuer = GrpcIssuer::default();
+        let keys = issuer
+            .generate_keys(Request::new(GenerateKeysRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(keys.token_type, 5);
+
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let client = Client::new(deserialize_public_key(&keys.public_key).unwrap());
+        let nonces = (0u8..2).map(|i| [i; 32]).collect();
+        let blinds = (0..2)
+            .map(|_| <VoprfGroup as Group>::Scalar::random(&mut OsRng))
+            .collect();
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(&token_challenge, nonces, blinds)
+            .unwrap();
+        let token_request = token_request.tls_serialize_detached().unwrap();
+
+        let too_many = issuer
+            .issue_token_response(Request::new(IssueTokenResponseRequest {
+                secret_key: keys.secret_key.clone(),
+                token_request: token_request.clone(),
+                max_tokens: 1,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(too_many.code(), tonic::Code::InvalidArgument);
+
+        let token_response = issuer
+            .issue_token_response(Request::new(IssueTokenResponseRequest {
+                secret_key: keys.secret_key.clone(),
+                token_request,
+                max_tokens: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .token_response;
+        let token_response =
+            TokenResponse::tls_deserialize(&mut token_response.as_slice()).unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+
+        let token_challenge = token_challenge.to_base64().unwrap();
+        let redeem = |token: Vec<u8>| {
+            issuer.redeem_token(Request::new(RedeemTokenRequest {
+                secret_key: keys.secret_key.clone(),
+                token,
+                token_challenge: token_challenge.clone(),
+            }))
+        };
+        let token = tokens[0].tls_serialize_detached().unwrap();
+        assert!(redeem(token.clone()).await.unwrap().into_inner().valid);
+        assert!(!redeem(token).await.unwrap().into_inner().valid);
+        assert!(!redeem(vec![0u8; 4]).await.unwrap().into_inner().valid);
+    }
+}
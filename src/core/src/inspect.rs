@@ -0,0 +1,237 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  introspection  ---------------------------------
+// -----------------------------------------------------------------------------
+//
+// Decoding of serialized protocol messages into their fields, without validating them, for
+// debugging, logging and support tooling:
+//   TokenInfo          token type, key ids, nonce and challenge digest of a token
+//                      (FFI: inspect_token)
+//   TokenRequestInfo   token type, truncated key id and number of tokens of a TokenRequest
+//                      (FFI: inspect_token_request), cheap enough to route requests or
+//                      enforce quotas on before paying for issuance
+// Nothing here needs a key, and what is decoded is attacker controlled: only use it to look
+// at messages, never to decide whether to accept them.
+
+use crate::config::{batched_tokens_mod, BatchedP384TokenType, GroupTokenType};
+use crate::crystal::{
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use crate::limits::InputKind;
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use kagippverify::{ParseError, Token};
+use serde::{Deserialize, Serialize};
+
+/// Fields of a token, binary ones hex encoded in JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub token_type: u16,
+    pub truncated_token_key_id: u8,
+    #[serde(with = "hex")]
+    pub token_key_id: [u8; 32],
+    #[serde(with = "hex")]
+    pub nonce: [u8; 32],
+    #[serde(with = "hex")]
+    pub challenge_digest: [u8; 32],
+}
+
+impl TokenInfo {
+    /// Fields of the serialized `token`, which is not validated
+    pub fn from_token(token: &[u8]) -> Result<Self, ParseError> {
+        let token = Token::parse(token)?;
+        let [.., truncated_token_key_id] = *token.token_key_id;
+        Ok(TokenInfo {
+            token_type: token.token_type,
+            truncated_token_key_id,
+            token_key_id: *token.token_key_id,
+            nonce: *token.nonce,
+            challenge_digest: *token.challenge_digest,
+        })
+    }
+}
+
+/// Header fields of a TokenRequest, without its blinded elements
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenRequestInfo {
+    pub token_type: u16,
+    pub truncated_token_key_id: u8,
+    /// number of tokens requested, i.e. of blinded elements
+    pub nr: usize,
+}
+
+impl TokenRequestInfo {
+    /// Fields of the serialized `token_request`, of any supported token type. Only lengths are
+    /// checked, the blinded elements are not decoded.
+    pub fn from_token_request(token_request: &[u8]) -> Result<Self, ParseError> {
+        let [t0, t1, truncated_token_key_id, rest @ ..] = token_request else {
+            return Err(ParseError::Truncated);
+        };
+        let token_type = u16::from_be_bytes([*t0, *t1]);
+        let nr = match token_type {
+            // token_type || truncated_token_key_id || blinded_msg
+            TOKEN_TYPE_PRIVATE_P384 => single_token_request_nr(
+                token_request.len(),
+                crate::private_tokens::TOKEN_REQUEST_LEN,
+            )?,
+            TOKEN_TYPE_PUBLIC_RSA => single_token_request_nr(
+                token_request.len(),
+                crate::public_tokens::TOKEN_REQUEST_LEN,
+            )?,
+            // token_type || truncated_token_key_id || blinded_elements<0..2^16-1>
+            token_type if token_type == BatchedP384TokenType as u16 => {
+                batched_token_request_nr(rest, crate::batched_p384::NE)?
+            }
+            token_type if token_type == GroupTokenType as u16 => {
+                batched_token_request_nr(rest, batched_tokens_mod::NE)?
+            }
+            token_type => return Err(ParseError::UnknownTokenType(token_type)),
+        };
+        Ok(TokenRequestInfo {
+            token_type,
+            truncated_token_key_id: *truncated_token_key_id,
+            nr,
+        })
+    }
+}
+
+fn single_token_request_nr(len: usize, expected_len: usize) -> Result<usize, ParseError> {
+    match len.cmp(&expected_len) {
+        std::cmp::Ordering::Less => Err(ParseError::Truncated),
+        std::cmp::Ordering::Greater => Err(ParseError::TrailingBytes),
+        std::cmp::Ordering::Equal => Ok(1),
+    }
+}
+
+fn batched_token_request_nr(rest: &[u8], ne: usize) -> Result<usize, ParseError> {
+    let [l0, l1, blinded_elements @ ..] = rest else {
+        return Err(ParseError::Truncated);
+    };
+    let len = usize::from(u16::from_be_bytes([*l0, *l1]));
+    if blinded_elements.len() > len {
+        return Err(ParseError::TrailingBytes);
+    }
+    // a partial blinded element is as truncated as a missing one
+    if blinded_elements.len() < len || len % ne != 0 {
+        return Err(ParseError::Truncated);
+    }
+    Ok(len / ne)
+}
+
+/// Returns the fields of the (base64) token as JSON, e.g.
+/// {"token_type":5,"truncated_token_key_id":12,"token_key_id":"…","nonce":"…",
+/// "challenge_digest":"…"}, see `TokenInfo::from_token`
+#[no_mangle]
+pub extern "C" fn inspect_token(token_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token = unsafe { decode_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&TokenInfo::from_token(&token)?)?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns the header fields of the (base64) TokenRequest as JSON, e.g.
+/// {"token_type":5,"truncated_token_key_id":12,"nr":10}, see
+/// `TokenRequestInfo::from_token_request`
+#[no_mangle]
+pub extern "C" fn inspect_token_request(token_request_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_request = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&TokenRequestInfo::from_token_request(&token_request)?)?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kagippverify::token::TOKEN_TYPE_BATCHED_RISTRETTO255;
+
+    #[test]
+    fn test_inspect_token() {
+        let mut token = vec![0x00, 0x05];
+        token.extend([1u8; 32]);
+        token.extend([2u8; 32]);
+        let mut token_key_id = [3u8; 32];
+        token_key_id[31] = 0x2a;
+        token.extend(token_key_id);
+        token.extend([4u8; 64]);
+
+        let info = TokenInfo::from_token(&token).unwrap();
+        assert_eq!(info.token_type, TOKEN_TYPE_BATCHED_RISTRETTO255);
+        assert_eq!(info.truncated_token_key_id, 0x2a);
+        assert_eq!(info.nonce, [1u8; 32]);
+        let json: serde_json::Value = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["challenge_digest"], hex::encode([2u8; 32]));
+        assert_eq!(serde_json::from_value::<TokenInfo>(json).unwrap(), info);
+
+        assert_eq!(
+            TokenInfo::from_token(&token[1..]),
+            Err(ParseError::UnknownTokenType(0x0501))
+        );
+        assert_eq!(
+            TokenInfo::from_token(&token[..token.len() - 1]),
+            Err(ParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_inspect_token_request() {
+        let mut token_request = vec![0x00, 0x05, 0x2a];
+        token_request.extend(((3 * batched_tokens_mod::NE) as u16).to_be_bytes());
+        token_request.extend(vec![1u8; 3 * batched_tokens_mod::NE]);
+        assert_eq!(
+            TokenRequestInfo::from_token_request(&token_request).unwrap(),
+            TokenRequestInfo {
+                token_type: TOKEN_TYPE_BATCHED_RISTRETTO255,
+                truncated_token_key_id: 0x2a,
+                nr: 3,
+            }
+        );
+        assert_eq!(
+            TokenRequestInfo::from_token_request(&token_request[..token_request.len() - 1]),
+            Err(ParseError::Truncated)
+        );
+        token_request.push(0);
+        assert_eq!(
+            TokenRequestInfo::from_token_request(&token_request),
+            Err(ParseError::TrailingBytes)
+        );
+
+        let mut token_request = vec![0x00, 0x02, 0x07];
+        token_request.extend(vec![1u8; crate::public_tokens::TOKEN_REQUEST_LEN - 3]);
+        let info = TokenRequestInfo::from_token_request(&token_request).unwrap();
+        assert_eq!((info.token_type, info.nr), (TOKEN_TYPE_PUBLIC_RSA, 1));
+        assert_eq!(
+            TokenRequestInfo::from_token_request(&[0x00, 0x42, 0x00]),
+            Err(ParseError::UnknownTokenType(0x0042))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// -----------------------------------------------------------------------------
+// -----------------------------  issuance  ------------------------------------
+// -----------------------------------------------------------------------------
+//
+// TokenRequest parsing and TokenResponse serialization shared by the issuers that evaluate
+// blinded elements themselves: the server module (and the handles and signers built on it)
+// and the stateless serverless functions, which can't use the server module on
+// WebAssembly. Clients read which blinded elements issuers answer from here too.
+// Everything here is synchronous and runtime free.
+
+use crate::config::{batched_tokens_mod, VoprfGroup};
+use batched_tokens_mod::server::serialize_public_key;
+use batched_tokens_mod::{PublicKey, TokenRequest, NE};
+use privacypass::{TokenType, TruncatedTokenKeyId};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use thiserror::Error;
+use tls_codec::{
+    Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait, TlsByteVecU16,
+};
+use voprf::{BlindedElement, EvaluationElement, Proof};
+
+/// Borrowed view over a serialized TokenRequest.
+/// Blinded elements are exposed as slices of the decoded request buffer instead of being
+/// copied one by one into an owned `TlsVecU16`, which matters for large batches.
+#[derive(Debug)]
+pub struct TokenRequestView<'a> {
+    token_type: TokenType,
+    truncated_token_key_id: TruncatedTokenKeyId,
+    blinded_elements: &'a [u8],
+}
+impl<'a> TokenRequestView<'a> {
+    /// Parses `token_type || truncated_token_key_id || blinded_elements<0..2^16-1>`
+    /// without copying the blinded elements
+    pub fn try_from_bytes(bytes: &'a [u8]) -> Result<Self, tls_codec::Error> {
+        let mut header = bytes;
+        let token_type = TokenType::tls_deserialize(&mut header)?;
+        let (truncated_token_key_id, rest) = match header.split_first() {
+            Some((id, rest)) => Ok((*id, rest)),
+            None => Err(tls_codec::Error::EndOfStream),
+        }?;
+        let Some((len_bytes, rest)) = rest.split_first_chunk::<2>() else {
+            return Err(tls_codec::Error::EndOfStream);
+        };
+        let len = usize::from(u16::from_be_bytes(*len_bytes));
+        if rest.len() < len {
+            return Err(tls_codec::Error::EndOfStream);
+        }
+        if rest.len() > len {
+            return Err(tls_codec::Error::TrailingData);
+        }
+        if len % NE != 0 {
+            return Err(tls_codec::Error::DecodingError(
+                "blinded elements length is not a multiple of the element size".to_string(),
+            ));
+        }
+        Ok(TokenRequestView {
+            token_type,
+            truncated_token_key_id,
+            blinded_elements: rest,
+        })
+    }
+
+    /// Token type the client asks to be issued tokens of
+    pub fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+
+    /// Returns the number of blinded elements
+    #[must_use]
+    pub fn nr(&self) -> usize {
+        self.blinded_elements.len() / NE
+    }
+
+    /// Truncated key id of the key the client asks to be issued tokens with
+    pub fn truncated_token_key_id(&self) -> TruncatedTokenKeyId {
+        self.truncated_token_key_id
+    }
+
+    /// Iterates over the serialized blinded elements
+    pub fn blinded_elements(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.blinded_elements.chunks_exact(NE)
+    }
+
+    pub fn truncate(&mut self, max_elements: usize) {
+        if let Some(truncated) = max_elements
+            .checked_mul(NE)
+            .and_then(|len| self.blinded_elements.get(..len))
+        {
+            self.blinded_elements = truncated;
+        }
+    }
+
+    pub fn to_token_request(&self) -> Result<TokenRequest, tls_codec::Error> {
+        let mut res_vec = self.token_type.tls_serialize_detached()?;
+        res_vec.reserve(3 + self.blinded_elements.len());
+        res_vec.push(self.truncated_token_key_id);
+        res_vec.extend_from_slice(&(self.blinded_elements.len() as u16).to_be_bytes());
+        res_vec.extend_from_slice(self.blinded_elements);
+        TokenRequest::tls_deserialize(&mut res_vec.as_slice())
+    }
+
+    /// Indices of the first occurrence of each blinded element, in request order
+    pub fn first_occurrences(&self) -> Vec<usize> {
+        first_occurrences(self.blinded_elements())
+    }
+
+    /// Index of the first blinded element repeating an earlier one, if any
+    pub fn first_duplicate(&self) -> Option<usize> {
+        let mut seen = HashSet::with_capacity(self.nr());
+        self.blinded_elements()
+            .position(|blinded_element| !seen.insert(blinded_element))
+    }
+
+    /// Serializes the TokenRequest, keeping only the first occurrence of each blinded element
+    pub fn to_deduplicated_bytes(&self) -> Result<Vec<u8>, tls_codec::Error> {
+        let mut seen = HashSet::with_capacity(self.nr());
+        let blinded_elements: Vec<u8> = self
+            .blinded_elements()
+            .filter(|blinded_element| seen.insert(*blinded_element))
+            .flatten()
+            .copied()
+            .collect();
+        let mut res_vec = self.token_type.tls_serialize_detached()?;
+        res_vec.reserve(3 + blinded_elements.len());
+        res_vec.push(self.truncated_token_key_id);
+        res_vec.extend_from_slice(&(blinded_elements.len() as u16).to_be_bytes());
+        res_vec.extend_from_slice(&blinded_elements);
+        Ok(res_vec)
+    }
+}
+
+/// Index of a blinded element repeating an earlier one of the same TokenRequest
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("blinded element {0} repeats an earlier one")]
+pub struct DuplicateBlindedElementError(pub usize);
+
+/// Blinded element of a TokenRequest that can't be evaluated, by index
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InvalidBlindedElementError {
+    #[error("blinded element {0} is not a valid group element encoding")]
+    InvalidEncoding(usize),
+    #[error("blinded element {0} is the identity element")]
+    Identity(usize),
+}
+
+impl InvalidBlindedElementError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            InvalidBlindedElementError::InvalidEncoding(_) => "blinded_element_invalid_encoding",
+            InvalidBlindedElementError::Identity(_) => "blinded_element_identity",
+        }
+    }
+}
+
+/// Indices of the first occurrence of each of the serialized `blinded_elements` of a
+/// TokenRequest, in request order: the blinded elements issuers answer when they drop
+/// repeated ones, as clients finalizing their tokens need to know
+pub(crate) fn first_occurrences<'a>(
+    blinded_elements: impl Iterator<Item = &'a [u8]>,
+) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    blinded_elements
+        .enumerate()
+        .filter(|(_, blinded_element)| seen.insert(*blinded_element))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// ristretto255 encodings are canonical, the identity's being all zeroes
+const IDENTITY_ELEMENT: [u8; NE] = [0u8; NE];
+
+/// Deserializes the blinded elements of `token_request`, refusing the identity and anything
+/// that doesn't decode to a group element before any of them gets evaluated
+pub(crate) fn deserialize_blinded_elements(
+    token_request: &TokenRequestView,
+) -> Result<Vec<BlindedElement<VoprfGroup>>, InvalidBlindedElementError> {
+    token_request
+        .blinded_elements()
+        .enumerate()
+        .map(|(index, blinded_element)| {
+            if blinded_element == IDENTITY_ELEMENT {
+                return Err(InvalidBlindedElementError::Identity(index));
+            }
+            BlindedElement::<VoprfGroup>::deserialize(blinded_element)
+                .map_err(|_| InvalidBlindedElementError::InvalidEncoding(index))
+        })
+        .collect()
+}
+
+/// Computes the token key id of an issuer public key, i.e. SHA256(serialize_public_key(pk))
+pub(crate) fn public_key_to_token_key_id(public_key: PublicKey) -> [u8; 32] {
+    Sha256::digest(serialize_public_key(public_key)).into()
+}
+
+/// Computes the truncated token key id of an issuer public key,
+/// i.e. the last byte of its token key id, as done by privacypass-rust
+pub(crate) fn public_key_to_truncated_token_key_id(public_key: PublicKey) -> TruncatedTokenKeyId {
+    let [.., truncated_token_key_id] = public_key_to_token_key_id(public_key);
+    truncated_token_key_id
+}
+
+/// Serializes `evaluated_elements || evaluated_proof[Ns + Ns]`, the TokenResponse of
+/// `messages` and their DLEQ `proof`
+pub(crate) fn serialize_token_response(
+    messages: &[EvaluationElement<VoprfGroup>],
+    proof: &Proof<VoprfGroup>,
+) -> Result<Vec<u8>, tls_codec::Error> {
+    let evaluated_elements = TlsByteVecU16::new(
+        messages
+            .iter()
+            .flat_map(|message| message.serialize())
+            .collect(),
+    );
+    let mut token_response = evaluated_elements.tls_serialize_detached()?;
+    token_response.extend_from_slice(&proof.serialize());
+    Ok(token_response)
+}
@@ -0,0 +1,422 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  issuer directory  ------------------------------
+// -----------------------------------------------------------------------------
+//
+// The RFC 9578 issuer directory, served at /.well-known/private-token-issuer-directory,
+// through which clients learn the issuer request URI and the keys tokens are issued with:
+//   {"issuer-request-uri":"https://issuer.example/token-request",
+//    "token-keys":[{"token-type":5,"token-key":"<base64url>","not-before":1700000000}]}
+// Token keys are the serialized public keys of `gen_keys`, base64url encoded with padding.
+// Issuers generate it from their active keys: a key's "not-before" is the start of its
+// validity window, see `set_key_validity`, and is left out for keys without one. Keys are
+// listed in the order given, preferred key first, and logged in the transparency log as they
+// get listed.
+// Clients, including those of third-party issuers, parse it with `IssuerDirectory::from_json`,
+// which checks every key of a known token type is a valid key of that type, and pick the key
+// to request tokens with for a challenge with `select_key`. Keys of token types this library
+// does not know are kept, but never selected. Client builds without the server feature only
+// know batched Ristretto255 tokens, the only ones they can request.
+// The parsing and selection functions are shared by server and client builds, while the
+// generation from active keys and its FFI are server only.
+
+use crate::config::batched_tokens_mod;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use http::header::HeaderValue;
+use kagippverify::token::TOKEN_TYPE_BATCHED_RISTRETTO255;
+#[cfg(feature = "server")]
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use privacypass::auth::authenticate::parse_www_authenticate_header;
+#[cfg(feature = "server")]
+use privacypass::TokenType;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[cfg(feature = "server")]
+const TOKEN_TYPE_BATCHED_P384: u16 = TokenType::BatchedTokenP384 as u16;
+
+#[derive(Error, Debug)]
+pub enum IssuerDirectoryError {
+    #[error("malformed issuer directory")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid issuer request URI")]
+    InvalidUri(#[from] http::uri::InvalidUri),
+    #[error("malformed base64url token key")]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid token key for token type {0}")]
+    InvalidKey(u16),
+    #[error("unsupported token type {0}")]
+    UnsupportedTokenType(u16),
+    #[error("malformed WWW-Authenticate header")]
+    InvalidHeader,
+    #[error("expected a single TokenChallenge in the WWW-Authenticate header")]
+    ChallengeCount,
+    #[error("no usable key for token type {0} in the issuer directory")]
+    NoKey(u16),
+    #[error("the challenge names a key that is not an active key of the issuer directory")]
+    UnknownKey,
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+    #[error("failed to log token key in the transparency log")]
+    TransparencyLog(#[from] crate::transparency::TransparencyLogFileError),
+}
+
+/// Checks `token_key` is a valid serialized public key of `token_type`
+fn check_token_key(token_type: u16, token_key: &[u8]) -> Result<(), IssuerDirectoryError> {
+    let valid = match token_type {
+        TOKEN_TYPE_BATCHED_RISTRETTO255 => {
+            batched_tokens_mod::server::deserialize_public_key(token_key).is_ok()
+        }
+        #[cfg(feature = "server")]
+        TOKEN_TYPE_PRIVATE_P384 | TOKEN_TYPE_BATCHED_P384 => {
+            p384::PublicKey::from_sec1_bytes(token_key).is_ok()
+        }
+        #[cfg(feature = "server")]
+        TOKEN_TYPE_PUBLIC_RSA => blind_rsa_signatures::PublicKey::from_spki(
+            token_key,
+            Some(&blind_rsa_signatures::Options::default()),
+        )
+        .is_ok(),
+        _ => return Err(IssuerDirectoryError::UnsupportedTokenType(token_type)),
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(IssuerDirectoryError::InvalidKey(token_type)),
+    }
+}
+
+fn is_known_token_type(token_type: u16) -> bool {
+    #[cfg(feature = "server")]
+    let known = [
+        TOKEN_TYPE_BATCHED_RISTRETTO255,
+        TOKEN_TYPE_PRIVATE_P384,
+        TOKEN_TYPE_BATCHED_P384,
+        TOKEN_TYPE_PUBLIC_RSA,
+    ];
+    #[cfg(not(feature = "server"))]
+    let known = [TOKEN_TYPE_BATCHED_RISTRETTO255];
+    known.contains(&token_type)
+}
+
+/// Key of an issuer directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryTokenKey {
+    #[serde(rename = "token-type")]
+    pub token_type: u16,
+    /// base64url encoded serialized public key
+    #[serde(rename = "token-key")]
+    pub token_key: String,
+    /// unix seconds
+    #[serde(
+        rename = "not-before",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub not_before: Option<u64>,
+}
+
+impl DirectoryTokenKey {
+    /// Serialized public key, checked to be a valid key of its token type
+    pub fn public_key(&self) -> Result<Vec<u8>, IssuerDirectoryError> {
+        let public_key = URL_SAFE.decode(&self.token_key)?;
+        check_token_key(self.token_type, &public_key)?;
+        Ok(public_key)
+    }
+
+    /// Whether the key is in use at `now` (in unix seconds)
+    pub fn is_active(&self, now: u64) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= now)
+    }
+}
+
+/// RFC 9578 issuer directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuerDirectory {
+    /// absolute, or relative to the URL the directory was fetched from
+    #[serde(rename = "issuer-request-uri")]
+    pub issuer_request_uri: String,
+    #[serde(rename = "token-keys")]
+    pub token_keys: Vec<DirectoryTokenKey>,
+}
+
+impl IssuerDirectory {
+    pub fn new(issuer_request_uri: &str, token_keys: Vec<DirectoryTokenKey>) -> Self {
+        IssuerDirectory {
+            issuer_request_uri: issuer_request_uri.to_string(),
+            token_keys,
+        }
+    }
+
+    /// Parses an issuer directory as fetched from an issuer, refusing it if its issuer
+    /// request URI is not a URI or any of its keys of a known token type is invalid
+    pub fn from_json(json: &str) -> Result<Self, IssuerDirectoryError> {
+        let directory: IssuerDirectory = serde_json::from_str(json)?;
+        directory.issuer_request_uri.parse::<http::Uri>()?;
+        for token_key in &directory.token_keys {
+            if is_known_token_type(token_key.token_type) {
+                token_key.public_key()?;
+            }
+        }
+        Ok(directory)
+    }
+
+    /// Key to request tokens of `token_type` with at `now` (in unix seconds). When the
+    /// challenge names a key (`challenge_token_key`, serialized), it must be an active key
+    /// of the directory; otherwise the active key with the latest "not-before" is picked,
+    /// the first listed one on ties.
+    pub fn select_key(
+        &self,
+        token_type: u16,
+        challenge_token_key: Option<&[u8]>,
+        now: u64,
+    ) -> Result<&DirectoryTokenKey, IssuerDirectoryError> {
+        if !is_known_token_type(token_type) {
+            return Err(IssuerDirectoryError::UnsupportedTokenType(token_type));
+        }
+        let mut active = self
+            .token_keys
+            .iter()
+            .filter(|token_key| token_key.token_type == token_type && token_key.is_active(now));
+        match challenge_token_key {
+            Some(challenge_token_key) => active
+                .find(|token_key| {
+                    token_key
+                        .public_key()
+                        .is_ok_and(|public_key| public_key == challenge_token_key)
+                })
+                .ok_or(IssuerDirectoryError::UnknownKey),
+            // max_by_key keeps the last maximum, rev makes it the first listed one
+            None => active
+                .rev()
+                .max_by_key(|token_key| token_key.not_before.unwrap_or(0))
+                .ok_or(IssuerDirectoryError::NoKey(token_type)),
+        }
+    }
+
+    /// Like `select_key`, for the single TokenChallenge of a WWW-Authenticate header, as
+    /// taken by `gen_token_request`
+    pub fn select_key_for_header(
+        &self,
+        www_authenticate_header: &str,
+        now: u64,
+    ) -> Result<&DirectoryTokenKey, IssuerDirectoryError> {
+        let header_value = HeaderValue::from_str(www_authenticate_header)
+            .map_err(|_| IssuerDirectoryError::InvalidHeader)?;
+        let challenges = parse_www_authenticate_header(&header_value)
+            .map_err(|_| IssuerDirectoryError::InvalidHeader)?;
+        let [challenge] = challenges.as_slice() else {
+            return Err(IssuerDirectoryError::ChallengeCount);
+        };
+        let token_type = challenge.token_challenge().token_type() as u16;
+        self.select_key(token_type, Some(challenge.token_key()), now)
+    }
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use global::*;
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod global {
+    use super::{check_token_key, DirectoryTokenKey, IssuerDirectory, IssuerDirectoryError};
+    use crate::config::BatchedGroup;
+    use crate::crystal::{
+        decode_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+        error_json_retval, JSONRetValRef,
+    };
+    use crate::key_validity::global_key_validities;
+    use crate::transparency::publish_key;
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use serde::Deserialize;
+    use sha2::{Digest, Sha256};
+
+    impl DirectoryTokenKey {
+        /// Directory entry of a public key of `token_type`, serialized as by `gen_keys`.
+        /// Pass 0 as `token_type` for batched ristretto255 (0x0005), as in `gen_keys`.
+        /// "not-before" comes from the validity window of the key, if it has one. The key is
+        /// logged in the process-wide transparency log as published, if it isn't yet.
+        pub fn new(token_type: u16, public_key: &[u8]) -> Result<Self, IssuerDirectoryError> {
+            let token_type = match token_type {
+                0 => BatchedGroup::default().token_type() as u16,
+                _ => token_type,
+            };
+            check_token_key(token_type, public_key)?;
+            publish_key(public_key)?;
+            let token_key_id: [u8; 32] = Sha256::digest(public_key).into();
+            Ok(DirectoryTokenKey {
+                token_type,
+                token_key: URL_SAFE.encode(public_key),
+                not_before: global_key_validities().get(&token_key_id).not_before,
+            })
+        }
+    }
+
+    impl IssuerDirectory {
+        /// Directory of the active keys, given as (token type, serialized public key) pairs,
+        /// preferred key first
+        pub fn from_public_keys<'a>(
+            issuer_request_uri: &str,
+            public_keys: impl IntoIterator<Item = (u16, &'a [u8])>,
+        ) -> Result<Self, IssuerDirectoryError> {
+            let token_keys = public_keys
+                .into_iter()
+                .map(|(token_type, public_key)| DirectoryTokenKey::new(token_type, public_key))
+                .collect::<Result<_, _>>()?;
+            Ok(Self::new(issuer_request_uri, token_keys))
+        }
+    }
+
+    /// Active key as given by Crystal
+    #[derive(Deserialize)]
+    struct ActiveKey {
+        token_type: u16,
+        /// base64 encoded
+        public_key: String,
+    }
+
+    /// Returns the issuer directory JSON of the active keys, to be served as is (with the
+    /// application/private-token-issuer-directory media type). `token_keys_cstr` is a JSON
+    /// array of the active keys, preferred key first, e.g.
+    /// [{"token_type":5,"public_key":"<base64 key>"}], see `gen_keys` for token types
+    #[no_mangle]
+    pub extern "C" fn issuer_directory(
+        issuer_request_uri_cstr: *const i8,
+        token_keys_cstr: *const i8,
+    ) -> *const i8 {
+        // NOTE: the value of result below would not be *const i8
+        //       if the begin_panic_handling and end_panic_handling macros where not there
+        begin_panic_handling!();
+        let result = panic::catch_unwind(|| {
+            let issuer_request_uri =
+                unsafe { decode_string_from_crystal(issuer_request_uri_cstr)? };
+            let token_keys_s = unsafe { decode_string_from_crystal(token_keys_cstr)? };
+            let active_keys: Vec<ActiveKey> = serde_json::from_str(&token_keys_s)?;
+            let token_keys = active_keys
+                .iter()
+                .map(|key| {
+                    let public_key = URL_SAFE.decode(&key.public_key)?;
+                    DirectoryTokenKey::new(key.token_type, &public_key)
+                })
+                .collect::<Result<_, IssuerDirectoryError>>()?;
+            let directory_json =
+                serde_json::to_string(&IssuerDirectory::new(&issuer_request_uri, token_keys))?;
+
+            let rv = JSONRetValRef {
+                retval: directory_json.as_str(),
+                error: "",
+            };
+            let out = encode_json_for_crystal(&rv)?;
+
+            // always end like this
+            Ok::<*const i8, Box<dyn std::error::Error>>(out)
+        });
+        end_panic_handling!();
+        result
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VoprfGroup;
+    use kagippverify::token::TOKEN_TYPE_PUBLIC_RSA;
+    use voprf::VoprfServer;
+
+    fn ristretto255_public_key(seed: u8) -> Vec<u8> {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[seed; 32], b"directory").unwrap();
+        batched_tokens_mod::server::serialize_public_key(server.get_public_key()).to_vec()
+    }
+
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_issuer_directory() {
+        use crate::key_validity::{global_key_validities, KeyValidity};
+        use sha2::{Digest, Sha256};
+
+        let public_keys: Vec<Vec<u8>> = [4u8, 5].map(ristretto255_public_key).to_vec();
+        let token_key_id: [u8; 32] = Sha256::digest(&public_keys[1]).into();
+        global_key_validities().set(
+            token_key_id,
+            KeyValidity::from_unix_seconds(1_700_000_000, 0),
+        );
+
+        let token_type = TOKEN_TYPE_BATCHED_RISTRETTO255;
+        let directory = IssuerDirectory::from_public_keys(
+            "https://issuer.example/token-request",
+            public_keys
+                .iter()
+                .map(|public_key| (token_type, public_key.as_slice())),
+        )
+        .unwrap();
+        global_key_validities().set(token_key_id, KeyValidity::default());
+
+        let json: serde_json::Value = serde_json::to_value(&directory).unwrap();
+        assert_eq!(
+            json["issuer-request-uri"],
+            "https://issuer.example/token-request"
+        );
+        assert_eq!(json["token-keys"][0]["token-type"], token_type);
+        assert_eq!(
+            json["token-keys"][0]["token-key"],
+            URL_SAFE.encode(&public_keys[0])
+        );
+        assert_eq!(json["token-keys"][1]["not-before"], 1_700_000_000u64);
+        // listed keys got logged in the transparency log
+        let logged = crate::transparency::global_log_entries();
+        assert!(public_keys
+            .iter()
+            .all(|public_key| logged.iter().any(|entry| &entry.public_key == public_key)));
+        assert!(IssuerDirectory::from_public_keys(
+            "https://issuer.example",
+            [(token_type, &b"x"[..])]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_issuer_directory_key_selection() {
+        let [old, new, later] = [4u8, 5, 6].map(ristretto255_public_key);
+        let json = serde_json::json!({
+            "issuer-request-uri": "https://issuer.example/token-request",
+            "token-keys": [
+                {"token-type": 5, "token-key": URL_SAFE.encode(&old), "not-before": 100},
+                {"token-type": 5, "token-key": URL_SAFE.encode(&new), "not-before": 200},
+                {"token-type": 5, "token-key": URL_SAFE.encode(&later), "not-before": 300},
+                // unknown token types are skipped rather than refused
+                {"token-type": 0xBEEF, "token-key": "AAAA"},
+            ]
+        })
+        .to_string();
+        let directory = IssuerDirectory::from_json(&json).unwrap();
+        let token_type = TOKEN_TYPE_BATCHED_RISTRETTO255;
+
+        let selected = directory.select_key(token_type, None, 250).unwrap();
+        assert_eq!(selected.public_key().unwrap(), new);
+        let selected = directory.select_key(token_type, Some(&old), 250).unwrap();
+        assert_eq!(selected.public_key().unwrap(), old);
+        assert!(matches!(
+            directory.select_key(token_type, Some(&later), 250),
+            Err(IssuerDirectoryError::UnknownKey)
+        ));
+        assert!(matches!(
+            directory.select_key(token_type, None, 50),
+            Err(IssuerDirectoryError::NoKey(_))
+        ));
+        let rsa_key = directory.select_key(TOKEN_TYPE_PUBLIC_RSA, None, 250);
+        #[cfg(feature = "server")]
+        assert!(matches!(rsa_key, Err(IssuerDirectoryError::NoKey(_))));
+        // client builds only know batched Ristretto255 tokens
+        #[cfg(not(feature = "server"))]
+        assert!(matches!(
+            rsa_key,
+            Err(IssuerDirectoryError::UnsupportedTokenType(_))
+        ));
+
+        let invalid = json.replace(&URL_SAFE.encode(&new), &URL_SAFE.encode(b"not a key"));
+        assert!(matches!(
+            IssuerDirectory::from_json(&invalid),
+            Err(IssuerDirectoryError::InvalidKey(5))
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+// -----------------------------------------------------------------------------
+// --------------------------------  JWK  --------------------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuer public keys as RFC 7517 JSON Web Keys, so that they can be published through
+// existing JWKS infrastructure. Every key carries its token key id as "kid" (base64url,
+// like every other JWK member), which is what tokens name their issuer key by.
+// - P-384 keys (token type 0x0001 and batched P-384) are "EC" keys over "P-384";
+// - blind RSA keys (token type 0x0002) are "RSA" keys;
+// - ristretto255 has no registered JWK curve, so its keys are "OKP" keys over a
+//   "ristretto255" curve (RFC 8037 style), whose "x" is the serialized element.
+
+use crate::config::{batched_tokens_mod, BatchedGroup};
+use crate::crystal::{
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetValRef,
+};
+use crate::limits::InputKind;
+use crate::server::UnsupportedTokenTypeError;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use batched_tokens_mod::server::deserialize_public_key;
+use kagippverify::public::public_key_components;
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use p384::elliptic_curve::sec1::ToEncodedPoint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JwkError {
+    #[error("invalid public key")]
+    InvalidKey,
+    #[error("no JWK representation for this token type")]
+    UnsupportedTokenType(#[from] UnsupportedTokenTypeError),
+}
+
+/// Public JSON Web Key, only holding the members of the key types above
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    pub kid: String,
+}
+
+impl Jwk {
+    fn new(kty: &str, crv: Option<&str>, public_key: &[u8]) -> Self {
+        Jwk {
+            kty: kty.to_string(),
+            crv: crv.map(str::to_string),
+            x: None,
+            y: None,
+            n: None,
+            e: None,
+            kid: URL_SAFE_NO_PAD.encode(Sha256::digest(public_key)),
+        }
+    }
+
+    /// JWK of a public key of `token_type`, serialized as by `gen_keys`. Pass 0 as
+    /// `token_type` for batched ristretto255 (0x0005), as in `gen_keys`
+    pub fn from_public_key(token_type: u16, public_key: &[u8]) -> Result<Self, JwkError> {
+        let batched = match token_type {
+            0 => BatchedGroup::default(),
+            TOKEN_TYPE_PRIVATE_P384 => BatchedGroup::P384,
+            TOKEN_TYPE_PUBLIC_RSA => {
+                let (n, e) = public_key_components(public_key).map_err(|_| JwkError::InvalidKey)?;
+                return Ok(Jwk {
+                    n: Some(URL_SAFE_NO_PAD.encode(n)),
+                    e: Some(URL_SAFE_NO_PAD.encode(e)),
+                    ..Jwk::new("RSA", None, public_key)
+                });
+            }
+            _ => BatchedGroup::from_token_type(token_type)
+                .ok_or(UnsupportedTokenTypeError(token_type))?,
+        };
+        match batched {
+            BatchedGroup::Ristretto255 => {
+                deserialize_public_key(public_key).map_err(|_| JwkError::InvalidKey)?;
+                Ok(Jwk {
+                    x: Some(URL_SAFE_NO_PAD.encode(public_key)),
+                    ..Jwk::new("OKP", Some("ristretto255"), public_key)
+                })
+            }
+            BatchedGroup::P384 => {
+                let point = p384::PublicKey::from_sec1_bytes(public_key)
+                    .map_err(|_| JwkError::InvalidKey)?
+                    .to_encoded_point(false);
+                let (Some(x), Some(y)) = (point.x(), point.y()) else {
+                    return Err(JwkError::InvalidKey);
+                };
+                Ok(Jwk {
+                    x: Some(URL_SAFE_NO_PAD.encode(x)),
+                    y: Some(URL_SAFE_NO_PAD.encode(y)),
+                    ..Jwk::new("EC", Some("P-384"), public_key)
+                })
+            }
+        }
+    }
+}
+
+/// Returns the JWK of a (base64 encoded) public key of `token_type` as JSON, see `gen_keys`
+/// for token types
+#[no_mangle]
+pub extern "C" fn public_key_to_jwk(token_type: u16, pk_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let public_key = unsafe { decode_untrusted_bytes_from_crystal(pk_cstr, InputKind::Key)? };
+        let jwk_json = serde_json::to_string(&Jwk::from_public_key(token_type, &public_key)?)?;
+
+        let rv = JSONRetValRef {
+            retval: jwk_json.as_str(),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{GroupTokenType, VoprfGroup};
+    use crate::test_fixtures::{p384_keypair, rsa_keypair};
+    use voprf::VoprfServer;
+
+    #[test]
+    fn test_ristretto255_jwk() {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[4u8; 32], b"jwk").unwrap();
+        let public_key =
+            batched_tokens_mod::server::serialize_public_key(server.get_public_key()).to_vec();
+        let jwk = Jwk::from_public_key(GroupTokenType as u16, &public_key).unwrap();
+        assert_eq!(jwk.kty, "OKP");
+        assert_eq!(jwk.crv.as_deref(), Some("ristretto255"));
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(jwk.x.unwrap()).unwrap(),
+            public_key.as_slice()
+        );
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(jwk.kid).unwrap(),
+            Sha256::digest(&public_key).as_slice()
+        );
+        assert!(Jwk::from_public_key(GroupTokenType as u16, b"not a key").is_err());
+    }
+
+    #[test]
+    fn test_p384_jwk() {
+        let (_, public_key) = p384_keypair(4);
+        let jwk = Jwk::from_public_key(TOKEN_TYPE_PRIVATE_P384, &public_key).unwrap();
+        assert_eq!(jwk.kty, "EC");
+        assert_eq!(jwk.crv.as_deref(), Some("P-384"));
+        // the coordinates are those of the (compressed) public key
+        let mut uncompressed = vec![0x04];
+        uncompressed.extend(URL_SAFE_NO_PAD.decode(jwk.x.unwrap()).unwrap());
+        uncompressed.extend(URL_SAFE_NO_PAD.decode(jwk.y.unwrap()).unwrap());
+        let point = p384::PublicKey::from_sec1_bytes(&uncompressed).unwrap();
+        assert_eq!(
+            point.to_encoded_point(true).as_bytes(),
+            public_key.as_slice()
+        );
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(jwk.kid).unwrap(),
+            Sha256::digest(&public_key).as_slice()
+        );
+        assert!(Jwk::from_public_key(TOKEN_TYPE_PRIVATE_P384, b"not a key").is_err());
+    }
+
+    #[test]
+    fn test_rsa_jwk() {
+        use crate::public_tokens::NK;
+
+        let public_key = rsa_keypair().public_key.clone();
+        let jwk = Jwk::from_public_key(TOKEN_TYPE_PUBLIC_RSA, &public_key).unwrap();
+        assert_eq!(jwk.kty, "RSA");
+        assert_eq!(jwk.crv, None);
+        let n = URL_SAFE_NO_PAD.decode(jwk.n.unwrap()).unwrap();
+        let e = URL_SAFE_NO_PAD.decode(jwk.e.unwrap()).unwrap();
+        assert_eq!(n.len(), NK);
+        assert_eq!(e, [0x01, 0x00, 0x01]);
+        assert_eq!(public_key_components(&public_key).unwrap(), (n, e));
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(jwk.kid).unwrap(),
+            Sha256::digest(&public_key).as_slice()
+        );
+        assert!(Jwk::from_public_key(TOKEN_TYPE_PUBLIC_RSA, b"not a key").is_err());
+    }
+}
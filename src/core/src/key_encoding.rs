@@ -0,0 +1,115 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  key encodings  ---------------------------------
+// -----------------------------------------------------------------------------
+//
+// Text encodings of keys over FFI. Keys are always the bytes of `gen_keys`: the serialized
+// scalar and element for ristretto255 (their raw 32 bytes), the scalar and compressed SEC1
+// point for P-384, and DER for blind RSA. Those bytes are base64url encoded by default, as
+// every FFI function taking keys expects. Each FFI function returning keys has an `_encoded`
+// variant taking the encoding to return them in (`gen_keys_encoded`, `gen_keys_avoiding_encoded`,
+// `gen_keys_from_seed_encoded`, `pp_key_manager_rotate_encoded`,
+// `pp_key_manager_public_keys_encoded`, `secret_key_from_pem_encoded`,
+// `public_key_from_pem_encoded` and `unwrap_key_encoded`), and `convert_key_encoding`
+// converts keys between encodings, e.g. before handing them to functions taking keys.
+
+use crate::crystal::{
+    decode_string_from_crystal, encode_secret_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetValRef,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyEncodingError {
+    #[error("unknown key encoding {0}")]
+    UnknownEncoding(u8),
+    #[error("malformed base64url key")]
+    Base64(#[from] base64::DecodeError),
+    #[error("malformed hex key")]
+    Hex(#[from] hex::FromHexError),
+}
+
+/// Text encoding of key bytes, numbered as over FFI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyEncoding {
+    /// padded base64url, as taken by the FFI functions
+    #[default]
+    Base64Url,
+    /// lowercase hex, accepted in either case
+    Hex,
+}
+
+impl KeyEncoding {
+    pub fn from_ffi(encoding: u8) -> Result<Self, KeyEncodingError> {
+        match encoding {
+            0 => Ok(KeyEncoding::Base64Url),
+            1 => Ok(KeyEncoding::Hex),
+            _ => Err(KeyEncodingError::UnknownEncoding(encoding)),
+        }
+    }
+
+    pub fn encode(self, key: &[u8]) -> String {
+        match self {
+            KeyEncoding::Base64Url => URL_SAFE.encode(key),
+            KeyEncoding::Hex => hex::encode(key),
+        }
+    }
+
+    pub fn decode(self, key: &str) -> Result<Vec<u8>, KeyEncodingError> {
+        match self {
+            KeyEncoding::Base64Url => Ok(URL_SAFE.decode(key)?),
+            KeyEncoding::Hex => Ok(hex::decode(key)?),
+        }
+    }
+}
+
+/// Converts a key (secret or public) from the `from` encoding to the `to` one, see
+/// `KeyEncoding` for their numbers
+#[no_mangle]
+pub extern "C" fn convert_key_encoding(key_cstr: *const i8, from: u8, to: u8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        // keys may be secret, every copy is wiped
+        let key_s = Zeroizing::new(unsafe { decode_string_from_crystal(key_cstr)? });
+        let key = Zeroizing::new(KeyEncoding::from_ffi(from)?.decode(&key_s)?);
+        let converted = Zeroizing::new(KeyEncoding::from_ffi(to)?.encode(&key));
+
+        let rv = JSONRetValRef {
+            retval: converted.as_str(),
+            error: "",
+        };
+        let out = encode_secret_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_encodings() {
+        let key = [0xfbu8, 0xff, 0x00, 0x10];
+        for encoding in [0, 1].map(|encoding| KeyEncoding::from_ffi(encoding).unwrap()) {
+            assert_eq!(encoding.decode(&encoding.encode(&key)).unwrap(), key);
+        }
+        assert_eq!(KeyEncoding::Base64Url.encode(&key), "-_8AEA==");
+        assert_eq!(KeyEncoding::Hex.encode(&key), "fbff0010");
+        assert_eq!(KeyEncoding::Hex.decode("FBFF0010").unwrap(), key);
+        assert_eq!(
+            KeyEncoding::from_ffi(2),
+            Err(KeyEncodingError::UnknownEncoding(2))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// -----------------------------------------------------------------------------
+// -----------------------------  key ids  -------------------------------------
+// -----------------------------------------------------------------------------
+//
+// Token key ids of issuer public keys, for front-end routers mapping incoming requests to
+// the issuer shard holding the right key without parsing them in full: token requests
+// carry the truncated token key id (the last byte of the token key id) and tokens carry
+// the whole token key id, which is SHA256 of the serialized public key for every token
+// type.
+
+use crate::config::{batched_tokens_mod, BatchedGroup};
+use crate::crystal::{
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetValRef,
+};
+use crate::limits::InputKind;
+use crate::server::UnsupportedTokenTypeError;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use batched_tokens_mod::server::deserialize_public_key;
+use kagippverify::public::public_key_components;
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use privacypass::TruncatedTokenKeyId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum KeyIdError {
+    #[error("invalid public key")]
+    InvalidKey,
+    #[error("no public keys for this token type")]
+    UnsupportedTokenType(#[from] UnsupportedTokenTypeError),
+}
+
+/// Token key id and truncated token key id of a public key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenKeyIds {
+    /// base64 encoded
+    pub token_key_id: String,
+    pub truncated_token_key_id: TruncatedTokenKeyId,
+}
+
+impl TokenKeyIds {
+    /// Key ids of a serialized public key, which is not checked
+    pub fn from_serialized_public_key(public_key: &[u8]) -> Self {
+        let token_key_id: [u8; 32] = Sha256::digest(public_key).into();
+        let [.., truncated_token_key_id] = token_key_id;
+        TokenKeyIds {
+            token_key_id: URL_SAFE.encode(token_key_id),
+            truncated_token_key_id,
+        }
+    }
+
+    /// Key ids of a public key of `token_type`, serialized as by `gen_keys`. Pass 0 as
+    /// `token_type` for batched ristretto255 (0x0005), as in `gen_keys`
+    pub fn from_public_key(token_type: u16, public_key: &[u8]) -> Result<Self, KeyIdError> {
+        let batched = match token_type {
+            0 => BatchedGroup::default(),
+            TOKEN_TYPE_PRIVATE_P384 => BatchedGroup::P384,
+            TOKEN_TYPE_PUBLIC_RSA => {
+                public_key_components(public_key).map_err(|_| KeyIdError::InvalidKey)?;
+                return Ok(Self::from_serialized_public_key(public_key));
+            }
+            _ => BatchedGroup::from_token_type(token_type)
+                .ok_or(UnsupportedTokenTypeError(token_type))?,
+        };
+        match batched {
+            BatchedGroup::Ristretto255 => {
+                deserialize_public_key(public_key).map_err(|_| KeyIdError::InvalidKey)?;
+            }
+            BatchedGroup::P384 => {
+                p384::PublicKey::from_sec1_bytes(public_key).map_err(|_| KeyIdError::InvalidKey)?;
+            }
+        }
+        Ok(Self::from_serialized_public_key(public_key))
+    }
+}
+
+/// Returns the key ids of a (base64 encoded) public key of `token_type` as JSON, e.g.
+/// {"token_key_id":"...","truncated_token_key_id":42}, see `gen_keys` for token types
+#[no_mangle]
+pub extern "C" fn public_key_to_key_ids(token_type: u16, pk_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let public_key = unsafe { decode_untrusted_bytes_from_crystal(pk_cstr, InputKind::Key)? };
+        let key_ids = TokenKeyIds::from_public_key(token_type, &public_key)?;
+        let key_ids_json = serde_json::to_string(&key_ids)?;
+
+        let rv = JSONRetValRef {
+            retval: key_ids_json.as_str(),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{GroupTokenType, VoprfGroup};
+    use crate::test_fixtures::{p384_keypair, rsa_keypair};
+    use voprf::VoprfServer;
+
+    #[test]
+    fn test_token_key_ids() {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[4u8; 32], b"key ids").unwrap();
+        let public_key =
+            batched_tokens_mod::server::serialize_public_key(server.get_public_key()).to_vec();
+        let key_ids = TokenKeyIds::from_public_key(GroupTokenType as u16, &public_key).unwrap();
+        let token_key_id = Sha256::digest(&public_key);
+        assert_eq!(
+            URL_SAFE.decode(&key_ids.token_key_id).unwrap(),
+            token_key_id.as_slice()
+        );
+        assert_eq!(key_ids.truncated_token_key_id, token_key_id[31]);
+        assert!(TokenKeyIds::from_public_key(GroupTokenType as u16, b"not a key").is_err());
+    }
+
+    /// Checks the key ids of a valid `public_key` of `token_type`
+    fn assert_token_key_ids(token_type: u16, public_key: &[u8]) {
+        let key_ids = TokenKeyIds::from_public_key(token_type, public_key).unwrap();
+        let token_key_id = Sha256::digest(public_key);
+        assert_eq!(
+            URL_SAFE.decode(&key_ids.token_key_id).unwrap(),
+            token_key_id.as_slice()
+        );
+        assert_eq!(key_ids.truncated_token_key_id, token_key_id[31]);
+        assert!(TokenKeyIds::from_public_key(token_type, b"not a key").is_err());
+    }
+
+    #[test]
+    fn test_p384_token_key_ids() {
+        let (_, public_key) = p384_keypair(4);
+        assert_token_key_ids(TOKEN_TYPE_PRIVATE_P384, &public_key);
+        // P-384 keys are not blind RSA keys
+        assert!(TokenKeyIds::from_public_key(TOKEN_TYPE_PUBLIC_RSA, &public_key).is_err());
+    }
+
+    #[test]
+    fn test_rsa_token_key_ids() {
+        let keypair = rsa_keypair();
+        assert_token_key_ids(TOKEN_TYPE_PUBLIC_RSA, &keypair.public_key);
+        // blind RSA keys are not P-384 keys
+        assert!(
+            TokenKeyIds::from_public_key(TOKEN_TYPE_PRIVATE_P384, &keypair.public_key).is_err()
+        );
+    }
+}
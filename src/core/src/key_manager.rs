@@ -0,0 +1,739 @@
+// -----------------------------------------------------------------------------
+// ----------------------------  key manager  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// Rotating keys used to mean Crystal generating a key, swapping it in its config, and
+// validating tokens against both keys by hand until the old tokens were gone.
+// `KeyManager` holds the current key plus up to `max_previous_keys` previous ones:
+// - tokens are issued with the key TokenRequests name through their truncated key id: the
+//   current key for clients that got challenges after the last rotation, or a previous key
+//   still in its grace period for the others;
+// - tokens are redeemed against whichever key they were issued with, as long as it is the
+//   current key or was retired less than `grace_period` ago. Expired keys (and previous keys
+//   beyond `max_previous_keys`) are dropped, and retired from the process-wide nonce store,
+//   which forgets the nonces of their tokens and refuses them from then on, see
+//   `set_nonce_key_lifetime`;
+// - keys rotate on demand (`rotate`, `install_key`), or on schedule when `rotation_interval`
+//   is set: the current key is rotated by the first issuance after it got that old.
+// New keys get a truncated key id not used by the keys still held, so tokens of the current
+// and previous keys can't be confused. Keys are logged in the process-wide transparency log
+// before being installed, and not installed if they can't be.
+// From Crystal, `pp_key_manager_new` returns an opaque handle the pp_key_manager_* functions
+// take, released with `pp_key_manager_free`.
+
+use crate::clock::{global_clock, Clock};
+use crate::config::{batched_tokens_mod, GroupTokenType, VoprfGroup};
+use crate::crystal::{
+    borrow_untrusted_bytes_from_crystal, borrow_untrusted_str_from_crystal, crystal_error,
+    decode_secret_bytes_from_crystal, decode_string_from_crystal,
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, encode_secret_json_for_crystal,
+    error_chain_json_retval, error_json_retval, CrystalErrorType, JSONRetVal, JSONRetValRef,
+};
+use crate::issuer_directory::{IssuerDirectory, IssuerDirectoryError};
+use crate::key_encoding::KeyEncoding;
+use crate::limits::InputKind;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::replay::{key_retired_at, retire_nonce_key, set_nonce_key_lifetime};
+use crate::server::{
+    check_key, check_redemption_challenge, issue_token_response_with_signer,
+    issue_with_signer_for_crystal, public_key_to_token_key_id,
+    public_key_to_truncated_token_key_id, sample_key_seed, validate_token_for_crystal,
+    verify_token_uniformly, GenKeysError, GenTokenResponseError, KeyPair, KeyUse, RustKeypair,
+    TokenRequestView, UnknownKeyIdError, ValidateTokenError, DEFAULT_KEY_INFO,
+};
+use crate::transparency::{publish_key_at, TransparencyLogFileError};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use batched_tokens_mod::{server::serialize_public_key, TokenResponse};
+use kagippverify::token::{TOKEN_INPUT_LEN, TOKEN_KEY_ID_OFFSET};
+use privacypass::{TokenType, TruncatedTokenKeyId};
+use rand::rngs::OsRng;
+use secrecy::{ExposeSecret, SecretBox};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use voprf::{derive_key, Mode, VoprfServer};
+use zeroize::Zeroizing;
+
+#[derive(Debug, Clone)]
+pub struct KeyManagerConfig {
+    /// previous keys kept for redemption, older ones are dropped on rotation
+    pub max_previous_keys: usize,
+    /// how long previous keys keep being accepted for redemption once rotated out
+    pub grace_period: Duration,
+    /// age at which the current key is rotated out by issuance, None to only rotate on demand
+    pub rotation_interval: Option<Duration>,
+}
+
+impl Default for KeyManagerConfig {
+    fn default() -> Self {
+        KeyManagerConfig {
+            max_previous_keys: 1,
+            grace_period: Duration::from_secs(24 * 60 * 60),
+            rotation_interval: None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum KeyManagerError {
+    #[error("failed to generate key")]
+    GenKeys(#[from] GenKeysError),
+    #[error("failed to load secret key")]
+    InvalidKey(voprf::Error),
+    #[error("key already retired, the nonces of its tokens were forgotten")]
+    KeyRetired,
+    #[error("failed to log key in the transparency log")]
+    TransparencyLog(#[from] TransparencyLogFileError),
+}
+
+struct ManagedKey {
+    server: VoprfServer<VoprfGroup>,
+    token_key_id: [u8; 32],
+    installed_at: u64,
+    /// when the key stopped being the current key
+    retired_at: Option<u64>,
+}
+
+pub struct KeyManager {
+    // newest (current) key last
+    keys: Mutex<Vec<ManagedKey>>,
+    config: KeyManagerConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl KeyManager {
+    /// Key manager holding no key yet, see `rotate` and `install_key`
+    pub fn new(config: KeyManagerConfig) -> Self {
+        Self::with_clock(config, global_clock())
+    }
+
+    pub fn with_clock(config: KeyManagerConfig, clock: Arc<dyn Clock>) -> Self {
+        KeyManager {
+            keys: Mutex::new(Vec::new()),
+            config,
+            clock,
+        }
+    }
+
+    fn keys(&self) -> std::sync::MutexGuard<'_, Vec<ManagedKey>> {
+        // a poisoned lock still holds valid keys, as they are only pushed and removed whole
+        self.keys.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Generates a new current key, retiring the previous one. Returns it, so that it can be
+    /// persisted and installed again after a restart.
+    pub fn rotate(&self) -> Result<RustKeypair, KeyManagerError> {
+        self.rotate_locked(&mut self.keys())
+    }
+
+    fn rotate_locked(&self, keys: &mut Vec<ManagedKey>) -> Result<RustKeypair, KeyManagerError> {
+        let taken: Vec<TruncatedTokenKeyId> = keys
+            .iter()
+            .map(|key| public_key_to_truncated_token_key_id(key.server.get_public_key()))
+            .collect();
+        let seed = sample_key_seed(&mut OsRng, DEFAULT_KEY_INFO, &taken)?;
+        let secret_key = derive_key::<VoprfGroup>(&seed, DEFAULT_KEY_INFO, Mode::Voprf)
+            .map_err(GenKeysError::DeriveKey)?;
+        let sk_bytes = Zeroizing::new(secret_key.to_bytes());
+        let server = VoprfServer::<VoprfGroup>::new_with_key(sk_bytes.as_slice())
+            .map_err(KeyManagerError::InvalidKey)?;
+        let public_key = serialize_public_key(server.get_public_key());
+        let installed_at = self.clock.unix_seconds();
+        publish_key_at(&public_key, installed_at)?;
+        self.push_key(keys, server, installed_at);
+        Ok(RustKeypair {
+            public_key,
+            secret_key: SecretBox::init_with(|| *sk_bytes),
+            token_type: TokenType::BatchedTokenRistretto255,
+            info: DEFAULT_KEY_INFO.to_vec(),
+        })
+    }
+
+    /// Makes `private_key` the current key, retiring the previous one, and returns its
+    /// serialized public key. Installing a key again makes it the current one again.
+    pub fn install_key(&self, private_key: &[u8]) -> Result<Vec<u8>, KeyManagerError> {
+        self.install_key_at(private_key, self.clock.unix_seconds())
+    }
+
+    /// Like `install_key`, for a key that became the current key at `installed_at` (unix
+    /// time, in seconds) rather than now, the previous key retiring as of then. Persisted keys
+    /// installed again this way after a restart keep the grace periods and rotation schedule
+    /// they had, rather than starting over.
+    /// NOTE: install persisted keys oldest first, so that the newest ends up current
+    pub fn install_key_at(
+        &self,
+        private_key: &[u8],
+        installed_at: u64,
+    ) -> Result<Vec<u8>, KeyManagerError> {
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+            .map_err(KeyManagerError::InvalidKey)?;
+        let public_key = serialize_public_key(server.get_public_key());
+        let mut keys = self.keys();
+        let token_key_id = public_key_to_token_key_id(server.get_public_key());
+        // its tokens could be redeemed again
+        if key_retired_at(&token_key_id).is_some() {
+            return Err(KeyManagerError::KeyRetired);
+        }
+        publish_key_at(&public_key, installed_at)?;
+        if keys
+            .iter()
+            .any(|key| key.token_key_id == token_key_id && key.retired_at.is_some())
+        {
+            // back in use, its nonces must be remembered again
+            let _ = set_nonce_key_lifetime(token_key_id, u64::MAX);
+        }
+        keys.retain(|key| key.token_key_id != token_key_id);
+        self.push_key(&mut keys, server, installed_at);
+        Ok(public_key)
+    }
+
+    /// Whether `private_key` is one of the keys accepted for redemption
+    pub fn holds_key(&self, private_key: &[u8]) -> Result<bool, KeyManagerError> {
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+            .map_err(KeyManagerError::InvalidKey)?;
+        let token_key_id = public_key_to_token_key_id(server.get_public_key());
+        let mut keys = self.keys();
+        self.prune(&mut keys, self.clock.unix_seconds());
+        Ok(keys.iter().any(|key| key.token_key_id == token_key_id))
+    }
+
+    /// Rotates the current key if `rotation_interval` went by since it was installed.
+    /// Returns whether it was rotated.
+    pub fn rotate_if_due(&self) -> Result<bool, KeyManagerError> {
+        let Some(rotation_interval) = self.config.rotation_interval else {
+            return Ok(false);
+        };
+        let mut keys = self.keys();
+        let due = keys.last().is_none_or(|current| {
+            self.clock.unix_seconds()
+                >= current
+                    .installed_at
+                    .saturating_add(rotation_interval.as_secs())
+        });
+        if due {
+            self.rotate_locked(&mut keys)?;
+        }
+        Ok(due)
+    }
+
+    fn push_key(
+        &self,
+        keys: &mut Vec<ManagedKey>,
+        server: VoprfServer<VoprfGroup>,
+        installed_at: u64,
+    ) {
+        if let Some(current) = keys.last_mut().filter(|key| key.retired_at.is_none()) {
+            current.retired_at = Some(installed_at);
+            let expires_at = installed_at.saturating_add(self.config.grace_period.as_secs());
+            // only the process-wide nonce store tracks key lifetimes, shared stores expire
+            // nonces on their own
+            let _ = set_nonce_key_lifetime(current.token_key_id, expires_at);
+        }
+        keys.push(ManagedKey {
+            token_key_id: public_key_to_token_key_id(server.get_public_key()),
+            server,
+            installed_at,
+            retired_at: None,
+        });
+        self.prune(keys, self.clock.unix_seconds());
+    }
+
+    /// Drops expired keys, and previous keys beyond `max_previous_keys`
+    fn prune(&self, keys: &mut Vec<ManagedKey>, now: u64) {
+        let (live, expired): (Vec<_>, Vec<_>) =
+            keys.drain(..).partition(|key| self.is_live(key, now));
+        *keys = live;
+        let excess = keys
+            .len()
+            .saturating_sub(self.config.max_previous_keys.saturating_add(1));
+        // dropped keys are refused for redemption from now on, so the nonces of their tokens
+        // can be forgotten right away rather than once their grace period would have ended,
+        // and their truncated key ids reused. Keys retired already are left as they are.
+        for key in expired.into_iter().chain(keys.drain(..excess)) {
+            let _ = retire_nonce_key(key.token_key_id);
+        }
+    }
+
+    fn is_live(&self, key: &ManagedKey, now: u64) -> bool {
+        key.retired_at.is_none_or(|retired_at| {
+            now < retired_at.saturating_add(self.config.grace_period.as_secs())
+        })
+    }
+
+    /// Serialized public key of the current key
+    pub fn current_public_key(&self) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        let current = keys.last()?;
+        Some(serialize_public_key(current.server.get_public_key()))
+    }
+
+    /// Serialized public keys of the keys accepted for redemption, newest first
+    pub fn public_keys(&self) -> Vec<Vec<u8>> {
+        let mut keys = self.keys();
+        self.prune(&mut keys, self.clock.unix_seconds());
+        keys.iter()
+            .rev()
+            .map(|key| serialize_public_key(key.server.get_public_key()))
+            .collect()
+    }
+
+    /// Issuer directory of the keys accepted for redemption, current key first
+    pub fn issuer_directory(
+        &self,
+        issuer_request_uri: &str,
+    ) -> Result<IssuerDirectory, IssuerDirectoryError> {
+        let public_keys = self.public_keys();
+        IssuerDirectory::from_public_keys(
+            issuer_request_uri,
+            public_keys
+                .iter()
+                .map(|public_key| (GroupTokenType as u16, public_key.as_slice())),
+        )
+    }
+
+    /// Key holding `truncated_token_key_id` that is still accepted, newest first, after
+    /// rotating the current key if due
+    fn issuance_key(
+        &self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+    ) -> Result<VoprfServer<VoprfGroup>, UnknownKeyIdError> {
+        // failing to rotate keeps issuing with the current key rather than refusing requests
+        let _ = self.rotate_if_due();
+        let mut keys = self.keys();
+        self.prune(&mut keys, self.clock.unix_seconds());
+        keys.iter()
+            .rev()
+            .find(|key| key.token_key_id.last() == Some(&truncated_token_key_id))
+            .map(|key| key.server.clone())
+            .ok_or(UnknownKeyIdError(truncated_token_key_id))
+    }
+
+    fn current_key(&self) -> Option<VoprfServer<VoprfGroup>> {
+        self.keys().last().map(|current| current.server.clone())
+    }
+
+    /// Key a serialized token names through its token key id, if still accepted
+    fn redemption_key(&self, token: &[u8]) -> Option<VoprfServer<VoprfGroup>> {
+        let token_key_id = token.get(TOKEN_KEY_ID_OFFSET..TOKEN_INPUT_LEN)?;
+        let mut keys = self.keys();
+        self.prune(&mut keys, self.clock.unix_seconds());
+        keys.iter()
+            .find(|key| key.token_key_id.as_slice() == token_key_id)
+            .map(|key| key.server.clone())
+    }
+
+    /// Issues a TokenResponse with the key the request names through its truncated key id
+    pub fn issue_token_response(
+        &self,
+        token_request: &TokenRequestView,
+    ) -> Result<TokenResponse, GenTokenResponseError> {
+        let server = self.issuance_key(token_request.truncated_token_key_id())?;
+        issue_token_response_with_signer(&server, token_request)
+    }
+
+    /// Checks a serialized token against the key it was issued with, if still accepted and
+    /// neither revoked nor outside of its validity window. Tokens are refused without a
+    /// `challenge_digest` while challenges are checked for freshness.
+    /// NOTE: only verifies the token, double spending is left to the caller's nonce store
+    pub fn verify_token(&self, token: &[u8], challenge_digest: Option<&[u8]>) -> bool {
+        if challenge_digest.is_none()
+            && check_redemption_challenge::<ValidateTokenError>(None).is_err()
+        {
+            return false;
+        }
+        self.redemption_key(token).is_some_and(|server| {
+            let token_key_id = public_key_to_token_key_id(server.get_public_key());
+            check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption).is_ok()
+                && bool::from(verify_token_uniformly(&server, token, challenge_digest))
+        })
+    }
+}
+
+/// Borrows the key manager behind a pointer returned by `pp_key_manager_new`
+///
+/// # Safety
+///
+/// Callers must provide either a null pointer or a key manager that was not freed yet.
+unsafe fn key_manager_from_crystal<'a>(
+    handle: *const KeyManager,
+) -> Result<&'a KeyManager, CrystalErrorType> {
+    unsafe { handle.as_ref() }.ok_or_else(|| crystal_error("null key manager handle"))
+}
+
+/// Creates a key manager holding no key yet, writing a handle to `handle_out`.
+/// NOTE: pass 0 as `rotation_interval_seconds` to only rotate keys on demand
+#[no_mangle]
+pub extern "C" fn pp_key_manager_new(
+    max_previous_keys: u32,
+    grace_period_seconds: u32,
+    rotation_interval_seconds: u32,
+    handle_out: *mut *mut KeyManager,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let config = KeyManagerConfig {
+            max_previous_keys: usize::try_from(max_previous_keys)?,
+            grace_period: Duration::from_secs(grace_period_seconds.into()),
+            rotation_interval: (rotation_interval_seconds > 0)
+                .then(|| Duration::from_secs(rotation_interval_seconds.into())),
+        };
+        let handle = Box::new(KeyManager::new(config));
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Releases a key manager returned by `pp_key_manager_new`, wiping its secret keys
+/// # Safety
+/// The handle must not be used anymore, by this or any other thread
+#[no_mangle]
+pub extern "C" fn pp_key_manager_free(handle: *mut KeyManager) {
+    if handle.is_null() {
+        return;
+    }
+    // Take the ownership back to rust and drop the owner
+    let _ = unsafe { Box::from_raw(handle) };
+}
+
+/// Rotates to a freshly generated key, returned like `gen_keys` does so it can be persisted
+#[no_mangle]
+pub extern "C" fn pp_key_manager_rotate(handle: *const KeyManager) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| unsafe { rotate_impl(handle, KeyEncoding::default()) });
+    end_panic_handling!();
+    result
+}
+
+/// Like `pp_key_manager_rotate`, but returns the keys in `encoding`, see `KeyEncoding`
+#[no_mangle]
+pub extern "C" fn pp_key_manager_rotate_encoded(
+    handle: *const KeyManager,
+    encoding: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        unsafe { rotate_impl(handle, encoding) }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the pp_key_manager_rotate FFI functions
+///
+/// # Safety
+///
+/// Callers must provide a valid handle.
+unsafe fn rotate_impl(
+    handle: *const KeyManager,
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let key_manager = unsafe { key_manager_from_crystal(handle)? };
+    let keypair = key_manager.rotate()?;
+    let keypair = KeyPair {
+        pk: encoding.encode(&keypair.public_key),
+        sk: encoding.encode(keypair.secret_key.expose_secret()),
+        token_type: GroupTokenType as u16,
+        error: "".to_string(),
+    };
+    let keypair_json = Zeroizing::new(serde_json::to_string(&keypair)?);
+
+    let rv = JSONRetValRef {
+        retval: keypair_json.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Makes a (base64 encoded) secret key the current key, see `KeyManager::install_key`
+#[no_mangle]
+pub extern "C" fn pp_key_manager_install_key(
+    handle: *const KeyManager,
+    sk_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let key_manager = unsafe { key_manager_from_crystal(handle)? };
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        key_manager.install_key(private_key.expose_secret())?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns a JSON array of the (base64 encoded) public keys accepted for redemption, current
+/// key first, e.g. for the issuer directory
+#[no_mangle]
+pub extern "C" fn pp_key_manager_public_keys(handle: *const KeyManager) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result =
+        panic::catch_unwind(|| unsafe { public_keys_impl(handle, KeyEncoding::default()) });
+    end_panic_handling!();
+    result
+}
+
+/// Like `pp_key_manager_public_keys`, but returns the keys in `encoding`, see `KeyEncoding`
+#[no_mangle]
+pub extern "C" fn pp_key_manager_public_keys_encoded(
+    handle: *const KeyManager,
+    encoding: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        unsafe { public_keys_impl(handle, encoding) }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the pp_key_manager_public_keys FFI functions
+///
+/// # Safety
+///
+/// Callers must provide a valid handle.
+unsafe fn public_keys_impl(
+    handle: *const KeyManager,
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let key_manager = unsafe { key_manager_from_crystal(handle)? };
+    let public_keys: Vec<String> = key_manager
+        .public_keys()
+        .iter()
+        .map(|public_key| encoding.encode(public_key))
+        .collect();
+
+    let rv = JSONRetVal {
+        retval: serde_json::to_string(&public_keys)?,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Returns the issuer directory JSON of the keys of `handle` accepted for redemption, current
+/// key first, see `issuer_directory`
+#[no_mangle]
+pub extern "C" fn pp_key_manager_issuer_directory(
+    handle: *const KeyManager,
+    issuer_request_uri_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let key_manager = unsafe { key_manager_from_crystal(handle)? };
+        let issuer_request_uri = unsafe { decode_string_from_crystal(issuer_request_uri_cstr)? };
+        let directory = key_manager.issuer_directory(&issuer_request_uri)?;
+
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&directory)?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_response_with_deadline`, issuing with the key of `handle` the request names
+/// NOTE: pass 0 as `timeout_ms` for no deadline
+#[no_mangle]
+pub extern "C" fn pp_key_manager_gen_token_response(
+    handle: *const KeyManager,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    timeout_ms: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let deadline = match timeout_ms {
+            0 => None,
+            _ => Some(Instant::now() + Duration::from_millis(u64::from(timeout_ms))),
+        };
+        let key_manager = unsafe { key_manager_from_crystal(handle)? };
+        let token_request_bytes = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let token_request = TokenRequestView::try_from_bytes(&token_request_bytes)?;
+        let server = key_manager.issuance_key(token_request.truncated_token_key_id())?;
+        issue_with_signer_for_crystal(&server, &token_request_bytes, max_nr, deadline)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_token`, against whichever key of `handle` the token was issued with
+#[no_mangle]
+pub extern "C" fn pp_key_manager_validate_token(
+    handle: *const KeyManager,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        let key_manager = unsafe { key_manager_from_crystal(handle)? };
+        let token_encoded =
+            unsafe { borrow_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        // tokens naming no accepted key are checked against the current key, which refuses them
+        let token_bytes = URL_SAFE.decode(token_encoded).unwrap_or_default();
+        let server = match key_manager.redemption_key(&token_bytes) {
+            Some(server) => server,
+            None => key_manager
+                .current_key()
+                .ok_or_else(|| crystal_error("no key installed"))?,
+        };
+        validate_token_for_crystal(&server, token_encoded, token_challenge_s)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_rotation_with_grace_period() {
+        // starting now, so that the nonce lifetimes set by rotations are in the future of
+        // the other tests, which share the process-wide nonce store
+        let clock = Arc::new(MockClock::new(std::time::SystemTime::now()));
+        let key_manager = KeyManager::with_clock(
+            KeyManagerConfig {
+                max_previous_keys: 2,
+                grace_period: Duration::from_secs(600),
+                rotation_interval: Some(Duration::from_secs(3_600)),
+            },
+            clock.clone(),
+        );
+        assert!(key_manager.current_public_key().is_none());
+        assert!(key_manager.rotate_if_due().unwrap());
+        let first = key_manager.current_public_key().unwrap();
+        assert!(!key_manager.rotate_if_due().unwrap());
+
+        // scheduled rotation keeps the previous key during the grace period
+        clock.advance(Duration::from_secs(3_600));
+        assert!(key_manager.rotate_if_due().unwrap());
+        let second = key_manager.current_public_key().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(key_manager.public_keys(), vec![second.clone(), first]);
+
+        // on demand rotation, and the first key expiring
+        clock.advance(Duration::from_secs(600));
+        let third = key_manager.rotate().unwrap().public_key;
+        assert_eq!(key_manager.public_keys(), vec![third.clone(), second]);
+
+        // installing a key again makes it current
+        let keypair = key_manager.rotate().unwrap();
+        key_manager
+            .install_key(keypair.secret_key.expose_secret())
+            .unwrap();
+        assert_eq!(key_manager.public_keys()[0], keypair.public_key);
+        assert_eq!(key_manager.public_keys().len(), 3);
+        assert_eq!(key_manager.public_keys()[1], third);
+
+        // keys are logged in the transparency log before being installed
+        let logged = crate::transparency::global_log_entries();
+        assert!(key_manager
+            .public_keys()
+            .iter()
+            .all(|public_key| logged.iter().any(|entry| &entry.public_key == public_key)));
+    }
+
+    #[test]
+    fn test_redemption_during_and_after_grace_period() {
+        use crate::replay::redeem_nonce;
+        use rand::RngCore;
+
+        let clock = Arc::new(MockClock::new(std::time::SystemTime::now()));
+        let key_manager = KeyManager::with_clock(
+            KeyManagerConfig {
+                max_previous_keys: 1,
+                grace_period: Duration::from_secs(600),
+                rotation_interval: None,
+            },
+            clock.clone(),
+        );
+        let keypair = key_manager.rotate().unwrap();
+        let server =
+            VoprfServer::<VoprfGroup>::new_with_key(keypair.secret_key.expose_secret()).unwrap();
+        let token_key_id = public_key_to_token_key_id(server.get_public_key());
+        // random nonces, as the process-wide nonce store is shared with the other tests
+        let mut nonces = [[0u8; 32]; 2];
+        nonces.iter_mut().for_each(|nonce| OsRng.fill_bytes(nonce));
+        let mut token = (GroupTokenType as u16).to_be_bytes().to_vec();
+        token.extend_from_slice(&nonces[0]);
+        token.extend_from_slice(&[9u8; 32]);
+        token.extend_from_slice(&token_key_id);
+        let authenticator = server.evaluate(&token).unwrap();
+        token.extend_from_slice(&authenticator);
+
+        // tokens of the previous key are accepted once during its grace period
+        key_manager.rotate().unwrap();
+        assert!(key_manager.verify_token(&token, Some(&[9u8; 32])));
+        assert!(redeem_nonce(nonces[0], token_key_id));
+        assert!(!redeem_nonce(nonces[0], token_key_id));
+
+        // past it the key is dropped and retired from the nonce store, so that its forgotten
+        // nonces can't be replayed
+        clock.advance(Duration::from_secs(600));
+        assert!(!key_manager.verify_token(&token, Some(&[9u8; 32])));
+        assert!(key_retired_at(&token_key_id).is_some());
+        assert!(!redeem_nonce(nonces[0], token_key_id));
+        assert!(!redeem_nonce(nonces[1], token_key_id));
+        assert!(matches!(
+            key_manager.install_key(keypair.secret_key.expose_secret()),
+            Err(KeyManagerError::KeyRetired)
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  key validity  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// Keys can be given a validity window (not-before / not-after, in unix seconds), outside of
+// which they are refused for both issuance and redemption, with a `KeyValidityError` whose
+// error code (key_not_yet_valid or key_expired) lets operators automate rotation.
+// The FFI functions, which get handed secret keys directly, consult the process-wide table
+// returned by `global_key_validities`, keyed by the full token key id of each key, which
+// Crystal fills in with `set_key_validity` for each keypair it loads. Keys without a window
+// are always valid.
+// Windows are only checked by `server::check_key`, which every issuance and redemption path
+// goes through along with the revocation list.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    crystal_error, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use crate::limits::InputKind;
+use privacypass::TruncatedTokenKeyId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Validity window of a key, in unix seconds, both ends included. None means unbounded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyValidity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<u64>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyValidityError {
+    #[error("key with truncated id {truncated_token_key_id} is not valid before {not_before}")]
+    NotYetValid {
+        truncated_token_key_id: TruncatedTokenKeyId,
+        not_before: u64,
+    },
+    #[error("key with truncated id {truncated_token_key_id} expired at {not_after}")]
+    Expired {
+        truncated_token_key_id: TruncatedTokenKeyId,
+        not_after: u64,
+    },
+}
+
+impl KeyValidityError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            KeyValidityError::NotYetValid { .. } => "key_not_yet_valid",
+            KeyValidityError::Expired { .. } => "key_expired",
+        }
+    }
+}
+
+impl KeyValidity {
+    /// Window from FFI arguments, where 0 stands for an unbounded end
+    pub fn from_unix_seconds(not_before: u64, not_after: u64) -> Self {
+        KeyValidity {
+            not_before: (not_before != 0).then_some(not_before),
+            not_after: (not_after != 0).then_some(not_after),
+        }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.not_before.is_none() && self.not_after.is_none()
+    }
+
+    /// Errors out if `now` (in unix seconds) is outside the window of the key with
+    /// `token_key_id`
+    pub fn check(&self, token_key_id: &[u8; 32], now: u64) -> Result<(), KeyValidityError> {
+        let [.., truncated_token_key_id] = *token_key_id;
+        if let Some(not_before) = self.not_before.filter(|not_before| now < *not_before) {
+            return Err(KeyValidityError::NotYetValid {
+                truncated_token_key_id,
+                not_before,
+            });
+        }
+        if let Some(not_after) = self.not_after.filter(|not_after| now > *not_after) {
+            return Err(KeyValidityError::Expired {
+                truncated_token_key_id,
+                not_after,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Validity windows of the keys that have one, by token key id
+pub struct KeyValidities {
+    windows: Mutex<BTreeMap<[u8; 32], KeyValidity>>,
+}
+
+impl KeyValidities {
+    pub const fn new() -> Self {
+        KeyValidities {
+            windows: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn with_windows<T>(&self, f: impl FnOnce(&mut BTreeMap<[u8; 32], KeyValidity>) -> T) -> T {
+        // a poisoned table still holds whole windows, as they are only ever overwritten whole
+        f(&mut self.windows.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Sets the window of a key, removing it if unbounded
+    pub fn set(&self, token_key_id: [u8; 32], validity: KeyValidity) {
+        self.with_windows(|windows| match validity.is_unbounded() {
+            true => windows.remove(&token_key_id),
+            false => windows.insert(token_key_id, validity),
+        });
+    }
+
+    pub fn get(&self, token_key_id: &[u8; 32]) -> KeyValidity {
+        self.with_windows(|windows| windows.get(token_key_id).copied().unwrap_or_default())
+    }
+
+    pub fn check(
+        &self,
+        token_key_id: &[u8; 32],
+        clock: &dyn Clock,
+    ) -> Result<(), KeyValidityError> {
+        self.get(token_key_id)
+            .check(token_key_id, clock.unix_seconds())
+    }
+}
+
+impl Default for KeyValidities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static GLOBAL_KEY_VALIDITIES: KeyValidities = KeyValidities::new();
+
+/// Validity windows consulted by the FFI functions and the `PrivacyPass` API
+pub fn global_key_validities() -> &'static KeyValidities {
+    &GLOBAL_KEY_VALIDITIES
+}
+
+/// Errors out if the key with `token_key_id` is outside of its process-wide validity window.
+/// NOTE: only meant to be called by `server::check_key`, which also checks revocation
+pub(crate) fn check_key_validity(token_key_id: &[u8; 32]) -> Result<(), KeyValidityError> {
+    global_key_validities().check(token_key_id, global_clock().as_ref())
+}
+
+/// Decodes a (base64 encoded) token key id, as returned by `public_key_to_key_ids`
+///
+/// # Safety
+/// `token_key_id_cstr` must be a valid pointer to a NUL terminated string
+unsafe fn decode_token_key_id(
+    token_key_id_cstr: *const i8,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let token_key_id = decode_untrusted_bytes_from_crystal(token_key_id_cstr, InputKind::Key)?;
+    Ok(<[u8; 32]>::try_from(token_key_id.as_slice())
+        .map_err(|_| crystal_error("token key id must be 32 bytes"))?)
+}
+
+/// Sets the validity window of the key with the given (base64 encoded) token key id, in
+/// unix seconds.
+/// NOTE: pass 0 for an unbounded end, and 0 for both to remove the window
+#[no_mangle]
+pub extern "C" fn set_key_validity(
+    token_key_id_cstr: *const i8,
+    not_before: u64,
+    not_after: u64,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if not_before != 0 && not_after != 0 && not_after < not_before {
+            Err(crystal_error("key validity window ends before it starts"))?;
+        }
+        let token_key_id = unsafe { decode_token_key_id(token_key_id_cstr)? };
+        global_key_validities().set(
+            token_key_id,
+            KeyValidity::from_unix_seconds(not_before, not_after),
+        );
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns the validity window of the key with the given (base64 encoded) token key id as
+/// JSON, e.g. {"not_before":1700000000,"not_after":1710000000}, leaving out unbounded ends
+#[no_mangle]
+pub extern "C" fn get_key_validity(token_key_id_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_key_id = unsafe { decode_token_key_id(token_key_id_cstr)? };
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&global_key_validities().get(&token_key_id))?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_key_validity_windows() {
+        let validities = KeyValidities::new();
+        let clock = MockClock::at_unix_seconds(1_000);
+        let key = [1; 32];
+        assert!(validities.check(&key, &clock).is_ok());
+
+        validities.set(key, KeyValidity::from_unix_seconds(2_000, 3_000));
+        assert_eq!(
+            validities.check(&key, &clock).unwrap_err().code(),
+            "key_not_yet_valid"
+        );
+        clock.set(std::time::UNIX_EPOCH + std::time::Duration::from_secs(3_000));
+        assert!(validities.check(&key, &clock).is_ok());
+        clock.advance(std::time::Duration::from_secs(1));
+        assert_eq!(
+            validities.check(&key, &clock).unwrap_err(),
+            KeyValidityError::Expired {
+                truncated_token_key_id: 1,
+                not_after: 3_000
+            }
+        );
+        // other keys are unaffected, even when sharing the truncated key id, and removing
+        // the window makes the key valid again
+        let mut colliding_key = [2; 32];
+        colliding_key[31] = 1;
+        assert!(validities.check(&colliding_key, &clock).is_ok());
+        validities.set(key, KeyValidity::default());
+        assert!(validities.check(&key, &clock).is_ok());
+    }
+}
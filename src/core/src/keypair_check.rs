@@ -0,0 +1,194 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  keypair check  ---------------------------------
+// -----------------------------------------------------------------------------
+//
+// Consistency check of an issuer keypair, e.g. before deploying keys restored from a
+// backup: the public key is derived again from the secret key and compared with the given
+// one. Tokens issued under a secret key whose published public key does not match it would
+// fail to verify at clients, so a mismatch is reported (with the key ids of the public key
+// the secret key actually has) rather than discovered in production.
+
+use crate::config::{batched_tokens_mod, BatchedGroup, VoprfGroup};
+use crate::crystal::{
+    decode_secret_bytes_from_crystal, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, JSONRetValRef,
+};
+use crate::key_id::TokenKeyIds;
+use crate::limits::InputKind;
+use crate::server::UnsupportedTokenTypeError;
+use kagippverify::token::{TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA};
+use p384::NistP384;
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use voprf::VoprfServer;
+
+#[derive(Error, Debug)]
+pub enum KeypairCheckError {
+    #[error("invalid secret key")]
+    InvalidSecretKey,
+    #[error("no keypair check for this token type")]
+    UnsupportedTokenType(#[from] UnsupportedTokenTypeError),
+}
+
+/// Outcome of a keypair check
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeypairCheck {
+    /// whether the given public key is the one of the secret key
+    pub matches: bool,
+    /// token key id of the public key derived from the secret key, base64 encoded
+    pub token_key_id: String,
+    /// truncated token key id of the public key derived from the secret key
+    pub truncated_token_key_id: u8,
+}
+
+/// Public key of a secret key of `token_type`, both serialized as by `gen_keys`. Pass 0 as
+/// `token_type` for batched ristretto255 (0x0005), as in `gen_keys`
+pub fn derive_public_key(token_type: u16, secret_key: &[u8]) -> Result<Vec<u8>, KeypairCheckError> {
+    let batched = match token_type {
+        0 => BatchedGroup::default(),
+        TOKEN_TYPE_PRIVATE_P384 => BatchedGroup::P384,
+        TOKEN_TYPE_PUBLIC_RSA => {
+            return crate::public_tokens::secret_key_to_public_key(secret_key)
+                .map_err(|_| KeypairCheckError::InvalidSecretKey);
+        }
+        _ => BatchedGroup::from_token_type(token_type)
+            .ok_or(UnsupportedTokenTypeError(token_type))?,
+    };
+    match batched {
+        BatchedGroup::Ristretto255 => {
+            let server = VoprfServer::<VoprfGroup>::new_with_key(secret_key)
+                .map_err(|_| KeypairCheckError::InvalidSecretKey)?;
+            Ok(batched_tokens_mod::server::serialize_public_key(server.get_public_key()).to_vec())
+        }
+        BatchedGroup::P384 => {
+            let server = VoprfServer::<NistP384>::new_with_key(secret_key)
+                .map_err(|_| KeypairCheckError::InvalidSecretKey)?;
+            Ok(crate::private_tokens::serialize_public_key(
+                server.get_public_key(),
+            ))
+        }
+    }
+}
+
+impl KeypairCheck {
+    /// Checks that `public_key` is the public key of `secret_key`, keys of `token_type`
+    /// being serialized as by `gen_keys`
+    pub fn new(
+        token_type: u16,
+        secret_key: &[u8],
+        public_key: &[u8],
+    ) -> Result<Self, KeypairCheckError> {
+        let derived = derive_public_key(token_type, secret_key)?;
+        let key_ids = TokenKeyIds::from_serialized_public_key(&derived);
+        Ok(KeypairCheck {
+            matches: bool::from(derived.ct_eq(public_key)),
+            token_key_id: key_ids.token_key_id,
+            truncated_token_key_id: key_ids.truncated_token_key_id,
+        })
+    }
+}
+
+/// Checks that a (base64 encoded) public key is the one of a secret key of `token_type`,
+/// see `gen_keys` for token types. Returns a `KeypairCheck` as JSON, e.g.
+/// {"matches":true,"token_key_id":"...","truncated_token_key_id":42}
+#[no_mangle]
+pub extern "C" fn check_keypair(
+    token_type: u16,
+    sk_cstr: *const i8,
+    pk_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let key_kind = InputKind::key_of(Some(token_type));
+        let secret_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, key_kind)? };
+        let public_key = unsafe { decode_untrusted_bytes_from_crystal(pk_cstr, key_kind)? };
+        let check = KeypairCheck::new(token_type, secret_key.expose_secret(), &public_key)?;
+        let check_json = serde_json::to_string(&check)?;
+
+        let rv = JSONRetValRef {
+            retval: check_json.as_str(),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GroupTokenType;
+    use crate::test_fixtures::{p384_keypair, rsa_keypairs};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use sha2::{Digest, Sha256};
+    use voprf::{derive_key, Mode};
+
+    /// Checks the first secret key against its public key and the public key of the second
+    fn assert_keypair_checks(token_type: u16, keys: [(Vec<u8>, Vec<u8>); 2]) {
+        let [(sk, pk), (_, other_pk)] = &keys;
+
+        let check = KeypairCheck::new(token_type, sk, pk).unwrap();
+        assert!(check.matches);
+        assert_eq!(
+            URL_SAFE.decode(&check.token_key_id).unwrap(),
+            Sha256::digest(pk).as_slice()
+        );
+        assert_eq!(check.truncated_token_key_id, Sha256::digest(pk)[31]);
+
+        let mismatch = KeypairCheck::new(token_type, sk, other_pk).unwrap();
+        assert!(!mismatch.matches);
+        assert_eq!(mismatch.token_key_id, check.token_key_id);
+        assert!(KeypairCheck::new(token_type, b"not a key", pk).is_err());
+    }
+
+    #[test]
+    fn test_check_keypair() {
+        let token_type = GroupTokenType as u16;
+        let keys = [4u8, 5].map(|seed| {
+            let secret_key = derive_key::<VoprfGroup>(&[seed; 32], b"check", Mode::Voprf)
+                .unwrap()
+                .to_bytes()
+                .to_vec();
+            let public_key = derive_public_key(token_type, &secret_key).unwrap();
+            (secret_key, public_key)
+        });
+        assert_keypair_checks(token_type, keys);
+    }
+
+    #[test]
+    fn test_check_p384_keypair() {
+        let keys = [4u8, 5].map(p384_keypair);
+        assert_eq!(
+            derive_public_key(TOKEN_TYPE_PRIVATE_P384, &keys[0].0).unwrap(),
+            keys[0].1
+        );
+        assert_keypair_checks(TOKEN_TYPE_PRIVATE_P384, keys);
+    }
+
+    #[test]
+    fn test_check_rsa_keypair() {
+        let keys = rsa_keypairs().each_ref().map(|keypair| {
+            (
+                keypair.secret_key.expose_secret().to_vec(),
+                keypair.public_key.clone(),
+            )
+        });
+        assert_eq!(
+            derive_public_key(TOKEN_TYPE_PUBLIC_RSA, &keys[0].0).unwrap(),
+            keys[0].1
+        );
+        assert_keypair_checks(TOKEN_TYPE_PUBLIC_RSA, keys);
+    }
+}
@@ -0,0 +1,459 @@
+// -----------------------------------------------------------------------------
+// --------------------------  KMS key wrapping  -------------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuer secret keys used to sit in the Crystal config as plain base64. With the `aws-kms`
+// or `gcp-kms` feature, `wrap_key` encrypts them under a key managed by AWS KMS or Google
+// Cloud KMS, which never leaves the KMS, so that only wrapped keys are kept at rest.
+// `pp_server_new_from_wrapped_key` unwraps a key straight into a server handle, without the
+// plaintext key ever crossing the FFI boundary; `unwrap_key` hands it back to Crystal for
+// the functions taking a secret key.
+// KMS keys are named with Tink-style URIs:
+// - "aws-kms://arn:aws:kms:<region>:<account>:key/<id>" (or an alias ARN), credentials and
+//   region coming from the usual AWS environment, profile or instance metadata;
+// - "gcp-kms://projects/<p>/locations/<l>/keyRings/<r>/cryptoKeys/<k>", credentials coming
+//   from the application default credentials.
+// Wrapped keys are bound to their purpose (an AWS encryption context, GCP additional
+// authenticated data), so that other ciphertexts of the same KMS key can't be unwrapped as
+// issuer keys.
+// GCP resource names end up in the request URL, so they are checked to be made of plain
+// segments beforehand. The buffers holding plaintext keys on their way to and from the KMS
+// are wiped, as far as they are owned here (the AWS SDK keeps its own copies).
+
+use crate::crystal::{
+    crystal_error, decode_secret_bytes_from_crystal, decode_string_from_crystal,
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, encode_secret_json_for_crystal,
+    error_chain_json_retval, error_json_retval, Base64Json, JSONRetVal, JSONRetValRef,
+};
+use crate::key_encoding::KeyEncoding;
+use crate::limits::InputKind;
+use crate::runtime::ffi_runtime;
+use crate::server_handle::ServerHandle;
+use secrecy::{ExposeSecret, SecretSlice};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Purpose wrapped keys are bound to
+const KEY_PURPOSE: &str = "kagi-privacypass-issuer-key";
+
+#[derive(Error, Debug)]
+pub enum KmsError {
+    #[error("unsupported KMS key URI {0:?}, expected aws-kms:// or gcp-kms://")]
+    UnsupportedUri(String),
+    #[error(
+        "invalid GCP crypto key name {0:?}, \
+         expected projects/<p>/locations/<l>/keyRings/<r>/cryptoKeys/<k>"
+    )]
+    InvalidGcpKeyName(String),
+    #[error("built without the {0} feature")]
+    NotBuiltWith(&'static str),
+    #[cfg(feature = "aws-kms")]
+    #[error("AWS KMS call failed")]
+    Aws(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[cfg(feature = "gcp-kms")]
+    #[error("GCP KMS call failed")]
+    Gcp(#[from] reqwest::Error),
+    #[cfg(feature = "gcp-kms")]
+    #[error("failed to get GCP credentials")]
+    GcpAuth(#[from] gcp_auth::Error),
+    #[cfg(feature = "gcp-kms")]
+    #[error("malformed GCP KMS message")]
+    GcpJson(#[from] serde_json::Error),
+    #[error("KMS response carries no key")]
+    MissingKey,
+    #[error("malformed base64 in KMS response")]
+    Base64(#[from] base64::DecodeError),
+}
+
+/// KMS key wrapping issuer keys, parsed from its URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KmsKey {
+    /// key id, key ARN or alias ARN
+    Aws(String),
+    /// resource name of the crypto key
+    Gcp(String),
+}
+
+impl KmsKey {
+    pub fn parse(uri: &str) -> Result<Self, KmsError> {
+        if let Some(key_id) = uri.strip_prefix("aws-kms://") {
+            return Ok(KmsKey::Aws(key_id.to_string()));
+        }
+        if let Some(key_name) = uri.strip_prefix("gcp-kms://") {
+            if !is_gcp_crypto_key_name(key_name) {
+                return Err(KmsError::InvalidGcpKeyName(key_name.to_string()));
+            }
+            return Ok(KmsKey::Gcp(key_name.to_string()));
+        }
+        Err(KmsError::UnsupportedUri(uri.to_string()))
+    }
+
+    /// Encrypts `private_key` under this KMS key
+    pub async fn wrap(&self, private_key: &[u8]) -> Result<Vec<u8>, KmsError> {
+        match self {
+            #[cfg(feature = "aws-kms")]
+            KmsKey::Aws(key_id) => aws::wrap(key_id, private_key).await,
+            #[cfg(feature = "gcp-kms")]
+            KmsKey::Gcp(key_name) => gcp::wrap(key_name, private_key).await,
+            #[allow(unreachable_patterns)]
+            key => Err(key.not_built_with()),
+        }
+    }
+
+    /// Decrypts a key returned by `wrap`
+    pub async fn unwrap(&self, wrapped_key: &[u8]) -> Result<SecretSlice<u8>, KmsError> {
+        match self {
+            #[cfg(feature = "aws-kms")]
+            KmsKey::Aws(key_id) => aws::unwrap(key_id, wrapped_key).await,
+            #[cfg(feature = "gcp-kms")]
+            KmsKey::Gcp(key_name) => gcp::unwrap(key_name, wrapped_key).await,
+            #[allow(unreachable_patterns)]
+            key => Err(key.not_built_with()),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn not_built_with(&self) -> KmsError {
+        match self {
+            KmsKey::Aws(_) => KmsError::NotBuiltWith("aws-kms"),
+            KmsKey::Gcp(_) => KmsError::NotBuiltWith("gcp-kms"),
+        }
+    }
+}
+
+/// Whether `key_name` is projects/<p>/locations/<l>/keyRings/<r>/cryptoKeys/<k>, the ids
+/// being made of ASCII letters, digits, '-' and '_' only, so that it can't alter the URL
+/// it is put in
+fn is_gcp_crypto_key_name(key_name: &str) -> bool {
+    const COLLECTIONS: [&str; 4] = ["projects", "locations", "keyRings", "cryptoKeys"];
+    let is_id = |id: &str| {
+        !id.is_empty()
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    let segments: Vec<&str> = key_name.split('/').collect();
+    segments.len() == 2 * COLLECTIONS.len()
+        && segments.chunks(2).zip(COLLECTIONS).all(
+            |(pair, collection)| matches!(pair, [name, id] if *name == collection && is_id(id)),
+        )
+}
+
+#[cfg(feature = "aws-kms")]
+mod aws {
+    use super::{KmsError, KEY_PURPOSE};
+    use crate::crystal::secret_from_slice;
+    use aws_sdk_kms::primitives::Blob;
+    use secrecy::SecretSlice;
+    use zeroize::Zeroizing;
+
+    async fn client() -> aws_sdk_kms::Client {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        aws_sdk_kms::Client::new(&config)
+    }
+
+    fn aws_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> KmsError {
+        KmsError::Aws(Box::new(err))
+    }
+
+    pub(super) async fn wrap(key_id: &str, private_key: &[u8]) -> Result<Vec<u8>, KmsError> {
+        let output = client()
+            .await
+            .encrypt()
+            .key_id(key_id)
+            .plaintext(Blob::new(private_key))
+            .encryption_context("purpose", KEY_PURPOSE)
+            .send()
+            .await
+            .map_err(aws_error)?;
+        let wrapped_key = output.ciphertext_blob.ok_or(KmsError::MissingKey)?;
+        Ok(wrapped_key.into_inner())
+    }
+
+    pub(super) async fn unwrap(
+        key_id: &str,
+        wrapped_key: &[u8],
+    ) -> Result<SecretSlice<u8>, KmsError> {
+        let output = client()
+            .await
+            .decrypt()
+            .key_id(key_id)
+            .ciphertext_blob(Blob::new(wrapped_key))
+            .encryption_context("purpose", KEY_PURPOSE)
+            .send()
+            .await
+            .map_err(aws_error)?;
+        let private_key = output.plaintext.ok_or(KmsError::MissingKey)?;
+        Ok(secret_from_slice(&Zeroizing::new(private_key.into_inner())))
+    }
+}
+
+#[cfg(feature = "gcp-kms")]
+mod gcp {
+    use super::{KmsError, KEY_PURPOSE};
+    use crate::crystal::secret_from_slice;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use bytes::Bytes;
+    use secrecy::SecretSlice;
+    use serde::{Deserialize, Serialize};
+    use zeroize::{Zeroize, Zeroizing};
+
+    const KMS_SCOPE: &str = "https://www.googleapis.com/auth/cloudkms";
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct EncryptRequest<'a> {
+        plaintext: &'a str,
+        additional_authenticated_data: &'a str,
+    }
+
+    #[derive(Deserialize)]
+    struct EncryptResponse {
+        ciphertext: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct DecryptRequest<'a> {
+        ciphertext: &'a str,
+        additional_authenticated_data: &'a str,
+    }
+
+    #[derive(Deserialize, Zeroize)]
+    struct DecryptResponse {
+        plaintext: Option<String>,
+    }
+
+    /// Calls `method` (encrypt or decrypt) of the crypto key `key_name`, checked by
+    /// `KmsKey::parse`, through the REST API. Both request and response bodies are wiped
+    /// once done with, as either carries a plaintext key.
+    async fn call<T: Serialize, R: serde::de::DeserializeOwned>(
+        key_name: &str,
+        method: &str,
+        request: &T,
+    ) -> Result<R, KmsError> {
+        let provider = gcp_auth::provider().await?;
+        let token = provider.token(&[KMS_SCOPE]).await?;
+        let body = Zeroizing::new(serde_json::to_vec(request)?);
+        let response = reqwest::Client::new()
+            .post(format!(
+                "https://cloudkms.googleapis.com/v1/{key_name}:{method}"
+            ))
+            .bearer_auth(token.as_str())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(Bytes::from_owner(body))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        // the response is only referenced here, so the conversion takes its buffer over
+        let response = Zeroizing::new(Vec::from(response));
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    pub(super) async fn wrap(key_name: &str, private_key: &[u8]) -> Result<Vec<u8>, KmsError> {
+        let plaintext = Zeroizing::new(STANDARD.encode(private_key));
+        let request = EncryptRequest {
+            plaintext: &plaintext,
+            additional_authenticated_data: &STANDARD.encode(KEY_PURPOSE),
+        };
+        let response: EncryptResponse = call(key_name, "encrypt", &request).await?;
+        let ciphertext = response.ciphertext.ok_or(KmsError::MissingKey)?;
+        Ok(STANDARD.decode(ciphertext)?)
+    }
+
+    pub(super) async fn unwrap(
+        key_name: &str,
+        wrapped_key: &[u8],
+    ) -> Result<SecretSlice<u8>, KmsError> {
+        let request = DecryptRequest {
+            ciphertext: &STANDARD.encode(wrapped_key),
+            additional_authenticated_data: &STANDARD.encode(KEY_PURPOSE),
+        };
+        let mut response: DecryptResponse = call(key_name, "decrypt", &request).await?;
+        let private_key = response
+            .plaintext
+            .as_ref()
+            .ok_or(KmsError::MissingKey)
+            .and_then(|plaintext| {
+                // reserve upfront, so that decoding never reallocates and leaves copies behind
+                let mut private_key = Zeroizing::new(Vec::with_capacity(
+                    base64::decoded_len_estimate(plaintext.len()),
+                ));
+                STANDARD.decode_vec(plaintext, &mut private_key)?;
+                Ok(private_key)
+            });
+        response.zeroize();
+        Ok(secret_from_slice(&private_key?))
+    }
+}
+
+/// Wraps a (base64 encoded) secret key, as returned by `gen_keys`, with the KMS key at
+/// `kms_uri`, returning the (base64 encoded) wrapped key
+#[no_mangle]
+pub extern "C" fn wrap_key(kms_uri_cstr: *const i8, sk_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let kms_key = KmsKey::parse(&unsafe { decode_string_from_crystal(kms_uri_cstr)? })?;
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let wrapped_key = ffi_runtime()?.block_on(kms_key.wrap(private_key.expose_secret()))?;
+
+        let rv = JSONRetValRef {
+            retval: Base64Json(&wrapped_key),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Unwraps a (base64 encoded) key returned by `wrap_key` with the KMS key at `kms_uri`,
+/// returning the (base64 encoded) secret key
+#[no_mangle]
+pub extern "C" fn unwrap_key(kms_uri_cstr: *const i8, wrapped_key_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| unsafe {
+        unwrap_key_impl(kms_uri_cstr, wrapped_key_cstr, KeyEncoding::default())
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `unwrap_key`, but returns the secret key in `encoding`, see `KeyEncoding`
+#[no_mangle]
+pub extern "C" fn unwrap_key_encoded(
+    kms_uri_cstr: *const i8,
+    wrapped_key_cstr: *const i8,
+    encoding: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        unsafe { unwrap_key_impl(kms_uri_cstr, wrapped_key_cstr, encoding) }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the unwrap_key FFI functions
+///
+/// # Safety
+///
+/// Callers must provide valid NUL terminated string pointers.
+unsafe fn unwrap_key_impl(
+    kms_uri_cstr: *const i8,
+    wrapped_key_cstr: *const i8,
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let kms_key = KmsKey::parse(&unsafe { decode_string_from_crystal(kms_uri_cstr)? })?;
+    let wrapped_key =
+        unsafe { decode_untrusted_bytes_from_crystal(wrapped_key_cstr, InputKind::Key)? };
+    let private_key = ffi_runtime()?.block_on(kms_key.unwrap(&wrapped_key))?;
+    let sk_s = Zeroizing::new(encoding.encode(private_key.expose_secret()));
+
+    let rv = JSONRetValRef {
+        retval: sk_s.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Unwraps a (base64 encoded) key returned by `wrap_key` with the KMS key at `kms_uri`,
+/// writing a handle holding it to `handle_out`
+#[no_mangle]
+pub extern "C" fn pp_server_new_from_wrapped_key(
+    kms_uri_cstr: *const i8,
+    wrapped_key_cstr: *const i8,
+    handle_out: *mut *mut ServerHandle,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let kms_key = KmsKey::parse(&unsafe { decode_string_from_crystal(kms_uri_cstr)? })?;
+        let wrapped_key =
+            unsafe { decode_untrusted_bytes_from_crystal(wrapped_key_cstr, InputKind::Key)? };
+        let private_key = ffi_runtime()?.block_on(kms_key.unwrap(&wrapped_key))?;
+        let handle = Box::new(ServerHandle::new(private_key)?);
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kms_uris() {
+        assert_eq!(
+            KmsKey::parse("aws-kms://arn:aws:kms:eu-west-1:111122223333:key/abcd").unwrap(),
+            KmsKey::Aws("arn:aws:kms:eu-west-1:111122223333:key/abcd".to_string())
+        );
+        assert_eq!(
+            KmsKey::parse("gcp-kms://projects/p/locations/global/keyRings/r/cryptoKeys/k").unwrap(),
+            KmsKey::Gcp("projects/p/locations/global/keyRings/r/cryptoKeys/k".to_string())
+        );
+        assert!(matches!(
+            KmsKey::parse("vault://transit/keys/pp"),
+            Err(KmsError::UnsupportedUri(_))
+        ));
+    }
+
+    #[test]
+    fn test_refuse_malformed_gcp_key_names() {
+        for key_name in [
+            "",
+            "projects/p/locations/global/keyRings/r",
+            "projects/p/locations/global/keyRings/r/cryptoKeys/k/cryptoKeyVersions/1",
+            "projects/p/locations/global/keyRings/r/cryptoKeys/",
+            "projects/p/locations/global/keyRings/r/cryptoKeys/k/",
+            "projects/p/locations/global/keyRings/r/keyRings/k",
+            "projects/../locations/global/keyRings/r/cryptoKeys/k",
+            "projects/p/locations/global/keyRings/r/cryptoKeys/k:decrypt?",
+            "projects/p/locations/global/keyRings/r/cryptoKeys/k#",
+            "projects/p%2F/locations/global/keyRings/r/cryptoKeys/k",
+        ] {
+            assert!(
+                matches!(
+                    KmsKey::parse(&format!("gcp-kms://{key_name}")),
+                    Err(KmsError::InvalidGcpKeyName(_))
+                ),
+                "{key_name:?}"
+            );
+        }
+        let key_name = "projects/my-project/locations/europe-west1/keyRings/pp_keys/cryptoKeys/k-1";
+        assert_eq!(
+            KmsKey::parse(&format!("gcp-kms://{key_name}")).unwrap(),
+            KmsKey::Gcp(key_name.to_string())
+        );
+    }
+}
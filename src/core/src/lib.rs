@@ -1,8 +1,33 @@
+// unwraps, explicit panics and panicking indexing are refused outside tests, errors are
+// returned instead
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::indexing_slicing
+    )
+)]
+
 #[macro_use]
 extern crate panic_handler;
 
 use serde::{Deserialize, Serialize};
-mod batched_memory_stores;
+#[cfg(all(feature = "actix", not(target_arch = "wasm32")))]
+pub mod actix_origin;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod attester;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod audit;
+#[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+pub mod axum_issuer;
+#[cfg(feature = "server")]
+pub mod batched_memory_stores;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod batched_p384;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod bloom_nonce_store;
 
 #[derive(Serialize, Deserialize)]
 struct MyTokenReqState {
@@ -56,16 +81,129 @@ struct HexBlind(#[serde(with = "hex")] Vec<u8>);
 #[derive(Serialize, Deserialize)]
 struct HexToken(#[serde(with = "hex")] Vec<u8>);
 
+#[cfg(any(feature = "server", feature = "client"))]
 use privacypass::Nonce;
+#[cfg(any(feature = "server", feature = "client"))]
 const NONCE_BYTES: usize = std::mem::size_of::<Nonce>();
 
+pub mod capabilities;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod ceremony;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod challenge_authentication;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod challenge_freshness;
+#[cfg(all(feature = "chaos", not(target_arch = "wasm32")))]
+pub mod chaos;
+pub mod clock;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(any(feature = "server", feature = "client", feature = "serverless"))]
 mod config;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod crypto_pool;
 pub mod crystal;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "file-key-store", not(target_arch = "wasm32")))]
+pub mod file_key_store;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod generic_batched;
+#[cfg(all(feature = "grpc", not(target_arch = "wasm32")))]
+pub mod grpc;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod inspect;
+#[cfg(any(
+    all(feature = "server", not(target_arch = "wasm32")),
+    feature = "serverless",
+    feature = "client"
+))]
+// the serverless functions and the client only use part of it, the rest is for the server module
+#[cfg_attr(
+    not(all(feature = "server", not(target_arch = "wasm32"))),
+    allow(dead_code)
+)]
+mod issuance;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod issuer_directory;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod jwk;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod key_encoding;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod key_id;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod key_manager;
+#[cfg(all(feature = "pem", not(target_arch = "wasm32")))]
+pub mod key_pem;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod key_validity;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod keypair_check;
+#[cfg(all(
+    any(feature = "aws-kms", feature = "gcp-kms"),
+    not(target_arch = "wasm32")
+))]
+pub mod kms;
+pub mod limits;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod nonce_store;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod origin;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod origin_name;
+#[cfg(all(feature = "pkcs11", not(target_arch = "wasm32")))]
+pub mod pkcs11_signer;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod private_tokens;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod public_tokens;
+#[cfg(all(feature = "redis", not(target_arch = "wasm32")))]
+pub mod redis_nonce_store;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod replay;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod retention;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod revocation;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod runtime;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub mod server;
+#[cfg(feature = "serverless")]
+pub mod serverless;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod server_handle;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod server_sync;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod shutdown;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod signer;
+#[cfg(all(feature = "sql", not(target_arch = "wasm32")))]
+pub mod sql_stores;
+#[cfg(all(test, feature = "server", not(target_arch = "wasm32")))]
+mod test_fixtures;
+#[cfg(feature = "client")]
+pub mod token_store;
+#[cfg(all(feature = "tower", not(target_arch = "wasm32")))]
+pub mod tower_origin;
+pub mod transparency;
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub use config::GroupTokenType;
-#[cfg(not(target_arch = "wasm32"))]
-pub use server::{GenKeysError, PrivacyPass, RustKeypair, ValidateTokenError};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use key_manager::{KeyManager, KeyManagerConfig, KeyManagerError};
+#[cfg(feature = "server")]
+pub use nonce_store::{
+    AtomicNonceStore, KeyValueBackend, KvNonceStore, NonceStore, PrivacyPassNonceStore,
+};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::{
+    GenKeysError, GenTokenResponseError, PrivacyPass, RustKeypair, ValidateTokenError,
+    DEFAULT_KEY_INFO,
+};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server_sync::PrivacyPassSync;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,286 @@
+// -----------------------------------------------------------------------------
+// ---------------------  limits on untrusted inputs  --------------------------
+// -----------------------------------------------------------------------------
+//
+// Every base64 input coming from the network is checked against these caps before
+// being copied or decoded, so that gigantic malformed strings are rejected cheaply.
+
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use kagippverify::token::TOKEN_TYPE_PUBLIC_RSA;
+use std::sync::RwLock;
+use thiserror::Error;
+
+/// Maximum lengths (in bytes of the encoded input) accepted by the FFI functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputLimits {
+    pub max_token_len: usize,
+    pub max_token_request_len: usize,
+    pub max_token_response_len: usize,
+    pub max_token_challenge_len: usize,
+    pub max_key_len: usize,
+    pub max_header_len: usize,
+    pub max_rsa_key_len: usize,
+    pub max_token_request_batch_len: usize,
+    pub max_token_batch_len: usize,
+}
+
+// A BatchedToken is 162 bytes, i.e. 216 base64 characters, a Blind RSA token 354 bytes,
+// i.e. 472 base64 characters.
+// TokenRequests and TokenResponses grow by 32 bytes (~43 characters) per element.
+// PKCS#1 encoded RSA-2048 secret keys are ~1190 bytes, i.e. ~1590 base64 characters, well
+// over the size of the other keys, so they get a limit of their own.
+// JSON arrays of TokenRequests issued for in one call may hold many requests, up to 16 at the
+// TokenRequest limit by default, and JSON arrays of tokens validated in one call up to ~1000
+// tokens.
+const DEFAULT_INPUT_LIMITS: InputLimits = InputLimits {
+    max_token_len: 512,
+    max_token_request_len: 256 * 1024,
+    max_token_response_len: 256 * 1024,
+    max_token_challenge_len: 4 * 1024,
+    max_key_len: 1024,
+    max_header_len: 8 * 1024,
+    max_rsa_key_len: 4 * 1024,
+    max_token_request_batch_len: 4 * 1024 * 1024,
+    max_token_batch_len: 256 * 1024,
+};
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        DEFAULT_INPUT_LIMITS
+    }
+}
+
+static INPUT_LIMITS: RwLock<InputLimits> = RwLock::new(DEFAULT_INPUT_LIMITS);
+
+/// Returns the currently active input limits
+pub fn input_limits() -> InputLimits {
+    // a poisoned lock still holds a valid InputLimits, as it is only ever overwritten whole
+    *INPUT_LIMITS.read().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Replaces the currently active input limits
+pub fn configure_input_limits(limits: InputLimits) {
+    *INPUT_LIMITS.write().unwrap_or_else(|err| err.into_inner()) = limits;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    Token,
+    TokenRequest,
+    TokenResponse,
+    TokenChallenge,
+    Key,
+    Header,
+    RsaKey,
+    TokenRequestBatch,
+    TokenBatch,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InputTooLongError {
+    #[error("token input too long ({0} > {1} bytes)")]
+    Token(usize, usize),
+    #[error("token request input too long ({0} > {1} bytes)")]
+    TokenRequest(usize, usize),
+    #[error("token response input too long ({0} > {1} bytes)")]
+    TokenResponse(usize, usize),
+    #[error("token challenge input too long ({0} > {1} bytes)")]
+    TokenChallenge(usize, usize),
+    #[error("key input too long ({0} > {1} bytes)")]
+    Key(usize, usize),
+    #[error("header input too long ({0} > {1} bytes)")]
+    Header(usize, usize),
+    #[error("RSA key input too long ({0} > {1} bytes)")]
+    RsaKey(usize, usize),
+    #[error("token request batch input too long ({0} > {1} bytes)")]
+    TokenRequestBatch(usize, usize),
+    #[error("token batch input too long ({0} > {1} bytes)")]
+    TokenBatch(usize, usize),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown input kind {0}")]
+pub struct UnknownInputKindError(pub u8);
+
+impl InputTooLongError {
+    /// Stable error code, distinct for each kind of input
+    pub fn code(&self) -> &'static str {
+        match self {
+            InputTooLongError::Token(..) => "token_too_long",
+            InputTooLongError::TokenRequest(..) => "token_request_too_long",
+            InputTooLongError::TokenResponse(..) => "token_response_too_long",
+            InputTooLongError::TokenChallenge(..) => "token_challenge_too_long",
+            InputTooLongError::Key(..) => "key_too_long",
+            InputTooLongError::Header(..) => "header_too_long",
+            InputTooLongError::RsaKey(..) => "rsa_key_too_long",
+            InputTooLongError::TokenRequestBatch(..) => "token_request_batch_too_long",
+            InputTooLongError::TokenBatch(..) => "token_batch_too_long",
+        }
+    }
+}
+
+impl InputKind {
+    /// Kind by FFI value, in declaration order: 0 for Token up to 8 for TokenBatch
+    pub fn from_ffi(kind: u8) -> Result<Self, UnknownInputKindError> {
+        match kind {
+            0 => Ok(InputKind::Token),
+            1 => Ok(InputKind::TokenRequest),
+            2 => Ok(InputKind::TokenResponse),
+            3 => Ok(InputKind::TokenChallenge),
+            4 => Ok(InputKind::Key),
+            5 => Ok(InputKind::Header),
+            6 => Ok(InputKind::RsaKey),
+            7 => Ok(InputKind::TokenRequestBatch),
+            8 => Ok(InputKind::TokenBatch),
+            _ => Err(UnknownInputKindError(kind)),
+        }
+    }
+
+    /// Kind of the keys of `token_type`, so that RSA keys aren't held to the limit of others
+    pub fn key_of(token_type: Option<u16>) -> Self {
+        match token_type {
+            Some(TOKEN_TYPE_PUBLIC_RSA) => InputKind::RsaKey,
+            _ => InputKind::Key,
+        }
+    }
+
+    fn max_len(self, limits: &InputLimits) -> usize {
+        match self {
+            InputKind::Token => limits.max_token_len,
+            InputKind::TokenRequest => limits.max_token_request_len,
+            InputKind::TokenResponse => limits.max_token_response_len,
+            InputKind::TokenChallenge => limits.max_token_challenge_len,
+            InputKind::Key => limits.max_key_len,
+            InputKind::Header => limits.max_header_len,
+            InputKind::RsaKey => limits.max_rsa_key_len,
+            InputKind::TokenRequestBatch => limits.max_token_request_batch_len,
+            InputKind::TokenBatch => limits.max_token_batch_len,
+        }
+    }
+
+    fn too_long(self, len: usize, max: usize) -> InputTooLongError {
+        match self {
+            InputKind::Token => InputTooLongError::Token(len, max),
+            InputKind::TokenRequest => InputTooLongError::TokenRequest(len, max),
+            InputKind::TokenResponse => InputTooLongError::TokenResponse(len, max),
+            InputKind::TokenChallenge => InputTooLongError::TokenChallenge(len, max),
+            InputKind::Key => InputTooLongError::Key(len, max),
+            InputKind::Header => InputTooLongError::Header(len, max),
+            InputKind::RsaKey => InputTooLongError::RsaKey(len, max),
+            InputKind::TokenRequestBatch => InputTooLongError::TokenRequestBatch(len, max),
+            InputKind::TokenBatch => InputTooLongError::TokenBatch(len, max),
+        }
+    }
+
+    fn set_max_len(self, limits: &mut InputLimits, max_len: usize) {
+        let limit = match self {
+            InputKind::Token => &mut limits.max_token_len,
+            InputKind::TokenRequest => &mut limits.max_token_request_len,
+            InputKind::TokenResponse => &mut limits.max_token_response_len,
+            InputKind::TokenChallenge => &mut limits.max_token_challenge_len,
+            InputKind::Key => &mut limits.max_key_len,
+            InputKind::Header => &mut limits.max_header_len,
+            InputKind::RsaKey => &mut limits.max_rsa_key_len,
+            InputKind::TokenRequestBatch => &mut limits.max_token_request_batch_len,
+            InputKind::TokenBatch => &mut limits.max_token_batch_len,
+        };
+        *limit = max_len;
+    }
+}
+
+/// Checks `len` against the active limit for inputs of type `kind`
+pub fn check_input_len(kind: InputKind, len: usize) -> Result<(), InputTooLongError> {
+    let max = kind.max_len(&input_limits());
+    match len <= max {
+        true => Ok(()),
+        false => Err(kind.too_long(len, max)),
+    }
+}
+
+/// Sets the input limits used by every FFI function, but those of RSA keys and of batches,
+/// see `set_input_limit`.
+/// NOTE: pass 0 for any of the arguments to keep the default value for that limit
+#[no_mangle]
+pub extern "C" fn set_input_limits(
+    max_token_len: u32,
+    max_token_request_len: u32,
+    max_token_response_len: u32,
+    max_token_challenge_len: u32,
+    max_key_len: u32,
+    max_header_len: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let or_default = |value: u32, default: usize| match value {
+            0 => Ok(default),
+            _ => usize::try_from(value),
+        };
+        let limits = InputLimits {
+            max_token_len: or_default(max_token_len, DEFAULT_INPUT_LIMITS.max_token_len)?,
+            max_token_request_len: or_default(
+                max_token_request_len,
+                DEFAULT_INPUT_LIMITS.max_token_request_len,
+            )?,
+            max_token_response_len: or_default(
+                max_token_response_len,
+                DEFAULT_INPUT_LIMITS.max_token_response_len,
+            )?,
+            max_token_challenge_len: or_default(
+                max_token_challenge_len,
+                DEFAULT_INPUT_LIMITS.max_token_challenge_len,
+            )?,
+            max_key_len: or_default(max_key_len, DEFAULT_INPUT_LIMITS.max_key_len)?,
+            max_header_len: or_default(max_header_len, DEFAULT_INPUT_LIMITS.max_header_len)?,
+            ..input_limits()
+        };
+        configure_input_limits(limits);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Sets the limit of a single kind of input, see `InputKind::from_ffi` for `kind`, e.g. 6 for
+/// RSA keys, 7 for TokenRequest batches or 8 for token batches, whose limits
+/// `set_input_limits` leaves as is.
+/// NOTE: pass 0 as `max_len` to restore the default value for that limit
+#[no_mangle]
+pub extern "C" fn set_input_limit(kind: u8, max_len: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let kind = InputKind::from_ffi(kind)?;
+        let max_len = match max_len {
+            0 => kind.max_len(&DEFAULT_INPUT_LIMITS),
+            _ => usize::try_from(max_len)?,
+        };
+        kind.set_max_len(
+            &mut INPUT_LIMITS.write().unwrap_or_else(|err| err.into_inner()),
+            max_len,
+        );
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
@@ -0,0 +1,303 @@
+// -----------------------------------------------------------------------------
+// ------------------------  internal metrics registry  ------------------------
+// -----------------------------------------------------------------------------
+//
+// Per-operation latency histograms and nonce garbage collection counters, exposed as JSON
+// through `get_metrics` and in the Prometheus text exposition format through
+// `get_metrics_prometheus`.
+
+use crate::crystal::{
+    crystal_error, decode_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default histogram bucket upper bounds, in seconds
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Issuance,
+    Redemption,
+}
+
+impl Operation {
+    const ALL: [Operation; 2] = [Operation::Issuance, Operation::Redemption];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Issuance => "issuance",
+            Operation::Redemption => "redemption",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// upper bounds of the buckets, in seconds
+    pub buckets: Vec<f64>,
+    /// non-cumulative counts, with one extra overflow bucket at the end
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &[f64]) -> Self {
+        Histogram {
+            buckets: buckets.to_vec(),
+            counts: vec![0; buckets.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.buckets.len());
+        if let Some(count) = self.counts.get_mut(bucket) {
+            *count += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Counters of the nonce garbage collection tasks, see `spawn_nonce_gc_task`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NonceGcStats {
+    pub passes: u64,
+    /// nonces forgotten over all passes
+    pub pruned: u64,
+    /// nonces left in the process-wide nonce store after the last pass
+    pub entries: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub issuance_latency: Histogram,
+    pub redemption_latency: Histogram,
+    pub nonce_gc: NonceGcStats,
+}
+
+struct Histograms {
+    issuance: Histogram,
+    redemption: Histogram,
+}
+
+struct Registry {
+    buckets: Vec<f64>,
+    histograms: Option<Histograms>, // created lazily
+    nonce_gc: NonceGcStats,
+}
+
+impl Registry {
+    fn histogram(&mut self, operation: Operation) -> &mut Histogram {
+        if self.buckets.is_empty() {
+            self.buckets = DEFAULT_LATENCY_BUCKETS.to_vec();
+        }
+        let buckets = &self.buckets;
+        let histograms = self.histograms.get_or_insert_with(|| Histograms {
+            issuance: Histogram::new(buckets),
+            redemption: Histogram::new(buckets),
+        });
+        match operation {
+            Operation::Issuance => &mut histograms.issuance,
+            Operation::Redemption => &mut histograms.redemption,
+        }
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    buckets: Vec::new(),
+    histograms: None,
+    nonce_gc: NonceGcStats {
+        passes: 0,
+        pruned: 0,
+        entries: 0,
+    },
+});
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    // metrics are best effort, a poisoned registry is still usable
+    let mut registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    f(&mut registry)
+}
+
+pub fn record_latency(operation: Operation, latency: Duration) {
+    with_registry(|registry| registry.histogram(operation).observe(latency.as_secs_f64()));
+}
+
+/// Records a nonce garbage collection pass, which forgot `pruned` nonces and left `entries`
+pub fn record_nonce_gc(pruned: usize, entries: usize) {
+    with_registry(|registry| {
+        let stats = &mut registry.nonce_gc;
+        stats.passes += 1;
+        stats.pruned += pruned as u64;
+        stats.entries = entries as u64;
+    });
+}
+
+/// Replaces the histogram buckets (upper bounds in seconds) and resets all histograms
+pub fn configure_latency_buckets(buckets: &[f64]) -> Result<(), String> {
+    if buckets.is_empty() {
+        return Err("at least one latency bucket is required".to_string());
+    }
+    let increasing = buckets
+        .windows(2)
+        .all(|pair| matches!(pair, [low, high] if low < high));
+    if !increasing || buckets.iter().any(|bound| !bound.is_finite()) {
+        return Err("latency buckets must be finite and strictly increasing".to_string());
+    }
+    with_registry(|registry| {
+        registry.buckets = buckets.to_vec();
+        registry.histograms = None;
+    });
+    Ok(())
+}
+
+pub fn metrics_snapshot() -> MetricsSnapshot {
+    with_registry(|registry| MetricsSnapshot {
+        issuance_latency: registry.histogram(Operation::Issuance).clone(),
+        redemption_latency: registry.histogram(Operation::Redemption).clone(),
+        nonce_gc: registry.nonce_gc,
+    })
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    with_registry(|registry| {
+        for operation in Operation::ALL {
+            let name = format!("kagipp_{}_duration_seconds", operation.name());
+            let histogram = registry.histogram(operation);
+            out += &format!("# HELP {} Latency of {} calls.\n", name, operation.name());
+            out += &format!("# TYPE {} histogram\n", name);
+            let mut cumulative = 0;
+            for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+                cumulative += count;
+                out += &format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, cumulative);
+            }
+            out += &format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, histogram.count);
+            out += &format!("{}_sum {}\n", name, histogram.sum);
+            out += &format!("{}_count {}\n", name, histogram.count);
+        }
+        let nonce_gc = registry.nonce_gc;
+        for (name, kind, help, value) in [
+            (
+                "kagipp_nonce_gc_passes_total",
+                "counter",
+                "Nonce garbage collection passes.",
+                nonce_gc.passes,
+            ),
+            (
+                "kagipp_nonce_gc_pruned_total",
+                "counter",
+                "Nonces forgotten by garbage collection.",
+                nonce_gc.pruned,
+            ),
+            (
+                "kagipp_nonce_store_entries",
+                "gauge",
+                "Nonces held by the process-wide nonce store.",
+                nonce_gc.entries,
+            ),
+        ] {
+            out += &format!("# HELP {} {}\n", name, help);
+            out += &format!("# TYPE {} {}\n", name, kind);
+            out += &format!("{} {}\n", name, value);
+        }
+    });
+    out
+}
+
+/// Records the time elapsed between its creation and its drop, so that every return path
+/// (including early returns through `?`) of an instrumented function gets measured
+pub struct LatencyTimer {
+    operation: Operation,
+    start: Instant,
+}
+
+impl LatencyTimer {
+    pub fn start(operation: Operation) -> Self {
+        LatencyTimer {
+            operation,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        record_latency(self.operation, self.start.elapsed());
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_metrics() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&metrics_snapshot())?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn get_metrics_prometheus() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let rv = JSONRetVal {
+            retval: render_prometheus(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Takes a JSON array of bucket upper bounds in seconds, e.g. "[0.001, 0.01, 0.1]"
+#[no_mangle]
+pub extern "C" fn set_latency_buckets(buckets_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let buckets_s = unsafe { decode_string_from_crystal(buckets_cstr)? };
+        let buckets: Vec<f64> = serde_json::from_str(&buckets_s)?;
+        configure_latency_buckets(&buckets).map_err(|err| crystal_error(&err))?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
@@ -0,0 +1,227 @@
+// -----------------------------------------------------------------------------
+// ----------------------------  nonce stores  ---------------------------------
+// -----------------------------------------------------------------------------
+//
+// Nonce stores record the nonces of redeemed tokens, so that a token is only accepted once.
+// The traits below are the stable interface to plug a store of one's own into redemption
+// (`redeem_token_concurrently`, or `set_shared_nonce_store` for the FFI), without depending on
+// privacypass: they are this crate's own, and `PrivacyPassNonceStore` adapts them to the
+// privacypass trait of the same name, for privacypass' `Server::redeem_token`.
+// - `NonceStore::exists` tells whether a nonce was recorded, `NonceStore::insert` records it.
+// - `AtomicNonceStore::insert_if_absent` does both in one step, so that concurrent redemptions
+//   of a token can't all succeed. Stores shared between processes should implement it with a
+//   conditional write of the backend, not with `exists` followed by `insert`.
+// - Methods can't return errors. A store that can't reach its backend should fail closed:
+//   report nonces as recorded, and `insert_if_absent` as failed, refusing tokens rather than
+//   accepting them twice.
+// - `NonceStore::flush` waits for acknowledged writes to be durable, for stores buffering them.
+//   It does nothing by default, and is called on shutdown for the shared nonce store.
+// `KvNonceStore` implements both on top of any key-value store offering a conditional put
+// (DynamoDB's `attribute_not_exists`, an etcd transaction on `create_revision == 0`, ...),
+// see `KeyValueBackend`.
+
+pub use crate::batched_memory_stores::AtomicNonceStore;
+
+use crate::NONCE_BYTES;
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Nonce of a token, read from its `nonce` field
+pub type Nonce = [u8; NONCE_BYTES];
+
+/// Records the nonces of redeemed tokens
+#[async_trait]
+pub trait NonceStore {
+    /// Whether `nonce` was recorded
+    async fn exists(&self, nonce: &Nonce) -> bool;
+
+    /// Records `nonce`
+    async fn insert(&self, nonce: Nonce);
+
+    /// Waits for recorded nonces to be durable, for stores acknowledging writes before they are
+    async fn flush(&self) {}
+}
+
+/// Adapts a `NonceStore` of this crate to the privacypass trait, for `Server::redeem_token`
+pub struct PrivacyPassNonceStore<S>(pub S);
+
+#[async_trait]
+impl<S: NonceStore + Send + Sync> privacypass::NonceStore for PrivacyPassNonceStore<S> {
+    async fn exists(&self, nonce: &privacypass::Nonce) -> bool {
+        self.0.exists(nonce).await
+    }
+
+    async fn insert(&self, nonce: privacypass::Nonce) {
+        self.0.insert(nonce).await
+    }
+}
+
+/// Minimal interface of an external key-value store, enough to back a `KvNonceStore`
+#[async_trait]
+pub trait KeyValueBackend: Send + Sync {
+    type Error: Send;
+
+    /// Stores `key`, unless already present, returning whether it was stored. Must be atomic:
+    /// of two concurrent calls for the same key, at most one returns true.
+    /// `ttl`, if any, is how long the key should be kept for, backends may keep it longer.
+    async fn put_if_absent(&self, key: &[u8], ttl: Option<Duration>) -> Result<bool, Self::Error>;
+
+    async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error>;
+
+    /// Waits for stored keys to be durable, see `NonceStore::flush`
+    async fn flush(&self) {}
+}
+
+/// Nonce store recording nonces as keys of a `KeyValueBackend`, failing closed on its errors
+pub struct KvNonceStore<B: KeyValueBackend> {
+    backend: B,
+    key_prefix: Vec<u8>,
+    ttl: Option<Duration>,
+    failures: AtomicU64,
+}
+
+impl<B: KeyValueBackend> KvNonceStore<B> {
+    /// Records nonces under `key_prefix` followed by the raw nonce, kept for `ttl` if any.
+    /// NOTE: an expired nonce can be redeemed again, so `ttl` must exceed how long its token
+    ///       is accepted for.
+    pub fn new(backend: B, key_prefix: &[u8], ttl: Option<Duration>) -> Self {
+        KvNonceStore {
+            backend,
+            key_prefix: key_prefix.to_vec(),
+            ttl,
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn key_prefix(&self) -> &[u8] {
+        &self.key_prefix
+    }
+
+    /// Number of backend calls that failed so far, each of them reported a nonce as spent
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn key(&self, nonce: &Nonce) -> Vec<u8> {
+        nonce_key(&self.key_prefix, nonce)
+    }
+
+    fn failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Key a nonce is recorded under, the raw nonce bytes following `key_prefix`
+pub(crate) fn nonce_key(key_prefix: &[u8], nonce: &Nonce) -> Vec<u8> {
+    [key_prefix, nonce.as_slice()].concat()
+}
+
+#[async_trait]
+impl<B: KeyValueBackend> NonceStore for KvNonceStore<B> {
+    async fn exists(&self, nonce: &Nonce) -> bool {
+        self.backend
+            .contains(&self.key(nonce))
+            .await
+            .unwrap_or_else(|_| {
+                self.failed();
+                true
+            })
+    }
+
+    async fn insert(&self, nonce: Nonce) {
+        if self
+            .backend
+            .put_if_absent(&self.key(&nonce), self.ttl)
+            .await
+            .is_err()
+        {
+            self.failed();
+        }
+    }
+
+    async fn flush(&self) {
+        self.backend.flush().await
+    }
+}
+
+#[async_trait]
+impl<B: KeyValueBackend> AtomicNonceStore for KvNonceStore<B> {
+    async fn insert_if_absent(&self, nonce: Nonce) -> bool {
+        self.backend
+            .put_if_absent(&self.key(&nonce), self.ttl)
+            .await
+            .unwrap_or_else(|_| {
+                self.failed();
+                false
+            })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+
+    /// Stand-in for a DynamoDB table or an etcd cluster
+    #[derive(Default)]
+    struct TestBackend {
+        keys: Mutex<HashSet<Vec<u8>>>,
+        down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl KeyValueBackend for TestBackend {
+        type Error = &'static str;
+
+        async fn put_if_absent(
+            &self,
+            key: &[u8],
+            _ttl: Option<Duration>,
+        ) -> Result<bool, Self::Error> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err("unreachable");
+            }
+            Ok(self.keys.lock().unwrap().insert(key.to_vec()))
+        }
+
+        async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err("unreachable");
+            }
+            Ok(self.keys.lock().unwrap().contains(key))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_nonce_store() {
+        let nonce_store = KvNonceStore::new(TestBackend::default(), b"pp:", None);
+        let nonce: Nonce = [9u8; 32];
+        assert!(!nonce_store.exists(&nonce).await);
+        assert!(nonce_store.insert_if_absent(nonce).await);
+        assert!(!nonce_store.insert_if_absent(nonce).await);
+        assert!(nonce_store.exists(&nonce).await);
+        assert!(nonce_store
+            .backend()
+            .keys
+            .lock()
+            .unwrap()
+            .contains(&[b"pp:".as_slice(), &nonce].concat()));
+
+        // an unreachable backend refuses every nonce
+        nonce_store.backend().down.store(true, Ordering::Relaxed);
+        assert!(nonce_store.exists(&[10u8; 32]).await);
+        assert!(!nonce_store.insert_if_absent([10u8; 32]).await);
+        assert_eq!(nonce_store.failures(), 2);
+    }
+}
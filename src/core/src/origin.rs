@@ -0,0 +1,489 @@
+// -----------------------------------------------------------------------------
+// -------------------------------  origin  ------------------------------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9577 origin side shared by the web framework integrations (`tower_origin`,
+// `actix_origin`): an `Origin` challenges clients for tokens of its issuer key and redeems
+// the tokens of `Authorization: PrivateToken token=...` headers with
+// `redeem_token_concurrently`, against its key store and nonce store. Tokens that are
+// malformed, invalid, for another challenge or already spent are refused alike, the
+// integrations answering them with a 401 carrying a new
+// `WWW-Authenticate: PrivateToken challenge=..., token-key=...` header.
+// All origins redeeming tokens of the same issuer should share a nonce store (e.g. a
+// `RedisNonceStore`), or the same token could be spent once per origin process.
+// Redemptions go through the same key and challenge checks as `PrivacyPass::redeem_token`.
+// When challenges are authenticated (`set_challenge_mac_key`) or checked for freshness
+// (`set_challenge_max_age`), the challenge is stamped accordingly, and renewed once half of
+// the maximum challenge age has passed, tokens of the previous challenge still being
+// accepted until it goes stale.
+// NOTE: otherwise the challenge carries no redemption context, so the challenge digest of
+//       tokens is the same for every request and is checked as such.
+
+use crate::batched_memory_stores::{AtomicNonceStore, TokenKeyIdLookup};
+use crate::challenge_authentication::{
+    authenticated_token_challenge, ChallengeAuthenticationError,
+};
+use crate::challenge_freshness::{challenge_max_age, timestamped_redemption_context};
+use crate::clock::{global_clock, Clock};
+use crate::config::{batched_tokens_mod, GroupTokenType};
+use crate::crystal::{
+    decode_untrusted_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use crate::limits::InputKind;
+use crate::server::{redeem_token_concurrently, ValidateTokenError};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use batched_tokens_mod::{server::RedeemTokenError, BatchedToken};
+use http::HeaderValue;
+use privacypass::auth::authenticate::{build_www_authenticate_header, TokenChallenge};
+use std::sync::{Arc, RwLock};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tls_codec::Deserialize as TlsDeserializeTrait;
+
+#[derive(Error, Debug)]
+pub enum OriginError {
+    #[error("invalid token challenge")]
+    InvalidChallenge,
+}
+
+/// Reasons an `Authorization` header is refused, all answered with a 401 and a new challenge
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorizationError {
+    #[error("missing authorization header")]
+    Missing,
+    #[error("malformed authorization header")]
+    Malformed,
+    #[error("token for another challenge")]
+    ChallengeMismatch,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("token already spent")]
+    DoubleSpending,
+}
+
+/// Why `parse_authorization_token` refused an `Authorization` header value
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorizationHeaderError {
+    #[error("empty authorization header")]
+    Empty,
+    #[error("authorization scheme is not PrivateToken")]
+    UnsupportedScheme,
+    #[error("no token parameter in authorization header")]
+    MissingToken,
+    #[error("more than one token parameter in authorization header")]
+    DuplicateToken,
+    #[error("token parameter is not valid base64url")]
+    InvalidBase64,
+    #[error("token parameter is not a serialized token")]
+    InvalidToken,
+}
+
+impl AuthorizationHeaderError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthorizationHeaderError::Empty => "authorization_empty",
+            AuthorizationHeaderError::UnsupportedScheme => "authorization_unsupported_scheme",
+            AuthorizationHeaderError::MissingToken => "authorization_missing_token",
+            AuthorizationHeaderError::DuplicateToken => "authorization_duplicate_token",
+            AuthorizationHeaderError::InvalidBase64 => "authorization_invalid_base64",
+            AuthorizationHeaderError::InvalidToken => "authorization_invalid_token",
+        }
+    }
+}
+
+/// Serialized token of an `Authorization: PrivateToken token=<base64url token>` header value,
+/// ready for `validate_token`. The token parameter may be quoted, and is checked to be a
+/// well-formed token of a known token type, not to be valid.
+pub fn parse_authorization_token(value: &str) -> Result<Vec<u8>, AuthorizationHeaderError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(AuthorizationHeaderError::Empty);
+    }
+    let (scheme, params) = value.split_once(' ').unwrap_or((value, ""));
+    if !scheme.eq_ignore_ascii_case("PrivateToken") {
+        return Err(AuthorizationHeaderError::UnsupportedScheme);
+    }
+    let mut tokens = params
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("token"))
+        .map(|(_, token)| token.trim().trim_matches('"'));
+    let token_b64 = tokens
+        .next()
+        .ok_or(AuthorizationHeaderError::MissingToken)?;
+    if tokens.next().is_some() {
+        return Err(AuthorizationHeaderError::DuplicateToken);
+    }
+    let token = URL_SAFE
+        .decode(token_b64)
+        .map_err(|_| AuthorizationHeaderError::InvalidBase64)?;
+    kagippverify::Token::parse(&token).map_err(|_| AuthorizationHeaderError::InvalidToken)?;
+    Ok(token)
+}
+
+/// Serialized token of an Authorization header value, see `parse_authorization_token`,
+/// returned in base64. Refused headers are reported with the code of their
+/// `AuthorizationHeaderError`.
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn token_from_authorization_header(
+    authorization_header_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let authorization_header_s = unsafe {
+            decode_untrusted_string_from_crystal(authorization_header_cstr, InputKind::Header)
+        }?;
+        let token = parse_authorization_token(&authorization_header_s)?;
+        let rv = JSONRetVal {
+            retval: URL_SAFE.encode(token),
+            error: "".to_string(),
+        };
+
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Parses the token of an `Authorization: PrivateToken token=<base64 token>` header value,
+/// refusing the headers `parse_authorization_token` refuses and tokens of other token types
+/// as malformed
+pub fn parse_authorization(value: &str) -> Result<BatchedToken, AuthorizationError> {
+    let token_bytes =
+        parse_authorization_token(value).map_err(|_| AuthorizationError::Malformed)?;
+    let mut token_slice = token_bytes.as_slice();
+    let token = BatchedToken::tls_deserialize(&mut token_slice)
+        .map_err(|_| AuthorizationError::Malformed)?;
+    match token_slice.is_empty() {
+        true => Ok(token),
+        false => Err(AuthorizationError::Malformed),
+    }
+}
+
+/// Like `parse_authorization`, for the `http` header value
+pub fn parse_authorization_header(value: &HeaderValue) -> Result<BatchedToken, AuthorizationError> {
+    parse_authorization(value.to_str().map_err(|_| AuthorizationError::Malformed)?)
+}
+
+/// Challenge of the origin, and the issuer key clients are told to fetch tokens of
+#[derive(Debug, Clone)]
+pub struct OriginConfig {
+    pub issuer_name: String,
+    pub origin_info: Vec<String>,
+    /// serialized issuer public key, as by `gen_keys`
+    pub token_key: Vec<u8>,
+}
+
+struct Challenge {
+    header: HeaderValue,
+    /// base64 encoded, as redeemed tokens are checked against
+    token_challenge: String,
+    digest: Vec<u8>,
+    issued_at: u64,
+}
+
+impl Challenge {
+    fn new(config: &OriginConfig) -> Result<Self, OriginError> {
+        let issued_at = global_clock().unix_seconds();
+        let token_challenge = match authenticated_token_challenge(
+            GroupTokenType,
+            &config.issuer_name,
+            &config.origin_info,
+        ) {
+            Ok(token_challenge) => token_challenge,
+            Err(ChallengeAuthenticationError::NoKey) => TokenChallenge::new(
+                GroupTokenType,
+                &config.issuer_name,
+                challenge_max_age().map(|_| timestamped_redemption_context(issued_at)),
+                &config.origin_info,
+            ),
+            Err(_) => return Err(OriginError::InvalidChallenge),
+        };
+        let digest = token_challenge
+            .digest()
+            .map_err(|_| OriginError::InvalidChallenge)?
+            .to_vec();
+        let (_, header) = build_www_authenticate_header(&token_challenge, &config.token_key, None)
+            .map_err(|_| OriginError::InvalidChallenge)?;
+        Ok(Challenge {
+            header,
+            token_challenge: token_challenge
+                .to_base64()
+                .map_err(|_| OriginError::InvalidChallenge)?,
+            digest,
+            issued_at,
+        })
+    }
+}
+
+struct Challenges {
+    current: Challenge,
+    // still accepted after a renewal, until stale
+    previous: Option<Challenge>,
+}
+
+pub struct Origin {
+    config: RwLock<OriginConfig>,
+    challenges: RwLock<Challenges>,
+    key_store: Arc<dyn TokenKeyIdLookup + Send + Sync>,
+    nonce_store: Arc<dyn AtomicNonceStore + Send + Sync>,
+}
+
+impl Origin {
+    pub fn new(
+        config: OriginConfig,
+        key_store: Arc<dyn TokenKeyIdLookup + Send + Sync>,
+        nonce_store: Arc<dyn AtomicNonceStore + Send + Sync>,
+    ) -> Result<Self, OriginError> {
+        let challenge = Challenge::new(&config)?;
+        Ok(Origin {
+            config: RwLock::new(config),
+            challenges: RwLock::new(Challenges {
+                current: challenge,
+                previous: None,
+            }),
+            key_store,
+            nonce_store,
+        })
+    }
+
+    /// Challenges clients for tokens of `token_key` from now on, e.g. after a key rotation.
+    /// Tokens of previous keys are still accepted as long as the key store holds them
+    pub fn set_token_key(&self, token_key: &[u8]) -> Result<(), OriginError> {
+        let mut config = self
+            .config
+            .write()
+            .map_err(|_| OriginError::InvalidChallenge)?;
+        let challenge = Challenge::new(&OriginConfig {
+            token_key: token_key.to_vec(),
+            ..config.clone()
+        })?;
+        *self
+            .challenges
+            .write()
+            .map_err(|_| OriginError::InvalidChallenge)? = Challenges {
+            current: challenge,
+            previous: None,
+        };
+        config.token_key = token_key.to_vec();
+        Ok(())
+    }
+
+    /// Renews the challenge once half of the maximum challenge age has passed, if challenges
+    /// are checked for freshness, so that clients get to redeem their tokens before it goes
+    /// stale
+    fn renew_challenge(&self) {
+        let Some(max_age) = challenge_max_age() else {
+            return;
+        };
+        let now = global_clock().unix_seconds();
+        let due =
+            |challenges: &Challenges| now.abs_diff(challenges.current.issued_at) > max_age / 2;
+        if !self
+            .challenges
+            .read()
+            .is_ok_and(|challenges| due(&challenges))
+        {
+            return;
+        }
+        let Ok(config) = self.config.read() else {
+            return;
+        };
+        let Ok(mut challenges) = self.challenges.write() else {
+            return;
+        };
+        // renewed by a concurrent call in the meantime
+        if !due(&challenges) {
+            return;
+        }
+        if let Ok(challenge) = Challenge::new(&config) {
+            let previous = std::mem::replace(&mut challenges.current, challenge);
+            challenges.previous = Some(previous);
+        }
+    }
+
+    /// Value of the `WWW-Authenticate` header of refused requests
+    pub fn challenge(&self) -> Option<HeaderValue> {
+        self.renew_challenge();
+        self.challenges
+            .read()
+            .ok()
+            .map(|challenges| challenges.current.header.clone())
+    }
+
+    /// Redeems the token of the `Authorization` header value of a request, if any
+    pub async fn redeem(&self, authorization: Option<&str>) -> Result<(), AuthorizationError> {
+        let token = parse_authorization(authorization.ok_or(AuthorizationError::Missing)?)?;
+        self.renew_challenge();
+        let token_challenge = match self.challenges.read() {
+            Ok(challenges) => [Some(&challenges.current), challenges.previous.as_ref()]
+                .into_iter()
+                .flatten()
+                .find(|challenge| {
+                    bool::from(token.challenge_digest().as_slice().ct_eq(&challenge.digest))
+                })
+                .map(|challenge| challenge.token_challenge.clone()),
+            Err(_) => return Err(AuthorizationError::InvalidToken),
+        };
+        let token_challenge = token_challenge.ok_or(AuthorizationError::ChallengeMismatch)?;
+        redeem_token_concurrently(
+            &*self.key_store,
+            &*self.nonce_store,
+            token,
+            &token_challenge,
+        )
+        .await
+        .map_err(|err| match err {
+            ValidateTokenError::RedeemToken(RedeemTokenError::DoubleSpending) => {
+                AuthorizationError::DoubleSpending
+            }
+            _ => AuthorizationError::InvalidToken,
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_authorization() {
+        for value in [
+            "Bearer token=AAAA",
+            "PrivateToken",
+            "PrivateToken challenge=AAAA",
+            "PrivateToken token=not*base64",
+            "PrivateToken token=AAAA",
+            "PrivateToken token=AAAA, token=AAAA",
+        ] {
+            assert_eq!(
+                parse_authorization(value).err(),
+                Some(AuthorizationError::Malformed),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            parse_authorization_header(&HeaderValue::from_bytes(b"PrivateToken \xff").unwrap())
+                .err(),
+            Some(AuthorizationError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_parse_authorization_token() {
+        let mut token = vec![0x00, 0x05];
+        token.extend([1u8; 32 + 32 + 32 + 64]);
+        let token_b64 = URL_SAFE.encode(&token);
+        for value in [
+            format!("PrivateToken token={}", token_b64),
+            format!("privatetoken token=\"{}\"", token_b64),
+        ] {
+            assert_eq!(parse_authorization_token(&value).unwrap(), token);
+        }
+        for (value, err) in [
+            (" ".to_string(), AuthorizationHeaderError::Empty),
+            (
+                format!("Bearer token={}", token_b64),
+                AuthorizationHeaderError::UnsupportedScheme,
+            ),
+            (
+                "PrivateToken".to_string(),
+                AuthorizationHeaderError::MissingToken,
+            ),
+            (
+                format!("PrivateToken token={0}, token={0}", token_b64),
+                AuthorizationHeaderError::DuplicateToken,
+            ),
+            (
+                "PrivateToken token=not*base64".to_string(),
+                AuthorizationHeaderError::InvalidBase64,
+            ),
+            (
+                "PrivateToken token=AAAA".to_string(),
+                AuthorizationHeaderError::InvalidToken,
+            ),
+        ] {
+            assert_eq!(parse_authorization_token(&value), Err(err), "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redeem_checks_the_key() {
+        use crate::batched_memory_stores::{MemoryKeyStoreRistretto255, MemoryNonceStore};
+        use crate::config::VoprfGroup;
+        use crate::key_validity::{global_key_validities, KeyValidity};
+        use crate::NONCE_BYTES;
+        use batched_tokens_mod::client::Client;
+        use batched_tokens_mod::server::{serialize_public_key, Server};
+        use privacypass::Nonce;
+        use rand::{rngs::OsRng, RngCore};
+        use sha2::{Digest, Sha256};
+        use tls_codec::Serialize as TlsSerializeTrait;
+        use voprf::Group;
+
+        let key_store = Arc::new(MemoryKeyStoreRistretto255::default());
+        let public_key = Server::new()
+            .create_keypair_with_params(&*key_store, &[7u8; 32], b"origin")
+            .await
+            .unwrap();
+        let config = OriginConfig {
+            issuer_name: "issuer.example".to_string(),
+            origin_info: vec!["origin.example".to_string()],
+            token_key: serialize_public_key(public_key).to_vec(),
+        };
+        let token_challenge = TokenChallenge::new(
+            GroupTokenType,
+            &config.issuer_name,
+            None,
+            &config.origin_info,
+        );
+        let mut nonce: Nonce = [0u8; NONCE_BYTES];
+        OsRng.fill_bytes(&mut nonce);
+        let client = Client::new(public_key);
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(
+                &token_challenge,
+                vec![nonce],
+                vec![<VoprfGroup as Group>::Scalar::random(&mut OsRng)],
+            )
+            .unwrap();
+        let token_response = Server::new()
+            .issue_token_response(&*key_store, token_request)
+            .await
+            .unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+        let token = tokens[0].tls_serialize_detached().unwrap();
+        let authorization = format!("PrivateToken token={}", URL_SAFE.encode(&token));
+
+        let origin = Origin::new(config, key_store, Arc::new(MemoryNonceStore::default())).unwrap();
+        let token_key_id: [u8; 32] = Sha256::digest(serialize_public_key(public_key)).into();
+        global_key_validities().set(token_key_id, KeyValidity::from_unix_seconds(0, 1));
+        assert_eq!(
+            origin.redeem(Some(&authorization)).await,
+            Err(AuthorizationError::InvalidToken)
+        );
+        // the refused redemption did not spend the token
+        global_key_validities().set(token_key_id, KeyValidity::default());
+        assert_eq!(origin.redeem(Some(&authorization)).await, Ok(()));
+        assert_eq!(
+            origin.redeem(Some(&authorization)).await,
+            Err(AuthorizationError::DoubleSpending)
+        );
+    }
+}
@@ -0,0 +1,342 @@
+// -----------------------------------------------------------------------------
+// ---------------------  rate-limited token origin names  ---------------------
+// -----------------------------------------------------------------------------
+//
+// Clients of rate-limited tokens (see attester.rs) encrypt their token request, along with
+// the name of the origin they want a token for, to the issuer's origin name key, so that
+// the attester relaying the request never learns the origin. Encryption is HPKE (RFC 9180)
+// in base mode, with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and AES-128-GCM, under the
+// info string "TokenRequest":
+//   encrypted_token_request = issuer_key_id[32] || enc[Nenc] || ciphertext
+//   InnerTokenRequest = token_request<1..2^16-1> || origin_name<1..2^16-1> || padding
+// issuer_key_id is the SHA256 of the HPKE suite ids followed by the issuer public key. The
+// AAD is token_type (2) || request_key[Npk] || issuer_key_id, so issuers only open a request
+// along with the request key the attester checked its signature with, and clients can't get
+// the origins they ask for counted under another key than the one the attester pinned.
+// Origin names are zero padded to a multiple of ORIGIN_NAME_PADDING bytes, so that the
+// ciphertext size doesn't give their exact length away.
+
+use crate::attester::{REQUEST_KEY_LEN, TOKEN_TYPE_RATE_LIMITED};
+use hpke::aead::AesGcm128;
+use hpke::kdf::HkdfSha256;
+use hpke::kem::X25519HkdfSha256;
+use hpke::{Deserializable, Kem as KemTrait, OpModeR, OpModeS, Serializable};
+use p384::ecdsa::signature::Signer;
+use p384::ecdsa::{Signature, SigningKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+type Kem = X25519HkdfSha256;
+
+// HPKE KEM, KDF and AEAD ids (RFC 9180 section 7)
+const SUITE_IDS: [u8; 6] = [0x00, 0x20, 0x00, 0x01, 0x00, 0x01];
+const HPKE_INFO: &[u8] = b"TokenRequest";
+/// Size of encapsulated keys, X25519 public keys
+const ENC_LEN: usize = 32;
+/// Size of issuer origin name key ids
+pub const ORIGIN_NAME_KEY_ID_LEN: usize = 32;
+/// Origin names are zero padded to a multiple of this many bytes
+pub const ORIGIN_NAME_PADDING: usize = 32;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OriginNameError {
+    #[error("malformed encrypted token request")]
+    Malformed,
+    #[error("invalid origin name key")]
+    InvalidKey,
+    #[error("token request was encrypted to another origin name key")]
+    UnknownKeyId,
+    #[error("token request or origin name is empty or too long")]
+    InvalidLength,
+    #[error("failed to encrypt token request")]
+    Encryption,
+    #[error("failed to decrypt token request")]
+    Decryption,
+    #[error("origin name is not valid UTF-8")]
+    InvalidOriginName,
+}
+
+/// Token request and origin name of an AccessTokenRequest, as decrypted by the issuer
+#[derive(Debug, PartialEq, Eq)]
+pub struct InnerTokenRequest {
+    pub token_request: Vec<u8>,
+    pub origin_name: String,
+}
+
+/// Issuer origin name key, that clients encrypt their token requests to
+#[derive(Clone)]
+pub struct OriginNamePublicKey {
+    public_key: <Kem as KemTrait>::PublicKey,
+    key_id: [u8; ORIGIN_NAME_KEY_ID_LEN],
+}
+
+impl OriginNamePublicKey {
+    fn new(public_key: <Kem as KemTrait>::PublicKey) -> Self {
+        let key_id = Sha256::new()
+            .chain_update(SUITE_IDS)
+            .chain_update(public_key.to_bytes())
+            .finalize()
+            .into();
+        OriginNamePublicKey { public_key, key_id }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, OriginNameError> {
+        let public_key = <Kem as KemTrait>::PublicKey::from_bytes(bytes)
+            .map_err(|_| OriginNameError::InvalidKey)?;
+        Ok(Self::new(public_key))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.public_key.to_bytes().to_vec()
+    }
+
+    pub fn key_id(&self) -> &[u8; ORIGIN_NAME_KEY_ID_LEN] {
+        &self.key_id
+    }
+
+    /// Encrypts `token_request` for `origin_name`, into the encrypted token request of an
+    /// AccessTokenRequest signed by `request_key`
+    pub fn encrypt_token_request(
+        &self,
+        request_key: &[u8; REQUEST_KEY_LEN],
+        token_request: &[u8],
+        origin_name: &str,
+    ) -> Result<Vec<u8>, OriginNameError> {
+        let field_len = |field: &[u8]| {
+            u16::try_from(field.len())
+                .ok()
+                .filter(|len| *len > 0)
+                .ok_or(OriginNameError::InvalidLength)
+        };
+        let token_request_len = field_len(token_request)?;
+        let origin_name_len = field_len(origin_name.as_bytes())?;
+
+        let mut plaintext = Vec::with_capacity(4 + token_request.len() + origin_name.len());
+        plaintext.extend_from_slice(&token_request_len.to_be_bytes());
+        plaintext.extend_from_slice(token_request);
+        plaintext.extend_from_slice(&origin_name_len.to_be_bytes());
+        plaintext.extend_from_slice(origin_name.as_bytes());
+        plaintext.resize(plaintext.len() + padding_len(origin_name.len()), 0);
+
+        let (enc, ciphertext) = hpke::single_shot_seal::<AesGcm128, HkdfSha256, Kem, _>(
+            &OpModeS::Base,
+            &self.public_key,
+            HPKE_INFO,
+            &plaintext,
+            &aad(request_key, &self.key_id),
+            &mut OsRng,
+        )
+        .map_err(|_| OriginNameError::Encryption)?;
+        let mut encrypted_token_request = self.key_id.to_vec();
+        encrypted_token_request.extend_from_slice(&enc.to_bytes());
+        encrypted_token_request.extend_from_slice(&ciphertext);
+        Ok(encrypted_token_request)
+    }
+}
+
+/// Issuer side of origin name encryption
+pub struct OriginNameKey {
+    secret_key: <Kem as KemTrait>::PrivateKey,
+    public_key: OriginNamePublicKey,
+}
+
+impl OriginNameKey {
+    /// Derives the key of `seed`, which is the secret to keep to load the key again.
+    /// NOTE: seeds should be at least 32 bytes sampled from a CSPRNG
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let (secret_key, public_key) = Kem::derive_keypair(seed);
+        OriginNameKey {
+            secret_key,
+            public_key: OriginNamePublicKey::new(public_key),
+        }
+    }
+
+    pub fn public_key(&self) -> &OriginNamePublicKey {
+        &self.public_key
+    }
+
+    /// Decrypts the encrypted token request of an AccessTokenRequest signed by `request_key`,
+    /// which the attester forwards along with it
+    pub fn decrypt_token_request(
+        &self,
+        request_key: &[u8; REQUEST_KEY_LEN],
+        encrypted_token_request: &[u8],
+    ) -> Result<InnerTokenRequest, OriginNameError> {
+        let (key_id, rest) = encrypted_token_request
+            .split_first_chunk::<ORIGIN_NAME_KEY_ID_LEN>()
+            .ok_or(OriginNameError::Malformed)?;
+        if key_id != self.public_key.key_id() {
+            return Err(OriginNameError::UnknownKeyId);
+        }
+        let (enc, ciphertext) = rest
+            .split_first_chunk::<ENC_LEN>()
+            .ok_or(OriginNameError::Malformed)?;
+        let enc = <Kem as KemTrait>::EncappedKey::from_bytes(enc)
+            .map_err(|_| OriginNameError::Malformed)?;
+        let plaintext = hpke::single_shot_open::<AesGcm128, HkdfSha256, Kem>(
+            &OpModeR::Base,
+            &self.secret_key,
+            &enc,
+            HPKE_INFO,
+            ciphertext,
+            &aad(request_key, key_id),
+        )
+        .map_err(|_| OriginNameError::Decryption)?;
+
+        let (token_request, rest) = split_field(&plaintext)?;
+        let (origin_name, padding) = split_field(rest)?;
+        if padding.len() != padding_len(origin_name.len()) || padding.iter().any(|byte| *byte != 0)
+        {
+            return Err(OriginNameError::Malformed);
+        }
+        Ok(InnerTokenRequest {
+            token_request: token_request.to_vec(),
+            origin_name: std::str::from_utf8(origin_name)
+                .map_err(|_| OriginNameError::InvalidOriginName)?
+                .to_string(),
+        })
+    }
+}
+
+/// Client side: AccessTokenRequest signed by `signing_key`, the client's request key, asking
+/// for a token of `origin_name` through `token_request`
+pub fn build_access_token_request(
+    signing_key: &SigningKey,
+    issuer_key: &OriginNamePublicKey,
+    token_request: &[u8],
+    origin_name: &str,
+) -> Result<Vec<u8>, OriginNameError> {
+    let request_key: [u8; REQUEST_KEY_LEN] = signing_key
+        .verifying_key()
+        .to_encoded_point(true)
+        .as_bytes()
+        .try_into()
+        .map_err(|_| OriginNameError::InvalidKey)?;
+    let encrypted_token_request =
+        issuer_key.encrypt_token_request(&request_key, token_request, origin_name)?;
+    let encrypted_token_request_len =
+        u16::try_from(encrypted_token_request.len()).map_err(|_| OriginNameError::InvalidLength)?;
+
+    let mut access_token_request = TOKEN_TYPE_RATE_LIMITED.to_be_bytes().to_vec();
+    access_token_request.extend_from_slice(&request_key);
+    access_token_request.extend_from_slice(&encrypted_token_request_len.to_be_bytes());
+    access_token_request.extend_from_slice(&encrypted_token_request);
+    let request_signature: Signature = signing_key.sign(&access_token_request);
+    access_token_request.extend_from_slice(&request_signature.to_bytes());
+    Ok(access_token_request)
+}
+
+fn aad(request_key: &[u8; REQUEST_KEY_LEN], key_id: &[u8; ORIGIN_NAME_KEY_ID_LEN]) -> Vec<u8> {
+    [
+        TOKEN_TYPE_RATE_LIMITED.to_be_bytes().as_slice(),
+        request_key.as_slice(),
+        key_id.as_slice(),
+    ]
+    .concat()
+}
+
+/// Zero bytes following an origin name of `len` bytes
+fn padding_len(len: usize) -> usize {
+    len.next_multiple_of(ORIGIN_NAME_PADDING) - len
+}
+
+/// Splits a field<1..2^16-1> off the front of `bytes`
+fn split_field(bytes: &[u8]) -> Result<(&[u8], &[u8]), OriginNameError> {
+    let (len, rest) = bytes
+        .split_first_chunk::<2>()
+        .ok_or(OriginNameError::Malformed)?;
+    match usize::from(u16::from_be_bytes(*len)) {
+        0 => Err(OriginNameError::Malformed),
+        len => rest.split_at_checked(len).ok_or(OriginNameError::Malformed),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attester::{AccessTokenRequest, Attester, RateLimitPolicy};
+
+    #[test]
+    fn test_origin_name_round_trip() {
+        let issuer_key = OriginNameKey::from_seed(&[1u8; 32]);
+        let public_key =
+            OriginNamePublicKey::from_bytes(&issuer_key.public_key().to_bytes()).unwrap();
+        assert_eq!(public_key.key_id(), issuer_key.public_key().key_id());
+        let signing_key = SigningKey::from_bytes(&[2u8; 48].into()).unwrap();
+        let request =
+            build_access_token_request(&signing_key, &public_key, &[7u8; 40], "origin.example")
+                .unwrap();
+
+        // the attester only sees the encrypted token request
+        let attester = Attester::new(RateLimitPolicy::default());
+        let encrypted_token_request = attester.check_request(&[1u8; 32], &request).unwrap();
+        let request_key = AccessTokenRequest::parse(&request).unwrap().request_key;
+        assert!(!encrypted_token_request
+            .windows(b"origin.example".len())
+            .any(|window| window == b"origin.example"));
+        assert_eq!(
+            issuer_key
+                .decrypt_token_request(request_key, encrypted_token_request)
+                .unwrap(),
+            InnerTokenRequest {
+                token_request: vec![7u8; 40],
+                origin_name: "origin.example".to_string(),
+            }
+        );
+
+        // requests are bound to the request key they were signed with
+        let other_request_key: [u8; REQUEST_KEY_LEN] = SigningKey::from_bytes(&[3u8; 48].into())
+            .unwrap()
+            .verifying_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            issuer_key.decrypt_token_request(&other_request_key, encrypted_token_request),
+            Err(OriginNameError::Decryption)
+        );
+        let mut tampered = encrypted_token_request.to_vec();
+        if let Some(byte) = tampered.last_mut() {
+            *byte ^= 1;
+        }
+        assert_eq!(
+            issuer_key.decrypt_token_request(request_key, &tampered),
+            Err(OriginNameError::Decryption)
+        );
+        assert_eq!(
+            OriginNameKey::from_seed(&[4u8; 32])
+                .decrypt_token_request(request_key, encrypted_token_request),
+            Err(OriginNameError::UnknownKeyId)
+        );
+    }
+
+    #[test]
+    fn test_origin_names_are_padded() {
+        let public_key = OriginNameKey::from_seed(&[5u8; 32]).public_key().clone();
+        let request_key = [2u8; REQUEST_KEY_LEN];
+        let encrypted_len = |origin_name: &str| {
+            public_key
+                .encrypt_token_request(&request_key, &[7u8; 40], origin_name)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(
+            encrypted_len("a.example"),
+            encrypted_len("longer-name.example")
+        );
+        assert_ne!(
+            encrypted_len("a.example"),
+            encrypted_len(&"a".repeat(ORIGIN_NAME_PADDING + 1))
+        );
+        assert_eq!(
+            public_key.encrypt_token_request(&request_key, &[7u8; 40], ""),
+            Err(OriginNameError::InvalidLength)
+        );
+    }
+}
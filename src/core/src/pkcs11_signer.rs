@@ -0,0 +1,437 @@
+// -----------------------------------------------------------------------------
+// ----------------------------  PKCS#11 signer  -------------------------------
+// -----------------------------------------------------------------------------
+//
+// `Pkcs11Signer` keeps the issuer secret key in an HSM, or any other PKCS#11 token, so that
+// it never enters the issuer's memory: the operations of `VoprfSigner` are forwarded to the
+// module, and only their results come back.
+// PKCS#11 has no ristretto255 mechanisms, and the DLEQ proof of issuance needs the secret
+// scalar itself rather than points multiplied by it, so the module has to implement both
+// operations as vendor-defined mechanisms (e.g. nShield CodeSafe or Luna FM firmware),
+// called with C_Sign on the private key object:
+// - blind evaluation signs the concatenated serialized blinded elements, into the
+//   concatenated evaluated elements followed by the serialized DLEQ proof, as laid out in a
+//   TokenResponse without the length prefix;
+// - evaluation signs a token input, into its VOPRF output.
+// The public key is the CKA_VALUE of the public key object labelled like the private key,
+// serialized as in token challenges. Blind evaluations are only returned once their DLEQ
+// proof verifies against it, as clients would refuse them anyway, and a module evaluating
+// with another key could otherwise tag the clients it issues to.
+// Without an HSM, handles fall back to the software signer, see `pp_server_new`.
+
+use crate::config::{batched_tokens_mod, VoprfGroup};
+use crate::crystal::{
+    crystal_error, decode_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use crate::server_handle::ServerHandle;
+use crate::signer::{SignerError, VoprfSigner};
+use batched_tokens_mod::{server::deserialize_public_key, PublicKey, NE, NS};
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::vendor_defined::VendorDefinedMechanism;
+use cryptoki::mechanism::{Mechanism, MechanismType};
+use cryptoki::object::{Attribute, AttributeType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use secrecy::{ExposeSecret, SecretString};
+use sha2::{Digest, Sha512};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+use voprf::{
+    BlindedElement, CipherSuite, EvaluationElement, Group, Proof, VoprfServerBatchEvaluateResult,
+};
+
+// RFC 9497 context string of the VOPRF mode, followed by the cipher suite identifier
+const VOPRF_CONTEXT_PREFIX: &[u8] = b"OPRFV1-\x01-";
+// length prefix of serialized elements in the proof transcripts
+const ELEM_LEN: [u8; 2] = (NE as u16).to_be_bytes();
+
+pub struct Pkcs11SignerConfig {
+    /// shared library of the PKCS#11 module, e.g. "/usr/lib/softhsm/libsofthsm2.so"
+    pub module_path: PathBuf,
+    /// index of the slot among those with a token present
+    pub slot_index: usize,
+    pub pin: SecretString,
+    pub key_label: String,
+    /// vendor-defined mechanisms described above, as offsets from CKM_VENDOR_DEFINED
+    pub blind_evaluate_mechanism: u32,
+    pub evaluate_mechanism: u32,
+}
+
+/// C_Sign on the private key object, behind a trait so that tests can stand in for a module
+trait SignSession: Send {
+    fn sign(&self, mechanism: MechanismType, data: &[u8]) -> Result<Vec<u8>, SignerError>;
+}
+
+struct KeySession {
+    session: Session,
+    private_key: ObjectHandle,
+}
+
+impl SignSession for KeySession {
+    fn sign(&self, mechanism: MechanismType, data: &[u8]) -> Result<Vec<u8>, SignerError> {
+        let mechanism =
+            Mechanism::VendorDefined(VendorDefinedMechanism::new::<()>(mechanism, None));
+        Ok(self.session.sign(&mechanism, self.private_key, data)?)
+    }
+}
+
+pub struct Pkcs11Signer {
+    // sessions can't be used from several threads at once, calls take turns
+    session: Mutex<Box<dyn SignSession>>,
+    public_key: PublicKey,
+    blind_evaluate_mechanism: MechanismType,
+    evaluate_mechanism: MechanismType,
+}
+
+impl Pkcs11Signer {
+    /// Loads the module, logs into its token and looks the key up
+    pub fn open(config: &Pkcs11SignerConfig) -> Result<Self, SignerError> {
+        let pkcs11 = Pkcs11::new(&config.module_path)?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        let slot = *pkcs11
+            .get_slots_with_token()?
+            .get(config.slot_index)
+            .ok_or(SignerError::SlotNotFound(config.slot_index))?;
+        let session = pkcs11.open_ro_session(slot)?;
+        session.login(
+            UserType::User,
+            Some(&AuthPin::new(config.pin.expose_secret().to_string())),
+        )?;
+
+        let find_key = |class| {
+            let template = [
+                Attribute::Class(class),
+                Attribute::Label(config.key_label.as_bytes().to_vec()),
+            ];
+            session
+                .find_objects(&template)?
+                .first()
+                .copied()
+                .ok_or_else(|| SignerError::KeyNotFound(config.key_label.clone()))
+        };
+        let private_key = find_key(ObjectClass::PRIVATE_KEY)?;
+        let public_key_object = find_key(ObjectClass::PUBLIC_KEY)?;
+        let public_key = match session
+            .get_attributes(public_key_object, &[AttributeType::Value])?
+            .as_slice()
+        {
+            [Attribute::Value(value)] => {
+                deserialize_public_key(value).map_err(|_| SignerError::InvalidResponse)?
+            }
+            _ => return Err(SignerError::InvalidResponse),
+        };
+
+        Ok(Pkcs11Signer {
+            session: Mutex::new(Box::new(KeySession {
+                session,
+                private_key,
+            })),
+            public_key,
+            blind_evaluate_mechanism: MechanismType::new_vendor_defined(
+                config.blind_evaluate_mechanism.into(),
+            )?,
+            evaluate_mechanism: MechanismType::new_vendor_defined(
+                config.evaluate_mechanism.into(),
+            )?,
+        })
+    }
+
+    fn sign(&self, mechanism: MechanismType, data: &[u8]) -> Result<Vec<u8>, SignerError> {
+        // a poisoned lock still holds a valid session, PKCS#11 calls don't leave it half updated
+        let session = self.session.lock().unwrap_or_else(|err| err.into_inner());
+        session.sign(mechanism, data)
+    }
+}
+
+/// Verifies the batched DLEQ proof of `evaluation`, that `public_key` evaluated
+/// `blinded_elements` into its messages, as clients do before unblinding (RFC 9497 section
+/// 2.2.2, with the composites of section 2.2.1)
+fn verify_batch_proof(
+    public_key: PublicKey,
+    blinded_elements: &[BlindedElement<VoprfGroup>],
+    evaluation: &VoprfServerBatchEvaluateResult<VoprfGroup>,
+) -> Result<(), SignerError> {
+    if evaluation.messages.len() != blinded_elements.len() {
+        return Err(SignerError::InvalidProof);
+    }
+    let context = [
+        VOPRF_CONTEXT_PREFIX,
+        <VoprfGroup as CipherSuite>::ID.as_bytes(),
+    ]
+    .concat();
+    let hash_to_scalar = |input: &[&[u8]]| {
+        <VoprfGroup as Group>::hash_to_scalar::<VoprfGroup>(
+            input,
+            &[b"HashToScalar-".as_slice(), context.as_slice()],
+        )
+        .map_err(|_| SignerError::InvalidProof)
+    };
+    let public_key_bytes = <VoprfGroup as Group>::serialize_elem(public_key);
+
+    // composites M and Z of the blinded and evaluated elements
+    let seed_dst = [b"Seed-".as_slice(), context.as_slice()].concat();
+    let seed_dst_len = u16::try_from(seed_dst.len()).map_err(|_| SignerError::InvalidProof)?;
+    let seed = Sha512::new()
+        .chain_update(ELEM_LEN)
+        .chain_update(public_key_bytes)
+        .chain_update(seed_dst_len.to_be_bytes())
+        .chain_update(&seed_dst)
+        .finalize();
+    let seed_len = u16::try_from(seed.len()).map_err(|_| SignerError::InvalidProof)?;
+    let mut composite_m = <VoprfGroup as Group>::identity_elem();
+    let mut composite_z = <VoprfGroup as Group>::identity_elem();
+    for (index, (blinded_element, message)) in blinded_elements
+        .iter()
+        .zip(&evaluation.messages)
+        .enumerate()
+    {
+        let index = u16::try_from(index).map_err(|_| SignerError::InvalidProof)?;
+        let blinded_bytes = blinded_element.serialize();
+        let evaluated_bytes = message.serialize();
+        let weight = hash_to_scalar(&[
+            seed_len.to_be_bytes().as_slice(),
+            seed.as_slice(),
+            index.to_be_bytes().as_slice(),
+            ELEM_LEN.as_slice(),
+            blinded_bytes.as_slice(),
+            ELEM_LEN.as_slice(),
+            evaluated_bytes.as_slice(),
+            b"Composite".as_slice(),
+        ])?;
+        composite_m =
+            <VoprfGroup as Group>::deserialize_elem(&blinded_bytes)? * &weight + &composite_m;
+        composite_z =
+            <VoprfGroup as Group>::deserialize_elem(&evaluated_bytes)? * &weight + &composite_z;
+    }
+
+    // proof = challenge (c) || response (s)
+    let proof_bytes = evaluation.proof.serialize();
+    let (challenge_bytes, response_bytes) = proof_bytes
+        .split_at_checked(NS)
+        .ok_or(SignerError::InvalidProof)?;
+    let challenge = <VoprfGroup as Group>::deserialize_scalar(challenge_bytes)?;
+    let response = <VoprfGroup as Group>::deserialize_scalar(response_bytes)?;
+    let t2 = <VoprfGroup as Group>::base_elem() * &response + &(public_key * &challenge);
+    let t3 = composite_m * &response + &(composite_z * &challenge);
+    let expected_challenge = hash_to_scalar(&[
+        ELEM_LEN.as_slice(),
+        public_key_bytes.as_slice(),
+        ELEM_LEN.as_slice(),
+        <VoprfGroup as Group>::serialize_elem(composite_m).as_slice(),
+        ELEM_LEN.as_slice(),
+        <VoprfGroup as Group>::serialize_elem(composite_z).as_slice(),
+        ELEM_LEN.as_slice(),
+        <VoprfGroup as Group>::serialize_elem(t2).as_slice(),
+        ELEM_LEN.as_slice(),
+        <VoprfGroup as Group>::serialize_elem(t3).as_slice(),
+        b"Challenge".as_slice(),
+    ])?;
+    if !bool::from(expected_challenge.ct_eq(&challenge)) {
+        return Err(SignerError::InvalidProof);
+    }
+    Ok(())
+}
+
+impl VoprfSigner for Pkcs11Signer {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    fn blind_evaluate(
+        &self,
+        blinded_elements: &[BlindedElement<VoprfGroup>],
+    ) -> Result<VoprfServerBatchEvaluateResult<VoprfGroup>, SignerError> {
+        let request: Vec<u8> = blinded_elements
+            .iter()
+            .flat_map(|blinded_element| blinded_element.serialize())
+            .collect();
+        let response = self.sign(self.blind_evaluate_mechanism, &request)?;
+
+        // evaluated elements have the size of blinded elements
+        let (evaluated_elements, proof) = response
+            .split_at_checked(blinded_elements.len() * NE)
+            .ok_or(SignerError::InvalidResponse)?;
+        let messages = evaluated_elements
+            .chunks_exact(NE)
+            .map(EvaluationElement::<VoprfGroup>::deserialize)
+            .collect::<Result<Vec<_>, _>>()?;
+        let proof = Proof::<VoprfGroup>::deserialize(proof)?;
+        let evaluation = VoprfServerBatchEvaluateResult { messages, proof };
+        verify_batch_proof(self.public_key, blinded_elements, &evaluation)?;
+        Ok(evaluation)
+    }
+
+    fn evaluate(&self, input: &[u8]) -> Result<Vec<u8>, SignerError> {
+        self.sign(self.evaluate_mechanism, input)
+    }
+}
+
+/// Opens the key labelled `key_label` in the token of the `slot_index`-th slot with a token
+/// present of the PKCS#11 module at `module_path`, logging in with `pin`, and writes a handle
+/// evaluating through it to `handle_out`. The mechanisms are the offsets from
+/// CKM_VENDOR_DEFINED of the module's VOPRF blind evaluation and evaluation mechanisms.
+/// NOTE: handles keep a session open on the token, and release it in `pp_server_free`
+#[no_mangle]
+pub extern "C" fn pp_server_new_from_pkcs11(
+    module_path_cstr: *const i8,
+    slot_index: u32,
+    pin_cstr: *const i8,
+    key_label_cstr: *const i8,
+    blind_evaluate_mechanism: u32,
+    evaluate_mechanism: u32,
+    handle_out: *mut *mut ServerHandle,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let config = Pkcs11SignerConfig {
+            module_path: PathBuf::from(unsafe { decode_string_from_crystal(module_path_cstr)? }),
+            slot_index: usize::try_from(slot_index)?,
+            pin: SecretString::from(unsafe { decode_string_from_crystal(pin_cstr)? }),
+            key_label: unsafe { decode_string_from_crystal(key_label_cstr)? },
+            blind_evaluate_mechanism,
+            evaluate_mechanism,
+        };
+        let signer = Pkcs11Signer::open(&config)?;
+        let handle = Box::new(ServerHandle::with_signer(Box::new(signer)));
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use voprf::{VoprfClient, VoprfServer};
+
+    /// Stands in for a module implementing the vendor-defined mechanisms in software
+    struct MockSession {
+        server: VoprfServer<VoprfGroup>,
+        evaluate_mechanism: MechanismType,
+    }
+
+    impl SignSession for MockSession {
+        fn sign(&self, mechanism: MechanismType, data: &[u8]) -> Result<Vec<u8>, SignerError> {
+            if mechanism == self.evaluate_mechanism {
+                return Ok(self.server.evaluate(data)?.to_vec());
+            }
+            let blinded_elements = data
+                .chunks_exact(NE)
+                .map(BlindedElement::<VoprfGroup>::deserialize)
+                .collect::<Result<Vec<_>, _>>()?;
+            let evaluation = self
+                .server
+                .batch_blind_evaluate(&mut OsRng, &blinded_elements)?;
+            let mut response: Vec<u8> = evaluation
+                .messages
+                .iter()
+                .flat_map(|message| message.serialize())
+                .collect();
+            response.extend(evaluation.proof.serialize());
+            Ok(response)
+        }
+    }
+
+    fn mock_signer(seed: u8, public_key: Option<PublicKey>) -> Pkcs11Signer {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[seed; 32], b"pkcs11").unwrap();
+        let evaluate_mechanism = MechanismType::new_vendor_defined(2u32.into()).unwrap();
+        Pkcs11Signer {
+            public_key: public_key.unwrap_or_else(|| server.get_public_key()),
+            session: Mutex::new(Box::new(MockSession {
+                server,
+                evaluate_mechanism,
+            })),
+            blind_evaluate_mechanism: MechanismType::new_vendor_defined(1u32.into()).unwrap(),
+            evaluate_mechanism,
+        }
+    }
+
+    fn blinded_elements(nr: u8) -> Vec<BlindedElement<VoprfGroup>> {
+        (0..nr)
+            .map(|i| {
+                VoprfClient::<VoprfGroup>::blind(&[i], &mut OsRng)
+                    .unwrap()
+                    .message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pkcs11_signer_verifies_proofs() {
+        let signer = mock_signer(1, None);
+        let input = b"token input";
+        let blind = VoprfClient::<VoprfGroup>::blind(input, &mut OsRng).unwrap();
+        let evaluation = signer
+            .blind_evaluate(std::slice::from_ref(&blind.message))
+            .unwrap();
+        let output = blind
+            .state
+            .finalize(
+                input,
+                &evaluation.messages[0],
+                &evaluation.proof,
+                signer.public_key(),
+            )
+            .unwrap();
+        assert_eq!(signer.evaluate(input).unwrap(), output.to_vec());
+
+        let evaluation = signer.blind_evaluate(&blinded_elements(5)).unwrap();
+        assert_eq!(evaluation.messages.len(), 5);
+    }
+
+    #[test]
+    fn test_pkcs11_signer_refuses_other_key() {
+        // the module evaluates with another key than the published one
+        let public_key = mock_signer(2, None).public_key();
+        let signer = mock_signer(3, Some(public_key));
+        for nr in [1, 5] {
+            assert!(matches!(
+                signer.blind_evaluate(&blinded_elements(nr)),
+                Err(SignerError::InvalidProof)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verify_batch_proof_refuses_mismatched_elements() {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[4u8; 32], b"pkcs11").unwrap();
+        let blinded_elements = blinded_elements(3);
+        let evaluation = server
+            .batch_blind_evaluate(&mut OsRng, &blinded_elements)
+            .unwrap();
+        let public_key = server.get_public_key();
+        assert!(verify_batch_proof(public_key, &blinded_elements, &evaluation).is_ok());
+
+        let mut reordered = blinded_elements.clone();
+        reordered.swap(0, 1);
+        assert!(matches!(
+            verify_batch_proof(public_key, &reordered, &evaluation),
+            Err(SignerError::InvalidProof)
+        ));
+        assert!(matches!(
+            verify_batch_proof(public_key, &blinded_elements[1..], &evaluation),
+            Err(SignerError::InvalidProof)
+        ));
+    }
+}
@@ -0,0 +1,562 @@
+// -----------------------------------------------------------------------------
+// ------------------  privately verifiable tokens (VOPRF P-384)  --------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9578 token type 0x0001, the standards-default privately verifiable token: one token per
+// TokenRequest, issued with the P-384 VOPRF of privacypass-rust's `private_tokens` module.
+//   TokenRequest  = token_type (2) || truncated_token_key_id (1) || blinded_msg[Ne]
+//   TokenResponse = evaluate_msg[Ne] || evaluate_proof[Ns+Ns]
+// Secret keys are serialized P-384 scalars, public keys compressed P-384 points, whose SHA256
+// is the token key id. Unlike batched keys, keys are generated without avoiding the truncated
+// key ids of keys still in use. Tokens are checked without branching on which check failed,
+// see `verify_p384_token_uniformly`, which batched P-384 tokens share. The FFI refuses replays,
+// while `validate_p384_token` leaves them to the caller.
+
+use crate::audit::{record_redemption, RedemptionOutcome};
+use crate::batched_memory_stores::MemoryKeyStoreP384;
+use crate::challenge_authentication::ChallengeAuthenticationError;
+use crate::challenge_freshness::ChallengeFreshnessError;
+use crate::crypto_pool::{run_blocking, CryptoPoolError};
+use crate::crystal::{
+    borrow_untrusted_str_from_crystal, decode_secret_bytes_from_crystal,
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, encode_secret_json_for_crystal,
+    error_chain_json_retval, error_json_retval, secret_from_slice, JSONRetVal, JSONRetValRef,
+};
+use crate::inspect::TokenRequestInfo;
+use crate::key_encoding::KeyEncoding;
+use crate::key_validity::KeyValidityError;
+use crate::limits::InputKind;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::replay::redeem_nonce;
+use crate::revocation::KeyRevokedError;
+use crate::runtime::ffi_runtime;
+use crate::server::{
+    check_key, check_redemption_challenge, token_response_for_crystal, KeyPair, KeyUse, PrivacyPass,
+};
+use generic_array::GenericArray;
+use kagippverify::token::{
+    token_nonce, CHALLENGE_DIGEST_OFFSET, TOKEN_INPUT_LEN, TOKEN_KEY_ID_OFFSET,
+    TOKEN_TYPE_PRIVATE_P384,
+};
+use p384::NistP384;
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::private_tokens::server::{CreateKeypairError, IssueTokenResponseError, Server};
+use privacypass::private_tokens::{TokenRequest, TokenResponse};
+use privacypass::TruncatedTokenKeyId;
+use rand::{rngs::OsRng, RngCore};
+use secrecy::{ExposeSecret, SecretSlice};
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+use thiserror::Error;
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use voprf::{derive_key, Group, Mode, VoprfServer};
+use zeroize::Zeroizing;
+
+/// Size of token authenticators, i.e. of P-384 VOPRF outputs
+const NK: usize = 48;
+const TOKEN_LEN: usize = TOKEN_INPUT_LEN + NK;
+/// token_type || truncated_token_key_id || blinded_msg[Ne], with compressed points
+pub(crate) const TOKEN_REQUEST_LEN: usize = 2 + 1 + 49;
+
+#[derive(Error, Debug)]
+pub enum PrivateTokenError {
+    #[error("failed to construct keypair")]
+    CreateKeypair(#[from] CreateKeypairError),
+    #[error("failed generating secret key")]
+    DeriveKey(voprf::Error),
+    #[error("failed to load secret key")]
+    InvalidKey(voprf::Error),
+    #[error("failed to issue token response")]
+    IssueTokenResponse(#[from] IssueTokenResponseError),
+    #[error("failed to (de)serialize token request")]
+    Tls(#[from] tls_codec::Error),
+    #[error("failed to run the P-384 VOPRF")]
+    CryptoPool(#[from] CryptoPoolError),
+    #[error("key was revoked")]
+    KeyRevoked(#[from] KeyRevokedError),
+    #[error("key is outside of its validity window")]
+    KeyValidity(#[from] KeyValidityError),
+    #[error("token challenge is not fresh")]
+    ChallengeFreshness(#[from] ChallengeFreshnessError),
+    #[error("token challenge is not authenticated")]
+    ChallengeAuthentication(#[from] ChallengeAuthenticationError),
+}
+
+/// P-384 issuer keypair
+#[derive(Debug)]
+pub struct P384Keypair {
+    /// compressed P-384 point
+    pub public_key: Vec<u8>,
+    /// serialized P-384 scalar
+    pub secret_key: SecretSlice<u8>,
+    /// info string the key was derived under
+    pub info: Vec<u8>,
+}
+
+pub(crate) fn serialize_public_key(public_key: <NistP384 as Group>::Elem) -> Vec<u8> {
+    NistP384::serialize_elem(public_key).to_vec()
+}
+
+pub(crate) fn public_key_to_token_key_id(public_key: <NistP384 as Group>::Elem) -> [u8; 32] {
+    Sha256::digest(serialize_public_key(public_key)).into()
+}
+
+pub(crate) fn public_key_to_truncated_token_key_id(
+    public_key: <NistP384 as Group>::Elem,
+) -> TruncatedTokenKeyId {
+    let [.., truncated_token_key_id] = public_key_to_token_key_id(public_key);
+    truncated_token_key_id
+}
+
+/// Derives a fresh P-384 keypair under `info`
+pub fn gen_p384_keys(
+    rt: &tokio::runtime::Handle,
+    info: &[u8],
+) -> Result<P384Keypair, PrivateTokenError> {
+    let mut seed = Zeroizing::new(GenericArray::<_, <NistP384 as Group>::ScalarLen>::default());
+    OsRng.fill_bytes(&mut seed);
+
+    let server = Server::new();
+    let key_store = MemoryKeyStoreP384::default();
+    let public_key = rt.block_on(server.create_keypair_with_params(&key_store, &seed, info))?;
+    let secret_key =
+        derive_key::<NistP384>(&seed, info, Mode::Voprf).map_err(PrivateTokenError::DeriveKey)?;
+
+    Ok(P384Keypair {
+        public_key: serialize_public_key(public_key),
+        secret_key: secret_from_slice(&NistP384::serialize_scalar(secret_key)),
+        info: info.to_vec(),
+    })
+}
+
+/// Issues the TokenResponse for a single token TokenRequest with a serialized secret key
+pub fn issue_p384_token_response(
+    rt: &tokio::runtime::Handle,
+    private_key: &[u8],
+    token_request: TokenRequest,
+) -> Result<TokenResponse, PrivateTokenError> {
+    let server = Server::new();
+    let key_store = MemoryKeyStoreP384::default();
+    rt.block_on(async {
+        let public_key = server.set_key(&key_store, private_key).await?;
+        check_key::<PrivateTokenError>(&public_key_to_token_key_id(public_key), KeyUse::Issuance)?;
+        Ok(server
+            .issue_token_response(&key_store, token_request)
+            .await?)
+    })
+}
+
+/// Checks a serialized P-384 token of `token_type` against `server`, combining the outcome of
+/// every check without branching on any of them, like `verify_token_uniformly` does for
+/// ristretto255 tokens. Type 0x0001 and batched P-384 tokens share the same layout.
+pub(crate) fn verify_p384_token_uniformly(
+    server: &VoprfServer<NistP384>,
+    token: &[u8],
+    token_type: u16,
+    challenge_digest: Option<&[u8]>,
+) -> Choice {
+    let size = token.len().ct_eq(&TOKEN_LEN);
+    let mut token_bytes = [0u8; TOKEN_LEN];
+    token_bytes
+        .iter_mut()
+        .zip(token)
+        .for_each(|(byte, token_byte)| *byte = *token_byte);
+    let (token_input, authenticator) = token_bytes.split_at(TOKEN_INPUT_LEN);
+    // the ranges are within TOKEN_INPUT_LEN, the empty fallback only ever fails the check
+    let field = |range: std::ops::Range<usize>| token_input.get(range).unwrap_or_default();
+
+    let token_type = field(0..2).ct_eq(&token_type.to_be_bytes());
+    let digest = match challenge_digest {
+        Some(challenge_digest) => {
+            field(CHALLENGE_DIGEST_OFFSET..TOKEN_KEY_ID_OFFSET).ct_eq(challenge_digest)
+        }
+        None => Choice::from(1),
+    };
+    let token_key_id = public_key_to_token_key_id(server.get_public_key());
+    let key_id = field(TOKEN_KEY_ID_OFFSET..TOKEN_INPUT_LEN).ct_eq(&token_key_id);
+    let authenticator = match server.evaluate(token_input) {
+        Ok(expected) => expected.as_slice().ct_eq(authenticator),
+        Err(_) => Choice::from(0),
+    };
+
+    size & token_type & digest & key_id & authenticator
+}
+
+/// Loads `private_key` and checks a serialized type 0x0001 token against it, returning the
+/// token key id of the key along with the validity of the token
+fn check_p384_token(
+    private_key: &[u8],
+    token: &[u8],
+    challenge_digest: Option<&[u8]>,
+) -> Result<([u8; 32], bool), PrivateTokenError> {
+    let server = VoprfServer::<NistP384>::new_with_key(private_key)
+        .map_err(PrivateTokenError::InvalidKey)?;
+    let token_key_id = public_key_to_token_key_id(server.get_public_key());
+    check_key::<PrivateTokenError>(&token_key_id, KeyUse::Redemption)?;
+    let valid = bool::from(verify_p384_token_uniformly(
+        &server,
+        token,
+        TOKEN_TYPE_PRIVATE_P384,
+        challenge_digest,
+    ));
+    Ok((token_key_id, valid))
+}
+
+/// Checks a serialized type 0x0001 token was issued with `private_key`, and for the challenge
+/// with `challenge_digest` if given, which it must be while challenges are checked for
+/// freshness. Records the redemption, but doesn't check for replays.
+pub fn validate_p384_token(
+    private_key: &[u8],
+    token: &[u8],
+    challenge_digest: Option<&[u8]>,
+) -> Result<bool, PrivateTokenError> {
+    if challenge_digest.is_none() {
+        check_redemption_challenge::<PrivateTokenError>(None)?;
+    }
+    let (token_key_id, valid) = check_p384_token(private_key, token, challenge_digest)?;
+    let [.., truncated_token_key_id] = token_key_id;
+    record_redemption(
+        truncated_token_key_id,
+        RedemptionOutcome::from_validity(valid),
+    );
+    Ok(valid)
+}
+
+impl PrivacyPass {
+    /// Like `gen_keys`, for type 0x0001 (P-384) tokens
+    pub async fn gen_keys_p384(&self) -> Result<P384Keypair, PrivateTokenError> {
+        let info = self.info().to_vec();
+        run_blocking(move || gen_p384_keys(&tokio::runtime::Handle::current(), &info)).await?
+    }
+
+    /// Like `gen_token_response`, for type 0x0001 (P-384) tokens, which are issued one at a time
+    pub async fn gen_token_response_p384(
+        &self,
+        private_key: &[u8],
+        token_request: TokenRequest,
+    ) -> Result<TokenResponse, PrivateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || {
+            issue_p384_token_response(
+                &tokio::runtime::Handle::current(),
+                private_key.expose_secret(),
+                token_request,
+            )
+        })
+        .await?
+    }
+
+    /// Like `validate_token`, for type 0x0001 (P-384) tokens
+    pub async fn validate_token_p384(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+    ) -> Result<bool, PrivateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        let token = token.to_vec();
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || validate_p384_token(private_key.expose_secret(), &token, None)).await?
+    }
+}
+
+/// Body of `gen_keys_p384`, deriving the key under `info` and returning it in `encoding`
+pub(crate) fn gen_keys_for_crystal(
+    rt: &tokio::runtime::Runtime,
+    info: &[u8],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let keypair = gen_p384_keys(rt.handle(), info)?;
+    let keypair = KeyPair {
+        pk: encoding.encode(&keypair.public_key),
+        sk: encoding.encode(keypair.secret_key.expose_secret()),
+        token_type: TOKEN_TYPE_PRIVATE_P384,
+        error: "".to_string(),
+    };
+    let keypair_json = Zeroizing::new(serde_json::to_string(&keypair)?);
+
+    let rv = JSONRetValRef {
+        retval: keypair_json.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Generates a type 0x0001 (P-384) keypair, returned like `gen_keys`. NOTE: `gen_keys_encoded` of
+/// its token type returns the keys in other encodings
+#[no_mangle]
+pub extern "C" fn gen_keys_p384() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let out = gen_keys_for_crystal(
+            ffi_runtime()?,
+            crate::server::DEFAULT_KEY_INFO,
+            KeyEncoding::default(),
+        )?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Body of `gen_token_response_p384`. The single blinded element of the TokenRequest is
+/// dropped instead of answered when `max_nr` is 0.
+pub(crate) fn issue_for_crystal(
+    rt: &tokio::runtime::Runtime,
+    private_key: &[u8],
+    token_request_bytes: &[u8],
+    max_nr: u16,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let requested_nr = TokenRequestInfo::from_token_request(token_request_bytes)?.nr;
+    let answered = requested_nr.min(usize::from(max_nr));
+    if answered == 0 {
+        return token_response_for_crystal(&[], requested_nr, None);
+    }
+    let token_request = TokenRequest::tls_deserialize(&mut &token_request_bytes[..])?;
+    let token_response = issue_p384_token_response(rt.handle(), private_key, token_request)?;
+    token_response_for_crystal(
+        &token_response.tls_serialize_detached()?,
+        requested_nr - answered,
+        None,
+    )
+}
+
+/// Body of `validate_token_p384`, returning "1" for valid tokens
+pub(crate) fn validate_token_for_crystal(
+    private_key: &[u8],
+    token: &[u8],
+    token_challenge_s: &str,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let challenge_digest = TokenChallenge::from_base64(token_challenge_s)?.digest()?;
+    check_redemption_challenge::<PrivateTokenError>(Some(token_challenge_s))?;
+    let (token_key_id, valid) =
+        check_p384_token(private_key, token, Some(challenge_digest.as_slice()))?;
+    let [.., truncated_token_key_id] = token_key_id;
+
+    // refuse replays, only recording nonces of valid tokens
+    let mut outcome = RedemptionOutcome::from_validity(valid);
+    if valid && !token_nonce(token).is_some_and(|nonce| redeem_nonce(nonce, token_key_id)) {
+        outcome = RedemptionOutcome::DoubleSpent;
+    }
+    record_redemption(truncated_token_key_id, outcome);
+    let valid_s = match outcome == RedemptionOutcome::Valid {
+        true => "1",
+        false => "0",
+    };
+
+    let rv = JSONRetVal {
+        retval: valid_s.to_string(),
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Issues the TokenResponse for a (base64 encoded) type 0x0001 TokenRequest, with a secret
+/// key from `gen_keys_p384`
+#[no_mangle]
+pub extern "C" fn gen_token_response_p384(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let token_request_bytes = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let out = issue_for_crystal(
+            ffi_runtime()?,
+            private_key.expose_secret(),
+            &token_request_bytes,
+            1,
+        )?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_token`, for type 0x0001 tokens. Returns "1" for valid tokens and "0" otherwise.
+#[no_mangle]
+pub extern "C" fn validate_token_p384(
+    sk_cstr: *const i8,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        // malformed base64 is rejected like any other invalid token
+        let token = unsafe { decode_untrusted_bytes_from_crystal(token_cstr, InputKind::Token) }
+            .unwrap_or_default();
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let out =
+            validate_token_for_crystal(private_key.expose_secret(), &token, token_challenge_s)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use privacypass::TokenType;
+    use voprf::{EvaluationElement, Proof, VoprfClient};
+
+    /// JSON return value of an FFI function
+    fn rv(out: *const i8) -> serde_json::Value {
+        let rv = serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap());
+        free_string(out);
+        rv.unwrap()
+    }
+
+    #[test]
+    fn test_p384_issuance_and_redemption_over_ffi() {
+        // keys, token responses and redemptions all go through the FFI routing on token types
+        let keypair = rv(crate::server::gen_keys(TOKEN_TYPE_PRIVATE_P384));
+        let keypair: KeyPair = serde_json::from_str(keypair["retval"].as_str().unwrap()).unwrap();
+        assert_eq!(keypair.token_type, TOKEN_TYPE_PRIVATE_P384);
+        let public_key_bytes = URL_SAFE.decode(&keypair.pk).unwrap();
+        let public_key = NistP384::deserialize_elem(&public_key_bytes).unwrap();
+        let token_challenge = TokenChallenge::new(
+            TokenType::PrivateToken,
+            "issuer.example",
+            None,
+            &["origin.example".to_string()],
+        );
+
+        // nonces are recorded process-wide, so a fresh one is redeemed
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+        let mut token_input = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        token_input.extend(nonce);
+        token_input.extend(token_challenge.digest().unwrap());
+        token_input.extend(Sha256::digest(&public_key_bytes));
+        let blind = VoprfClient::<NistP384>::blind(&token_input, &mut OsRng).unwrap();
+        let mut token_request = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        token_request.push(*Sha256::digest(&public_key_bytes).last().unwrap());
+        token_request.extend(blind.message.serialize());
+
+        let sk_cstr = encode_string_for_crystal(keypair.sk.clone()).unwrap();
+        let token_request_cstr =
+            encode_string_for_crystal(URL_SAFE.encode(&token_request)).unwrap();
+        let rv_issued = rv(crate::server::gen_token_response(
+            sk_cstr,
+            token_request_cstr,
+            1,
+        ));
+        assert_eq!(rv_issued["error"], "");
+        assert_eq!(rv_issued["dropped"], 0);
+        let token_response = URL_SAFE
+            .decode(rv_issued["retval"].as_str().unwrap())
+            .unwrap();
+        let (evaluated_element, proof) = token_response.split_at(49);
+        let authenticator = blind
+            .state
+            .finalize(
+                &token_input,
+                &EvaluationElement::deserialize(evaluated_element).unwrap(),
+                &Proof::deserialize(proof).unwrap(),
+                public_key,
+            )
+            .unwrap();
+        let mut token = token_input.clone();
+        token.extend(authenticator);
+
+        let token_cstr = encode_string_for_crystal(URL_SAFE.encode(&token)).unwrap();
+        let token_challenge_cstr =
+            encode_string_for_crystal(token_challenge.to_base64().unwrap()).unwrap();
+        let validate = || {
+            rv(crate::server::validate_token(
+                sk_cstr,
+                token_cstr,
+                token_challenge_cstr,
+            ))["retval"]
+                .clone()
+        };
+        assert_eq!(validate(), "1");
+        // a replay is refused
+        assert_eq!(validate(), "0");
+        for cstr in [
+            sk_cstr,
+            token_request_cstr,
+            token_cstr,
+            token_challenge_cstr,
+        ] {
+            free_string(cstr);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_p384_issuance_and_redemption() {
+        let privacy_pass = PrivacyPass::new();
+        let keypair = privacy_pass.gen_keys_p384().await.unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let public_key = NistP384::deserialize_elem(&keypair.public_key).unwrap();
+
+        // token_input = token_type || nonce || challenge_digest || token_key_id
+        let mut token_input = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        token_input.extend([7u8; 32]);
+        token_input.extend([9u8; 32]);
+        token_input.extend(Sha256::digest(&keypair.public_key));
+
+        let blind = VoprfClient::<NistP384>::blind(&token_input, &mut OsRng).unwrap();
+        let mut token_request_bytes = TOKEN_TYPE_PRIVATE_P384.to_be_bytes().to_vec();
+        token_request_bytes.push(*Sha256::digest(&keypair.public_key).last().unwrap());
+        token_request_bytes.extend(blind.message.serialize());
+        let token_request =
+            TokenRequest::tls_deserialize(&mut token_request_bytes.as_slice()).unwrap();
+
+        let token_response = privacy_pass
+            .gen_token_response_p384(sk, token_request)
+            .await
+            .unwrap();
+        let token_response_bytes = token_response.tls_serialize_detached().unwrap();
+        let (evaluated_element, proof) = token_response_bytes.split_at(49);
+        let authenticator = blind
+            .state
+            .finalize(
+                &token_input,
+                &EvaluationElement::deserialize(evaluated_element).unwrap(),
+                &Proof::deserialize(proof).unwrap(),
+                public_key,
+            )
+            .unwrap();
+        let mut token = token_input.clone();
+        token.extend(authenticator);
+
+        assert!(privacy_pass.validate_token_p384(&token, sk).await.unwrap());
+        assert!(validate_p384_token(sk, &token, Some(&[9u8; 32])).unwrap());
+        assert!(!validate_p384_token(sk, &token, Some(&[0u8; 32])).unwrap());
+        let last = token.len() - 1;
+        token[last] ^= 1;
+        assert!(!privacy_pass.validate_token_p384(&token, sk).await.unwrap());
+        assert!(!privacy_pass
+            .validate_token_p384(&[0u8; 4], sk)
+            .await
+            .unwrap());
+    }
+}
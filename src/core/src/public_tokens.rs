@@ -0,0 +1,432 @@
+// -----------------------------------------------------------------------------
+// ---------------  publicly verifiable tokens (Blind RSA)  --------------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9578 token type 0x0002, issued with RSABSSA-SHA384-PSS-Deterministic over 2048-bit keys.
+// Unlike the batched ristretto255 tokens, these are verified with the issuer public key only,
+// so origins can check them without holding the issuer secret. Verification is shared with
+// the no_std kagippverify crate, so that embedded verifiers accept exactly the same tokens.
+//   TokenRequest  = token_type (2) || truncated_token_key_id (1) || blinded_msg[Nk]
+//   TokenResponse = blind_sig[Nk]
+// Keys are exchanged as DER: the secret key as PKCS#1, the public key as a RSASSA-PSS
+// SubjectPublicKeyInfo, whose SHA256 is the token key id.
+
+use crate::audit::{record_redemption, RedemptionOutcome};
+use crate::challenge_authentication::ChallengeAuthenticationError;
+use crate::challenge_freshness::ChallengeFreshnessError;
+use crate::crystal::{
+    borrow_untrusted_str_from_crystal, decode_secret_bytes_from_crystal,
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, encode_secret_json_for_crystal,
+    error_chain_json_retval, error_json_retval, secret_from_slice, JSONRetVal, JSONRetValRef,
+};
+use crate::inspect::TokenRequestInfo;
+use crate::key_encoding::KeyEncoding;
+use crate::key_validity::KeyValidityError;
+use crate::limits::InputKind;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::replay::redeem_nonce;
+use crate::revocation::KeyRevokedError;
+use crate::server::{
+    check_key, check_redemption_challenge, token_response_for_crystal, KeyPair, KeyUse,
+};
+use blind_rsa_signatures::{KeyPair as RsaKeyPair, Options, PublicKey, SecretKey};
+use kagippverify::public::{verify_public_token, VerifyError};
+use kagippverify::token::{token_nonce, Token, TOKEN_TYPE_PUBLIC_RSA};
+use privacypass::auth::authenticate::TokenChallenge;
+use rand::rngs::OsRng;
+use secrecy::{ExposeSecret, SecretSlice};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+pub const RSA_MODULUS_BITS: usize = 2048;
+/// Size of blinded messages, blind signatures and token authenticators
+pub const NK: usize = RSA_MODULUS_BITS / 8;
+pub(crate) const TOKEN_REQUEST_LEN: usize = 2 + 1 + NK;
+
+#[derive(Error, Debug)]
+pub enum PublicTokenError {
+    #[error("RSA operation failed")]
+    Rsa(#[from] blind_rsa_signatures::Error),
+    #[error("invalid token type")]
+    InvalidTokenType,
+    #[error("incorrect number of bytes ({0}) for a token request")]
+    WrongTokenRequestSize(usize),
+    #[error("key id not found")]
+    KeyIdNotFound,
+    #[error("key was revoked")]
+    KeyRevoked(#[from] KeyRevokedError),
+    #[error("key is outside of its validity window")]
+    KeyValidity(#[from] KeyValidityError),
+    #[error("invalid public key")]
+    InvalidPublicKey,
+    #[error("token challenge is not fresh")]
+    ChallengeFreshness(#[from] ChallengeFreshnessError),
+    #[error("token challenge is not authenticated")]
+    ChallengeAuthentication(#[from] ChallengeAuthenticationError),
+}
+
+/// Blind RSA issuer keypair, DER encoded
+pub struct RsaKeypair {
+    /// RSASSA-PSS SubjectPublicKeyInfo
+    pub public_key: Vec<u8>,
+    /// PKCS#1 RSAPrivateKey
+    pub secret_key: SecretSlice<u8>,
+}
+
+/// Parameters of RSABSSA-SHA384-PSS-Deterministic: SHA-384, 48 byte salt, no randomizer
+pub(crate) fn rsa_options() -> Options {
+    Options::default()
+}
+
+fn serialize_public_key(public_key: &PublicKey) -> Result<Vec<u8>, PublicTokenError> {
+    Ok(public_key.to_spki(Some(&rsa_options()))?)
+}
+
+/// Token key id of a serialized public key, as carried by tokens
+pub fn public_key_to_token_key_id(public_key: &[u8]) -> [u8; 32] {
+    Sha256::digest(public_key).into()
+}
+
+pub fn gen_rsa_keys() -> Result<RsaKeypair, PublicTokenError> {
+    let keypair = RsaKeyPair::generate(&mut OsRng, RSA_MODULUS_BITS)?;
+    Ok(RsaKeypair {
+        public_key: serialize_public_key(&keypair.pk)?,
+        secret_key: secret_from_slice(&Zeroizing::new(keypair.sk.to_der()?)),
+    })
+}
+
+/// Blind signs a serialized TokenRequest with a DER encoded secret key, returning the
+/// serialized TokenResponse
+pub fn issue_rsa_token_response(
+    secret_key: &[u8],
+    token_request: &[u8],
+) -> Result<Vec<u8>, PublicTokenError> {
+    let wrong_size = PublicTokenError::WrongTokenRequestSize(token_request.len());
+    if token_request.len() != TOKEN_REQUEST_LEN {
+        return Err(wrong_size);
+    }
+    // token_type (2) || truncated_token_key_id (1) || blinded_msg
+    let Some(([token_type @ .., truncated_key_id], blinded_msg)) =
+        token_request.split_first_chunk::<3>()
+    else {
+        return Err(wrong_size);
+    };
+    if *token_type != TOKEN_TYPE_PUBLIC_RSA.to_be_bytes() {
+        return Err(PublicTokenError::InvalidTokenType);
+    }
+    let secret_key = SecretKey::from_der(secret_key)?;
+    let public_key = serialize_public_key(&secret_key.public_key()?)?;
+    let token_key_id = public_key_to_token_key_id(&public_key);
+    let [.., truncated_token_key_id] = token_key_id;
+    if *truncated_key_id != truncated_token_key_id {
+        return Err(PublicTokenError::KeyIdNotFound);
+    }
+    check_key::<PublicTokenError>(&token_key_id, KeyUse::Issuance)?;
+
+    let blind_signature = secret_key.blind_sign(&mut OsRng, blinded_msg, &rsa_options())?;
+    Ok(blind_signature.0)
+}
+
+/// Checks a serialized token against the issuer public key and the digest of the challenge
+/// it was issued for. Only needs the public key, so that origins can verify tokens themselves.
+pub fn verify_rsa_token(
+    public_key: &[u8],
+    token: &[u8],
+    challenge_digest: &[u8; 32],
+) -> Result<bool, PublicTokenError> {
+    let Ok(token) = Token::parse(token) else {
+        return Ok(false);
+    };
+    if token.challenge_digest != challenge_digest {
+        return Ok(false);
+    }
+    match verify_public_token(&token, public_key) {
+        Ok(()) => Ok(true),
+        Err(VerifyError::InvalidPublicKey) => Err(PublicTokenError::InvalidPublicKey),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Body of `gen_keys_rsa`, returning the keys in `encoding`
+pub(crate) fn gen_keys_for_crystal(
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let keypair = gen_rsa_keys()?;
+    let keypair = KeyPair {
+        pk: encoding.encode(&keypair.public_key),
+        sk: encoding.encode(keypair.secret_key.expose_secret()),
+        token_type: TOKEN_TYPE_PUBLIC_RSA,
+        error: "".to_string(),
+    };
+    let keypair_json = Zeroizing::new(serde_json::to_string(&keypair)?);
+
+    let rv = JSONRetValRef {
+        retval: keypair_json.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Generates a Blind RSA (token type 0x0002) keypair, returned like `gen_keys`. NOTE: `gen_keys_encoded` of
+/// its token type returns the keys in other encodings
+#[no_mangle]
+pub extern "C" fn gen_keys_rsa() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let out = gen_keys_for_crystal(KeyEncoding::default())?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Body of `gen_token_response_rsa`. The single blinded message of the TokenRequest is
+/// dropped instead of answered when `max_nr` is 0.
+pub(crate) fn issue_for_crystal(
+    secret_key: &[u8],
+    token_request: &[u8],
+    max_nr: u16,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let requested_nr = TokenRequestInfo::from_token_request(token_request)?.nr;
+    let answered = requested_nr.min(usize::from(max_nr));
+    if answered == 0 {
+        return token_response_for_crystal(&[], requested_nr, None);
+    }
+    let token_response = issue_rsa_token_response(secret_key, token_request)?;
+    token_response_for_crystal(&token_response, requested_nr - answered, None)
+}
+
+/// Public key of a DER encoded secret key, serialized like those of `gen_rsa_keys`
+pub(crate) fn secret_key_to_public_key(secret_key: &[u8]) -> Result<Vec<u8>, PublicTokenError> {
+    serialize_public_key(&SecretKey::from_der(secret_key)?.public_key()?)
+}
+
+/// Body of `validate_token_rsa`, returning "1" for valid tokens
+pub(crate) fn validate_token_for_crystal(
+    public_key: &[u8],
+    token: &[u8],
+    token_challenge_s: &str,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let challenge_digest = TokenChallenge::from_base64(token_challenge_s)?.digest()?;
+    check_redemption_challenge::<PublicTokenError>(Some(token_challenge_s))?;
+
+    let token_key_id = public_key_to_token_key_id(public_key);
+    let [.., truncated_token_key_id] = token_key_id;
+    check_key::<PublicTokenError>(&token_key_id, KeyUse::Redemption)?;
+    let valid = verify_rsa_token(public_key, token, &challenge_digest)?;
+
+    // refuse replays, only recording nonces of valid tokens
+    let mut outcome = RedemptionOutcome::from_validity(valid);
+    if valid && !token_nonce(token).is_some_and(|nonce| redeem_nonce(nonce, token_key_id)) {
+        outcome = RedemptionOutcome::DoubleSpent;
+    }
+    record_redemption(truncated_token_key_id, outcome);
+    let valid_s = match outcome == RedemptionOutcome::Valid {
+        true => "1",
+        false => "0",
+    };
+
+    let rv = JSONRetVal {
+        retval: valid_s.to_string(),
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Blind signs a (base64 encoded) type 0x0002 TokenRequest with a secret key from `gen_keys_rsa`
+#[no_mangle]
+pub extern "C" fn gen_token_response_rsa(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let secret_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::RsaKey)? };
+        let token_request = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let out = issue_for_crystal(secret_key.expose_secret(), &token_request, 1)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Checks a type 0x0002 token with the issuer public key only, refusing replays like
+/// `validate_token`. Returns "1" for valid tokens and "0" otherwise.
+#[no_mangle]
+pub extern "C" fn validate_token_rsa(
+    pk_cstr: *const i8,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        let public_key =
+            unsafe { decode_untrusted_bytes_from_crystal(pk_cstr, InputKind::RsaKey)? };
+        let token = unsafe { decode_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let out = validate_token_for_crystal(&public_key, &token, token_challenge_s)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use blind_rsa_signatures::BlindSignature;
+    use privacypass::{TokenType, TruncatedTokenKeyId};
+
+    fn truncated_token_key_id(public_key: &[u8]) -> TruncatedTokenKeyId {
+        let [.., truncated_token_key_id] = public_key_to_token_key_id(public_key);
+        truncated_token_key_id
+    }
+
+    #[test]
+    fn test_rsa_issuance_and_public_verification() {
+        let keypair = gen_rsa_keys().unwrap();
+        let public_key = PublicKey::from_spki(&keypair.public_key, Some(&rsa_options())).unwrap();
+        let challenge_digest = [9u8; 32];
+
+        // token_input = token_type || nonce || challenge_digest || token_key_id
+        let mut token_input = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        token_input.extend([5u8; 32]);
+        token_input.extend(challenge_digest);
+        token_input.extend(public_key_to_token_key_id(&keypair.public_key));
+
+        let blinding = public_key
+            .blind(&mut OsRng, &token_input, false, &rsa_options())
+            .unwrap();
+        let mut token_request = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        token_request.push(truncated_token_key_id(&keypair.public_key));
+        token_request.extend(&blinding.blind_msg.0);
+        let token_response =
+            issue_rsa_token_response(keypair.secret_key.expose_secret(), &token_request).unwrap();
+
+        let signature = public_key
+            .finalize(
+                &BlindSignature::new(token_response),
+                &blinding.secret,
+                None,
+                &token_input,
+                &rsa_options(),
+            )
+            .unwrap();
+        let mut token = token_input.clone();
+        token.extend(&signature.0);
+
+        assert!(verify_rsa_token(&keypair.public_key, &token, &challenge_digest).unwrap());
+        assert!(!verify_rsa_token(&keypair.public_key, &token, &[0u8; 32]).unwrap());
+        let last = token.len() - 1;
+        token[last] ^= 1;
+        assert!(!verify_rsa_token(&keypair.public_key, &token, &challenge_digest).unwrap());
+
+        token_request[2] ^= 1;
+        assert!(matches!(
+            issue_rsa_token_response(keypair.secret_key.expose_secret(), &token_request),
+            Err(PublicTokenError::KeyIdNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_rsa_issuance_and_redemption_over_ffi() {
+        // base64 RSA-2048 secret keys are longer than the keys of the other token types
+        let keypair = gen_rsa_keys().unwrap();
+        let sk_b64 = URL_SAFE.encode(keypair.secret_key.expose_secret());
+        assert!(sk_b64.len() > crate::limits::InputLimits::default().max_key_len);
+        let public_key = PublicKey::from_spki(&keypair.public_key, Some(&rsa_options())).unwrap();
+        let token_challenge = TokenChallenge::new(
+            TokenType::PublicToken,
+            "issuer.example",
+            None,
+            &["origin.example".to_string()],
+        );
+
+        let mut token_input = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        token_input.extend([6u8; 32]);
+        token_input.extend(token_challenge.digest().unwrap());
+        token_input.extend(public_key_to_token_key_id(&keypair.public_key));
+        let blinding = public_key
+            .blind(&mut OsRng, &token_input, false, &rsa_options())
+            .unwrap();
+        let mut token_request = TOKEN_TYPE_PUBLIC_RSA.to_be_bytes().to_vec();
+        token_request.push(truncated_token_key_id(&keypair.public_key));
+        token_request.extend(&blinding.blind_msg.0);
+
+        let sk_cstr = encode_string_for_crystal(sk_b64).unwrap();
+        let token_request_cstr =
+            encode_string_for_crystal(URL_SAFE.encode(&token_request)).unwrap();
+        let issue = |max_nr| {
+            let out = crate::server::gen_token_response(sk_cstr, token_request_cstr, max_nr);
+            let rv: serde_json::Value =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            rv
+        };
+        // the blinded message is dropped rather than answered past the limit
+        let rv = issue(0);
+        assert_eq!(rv["retval"], "");
+        assert_eq!(rv["dropped"], 1);
+        let rv = issue(1);
+        assert_eq!(rv["dropped"], 0);
+        let token_response = URL_SAFE.decode(rv["retval"].as_str().unwrap()).unwrap();
+        let signature = public_key
+            .finalize(
+                &BlindSignature::new(token_response),
+                &blinding.secret,
+                None,
+                &token_input,
+                &rsa_options(),
+            )
+            .unwrap();
+        let mut token = token_input.clone();
+        token.extend(&signature.0);
+
+        // redeemed once through the generic validate_token, which routes on the token type
+        let token_cstr = encode_string_for_crystal(URL_SAFE.encode(&token)).unwrap();
+        let token_challenge_cstr =
+            encode_string_for_crystal(token_challenge.to_base64().unwrap()).unwrap();
+        let validate = || {
+            let out = crate::server::validate_token(sk_cstr, token_cstr, token_challenge_cstr);
+            let rv: JSONRetVal =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            rv.retval
+        };
+        assert_eq!(validate(), "1");
+        assert_eq!(validate(), "0");
+        for cstr in [
+            sk_cstr,
+            token_request_cstr,
+            token_cstr,
+            token_challenge_cstr,
+        ] {
+            free_string(cstr);
+        }
+    }
+}
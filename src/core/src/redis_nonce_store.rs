@@ -0,0 +1,275 @@
+// -----------------------------------------------------------------------------
+// --------------------------  Redis nonce store  ------------------------------
+// -----------------------------------------------------------------------------
+//
+// Double-spend protection used to be either per process (the process-wide nonce store of
+// `validate_token`) or left to Crystal. `RedisNonceStore` records redeemed nonces in Redis,
+// so that every issuer process pointing at the same Redis refuses a token redeemed by any
+// of them. Nonces are recorded with `SET NX`, so concurrent redemptions of a token can't all
+// succeed, and optionally expire once their tokens can no longer be redeemed anyway.
+// Redis errors fail closed: a nonce that can't be checked or recorded is reported as spent.
+// The store is a `KvNonceStore` over Redis, which does the key building and failure handling.
+// Nonces are recorded along with the time they were, so that `Retain` can also prune nonces
+// recorded without a ttl, walking the keys under the prefix with `SCAN`.
+
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    decode_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use crate::nonce_store::{KeyValueBackend, KvNonceStore};
+use crate::replay::set_shared_nonce_store;
+use crate::retention::{Retain, RetentionPolicy};
+use crate::runtime::ffi_runtime;
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Prefix of the Redis keys nonces are recorded under, unless configured otherwise
+pub const DEFAULT_KEY_PREFIX: &str = "kagipp:nonce:";
+
+/// Keys asked for per `SCAN` when pruning
+const SCAN_COUNT: usize = 1_000;
+
+#[derive(Debug, Clone)]
+pub struct RedisNonceStoreOptions {
+    /// prepended to nonces to build their Redis keys, so that several deployments
+    /// (or key rotations) can share a Redis without seeing each other's nonces
+    pub key_prefix: String,
+    /// how long nonces are remembered, None to remember them forever
+    /// NOTE: an expired nonce can be redeemed again, so this must exceed how long
+    ///       its token is accepted for
+    pub ttl: Option<Duration>,
+}
+
+impl Default for RedisNonceStoreOptions {
+    fn default() -> Self {
+        RedisNonceStoreOptions {
+            key_prefix: DEFAULT_KEY_PREFIX.to_string(),
+            ttl: None,
+        }
+    }
+}
+
+/// Redis connection nonces are recorded with, see `RedisNonceStore`
+pub struct RedisBackend {
+    // multiplexed over a single connection, reconnecting on failure
+    connection: ConnectionManager,
+}
+
+#[async_trait]
+impl KeyValueBackend for RedisBackend {
+    type Error = redis::RedisError;
+
+    async fn put_if_absent(&self, key: &[u8], ttl: Option<Duration>) -> Result<bool, Self::Error> {
+        let mut command = redis::cmd("SET");
+        command
+            .arg(key)
+            .arg(global_clock().unix_seconds())
+            .arg("NX");
+        if let Some(ttl) = ttl {
+            command.arg("PX").arg(ttl.as_millis().max(1) as u64);
+        }
+        // SET NX replies nil if the key was already set
+        let reply: Option<String> = command.query_async(&mut self.connection.clone()).await?;
+        Ok(reply.is_some())
+    }
+
+    async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        redis::cmd("EXISTS")
+            .arg(key)
+            .query_async(&mut self.connection.clone())
+            .await
+    }
+}
+
+impl RedisBackend {
+    /// Forgets the nonces under `key_prefix` recorded more than `max_age_seconds` before `now`,
+    /// returning how many. Walks every key under the prefix, so meant for periodic pruning.
+    /// NOTE: a forgotten nonce can be redeemed again, so `max_age_seconds` must exceed how
+    ///       long its token is accepted for.
+    pub async fn prune_older_than(
+        &self,
+        key_prefix: &[u8],
+        max_age_seconds: u64,
+        now: u64,
+    ) -> Result<u64, redis::RedisError> {
+        let horizon = now.saturating_sub(max_age_seconds);
+        let pattern = [glob_escape(key_prefix).as_slice(), b"*"].concat();
+        let mut connection = self.connection.clone();
+        let mut cursor = 0u64;
+        let mut pruned = 0;
+        loop {
+            let (next, keys): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut connection)
+                .await?;
+            if !keys.is_empty() {
+                let recorded_at: Vec<Option<String>> = redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async(&mut connection)
+                    .await?;
+                let expired: Vec<&Vec<u8>> = keys
+                    .iter()
+                    .zip(recorded_at)
+                    .filter(|(_, recorded_at)| {
+                        recorded_at
+                            .as_deref()
+                            .and_then(|recorded_at| recorded_at.parse::<u64>().ok())
+                            .is_some_and(|recorded_at| recorded_at < horizon)
+                    })
+                    .map(|(key, _)| key)
+                    .collect();
+                if !expired.is_empty() {
+                    let deleted: u64 = redis::cmd("DEL")
+                        .arg(&expired)
+                        .query_async(&mut connection)
+                        .await?;
+                    pruned += deleted;
+                }
+            }
+            if next == 0 {
+                return Ok(pruned);
+            }
+            cursor = next;
+        }
+    }
+}
+
+/// `prefix` with the glob characters of `SCAN MATCH` escaped
+fn glob_escape(prefix: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(prefix.len());
+    for &byte in prefix {
+        if matches!(byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    escaped
+}
+
+/// Nonce store recording nonces in Redis, the raw nonce bytes following the key prefix.
+/// `failures` counts the Redis commands that failed, each of them reported a nonce as spent.
+pub type RedisNonceStore = KvNonceStore<RedisBackend>;
+
+impl RedisNonceStore {
+    /// Connects to the Redis at `url`, e.g. "redis://127.0.0.1:6379/0"
+    pub async fn connect(
+        url: &str,
+        options: RedisNonceStoreOptions,
+    ) -> Result<Self, redis::RedisError> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(Self::with_connection(connection, options))
+    }
+
+    pub fn with_connection(connection: ConnectionManager, options: RedisNonceStoreOptions) -> Self {
+        KvNonceStore::new(
+            RedisBackend { connection },
+            options.key_prefix.as_bytes(),
+            options.ttl,
+        )
+    }
+
+    /// See `RedisBackend::prune_older_than`
+    pub async fn prune_older_than(
+        &self,
+        max_age_seconds: u64,
+        now: u64,
+    ) -> Result<u64, redis::RedisError> {
+        self.backend()
+            .prune_older_than(self.key_prefix(), max_age_seconds, now)
+            .await
+    }
+}
+
+// NOTE: a pruning pass that fails drops nothing, the next pass tries again
+#[async_trait]
+impl Retain for RedisNonceStore {
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize {
+        match policy.max_nonce_age {
+            Some(max_age) => self
+                .prune_older_than(max_age.as_secs(), now)
+                .await
+                .map_or(0, |pruned| pruned as usize),
+            None => 0,
+        }
+    }
+}
+
+/// Makes `validate_token` (and the other redemption FFI calls) record redeemed nonces in the
+/// Redis at `url` (e.g. "redis://127.0.0.1:6379/0"), under `key_prefix`, for `ttl_seconds`.
+/// NOTE: pass an empty `key_prefix` to keep the default, and 0 as `ttl_seconds` to never
+///       forget nonces. `set_replay_protection` goes back to a process-wide nonce store.
+#[no_mangle]
+pub extern "C" fn set_redis_nonce_store(
+    url_cstr: *const i8,
+    key_prefix_cstr: *const i8,
+    ttl_seconds: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let url = unsafe { decode_string_from_crystal(url_cstr)? };
+        let key_prefix = unsafe { decode_string_from_crystal(key_prefix_cstr)? };
+        let options = RedisNonceStoreOptions {
+            key_prefix: match key_prefix.is_empty() {
+                true => DEFAULT_KEY_PREFIX.to_string(),
+                false => key_prefix,
+            },
+            ttl: (ttl_seconds > 0).then(|| Duration::from_secs(ttl_seconds.into())),
+        };
+        let rt = ffi_runtime()?;
+        let nonce_store = rt.block_on(RedisNonceStore::connect(&url, options))?;
+        set_shared_nonce_store(Arc::new(nonce_store));
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nonce_store::{nonce_key, Nonce};
+
+    #[test]
+    fn test_nonce_keys() {
+        let nonce: Nonce = [7u8; 32];
+        let key = nonce_key(DEFAULT_KEY_PREFIX.as_bytes(), &nonce);
+        assert_eq!(
+            &key[..DEFAULT_KEY_PREFIX.len()],
+            DEFAULT_KEY_PREFIX.as_bytes()
+        );
+        assert_eq!(&key[DEFAULT_KEY_PREFIX.len()..], &nonce[..]);
+        assert_ne!(key, nonce_key(b"other:", &nonce));
+        assert_ne!(key, nonce_key(DEFAULT_KEY_PREFIX.as_bytes(), &[8u8; 32]));
+    }
+
+    #[test]
+    fn test_glob_escape() {
+        assert_eq!(
+            glob_escape(DEFAULT_KEY_PREFIX.as_bytes()),
+            DEFAULT_KEY_PREFIX.as_bytes()
+        );
+        assert_eq!(glob_escape(br"a*b?[c]\\"), br"a\*b\?\[c\]\\\\".to_vec());
+    }
+}
@@ -0,0 +1,457 @@
+// -----------------------------------------------------------------------------
+// ------------------------  replay protection  --------------------------------
+// -----------------------------------------------------------------------------
+//
+// `validate_token` used to redeem tokens against a fresh, empty nonce store, so it accepted
+// the same token any number of times unless Crystal kept its own nonce store. It now records
+// the nonce of every valid token in a process-wide nonce store by default, and refuses tokens
+// whose nonce was seen before. Integrators keeping their own nonce store can opt out
+// explicitly through `set_replay_protection`. Deployments running several issuer processes
+// can instead share a nonce store between them, see `set_shared_nonce_store`, or persist
+// and exchange snapshots of the process-wide one, see `export_nonce_store`.
+
+use crate::batched_memory_stores::{
+    AtomicNonceStore, EvictionPolicy, ShardedMemoryNonceStore, StoreLimits, DEFAULT_NONCE_SHARDS,
+};
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    decode_bytes_from_crystal, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    error_chain_json_retval, error_json_retval, Base64Json, JSONRetVal, JSONRetValRef,
+};
+use crate::limits::InputKind;
+use crate::nonce_store::NonceStore;
+use crate::retention::process_max_nonce_age;
+use crate::runtime::ffi_runtime;
+use crate::shutdown::{register_flush, Flush};
+use crate::NONCE_BYTES;
+use async_trait::async_trait;
+use privacypass::Nonce;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Default number of nonces remembered, past which the oldest ones are forgotten
+/// NOTE: a forgotten nonce can be redeemed again, so this errs on the generous side
+pub const DEFAULT_MAX_NONCES: usize = 1 << 20;
+
+struct ReplayProtection {
+    enabled: bool,
+    nonce_store: Option<Arc<ShardedMemoryNonceStore>>, // created lazily
+    // consulted instead of nonce_store when set
+    shared_nonce_store: Option<Arc<dyn AtomicNonceStore + Send + Sync>>,
+}
+
+static REPLAY_PROTECTION: RwLock<ReplayProtection> = RwLock::new(ReplayProtection {
+    enabled: true,
+    nonce_store: None,
+    shared_nonce_store: None,
+});
+
+fn default_nonce_store(max_nonces: usize) -> Arc<ShardedMemoryNonceStore> {
+    Arc::new(ShardedMemoryNonceStore::with_limits(
+        DEFAULT_NONCE_SHARDS,
+        StoreLimits {
+            max_entries: Some(max_nonces),
+            eviction_policy: EvictionPolicy::EvictOldest,
+        },
+    ))
+}
+
+/// Turns replay protection on or off, resetting the nonces seen so far.
+/// NOTE: pass 0 as `max_nonces` to keep the default. A shared nonce store is dropped, in favour
+///       of a process-wide one.
+pub fn configure_replay_protection(enabled: bool, max_nonces: usize) {
+    let max_nonces = match max_nonces {
+        0 => DEFAULT_MAX_NONCES,
+        _ => max_nonces,
+    };
+    // a poisoned lock still holds a valid configuration, as it is only ever overwritten whole
+    *REPLAY_PROTECTION
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = ReplayProtection {
+        enabled,
+        nonce_store: enabled.then(|| default_nonce_store(max_nonces)),
+        shared_nonce_store: None,
+    };
+}
+
+/// Turns replay protection on, recording redeemed nonces in `nonce_store` (e.g. a
+/// `RedisNonceStore`) instead of the process-wide nonce store, so that a token redeemed
+/// by one issuer process is refused by the others. The store is flushed on shutdown.
+pub fn set_shared_nonce_store(nonce_store: Arc<dyn AtomicNonceStore + Send + Sync>) {
+    register_flush(Arc::new(SharedNonceStoreFlush(nonce_store.clone())));
+    // a poisoned lock still holds a valid configuration, as it is only ever overwritten whole
+    *REPLAY_PROTECTION
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = ReplayProtection {
+        enabled: true,
+        nonce_store: None,
+        shared_nonce_store: Some(nonce_store),
+    };
+}
+
+/// Flushes a shared nonce store on shutdown
+struct SharedNonceStoreFlush(Arc<dyn AtomicNonceStore + Send + Sync>);
+
+#[async_trait]
+impl Flush for SharedNonceStoreFlush {
+    async fn flush(&self) {
+        self.0.flush().await
+    }
+}
+
+fn shared_nonce_store() -> Option<Arc<dyn AtomicNonceStore + Send + Sync>> {
+    let replay_protection = REPLAY_PROTECTION
+        .read()
+        .unwrap_or_else(|err| err.into_inner());
+    match replay_protection.enabled {
+        true => replay_protection.shared_nonce_store.clone(),
+        false => None,
+    }
+}
+
+/// The nonce store consulted by `validate_token`, or None if replay protection was turned off
+/// or a shared nonce store is consulted instead
+pub fn replay_nonce_store() -> Option<Arc<ShardedMemoryNonceStore>> {
+    {
+        let replay_protection = REPLAY_PROTECTION
+            .read()
+            .unwrap_or_else(|err| err.into_inner());
+        if !replay_protection.enabled || replay_protection.shared_nonce_store.is_some() {
+            return None;
+        }
+        if let Some(nonce_store) = &replay_protection.nonce_store {
+            return Some(nonce_store.clone());
+        }
+    }
+    let mut replay_protection = REPLAY_PROTECTION
+        .write()
+        .unwrap_or_else(|err| err.into_inner());
+    if !replay_protection.enabled || replay_protection.shared_nonce_store.is_some() {
+        return None;
+    }
+    Some(
+        replay_protection
+            .nonce_store
+            .get_or_insert_with(|| default_nonce_store(DEFAULT_MAX_NONCES))
+            .clone(),
+    )
+}
+
+/// Records `nonce`, of a token issued with the key of `token_key_id`, as redeemed, returning
+/// false if it was redeemed before, or if the key retired, see `set_nonce_key_lifetime`
+/// NOTE: blocks on the shared nonce store if one is set, so it must not be called from within
+///       a runtime, which FFI callers never are
+pub fn redeem_nonce(nonce: Nonce, token_key_id: [u8; 32]) -> bool {
+    if let Some(nonce_store) = shared_nonce_store() {
+        return match ffi_runtime() {
+            Ok(rt) => rt.block_on(nonce_store.insert_if_absent(nonce)),
+            // a nonce that can't be recorded is refused, rather than risking a double spend
+            Err(_) => false,
+        };
+    }
+    redeem_nonce_in_process(nonce, token_key_id)
+}
+
+fn redeem_nonce_in_process(nonce: Nonce, token_key_id: [u8; 32]) -> bool {
+    match replay_nonce_store() {
+        Some(nonce_store) => nonce_store.insert_if_absent_for_key(nonce, token_key_id),
+        None => true,
+    }
+}
+
+/// Shared nonce stores are async, and the sync API can't block on them: its callers may well
+/// be running within a runtime
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("a shared nonce store is set, redeem tokens through the async API")]
+pub struct SharedNonceStoreError;
+
+/// Refuses synchronous redemptions while a shared nonce store is set, see `redeem_nonce_sync`
+pub fn check_sync_redemption() -> Result<(), SharedNonceStoreError> {
+    match shared_nonce_store() {
+        Some(_) => Err(SharedNonceStoreError),
+        None => Ok(()),
+    }
+}
+
+/// Like `redeem_nonce`, but never blocks: refused while a shared nonce store is set, so that
+/// it can be called from anywhere, runtimes included
+pub fn redeem_nonce_sync(
+    nonce: Nonce,
+    token_key_id: [u8; 32],
+) -> Result<bool, SharedNonceStoreError> {
+    check_sync_redemption()?;
+    Ok(redeem_nonce_in_process(nonce, token_key_id))
+}
+
+/// Turns the replay protection of `validate_token` on (the default) or off.
+/// NOTE: only turn it off if redeemed nonces are tracked elsewhere, e.g. at Crystal level.
+///       Pass 0 as `max_nonces` to keep the default number of remembered nonces.
+#[no_mangle]
+pub extern "C" fn set_replay_protection(enabled: bool, max_nonces: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        configure_replay_protection(enabled, usize::try_from(max_nonces)?);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NonceStoreError {
+    #[error("nonce store snapshot is truncated")]
+    Truncated,
+    #[error("unsupported nonce store snapshot version {0}")]
+    UnsupportedVersion(u8),
+    #[error("replay protection is off, or nonces are recorded in a shared nonce store")]
+    NoProcessWideStore,
+    #[error("key already retired, the nonces of its tokens were forgotten")]
+    KeyRetired,
+    #[error("token key id is not 32 bytes long")]
+    InvalidTokenKeyId,
+}
+
+const SNAPSHOT_VERSION: u8 = 1;
+// nonce || recorded_at (u64, big endian)
+const SNAPSHOT_ENTRY_LEN: usize = NONCE_BYTES + 8;
+
+/// Serializes recorded nonces as version (1 byte) || entries, each entry being the nonce
+/// followed by the unix time it was recorded at (8 bytes, big endian), oldest first
+pub fn encode_nonce_snapshot(nonces: &[(Nonce, u64)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + nonces.len() * SNAPSHOT_ENTRY_LEN);
+    bytes.push(SNAPSHOT_VERSION);
+    for (nonce, recorded_at) in nonces {
+        bytes.extend_from_slice(nonce);
+        bytes.extend_from_slice(&recorded_at.to_be_bytes());
+    }
+    bytes
+}
+
+pub fn decode_nonce_snapshot(bytes: &[u8]) -> Result<Vec<(Nonce, u64)>, NonceStoreError> {
+    let (version, entries) = bytes.split_first().ok_or(NonceStoreError::Truncated)?;
+    if *version != SNAPSHOT_VERSION {
+        return Err(NonceStoreError::UnsupportedVersion(*version));
+    }
+    if entries.len() % SNAPSHOT_ENTRY_LEN != 0 {
+        return Err(NonceStoreError::Truncated);
+    }
+    let mut nonces = Vec::with_capacity(entries.len() / SNAPSHOT_ENTRY_LEN);
+    for entry in entries.chunks_exact(SNAPSHOT_ENTRY_LEN) {
+        let (nonce, recorded_at) = entry.split_at(NONCE_BYTES);
+        let nonce = Nonce::try_from(nonce).map_err(|_| NonceStoreError::Truncated)?;
+        let recorded_at = recorded_at
+            .try_into()
+            .map_err(|_| NonceStoreError::Truncated)?;
+        nonces.push((nonce, u64::from_be_bytes(recorded_at)));
+    }
+    Ok(nonces)
+}
+
+/// Snapshot of the nonces recorded by the process-wide nonce store
+pub fn export_nonces() -> Result<Vec<u8>, NonceStoreError> {
+    let nonce_store = replay_nonce_store().ok_or(NonceStoreError::NoProcessWideStore)?;
+    Ok(encode_nonce_snapshot(&nonce_store.snapshot()))
+}
+
+/// Merges a snapshot into the process-wide nonce store, returning the number of nonces it didn't
+/// hold yet. Nonces keep the time they were first recorded at, so they expire as they would have:
+/// those older than `max_nonce_age` at `now` (unix time, in seconds) are dropped, and the others
+/// are restored oldest first.
+pub fn import_nonces(
+    snapshot: &[u8],
+    max_nonce_age: Option<Duration>,
+    now: u64,
+) -> Result<usize, NonceStoreError> {
+    let nonces = unexpired_nonces(decode_nonce_snapshot(snapshot)?, max_nonce_age, now);
+    let nonce_store = replay_nonce_store().ok_or(NonceStoreError::NoProcessWideStore)?;
+    Ok(nonces
+        .into_iter()
+        .filter(|(nonce, recorded_at)| nonce_store.restore(*nonce, *recorded_at))
+        .count())
+}
+
+/// The nonces not older than `max_nonce_age` at `now`, oldest first
+fn unexpired_nonces(
+    mut nonces: Vec<(Nonce, u64)>,
+    max_nonce_age: Option<Duration>,
+    now: u64,
+) -> Vec<(Nonce, u64)> {
+    if let Some(max_age) = max_nonce_age {
+        nonces.retain(|(_, recorded_at)| now.saturating_sub(*recorded_at) <= max_age.as_secs());
+    }
+    nonces.sort_by_key(|(_, recorded_at)| *recorded_at);
+    nonces
+}
+
+/// Forgets the nonces of tokens issued with the key of `token_key_id` from the process-wide
+/// nonce store once `retires_at` (unix time, in seconds) is reached. From then on the key is
+/// refused for redemption, see `key_retired_at`, and can't be given a new lifetime.
+/// NOTE: keys are told apart by their full token key id, so that retiring a key doesn't
+///       forget the nonces of live keys sharing its truncated key id.
+pub fn set_nonce_key_lifetime(
+    token_key_id: [u8; 32],
+    retires_at: u64,
+) -> Result<(), NonceStoreError> {
+    let nonce_store = replay_nonce_store().ok_or(NonceStoreError::NoProcessWideStore)?;
+    if !nonce_store.set_key_lifetime(token_key_id, retires_at) {
+        return Err(NonceStoreError::KeyRetired);
+    }
+    nonce_store.prune_retired_keys(global_clock().unix_seconds());
+    Ok(())
+}
+
+/// Retires the key of `token_key_id` right away, see `set_nonce_key_lifetime`
+pub fn retire_nonce_key(token_key_id: [u8; 32]) -> Result<(), NonceStoreError> {
+    set_nonce_key_lifetime(token_key_id, global_clock().unix_seconds())
+}
+
+/// Unix time (in seconds) the key of `token_key_id` retired at in the process-wide nonce
+/// store, None if it didn't retire (or there is no process-wide nonce store)
+pub fn key_retired_at(token_key_id: &[u8; 32]) -> Option<u64> {
+    let nonce_store = replay_nonce_store()?;
+    nonce_store.prune_retired_keys(global_clock().unix_seconds());
+    nonce_store.key_retired_at(token_key_id)
+}
+
+/// Bounds how long the nonces of tokens issued with the key of the (base64 encoded) token key
+/// id, as returned by `public_key_to_key_ids`, are remembered to the lifetime of that key,
+/// which retires `ttl_seconds` from now (0 to retire it right away).
+/// NOTE: once retired, the key is refused for redemption and can't be given a new ttl
+#[no_mangle]
+pub extern "C" fn set_key_nonce_ttl(token_key_id_cstr: *const i8, ttl_seconds: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_key_id =
+            unsafe { decode_untrusted_bytes_from_crystal(token_key_id_cstr, InputKind::Key)? };
+        let token_key_id = <[u8; 32]>::try_from(token_key_id.as_slice())
+            .map_err(|_| NonceStoreError::InvalidTokenKeyId)?;
+        let retires_at = global_clock()
+            .unix_seconds()
+            .saturating_add(ttl_seconds.into());
+        set_nonce_key_lifetime(token_key_id, retires_at)?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns a (base64 encoded) snapshot of the nonces redeemed so far, see
+/// `encode_nonce_snapshot` for its layout, to persist across restarts or share between
+/// workers through `import_nonce_store`
+#[no_mangle]
+pub extern "C" fn export_nonce_store() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let snapshot = export_nonces()?;
+
+        let rv = JSONRetValRef {
+            retval: Base64Json(&snapshot),
+            error: "",
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Adds the nonces of a (base64 encoded) snapshot from `export_nonce_store` to the nonces
+/// redeemed so far, returning how many were new. Nonces older than the max nonce age of
+/// `set_retention_policy` or `start_nonce_gc` are dropped.
+/// NOTE: merging, rather than replacing, keeps the nonces redeemed since the snapshot was taken
+#[no_mangle]
+pub extern "C" fn import_nonce_store(snapshot_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let snapshot = unsafe { decode_bytes_from_crystal(snapshot_cstr)? };
+        let imported = import_nonces(
+            &snapshot,
+            process_max_nonce_age(),
+            global_clock().unix_seconds(),
+        )?;
+
+        let rv = JSONRetVal {
+            retval: imported.to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batched_memory_stores::ShardedMemoryNonceStore;
+
+    #[test]
+    fn test_nonce_snapshot_roundtrip() {
+        let nonce_store = ShardedMemoryNonceStore::default();
+        assert!(nonce_store.restore([2u8; 32], 200));
+        assert!(nonce_store.restore([1u8; 32], 100));
+        let snapshot = encode_nonce_snapshot(&nonce_store.snapshot());
+        assert_eq!(snapshot.len(), 1 + 2 * SNAPSHOT_ENTRY_LEN);
+
+        let nonces = decode_nonce_snapshot(&snapshot).unwrap();
+        assert_eq!(nonces, vec![([1u8; 32], 100), ([2u8; 32], 200)]);
+        let restored = ShardedMemoryNonceStore::default();
+        assert!(restored.restore([1u8; 32], 100));
+        let new = nonces
+            .into_iter()
+            .filter(|(nonce, recorded_at)| restored.restore(*nonce, *recorded_at))
+            .count();
+        assert_eq!(new, 1);
+        assert!(!restored.insert_if_absent([2u8; 32]));
+
+        // expired nonces are dropped, the others restored oldest first
+        let nonces = vec![([3u8; 32], 300), ([1u8; 32], 100), ([2u8; 32], 200)];
+        assert_eq!(
+            unexpired_nonces(nonces, Some(Duration::from_secs(150)), 350),
+            vec![([2u8; 32], 200), ([3u8; 32], 300)]
+        );
+
+        assert_eq!(
+            decode_nonce_snapshot(&snapshot[..snapshot.len() - 1]),
+            Err(NonceStoreError::Truncated)
+        );
+        assert_eq!(
+            decode_nonce_snapshot(&[2]),
+            Err(NonceStoreError::UnsupportedVersion(2))
+        );
+    }
+}
@@ -0,0 +1,441 @@
+// -----------------------------------------------------------------------------
+// -------------------------  data retention  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// Declarative limits on how long stores keep what they record: how old a recorded nonce can
+// get, how many past keys a key store keeps, and how far back the audit log goes. Stores
+// implement `Retain` (the memory, Bloom filter, Redis and SQL nonce stores, and the memory, SQL
+// and file key stores), and are pruned periodically by `spawn_retention_task`, or, for the
+// process-wide replay nonce store and audit log used by the FFI, by the task started with
+// `set_retention_policy`. Long-running issuers can instead start a nonce garbage collection
+// task with `start_nonce_gc`, see `spawn_nonce_gc_task`. Both are process-wide, like the nonce
+// store they prune, which every server handle shares.
+// Pruning tasks stop on shutdown, see `shutdown_token`.
+
+use crate::audit::prune_audit_log;
+use crate::batched_memory_stores::{
+    MemoryKeyStoreP384, MemoryKeyStoreRistretto255, MemoryNonceStore, ShardedMemoryNonceStore,
+};
+use crate::bloom_nonce_store::BloomNonceStore;
+use crate::clock::{global_clock, Clock};
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use crate::metrics::record_nonce_gc;
+use crate::replay::replay_nonce_store;
+use crate::runtime::ffi_runtime;
+use crate::shutdown::shutdown_token;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Limits enforced by pruning, None meaning unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetentionPolicy {
+    /// nonces recorded longer ago are forgotten.
+    /// NOTE: a forgotten nonce can be redeemed again, so this must exceed how long tokens
+    ///       are accepted for
+    pub max_nonce_age: Option<Duration>,
+    /// number of most recently installed keys kept by key stores
+    pub key_history_depth: Option<usize>,
+    /// audit log buckets older than this are dropped
+    pub audit_horizon: Option<Duration>,
+}
+
+/// Default interval between two pruning passes
+pub const DEFAULT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[async_trait]
+pub trait Retain: Send + Sync {
+    /// Drops what `policy` no longer allows keeping at `now` (in unix seconds), returning the
+    /// number of dropped entries
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize;
+}
+
+#[async_trait]
+impl Retain for MemoryNonceStore {
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize {
+        let expired = match policy.max_nonce_age {
+            Some(max_age) => self.prune_older_than(max_age.as_secs(), now),
+            None => 0,
+        };
+        expired + self.prune_retired_keys(now)
+    }
+}
+
+#[async_trait]
+impl Retain for ShardedMemoryNonceStore {
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize {
+        let expired = match policy.max_nonce_age {
+            Some(max_age) => self.prune_older_than(max_age.as_secs(), now),
+            None => 0,
+        };
+        expired + self.prune_retired_keys(now)
+    }
+}
+
+#[async_trait]
+impl Retain for BloomNonceStore {
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize {
+        match policy.max_nonce_age {
+            Some(max_age) => self.prune_older_than(max_age.as_secs(), now),
+            None => 0,
+        }
+    }
+}
+
+#[async_trait]
+impl Retain for MemoryKeyStoreRistretto255 {
+    async fn apply_retention(&self, policy: &RetentionPolicy, _now: u64) -> usize {
+        match policy.key_history_depth {
+            Some(depth) => self.retain_newest_keys(depth),
+            None => 0,
+        }
+    }
+}
+
+#[async_trait]
+impl Retain for MemoryKeyStoreP384 {
+    async fn apply_retention(&self, policy: &RetentionPolicy, _now: u64) -> usize {
+        match policy.key_history_depth {
+            Some(depth) => self.retain_newest_keys(depth),
+            None => 0,
+        }
+    }
+}
+
+/// The process-wide replay nonce store and the audit log
+pub struct ProcessWideStores;
+
+#[async_trait]
+impl Retain for ProcessWideStores {
+    async fn apply_retention(&self, policy: &RetentionPolicy, now: u64) -> usize {
+        let mut dropped = 0;
+        if let Some(nonce_store) = replay_nonce_store() {
+            dropped += nonce_store.apply_retention(policy, now).await;
+        }
+        if let Some(horizon) = policy.audit_horizon {
+            dropped += prune_audit_log(horizon.as_secs(), now);
+        }
+        dropped
+    }
+}
+
+/// Runs a single pruning pass over `stores`, returning the number of dropped entries
+pub async fn apply_retention(
+    stores: &[Arc<dyn Retain>],
+    policy: &RetentionPolicy,
+    clock: &dyn Clock,
+) -> usize {
+    let now = clock.unix_seconds();
+    let mut dropped = 0;
+    for store in stores {
+        dropped += store.apply_retention(policy, now).await;
+    }
+    dropped
+}
+
+/// Prunes `stores` every `interval` until shutdown.
+/// Must be called from within a tokio runtime.
+pub fn spawn_retention_task(
+    policy: RetentionPolicy,
+    stores: Vec<Arc<dyn Retain>>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) -> tokio::task::JoinHandle<()> {
+    let token = shutdown_token();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = ticker.tick() => {
+                    apply_retention(&stores, &policy, &clock).await;
+                }
+            }
+        }
+    })
+}
+
+/// Nonce garbage collection task, stopped when dropped or on shutdown
+pub struct NonceGcTask {
+    token: CancellationToken,
+    max_nonce_age: Option<Duration>,
+}
+
+impl Drop for NonceGcTask {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+/// Runs a single garbage collection pass over the process-wide nonce store, forgetting nonces
+/// older than `max_nonce_age` (if any) and those of retired keys, and records it in the
+/// metrics. Returns the number of forgotten nonces.
+pub fn collect_nonces(max_nonce_age: Option<Duration>, now: u64) -> usize {
+    let Some(nonce_store) = replay_nonce_store() else {
+        return 0;
+    };
+    let expired = match max_nonce_age {
+        Some(max_age) => nonce_store.prune_older_than(max_age.as_secs(), now),
+        None => 0,
+    };
+    let pruned = expired + nonce_store.prune_retired_keys(now);
+    record_nonce_gc(pruned, nonce_store.stats().entries);
+    pruned
+}
+
+/// Collects the nonces of the process-wide nonce store every `interval` on `rt`, see
+/// `collect_nonces`, until the returned task is dropped or on shutdown
+pub fn spawn_nonce_gc_task(
+    rt: &tokio::runtime::Runtime,
+    max_nonce_age: Option<Duration>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) -> NonceGcTask {
+    let token = shutdown_token().child_token();
+    let cancelled = token.clone();
+    rt.spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = cancelled.cancelled() => break,
+                _ = ticker.tick() => {
+                    collect_nonces(max_nonce_age, clock.unix_seconds());
+                }
+            }
+        }
+    });
+    NonceGcTask {
+        token,
+        max_nonce_age,
+    }
+}
+
+/// The task started with `start_nonce_gc`
+static NONCE_GC: Mutex<Option<NonceGcTask>> = Mutex::new(None);
+
+/// Replaces the process-wide nonce garbage collection task with `task`, None stopping it
+fn replace_nonce_gc_task(task: Option<NonceGcTask>) {
+    // a poisoned lock still holds a valid task, as it is only ever overwritten whole
+    *NONCE_GC.lock().unwrap_or_else(|err| err.into_inner()) = task;
+}
+
+/// Shortest nonce age enforced on the process-wide nonce store, by the retention policy (see
+/// `set_retention_policy`) or the nonce garbage collection task (see `start_nonce_gc`)
+pub fn process_max_nonce_age() -> Option<Duration> {
+    let policy = with_global_retention(|retention| retention.policy.max_nonce_age);
+    let gc = NONCE_GC
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+        .and_then(|task| task.max_nonce_age);
+    match (policy, gc) {
+        (Some(policy), Some(gc)) => Some(policy.min(gc)),
+        (policy, gc) => policy.or(gc),
+    }
+}
+
+struct GlobalRetention {
+    policy: RetentionPolicy,
+    interval: Duration,
+    running: bool,
+}
+
+static GLOBAL_RETENTION: Mutex<GlobalRetention> = Mutex::new(GlobalRetention {
+    policy: RetentionPolicy {
+        max_nonce_age: None,
+        key_history_depth: None,
+        audit_horizon: None,
+    },
+    interval: DEFAULT_PRUNE_INTERVAL,
+    running: false,
+});
+
+fn with_global_retention<T>(f: impl FnOnce(&mut GlobalRetention) -> T) -> T {
+    // a poisoned lock still holds a valid configuration, as it is only ever overwritten whole
+    f(&mut GLOBAL_RETENTION
+        .lock()
+        .unwrap_or_else(|err| err.into_inner()))
+}
+
+/// Applies `policy` to the process-wide stores every `interval`, starting the pruning task
+/// on a dedicated thread if it isn't running yet. Later calls replace the policy.
+pub fn configure_retention(
+    policy: RetentionPolicy,
+    interval: Duration,
+) -> Result<(), std::io::Error> {
+    let start = with_global_retention(|retention| {
+        retention.policy = policy;
+        retention.interval = interval;
+        !std::mem::replace(&mut retention.running, true)
+    });
+    if !start {
+        return Ok(());
+    }
+    let token = shutdown_token();
+    let spawned = std::thread::Builder::new()
+        .name("pp-retention".to_string())
+        .spawn(move || {
+            if let Ok(rt) = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+            {
+                rt.block_on(async {
+                    let stores: Vec<Arc<dyn Retain>> = vec![Arc::new(ProcessWideStores)];
+                    loop {
+                        let (policy, interval) = with_global_retention(|retention| {
+                            (retention.policy, retention.interval)
+                        });
+                        apply_retention(&stores, &policy, &global_clock()).await;
+                        tokio::select! {
+                            _ = token.cancelled() => break,
+                            _ = tokio::time::sleep(interval) => {}
+                        }
+                    }
+                });
+            }
+            with_global_retention(|retention| retention.running = false);
+        });
+    if let Err(err) = spawned {
+        with_global_retention(|retention| retention.running = false);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Sets the retention policy of the process-wide replay nonce store and audit log, pruned
+/// every `interval_seconds`. NOTE: pass 0 for a limit to leave it unlimited, and 0 as
+/// `interval_seconds` for the default interval
+#[no_mangle]
+pub extern "C" fn set_retention_policy(
+    max_nonce_age_seconds: u32,
+    audit_horizon_seconds: u32,
+    interval_seconds: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let seconds = |seconds: u32| (seconds > 0).then(|| Duration::from_secs(seconds.into()));
+        let policy = RetentionPolicy {
+            max_nonce_age: seconds(max_nonce_age_seconds),
+            // key stores of the FFI only live for the duration of a call
+            key_history_depth: None,
+            audit_horizon: seconds(audit_horizon_seconds),
+        };
+        configure_retention(
+            policy,
+            seconds(interval_seconds).unwrap_or(DEFAULT_PRUNE_INTERVAL),
+        )?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Prunes the nonces redeemed through `validate_token` and the pp_server_* functions every
+/// `interval_seconds` in the background, forgetting those older than `max_nonce_age_seconds`
+/// and those of retired keys (see `set_key_nonce_ttl`), and records the outcome in the
+/// metrics. The nonce store is process-wide, so is the task: it replaces the one previously
+/// started, and runs until `stop_nonce_gc` or shutdown.
+/// NOTE: pass 0 as `max_nonce_age_seconds` to only forget nonces of retired keys, and 0 as
+///       `interval_seconds` for the default interval. A forgotten nonce can be redeemed again,
+///       so `max_nonce_age_seconds` must exceed how long tokens are accepted for.
+#[no_mangle]
+pub extern "C" fn start_nonce_gc(max_nonce_age_seconds: u32, interval_seconds: u32) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let seconds = |seconds: u32| (seconds > 0).then(|| Duration::from_secs(seconds.into()));
+        replace_nonce_gc_task(Some(spawn_nonce_gc_task(
+            ffi_runtime()?,
+            seconds(max_nonce_age_seconds),
+            seconds(interval_seconds).unwrap_or(DEFAULT_PRUNE_INTERVAL),
+            global_clock(),
+        )));
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Stops the task started with `start_nonce_gc`, if any
+#[no_mangle]
+pub extern "C" fn stop_nonce_gc() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        replace_nonce_gc_task(None);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_apply_retention() {
+        let nonce_store = Arc::new(MemoryNonceStore::default());
+        let key_store = Arc::new(MemoryKeyStoreRistretto255::default());
+        for seed in 0u8..3 {
+            let server =
+                voprf::VoprfServer::<voprf::Ristretto255>::new_from_seed(&[seed; 32], b"").unwrap();
+            key_store.insert_checked(seed, server).unwrap();
+        }
+        nonce_store.insert_if_absent([0u8; 32]);
+        let stores: Vec<Arc<dyn Retain>> = vec![nonce_store.clone(), key_store.clone()];
+        let clock = MockClock::new(global_clock().now());
+
+        // unlimited by default
+        assert_eq!(
+            apply_retention(&stores, &RetentionPolicy::default(), &clock).await,
+            0
+        );
+        let policy = RetentionPolicy {
+            max_nonce_age: Some(Duration::from_secs(3_600)),
+            key_history_depth: Some(1),
+            audit_horizon: None,
+        };
+        assert_eq!(apply_retention(&stores, &policy, &clock).await, 2);
+        clock.advance(Duration::from_secs(3_601));
+        assert_eq!(apply_retention(&stores, &policy, &clock).await, 1);
+        assert_eq!(nonce_store.stats().entries, 0);
+        assert_eq!(key_store.stats().entries, 1);
+    }
+}
@@ -0,0 +1,219 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  key revocation  --------------------------------
+// -----------------------------------------------------------------------------
+//
+// Revoked truncated key ids are refused for both issuance and redemption.
+// Key stores consult a RevocationStore through RevocationCheckingKeyStore, so replicas sharing
+// a RevocationStore implementation refuse a compromised key as soon as it is revoked.
+// The FFI functions, which get handed secret keys directly, consult the process-wide list
+// returned by `global_revocations`, which Crystal keeps in sync through `set_revoked_key_ids`.
+// NOTE: truncated key ids are a single byte, so revoking one also refuses any other key
+//       sharing the same truncated id.
+
+use crate::batched_memory_stores::{MemoryRevocationStore, TokenKeyIdLookup};
+use crate::crystal::{
+    crystal_error, decode_string_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, JSONRetVal,
+};
+use async_trait::async_trait;
+use privacypass::batched_tokens_ristretto255::server::BatchedKeyStore;
+use privacypass::TruncatedTokenKeyId;
+use thiserror::Error;
+use voprf::{Ristretto255, VoprfServer};
+
+#[async_trait]
+pub trait RevocationStore: Send + Sync {
+    async fn is_revoked(&self, truncated_token_key_id: &TruncatedTokenKeyId) -> bool;
+    async fn revoke(&self, truncated_token_key_id: TruncatedTokenKeyId);
+}
+
+#[async_trait]
+impl<RS: RevocationStore + ?Sized> RevocationStore for &RS {
+    async fn is_revoked(&self, truncated_token_key_id: &TruncatedTokenKeyId) -> bool {
+        (**self).is_revoked(truncated_token_key_id).await
+    }
+
+    async fn revoke(&self, truncated_token_key_id: TruncatedTokenKeyId) {
+        (**self).revoke(truncated_token_key_id).await
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("key with truncated id {0} was revoked")]
+pub struct KeyRevokedError(pub TruncatedTokenKeyId);
+
+static GLOBAL_REVOCATIONS: MemoryRevocationStore = MemoryRevocationStore::new();
+
+/// Revocation list consulted by the FFI functions and the `PrivacyPass` API
+pub fn global_revocations() -> &'static MemoryRevocationStore {
+    &GLOBAL_REVOCATIONS
+}
+
+/// Errors out if `truncated_token_key_id` is in the process-wide revocation list
+pub fn check_not_revoked(
+    truncated_token_key_id: TruncatedTokenKeyId,
+) -> Result<(), KeyRevokedError> {
+    match global_revocations().contains(&truncated_token_key_id) {
+        true => Err(KeyRevokedError(truncated_token_key_id)),
+        false => Ok(()),
+    }
+}
+
+/// Key store wrapper refusing to store or hand out revoked keys
+pub struct RevocationCheckingKeyStore<KS, RS> {
+    key_store: KS,
+    revocations: RS,
+}
+
+impl<KS, RS> RevocationCheckingKeyStore<KS, RS> {
+    pub fn new(key_store: KS, revocations: RS) -> Self {
+        RevocationCheckingKeyStore {
+            key_store,
+            revocations,
+        }
+    }
+}
+
+#[async_trait]
+impl<KS, RS> BatchedKeyStore for RevocationCheckingKeyStore<KS, RS>
+where
+    KS: BatchedKeyStore + Send + Sync,
+    RS: RevocationStore,
+{
+    async fn insert(
+        &self,
+        truncated_token_key_id: TruncatedTokenKeyId,
+        server: VoprfServer<Ristretto255>,
+    ) {
+        if self.revocations.is_revoked(&truncated_token_key_id).await {
+            return;
+        }
+        self.key_store.insert(truncated_token_key_id, server).await
+    }
+
+    async fn get(
+        &self,
+        truncated_token_key_id: &TruncatedTokenKeyId,
+    ) -> Option<VoprfServer<Ristretto255>> {
+        // checked on every lookup, so that keys revoked after insertion are refused too
+        if self.revocations.is_revoked(truncated_token_key_id).await {
+            return None;
+        }
+        self.key_store.get(truncated_token_key_id).await
+    }
+}
+
+#[async_trait]
+impl<KS, RS> TokenKeyIdLookup for RevocationCheckingKeyStore<KS, RS>
+where
+    KS: TokenKeyIdLookup + Send + Sync,
+    RS: RevocationStore,
+{
+    async fn get_by_token_key_id(&self, token_key_id: &[u8]) -> Option<VoprfServer<Ristretto255>> {
+        let truncated_token_key_id = *token_key_id.last()?;
+        if self.revocations.is_revoked(&truncated_token_key_id).await {
+            return None;
+        }
+        self.key_store.get_by_token_key_id(token_key_id).await
+    }
+}
+
+/// Adds a truncated key id to the process-wide revocation list
+#[no_mangle]
+pub extern "C" fn revoke_key_id(truncated_token_key_id: u8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        global_revocations().insert(truncated_token_key_id);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Replaces the process-wide revocation list with a JSON array of truncated key ids,
+/// e.g. "[3, 17]", as read from the revocation store shared by all replicas
+#[no_mangle]
+pub extern "C" fn set_revoked_key_ids(key_ids_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let key_ids_s = unsafe { decode_string_from_crystal(key_ids_cstr)? };
+        let key_ids: Vec<TruncatedTokenKeyId> = match serde_json::from_str(&key_ids_s) {
+            Ok(key_ids) => Ok(key_ids),
+            Err(_) => Err(crystal_error("expected a JSON array of truncated key ids")),
+        }?;
+        global_revocations().replace(key_ids);
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Returns the process-wide revocation list as a JSON array of truncated key ids
+#[no_mangle]
+pub extern "C" fn get_revoked_key_ids() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&global_revocations().revoked_key_ids())?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batched_memory_stores::MemoryKeyStoreRistretto255;
+
+    #[tokio::test]
+    async fn test_revoked_keys_are_refused() {
+        let server = VoprfServer::<Ristretto255>::new_from_seed(&[1u8; 32], b"test").unwrap();
+        let revocations = MemoryRevocationStore::new();
+        let key_store =
+            RevocationCheckingKeyStore::new(MemoryKeyStoreRistretto255::default(), &revocations);
+
+        key_store.insert(1, server.clone()).await;
+        assert!(key_store.get(&1).await.is_some());
+
+        // revoked after insertion
+        revocations.revoke(1).await;
+        assert!(key_store.get(&1).await.is_none());
+
+        // revoked before insertion
+        revocations.revoke(2).await;
+        key_store.insert(2, server).await;
+        assert!(key_store.get(&2).await.is_none());
+    }
+}
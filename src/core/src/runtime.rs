@@ -0,0 +1,42 @@
+// -----------------------------------------------------------------------------
+// -------------------------  shared FFI runtime  ------------------------------
+// -----------------------------------------------------------------------------
+//
+// FFI calls used to build, and tear down, a tokio runtime (and its worker threads) each.
+// They now share a lazily built multi-threaded runtime, living as long as the process.
+// NOTE: `block_on` can't be called from within a runtime, which FFI callers never are.
+
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+static FFI_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The runtime running the async parts of FFI calls, built on first use
+pub fn ffi_runtime() -> Result<&'static Runtime, std::io::Error> {
+    if let Some(rt) = FFI_RUNTIME.get() {
+        return Ok(rt);
+    }
+    // a failed build is not cached, so that a later call can try again
+    let rt = Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("pp-ffi")
+        .build()?;
+    // if another thread won the race, the runtime built here is dropped
+    Ok(FFI_RUNTIME.get_or_init(|| rt))
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi_runtime_is_shared() {
+        let rt = ffi_runtime().unwrap();
+        assert!(std::ptr::eq(rt, ffi_runtime().unwrap()));
+        assert_eq!(rt.block_on(async { 1 + 1 }), 2);
+    }
+}
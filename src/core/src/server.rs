@@ -2,38 +2,83 @@
 // -----------------------  Privacy Pass Code  ---------------------------------
 // -----------------------------------------------------------------------------
 
-#![allow(unreachable_patterns)] // used to catch possible error types not yet defined by dependencies
-
-use crate::config::{batched_tokens_mod, GroupTokenType, MemoryKeyStore, VoprfGroup, VERBOSE};
+#![allow(unreachable_patterns)]
+// used to catch possible error types not yet defined by dependencies
+use crate::config::{
+    batched_tokens_mod, BatchedGroup, BatchedP384TokenType, GroupTokenType, MemoryKeyStore,
+    VoprfGroup, VERBOSE,
+};
 
-use crate::batched_memory_stores::MemoryNonceStore;
+use crate::audit::{record_redemption, RedemptionOutcome};
+use crate::batched_memory_stores::TokenKeyIdLookup;
+use crate::challenge_authentication::{
+    challenges_authenticated, check_challenge_authentication, ChallengeAuthenticationError,
+};
+use crate::challenge_freshness::{
+    challenge_max_age, check_challenge_freshness, ChallengeFreshnessError,
+};
+use crate::crypto_pool::{run_blocking, CryptoPoolError};
 use crate::crystal::{
-    crystal_error, decode_bytes_from_crystal, decode_string_from_crystal,
-    encode_string_for_crystal, error_json_retval, CrystalErrorType, JSONRetVal,
+    borrow_untrusted_bytes_from_crystal, borrow_untrusted_str_from_crystal, crystal_error,
+    decode_secret_bytes_array_from_crystal, decode_secret_bytes_from_crystal,
+    decode_string_from_crystal, decode_untrusted_bytes_from_crystal, encode_json_for_crystal,
+    encode_secret_json_for_crystal, error_chain_json_retval, error_json_retval, secret_from_slice,
+    Base64Json, JSONErrorRetVal, JSONRetVal, JSONRetValRef,
 };
+use crate::inspect::TokenRequestInfo;
+pub(crate) use crate::issuance::{
+    deserialize_blinded_elements, public_key_to_token_key_id, public_key_to_truncated_token_key_id,
+    serialize_token_response,
+};
+pub use crate::issuance::{
+    DuplicateBlindedElementError, InvalidBlindedElementError, TokenRequestView,
+};
+use crate::key_encoding::KeyEncoding;
+use crate::key_validity::{check_key_validity, KeyValidityError};
+use crate::limits::{check_input_len, InputKind};
+use crate::metrics::{LatencyTimer, Operation};
+use crate::replay::{key_retired_at, redeem_nonce, SharedNonceStoreError};
+use crate::revocation::{check_not_revoked, KeyRevokedError};
+use crate::runtime::ffi_runtime;
+use crate::signer::{SignerError, VoprfSigner};
+use crate::NONCE_BYTES;
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use batched_tokens_mod::{
     server::{serialize_public_key, RedeemTokenError, Server},
-    BatchedToken, BlindedElement, PublicKey, TokenRequest, TokenResponse,
+    BatchedToken, BlindedElement, PublicKey, TokenRequest, TokenResponse, NE,
 };
 use generic_array::GenericArray;
+use hmac::{Hmac, Mac};
 use http::{HeaderName, HeaderValue};
+use kagippverify::token::{
+    authenticator_len, token_nonce, CHALLENGE_DIGEST_OFFSET, TOKEN_INPUT_LEN, TOKEN_KEY_ID_OFFSET,
+    TOKEN_TYPE_PRIVATE_P384, TOKEN_TYPE_PUBLIC_RSA,
+};
+use kagippverify::{ParseError, Token};
 use privacypass::batched_tokens_ristretto255::server::{
-    CreateKeypairError, IssueTokenResponseError,
+    BatchedKeyStore, CreateKeypairError, IssueTokenResponseError,
 };
-use privacypass::{auth::authenticate::TokenChallenge, TokenType, TruncatedTokenKeyId};
-use rand::{rngs::OsRng, RngCore};
+use privacypass::{auth::authenticate::TokenChallenge, Nonce, TokenType, TruncatedTokenKeyId};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use secrecy::{ExposeSecret, SecretBox};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use subtle::{Choice, ConstantTimeEq};
 use thiserror::Error;
 use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait, TlsVecU16};
 use tls_codec_derive::{TlsDeserialize, TlsSerialize, TlsSize};
-
-#[derive(Serialize, Deserialize)]
-struct KeyPair {
-    sk: String,
-    pk: String,
-    token_type: u16,
-    error: String,
+use tokio_util::sync::CancellationToken;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+// wiped on drop, as it carries the serialized secret key
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct KeyPair {
+    pub(crate) sk: String,
+    pub(crate) pk: String,
+    pub(crate) token_type: u16,
+    pub(crate) error: String,
 }
 #[derive(Serialize, Deserialize)]
 struct JSONTokens {
@@ -79,9 +124,30 @@ impl MyTokenRequest {
             return;
         }
 
-        for _ in max_elements..self.nr() {
-            self.blinded_elements.pop();
+        let mut blinded_elements =
+            std::mem::replace(&mut self.blinded_elements, TlsVecU16::new(Vec::new())).into_vec();
+        blinded_elements.truncate(max_elements);
+        self.blinded_elements = TlsVecU16::new(blinded_elements);
+    }
+
+    /// Splits the request into requests of at most `max_elements` blinded elements each,
+    /// keeping their order. NOTE: `max_elements` is at least 1, an empty request gives none.
+    pub fn split(self, max_elements: usize) -> Vec<MyTokenRequest> {
+        let mut blinded_elements = self.blinded_elements.into_vec().into_iter().peekable();
+        let mut chunks = Vec::new();
+        while blinded_elements.peek().is_some() {
+            chunks.push(MyTokenRequest {
+                token_type: self.token_type,
+                truncated_token_key_id: self.truncated_token_key_id,
+                blinded_elements: TlsVecU16::new(
+                    blinded_elements
+                        .by_ref()
+                        .take(max_elements.max(1))
+                        .collect(),
+                ),
+            });
         }
+        chunks
     }
 
     pub fn to_token_request(&self) -> Result<TokenRequest, tls_codec::Error> {
@@ -91,62 +157,481 @@ impl MyTokenRequest {
     }
 }
 
-use privacypass::auth::authenticate::build_www_authenticate_header;
-use voprf::{derive_key, Group, Mode};
+/// What issuance does with TokenRequests repeating a blinded element. Honest clients blind
+/// every token with fresh randomness, so repeats only make the issuer evaluate elements that
+/// can't yield distinct tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateElementPolicy {
+    /// refuse the whole request with a `DuplicateBlindedElementError`, the default
+    Reject,
+    /// issue for the first occurrence of each blinded element only, in request order. The
+    /// gen_token_response FFI functions report the indices of the blinded elements answered
+    /// as `kept`, which `PrivacyPassClient::finalize` and `gen_token` infer on their own.
+    Deduplicate,
+}
 
-use privacypass::auth::authenticate::RedemptionContext;
+static DEDUPLICATE_ELEMENTS: AtomicBool = AtomicBool::new(false);
 
-#[no_mangle]
-pub extern "C" fn gen_keys() -> *const i8 {
-    // NOTE: the value of result below would not be *const i8
-    //       if the begin_panic_handling and end_panic_handling macros where not there
-    begin_panic_handling!();
-    let result = panic::catch_unwind(|| {
-        // sample randomness for key generation
-        let mut seed = GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default();
-        OsRng.fill_bytes(&mut seed);
+/// Selects what `gen_token_response` does with TokenRequests repeating a blinded element
+pub fn set_duplicate_element_policy(policy: DuplicateElementPolicy) {
+    DEDUPLICATE_ELEMENTS.store(
+        policy == DuplicateElementPolicy::Deduplicate,
+        Ordering::Relaxed,
+    );
+}
+
+pub fn duplicate_element_policy() -> DuplicateElementPolicy {
+    match DEDUPLICATE_ELEMENTS.load(Ordering::Relaxed) {
+        true => DuplicateElementPolicy::Deduplicate,
+        false => DuplicateElementPolicy::Reject,
+    }
+}
 
-        // setting domain separation for VOPRF secret key generation
-        // as recommended by RFC 9578 (PP issuance protocol), section 5.5
-        let info = b"PrivacyPass";
+/// TokenRequest left to issue for once repeated blinded elements were dropped
+pub(crate) struct DeduplicatedTokenRequest {
+    /// the serialized TokenRequest
+    pub bytes: Vec<u8>,
+    /// indices of the blinded elements of the original request kept, in order
+    pub kept: Vec<usize>,
+}
 
-        // generate keys
-        let rt = tokio::runtime::Runtime::new()?;
-        let key_store = MemoryKeyStore::default();
-        let server = Server::new();
-        let public_key = rt.block_on(async {
-            server
-                .create_keypair_with_params(&key_store, &seed, info)
-                .await
+/// Applies `policy` to the blinded elements of `token_request`, returning the request to
+/// issue for instead when repeated ones were dropped
+pub(crate) fn deduplicated_token_request(
+    token_request: &TokenRequestView,
+    policy: DuplicateElementPolicy,
+) -> Result<Option<DeduplicatedTokenRequest>, GenTokenResponseError> {
+    let Some(index) = token_request.first_duplicate() else {
+        return Ok(None);
+    };
+    match policy {
+        DuplicateElementPolicy::Reject => Err(DuplicateBlindedElementError(index).into()),
+        DuplicateElementPolicy::Deduplicate => Ok(Some(DeduplicatedTokenRequest {
+            bytes: token_request
+                .to_deduplicated_bytes()
+                .map_err(GenTokenResponseError::Tls)?,
+            kept: token_request.first_occurrences(),
+        })),
+    }
+}
+
+/// Fast path for TokenRequests carrying a single BlindedElement.
+/// The spec still requires a DLEQ proof for nr == 1, but for a single element the batched
+/// proof is the same one produced by `VoprfServer::blind_evaluate`, so we skip the key store
+/// and the batch evaluation machinery, and assemble the TokenResponse wire bytes directly.
+fn issue_single_token_response(
+    private_key: &[u8],
+    token_request: &TokenRequestView,
+) -> Result<TokenResponse, GenTokenResponseError> {
+    if token_request.nr() != 1 {
+        return Err(GenTokenResponseError::NotSingleElement(token_request.nr()));
+    }
+    let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+        .map_err(GenTokenResponseError::InvalidKey)?;
+    issue_token_response_with_signer(&server, token_request)
+}
+
+/// Issues a TokenResponse evaluating the blinded elements directly with the VOPRF server,
+/// without a key store or an async runtime. Single element requests take the fast path above.
+pub(crate) fn issue_token_response_sync(
+    private_key: &[u8],
+    token_request: &TokenRequestView,
+) -> Result<TokenResponse, GenTokenResponseError> {
+    if token_request.nr() == 1 {
+        return issue_single_token_response(private_key, token_request);
+    }
+    let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+        .map_err(GenTokenResponseError::InvalidKey)?;
+    issue_token_response_with_signer(&server, token_request)
+}
+
+/// Issues a TokenResponse having `signer` evaluate the blinded elements, which is how
+/// issuance works when the secret key is not in memory, e.g. held by an HSM
+pub(crate) fn issue_token_response_with_signer<S: VoprfSigner + ?Sized>(
+    signer: &S,
+    token_request: &TokenRequestView,
+) -> Result<TokenResponse, GenTokenResponseError> {
+    if token_request.token_type() != GroupTokenType {
+        return Err(GenTokenResponseError::InvalidTokenType);
+    }
+    let token_key_id = public_key_to_token_key_id(signer.public_key());
+    let [.., truncated_token_key_id] = token_key_id;
+    if truncated_token_key_id != token_request.truncated_token_key_id() {
+        return Err(GenTokenResponseError::KeyIdNotFound);
+    }
+    check_key::<GenTokenResponseError>(&token_key_id, KeyUse::Issuance)?;
+
+    let deduplicated_request =
+        deduplicated_token_request(token_request, duplicate_element_policy())?;
+    let deduplicated;
+    let token_request = match &deduplicated_request {
+        Some(deduplicated_request) => {
+            deduplicated = TokenRequestView::try_from_bytes(&deduplicated_request.bytes)
+                .map_err(GenTokenResponseError::Tls)?;
+            &deduplicated
+        }
+        None => token_request,
+    };
+
+    let blinded_elements = deserialize_blinded_elements(token_request)?;
+    evaluate_blinded_elements(signer, &blinded_elements)
+}
+
+/// Has `signer` evaluate blinded elements already checked by `deserialize_blinded_elements`
+/// into a TokenResponse
+fn evaluate_blinded_elements<S: VoprfSigner + ?Sized>(
+    signer: &S,
+    blinded_elements: &[BlindedElement<VoprfGroup>],
+) -> Result<TokenResponse, GenTokenResponseError> {
+    let evaluation = signer
+        .blind_evaluate(blinded_elements)
+        .map_err(|err| match err {
+            SignerError::Voprf(err) => GenTokenResponseError::Evaluate(err),
+            #[cfg(feature = "pkcs11")]
+            err => GenTokenResponseError::Signer(err),
         })?;
 
-        // serialise keys
-        let pk_s = URL_SAFE.encode(serialize_public_key(public_key));
-        let sk_bytes = match derive_key::<VoprfGroup>(&seed, info, Mode::Voprf) {
-            Ok(res) => Ok(res.to_bytes()),
-            Err(_) => Err(crystal_error("failed generating secret key")),
-        }?;
-        let sk_s = URL_SAFE.encode(sk_bytes);
-
-        // construct keypair structure
-        let keypair: KeyPair = KeyPair {
-            pk: pk_s,
-            sk: sk_s,
-            token_type: GroupTokenType as u16,
-            error: "".to_string(),
+    let token_response_bytes = serialize_token_response(&evaluation.messages, &evaluation.proof)
+        .map_err(|_| GenTokenResponseError::InvalidTokenResponse)?;
+    TokenResponse::try_from_bytes(&token_response_bytes)
+        .map_err(|_| GenTokenResponseError::InvalidTokenResponse)
+}
+
+use privacypass::auth::authenticate::{
+    build_www_authenticate_header, parse_www_authenticate_header,
+};
+use voprf::{derive_key, Group, Mode, VoprfClient, VoprfServer};
+
+use crate::batched_memory_stores::{AtomicNonceStore, TokenKeyIdLookup};
+use crate::nonce_store::NonceStore;
+use privacypass::auth::authenticate::RedemptionContext;
+
+/// Redeems a token like `Server::redeem_token`, but runs the nonce store lookup (possibly
+/// a network round trip) concurrently with the local VOPRF verification, and reconciles both
+/// results at the end. Invalid tokens are reported as such even if their nonce was seen before.
+/// The key is looked up by the token's full key id, so keys sharing a truncated id are told apart,
+/// and the nonce is recorded atomically, so concurrent redemptions of a token can't all succeed.
+/// The token must carry the digest of the (base64) `token_challenge`, which goes through the
+/// same authentication and freshness checks as those of `PrivacyPass::redeem_token`, and the
+/// key through the same revocation and validity checks.
+pub async fn redeem_token_concurrently<
+    KS: TokenKeyIdLookup + ?Sized,
+    NS: AtomicNonceStore + ?Sized,
+>(
+    key_store: &KS,
+    nonce_store: &NS,
+    token: BatchedToken,
+    token_challenge: &str,
+) -> Result<(), ValidateTokenError> {
+    let _timer = LatencyTimer::start(Operation::Redemption);
+    if token.token_type() != GroupTokenType {
+        Err(RedeemTokenError::InvalidToken)?;
+    }
+    check_redemption_challenge::<ValidateTokenError>(Some(token_challenge))?;
+    let challenge_digest = TokenChallenge::from_base64(token_challenge)
+        .map_err(|_| ValidateTokenError::ChallengeDigest)?
+        .digest()
+        .map_err(|_| ValidateTokenError::ChallengeDigest)?;
+    let token_key_id = *token.token_key_id();
+    let [.., truncated_token_key_id] = token_key_id;
+    check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+    let server = key_store
+        .get_by_token_key_id(&token_key_id)
+        .await
+        .ok_or(RedeemTokenError::KeyIdNotFound)?;
+
+    let verification = async {
+        // token_input = token_type || nonce || challenge_digest || token_key_id
+        let mut token_input = Vec::with_capacity(2 + NONCE_BYTES + 32 + 32);
+        token_input.extend_from_slice(&(GroupTokenType as u16).to_be_bytes());
+        token_input.extend_from_slice(&token.nonce());
+        token_input.extend_from_slice(token.challenge_digest());
+        token_input.extend_from_slice(&token_key_id);
+        let digest = token.challenge_digest().as_slice().ct_eq(&challenge_digest);
+        let authenticator = match server.evaluate(&token_input) {
+            Ok(authenticator) => authenticator.as_slice().ct_eq(token.authenticator()),
+            Err(_) => Choice::from(0),
         };
-        let keypair_json = serde_json::to_string(&keypair)?;
+        bool::from(digest & authenticator)
+    };
+    let (spent, valid) = tokio::join!(nonce_store.exists(&token.nonce()), verification);
+
+    let outcome = match valid {
+        false => RedemptionOutcome::Invalid,
+        true if spent || !nonce_store.insert_if_absent(token.nonce()).await => {
+            RedemptionOutcome::DoubleSpent
+        }
+        true => RedemptionOutcome::Valid,
+    };
+    record_redemption(truncated_token_key_id, outcome);
+    match outcome {
+        RedemptionOutcome::Valid => Ok(()),
+        RedemptionOutcome::DoubleSpent => Err(RedeemTokenError::DoubleSpending.into()),
+        _ => Err(RedeemTokenError::InvalidToken.into()),
+    }
+}
 
-        if VERBOSE {
-            println!("R: Issuer keypair {}", keypair_json);
+// BatchedToken = token_input || authenticator[Nk], see kagippverify::token for the layout
+const TOKEN_LEN: usize = std::mem::size_of::<BatchedToken>();
+
+/// Checks a serialized token against `server`, combining the outcome of every check
+/// (size, token type, challenge digest, key id, VOPRF authenticator) without branching on
+/// any of them, so that network observers can't time which one failed.
+/// Tokens of the wrong size are checked as if zero-padded or truncated to the right size.
+pub(crate) fn verify_token_uniformly<S: VoprfSigner + ?Sized>(
+    server: &S,
+    token: &[u8],
+    challenge_digest: Option<&[u8]>,
+) -> Choice {
+    let size = token.len().ct_eq(&TOKEN_LEN);
+    let mut token_bytes = [0u8; TOKEN_LEN];
+    token_bytes
+        .iter_mut()
+        .zip(token)
+        .for_each(|(byte, token_byte)| *byte = *token_byte);
+    let (token_input, authenticator) = token_bytes.split_at(TOKEN_INPUT_LEN);
+    // the ranges are within TOKEN_INPUT_LEN, the empty fallback only ever fails the check
+    let field = |range: std::ops::Range<usize>| token_input.get(range).unwrap_or_default();
+
+    let token_type = field(0..2).ct_eq(&(GroupTokenType as u16).to_be_bytes());
+    let digest = match challenge_digest {
+        Some(challenge_digest) => {
+            field(CHALLENGE_DIGEST_OFFSET..TOKEN_KEY_ID_OFFSET).ct_eq(challenge_digest)
         }
+        None => Choice::from(1),
+    };
+    let token_key_id = public_key_to_token_key_id(server.public_key());
+    let key_id = field(TOKEN_KEY_ID_OFFSET..TOKEN_INPUT_LEN).ct_eq(&token_key_id);
+    let authenticator = match server.evaluate(token_input) {
+        Ok(expected) => expected.as_slice().ct_eq(authenticator),
+        Err(_) => Choice::from(0),
+    };
+
+    size & token_type & digest & key_id & authenticator
+}
 
-        let rv = JSONRetVal {
-            retval: keypair_json,
-            error: "".to_string(),
-        };
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+/// What a key is about to be used for, see `check_key`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyUse {
+    Issuance,
+    Redemption,
+}
+
+/// Refuses keys that are revoked or outside of their validity window, and for redemptions
+/// keys retired from the nonce store (whose tokens could be replayed, see
+/// `set_nonce_key_lifetime`), recording refused redemptions in the audit log.
+/// NOTE: every issuance and redemption path goes through here, keep it that way
+pub(crate) fn check_key<E>(token_key_id: &[u8; 32], key_use: KeyUse) -> Result<(), E>
+where
+    E: From<KeyRevokedError> + From<KeyValidityError>,
+{
+    let [.., truncated_token_key_id] = *token_key_id;
+    let refused = |outcome| {
+        if key_use == KeyUse::Redemption {
+            record_redemption(truncated_token_key_id, outcome)
+        }
+    };
+    check_not_revoked(truncated_token_key_id)
+        .inspect_err(|_| refused(RedemptionOutcome::KeyRevoked))?;
+    let retired_at = match key_use {
+        KeyUse::Issuance => None,
+        KeyUse::Redemption => key_retired_at(token_key_id),
+    };
+    check_key_validity(token_key_id)
+        .and_then(|()| match retired_at {
+            Some(not_after) => Err(KeyValidityError::Expired {
+                truncated_token_key_id,
+                not_after,
+            }),
+            None => Ok(()),
+        })
+        .inspect_err(|err| {
+            refused(match err {
+                KeyValidityError::NotYetValid { .. } => RedemptionOutcome::KeyNotYetValid,
+                KeyValidityError::Expired { .. } => RedemptionOutcome::KeyExpired,
+            })
+        })?;
+    Ok(())
+}
+
+/// Refuses redemptions against the (base64) `token_challenge` if it fails the authentication
+/// and freshness checks set up, and redemptions without a challenge as soon as challenges
+/// are authenticated or checked for freshness, as there is then no MAC or issuance time to
+/// check.
+/// NOTE: every redemption path goes through here, keep it that way
+pub(crate) fn check_redemption_challenge<E>(token_challenge: Option<&str>) -> Result<(), E>
+where
+    E: From<ChallengeAuthenticationError> + From<ChallengeFreshnessError>,
+{
+    match token_challenge {
+        Some(token_challenge) => {
+            check_challenge_authentication(token_challenge)?;
+            check_challenge_freshness(token_challenge)?;
+        }
+        None if challenges_authenticated() => Err(ChallengeAuthenticationError::NotAuthenticated)?,
+        None if challenge_max_age().is_some() => Err(ChallengeFreshnessError::NotTimestamped)?,
+        None => {}
+    }
+    Ok(())
+}
+
+/// How many seeds `sample_key_seed` tries before giving up on finding a free truncated key id
+const MAX_KEY_ID_ATTEMPTS: usize = 1024;
+
+/// Samples a key seed whose truncated key id is not in `taken`. Truncated key ids are a single
+/// byte, so on collision with a key still in use the seed is resampled and the key re-derived.
+pub(crate) fn sample_key_seed<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+) -> Result<Zeroizing<GenericArray<u8, <VoprfGroup as Group>::ScalarLen>>, GenKeysError> {
+    if (0..=TruncatedTokenKeyId::MAX).all(|id| taken.contains(&id)) {
+        return Err(GenKeysError::NoFreeKeyId);
+    }
+    for _ in 0..MAX_KEY_ID_ATTEMPTS {
+        let mut seed =
+            Zeroizing::new(GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default());
+        rng.fill_bytes(&mut seed);
+        if taken.is_empty() {
+            return Ok(seed);
+        }
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&seed, info)
+            .map_err(GenKeysError::DeriveKey)?;
+        if !taken.contains(&public_key_to_truncated_token_key_id(
+            server.get_public_key(),
+        )) {
+            return Ok(seed);
+        }
+    }
+    Err(GenKeysError::NoFreeKeyId)
+}
+
+/// Generates batched keys of `group` whose truncated key id is not in `taken`
+fn gen_batched_keys_for_crystal(
+    group: BatchedGroup,
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    match group {
+        BatchedGroup::Ristretto255 => gen_ristretto255_keys_for_crystal(info, taken, encoding),
+        BatchedGroup::P384 => {
+            crate::batched_p384::gen_keys_for_crystal(ffi_runtime()?, info, taken, encoding)
+        }
+    }
+}
+
+/// Generates keys for `token_type` as described in `gen_keys`, deriving them under `info`
+/// and returning them in `encoding`. Batched keys avoid the truncated key ids in `taken`,
+/// other token types take none.
+fn gen_keys_for_token_type(
+    token_type: u16,
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let batched_group = match token_type {
+        0 => Some(BatchedGroup::default()),
+        _ => BatchedGroup::from_token_type(token_type),
+    };
+    if let Some(batched_group) = batched_group {
+        return gen_batched_keys_for_crystal(batched_group, info, taken, encoding);
+    }
+    if !taken.is_empty() {
+        Err(crystal_error("only batched keys avoid taken key ids"))?;
+    }
+    match token_type {
+        TOKEN_TYPE_PRIVATE_P384 => {
+            crate::private_tokens::gen_keys_for_crystal(ffi_runtime()?, info, encoding)
+        }
+        // blind RSA keys are not derived from a seed, there is no info to namespace them with
+        TOKEN_TYPE_PUBLIC_RSA if info != DEFAULT_KEY_INFO => {
+            Err(crystal_error("blind RSA keys take no info string"))?
+        }
+        TOKEN_TYPE_PUBLIC_RSA => crate::public_tokens::gen_keys_for_crystal(encoding),
+        _ => Err(UnsupportedTokenTypeError(token_type).into()),
+    }
+}
+
+fn gen_ristretto255_keys_for_crystal(
+    info: &[u8],
+    taken: &[TruncatedTokenKeyId],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    // sample randomness for key generation
+    let seed = sample_key_seed(&mut OsRng, info, taken)?;
+    ristretto255_keys_from_seed_for_crystal(&seed, info, encoding)
+}
+
+/// Decodes an optional key derivation info string, DEFAULT_KEY_INFO when null
+///
+/// # Safety
+///
+/// Callers must provide either a null pointer or a valid NUL terminated string pointer.
+unsafe fn decode_info_from_crystal(
+    info_cstr: *const i8,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match info_cstr.is_null() {
+        true => Ok(DEFAULT_KEY_INFO.to_vec()),
+        false => Ok(unsafe { decode_string_from_crystal(info_cstr)? }.into_bytes()),
+    }
+}
+
+/// Derives the ristretto255 keypair of `seed` under `info`, as a KeyPair JSON with the keys
+/// in `encoding`
+fn ristretto255_keys_from_seed_for_crystal(
+    seed: &[u8],
+    info: &[u8],
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    // generate keys
+    let rt = ffi_runtime()?;
+    let key_store = MemoryKeyStore::default();
+    let server = Server::new();
+    let public_key = rt.block_on(async {
+        server
+            .create_keypair_with_params(&key_store, seed, info)
+            .await
+    })?;
+
+    // serialise keys
+    let pk_s = encoding.encode(&serialize_public_key(public_key));
+    let sk_bytes = match derive_key::<VoprfGroup>(seed, info, Mode::Voprf) {
+        Ok(res) => Ok(Zeroizing::new(res.to_bytes())),
+        Err(_) => Err(crystal_error("failed generating secret key")),
+    }?;
+    let sk_s = encoding.encode(sk_bytes.as_slice());
+
+    // construct keypair structure
+    let keypair: KeyPair = KeyPair {
+        pk: pk_s,
+        sk: sk_s,
+        token_type: GroupTokenType as u16,
+        error: "".to_string(),
+    };
+    let keypair_json = Zeroizing::new(serde_json::to_string(&keypair)?);
+
+    if VERBOSE {
+        println!("R: Issuer keypair {}", *keypair_json);
+    }
+
+    let rv = JSONRetValRef {
+        retval: keypair_json.as_str(),
+        error: "",
+    };
+    Ok(encode_secret_json_for_crystal(&rv)?)
+}
+
+/// Generates keys for `token_type`: 0x0001 (P-384), 0x0002 (blind RSA), 0x0005 (batched
+/// ristretto255) or batched P-384. NOTE: pass 0 for batched ristretto255
+#[no_mangle]
+pub extern "C" fn gen_keys(token_type: u16) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let out =
+            gen_keys_for_token_type(token_type, DEFAULT_KEY_INFO, &[], KeyEncoding::default())?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -155,37 +640,17 @@ pub extern "C" fn gen_keys() -> *const i8 {
     result
 }
 
+/// Like `gen_keys`, but derives the key under `info_cstr` instead of DEFAULT_KEY_INFO, so
+/// that deployments can namespace their keys per environment (e.g. "PrivacyPass staging").
+/// NOTE: pass a null `info_cstr` for DEFAULT_KEY_INFO. Blind RSA keys take no info string.
 #[no_mangle]
-pub extern "C" fn gen_token_challenge(
-    issuer_name_cstr: *const i8,
-    origin_info_cstr: *const i8,
-) -> *const i8 {
+pub extern "C" fn gen_keys_with_info(token_type: u16, info_cstr: *const i8) -> *const i8 {
     // NOTE: the value of result below would not be *const i8
     //       if the begin_panic_handling and end_panic_handling macros where not there
     begin_panic_handling!();
     let result = panic::catch_unwind(|| {
-        let issuer_name_s = unsafe { decode_string_from_crystal(issuer_name_cstr)? };
-        let origin_info_s = unsafe { decode_string_from_crystal(origin_info_cstr)? };
-        let redemption_context: Option<RedemptionContext> = None;
-
-        let token_challenge: TokenChallenge = TokenChallenge::new(
-            GroupTokenType,
-            &issuer_name_s,
-            redemption_context,
-            &[origin_info_s],
-        );
-
-        let token_challenge_s = token_challenge.to_base64()?;
-        if VERBOSE {
-            println!("R: TokenChallenge: {:?}", token_challenge_s);
-        }
-
-        let rv = JSONRetVal {
-            retval: token_challenge_s,
-            error: "".to_string(),
-        };
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let info = unsafe { decode_info_from_crystal(info_cstr)? };
+        let out = gen_keys_for_token_type(token_type, &info, &[], KeyEncoding::default())?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -194,40 +659,22 @@ pub extern "C" fn gen_token_challenge(
     result
 }
 
+/// Like `gen_keys_with_info`, but returns the keys in `encoding` (see `KeyEncoding`: 0 for
+/// base64url as taken by the other FFI functions, 1 for hex). Covers the keys of every token
+/// type, `gen_keys_p384` and `gen_keys_rsa` included
 #[no_mangle]
-// NOTE: pass max_age = 0 for no max-age component in header
-pub extern "C" fn gen_www_authenticate_header(
-    token_challenge_c: *const i8,
-    token_key_c: *const i8,
-    max_age_u32: u32,
+pub extern "C" fn gen_keys_encoded(
+    token_type: u16,
+    info_cstr: *const i8,
+    encoding: u8,
 ) -> *const i8 {
     // NOTE: the value of result below would not be *const i8
     //       if the begin_panic_handling and end_panic_handling macros where not there
     begin_panic_handling!();
     let result = panic::catch_unwind(|| {
-        // parse inputs
-        let token_challenge_s = unsafe { decode_string_from_crystal(token_challenge_c)? };
-        let token_key_s = unsafe { decode_string_from_crystal(token_key_c)? };
-
-        // prepare WWW-Authenticate header value
-        let token_challenge = TokenChallenge::from_base64(&token_challenge_s)?;
-        let token_key_bytes: Vec<u8> = URL_SAFE.decode(token_key_s)?;
-        let max_age: Option<u32> = if max_age_u32 == 0 {
-            None
-        } else {
-            Some(max_age_u32)
-        };
-        let (_, www_authenticate_header) =
-            build_www_authenticate_header(&token_challenge, token_key_bytes.as_slice(), max_age)?;
-
-        // encode header value to pass to return
-        let www_authenticate_header_s = www_authenticate_header.to_str()?.to_string();
-        let rv = JSONRetVal {
-            retval: www_authenticate_header_s,
-            error: "".to_string(),
-        };
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let info = unsafe { decode_info_from_crystal(info_cstr)? };
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        let out = gen_keys_for_token_type(token_type, &info, &[], encoding)?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -236,62 +683,18 @@ pub extern "C" fn gen_www_authenticate_header(
     result
 }
 
+/// Like `gen_keys` for batched ristretto255, but re-derives the key until its truncated key id
+/// is not one of `taken_key_ids_cstr`, a JSON array of the truncated key ids of keys still in
+/// use
 #[no_mangle]
-pub extern "C" fn gen_token_response(
-    sk_cstr: *const i8,
-    token_request_cstr: *const i8,
-    max_nr: u16, // max number of BlindedElements that a client can send and get a response for
-) -> *const i8 {
+pub extern "C" fn gen_keys_avoiding(taken_key_ids_cstr: *const i8) -> *const i8 {
     // NOTE: the value of result below would not be *const i8
     //       if the begin_panic_handling and end_panic_handling macros where not there
     begin_panic_handling!();
     let result = panic::catch_unwind(|| {
-        let rt = tokio::runtime::Runtime::new()?;
-        let sk_s = unsafe { decode_string_from_crystal(sk_cstr)? };
-        let private_key = URL_SAFE.decode(sk_s.as_bytes())?;
-        let token_request_s = unsafe { decode_string_from_crystal(token_request_cstr)? };
-        let token_request_bytes = URL_SAFE.decode(token_request_s)?;
-
-        // parse token request
-        let mut token_request = TokenRequest::tls_deserialize(&mut token_request_bytes.as_slice())?;
-        let max_nr_usize = usize::from(max_nr);
-        if token_request.nr() > max_nr_usize {
-            let mut temp_token_request =
-                MyTokenRequest::tls_deserialize(&mut token_request_bytes.as_slice())?;
-            temp_token_request.truncate(max_nr_usize);
-            token_request = temp_token_request.to_token_request()?;
-            if VERBOSE {
-                println!(
-                    "R: TokenRequest was truncated to {:?} elements",
-                    token_request.nr()
-                );
-            }
-        }
-
-        let key_store = MemoryKeyStore::default();
-        let server = Server::new();
-        rt.block_on(async {
-            let _public_key = server.set_key(&key_store, &private_key).await?;
-            Ok::<PublicKey, Box<dyn std::error::Error>>(_public_key)
-        })?;
-
-        // generate token response
-        let token_response = rt.block_on(async {
-            let _token_response = server
-                .issue_token_response(&key_store, token_request)
-                .await?;
-            Ok::<TokenResponse, Box<dyn std::error::Error>>(_token_response)
-        })?;
-
-        let res_vec = token_response.tls_serialize_detached()?;
-
-        let rv = JSONRetVal {
-            retval: URL_SAFE.encode(res_vec),
-            error: "".to_string(),
-        };
-
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let taken_key_ids = unsafe { decode_taken_key_ids_from_crystal(taken_key_ids_cstr)? };
+        let out =
+            gen_keys_for_token_type(0, DEFAULT_KEY_INFO, &taken_key_ids, KeyEncoding::default())?;
 
         // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
@@ -300,233 +703,2094 @@ pub extern "C" fn gen_token_response(
     result
 }
 
+/// Like `gen_keys_avoiding`, but returns the keys in `encoding`, see `KeyEncoding`
 #[no_mangle]
-pub extern "C" fn validate_token(
-    sk_cstr: *const i8,
-    token_cstr: *const i8,
-    token_challenge_cstr: *const i8,
+pub extern "C" fn gen_keys_avoiding_encoded(
+    taken_key_ids_cstr: *const i8,
+    encoding: u8,
 ) -> *const i8 {
     // NOTE: the value of result below would not be *const i8
     //       if the begin_panic_handling and end_panic_handling macros where not there
     begin_panic_handling!();
     let result = panic::catch_unwind(|| {
-        // create tokio runtime
-        let rt = tokio::runtime::Runtime::new()?;
-
-        // parse inputs
-        let private_key = unsafe { decode_bytes_from_crystal(sk_cstr)? };
-        // let token_bytes = decode_bytes_from_crystal(token_cstr)?;
-        let token_s = unsafe { decode_string_from_crystal(token_cstr)? };
-        let token_s_2 = token_s.clone();
-        let token_bytes = URL_SAFE.decode(token_s)?;
-        let token_bytes_2 = token_bytes.clone();
-
-        // check we did get the right amount of bytes for a token
-        match token_bytes.len() == std::mem::size_of::<BatchedToken>() {
-            true => Ok(()),
-            false => Err(crystal_error("incorrect number of bytes for a token")),
-        }?;
-
-        // check we didn't get an alternative URL_SAFE encoding due to malleability of base64
-        // NOTE: may be overkill, dependingo n how URL_SAFE.decode is implemented
-        let token_s_prime = URL_SAFE.encode(token_bytes_2);
-        match token_s_2 == token_s_prime {
-            true => Ok(()),
-            false => Err(crystal_error("received alternative encoding of token")),
-        }?;
-
-        // token challenge for possible assert check (see below)
-        let token_challenge_s = unsafe { decode_string_from_crystal(token_challenge_cstr)? };
-        let token_challenge = TokenChallenge::from_base64(&token_challenge_s)?;
-        let challenge_digest = token_challenge.digest()?;
-
-        // load secret key
-        let key_store = MemoryKeyStore::default();
-        let server = Server::new();
-
-        // NOTE: this line loads the public key into the keystore.
-        // this allows correctly redeeming the token later on.
-        rt.block_on(async {
-            let _public_key = server.set_key(&key_store, &private_key).await?;
-            Ok::<PublicKey, Box<dyn std::error::Error>>(_public_key)
-        })?;
-
-        // the following is kind of a hack:
-        // it deals with tls_codec::Error giving a very uninformative error message
-        // and not working nice with the `anyhow` crate
-        let token: BatchedToken = match BatchedToken::tls_deserialize(&mut token_bytes.as_slice()) {
-            Ok(val) => Ok(val),
-            Err(_loll) => Err(crystal_error("failed to tls_deserialize token")),
-        }?;
-
-        // check challenge digest manually.
-        // NOTE: likely uneeded, happening within redeem_token via VOPRF evaluation
-        match token.challenge_digest() == challenge_digest.as_slice() {
-            true => Ok(()),
-            false => Err(crystal_error("direct TokenChallenge digest fails")),
-        }?;
-
-        // create empty nonce_store
-        // NOTE: To avoid double redemption of tokens, a nonce store should be
-        //       implemented somewhere. This can be done at Crystal level.
-        //       This nonce_store is required by the rust library, even if empty.
-        let nonce_store = MemoryNonceStore::default();
-
-        // verify token is valid
-        let valid = rt.block_on(async {
-            match server.redeem_token(&key_store, &nonce_store, token.clone())
-                .await {
-                Ok(_) => Ok::<bool, CrystalErrorType>(true),
-                Err(err) => match err {
-                    RedeemTokenError::InvalidToken => Ok::<bool, CrystalErrorType>(false),
-                    RedeemTokenError::DoubleSpending => Err(crystal_error("doubly spent token (should never hit this)")), // we just created an empty nonce_store, how did you hit this???
-                    RedeemTokenError::KeyIdNotFound => Err(crystal_error("key id not found")), // we just loaded the key, is the token for some key that just expired?
-                    _ => Err(crystal_error("unrecognized RedeemTokenError, was the privacypass-rust library updated with a new one?"))
-                }
-            }
-        })?;
-        let valid_s = match valid {
-            true => "1",
-            false => "0",
-        };
-
-        let rv = JSONRetVal {
-            retval: valid_s.to_string(),
-            error: "".to_string(),
-        };
-        let rv_s = serde_json::to_string(&rv)?;
-        let out = encode_string_for_crystal(rv_s)?;
+        let taken_key_ids = unsafe { decode_taken_key_ids_from_crystal(taken_key_ids_cstr)? };
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        let out = gen_keys_for_token_type(0, DEFAULT_KEY_INFO, &taken_key_ids, encoding)?;
 
+        // always end like this
         Ok::<*const i8, Box<dyn std::error::Error>>(out)
     });
     end_panic_handling!();
     result
 }
 
-pub struct PrivacyPass {}
+/// Like `gen_keys_avoiding_encoded`, for the batched `token_type` (0x0005 or batched P-384,
+/// 0 meaning 0x0005 as in `gen_keys`)
+#[no_mangle]
+pub extern "C" fn gen_keys_avoiding_for_token_type(
+    token_type: u16,
+    taken_key_ids_cstr: *const i8,
+    encoding: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let taken_key_ids = unsafe { decode_taken_key_ids_from_crystal(taken_key_ids_cstr)? };
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        let out = gen_keys_for_token_type(token_type, DEFAULT_KEY_INFO, &taken_key_ids, encoding)?;
 
-#[derive(Error, Debug)]
-#[allow(dead_code)]
-pub enum ValidateTokenError {
-    #[error("failed to serialize token challenge")]
-    Serialize(#[from] privacypass::auth::authenticate::SerializationError),
-    #[error("incorrect number of bytes ({0}) for a token")]
-    WrongTokenSize(usize),
-    #[error("failed to construct keypair")]
-    CreateKeypair(#[from] CreateKeypairError),
-    #[error("failed to deserialize token")]
-    TlsDeserialize(#[from] tls_codec::Error),
-    #[error("direct TokenChallenge digest fails")]
-    ChallengeDigest,
-    #[error("doubly spent token (should never hit this)")]
-    DoubleSpending,
-    #[error("key id not found")]
-    KeyIdNotFound,
-    #[error("failed to redeem token")]
-    RedeemToken(#[from] RedeemTokenError),
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
 }
 
-#[derive(Error, Debug)]
-pub enum GenKeysError {
-    #[error("failed to construct keypair")]
-    CreateKeypair(#[from] CreateKeypairError),
-    #[error("failed generating secret key")]
-    DeriveKey(voprf::Error),
+/// Decodes a JSON array of truncated key ids
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated string pointer.
+unsafe fn decode_taken_key_ids_from_crystal(
+    taken_key_ids_cstr: *const i8,
+) -> Result<Vec<TruncatedTokenKeyId>, Box<dyn std::error::Error>> {
+    let taken_key_ids_s = unsafe { decode_string_from_crystal(taken_key_ids_cstr)? };
+    match serde_json::from_str(&taken_key_ids_s) {
+        Ok(key_ids) => Ok(key_ids),
+        Err(_) => Err(crystal_error("expected a JSON array of truncated key ids").into()),
+    }
 }
 
-#[derive(Error, Debug)]
-pub enum GenTokenResponseError {
-    #[error("requested {0} tokens, max is {1}")]
-    RequestedTooManyTokens(usize, usize),
-    #[error("failed to construct keypair")]
-    CreateKeypair(#[from] CreateKeypairError),
-    #[error("failed to issue token response")]
-    IssueTokenResponse(#[from] IssueTokenResponseError),
+/// Derives a batched ristretto255 keypair from a (base64 encoded) seed of at least
+/// MIN_KEY_SEED_LEN bytes, e.g. one derived from a master secret held in an HSM or vault, so
+/// that the same seed and info string always give the same keypair.
+/// NOTE: pass a null `info_cstr` for DEFAULT_KEY_INFO
+#[no_mangle]
+pub extern "C" fn gen_keys_from_seed(seed_cstr: *const i8, info_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| unsafe {
+        gen_keys_from_seed_impl(seed_cstr, info_cstr, KeyEncoding::default())
+    });
+    end_panic_handling!();
+    result
 }
 
-#[derive(Debug)]
-pub struct RustKeypair {
-    pub public_key: Vec<u8>,
-    pub secret_key: [u8; 32],
-    pub token_type: TokenType,
+/// Like `gen_keys_from_seed`, but returns the keys in `encoding`, see `KeyEncoding`
+#[no_mangle]
+pub extern "C" fn gen_keys_from_seed_encoded(
+    seed_cstr: *const i8,
+    info_cstr: *const i8,
+    encoding: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let encoding = KeyEncoding::from_ffi(encoding)?;
+        unsafe { gen_keys_from_seed_impl(seed_cstr, info_cstr, encoding) }
+    });
+    end_panic_handling!();
+    result
 }
 
-impl PrivacyPass {
-    pub fn new() -> Self {
-        PrivacyPass {}
+/// Shared body of the gen_keys_from_seed FFI functions
+///
+/// # Safety
+///
+/// Callers must provide a valid NUL terminated seed string pointer, and either a null pointer
+/// or a valid NUL terminated info string pointer.
+unsafe fn gen_keys_from_seed_impl(
+    seed_cstr: *const i8,
+    info_cstr: *const i8,
+    encoding: KeyEncoding,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let seed = unsafe { decode_secret_bytes_from_crystal(seed_cstr, InputKind::Key)? };
+    if seed.expose_secret().len() < MIN_KEY_SEED_LEN {
+        Err(GenKeysError::SeedTooShort(seed.expose_secret().len()))?;
     }
+    let info = unsafe { decode_info_from_crystal(info_cstr)? };
+    ristretto255_keys_from_seed_for_crystal(seed.expose_secret(), &info, encoding)
+}
 
-    pub async fn validate_token(
-        &self,
-        token: &[u8],
-        private_key: &[u8],
-    ) -> Result<bool, ValidateTokenError> {
-        if token.len() != std::mem::size_of::<BatchedToken>() {
-            return Err(ValidateTokenError::WrongTokenSize(token.len()));
-        }
-
-        // Needed to make sure public key is in key store.
-        let server = Server::new();
-        let key_store = MemoryKeyStore::default();
-        let nonce_store = MemoryNonceStore::default();
-        let _pub_key = server.set_key(&key_store, private_key).await?;
+#[derive(Error, Debug)]
+pub enum RedemptionContextError {
+    #[error("malformed base64url redemption context")]
+    InvalidBase64(#[from] base64::DecodeError),
+    #[error("redemption context must be empty or 32 bytes, got {0}")]
+    InvalidLength(usize),
+    #[error("redemption context key too short ({0} < {MIN_REDEMPTION_CONTEXT_KEY_LEN} bytes)")]
+    KeyTooShort(usize),
+}
 
-        let tkn = token.to_vec();
-        let token = BatchedToken::tls_deserialize(&mut tkn.as_slice())?;
+/// Shortest key `derive_redemption_context` accepts
+pub const MIN_REDEMPTION_CONTEXT_KEY_LEN: usize = 32;
+
+/// Redemption context binding a challenge to `request_data`, e.g. a session id or the
+/// client's address and the request time: the HMAC-SHA256 of the data under `context_key`, a
+/// secret of the origin, so that origins can compute it again when the token is redeemed
+/// while clients and issuers, who see the challenge, can't brute force low entropy data back
+/// out of it
+pub fn derive_redemption_context(
+    context_key: &[u8],
+    request_data: &[u8],
+) -> Result<RedemptionContext, RedemptionContextError> {
+    if context_key.len() < MIN_REDEMPTION_CONTEXT_KEY_LEN {
+        return Err(RedemptionContextError::KeyTooShort(context_key.len()));
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(context_key)
+        .map_err(|_| RedemptionContextError::KeyTooShort(context_key.len()))?;
+    mac.update(request_data);
+    Ok(mac.finalize().into_bytes().into())
+}
 
-        match server
-            .redeem_token(&key_store, &nonce_store, token.clone())
-            .await
-        {
-            Ok(_) => Ok(true),
-            Err(err) => match err {
-                RedeemTokenError::InvalidToken => Ok(false),
-                RedeemTokenError::DoubleSpending => Err(ValidateTokenError::DoubleSpending), // we just created an empty nonce_store, how did you hit this???
-                RedeemTokenError::KeyIdNotFound => Err(ValidateTokenError::KeyIdNotFound), // we just loaded the key, is the token for some key that just expired?
-                e => Err(ValidateTokenError::RedeemToken(e)),
-            },
-        }
+/// Shared body of the gen_token_challenge FFI functions
+fn token_challenge_for_crystal(
+    issuer_name_s: &str,
+    origin_info_s: String,
+    redemption_context: Option<RedemptionContext>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let token_challenge: TokenChallenge = TokenChallenge::new(
+        GroupTokenType,
+        issuer_name_s,
+        redemption_context,
+        &[origin_info_s],
+    );
+
+    let token_challenge_s = token_challenge.to_base64()?;
+    if VERBOSE {
+        println!("R: TokenChallenge: {:?}", token_challenge_s);
     }
 
-    pub async fn gen_keys(&self) -> Result<RustKeypair, GenKeysError> {
-        // sample randomness for key generation
-        let mut seed = GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default();
+    let rv = JSONRetVal {
+        retval: token_challenge_s,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+#[no_mangle]
+pub extern "C" fn gen_token_challenge(
+    issuer_name_cstr: *const i8,
+    origin_info_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let issuer_name_s = unsafe { decode_string_from_crystal(issuer_name_cstr)? };
+        let origin_info_s = unsafe { decode_string_from_crystal(origin_info_cstr)? };
+        let out = token_challenge_for_crystal(&issuer_name_s, origin_info_s, None)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+// NOTE: pass an empty redemption context for none, i.e. what gen_token_challenge does
+pub extern "C" fn gen_token_challenge_with_context(
+    issuer_name_cstr: *const i8,
+    origin_info_cstr: *const i8,
+    redemption_context_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let issuer_name_s = unsafe { decode_string_from_crystal(issuer_name_cstr)? };
+        let origin_info_s = unsafe { decode_string_from_crystal(origin_info_cstr)? };
+        let redemption_context_s = unsafe {
+            borrow_untrusted_str_from_crystal(redemption_context_cstr, InputKind::TokenChallenge)?
+        };
+        let redemption_context = match URL_SAFE
+            .decode(redemption_context_s)
+            .map_err(RedemptionContextError::from)?
+        {
+            context if context.is_empty() => None,
+            context => Some(
+                RedemptionContext::try_from(context.as_slice())
+                    .map_err(|_| RedemptionContextError::InvalidLength(context.len()))?,
+            ),
+        };
+        let out = token_challenge_for_crystal(&issuer_name_s, origin_info_s, redemption_context)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Base64 redemption context of the request data string under the (base64 encoded) secret
+/// key of the origin, see `derive_redemption_context`
+#[no_mangle]
+pub extern "C" fn gen_redemption_context(
+    context_key_cstr: *const i8,
+    request_data_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let context_key =
+            unsafe { decode_secret_bytes_from_crystal(context_key_cstr, InputKind::Key)? };
+        let request_data =
+            unsafe { borrow_untrusted_str_from_crystal(request_data_cstr, InputKind::Header)? };
+        let redemption_context =
+            derive_redemption_context(context_key.expose_secret(), request_data.as_bytes())?;
+        let rv = JSONRetVal {
+            retval: URL_SAFE.encode(redemption_context),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+// NOTE: pass max_age = 0 for no max-age component in header
+pub extern "C" fn gen_www_authenticate_header(
+    token_challenge_c: *const i8,
+    token_key_c: *const i8,
+    max_age_u32: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        // parse inputs
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_c, InputKind::TokenChallenge)?
+        };
+        let token_key_bytes =
+            unsafe { decode_untrusted_bytes_from_crystal(token_key_c, InputKind::Key)? };
+        let out = www_authenticate_header_for_crystal(
+            token_challenge_s,
+            token_key_bytes.as_slice(),
+            max_age_u32,
+        )?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the gen_www_authenticate_header FFI functions
+pub(crate) fn www_authenticate_header_for_crystal(
+    token_challenge_s: &str,
+    token_key_bytes: &[u8],
+    max_age_u32: u32,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    // prepare WWW-Authenticate header value
+    let token_challenge = TokenChallenge::from_base64(token_challenge_s)?;
+    let max_age: Option<u32> = if max_age_u32 == 0 {
+        None
+    } else {
+        Some(max_age_u32)
+    };
+    let (_, www_authenticate_header) =
+        build_www_authenticate_header(&token_challenge, token_key_bytes, max_age)?;
+
+    // encode header value to pass to return
+    let www_authenticate_header_s = www_authenticate_header.to_str()?.to_string();
+    let rv = JSONRetVal {
+        retval: www_authenticate_header_s,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// One challenge of a WWW-Authenticate header value, with the arguments
+/// `gen_www_authenticate_header` built it from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WwwAuthenticateChallenge {
+    /// base64 TokenChallenge
+    pub token_challenge: String,
+    /// base64 serialized issuer public key
+    pub token_key: String,
+    /// None if the header sets no max-age
+    #[serde(default)]
+    pub max_age: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseWwwAuthenticateError {
+    #[error("malformed WWW-Authenticate header")]
+    InvalidHeader,
+    #[error("invalid token challenge")]
+    InvalidChallenge,
+    #[error("max-age {0} does not fit in 32 bits")]
+    MaxAge(usize),
+}
+
+/// Decomposes a `WWW-Authenticate: PrivateToken challenge=..., token-key=...[, max-age=...]`
+/// header value, e.g. one of `gen_www_authenticate_header`, into its challenges, in header
+/// order, so that origins can check what they served and tests can start from the header
+pub fn parse_www_authenticate_challenges(
+    header: &str,
+) -> Result<Vec<WwwAuthenticateChallenge>, ParseWwwAuthenticateError> {
+    let header_value =
+        HeaderValue::from_str(header).map_err(|_| ParseWwwAuthenticateError::InvalidHeader)?;
+    parse_www_authenticate_header(&header_value)
+        .map_err(|_| ParseWwwAuthenticateError::InvalidHeader)?
+        .iter()
+        .map(|challenge| {
+            let max_age = challenge
+                .max_age()
+                .map(|max_age| {
+                    u32::try_from(max_age).map_err(|_| ParseWwwAuthenticateError::MaxAge(max_age))
+                })
+                .transpose()?;
+            Ok(WwwAuthenticateChallenge {
+                token_challenge: challenge
+                    .token_challenge()
+                    .to_base64()
+                    .map_err(|_| ParseWwwAuthenticateError::InvalidChallenge)?,
+                token_key: URL_SAFE.encode(challenge.token_key()),
+                max_age,
+            })
+        })
+        .collect()
+}
+
+#[derive(Error, Debug)]
+pub enum BuildWwwAuthenticateError {
+    #[error("no challenge to build a WWW-Authenticate header of")]
+    NoChallenge,
+    #[error("invalid token challenge")]
+    InvalidChallenge,
+    #[error("malformed base64url token key")]
+    InvalidTokenKey(#[from] base64::DecodeError),
+    #[error("WWW-Authenticate header is not valid ASCII")]
+    InvalidHeader,
+}
+
+/// Single WWW-Authenticate header value presenting all of `challenges`, in order, e.g. tokens
+/// of several token types or issuers, as RFC 9577 allows. Values are those
+/// `parse_www_authenticate_challenges` returns.
+pub fn build_www_authenticate_header_multi(
+    challenges: &[WwwAuthenticateChallenge],
+) -> Result<HeaderValue, BuildWwwAuthenticateError> {
+    if challenges.is_empty() {
+        return Err(BuildWwwAuthenticateError::NoChallenge);
+    }
+    let values = challenges
+        .iter()
+        .map(|challenge| {
+            let token_challenge = TokenChallenge::from_base64(&challenge.token_challenge)
+                .map_err(|_| BuildWwwAuthenticateError::InvalidChallenge)?;
+            let token_key = URL_SAFE.decode(&challenge.token_key)?;
+            let (_, value) =
+                build_www_authenticate_header(&token_challenge, &token_key, challenge.max_age)
+                    .map_err(|_| BuildWwwAuthenticateError::InvalidChallenge)?;
+            value
+                .to_str()
+                .map(str::to_string)
+                .map_err(|_| BuildWwwAuthenticateError::InvalidHeader)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    HeaderValue::from_str(&values.join(", ")).map_err(|_| BuildWwwAuthenticateError::InvalidHeader)
+}
+
+/// Like `gen_www_authenticate_header`, for a JSON array of challenges
+/// `[{"token_challenge": "<base64>", "token_key": "<base64>", "max_age": 60}, ...]`, `max_age`
+/// being null or left out for no max-age, see `build_www_authenticate_header_multi`
+#[no_mangle]
+pub extern "C" fn gen_www_authenticate_header_multi(challenges_json_c: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let challenges_json =
+            unsafe { borrow_untrusted_str_from_crystal(challenges_json_c, InputKind::Header)? };
+        let challenges: Vec<WwwAuthenticateChallenge> = serde_json::from_str(challenges_json)?;
+        let www_authenticate_header = build_www_authenticate_header_multi(&challenges)?;
+        let rv = JSONRetVal {
+            retval: www_authenticate_header.to_str()?.to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Inverse of `gen_www_authenticate_header`: the JSON array of the challenges of a header
+/// value, see `parse_www_authenticate_challenges`
+#[no_mangle]
+pub extern "C" fn decode_www_authenticate_header(
+    www_authenticate_header_c: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let www_authenticate_header_s = unsafe {
+            borrow_untrusted_str_from_crystal(www_authenticate_header_c, InputKind::Header)?
+        };
+        let challenges = parse_www_authenticate_challenges(www_authenticate_header_s)?;
+        let rv = JSONRetVal {
+            retval: serde_json::to_string(&challenges)?,
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn gen_token_response(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+    max_nr: u16, // max number of BlindedElements that a client can send and get a response for
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| unsafe {
+        gen_token_response_impl(
+            sk_cstr,
+            token_request_cstr,
+            max_nr,
+            OverLimitPolicy::Truncate,
+            None,
+        )
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+// NOTE: issuance is abandoned if not completed within timeout_ms milliseconds from the call,
+//       pass timeout_ms = 0 for no deadline
+pub extern "C" fn gen_token_response_with_deadline(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    timeout_ms: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let deadline = match timeout_ms {
+            0 => None,
+            _ => Some(Instant::now() + Duration::from_millis(u64::from(timeout_ms))),
+        };
+        unsafe {
+            gen_token_response_impl(
+                sk_cstr,
+                token_request_cstr,
+                max_nr,
+                OverLimitPolicy::Truncate,
+                deadline,
+            )
+        }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_response`, but with `over_limit_policy` (see `OverLimitPolicy`: 0 to
+/// truncate, 1 to reject, 2 to issue none) deciding what happens to TokenRequests asking for
+/// more than `max_nr` tokens
+#[no_mangle]
+pub extern "C" fn gen_token_response_with_policy(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    over_limit_policy: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let over_limit = OverLimitPolicy::from_ffi(over_limit_policy)?;
+        unsafe { gen_token_response_impl(sk_cstr, token_request_cstr, max_nr, over_limit, None) }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Rejects TokenRequests repeating a blinded element (the default), or with `deduplicate`
+/// issues for the first occurrence of each blinded element only, see `DuplicateElementPolicy`
+#[no_mangle]
+pub extern "C" fn set_duplicate_element_handling(deduplicate: bool) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        set_duplicate_element_policy(match deduplicate {
+            true => DuplicateElementPolicy::Deduplicate,
+            false => DuplicateElementPolicy::Reject,
+        });
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_response`, but instead of truncating TokenRequests asking for more than
+/// `max_nr` tokens, issues for every `max_nr` blinded elements in turn. Requests asking for
+/// more than `max_total` tokens are refused. Returns the JSON array of the base64
+/// TokenResponses, in request order, see `PrivacyPass::gen_token_response_chunks`
+#[no_mangle]
+pub extern "C" fn gen_token_response_chunks(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    max_total: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let token_request_bytes = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let out =
+            token_response_chunks_for_crystal(&token_request_bytes, max_nr, max_total, |chunk| {
+                issue_token_response_sync(private_key.expose_secret(), chunk)
+            })?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the gen_token_response_chunks FFI functions, `issue` issuing for each chunk
+pub(crate) fn token_response_chunks_for_crystal(
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    max_total: u16,
+    issue: impl Fn(&TokenRequestView) -> Result<TokenResponse, GenTokenResponseError>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    match wire_token_type(token_request_bytes) {
+        Some(token_type) if token_type != GroupTokenType as u16 => {
+            Err(UnsupportedTokenTypeError(token_type))?
+        }
+        _ => {}
+    }
+    let token_request = MyTokenRequest::tls_deserialize(&mut &token_request_bytes[..])?;
+    let token_responses = issue_token_response_chunks_with(
+        token_request,
+        usize::from(max_nr),
+        usize::from(max_total),
+        issue,
+    )?
+    .iter()
+    .map(TlsSerializeTrait::tls_serialize_detached)
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let rv = JSONRetVal {
+        retval: serde_json::to_string(
+            &token_responses
+                .iter()
+                .map(|token_response| Base64Json(token_response))
+                .collect::<Vec<_>>(),
+        )?,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Outcome of one TokenRequest of `gen_token_responses`, laid out like the return value of
+/// `gen_token_response` for it would be
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TokenResponseOutcome<'a> {
+    Issued(TokenResponseRetVal<'a>),
+    Failed(JSONErrorRetVal),
+}
+
+/// Issues TokenResponses for a JSON array of base64 TokenRequests, loading the key once for
+/// all of them. Returns the JSON array of their outcomes, in request order: for each, either
+/// {"retval":"<TokenResponse>","error":"","dropped":0} or the error it failed with, as
+/// returned by `gen_token_response`. Only batched tokens of the ristretto255 group are issued.
+#[no_mangle]
+pub extern "C" fn gen_token_responses(
+    sk_cstr: *const i8,
+    token_requests_cstr: *const i8,
+    max_nr: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let token_requests_json = unsafe {
+            borrow_untrusted_str_from_crystal(token_requests_cstr, InputKind::TokenRequestBatch)?
+        };
+        let loaded_key = LoadedKey::load(private_key.expose_secret())?;
+        let out = token_responses_for_crystal(token_requests_json, |token_request_bytes| {
+            issue_serialized_token_response(
+                private_key.expose_secret(),
+                Some(&loaded_key),
+                token_request_bytes,
+                max_nr,
+                None,
+            )
+        })?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the gen_token_responses FFI functions, `issue` issuing for each serialized
+/// TokenRequest of the JSON array `token_requests_json`
+pub(crate) fn token_responses_for_crystal(
+    token_requests_json: &str,
+    issue: impl Fn(&[u8]) -> Result<IssuedTokenResponse, Box<dyn std::error::Error>>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let token_requests: Vec<&str> = serde_json::from_str(token_requests_json)?;
+    let issued: Vec<Result<IssuedTokenResponse, Box<dyn std::error::Error>>> = token_requests
+        .iter()
+        .map(|token_request| {
+            // each request is still held to the limit of a single TokenRequest
+            check_input_len(InputKind::TokenRequest, token_request.len())?;
+            let token_request_bytes = URL_SAFE.decode(token_request)?;
+            match wire_token_type(&token_request_bytes) {
+                Some(token_type) if token_type != GroupTokenType as u16 => {
+                    Err(UnsupportedTokenTypeError(token_type))?
+                }
+                _ => {}
+            }
+            issue(&token_request_bytes)
+        })
+        .collect();
+    let outcomes: Vec<TokenResponseOutcome> = issued
+        .iter()
+        .map(|issued| match issued {
+            Ok(issued) => TokenResponseOutcome::Issued(issued.to_retval()),
+            Err(err) => TokenResponseOutcome::Failed(JSONErrorRetVal::from_error(err.as_ref())),
+        })
+        .collect();
+
+    let rv = JSONRetVal {
+        retval: serde_json::to_string(&outcomes)?,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Token type a serialized token or token request starts with
+fn wire_token_type(bytes: &[u8]) -> Option<u16> {
+    bytes
+        .first_chunk::<2>()
+        .map(|token_type| u16::from_be_bytes(*token_type))
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown over-limit policy {0}")]
+pub struct UnknownOverLimitPolicyError(pub u8);
+
+/// What issuance does with TokenRequests asking for more than `max_nr` tokens, numbered as
+/// over FFI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverLimitPolicy {
+    /// issue for the first `max_nr` blinded elements, dropping the others
+    #[default]
+    Truncate,
+    /// refuse the request with a RequestedTooManyTokens error
+    Reject,
+    /// answer with an empty retval instead of a token response, dropping every blinded element
+    IssueNone,
+}
+
+impl OverLimitPolicy {
+    pub fn from_ffi(policy: u8) -> Result<Self, UnknownOverLimitPolicyError> {
+        match policy {
+            0 => Ok(OverLimitPolicy::Truncate),
+            1 => Ok(OverLimitPolicy::Reject),
+            2 => Ok(OverLimitPolicy::IssueNone),
+            _ => Err(UnknownOverLimitPolicyError(policy)),
+        }
+    }
+}
+
+/// Shared body of the gen_token_response FFI functions
+///
+/// # Safety
+///
+/// Callers must provide valid NUL terminated string pointers.
+unsafe fn gen_token_response_impl(
+    sk_cstr: *const i8,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    over_limit: OverLimitPolicy,
+    deadline: Option<Instant>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let _timer = LatencyTimer::start(Operation::Issuance);
+    let rt = ffi_runtime()?;
+    let token_request_bytes = unsafe {
+        decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+    };
+    let key_kind = InputKind::key_of(wire_token_type(&token_request_bytes));
+    let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, key_kind)? };
+    if let Some(out) = over_limit_response(&token_request_bytes, max_nr, over_limit)? {
+        return Ok(out);
+    }
+    // route on the token type of the request, too short requests are rejected below
+    match wire_token_type(&token_request_bytes) {
+        Some(token_type) if token_type == GroupTokenType as u16 => {}
+        Some(TOKEN_TYPE_PRIVATE_P384) => {
+            check_deadline(deadline, None)?;
+            return crate::private_tokens::issue_for_crystal(
+                rt,
+                private_key.expose_secret(),
+                &token_request_bytes,
+                max_nr,
+            );
+        }
+        Some(TOKEN_TYPE_PUBLIC_RSA) => {
+            check_deadline(deadline, None)?;
+            return crate::public_tokens::issue_for_crystal(
+                private_key.expose_secret(),
+                &token_request_bytes,
+                max_nr,
+            );
+        }
+        Some(token_type) if token_type == BatchedP384TokenType as u16 => {
+            check_deadline(deadline, None)?;
+            return crate::batched_p384::issue_for_crystal(
+                rt,
+                private_key.expose_secret(),
+                token_request_bytes,
+                max_nr,
+            );
+        }
+        Some(token_type) => Err(UnsupportedTokenTypeError(token_type))?,
+        None => {}
+    }
+    issue_for_crystal(
+        private_key.expose_secret(),
+        None,
+        &token_request_bytes,
+        max_nr,
+        deadline,
+    )
+}
+
+/// Applies `over_limit` to TokenRequests asking for more than `max_nr` tokens, returning what
+/// to answer instead of issuing, if anything
+pub(crate) fn over_limit_response(
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    over_limit: OverLimitPolicy,
+) -> Result<Option<*const i8>, Box<dyn std::error::Error>> {
+    // truncation is left to issuance, malformed requests are rejected there too
+    let requested_nr = TokenRequestInfo::from_token_request(token_request_bytes)
+        .map_or(0, |token_request_info| token_request_info.nr);
+    if requested_nr <= usize::from(max_nr) {
+        return Ok(None);
+    }
+    match over_limit {
+        OverLimitPolicy::Truncate => Ok(None),
+        OverLimitPolicy::Reject => Err(GenTokenResponseError::RequestedTooManyTokens(
+            requested_nr,
+            usize::from(max_nr),
+        ))?,
+        OverLimitPolicy::IssueNone => {
+            Ok(Some(token_response_for_crystal(&[], requested_nr, None)?))
+        }
+    }
+}
+
+/// Issuer key loaded once for batched issuance, e.g. for all the requests of a batch
+pub(crate) struct LoadedKey {
+    server: VoprfServer<VoprfGroup>,
+}
+
+impl LoadedKey {
+    pub(crate) fn load(private_key: &[u8]) -> Result<Self, GenTokenResponseError> {
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+            .map_err(GenTokenResponseError::InvalidKey)?;
+        Ok(LoadedKey { server })
+    }
+
+    pub(crate) fn public_key(&self) -> PublicKey {
+        self.server.get_public_key()
+    }
+
+    pub(crate) fn voprf_server(&self) -> &VoprfServer<VoprfGroup> {
+        &self.server
+    }
+}
+
+/// Issues a TokenResponse for the serialized `token_request_bytes`, returning it encoded for
+/// Crystal. `loaded_key` is installed from `private_key` on the spot if not given.
+pub(crate) fn issue_for_crystal(
+    private_key: &[u8],
+    loaded_key: Option<&LoadedKey>,
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    deadline: Option<Instant>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let issued = issue_serialized_token_response(
+        private_key,
+        loaded_key,
+        token_request_bytes,
+        max_nr,
+        deadline,
+    )?;
+    Ok(encode_json_for_crystal(&issued.to_retval())?)
+}
+
+/// Serialized TokenResponse, along with what it leaves unanswered of its TokenRequest
+pub(crate) struct IssuedTokenResponse {
+    pub token_response: Vec<u8>,
+    /// number of blinded elements left unanswered
+    pub dropped: usize,
+    /// indices of the blinded elements answered, when repeated ones were dropped
+    pub kept: Option<Vec<usize>>,
+}
+
+impl IssuedTokenResponse {
+    pub(crate) fn to_retval(&self) -> TokenResponseRetVal<'_> {
+        TokenResponseRetVal {
+            retval: Base64Json(&self.token_response),
+            error: "",
+            dropped: self.dropped,
+            kept: self.kept.as_deref(),
+        }
+    }
+}
+
+/// Body of `issue_for_crystal`
+pub(crate) fn issue_serialized_token_response(
+    private_key: &[u8],
+    loaded_key: Option<&LoadedKey>,
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    deadline: Option<Instant>,
+) -> Result<IssuedTokenResponse, Box<dyn std::error::Error>> {
+    let (token_request_view, requested_nr) = parse_token_request(token_request_bytes, max_nr)?;
+    let deduplicated_request =
+        deduplicated_token_request(&token_request_view, duplicate_element_policy())?;
+    let token_request_view = match &deduplicated_request {
+        Some(deduplicated_request) => {
+            TokenRequestView::try_from_bytes(&deduplicated_request.bytes)?
+        }
+        None => token_request_view,
+    };
+    let dropped = requested_nr - token_request_view.nr();
+    let kept = deduplicated_request
+        .as_ref()
+        .map(|deduplicated_request| deduplicated_request.kept.clone());
+
+    // fast path for single element requests, skipping the key store and batch machinery
+    if token_request_view.nr() == 1 {
+        check_deadline(deadline, None)?;
+        let token_response = issue_single_token_response(private_key, &token_request_view)?;
+        return Ok(IssuedTokenResponse {
+            token_response: token_response.tls_serialize_detached()?,
+            dropped,
+            kept,
+        });
+    }
+
+    check_deadline(deadline, None)?;
+    if token_request_view.token_type() != GroupTokenType {
+        Err(GenTokenResponseError::InvalidTokenType)?;
+    }
+    let blinded_elements = deserialize_blinded_elements(&token_request_view)?;
+
+    let installed_key;
+    let loaded_key = match loaded_key {
+        Some(loaded_key) => loaded_key,
+        None => {
+            installed_key = LoadedKey::load(private_key)?;
+            &installed_key
+        }
+    };
+    let token_key_id = public_key_to_token_key_id(loaded_key.public_key());
+    let [.., truncated_token_key_id] = token_key_id;
+    if truncated_token_key_id != token_request_view.truncated_token_key_id() {
+        Err(UnknownKeyIdError(
+            token_request_view.truncated_token_key_id(),
+        ))?;
+    }
+    check_key::<GenTokenResponseError>(&token_key_id, KeyUse::Issuance)?;
+
+    // generate token response
+    check_deadline(deadline, None)?;
+    let token_response = evaluate_blinded_elements(loaded_key.voprf_server(), &blinded_elements)?;
+
+    Ok(IssuedTokenResponse {
+        token_response: token_response.tls_serialize_detached()?,
+        dropped,
+        kept,
+    })
+}
+
+/// Like `issue_for_crystal`, having `signer` evaluate the blinded elements
+pub(crate) fn issue_with_signer_for_crystal<S: VoprfSigner + ?Sized>(
+    signer: &S,
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    deadline: Option<Instant>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let issued = issue_serialized_with_signer(signer, token_request_bytes, max_nr, deadline)?;
+    Ok(encode_json_for_crystal(&issued.to_retval())?)
+}
+
+/// Body of `issue_with_signer_for_crystal`
+pub(crate) fn issue_serialized_with_signer<S: VoprfSigner + ?Sized>(
+    signer: &S,
+    token_request_bytes: &[u8],
+    max_nr: u16,
+    deadline: Option<Instant>,
+) -> Result<IssuedTokenResponse, Box<dyn std::error::Error>> {
+    let (token_request_view, requested_nr) = parse_token_request(token_request_bytes, max_nr)?;
+    let deduplicated_request =
+        deduplicated_token_request(&token_request_view, duplicate_element_policy())?;
+    let token_request_view = match &deduplicated_request {
+        Some(deduplicated_request) => {
+            TokenRequestView::try_from_bytes(&deduplicated_request.bytes)?
+        }
+        None => token_request_view,
+    };
+    let dropped = requested_nr - token_request_view.nr();
+    let kept = deduplicated_request
+        .as_ref()
+        .map(|deduplicated_request| deduplicated_request.kept.clone());
+    check_deadline(deadline, None)?;
+    let token_response = issue_token_response_with_signer(signer, &token_request_view)?;
+
+    Ok(IssuedTokenResponse {
+        token_response: token_response.tls_serialize_detached()?,
+        dropped,
+        kept,
+    })
+}
+
+/// Issues a TokenResponse for each chunk of at most `max_nr` blinded elements of
+/// `token_request`, in order, instead of truncating it, refusing requests of more than
+/// `max_total` elements. Repeated blinded elements are handled across the whole request, as
+/// set with `set_duplicate_element_policy`.
+pub(crate) fn issue_token_response_chunks(
+    private_key: &[u8],
+    token_request: MyTokenRequest,
+    max_nr: usize,
+    max_total: usize,
+) -> Result<Vec<TokenResponse>, GenTokenResponseError> {
+    issue_token_response_chunks_with(token_request, max_nr, max_total, |chunk| {
+        issue_token_response_sync(private_key, chunk)
+    })
+}
+
+/// Like `issue_token_response_chunks`, `issue` issuing for each chunk
+fn issue_token_response_chunks_with(
+    token_request: MyTokenRequest,
+    max_nr: usize,
+    max_total: usize,
+    issue: impl Fn(&TokenRequestView) -> Result<TokenResponse, GenTokenResponseError>,
+) -> Result<Vec<TokenResponse>, GenTokenResponseError> {
+    if token_request.nr() > max_total {
+        return Err(GenTokenResponseError::RequestedTooManyTokens(
+            token_request.nr(),
+            max_total,
+        ));
+    }
+    if max_nr == 0 && token_request.nr() > 0 {
+        return Err(GenTokenResponseError::RequestedTooManyTokens(
+            token_request.nr(),
+            max_nr,
+        ));
+    }
+    let token_request_bytes = token_request
+        .tls_serialize_detached()
+        .map_err(GenTokenResponseError::Tls)?;
+    let token_request_view = TokenRequestView::try_from_bytes(&token_request_bytes)
+        .map_err(GenTokenResponseError::Tls)?;
+    let deduplicated_request =
+        deduplicated_token_request(&token_request_view, duplicate_element_policy())?;
+    let token_request = match deduplicated_request {
+        Some(deduplicated_request) => {
+            MyTokenRequest::tls_deserialize(&mut deduplicated_request.bytes.as_slice())
+                .map_err(GenTokenResponseError::Tls)?
+        }
+        None => token_request,
+    };
+
+    token_request
+        .split(max_nr)
+        .iter()
+        .map(|chunk| {
+            let chunk_bytes = chunk
+                .tls_serialize_detached()
+                .map_err(GenTokenResponseError::Tls)?;
+            let chunk = TokenRequestView::try_from_bytes(&chunk_bytes)
+                .map_err(GenTokenResponseError::Tls)?;
+            issue(&chunk)
+        })
+        .collect()
+}
+
+/// Return value of the gen_token_response FFI functions: the base64 TokenResponse, along with
+/// the number of blinded elements of the request it leaves unanswered and, when repeated ones
+/// were dropped, the indices of those it answers, in order
+#[derive(Serialize)]
+pub(crate) struct TokenResponseRetVal<'a> {
+    pub retval: Base64Json<'a>,
+    pub error: &'a str,
+    pub dropped: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kept: Option<&'a [usize]>,
+}
+
+/// Encodes the serialized `token_response` for Crystal, see `TokenResponseRetVal`
+pub(crate) fn token_response_for_crystal(
+    token_response: &[u8],
+    dropped: usize,
+    kept: Option<&[usize]>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let rv = TokenResponseRetVal {
+        retval: Base64Json(token_response),
+        error: "",
+        dropped,
+        kept,
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Parses a TokenRequest, borrowing the blinded elements from `token_request_bytes`, and
+/// truncates it to `max_nr` elements. Returns the number of elements requested, before
+/// truncation.
+fn parse_token_request(
+    token_request_bytes: &[u8],
+    max_nr: u16,
+) -> Result<(TokenRequestView, usize), tls_codec::Error> {
+    let mut token_request_view = TokenRequestView::try_from_bytes(token_request_bytes)?;
+    let requested_nr = token_request_view.nr();
+    let max_nr_usize = usize::from(max_nr);
+    if requested_nr > max_nr_usize {
+        token_request_view.truncate(max_nr_usize);
+        if VERBOSE {
+            println!(
+                "R: TokenRequest was truncated to {:?} elements",
+                token_request_view.nr()
+            );
+        }
+    }
+    Ok((token_request_view, requested_nr))
+}
+
+/// Errors out if `deadline` has passed or `cancellation` was cancelled
+fn check_deadline(
+    deadline: Option<Instant>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<(), GenTokenResponseError> {
+    if cancellation.is_some_and(|token| token.is_cancelled()) {
+        return Err(GenTokenResponseError::Cancelled);
+    }
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(GenTokenResponseError::DeadlineExceeded)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PrevalidateTokenError {
+    #[error("token is not canonical base64url")]
+    NonCanonicalEncoding,
+    #[error("token is too short to carry a token type")]
+    Truncated,
+    #[error("unsupported token type {0:#06x}")]
+    UnsupportedTokenType(u16),
+    #[error("token of type {token_type:#06x} is {len} bytes long, expected {expected}")]
+    WrongLength {
+        token_type: u16,
+        len: usize,
+        expected: usize,
+    },
+}
+
+impl PrevalidateTokenError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            PrevalidateTokenError::NonCanonicalEncoding => "token_non_canonical_encoding",
+            PrevalidateTokenError::Truncated => "token_truncated",
+            PrevalidateTokenError::UnsupportedTokenType(_) => "unsupported_token_type",
+            PrevalidateTokenError::WrongLength { .. } => "token_wrong_length",
+        }
+    }
+}
+
+/// Checks the structure of a (base64) token without a key: canonical encoding, a token type
+/// tokens are issued for, and the length of that token type, returning the token type.
+/// Front-end layers can drop tokens failing this before loading keys or touching the nonce
+/// store, passing the token doesn't make it valid.
+pub fn prevalidate_encoded_token(token_encoded: &[u8]) -> Result<u16, PrevalidateTokenError> {
+    let token = URL_SAFE
+        .decode(token_encoded)
+        .ok()
+        .filter(|token| URL_SAFE.encode(token).as_bytes() == token_encoded)
+        .ok_or(PrevalidateTokenError::NonCanonicalEncoding)?;
+    let token_type = wire_token_type(&token).ok_or(PrevalidateTokenError::Truncated)?;
+    match Token::parse(&token) {
+        Ok(token) => Ok(token.token_type),
+        Err(ParseError::UnknownTokenType(token_type)) => {
+            Err(PrevalidateTokenError::UnsupportedTokenType(token_type))
+        }
+        // the token type is known, so only the length can be off
+        Err(_) => Err(PrevalidateTokenError::WrongLength {
+            token_type,
+            len: token.len(),
+            expected: TOKEN_INPUT_LEN + authenticator_len(token_type).unwrap_or_default(),
+        }),
+    }
+}
+
+/// Returns "1" if the (base64) token passes `prevalidate_encoded_token`, and an error whose
+/// code tells why otherwise. It takes no key and doesn't touch the nonce store.
+#[no_mangle]
+pub extern "C" fn prevalidate_token(token_cstr: *const i8) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_encoded =
+            unsafe { borrow_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        prevalidate_encoded_token(token_encoded)?;
+
+        let rv = JSONRetVal {
+            retval: "1".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn validate_token(
+    sk_cstr: *const i8,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+
+        // parse inputs
+        let token_encoded =
+            unsafe { borrow_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+
+        // token challenge, whose digest the token must carry
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+
+        // route on the token type of the token, read from its first base64 quantum.
+        // Malformed tokens take the ristretto255 path, which rejects them uniformly.
+        let token_type = token_encoded
+            .get(..4)
+            .and_then(|quantum| URL_SAFE.decode(quantum).ok())
+            .and_then(|prefix| wire_token_type(&prefix));
+        let key_kind = InputKind::key_of(token_type);
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, key_kind)? };
+        match token_type {
+            Some(token_type) if token_type == GroupTokenType as u16 => {}
+            Some(TOKEN_TYPE_PRIVATE_P384) => {
+                let token = URL_SAFE.decode(token_encoded).unwrap_or_default();
+                return crate::private_tokens::validate_token_for_crystal(
+                    private_key.expose_secret(),
+                    &token,
+                    token_challenge_s,
+                );
+            }
+            Some(TOKEN_TYPE_PUBLIC_RSA) => {
+                let token = URL_SAFE.decode(token_encoded).unwrap_or_default();
+                let public_key =
+                    crate::public_tokens::secret_key_to_public_key(private_key.expose_secret())?;
+                return crate::public_tokens::validate_token_for_crystal(
+                    &public_key,
+                    &token,
+                    token_challenge_s,
+                );
+            }
+            Some(token_type) if token_type == BatchedP384TokenType as u16 => {
+                return crate::batched_p384::validate_token_for_crystal(
+                    private_key.expose_secret(),
+                    token_encoded,
+                    token_challenge_s,
+                );
+            }
+            Some(token_type) => Err(UnsupportedTokenTypeError(token_type))?,
+            None => {}
+        }
+
+        // load secret key
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key.expose_secret())
+            .map_err(|_| crystal_error("failed to load secret key"))?;
+        validate_token_for_crystal(&server, token_encoded, token_challenge_s)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_token`, but for origins accepting tokens of several keys, e.g. the current
+/// and previous ones mid-rotation: `sks_cstr` is a JSON array of (base64 encoded) secret keys,
+/// and the token is redeemed against the one whose key id it carries.
+/// NOTE: only ristretto255 batched tokens are accepted, other token types are unsupported
+#[no_mangle]
+pub extern "C" fn validate_token_multi(
+    sks_cstr: *const i8,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+
+        // parse inputs
+        let private_keys =
+            unsafe { decode_secret_bytes_array_from_crystal(sks_cstr, InputKind::Key)? };
+        let token_encoded =
+            unsafe { borrow_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+
+        // Malformed tokens take the ristretto255 path, which rejects them uniformly
+        let token_type = token_encoded
+            .get(..4)
+            .and_then(|quantum| URL_SAFE.decode(quantum).ok())
+            .and_then(|prefix| wire_token_type(&prefix));
+        match token_type {
+            Some(token_type) if token_type != GroupTokenType as u16 => {
+                Err(UnsupportedTokenTypeError(token_type))?
+            }
+            _ => {}
+        }
+
+        // load secret keys, keys sharing a truncated key id are told apart by their full one
+        let key_store = MemoryKeyStore::default();
+        let mut first_server = None;
+        for private_key in &private_keys {
+            let server = VoprfServer::<VoprfGroup>::new_with_key(private_key.expose_secret())
+                .map_err(|_| crystal_error("failed to load secret key"))?;
+            let truncated_token_key_id =
+                public_key_to_truncated_token_key_id(server.get_public_key());
+            first_server.get_or_insert_with(|| server.clone());
+            key_store.insert_checked(truncated_token_key_id, server)?;
+        }
+        let first_server = first_server.ok_or_else(|| crystal_error("no secret key given"))?;
+
+        // tokens carrying none of the key ids are checked against the first key, which
+        // rejects them like any other invalid token
+        let token_bytes = URL_SAFE.decode(token_encoded).unwrap_or_default();
+        let server = match token_bytes.get(TOKEN_KEY_ID_OFFSET..TOKEN_INPUT_LEN) {
+            Some(token_key_id) => ffi_runtime()?
+                .block_on(key_store.get_by_token_key_id(token_key_id))
+                .unwrap_or(first_server),
+            None => first_server,
+        };
+        validate_token_for_crystal(&server, token_encoded, token_challenge_s)
+    });
+    end_panic_handling!();
+    result
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ChallengeOriginError {
+    #[error("token challenge is not bound to an origin")]
+    NotBound,
+    #[error("token challenge origins {0:?} are not allowed")]
+    NotAllowed(Vec<String>),
+}
+
+impl ChallengeOriginError {
+    /// Stable code reported over FFI
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChallengeOriginError::NotBound => "challenge_origin_not_bound",
+            ChallengeOriginError::NotAllowed(_) => "challenge_origin_not_allowed",
+        }
+    }
+}
+
+/// Errors out unless the origin info of the base64 `token_challenge_s` names one of
+/// `allowed_origins` (compared case insensitively, as host names are). Challenges bound to no
+/// origin are refused, their tokens being redeemable anywhere, and an empty `allowed_origins`
+/// allows no origin at all. Malformed challenges are left to the token validation to refuse.
+pub fn check_challenge_origin<S: AsRef<str>>(
+    token_challenge_s: &str,
+    allowed_origins: &[S],
+) -> Result<(), ChallengeOriginError> {
+    let token_challenge = URL_SAFE.decode(token_challenge_s).unwrap_or_default();
+    let Ok(token_challenge) = kagippverify::TokenChallenge::parse(&token_challenge) else {
+        return Ok(());
+    };
+    if token_challenge.origin_info.is_empty() {
+        return Err(ChallengeOriginError::NotBound);
+    }
+    let allowed = token_challenge.origin_info.iter().any(|origin| {
+        allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_ref().eq_ignore_ascii_case(origin))
+    });
+    match allowed {
+        true => Ok(()),
+        false => Err(ChallengeOriginError::NotAllowed(
+            token_challenge
+                .origin_info
+                .iter()
+                .map(|origin| origin.to_string())
+                .collect(),
+        )),
+    }
+}
+
+/// Like `validate_token`, but also checks the challenge is bound to one of the origins of
+/// `allowed_origins_cstr`, a JSON array of origin names, e.g. "[\"a.example\", \"b.example\"]",
+/// see `check_challenge_origin`.
+/// NOTE: an empty array allows no origin, refusing every token, use `validate_token` to
+///       accept tokens of any origin
+#[no_mangle]
+pub extern "C" fn validate_token_for_origins(
+    sk_cstr: *const i8,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+    allowed_origins_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let allowed_origins_json =
+            unsafe { borrow_untrusted_str_from_crystal(allowed_origins_cstr, InputKind::Header)? };
+        let allowed_origins: Vec<String> = serde_json::from_str(allowed_origins_json)?;
+        check_challenge_origin(token_challenge_s, &allowed_origins)?;
+        let out = validate_token(sk_cstr, token_cstr, token_challenge_cstr);
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the validate_token FFI functions, returning "1" for valid tokens
+pub(crate) fn validate_token_for_crystal<S: VoprfSigner + ?Sized>(
+    server: &S,
+    token_encoded: &[u8],
+    token_challenge_s: &str,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let challenge_digest = redemption_challenge_digest(token_challenge_s)?;
+    let valid = redeem_encoded_token(server, token_encoded, &challenge_digest)?;
+    let valid_s = match valid {
+        true => "1",
+        false => "0",
+    };
+
+    let rv = JSONRetVal {
+        retval: valid_s.to_string(),
+        error: "".to_string(),
+    };
+    let out = encode_json_for_crystal(&rv)?;
+
+    Ok(out)
+}
+
+/// Digest of the base64 `token_challenge_s` that redeemed tokens must carry, once the
+/// challenge passed the authentication and freshness checks set up
+pub(crate) fn redemption_challenge_digest(
+    token_challenge_s: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let token_challenge = TokenChallenge::from_base64(token_challenge_s)?;
+    let challenge_digest = token_challenge.digest()?.to_vec();
+    check_redemption_challenge::<ValidateTokenError>(Some(token_challenge_s))?;
+    Ok(challenge_digest)
+}
+
+/// Redeems the base64 `token_encoded` against `server` and `challenge_digest`, returning
+/// whether it is valid and wasn't redeemed before
+pub(crate) fn redeem_encoded_token<S: VoprfSigner + ?Sized>(
+    server: &S,
+    token_encoded: &[u8],
+    challenge_digest: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let token_key_id = public_key_to_token_key_id(server.public_key());
+    let [.., truncated_token_key_id] = token_key_id;
+    check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+
+    // NOTE: from here on the token is attacker controlled. Malformed base64 decodes to an
+    //       empty token rather than erroring out, so that every rejected token takes the
+    //       same path and is reported the same way, whichever check it failed.
+    let token_bytes = URL_SAFE.decode(token_encoded).unwrap_or_default();
+
+    // check we didn't get an alternative URL_SAFE encoding due to malleability of base64
+    // NOTE: may be overkill, dependingo n how URL_SAFE.decode is implemented
+    let mut token_reencoded = [0u8; 4 * std::mem::size_of::<BatchedToken>().div_ceil(3)];
+    let token_reencoded_len = URL_SAFE
+        .encode_slice(&token_bytes, &mut token_reencoded)
+        .unwrap_or(0);
+    let canonical_encoding = token_encoded.ct_eq(
+        token_reencoded
+            .get(..token_reencoded_len)
+            .unwrap_or_default(),
+    );
+
+    // verify token is valid
+    let valid = bool::from(
+        canonical_encoding & verify_token_uniformly(server, &token_bytes, Some(challenge_digest)),
+    );
+
+    // refuse replays, only recording nonces of valid tokens
+    let mut outcome = RedemptionOutcome::from_validity(valid);
+    if valid && !token_nonce(&token_bytes).is_some_and(|nonce| redeem_nonce(nonce, token_key_id)) {
+        outcome = RedemptionOutcome::DoubleSpent;
+    }
+    record_redemption(truncated_token_key_id, outcome);
+    Ok(outcome == RedemptionOutcome::Valid)
+}
+
+/// Verdict on one token of `validate_tokens`, laid out like the return value of
+/// `validate_token` for it would be
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TokenVerdict {
+    Checked(JSONRetVal),
+    Failed(JSONErrorRetVal),
+}
+
+/// Validates a JSON array of base64 tokens against the same token challenge, loading the key
+/// and checking the challenge once for all of them. Returns the JSON array of their verdicts,
+/// in order: for each, either {"retval":"1","error":""} ("0" for invalid or replayed tokens)
+/// or the error it failed with, as returned by `validate_token`. Only batched tokens are
+/// validated, all of the group of the first token of a batched type (ristretto255 if none),
+/// the key being of that group. The array as a whole is held to the token batch input limit.
+#[no_mangle]
+pub extern "C" fn validate_tokens(
+    sk_cstr: *const i8,
+    tokens_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        // parse inputs
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let tokens_json =
+            unsafe { borrow_untrusted_str_from_crystal(tokens_cstr, InputKind::TokenBatch)? };
+        let tokens_encoded: Vec<&str> = serde_json::from_str(tokens_json)?;
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let challenge_digest = redemption_challenge_digest(token_challenge_s)?;
+        let challenge_digest = challenge_digest.as_slice();
+        let batched_group = tokens_encoded
+            .iter()
+            .filter_map(|token_encoded| encoded_token_type(token_encoded.as_bytes()))
+            .find_map(BatchedGroup::from_token_type)
+            .unwrap_or_default();
+        let redeem: Box<dyn Fn(&[u8]) -> Result<bool, Box<dyn std::error::Error>> + '_> =
+            match batched_group {
+                BatchedGroup::Ristretto255 => {
+                    let server =
+                        VoprfServer::<VoprfGroup>::new_with_key(private_key.expose_secret())
+                            .map_err(|_| crystal_error("failed to load secret key"))?;
+                    Box::new(move |token_encoded| {
+                        redeem_encoded_token(&server, token_encoded, challenge_digest)
+                    })
+                }
+                BatchedGroup::P384 => {
+                    let server =
+                        crate::batched_p384::load_batched_p384_key(private_key.expose_secret())?;
+                    Box::new(move |token_encoded| {
+                        Ok(crate::batched_p384::redeem_encoded_token(
+                            &server,
+                            token_encoded,
+                            challenge_digest,
+                        )?)
+                    })
+                }
+            };
+
+        let out =
+            token_verdicts_for_crystal(&tokens_encoded, batched_group.token_type() as u16, redeem)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Token type of a base64 token, from its first 3 bytes
+fn encoded_token_type(token_encoded: &[u8]) -> Option<u16> {
+    token_encoded
+        .get(..4)
+        .and_then(|quantum| URL_SAFE.decode(quantum).ok())
+        .and_then(|prefix| wire_token_type(&prefix))
+}
+
+/// Shared body of the validate_tokens FFI functions, `redeem` redeeming each base64 token of
+/// `tokens_encoded`, those of another type than `token_type` being refused
+pub(crate) fn token_verdicts_for_crystal(
+    tokens_encoded: &[&str],
+    token_type: u16,
+    redeem: impl Fn(&[u8]) -> Result<bool, Box<dyn std::error::Error>>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let verdicts: Vec<TokenVerdict> = tokens_encoded
+        .iter()
+        .map(|token_encoded| {
+            // each token is a redemption of its own
+            let _timer = LatencyTimer::start(Operation::Redemption);
+            let token_encoded = token_encoded.as_bytes();
+            check_input_len(InputKind::Token, token_encoded.len())?;
+            // malformed tokens take the path of the group of the batch, which rejects them
+            // uniformly
+            match encoded_token_type(token_encoded) {
+                Some(other) if other != token_type => Err(UnsupportedTokenTypeError(other))?,
+                _ => {}
+            }
+            redeem(token_encoded)
+        })
+        .map(
+            |valid: Result<bool, Box<dyn std::error::Error>>| match valid {
+                Ok(valid) => TokenVerdict::Checked(JSONRetVal {
+                    retval: match valid {
+                        true => "1",
+                        false => "0",
+                    }
+                    .to_string(),
+                    error: "".to_string(),
+                }),
+                Err(err) => TokenVerdict::Failed(JSONErrorRetVal::from_error(err.as_ref())),
+            },
+        )
+        .collect();
+
+    let rv = JSONRetVal {
+        retval: serde_json::to_string(&verdicts)?,
+        error: "".to_string(),
+    };
+    Ok(encode_json_for_crystal(&rv)?)
+}
+
+/// Runs a throwaway issuance and redemption round, so that the first real request
+/// doesn't pay for cold code paths and lazily initialized state after a deploy
+#[no_mangle]
+pub extern "C" fn pp_warmup() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        PrivacyPass::warmup()?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// setting domain separation for VOPRF secret key generation
+// as recommended by RFC 9578 (PP issuance protocol), section 5.5
+pub const DEFAULT_KEY_INFO: &[u8] = b"PrivacyPass";
+
+/// Shortest caller supplied key seed accepted, as many bytes as sampled ones
+pub const MIN_KEY_SEED_LEN: usize = 32;
+
+/// Issuer name and origin info of the challenges of `PrivacyPass::gen_token_challenge`,
+/// unless set with `with_issuer_name` and `with_origin_info`
+pub const DEFAULT_ISSUER_NAME: &str = "privacy-pass-issuer.kagi.com";
+pub const DEFAULT_ORIGIN_INFO: &str = "privacy-pass-origin.kagi.com";
+
+pub struct PrivacyPass {
+    // VOPRF key derivation info, see DEFAULT_KEY_INFO
+    info: Vec<u8>,
+    // of the challenges of token_challenge, see DEFAULT_ISSUER_NAME
+    issuer_name: String,
+    origin_info: String,
+    // group of the batched tokens challenged for, see with_batched_group
+    batched_group: BatchedGroup,
+}
+
+#[derive(Error, Debug)]
+#[allow(dead_code)]
+pub enum ValidateTokenError {
+    #[error("failed to serialize token challenge")]
+    Serialize(#[from] privacypass::auth::authenticate::SerializationError),
+    #[error("failed to construct keypair")]
+    CreateKeypair(#[from] CreateKeypairError),
+    #[error("failed to deserialize token")]
+    TlsDeserialize(#[from] tls_codec::Error),
+    #[error("direct TokenChallenge digest fails")]
+    ChallengeDigest,
+    #[error("doubly spent token (should never hit this)")]
+    DoubleSpending,
+    #[error("key id not found")]
+    KeyIdNotFound,
+    #[error("failed to redeem token")]
+    RedeemToken(#[from] RedeemTokenError),
+    #[error("failed to run token verification")]
+    CryptoPool(#[from] CryptoPoolError),
+    #[error("failed to load secret key")]
+    InvalidKey(voprf::Error),
+    #[error(
+        "key was derived under info {:?}, expected {:?}",
+        String::from_utf8_lossy(.found),
+        String::from_utf8_lossy(.expected)
+    )]
+    InfoMismatch { expected: Vec<u8>, found: Vec<u8> },
+    #[error("key was revoked")]
+    KeyRevoked(#[from] KeyRevokedError),
+    #[error("key is outside of its validity window")]
+    KeyValidity(#[from] KeyValidityError),
+    #[error("token challenge is not fresh")]
+    ChallengeFreshness(#[from] ChallengeFreshnessError),
+    #[error("token challenge is not authenticated")]
+    ChallengeAuthentication(#[from] ChallengeAuthenticationError),
+    #[error("token challenge is not bound to an allowed origin")]
+    ChallengeOrigin(#[from] ChallengeOriginError),
+    #[error("nonces can't be recorded synchronously")]
+    SharedNonceStore(#[from] SharedNonceStoreError),
+}
+
+/// Digest of the (base64) TokenChallenge a redeemed token must carry, if given
+pub(crate) fn redemption_challenge_digest(
+    token_challenge: Option<&str>,
+) -> Result<Option<Vec<u8>>, ValidateTokenError> {
+    token_challenge
+        .map(|token_challenge| {
+            TokenChallenge::from_base64(token_challenge)
+                .map_err(|_| ValidateTokenError::ChallengeDigest)?
+                .digest()
+                .map(|digest| digest.to_vec())
+                .map_err(|_| ValidateTokenError::ChallengeDigest)
+        })
+        .transpose()
+}
+
+/// Token type found on the wire that no issuance or redemption path exists for
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unsupported token type {0:#06x}")]
+pub struct UnsupportedTokenTypeError(pub u16);
+
+/// Truncated key id of a TokenRequest that none of the loaded keys has
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown key id {0}")]
+pub struct UnknownKeyIdError(pub TruncatedTokenKeyId);
+
+impl From<UnknownKeyIdError> for GenTokenResponseError {
+    fn from(_: UnknownKeyIdError) -> Self {
+        GenTokenResponseError::KeyIdNotFound
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WarmupError {
+    #[error("warm-up VOPRF round failed")]
+    Voprf(voprf::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum GenKeysError {
+    #[error("failed to construct keypair")]
+    CreateKeypair(#[from] CreateKeypairError),
+    #[error("failed generating secret key")]
+    DeriveKey(voprf::Error),
+    #[error("failed to run key generation")]
+    CryptoPool(#[from] CryptoPoolError),
+    #[error("no free truncated key id")]
+    NoFreeKeyId,
+    #[error("key seed is {0} bytes, at least {MIN_KEY_SEED_LEN} are needed")]
+    SeedTooShort(usize),
+}
+
+#[derive(Error, Debug)]
+pub enum GenTokenResponseError {
+    #[error("requested {0} tokens, max is {1}")]
+    RequestedTooManyTokens(usize, usize),
+    #[error("failed to construct keypair")]
+    CreateKeypair(#[from] CreateKeypairError),
+    #[error("failed to issue token response")]
+    IssueTokenResponse(#[from] IssueTokenResponseError),
+    #[error("single element fast path called on a request with {0} elements")]
+    NotSingleElement(usize),
+    #[error("invalid token type")]
+    InvalidTokenType,
+    /// none of the loaded keys has the truncated key id of the request, see
+    /// `UnknownKeyIdError`
+    #[error("key id not found")]
+    KeyIdNotFound,
+    #[error("failed to load secret key")]
+    InvalidKey(voprf::Error),
+    #[error("failed to evaluate blinded element")]
+    Evaluate(voprf::Error),
+    #[error("failed to (de)serialize token request")]
+    Tls(tls_codec::Error),
+    #[error("failed to assemble token response")]
+    InvalidTokenResponse,
+    #[error("token issuance was cancelled")]
+    Cancelled,
+    #[error("token issuance deadline exceeded")]
+    DeadlineExceeded,
+    #[error("failed to run token issuance")]
+    CryptoPool(#[from] CryptoPoolError),
+    #[error("key was revoked")]
+    KeyRevoked(#[from] KeyRevokedError),
+    #[error("key is outside of its validity window")]
+    KeyValidity(#[from] KeyValidityError),
+    #[cfg(feature = "pkcs11")]
+    #[error("signer failed to evaluate blinded elements")]
+    Signer(SignerError),
+    #[error("token request repeats a blinded element")]
+    DuplicateBlindedElement(#[from] DuplicateBlindedElementError),
+    #[error("token request carries an invalid blinded element")]
+    InvalidBlindedElement(#[from] InvalidBlindedElementError),
+}
+
+#[derive(Debug)]
+pub struct RustKeypair {
+    pub public_key: Vec<u8>,
+    pub secret_key: SecretBox<[u8; 32]>,
+    pub token_type: TokenType,
+    /// info string the key was derived under
+    pub info: Vec<u8>,
+}
+
+impl PrivacyPass {
+    pub fn new() -> Self {
+        Self::with_info(DEFAULT_KEY_INFO)
+    }
+
+    /// Uses `info` instead of DEFAULT_KEY_INFO for VOPRF key derivation
+    pub fn with_info(info: &[u8]) -> Self {
+        PrivacyPass {
+            info: info.to_vec(),
+            issuer_name: DEFAULT_ISSUER_NAME.to_string(),
+            origin_info: DEFAULT_ORIGIN_INFO.to_string(),
+            batched_group: BatchedGroup::default(),
+        }
+    }
+
+    /// Challenges tokens for `issuer_name` instead of DEFAULT_ISSUER_NAME
+    pub fn with_issuer_name(mut self, issuer_name: &str) -> Self {
+        self.issuer_name = issuer_name.to_string();
+        self
+    }
+
+    /// Challenges tokens for `origin_info` instead of DEFAULT_ORIGIN_INFO
+    pub fn with_origin_info(mut self, origin_info: &str) -> Self {
+        self.origin_info = origin_info.to_string();
+        self
+    }
+
+    /// Challenges for batched tokens of `batched_group` instead of ristretto255, to be issued
+    /// and redeemed with the `*_batched_p384` methods for P-384
+    pub fn with_batched_group(mut self, batched_group: BatchedGroup) -> Self {
+        self.batched_group = batched_group;
+        self
+    }
+
+    pub fn info(&self) -> &[u8] {
+        &self.info
+    }
+
+    pub fn issuer_name(&self) -> &str {
+        &self.issuer_name
+    }
+
+    pub fn origin_info(&self) -> &str {
+        &self.origin_info
+    }
+
+    pub fn batched_group(&self) -> BatchedGroup {
+        self.batched_group
+    }
+
+    /// Stops background tasks, waits for running issuance and redemption operations, and
+    /// flushes pending store writes, so that the embedding process can restart without losing
+    /// replay state
+    pub async fn shutdown(&self) -> Result<(), CryptoPoolError> {
+        crate::shutdown::shutdown().await
+    }
+
+    /// Exercises key derivation, blinding, evaluation, proof verification and redemption
+    /// on a throwaway key. Meant to be called once at process start.
+    pub fn warmup() -> Result<(), WarmupError> {
+        let mut seed =
+            Zeroizing::new(GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default());
         OsRng.fill_bytes(&mut seed);
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&seed, DEFAULT_KEY_INFO)
+            .map_err(WarmupError::Voprf)?;
+
+        let input = b"warmup";
+        let blind =
+            VoprfClient::<VoprfGroup>::blind(input, &mut OsRng).map_err(WarmupError::Voprf)?;
+        let evaluation = server.blind_evaluate(&mut OsRng, &blind.message);
+        blind
+            .state
+            .finalize(
+                input,
+                &evaluation.message,
+                &evaluation.proof,
+                server.get_public_key(),
+            )
+            .map_err(WarmupError::Voprf)?;
+        server.evaluate(input).map_err(WarmupError::Voprf)?;
+        Ok(())
+    }
 
-        // setting domain separation for VOPRF secret key generation
-        // as recommended by RFC 9578 (PP issuance protocol), section 5.5
-        let info = b"PrivacyPass";
-        let server = Server::new();
-        let key_store = MemoryKeyStore::default();
+    /// Checks `token` was issued with `private_key`.
+    /// Every check runs whatever the outcome of the others, so rejected tokens of any kind
+    /// (wrong size included) take as long as valid ones and are all reported as `Ok(false)`.
+    /// NOTE: the challenge of the token is not checked, so this errors out while challenges
+    ///       are checked for freshness, use `redeem_token` then
+    pub async fn validate_token(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+    ) -> Result<bool, ValidateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        check_redemption_challenge::<ValidateTokenError>(None)?;
+        let tkn = token.to_vec();
+        let private_key = secret_from_slice(private_key);
+
+        run_blocking(move || {
+            let server = VoprfServer::<VoprfGroup>::new_with_key(private_key.expose_secret())
+                .map_err(ValidateTokenError::InvalidKey)?;
+            let token_key_id = public_key_to_token_key_id(server.get_public_key());
+            let [.., truncated_token_key_id] = token_key_id;
+            check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+            let valid = bool::from(verify_token_uniformly(&server, &tkn, None));
+            record_redemption(
+                truncated_token_key_id,
+                RedemptionOutcome::from_validity(valid),
+            );
+            Ok(valid)
+        })
+        .await?
+    }
+
+    /// Like `validate_token`, but also checks the token carries the digest of the (base64)
+    /// `token_challenge` if given, and refuses replays like the validate_token FFI function,
+    /// see `set_replay_protection`. Redemptions without a challenge are refused while
+    /// challenges are checked for freshness.
+    pub async fn redeem_token(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+        token_challenge: Option<&str>,
+    ) -> Result<bool, ValidateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        check_redemption_challenge::<ValidateTokenError>(token_challenge)?;
+        let challenge_digest = redemption_challenge_digest(token_challenge)?;
+        let tkn = token.to_vec();
+        let private_key = secret_from_slice(private_key);
+
+        run_blocking(move || {
+            let server = VoprfServer::<VoprfGroup>::new_with_key(private_key.expose_secret())
+                .map_err(ValidateTokenError::InvalidKey)?;
+            let token_key_id = public_key_to_token_key_id(server.get_public_key());
+            let [.., truncated_token_key_id] = token_key_id;
+            check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+            let valid = bool::from(verify_token_uniformly(
+                &server,
+                &tkn,
+                challenge_digest.as_deref(),
+            ));
+            // refuse replays, only recording nonces of valid tokens
+            let mut outcome = RedemptionOutcome::from_validity(valid);
+            if valid && !token_nonce(&tkn).is_some_and(|nonce| redeem_nonce(nonce, token_key_id)) {
+                outcome = RedemptionOutcome::DoubleSpent;
+            }
+            record_redemption(truncated_token_key_id, outcome);
+            Ok(outcome == RedemptionOutcome::Valid)
+        })
+        .await?
+    }
+
+    /// Like `redeem_token`, but also checks `token_challenge` is bound to one of
+    /// `allowed_origins`, see `check_challenge_origin`
+    pub async fn redeem_token_for_origins(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+        token_challenge: &str,
+        allowed_origins: &[&str],
+    ) -> Result<bool, ValidateTokenError> {
+        check_challenge_origin(token_challenge, allowed_origins)?;
+        self.redeem_token(token, private_key, Some(token_challenge))
+            .await
+    }
+
+    /// Like `validate_token`, but first checks `keypair` was derived under this instance's info.
+    /// Keys derived under another info string would otherwise just reject every token.
+    pub async fn validate_token_with_keypair(
+        &self,
+        token: &[u8],
+        keypair: &RustKeypair,
+    ) -> Result<bool, ValidateTokenError> {
+        if keypair.info != self.info {
+            return Err(ValidateTokenError::InfoMismatch {
+                expected: self.info.clone(),
+                found: keypair.info.clone(),
+            });
+        }
+        self.validate_token(token, keypair.secret_key.expose_secret())
+            .await
+    }
 
-        let public_key = server
-            .create_keypair_with_params(&key_store, &seed, info)
-            .await?;
+    pub async fn gen_keys(&self) -> Result<RustKeypair, GenKeysError> {
+        self.gen_keys_from_rng(&mut OsRng, &[]).await
+    }
+
+    /// Like `gen_keys`, but re-derives the key until its truncated key id is not one of `taken`,
+    /// the truncated key ids of keys still in use.
+    pub async fn gen_keys_avoiding(
+        &self,
+        taken: &[TruncatedTokenKeyId],
+    ) -> Result<RustKeypair, GenKeysError> {
+        self.gen_keys_from_rng(&mut OsRng, taken).await
+    }
+
+    /// Like `gen_keys`, but with the key seed sampled from a caller supplied RNG,
+    /// so that tests can be seeded and fuzzers can inject edge-case seeds.
+    /// NOTE: only for testing, production code must use the OsRng-backed `gen_keys`
+    #[cfg(feature = "injectable-rng")]
+    pub async fn gen_keys_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+    ) -> Result<RustKeypair, GenKeysError> {
+        self.gen_keys_from_rng(rng, &[]).await
+    }
 
-        let secret_key =
-            derive_key::<VoprfGroup>(&seed, info, Mode::Voprf).map_err(GenKeysError::DeriveKey)?;
+    async fn gen_keys_from_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        taken: &[TruncatedTokenKeyId],
+    ) -> Result<RustKeypair, GenKeysError> {
+        // sample randomness for key generation
+        let seed = sample_key_seed(rng, &self.info, taken)?;
+        self.keypair_from_seed(Zeroizing::new(seed.to_vec())).await
+    }
+
+    /// Derives the keypair of a caller supplied `seed` of at least MIN_KEY_SEED_LEN bytes, e.g.
+    /// one derived from a master secret held in an HSM or vault, so that the same seed always
+    /// gives the same keypair
+    pub async fn gen_keys_from_seed(&self, seed: &[u8]) -> Result<RustKeypair, GenKeysError> {
+        if seed.len() < MIN_KEY_SEED_LEN {
+            return Err(GenKeysError::SeedTooShort(seed.len()));
+        }
+        self.keypair_from_seed(Zeroizing::new(seed.to_vec())).await
+    }
 
-        Ok(RustKeypair {
-            public_key: serialize_public_key(public_key),
-            secret_key: secret_key.to_bytes(),
-            token_type: TokenType::BatchedTokenRistretto255,
+    async fn keypair_from_seed(
+        &self,
+        seed: Zeroizing<Vec<u8>>,
+    ) -> Result<RustKeypair, GenKeysError> {
+        let info = self.info.clone();
+
+        run_blocking(move || {
+            let server = Server::new();
+            let key_store = MemoryKeyStore::default();
+
+            let public_key = tokio::runtime::Handle::current()
+                .block_on(server.create_keypair_with_params(&key_store, &seed, &info))?;
+
+            let secret_key = derive_key::<VoprfGroup>(&seed, &info, Mode::Voprf)
+                .map_err(GenKeysError::DeriveKey)?;
+
+            Ok(RustKeypair {
+                public_key: serialize_public_key(public_key),
+                secret_key: SecretBox::init_with(|| secret_key.to_bytes()),
+                token_type: TokenType::BatchedTokenRistretto255,
+                info,
+            })
         })
+        .await?
     }
 
     pub fn gen_www_authenticate_header(
         token_key: &[u8],
     ) -> Result<(HeaderName, HeaderValue), String> {
-        let token_challenge = Self::gen_token_challenge();
+        Self::new().www_authenticate_header(token_key)
+    }
+
+    pub fn gen_token_challenge() -> TokenChallenge {
+        Self::new().token_challenge()
+    }
+
+    /// Like `gen_www_authenticate_header`, challenging for the issuer name and origin info
+    /// set with `with_issuer_name` and `with_origin_info`
+    pub fn www_authenticate_header(
+        &self,
+        token_key: &[u8],
+    ) -> Result<(HeaderName, HeaderValue), String> {
+        let token_challenge = self.token_challenge();
         build_www_authenticate_header(&token_challenge, token_key, None)
             .or(Err("invalid token challenge".to_string()))
     }
 
-    pub fn gen_token_challenge() -> TokenChallenge {
+    /// Like `gen_token_challenge`, for the issuer name, origin info and batched group set with
+    /// `with_issuer_name`, `with_origin_info` and `with_batched_group`
+    pub fn token_challenge(&self) -> TokenChallenge {
         TokenChallenge::new(
-            GroupTokenType,
-            "privacy-pass-issuer.kagi.com",
+            self.batched_group.token_type(),
+            &self.issuer_name,
             None, /* redemption_context */
-            &["privacy-pass-origin.kagi.com".to_string()],
+            &[self.origin_info.clone()],
         )
     }
 
@@ -536,6 +2800,7 @@ impl PrivacyPass {
         token_request: TokenRequest,
         max_requests: usize,
     ) -> Result<TokenResponse, GenTokenResponseError> {
+        let _timer = LatencyTimer::start(Operation::Issuance);
         if token_request.nr() > max_requests {
             return Err(GenTokenResponseError::RequestedTooManyTokens(
                 token_request.nr(),
@@ -543,13 +2808,70 @@ impl PrivacyPass {
             ));
         }
 
-        let server = Server::new();
-        let key_store = MemoryKeyStore::default();
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || {
+            let token_request_bytes = token_request
+                .tls_serialize_detached()
+                .map_err(GenTokenResponseError::Tls)?;
+            let token_request_view = TokenRequestView::try_from_bytes(&token_request_bytes)
+                .map_err(GenTokenResponseError::Tls)?;
+            issue_token_response_sync(private_key.expose_secret(), &token_request_view)
+        })
+        .await?
+    }
+
+    /// Like `gen_token_response`, but instead of refusing TokenRequests asking for more than
+    /// `max_requests` tokens, issues a TokenResponse for every `max_requests` blinded elements,
+    /// in request order. Requests asking for more than `max_total` tokens are still refused.
+    /// Clients finalize the responses with `PrivacyPassClient::finalize_chunks`.
+    pub async fn gen_token_response_chunks(
+        &self,
+        private_key: &[u8],
+        token_request: MyTokenRequest,
+        max_requests: usize,
+        max_total: usize,
+    ) -> Result<Vec<TokenResponse>, GenTokenResponseError> {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let private_key = secret_from_slice(private_key);
+        run_blocking(move || {
+            issue_token_response_chunks(
+                private_key.expose_secret(),
+                token_request,
+                max_requests,
+                max_total,
+            )
+        })
+        .await?
+    }
+
+    /// Like `gen_token_response`, but abandons issuance once `deadline` has passed or
+    /// `cancellation` was cancelled, e.g. because the requesting client disconnected.
+    /// NOTE: VOPRF evaluation is CPU-bound and can't be interrupted halfway, so both are checked
+    ///       before evaluation starts and whenever the issuance future yields (e.g. on key store
+    ///       access).
+    pub async fn gen_token_response_with_cancellation(
+        &self,
+        private_key: &[u8],
+        token_request: TokenRequest,
+        max_requests: usize,
+        deadline: Option<Instant>,
+        cancellation: &CancellationToken,
+    ) -> Result<TokenResponse, GenTokenResponseError> {
+        check_deadline(deadline, Some(cancellation))?;
 
-        server.set_key(&key_store, private_key).await?;
-        Ok(server
-            .issue_token_response(&key_store, token_request)
-            .await?)
+        let issuance = self.gen_token_response(private_key, token_request, max_requests);
+        let deadline_reached = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = cancellation.cancelled() => Err(GenTokenResponseError::Cancelled),
+            _ = deadline_reached => Err(GenTokenResponseError::DeadlineExceeded),
+            token_response = issuance => token_response,
+        }
     }
 }
 
@@ -558,3 +2880,933 @@ impl Default for PrivacyPass {
         Self::new()
     }
 }
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crystal::{encode_string_for_crystal, free_string, JSONErrorRetVal};
+    use crate::limits::input_limits;
+    use proptest::prelude::*;
+    use sha2::Digest;
+
+    /// Serializes a TokenRequest with the given truncated key id and blinded elements
+    fn token_request_bytes(truncated_token_key_id: u8, blinded_elements: &[[u8; NE]]) -> Vec<u8> {
+        let mut bytes = (GroupTokenType as u16).to_be_bytes().to_vec();
+        bytes.push(truncated_token_key_id);
+        bytes.extend_from_slice(&((blinded_elements.len() * NE) as u16).to_be_bytes());
+        for blinded_element in blinded_elements {
+            bytes.extend_from_slice(blinded_element);
+        }
+        bytes
+    }
+
+    fn serialized_elements(token_request: &MyTokenRequest) -> Vec<Vec<u8>> {
+        token_request
+            .blinded_elements
+            .iter()
+            .map(|blinded_element| blinded_element.tls_serialize_detached().unwrap())
+            .collect()
+    }
+
+    /// Builds a valid serialized token for `server`, by computing its authenticator directly
+    fn valid_token_bytes(server: &VoprfServer<VoprfGroup>, challenge_digest: &[u8]) -> Vec<u8> {
+        let mut token = (GroupTokenType as u16).to_be_bytes().to_vec();
+        token.extend_from_slice(&[7u8; NONCE_BYTES]);
+        token.extend_from_slice(challenge_digest);
+        token.extend_from_slice(&public_key_to_token_key_id(server.get_public_key()));
+        let authenticator = server.evaluate(&token).unwrap();
+        token.extend_from_slice(&authenticator);
+        token
+    }
+
+    #[test]
+    fn test_verify_token_uniformly() {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[1u8; 32], b"PrivacyPass").unwrap();
+        let challenge_digest = [3u8; 32];
+        let token = valid_token_bytes(&server, &challenge_digest);
+        assert_eq!(token.len(), TOKEN_LEN);
+        let verify = |token: &[u8], digest: &[u8]| {
+            bool::from(verify_token_uniformly(&server, token, Some(digest)))
+        };
+
+        assert!(verify(&token, &challenge_digest));
+        assert!(bool::from(verify_token_uniformly(&server, &token, None)));
+        assert!(!verify(&token, &[4u8; 32]));
+        assert!(!verify(&token[..TOKEN_LEN - 1], &challenge_digest));
+        assert!(!verify(
+            &[token.as_slice(), &[0u8]].concat(),
+            &challenge_digest
+        ));
+        assert!(!verify(&[], &challenge_digest));
+        for offset in [
+            0,
+            2,
+            CHALLENGE_DIGEST_OFFSET,
+            TOKEN_KEY_ID_OFFSET,
+            TOKEN_INPUT_LEN,
+        ] {
+            let mut tampered = token.clone();
+            tampered[offset] ^= 1;
+            assert!(!verify(&tampered, &challenge_digest), "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn test_parse_www_authenticate_challenges() {
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let token_key = [5u8; 32];
+        let (_, header) =
+            build_www_authenticate_header(&token_challenge, &token_key, Some(60)).unwrap();
+        let challenges = parse_www_authenticate_challenges(header.to_str().unwrap()).unwrap();
+        assert_eq!(
+            challenges,
+            [WwwAuthenticateChallenge {
+                token_challenge: token_challenge.to_base64().unwrap(),
+                token_key: URL_SAFE.encode(token_key),
+                max_age: Some(60),
+            }]
+        );
+
+        let (_, header) =
+            build_www_authenticate_header(&token_challenge, &token_key, None).unwrap();
+        let challenges = parse_www_authenticate_challenges(header.to_str().unwrap()).unwrap();
+        assert_eq!(challenges[0].max_age, None);
+        // a max-age of 0 is kept, rather than taken for none
+        let (_, header) =
+            build_www_authenticate_header(&token_challenge, &token_key, Some(0)).unwrap();
+        let challenges = parse_www_authenticate_challenges(header.to_str().unwrap()).unwrap();
+        assert_eq!(challenges[0].max_age, Some(0));
+        assert!(parse_www_authenticate_challenges("Basic realm=x").is_err());
+    }
+
+    #[test]
+    fn test_build_www_authenticate_header_multi() {
+        let challenges = [
+            WwwAuthenticateChallenge {
+                token_challenge: PrivacyPass::gen_token_challenge().to_base64().unwrap(),
+                token_key: URL_SAFE.encode([5u8; 32]),
+                max_age: Some(60),
+            },
+            WwwAuthenticateChallenge {
+                token_challenge: TokenChallenge::new(
+                    GroupTokenType,
+                    "other-issuer.example",
+                    None,
+                    &["origin.example".to_string()],
+                )
+                .to_base64()
+                .unwrap(),
+                token_key: URL_SAFE.encode([6u8; 32]),
+                max_age: None,
+            },
+        ];
+        let header = build_www_authenticate_header_multi(&challenges).unwrap();
+        assert_eq!(
+            parse_www_authenticate_challenges(header.to_str().unwrap()).unwrap(),
+            challenges
+        );
+        assert!(matches!(
+            build_www_authenticate_header_multi(&[]),
+            Err(BuildWwwAuthenticateError::NoChallenge)
+        ));
+    }
+
+    #[test]
+    fn test_prevalidate_encoded_token() {
+        let mut token = vec![0x00, 0x05];
+        token.extend([1u8; TOKEN_INPUT_LEN + 64 - 2]);
+        let encoded = URL_SAFE.encode(&token);
+        assert_eq!(
+            prevalidate_encoded_token(encoded.as_bytes()),
+            Ok(GroupTokenType as u16)
+        );
+
+        // padding is part of the canonical encoding
+        let mut short = URL_SAFE.encode(&token[..token.len() - 1]);
+        assert_eq!(
+            prevalidate_encoded_token(short.as_bytes()),
+            Err(PrevalidateTokenError::WrongLength {
+                token_type: GroupTokenType as u16,
+                len: TOKEN_INPUT_LEN + 63,
+                expected: TOKEN_INPUT_LEN + 64,
+            })
+        );
+        short.truncate(short.trim_end_matches('=').len());
+        assert_eq!(
+            prevalidate_encoded_token(short.as_bytes()),
+            Err(PrevalidateTokenError::NonCanonicalEncoding)
+        );
+        assert_eq!(
+            prevalidate_encoded_token(URL_SAFE.encode([0, 0x42, 0]).as_bytes()),
+            Err(PrevalidateTokenError::UnsupportedTokenType(0x0042))
+        );
+        let mut p384_token = (BatchedP384TokenType as u16).to_be_bytes().to_vec();
+        p384_token.extend([1u8; TOKEN_INPUT_LEN + 48 - 2]);
+        assert_eq!(
+            prevalidate_encoded_token(URL_SAFE.encode(&p384_token).as_bytes()),
+            Ok(BatchedP384TokenType as u16)
+        );
+        assert_eq!(
+            prevalidate_encoded_token(URL_SAFE.encode([0]).as_bytes())
+                .unwrap_err()
+                .code(),
+            "token_truncated"
+        );
+    }
+
+    #[test]
+    fn test_check_challenge_origin() {
+        let token_challenge = |origin_info: &[String]| {
+            TokenChallenge::new(GroupTokenType, "issuer.example", None, origin_info)
+                .to_base64()
+                .unwrap()
+        };
+        let bound = token_challenge(&["a.example".to_string(), "b.example".to_string()]);
+        assert!(check_challenge_origin(&bound, &["b.example"]).is_ok());
+        assert!(check_challenge_origin(&bound, &["c.example", "A.Example"]).is_ok());
+        let err = check_challenge_origin(&bound, &["c.example"]).unwrap_err();
+        assert_eq!(err.code(), "challenge_origin_not_allowed");
+        assert_eq!(
+            check_challenge_origin(&token_challenge(&[]), &["a.example"]),
+            Err(ChallengeOriginError::NotBound)
+        );
+        let no_origin: [&str; 0] = [];
+        assert!(check_challenge_origin(&bound, &no_origin).is_err());
+    }
+
+    #[test]
+    fn test_validate_token_for_origins() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[8u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let token = valid_token_bytes(&server, &token_challenge.digest().unwrap());
+        let inputs = [
+            URL_SAFE.encode(sk_bytes),
+            URL_SAFE.encode(token),
+            token_challenge.to_base64().unwrap(),
+        ]
+        .map(|input| encode_string_for_crystal(input).unwrap());
+        let validate = |allowed_origins: &[&str]| {
+            let allowed_origins_cstr =
+                encode_string_for_crystal(serde_json::to_string(allowed_origins).unwrap()).unwrap();
+            let out =
+                validate_token_for_origins(inputs[0], inputs[1], inputs[2], allowed_origins_cstr);
+            free_string(allowed_origins_cstr);
+            let rv: serde_json::Value =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            rv
+        };
+
+        // an empty allowlist refuses the token, like another origin does, without spending it
+        for allowed_origins in [&[][..], &["other.example"][..]] {
+            let rv = validate(allowed_origins);
+            assert_eq!(
+                rv["code"], "challenge_origin_not_allowed",
+                "{:?}",
+                allowed_origins
+            );
+        }
+        assert_eq!(validate(&[DEFAULT_ORIGIN_INFO])["retval"], "1");
+        inputs.into_iter().for_each(free_string);
+    }
+
+    #[test]
+    fn test_configurable_challenge_names() {
+        let defaults = PrivacyPass::new();
+        assert_eq!(defaults.issuer_name(), DEFAULT_ISSUER_NAME);
+        assert_eq!(defaults.origin_info(), DEFAULT_ORIGIN_INFO);
+
+        let privacy_pass = PrivacyPass::new()
+            .with_issuer_name("issuer.example")
+            .with_origin_info("origin.example");
+        let expected = TokenChallenge::new(
+            GroupTokenType,
+            "issuer.example",
+            None,
+            &["origin.example".to_string()],
+        );
+        assert_eq!(
+            privacy_pass.token_challenge().to_base64().unwrap(),
+            expected.to_base64().unwrap()
+        );
+        assert_ne!(
+            PrivacyPass::gen_token_challenge().to_base64().unwrap(),
+            expected.to_base64().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_gen_token_challenge_with_context() {
+        let context_key = [3u8; MIN_REDEMPTION_CONTEXT_KEY_LEN];
+        let context = derive_redemption_context(&context_key, b"session 42").unwrap();
+        assert_eq!(
+            context,
+            derive_redemption_context(&context_key, b"session 42").unwrap()
+        );
+        assert_ne!(
+            context,
+            derive_redemption_context(&context_key, b"session 43").unwrap()
+        );
+        // the context can't be computed without the key
+        assert_ne!(context, <[u8; 32]>::from(Sha256::digest(b"session 42")));
+        assert_ne!(
+            context,
+            derive_redemption_context(&[4u8; 32], b"session 42").unwrap()
+        );
+        assert!(matches!(
+            derive_redemption_context(&[3u8; 16], b"session 42"),
+            Err(RedemptionContextError::KeyTooShort(16))
+        ));
+
+        let issuer_cstr = encode_string_for_crystal("issuer.example".to_string()).unwrap();
+        let origin_cstr = encode_string_for_crystal("origin.example".to_string()).unwrap();
+        let challenge = |context: &[u8]| {
+            let context_cstr = encode_string_for_crystal(URL_SAFE.encode(context)).unwrap();
+            let out = gen_token_challenge_with_context(issuer_cstr, origin_cstr, context_cstr);
+            free_string(context_cstr);
+            let json = unsafe { decode_string_from_crystal(out) }.unwrap();
+            free_string(out);
+            json
+        };
+
+        let rv: JSONRetVal = serde_json::from_str(&challenge(&context)).unwrap();
+        let expected = TokenChallenge::new(
+            GroupTokenType,
+            "issuer.example",
+            Some(context),
+            &["origin.example".to_string()],
+        );
+        assert_eq!(rv.retval, expected.to_base64().unwrap());
+
+        let rv: JSONRetVal = serde_json::from_str(&challenge(&[])).unwrap();
+        let out = gen_token_challenge(issuer_cstr, origin_cstr);
+        let without_context: JSONRetVal =
+            serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+        free_string(out);
+        assert_eq!(rv.retval, without_context.retval);
+
+        let rv: JSONErrorRetVal = serde_json::from_str(&challenge(&[1u8; 16])).unwrap();
+        assert!(!rv.error.is_empty());
+        for cstr in [issuer_cstr, origin_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_validate_token_refuses_replays() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[2u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let token = valid_token_bytes(&server, &token_challenge.digest().unwrap());
+
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        let token_cstr = encode_string_for_crystal(URL_SAFE.encode(&token)).unwrap();
+        let token_challenge_cstr =
+            encode_string_for_crystal(token_challenge.to_base64().unwrap()).unwrap();
+        let validate = || {
+            let out = validate_token(sk_cstr, token_cstr, token_challenge_cstr);
+            let rv: JSONRetVal =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            rv.retval
+        };
+
+        assert_eq!(validate(), "1");
+        assert_eq!(validate(), "0");
+        for cstr in [sk_cstr, token_cstr, token_challenge_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_validate_tokens() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[7u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let token = URL_SAFE.encode(valid_token_bytes(
+            &server,
+            &token_challenge.digest().unwrap(),
+        ));
+        let tokens = [
+            token.clone(),
+            token,
+            URL_SAFE.encode([0, 0x42, 0]),
+            "not a token".to_string(),
+        ];
+
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        let tokens_cstr =
+            encode_string_for_crystal(serde_json::to_string(&tokens).unwrap()).unwrap();
+        let token_challenge_cstr =
+            encode_string_for_crystal(token_challenge.to_base64().unwrap()).unwrap();
+        let out = validate_tokens(sk_cstr, tokens_cstr, token_challenge_cstr);
+        let rv: JSONRetVal =
+            serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+        free_string(out);
+        let verdicts: Vec<serde_json::Value> = serde_json::from_str(&rv.retval).unwrap();
+
+        // the second copy of the token is a replay of the first
+        let retvals: Vec<_> = verdicts.iter().map(|verdict| &verdict["retval"]).collect();
+        assert_eq!(retvals, ["1", "0", "", "0"]);
+        assert_eq!(verdicts[2]["code"], "unsupported_token_type");
+        for cstr in [sk_cstr, tokens_cstr, token_challenge_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_validate_token_multi() {
+        let servers: Vec<_> = [4u8, 5, 6]
+            .iter()
+            .map(|seed| {
+                let sk_bytes = derive_key::<VoprfGroup>(&[*seed; 32], b"PrivacyPass", Mode::Voprf)
+                    .unwrap()
+                    .to_bytes();
+                let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+                (URL_SAFE.encode(sk_bytes), server)
+            })
+            .collect();
+        // the previous and current keys are loaded, the third one isn't
+        let sks_json = serde_json::to_string(&[&servers[0].0, &servers[1].0]).unwrap();
+        let sks_cstr = encode_string_for_crystal(sks_json).unwrap();
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let token_challenge_cstr =
+            encode_string_for_crystal(token_challenge.to_base64().unwrap()).unwrap();
+        let validate = |server: &VoprfServer<VoprfGroup>| {
+            let token = valid_token_bytes(server, &token_challenge.digest().unwrap());
+            let token_cstr = encode_string_for_crystal(URL_SAFE.encode(&token)).unwrap();
+            let out = validate_token_multi(sks_cstr, token_cstr, token_challenge_cstr);
+            let rv: JSONRetVal =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            free_string(token_cstr);
+            rv.retval
+        };
+
+        assert_eq!(validate(&servers[0].1), "1");
+        assert_eq!(validate(&servers[1].1), "1");
+        assert_eq!(validate(&servers[2].1), "0");
+        for cstr in [sks_cstr, token_challenge_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_token_types_are_reported() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[2u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        // token type 0x0042
+        let token_cstr = encode_string_for_crystal(URL_SAFE.encode([0, 0x42, 0])).unwrap();
+        let token_challenge_cstr =
+            encode_string_for_crystal(PrivacyPass::gen_token_challenge().to_base64().unwrap())
+                .unwrap();
+
+        for out in [
+            validate_token(sk_cstr, token_cstr, token_challenge_cstr),
+            gen_token_response(sk_cstr, token_cstr, 1),
+            gen_keys(0x0042),
+        ] {
+            let rv: JSONErrorRetVal =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            assert_eq!(rv.code, "unsupported_token_type");
+        }
+        for cstr in [sk_cstr, token_cstr, token_challenge_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_deduplicated_token_request() {
+        let [a, b, c] = [[1u8; NE], [2u8; NE], [3u8; NE]];
+        let bytes = token_request_bytes(7, &[a, b, a, c, b]);
+        let view = TokenRequestView::try_from_bytes(&bytes).unwrap();
+        assert_eq!(view.first_duplicate(), Some(2));
+
+        let err = deduplicated_token_request(&view, DuplicateElementPolicy::Reject).unwrap_err();
+        assert!(matches!(
+            err,
+            GenTokenResponseError::DuplicateBlindedElement(DuplicateBlindedElementError(2))
+        ));
+        assert_eq!(
+            crate::crystal::error_code(&err),
+            "duplicate_blinded_element"
+        );
+
+        // first occurrences are kept, in order
+        let deduplicated = deduplicated_token_request(&view, DuplicateElementPolicy::Deduplicate)
+            .unwrap()
+            .unwrap();
+        assert_eq!(deduplicated, token_request_bytes(7, &[a, b, c]));
+        let view = TokenRequestView::try_from_bytes(&deduplicated).unwrap();
+        assert_eq!(view.first_duplicate(), None);
+        assert!(
+            deduplicated_token_request(&view, DuplicateElementPolicy::Reject)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_blinded_elements() {
+        let blinded_element: [u8; NE] = VoprfClient::<VoprfGroup>::blind(b"input", &mut OsRng)
+            .unwrap()
+            .message
+            .serialize()
+            .into();
+        let bytes = token_request_bytes(7, &[blinded_element, blinded_element]);
+        let view = TokenRequestView::try_from_bytes(&bytes).unwrap();
+        assert_eq!(deserialize_blinded_elements(&view).unwrap().len(), 2);
+
+        let bytes = token_request_bytes(7, &[blinded_element, [0u8; NE]]);
+        let view = TokenRequestView::try_from_bytes(&bytes).unwrap();
+        let err = deserialize_blinded_elements(&view).unwrap_err();
+        assert_eq!(err, InvalidBlindedElementError::Identity(1));
+        assert_eq!(err.code(), "blinded_element_identity");
+
+        let bytes = token_request_bytes(7, &[[0xffu8; NE], blinded_element]);
+        let view = TokenRequestView::try_from_bytes(&bytes).unwrap();
+        assert_eq!(
+            deserialize_blinded_elements(&view).unwrap_err(),
+            InvalidBlindedElementError::InvalidEncoding(0)
+        );
+    }
+
+    #[test]
+    fn test_gen_token_response_with_policy() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[4u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        let blinded_elements: Vec<[u8; NE]> = (0..3u8)
+            .map(|i| {
+                VoprfClient::<VoprfGroup>::blind(&[i], &mut OsRng)
+                    .unwrap()
+                    .message
+                    .serialize()
+                    .into()
+            })
+            .collect();
+        let truncated_token_key_id = public_key_to_truncated_token_key_id(server.get_public_key());
+        let token_request_cstr = encode_string_for_crystal(URL_SAFE.encode(token_request_bytes(
+            truncated_token_key_id,
+            &blinded_elements,
+        )))
+        .unwrap();
+        let gen = |over_limit_policy| {
+            let out =
+                gen_token_response_with_policy(sk_cstr, token_request_cstr, 2, over_limit_policy);
+            let rv: serde_json::Value =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            rv
+        };
+
+        let truncated = gen(0);
+        assert_eq!(truncated["error"], "");
+        assert_eq!(truncated["dropped"], 1);
+        let token_response = URL_SAFE
+            .decode(truncated["retval"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            usize::from(u16::from_be_bytes([token_response[0], token_response[1]])),
+            2 * NE
+        );
+
+        let rejected = gen(1);
+        assert_eq!(rejected["retval"], "");
+        assert_eq!(rejected["causes"][0], "requested 3 tokens, max is 2");
+
+        let none = gen(2);
+        assert_eq!((&none["retval"], &none["dropped"]), (&"".into(), &3.into()));
+        assert_eq!(gen(3)["causes"][0], "unknown over-limit policy 3");
+
+        for cstr in [sk_cstr, token_request_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[test]
+    fn test_issue_token_response_chunks() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[5u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let truncated_token_key_id = public_key_to_truncated_token_key_id(server.get_public_key());
+        let blinded_elements: Vec<[u8; NE]> = (0..5u8)
+            .map(|i| {
+                VoprfClient::<VoprfGroup>::blind(&[i], &mut OsRng)
+                    .unwrap()
+                    .message
+                    .serialize()
+                    .into()
+            })
+            .collect();
+        let bytes = token_request_bytes(truncated_token_key_id, &blinded_elements);
+        let token_request = || MyTokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+
+        let chunks: Vec<Vec<u8>> = token_request()
+            .split(2)
+            .iter()
+            .map(|chunk| chunk.tls_serialize_detached().unwrap())
+            .collect();
+        assert_eq!(
+            chunks,
+            blinded_elements
+                .chunks(2)
+                .map(|chunk| token_request_bytes(truncated_token_key_id, chunk))
+                .collect::<Vec<_>>()
+        );
+
+        let token_responses =
+            issue_token_response_chunks(&sk_bytes, token_request(), 2, 5).unwrap();
+        let evaluated_lens: Vec<usize> = token_responses
+            .iter()
+            .map(|token_response| {
+                let bytes = token_response.tls_serialize_detached().unwrap();
+                usize::from(u16::from_be_bytes([bytes[0], bytes[1]]))
+            })
+            .collect();
+        assert_eq!(evaluated_lens, [2 * NE, 2 * NE, NE]);
+        assert!(matches!(
+            issue_token_response_chunks(&sk_bytes, token_request(), 0, 5),
+            Err(GenTokenResponseError::RequestedTooManyTokens(5, 0))
+        ));
+        assert!(matches!(
+            issue_token_response_chunks(&sk_bytes, token_request(), 2, 4),
+            Err(GenTokenResponseError::RequestedTooManyTokens(5, 4))
+        ));
+    }
+
+    #[test]
+    fn test_gen_token_responses() {
+        let sk_bytes = derive_key::<VoprfGroup>(&[6u8; 32], b"PrivacyPass", Mode::Voprf)
+            .unwrap()
+            .to_bytes();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes).unwrap();
+        let truncated_token_key_id = public_key_to_truncated_token_key_id(server.get_public_key());
+        let blinded_elements: Vec<[u8; NE]> = (0..3u8)
+            .map(|i| {
+                VoprfClient::<VoprfGroup>::blind(&[i], &mut OsRng)
+                    .unwrap()
+                    .message
+                    .serialize()
+                    .into()
+            })
+            .collect();
+        let token_requests = [
+            URL_SAFE.encode(token_request_bytes(
+                truncated_token_key_id,
+                &blinded_elements,
+            )),
+            URL_SAFE.encode([0, 0x42, 0]),
+            URL_SAFE.encode(token_request_bytes(
+                truncated_token_key_id,
+                &blinded_elements[..1],
+            )),
+            "A".repeat(input_limits().max_token_request_len + 4),
+        ];
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        let token_requests_cstr =
+            encode_string_for_crystal(serde_json::to_string(&token_requests).unwrap()).unwrap();
+
+        let out = gen_token_responses(sk_cstr, token_requests_cstr, 2);
+        let rv: JSONRetVal =
+            serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+        free_string(out);
+        let outcomes: Vec<serde_json::Value> = serde_json::from_str(&rv.retval).unwrap();
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!(
+            (&outcomes[0]["error"], &outcomes[0]["dropped"]),
+            (&"".into(), &1.into())
+        );
+        assert_ne!(outcomes[0]["retval"], "");
+        assert_eq!(outcomes[1]["code"], "unsupported_token_type");
+        assert_eq!(outcomes[1]["retval"], "");
+        assert_eq!(outcomes[2]["dropped"], 0);
+        assert_ne!(outcomes[2]["retval"], "");
+        // the array is over the TokenRequest limit, the request over it fails on its own
+        assert_eq!(outcomes[3]["code"], "token_request_too_long");
+
+        for cstr in [sk_cstr, token_requests_cstr] {
+            free_string(cstr);
+        }
+    }
+
+    #[cfg(feature = "injectable-rng")]
+    #[tokio::test]
+    async fn test_validate_token_detects_info_mismatch() {
+        let keypair = PrivacyPass::with_info(b"other info")
+            .gen_keys()
+            .await
+            .unwrap();
+        let server =
+            VoprfServer::<VoprfGroup>::new_with_key(keypair.secret_key.expose_secret()).unwrap();
+        let token = valid_token_bytes(&server, &[3u8; 32]);
+
+        let valid = PrivacyPass::with_info(b"other info")
+            .validate_token_with_keypair(&token, &keypair)
+            .await
+            .unwrap();
+        assert!(valid);
+        let err = PrivacyPass::new()
+            .validate_token_with_keypair(&token, &keypair)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ValidateTokenError::InfoMismatch { .. }));
+    }
+
+    #[cfg(feature = "injectable-rng")]
+    #[tokio::test]
+    async fn seeded_rng_gives_deterministic_keys() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let pp = PrivacyPass::new();
+        let keypair_1 = pp
+            .gen_keys_with_rng(&mut StdRng::seed_from_u64(42))
+            .await
+            .unwrap();
+        let keypair_2 = pp
+            .gen_keys_with_rng(&mut StdRng::seed_from_u64(42))
+            .await
+            .unwrap();
+        assert_eq!(
+            keypair_1.secret_key.expose_secret(),
+            keypair_2.secret_key.expose_secret()
+        );
+        assert_eq!(keypair_1.public_key, keypair_2.public_key);
+    }
+
+    #[tokio::test]
+    async fn test_gen_keys_from_seed() {
+        let seed = [9u8; MIN_KEY_SEED_LEN];
+        let keypair_1 = PrivacyPass::new().gen_keys_from_seed(&seed).await.unwrap();
+        let keypair_2 = PrivacyPass::new().gen_keys_from_seed(&seed).await.unwrap();
+        assert_eq!(keypair_1.public_key, keypair_2.public_key);
+
+        // keys are namespaced by the info string
+        let other_info = PrivacyPass::with_info(b"staging")
+            .gen_keys_from_seed(&seed)
+            .await
+            .unwrap();
+        assert_ne!(keypair_1.public_key, other_info.public_key);
+
+        let err = PrivacyPass::new()
+            .gen_keys_from_seed(&seed[1..])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GenKeysError::SeedTooShort(31)));
+    }
+
+    #[tokio::test]
+    async fn test_gen_keys_with_info() {
+        // the key is derived under the given info string
+        let seed = [9u8; MIN_KEY_SEED_LEN];
+        let keypair = PrivacyPass::with_info(b"staging")
+            .gen_keys_from_seed(&seed)
+            .await
+            .unwrap();
+        let secret_key = derive_key::<VoprfGroup>(&seed, b"staging", Mode::Voprf).unwrap();
+        assert_eq!(
+            keypair.secret_key.expose_secret().as_slice(),
+            secret_key.to_bytes().as_slice()
+        );
+        assert_eq!(keypair.info, b"staging");
+
+        // and only validates tokens of instances with that info string
+        let server =
+            VoprfServer::<VoprfGroup>::new_with_key(keypair.secret_key.expose_secret()).unwrap();
+        let token = valid_token_bytes(&server, &[4u8; 32]);
+        let err = PrivacyPass::with_info(b"production")
+            .validate_token_with_keypair(&token, &keypair)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ValidateTokenError::InfoMismatch { expected, found }
+                if expected == b"production" && found == b"staging"
+        ));
+    }
+
+    #[test]
+    fn test_gen_keys_with_info_ffi() {
+        let rv = |out: *const i8| -> serde_json::Value {
+            let rv = serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap());
+            free_string(out);
+            rv.unwrap()
+        };
+        let info_cstr = encode_string_for_crystal("staging".to_string()).unwrap();
+        let out = rv(gen_keys_with_info(GroupTokenType as u16, info_cstr));
+        let keypair: serde_json::Value =
+            serde_json::from_str(out["retval"].as_str().unwrap()).unwrap();
+        let secret_key = URL_SAFE.decode(keypair["sk"].as_str().unwrap()).unwrap();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&secret_key).unwrap();
+        assert_eq!(
+            URL_SAFE.decode(keypair["pk"].as_str().unwrap()).unwrap(),
+            serialize_public_key(server.get_public_key()).to_vec()
+        );
+
+        // blind RSA keys are not derived under an info string
+        let out = rv(gen_keys_with_info(TOKEN_TYPE_PUBLIC_RSA, info_cstr));
+        assert!(out["error"]
+            .as_str()
+            .unwrap()
+            .contains("take no info string"));
+        free_string(info_cstr);
+    }
+
+    #[test]
+    fn test_gen_keys_avoiding_for_token_type() {
+        let rv = |out: *const i8| -> serde_json::Value {
+            let rv = serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap());
+            free_string(out);
+            rv.unwrap()
+        };
+        let taken: Vec<u8> = (0..128).collect();
+        let taken_cstr = encode_string_for_crystal(serde_json::to_string(&taken).unwrap()).unwrap();
+        let out = rv(gen_keys_avoiding_for_token_type(
+            BatchedP384TokenType as u16,
+            taken_cstr,
+            0,
+        ));
+        let keypair: serde_json::Value =
+            serde_json::from_str(out["retval"].as_str().unwrap()).unwrap();
+        assert_eq!(keypair["token_type"], BatchedP384TokenType as u16);
+        let public_key = URL_SAFE.decode(keypair["pk"].as_str().unwrap()).unwrap();
+        assert!(*Sha256::digest(&public_key).last().unwrap() >= 128);
+
+        // only batched keys avoid taken key ids
+        let out = rv(gen_keys_avoiding_for_token_type(
+            TOKEN_TYPE_PRIVATE_P384,
+            taken_cstr,
+            0,
+        ));
+        assert!(out["error"].as_str().unwrap().contains("only batched keys"));
+        free_string(taken_cstr);
+    }
+
+    #[test]
+    fn test_gen_keys_from_seed_encoded() {
+        let keypair = |out: *const i8| -> serde_json::Value {
+            let rv: serde_json::Value =
+                serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+            free_string(out);
+            serde_json::from_str(rv["retval"].as_str().unwrap()).unwrap()
+        };
+        let seed_cstr =
+            encode_string_for_crystal(URL_SAFE.encode([9u8; MIN_KEY_SEED_LEN])).unwrap();
+        let base64url = keypair(gen_keys_from_seed(seed_cstr, std::ptr::null()));
+        let hex = keypair(gen_keys_from_seed_encoded(seed_cstr, std::ptr::null(), 1));
+        for key in ["pk", "sk"] {
+            assert_eq!(
+                KeyEncoding::Hex.decode(hex[key].as_str().unwrap()).unwrap(),
+                KeyEncoding::Base64Url
+                    .decode(base64url[key].as_str().unwrap())
+                    .unwrap()
+            );
+        }
+
+        // unknown encodings are refused
+        let out = gen_keys_from_seed_encoded(seed_cstr, std::ptr::null(), 2);
+        let rv: serde_json::Value =
+            serde_json::from_str(&unsafe { decode_string_from_crystal(out) }.unwrap()).unwrap();
+        assert!(rv["error"]
+            .as_str()
+            .unwrap()
+            .contains("unknown key encoding"));
+        free_string(out);
+        free_string(seed_cstr);
+    }
+
+    proptest! {
+        #[test]
+        fn truncate_keeps_ordered_prefix(
+            truncated_token_key_id in any::<u8>(),
+            blinded_elements in prop::collection::vec(any::<[u8; NE]>(), 0..64),
+            max_elements in 0usize..80,
+        ) {
+            let bytes = token_request_bytes(truncated_token_key_id, &blinded_elements);
+            let original = MyTokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+            let mut truncated = MyTokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+            truncated.truncate(max_elements);
+
+            let expected_nr = blinded_elements.len().min(max_elements);
+            prop_assert_eq!(truncated.nr(), expected_nr);
+            prop_assert_eq!(truncated.truncated_token_key_id, truncated_token_key_id);
+            prop_assert_eq!(
+                serialized_elements(&truncated),
+                serialized_elements(&original)[..expected_nr].to_vec()
+            );
+            prop_assert_eq!(
+                truncated.tls_serialize_detached().unwrap(),
+                token_request_bytes(truncated_token_key_id, &blinded_elements[..expected_nr])
+            );
+        }
+
+        #[test]
+        fn my_token_request_round_trips(
+            truncated_token_key_id in any::<u8>(),
+            blinded_elements in prop::collection::vec(any::<[u8; NE]>(), 0..64),
+        ) {
+            let bytes = token_request_bytes(truncated_token_key_id, &blinded_elements);
+            let token_request = MyTokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+            prop_assert_eq!(token_request.nr(), blinded_elements.len());
+            prop_assert_eq!(token_request.tls_serialize_detached().unwrap(), bytes);
+        }
+
+        #[test]
+        fn to_token_request_matches_direct_conversion(
+            truncated_token_key_id in any::<u8>(),
+            blinded_elements in prop::collection::vec(any::<[u8; NE]>(), 0..64),
+        ) {
+            let bytes = token_request_bytes(truncated_token_key_id, &blinded_elements);
+            let direct = TokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+            let converted = MyTokenRequest::tls_deserialize(&mut bytes.as_slice())
+                .unwrap()
+                .to_token_request()
+                .unwrap();
+            prop_assert_eq!(converted.nr(), direct.nr());
+            prop_assert_eq!(
+                converted.tls_serialize_detached().unwrap(),
+                direct.tls_serialize_detached().unwrap()
+            );
+        }
+
+        #[test]
+        fn token_request_view_matches_my_token_request(
+            truncated_token_key_id in any::<u8>(),
+            blinded_elements in prop::collection::vec(any::<[u8; NE]>(), 0..64),
+            max_elements in 0usize..80,
+        ) {
+            let bytes = token_request_bytes(truncated_token_key_id, &blinded_elements);
+            let mut view = TokenRequestView::try_from_bytes(&bytes).unwrap();
+            let mut token_request = MyTokenRequest::tls_deserialize(&mut bytes.as_slice()).unwrap();
+            prop_assert_eq!(view.nr(), token_request.nr());
+            prop_assert_eq!(
+                view.blinded_elements().map(<[u8]>::to_vec).collect::<Vec<_>>(),
+                serialized_elements(&token_request)
+            );
+
+            view.truncate(max_elements);
+            token_request.truncate(max_elements);
+            prop_assert_eq!(
+                view.to_token_request().unwrap().tls_serialize_detached().unwrap(),
+                token_request.to_token_request().unwrap().tls_serialize_detached().unwrap()
+            );
+        }
+    }
+}
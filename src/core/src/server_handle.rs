@@ -0,0 +1,803 @@
+// -----------------------------------------------------------------------------
+// ---------------------  handle-based server FFI  -----------------------------
+// -----------------------------------------------------------------------------
+//
+// The FFI functions taking a secret key decode it, load it and install it in a fresh key
+// store on every call. `pp_server_new` does that once, returning an opaque handle holding
+// the loaded key and its key store, which the pp_server_* variants of those functions reuse. Handles can be used from several threads at once, and must be released
+// with `pp_server_free` once no call is using them anymore.
+// `pp_server_new_with_keys` loads several keys, each request being handled with the key it
+// names through its (truncated) token key id.
+// A handle can hold a `VoprfSigner` instead of a secret key (e.g. a PKCS#11 module, see
+// `pp_server_new_from_pkcs11`), all pp_server_* functions then go through the signer.
+// Every FFI function taking a batched ristretto255 secret key has a pp_server_* variant,
+// except `validate_token_multi`, which is `pp_server_validate_token` on a handle of
+// `pp_server_new_with_keys`.
+// Redeemed nonces are recorded in the process-wide nonce store shared by all handles, which
+// is pruned process-wide too, see `start_nonce_gc` and `set_retention_policy`.
+
+use crate::config::{batched_tokens_mod, GroupTokenType};
+use crate::crystal::{
+    borrow_untrusted_bytes_from_crystal, borrow_untrusted_str_from_crystal, crystal_error,
+    decode_secret_bytes_array_from_crystal, decode_secret_bytes_from_crystal,
+    decode_untrusted_bytes_from_crystal, encode_json_for_crystal, error_chain_json_retval,
+    error_json_retval, CrystalErrorType, JSONRetVal,
+};
+use crate::limits::InputKind;
+use crate::metrics::{LatencyTimer, Operation};
+use crate::server::{
+    check_challenge_origin, issue_serialized_token_response, issue_serialized_with_signer,
+    issue_token_response_with_signer, over_limit_response, public_key_to_token_key_id,
+    redeem_encoded_token, redemption_challenge_digest, token_response_chunks_for_crystal,
+    token_responses_for_crystal, token_verdicts_for_crystal, validate_token_for_crystal,
+    www_authenticate_header_for_crystal, IssuedTokenResponse, LoadedKey, OverLimitPolicy,
+    TokenRequestView, UnknownKeyIdError,
+};
+use crate::signer::VoprfSigner;
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use batched_tokens_mod::server::serialize_public_key;
+use kagippverify::token::{TOKEN_INPUT_LEN, TOKEN_KEY_ID_OFFSET};
+use secrecy::{ExposeSecret, SecretSlice};
+use std::time::{Duration, Instant};
+
+/// Issuer state shared by the calls made through a handle
+pub struct ServerHandle {
+    keys: HandleKeys,
+    /// public key of the first key, which challenges are built with
+    public_key: Vec<u8>,
+}
+
+enum HandleKeys {
+    /// secret keys in memory, in the order they were given
+    Software(Vec<SoftwareKey>),
+    /// secret key held by a signer, which evaluates everything
+    Signer(Box<dyn VoprfSigner>),
+}
+
+/// Secret key in memory, loaded once for issuance and redemption
+struct SoftwareKey {
+    private_key: SecretSlice<u8>,
+    loaded_key: LoadedKey,
+    token_key_id: [u8; 32],
+}
+
+impl SoftwareKey {
+    fn load(private_key: SecretSlice<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        let loaded_key = LoadedKey::load(private_key.expose_secret())?;
+        Ok(SoftwareKey {
+            token_key_id: public_key_to_token_key_id(loaded_key.public_key()),
+            private_key,
+            loaded_key,
+        })
+    }
+}
+
+impl ServerHandle {
+    pub fn new(private_key: SecretSlice<u8>) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_keys(vec![private_key])
+    }
+
+    /// Loads several keys, e.g. during a key rotation. TokenRequests and tokens are handled
+    /// with whichever key they name, challenges are built with the first key. Keys sharing a
+    /// truncated key id are refused, as TokenRequests could not tell them apart.
+    pub fn with_keys(
+        private_keys: Vec<SecretSlice<u8>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = private_keys
+            .into_iter()
+            .map(SoftwareKey::load)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut truncated_token_key_ids = std::collections::HashSet::new();
+        for key in &keys {
+            let [.., truncated_token_key_id] = key.token_key_id;
+            if !truncated_token_key_ids.insert(truncated_token_key_id) {
+                Err(crystal_error(&format!(
+                    "two secret keys share truncated key id {}",
+                    truncated_token_key_id
+                )))?;
+            }
+        }
+        let first_key = keys
+            .first()
+            .ok_or_else(|| crystal_error("no secret key given"))?;
+        let public_key = serialize_public_key(first_key.loaded_key.public_key());
+        Ok(ServerHandle {
+            keys: HandleKeys::Software(keys),
+            public_key,
+        })
+    }
+
+    pub fn with_signer(signer: Box<dyn VoprfSigner>) -> Self {
+        let public_key = serialize_public_key(signer.public_key());
+        ServerHandle {
+            keys: HandleKeys::Signer(signer),
+            public_key,
+        }
+    }
+
+    /// Signer checking `token`, i.e. the key it names through its token key id. Tokens
+    /// naming none of the keys are checked against the first key, which refuses them.
+    fn redemption_signer(&self, token: &[u8]) -> Result<&dyn VoprfSigner, CrystalErrorType> {
+        match &self.keys {
+            HandleKeys::Software(keys) => {
+                let token_key_id = token.get(TOKEN_KEY_ID_OFFSET..TOKEN_INPUT_LEN);
+                let key = keys
+                    .iter()
+                    .find(|key| Some(key.token_key_id.as_slice()) == token_key_id)
+                    .or(keys.first())
+                    .ok_or_else(|| crystal_error("no secret key loaded"))?;
+                Ok(key.loaded_key.voprf_server())
+            }
+            HandleKeys::Signer(signer) => Ok(signer.as_ref()),
+        }
+    }
+
+    /// Issues a TokenResponse for the serialized `token_request_bytes`, with the key it names
+    fn issue(
+        &self,
+        token_request_bytes: &[u8],
+        max_nr: u16,
+        deadline: Option<Instant>,
+    ) -> Result<IssuedTokenResponse, Box<dyn std::error::Error>> {
+        match &self.keys {
+            HandleKeys::Software(keys) => {
+                let key = issuance_key(keys, token_request_bytes)?;
+                issue_serialized_token_response(
+                    key.private_key.expose_secret(),
+                    Some(&key.loaded_key),
+                    token_request_bytes,
+                    max_nr,
+                    deadline,
+                )
+            }
+            HandleKeys::Signer(signer) => {
+                issue_serialized_with_signer(signer.as_ref(), token_request_bytes, max_nr, deadline)
+            }
+        }
+    }
+
+    /// Signer issuing for the serialized `token_request_bytes`, i.e. the key it names
+    fn issuance_signer(
+        &self,
+        token_request_bytes: &[u8],
+    ) -> Result<&dyn VoprfSigner, Box<dyn std::error::Error>> {
+        match &self.keys {
+            HandleKeys::Software(keys) => Ok(issuance_key(keys, token_request_bytes)?
+                .loaded_key
+                .voprf_server()),
+            HandleKeys::Signer(signer) => Ok(signer.as_ref()),
+        }
+    }
+}
+
+/// Key of `keys` the serialized `token_request_bytes` names through its truncated token key id
+fn issuance_key<'a>(
+    keys: &'a [SoftwareKey],
+    token_request_bytes: &[u8],
+) -> Result<&'a SoftwareKey, Box<dyn std::error::Error>> {
+    let truncated_token_key_id =
+        TokenRequestView::try_from_bytes(token_request_bytes)?.truncated_token_key_id();
+    Ok(keys
+        .iter()
+        .find(|key| key.token_key_id.last() == Some(&truncated_token_key_id))
+        .ok_or(UnknownKeyIdError(truncated_token_key_id))?)
+}
+
+/// Borrows the handle behind a pointer returned by `pp_server_new`
+///
+/// # Safety
+///
+/// Callers must provide either a null pointer or a handle that was not freed yet.
+unsafe fn handle_from_crystal<'a>(
+    handle: *const ServerHandle,
+) -> Result<&'a ServerHandle, CrystalErrorType> {
+    unsafe { handle.as_ref() }.ok_or_else(|| crystal_error("null server handle"))
+}
+
+/// Loads a (base64 encoded) secret key, writing a handle to `handle_out`
+#[no_mangle]
+pub extern "C" fn pp_server_new(
+    sk_cstr: *const i8,
+    handle_out: *mut *mut ServerHandle,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let private_key = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key)? };
+        let handle = Box::new(ServerHandle::new(private_key)?);
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Loads a JSON array of (base64 encoded) secret keys, e.g. the new and previous keys during
+/// a rotation, writing a handle to `handle_out`. TokenRequests and tokens are handled with
+/// the key they name, requests naming none of them fail with an "unknown_key_id" error.
+/// Challenges (`pp_server_gen_www_authenticate_header`) are built with the first key.
+#[no_mangle]
+pub extern "C" fn pp_server_new_with_keys(
+    sks_cstr: *const i8,
+    handle_out: *mut *mut ServerHandle,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        if handle_out.is_null() {
+            Err(crystal_error("null handle output pointer"))?;
+        }
+        let private_keys =
+            unsafe { decode_secret_bytes_array_from_crystal(sks_cstr, InputKind::Key)? };
+        let handle = Box::new(ServerHandle::with_keys(private_keys)?);
+        unsafe { *handle_out = Box::into_raw(handle) };
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Releases a handle returned by `pp_server_new`, wiping its secret key
+/// # Safety
+/// The handle must not be used anymore, by this or any other thread
+#[no_mangle]
+pub extern "C" fn pp_server_free(handle: *mut ServerHandle) {
+    if handle.is_null() {
+        return;
+    }
+    // Take the ownership back to rust and drop the owner
+    let _ = unsafe { Box::from_raw(handle) };
+}
+
+/// Like `gen_token_response`, using the key loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_gen_token_response(
+    handle: *const ServerHandle,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| unsafe {
+        gen_token_response_impl(
+            handle,
+            token_request_cstr,
+            max_nr,
+            OverLimitPolicy::Truncate,
+            None,
+        )
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_response_with_deadline`, using the key loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_gen_token_response_with_deadline(
+    handle: *const ServerHandle,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    timeout_ms: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let deadline = match timeout_ms {
+            0 => None,
+            _ => Some(Instant::now() + Duration::from_millis(u64::from(timeout_ms))),
+        };
+        unsafe {
+            gen_token_response_impl(
+                handle,
+                token_request_cstr,
+                max_nr,
+                OverLimitPolicy::Truncate,
+                deadline,
+            )
+        }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_response_with_policy`, using the keys loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_gen_token_response_with_policy(
+    handle: *const ServerHandle,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    over_limit_policy: u8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let over_limit = OverLimitPolicy::from_ffi(over_limit_policy)?;
+        unsafe { gen_token_response_impl(handle, token_request_cstr, max_nr, over_limit, None) }
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Shared body of the pp_server_gen_token_response FFI functions
+///
+/// # Safety
+///
+/// Callers must provide a valid handle and NUL terminated string pointer.
+unsafe fn gen_token_response_impl(
+    handle: *const ServerHandle,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    over_limit: OverLimitPolicy,
+    deadline: Option<Instant>,
+) -> Result<*const i8, Box<dyn std::error::Error>> {
+    let _timer = LatencyTimer::start(Operation::Issuance);
+    let handle = unsafe { handle_from_crystal(handle)? };
+    let token_request_bytes = unsafe {
+        decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+    };
+    if let Some(out) = over_limit_response(&token_request_bytes, max_nr, over_limit)? {
+        return Ok(out);
+    }
+    let issued = handle.issue(&token_request_bytes, max_nr, deadline)?;
+    Ok(encode_json_for_crystal(&issued.to_retval())?)
+}
+
+/// Like `gen_token_response_chunks`, using the keys loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_gen_token_response_chunks(
+    handle: *const ServerHandle,
+    token_request_cstr: *const i8,
+    max_nr: u16,
+    max_total: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let handle = unsafe { handle_from_crystal(handle)? };
+        let token_request_bytes = unsafe {
+            decode_untrusted_bytes_from_crystal(token_request_cstr, InputKind::TokenRequest)?
+        };
+        let signer = handle.issuance_signer(&token_request_bytes)?;
+        let out =
+            token_response_chunks_for_crystal(&token_request_bytes, max_nr, max_total, |chunk| {
+                issue_token_response_with_signer(signer, chunk)
+            })?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_token_responses`, using the keys loaded in `handle`, each TokenRequest being
+/// issued with the key it names
+#[no_mangle]
+pub extern "C" fn pp_server_gen_token_responses(
+    handle: *const ServerHandle,
+    token_requests_cstr: *const i8,
+    max_nr: u16,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        let handle = unsafe { handle_from_crystal(handle)? };
+        let token_requests_json = unsafe {
+            borrow_untrusted_str_from_crystal(token_requests_cstr, InputKind::TokenRequestBatch)?
+        };
+        let out = token_responses_for_crystal(token_requests_json, |token_request_bytes| {
+            handle.issue(token_request_bytes, max_nr, None)
+        })?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_token`, using the key loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_validate_token(
+    handle: *const ServerHandle,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        let handle = unsafe { handle_from_crystal(handle)? };
+        let token_encoded =
+            unsafe { borrow_untrusted_bytes_from_crystal(token_cstr, InputKind::Token)? };
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        // tokens are parsed again by validate_token_for_crystal, this only picks the key
+        let token_bytes = URL_SAFE.decode(token_encoded).unwrap_or_default();
+        validate_token_for_crystal(
+            handle.redemption_signer(&token_bytes)?,
+            token_encoded,
+            token_challenge_s,
+        )
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_tokens`, using the keys loaded in `handle`, each token being checked against
+/// the key it names. Only batched ristretto255 tokens are validated.
+#[no_mangle]
+pub extern "C" fn pp_server_validate_tokens(
+    handle: *const ServerHandle,
+    tokens_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let handle = unsafe { handle_from_crystal(handle)? };
+        let tokens_json =
+            unsafe { borrow_untrusted_str_from_crystal(tokens_cstr, InputKind::TokenBatch)? };
+        let tokens_encoded: Vec<&str> = serde_json::from_str(tokens_json)?;
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let challenge_digest = redemption_challenge_digest(token_challenge_s)?;
+        let out =
+            token_verdicts_for_crystal(&tokens_encoded, GroupTokenType as u16, |token_encoded| {
+                // tokens are parsed again by redeem_encoded_token, this only picks the key
+                let token_bytes = URL_SAFE.decode(token_encoded).unwrap_or_default();
+                redeem_encoded_token(
+                    handle.redemption_signer(&token_bytes)?,
+                    token_encoded,
+                    &challenge_digest,
+                )
+            })?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `validate_token_for_origins`, using the keys loaded in `handle`
+#[no_mangle]
+pub extern "C" fn pp_server_validate_token_for_origins(
+    handle: *const ServerHandle,
+    token_cstr: *const i8,
+    token_challenge_cstr: *const i8,
+    allowed_origins_cstr: *const i8,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        let allowed_origins_json =
+            unsafe { borrow_untrusted_str_from_crystal(allowed_origins_cstr, InputKind::Header)? };
+        let allowed_origins: Vec<String> = serde_json::from_str(allowed_origins_json)?;
+        check_challenge_origin(token_challenge_s, &allowed_origins)?;
+        let out = pp_server_validate_token(handle, token_cstr, token_challenge_cstr);
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+/// Like `gen_www_authenticate_header`, using the public key of the key loaded in `handle`
+/// NOTE: pass max_age = 0 for no max-age component in header
+#[no_mangle]
+pub extern "C" fn pp_server_gen_www_authenticate_header(
+    handle: *const ServerHandle,
+    token_challenge_cstr: *const i8,
+    max_age_u32: u32,
+) -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let handle = unsafe { handle_from_crystal(handle)? };
+        let token_challenge_s = unsafe {
+            borrow_untrusted_str_from_crystal(token_challenge_cstr, InputKind::TokenChallenge)?
+        };
+        www_authenticate_header_for_crystal(token_challenge_s, &handle.public_key, max_age_u32)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VoprfGroup;
+    use crate::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use voprf::VoprfServer;
+
+    fn retval(out: *const i8) -> serde_json::Value {
+        let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+        free_string(out);
+        serde_json::from_str(&out_s).unwrap()
+    }
+
+    #[test]
+    fn test_server_handle_lifecycle() {
+        let sk_bytes = voprf::derive_key::<VoprfGroup>(
+            &[3u8; 32],
+            crate::server::DEFAULT_KEY_INFO,
+            voprf::Mode::Voprf,
+        )
+        .unwrap()
+        .to_bytes();
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(sk_bytes)).unwrap();
+        let mut handle = std::ptr::null_mut();
+        assert_eq!(retval(pp_server_new(sk_cstr, &mut handle))["error"], "");
+        free_string(sk_cstr);
+        assert!(!handle.is_null());
+
+        let challenge = encode_string_for_crystal(
+            crate::PrivacyPass::gen_token_challenge()
+                .to_base64()
+                .unwrap(),
+        )
+        .unwrap();
+        let header = retval(pp_server_gen_www_authenticate_header(handle, challenge, 0));
+        assert!(header["retval"]
+            .as_str()
+            .unwrap()
+            .starts_with("PrivateToken"));
+
+        let token = encode_string_for_crystal("AAAA".to_string()).unwrap();
+        let validity = retval(pp_server_validate_token(handle, token, challenge));
+        assert_eq!(validity["retval"], "0");
+        free_string(token);
+        free_string(challenge);
+        pp_server_free(handle);
+
+        // null handles are errors rather than crashes
+        let error = retval(pp_server_validate_token(
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+        ));
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("null server handle"));
+    }
+
+    /// Secret key derived from `seed`, and its truncated key id
+    fn derived_key(seed: u8) -> (Vec<u8>, u8) {
+        let sk = voprf::derive_key::<VoprfGroup>(
+            &[seed; 32],
+            crate::server::DEFAULT_KEY_INFO,
+            voprf::Mode::Voprf,
+        )
+        .unwrap()
+        .to_bytes()
+        .to_vec();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&sk).unwrap();
+        let key_id = crate::server::public_key_to_truncated_token_key_id(server.get_public_key());
+        (sk, key_id)
+    }
+
+    #[test]
+    fn test_issuance_key_selection() {
+        use crate::config::batched_tokens_mod::{client::Client, TokenResponse};
+        use tls_codec::{Deserialize as _, Serialize as _};
+        use voprf::Group;
+
+        // two keys with distinct truncated key ids
+        let first = derived_key(5);
+        let second = (6..=u8::MAX)
+            .map(derived_key)
+            .find(|(_, key_id)| *key_id != first.1)
+            .unwrap();
+        let (sk_bytes, key_ids): (Vec<_>, Vec<_>) = [first, second].into_iter().unzip();
+        let sks_json = serde_json::to_string(
+            &sk_bytes
+                .iter()
+                .map(|sk| URL_SAFE.encode(sk))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let sks_cstr = encode_string_for_crystal(sks_json).unwrap();
+        let mut handle = std::ptr::null_mut();
+        assert_eq!(
+            retval(pp_server_new_with_keys(sks_cstr, &mut handle))["error"],
+            ""
+        );
+        free_string(sks_cstr);
+
+        // a TokenRequest naming the second key is issued with it, as its proof shows
+        let public_key = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes[1])
+            .unwrap()
+            .get_public_key();
+        let client = Client::new(public_key);
+        let blinds = vec![<VoprfGroup as Group>::Scalar::random(
+            &mut rand::rngs::OsRng,
+        )];
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(
+                &crate::PrivacyPass::gen_token_challenge(),
+                vec![[1u8; 32]],
+                blinds,
+            )
+            .unwrap();
+        let token_request_cstr = encode_string_for_crystal(
+            URL_SAFE.encode(token_request.tls_serialize_detached().unwrap()),
+        )
+        .unwrap();
+        let rv = retval(pp_server_gen_token_response(handle, token_request_cstr, 1));
+        free_string(token_request_cstr);
+        let token_response = URL_SAFE.decode(rv["retval"].as_str().unwrap()).unwrap();
+        let token_response =
+            TokenResponse::tls_deserialize(&mut token_response.as_slice()).unwrap();
+        assert!(client.issue_tokens(&token_response, &token_states).is_ok());
+        // while the first key's proof doesn't verify for it
+        let first_public_key = VoprfServer::<VoprfGroup>::new_with_key(&sk_bytes[0])
+            .unwrap()
+            .get_public_key();
+        assert!(Client::new(first_public_key)
+            .issue_tokens(&token_response, &token_states)
+            .is_err());
+
+        // a TokenRequest naming neither key
+        let unknown_key_id = (0..=u8::MAX).find(|id| !key_ids.contains(id)).unwrap();
+        let mut token_request = (crate::GroupTokenType as u16).to_be_bytes().to_vec();
+        token_request.push(unknown_key_id);
+        token_request.extend_from_slice(&32u16.to_be_bytes());
+        token_request.extend_from_slice(&[0u8; 32]);
+        let token_request_cstr =
+            encode_string_for_crystal(URL_SAFE.encode(&token_request)).unwrap();
+        let error = retval(pp_server_gen_token_response(handle, token_request_cstr, 1));
+        assert_eq!(error["code"], "unknown_key_id");
+        free_string(token_request_cstr);
+        pp_server_free(handle);
+    }
+
+    #[test]
+    fn test_batch_variants() {
+        use crate::config::batched_tokens_mod::{client::Client, TokenResponse};
+        use tls_codec::{Deserialize as _, Serialize as _};
+        use voprf::Group;
+
+        let (sk, key_id) = derived_key(7);
+        let sk_cstr = encode_string_for_crystal(URL_SAFE.encode(&sk)).unwrap();
+        let mut handle = std::ptr::null_mut();
+        assert_eq!(retval(pp_server_new(sk_cstr, &mut handle))["error"], "");
+        free_string(sk_cstr);
+
+        // a TokenRequest naming the key is issued, one naming another key is refused alone
+        let client = Client::new(
+            VoprfServer::<VoprfGroup>::new_with_key(&sk)
+                .unwrap()
+                .get_public_key(),
+        );
+        let blinds = vec![<VoprfGroup as Group>::Scalar::random(
+            &mut rand::rngs::OsRng,
+        )];
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(
+                &crate::PrivacyPass::gen_token_challenge(),
+                vec![[2u8; 32]],
+                blinds,
+            )
+            .unwrap();
+        let mut unknown_token_request = (crate::GroupTokenType as u16).to_be_bytes().to_vec();
+        unknown_token_request.push(key_id.wrapping_add(1));
+        unknown_token_request.extend_from_slice(&32u16.to_be_bytes());
+        unknown_token_request.extend_from_slice(&[0u8; 32]);
+        let token_requests_cstr = encode_string_for_crystal(
+            serde_json::to_string(&[
+                URL_SAFE.encode(token_request.tls_serialize_detached().unwrap()),
+                URL_SAFE.encode(&unknown_token_request),
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+        let rv = retval(pp_server_gen_token_responses(
+            handle,
+            token_requests_cstr,
+            1,
+        ));
+        free_string(token_requests_cstr);
+        let outcomes: serde_json::Value =
+            serde_json::from_str(rv["retval"].as_str().unwrap()).unwrap();
+        let token_response = URL_SAFE
+            .decode(outcomes[0]["retval"].as_str().unwrap())
+            .unwrap();
+        let token_response =
+            TokenResponse::tls_deserialize(&mut token_response.as_slice()).unwrap();
+        assert!(client.issue_tokens(&token_response, &token_states).is_ok());
+        assert_eq!(outcomes[1]["code"], "unknown_key_id");
+
+        let challenge = encode_string_for_crystal(
+            crate::PrivacyPass::gen_token_challenge()
+                .to_base64()
+                .unwrap(),
+        )
+        .unwrap();
+        let tokens = encode_string_for_crystal("[\"AAAA\"]".to_string()).unwrap();
+        let rv = retval(pp_server_validate_tokens(handle, tokens, challenge));
+        free_string(tokens);
+        let verdicts: serde_json::Value =
+            serde_json::from_str(rv["retval"].as_str().unwrap()).unwrap();
+        assert_eq!(verdicts[0]["retval"], "0");
+
+        // challenges bound to no origin are refused
+        let token = encode_string_for_crystal("AAAA".to_string()).unwrap();
+        let origins = encode_string_for_crystal("[\"a.example\"]".to_string()).unwrap();
+        let rv = retval(pp_server_validate_token_for_origins(
+            handle, token, challenge, origins,
+        ));
+        assert_ne!(rv["error"], "");
+        free_string(origins);
+        free_string(token);
+        free_string(challenge);
+        pp_server_free(handle);
+    }
+
+    #[test]
+    fn test_keys_sharing_a_truncated_key_id() {
+        // derived keys, until two of them share a truncated key id
+        let mut by_key_id = std::collections::HashMap::new();
+        let (first, second) = (0..=u8::MAX)
+            .map(derived_key)
+            .find_map(|(sk, key_id)| {
+                by_key_id
+                    .insert(key_id, sk.clone())
+                    .map(|first| (first, sk))
+            })
+            .unwrap();
+        for private_keys in [[first.clone(), first.clone()], [first, second]] {
+            let private_keys = private_keys.map(SecretSlice::from).to_vec();
+            let err = ServerHandle::with_keys(private_keys).err().unwrap();
+            assert!(
+                err.to_string().contains("share truncated key id"),
+                "{}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// -----------------------------------------------------------------------------
+// ----------------------  synchronous issuer API  -----------------------------
+// -----------------------------------------------------------------------------
+//
+// VOPRF operations are CPU-bound, so the `PrivacyPass` requirement of an async runtime (to
+// install keys in key stores and run issuance through privacypass-rust) is pure overhead for
+// callers without one, such as the Crystal integration. `PrivacyPassSync` offers the same
+// key generation, issuance and redemption calls, evaluating the blinded elements directly
+// with the VOPRF server, without tokio and without any `block_on`. As with `PrivacyPass`,
+// `validate_token` only checks tokens, and `redeem_token` also refuses replays. Shared nonce
+// stores are async, so `redeem_token` refuses to run while one is set rather than blocking
+// on it, see `redeem_nonce_sync`.
+
+use crate::audit::{record_redemption, RedemptionOutcome};
+use crate::config::{batched_tokens_mod, VoprfGroup};
+use crate::metrics::{LatencyTimer, Operation};
+use crate::replay::{check_sync_redemption, redeem_nonce_sync};
+use crate::server::{
+    check_key, check_redemption_challenge, issue_token_response_sync, public_key_to_token_key_id,
+    redemption_challenge_digest, sample_key_seed, verify_token_uniformly, GenKeysError,
+    GenTokenResponseError, KeyUse, RustKeypair, TokenRequestView, ValidateTokenError,
+    DEFAULT_KEY_INFO,
+};
+use batched_tokens_mod::{server::serialize_public_key, TokenRequest, TokenResponse};
+use kagippverify::token::token_nonce;
+use privacypass::{TokenType, TruncatedTokenKeyId};
+use rand::rngs::OsRng;
+use secrecy::{ExposeSecret, SecretBox};
+use tls_codec::Serialize as TlsSerializeTrait;
+use voprf::{derive_key, Mode, VoprfServer};
+
+pub struct PrivacyPassSync {
+    // VOPRF key derivation info, see DEFAULT_KEY_INFO
+    info: Vec<u8>,
+}
+
+impl PrivacyPassSync {
+    pub fn new() -> Self {
+        Self::with_info(DEFAULT_KEY_INFO)
+    }
+
+    /// Instance deriving keys under a custom info string, see `PrivacyPass::with_info`
+    pub fn with_info(info: &[u8]) -> Self {
+        PrivacyPassSync {
+            info: info.to_vec(),
+        }
+    }
+
+    pub fn info(&self) -> &[u8] {
+        &self.info
+    }
+
+    pub fn gen_keys(&self) -> Result<RustKeypair, GenKeysError> {
+        self.gen_keys_avoiding(&[])
+    }
+
+    /// Like `gen_keys`, but re-derives the key until its truncated key id is not one of `taken`
+    pub fn gen_keys_avoiding(
+        &self,
+        taken: &[TruncatedTokenKeyId],
+    ) -> Result<RustKeypair, GenKeysError> {
+        let seed = sample_key_seed(&mut OsRng, &self.info, taken)?;
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&seed, &self.info)
+            .map_err(GenKeysError::DeriveKey)?;
+        let secret_key = derive_key::<VoprfGroup>(&seed, &self.info, Mode::Voprf)
+            .map_err(GenKeysError::DeriveKey)?;
+
+        Ok(RustKeypair {
+            public_key: serialize_public_key(server.get_public_key()),
+            secret_key: SecretBox::init_with(|| secret_key.to_bytes()),
+            token_type: TokenType::BatchedTokenRistretto255,
+            info: self.info.clone(),
+        })
+    }
+
+    /// Same as `PrivacyPass::gen_token_response`, on the calling thread
+    pub fn gen_token_response(
+        &self,
+        private_key: &[u8],
+        token_request: TokenRequest,
+        max_requests: usize,
+    ) -> Result<TokenResponse, GenTokenResponseError> {
+        let _timer = LatencyTimer::start(Operation::Issuance);
+        if token_request.nr() > max_requests {
+            return Err(GenTokenResponseError::RequestedTooManyTokens(
+                token_request.nr(),
+                max_requests,
+            ));
+        }
+        let token_request_bytes = token_request
+            .tls_serialize_detached()
+            .map_err(GenTokenResponseError::Tls)?;
+        let token_request = TokenRequestView::try_from_bytes(&token_request_bytes)
+            .map_err(GenTokenResponseError::Tls)?;
+        issue_token_response_sync(private_key, &token_request)
+    }
+
+    /// Same as `PrivacyPass::validate_token`, on the calling thread: replays are not refused,
+    /// use `redeem_token` for that
+    pub fn validate_token(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+    ) -> Result<bool, ValidateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        check_redemption_challenge::<ValidateTokenError>(None)?;
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+            .map_err(ValidateTokenError::InvalidKey)?;
+        let token_key_id = public_key_to_token_key_id(server.get_public_key());
+        let [.., truncated_token_key_id] = token_key_id;
+        check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+        let valid = bool::from(verify_token_uniformly(&server, token, None));
+        record_redemption(
+            truncated_token_key_id,
+            RedemptionOutcome::from_validity(valid),
+        );
+        Ok(valid)
+    }
+
+    /// Same as `PrivacyPass::redeem_token`, on the calling thread: the nonces of valid tokens
+    /// are recorded, and tokens whose nonce was redeemed before are refused, see
+    /// `set_replay_protection`. Errors out while a shared nonce store is set, as its nonces
+    /// can only be recorded through the async API.
+    pub fn redeem_token(
+        &self,
+        token: &[u8],
+        private_key: &[u8],
+        token_challenge: Option<&str>,
+    ) -> Result<bool, ValidateTokenError> {
+        let _timer = LatencyTimer::start(Operation::Redemption);
+        check_redemption_challenge::<ValidateTokenError>(token_challenge)?;
+        // refused whatever the token, so that the error doesn't tell valid tokens apart
+        check_sync_redemption()?;
+        let challenge_digest = redemption_challenge_digest(token_challenge)?;
+        let server = VoprfServer::<VoprfGroup>::new_with_key(private_key)
+            .map_err(ValidateTokenError::InvalidKey)?;
+        let token_key_id = public_key_to_token_key_id(server.get_public_key());
+        let [.., truncated_token_key_id] = token_key_id;
+        check_key::<ValidateTokenError>(&token_key_id, KeyUse::Redemption)?;
+        let valid = bool::from(verify_token_uniformly(
+            &server,
+            token,
+            challenge_digest.as_deref(),
+        ));
+        // refuse replays, only recording nonces of valid tokens
+        let mut outcome = RedemptionOutcome::from_validity(valid);
+        if valid {
+            let redeemed = match token_nonce(token) {
+                Some(nonce) => redeem_nonce_sync(nonce, token_key_id)?,
+                None => false,
+            };
+            if !redeemed {
+                outcome = RedemptionOutcome::DoubleSpent;
+            }
+        }
+        record_redemption(truncated_token_key_id, outcome);
+        Ok(outcome == RedemptionOutcome::Valid)
+    }
+
+    /// Like `validate_token`, but first checks `keypair` was derived under this instance's info
+    pub fn validate_token_with_keypair(
+        &self,
+        token: &[u8],
+        keypair: &RustKeypair,
+    ) -> Result<bool, ValidateTokenError> {
+        if keypair.info != self.info {
+            return Err(ValidateTokenError::InfoMismatch {
+                expected: self.info.clone(),
+                found: keypair.info.clone(),
+            });
+        }
+        self.validate_token(token, keypair.secret_key.expose_secret())
+    }
+}
+
+impl Default for PrivacyPassSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrivacyPass;
+    use batched_tokens_mod::client::Client;
+    use batched_tokens_mod::server::deserialize_public_key;
+    use privacypass::Nonce;
+    use rand::RngCore;
+    use voprf::Group;
+
+    /// Random nonces, as redeemed nonces are recorded process-wide
+    fn random_nonces(nr: usize) -> Vec<Nonce> {
+        (0..nr)
+            .map(|_| {
+                let mut nonce = [0u8; 32];
+                OsRng.fill_bytes(&mut nonce);
+                nonce
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_issuance_and_redemption() {
+        let privacy_pass = PrivacyPassSync::new();
+        let keypair = privacy_pass.gen_keys().unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let public_key = deserialize_public_key(&keypair.public_key).unwrap();
+
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let nonces = random_nonces(3);
+        let blinds = (0..3)
+            .map(|_| <VoprfGroup as Group>::Scalar::random(&mut OsRng))
+            .collect();
+        let client = Client::new(public_key);
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(&token_challenge, nonces, blinds)
+            .unwrap();
+
+        let token_response = privacy_pass
+            .gen_token_response(sk, token_request, 3)
+            .unwrap();
+        // the client checks the batched DLEQ proof
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+        assert_eq!(tokens.len(), 3);
+        for token in tokens {
+            let token_bytes = token.tls_serialize_detached().unwrap();
+            assert!(privacy_pass
+                .validate_token_with_keypair(&token_bytes, &keypair)
+                .unwrap());
+            // validation doesn't spend the token, redemption does
+            assert!(privacy_pass.validate_token(&token_bytes, sk).unwrap());
+            assert!(privacy_pass.redeem_token(&token_bytes, sk, None).unwrap());
+            assert!(!privacy_pass.redeem_token(&token_bytes, sk, None).unwrap());
+        }
+        assert!(!privacy_pass.validate_token(&[0u8; 4], sk).unwrap());
+        assert!(!privacy_pass.redeem_token(&[0u8; 4], sk, None).unwrap());
+    }
+
+    #[test]
+    fn test_keys_outside_of_their_validity_window_are_refused() {
+        use crate::clock::{global_clock, Clock};
+        use crate::key_validity::{global_key_validities, KeyValidity, KeyValidityError};
+        use tls_codec::Deserialize;
+
+        let privacy_pass = PrivacyPassSync::new();
+        let keypair = privacy_pass.gen_keys().unwrap();
+        let sk = keypair.secret_key.expose_secret();
+        let public_key = deserialize_public_key(&keypair.public_key).unwrap();
+        let token_key_id = public_key_to_token_key_id(public_key);
+
+        let token_challenge = PrivacyPass::gen_token_challenge();
+        let client = Client::new(public_key);
+        let blinds = vec![<VoprfGroup as Group>::Scalar::random(&mut OsRng)];
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(&token_challenge, random_nonces(1), blinds)
+            .unwrap();
+        let token_request = token_request.tls_serialize_detached().unwrap();
+        let issue = || {
+            let token_request =
+                TokenRequest::tls_deserialize(&mut token_request.as_slice()).unwrap();
+            privacy_pass.gen_token_response(sk, token_request, 1)
+        };
+        let token_response = issue().unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+        let token_bytes = tokens[0].tls_serialize_detached().unwrap();
+
+        let now = global_clock().unix_seconds();
+        for (validity, not_yet_valid) in [
+            (KeyValidity::from_unix_seconds(now + 3_600, 0), true),
+            (KeyValidity::from_unix_seconds(0, now - 3_600), false),
+        ] {
+            global_key_validities().set(token_key_id, validity);
+            let issued = issue();
+            let redeemed = privacy_pass.validate_token(&token_bytes, sk);
+            let (
+                Err(GenTokenResponseError::KeyValidity(issuance_err)),
+                Err(ValidateTokenError::KeyValidity(redemption_err)),
+            ) = (issued, redeemed)
+            else {
+                panic!("key outside of its validity window was accepted");
+            };
+            for err in [issuance_err, redemption_err] {
+                assert_eq!(
+                    matches!(err, KeyValidityError::NotYetValid { .. }),
+                    not_yet_valid
+                );
+            }
+        }
+
+        global_key_validities().set(token_key_id, KeyValidity::default());
+        assert!(privacy_pass.validate_token(&token_bytes, sk).unwrap());
+    }
+}
@@ -0,0 +1,237 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  serverless issuer  -----------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuer side functions for serverless WebAssembly runtimes (Cloudflare Workers on
+// wasm32-unknown-unknown, WASI hosts on wasm32-wasip1), where the server module can't run:
+// it needs tokio, and its key and nonce stores assume a long-lived process. These functions
+// are synchronous, keep no state and take the secret key with every call, like the FFI:
+//   gen_keys             derives an issuer keypair from a fresh seed
+//   gen_token_response   evaluates a serialized TokenRequest into a serialized TokenResponse
+//   validate_token       checks a serialized token was issued with the secret key
+// The only platform requirement is randomness, taken from OsRng, i.e. getrandom: through its
+// js backend on wasm32-unknown-unknown, which the `serverless` feature enables, and through
+// random_get on WASI. See build_serverless.sh for both targets.
+// NOTE: only batched ristretto255 tokens are supported. Revocation, key validity windows,
+//       audit records and replay protection are server only: serverless callers must record
+//       the nonce of every accepted token (see `token_nonce`) in their own storage, e.g. a
+//       Workers KV namespace or a Durable Object, and refuse tokens whose nonce is recorded.
+
+use crate::config::{batched_tokens_mod, VoprfGroup};
+use crate::issuance::{
+    deserialize_blinded_elements, public_key_to_token_key_id, public_key_to_truncated_token_key_id,
+    serialize_token_response, DuplicateBlindedElementError, InvalidBlindedElementError,
+    TokenRequestView,
+};
+use batched_tokens_mod::server::serialize_public_key;
+use generic_array::GenericArray;
+use kagippverify::token::{Token, TOKEN_TYPE_BATCHED_RISTRETTO255};
+use privacypass::TokenType;
+use rand::{rngs::OsRng, RngCore};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use voprf::{derive_key, Group, Mode, VoprfServer};
+use zeroize::Zeroizing;
+
+pub use kagippverify::token::token_nonce;
+
+#[derive(Error, Debug)]
+pub enum ServerlessError {
+    #[error("invalid secret key")]
+    InvalidKey(#[source] voprf::Error),
+    #[error("failed to derive keypair")]
+    DeriveKey(#[source] voprf::Error),
+    #[error("malformed token request")]
+    MalformedTokenRequest(#[source] tls_codec::Error),
+    #[error("invalid token type")]
+    InvalidTokenType,
+    #[error("token request for an unknown key id {0}")]
+    UnknownKeyId(u8),
+    #[error("requested {0} tokens, the maximum is {1}")]
+    RequestedTooManyTokens(usize, usize),
+    #[error("repeated blinded element")]
+    DuplicateBlindedElement(#[from] DuplicateBlindedElementError),
+    #[error("invalid blinded element")]
+    InvalidBlindedElement(#[from] InvalidBlindedElementError),
+    #[error("failed to evaluate blinded elements")]
+    Evaluate(#[source] voprf::Error),
+    #[error("failed to serialize token response")]
+    TokenResponse(#[source] tls_codec::Error),
+}
+
+pub struct ServerlessKeypair {
+    /// serialized public key, as published in the issuer directory
+    pub public_key: Vec<u8>,
+    pub secret_key: Zeroizing<Vec<u8>>,
+}
+
+/// Derives a keypair from a fresh seed under the VOPRF key derivation `info`, see
+/// `DEFAULT_KEY_INFO` for the one of the server module
+pub fn gen_keys(info: &[u8]) -> Result<ServerlessKeypair, ServerlessError> {
+    let mut seed = Zeroizing::new(GenericArray::<_, <VoprfGroup as Group>::ScalarLen>::default());
+    OsRng.fill_bytes(&mut seed);
+    let server = VoprfServer::<VoprfGroup>::new_from_seed(&seed, info)
+        .map_err(ServerlessError::DeriveKey)?;
+    let secret_key =
+        derive_key::<VoprfGroup>(&seed, info, Mode::Voprf).map_err(ServerlessError::DeriveKey)?;
+    Ok(ServerlessKeypair {
+        public_key: serialize_public_key(server.get_public_key()).to_vec(),
+        secret_key: Zeroizing::new(secret_key.to_bytes().to_vec()),
+    })
+}
+
+/// Evaluates a serialized TokenRequest of at most `max_tokens` tokens with `secret_key`,
+/// returning the serialized TokenResponse. As in the server module, TokenRequests holding the
+/// identity or repeating a blinded element are refused before anything gets evaluated.
+pub fn gen_token_response(
+    secret_key: &[u8],
+    token_request: &[u8],
+    max_tokens: usize,
+) -> Result<Vec<u8>, ServerlessError> {
+    let token_request = TokenRequestView::try_from_bytes(token_request)
+        .map_err(ServerlessError::MalformedTokenRequest)?;
+    if token_request.token_type() != TokenType::BatchedTokenRistretto255 {
+        return Err(ServerlessError::InvalidTokenType);
+    }
+    if token_request.nr() > max_tokens {
+        return Err(ServerlessError::RequestedTooManyTokens(
+            token_request.nr(),
+            max_tokens,
+        ));
+    }
+
+    let server =
+        VoprfServer::<VoprfGroup>::new_with_key(secret_key).map_err(ServerlessError::InvalidKey)?;
+    if public_key_to_truncated_token_key_id(server.get_public_key())
+        != token_request.truncated_token_key_id()
+    {
+        return Err(ServerlessError::UnknownKeyId(
+            token_request.truncated_token_key_id(),
+        ));
+    }
+    if let Some(index) = token_request.first_duplicate() {
+        return Err(DuplicateBlindedElementError(index).into());
+    }
+    let blinded_elements = deserialize_blinded_elements(&token_request)?;
+    let evaluation = server
+        .batch_blind_evaluate(&mut OsRng, &blinded_elements)
+        .map_err(ServerlessError::Evaluate)?;
+    serialize_token_response(&evaluation.messages, &evaluation.proof)
+        .map_err(ServerlessError::TokenResponse)
+}
+
+/// Whether a serialized token was issued with `secret_key`, for the challenge whose
+/// serialization is `token_challenge` if given.
+/// NOTE: spent tokens are still valid, see the replay protection note above
+pub fn validate_token(
+    secret_key: &[u8],
+    token: &[u8],
+    token_challenge: Option<&[u8]>,
+) -> Result<bool, ServerlessError> {
+    let server =
+        VoprfServer::<VoprfGroup>::new_with_key(secret_key).map_err(ServerlessError::InvalidKey)?;
+    let Ok(token) = Token::parse(token) else {
+        return Ok(false);
+    };
+    if token.token_type != TOKEN_TYPE_BATCHED_RISTRETTO255
+        || *token.token_key_id != public_key_to_token_key_id(server.get_public_key())
+        || !token_challenge.map_or(true, |challenge| token.matches_challenge(challenge))
+    {
+        return Ok(false);
+    }
+    Ok(match server.evaluate(token.token_input()) {
+        Ok(authenticator) => bool::from(authenticator.as_slice().ct_eq(token.authenticator)),
+        Err(_) => false,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::client::{PrivacyPassClient, TokenResponse};
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use batched_tokens_mod::server::deserialize_public_key;
+    use batched_tokens_mod::NE;
+    use privacypass::auth::authenticate::TokenChallenge;
+    use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+
+    #[test]
+    fn test_serverless_issuer() {
+        let keypair = gen_keys(b"serverless test").unwrap();
+        let token_challenge = TokenChallenge::new(
+            TokenType::BatchedTokenRistretto255,
+            "issuer.example",
+            None,
+            &["origin.example".to_string()],
+        );
+        let client = PrivacyPassClient::new(
+            token_challenge.clone(),
+            deserialize_public_key(&keypair.public_key).unwrap(),
+        );
+        let (token_request, state) = client.token_request(2).unwrap();
+        let token_request = token_request.tls_serialize_detached().unwrap();
+
+        assert!(matches!(
+            gen_token_response(&keypair.secret_key, &token_request, 1),
+            Err(ServerlessError::RequestedTooManyTokens(2, 1))
+        ));
+        let token_response = gen_token_response(&keypair.secret_key, &token_request, 2).unwrap();
+        let token_response =
+            TokenResponse::tls_deserialize(&mut token_response.as_slice()).unwrap();
+        let tokens = client.finalize(&state, &token_response).unwrap();
+
+        let challenge = URL_SAFE
+            .decode(token_challenge.to_base64().unwrap())
+            .unwrap();
+        let token = tokens[0].tls_serialize_detached().unwrap();
+        assert!(validate_token(&keypair.secret_key, &token, Some(&challenge)).unwrap());
+        assert!(validate_token(&keypair.secret_key, &token, None).unwrap());
+        assert!(!validate_token(&keypair.secret_key, &token, Some(b"other challenge")).unwrap());
+        assert!(!validate_token(&keypair.secret_key, &token[1..], None).unwrap());
+
+        let other = gen_keys(b"serverless test").unwrap();
+        assert!(!validate_token(&other.secret_key, &token, None).unwrap());
+    }
+
+    #[test]
+    fn test_serverless_refuses_unsafe_blinded_elements() {
+        let keypair = gen_keys(b"serverless test").unwrap();
+        let server = VoprfServer::<VoprfGroup>::new_with_key(&keypair.secret_key).unwrap();
+        let truncated_token_key_id = public_key_to_truncated_token_key_id(server.get_public_key());
+        let token_request = |blinded_elements: &[[u8; NE]]| {
+            let mut bytes = TOKEN_TYPE_BATCHED_RISTRETTO255.to_be_bytes().to_vec();
+            bytes.push(truncated_token_key_id);
+            bytes.extend_from_slice(&((blinded_elements.len() * NE) as u16).to_be_bytes());
+            bytes.extend(blinded_elements.iter().flatten());
+            bytes
+        };
+        let element = serialize_public_key(server.get_public_key());
+        let element: [u8; NE] = element.as_slice().try_into().unwrap();
+
+        assert!(matches!(
+            gen_token_response(
+                &keypair.secret_key,
+                &token_request(&[element, [0u8; NE]]),
+                2
+            ),
+            Err(ServerlessError::InvalidBlindedElement(
+                InvalidBlindedElementError::Identity(1)
+            ))
+        ));
+        assert!(matches!(
+            gen_token_response(&keypair.secret_key, &token_request(&[element, element]), 2),
+            Err(ServerlessError::DuplicateBlindedElement(
+                DuplicateBlindedElementError(1)
+            ))
+        ));
+        assert!(gen_token_response(&keypair.secret_key, &token_request(&[element]), 1).is_ok());
+        assert!(matches!(
+            gen_token_response(&keypair.secret_key, &token_request(&[element])[1..], 1),
+            Err(ServerlessError::MalformedTokenRequest(_))
+        ));
+    }
+}
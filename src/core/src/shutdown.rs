@@ -0,0 +1,131 @@
+// -----------------------------------------------------------------------------
+// -------------------------  graceful shutdown  -------------------------------
+// -----------------------------------------------------------------------------
+//
+// Stores with pending writes register a Flush hook, as `set_shared_nonce_store` does for the
+// shared nonce store, and background tasks (pruning, key reload watchers) stop once
+// `shutdown_token()` is cancelled.
+// `shutdown()` stops the background tasks, waits for running crypto operations to complete,
+// then flushes every store, so that an embedding process can restart without losing replay
+// state. Calls made after a shutdown start over with a fresh token and no registered hooks.
+
+use crate::crypto_pool::{drain_crypto_pool, CryptoPoolError};
+use crate::crystal::{
+    encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+};
+use crate::runtime::ffi_runtime;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+#[async_trait]
+pub trait Flush: Send + Sync {
+    async fn flush(&self);
+}
+
+struct Lifecycle {
+    token: Option<CancellationToken>,
+    flush_hooks: Vec<Arc<dyn Flush>>,
+}
+
+static LIFECYCLE: Mutex<Lifecycle> = Mutex::new(Lifecycle {
+    token: None,
+    flush_hooks: Vec::new(),
+});
+
+fn with_lifecycle<T>(f: impl FnOnce(&mut Lifecycle) -> T) -> T {
+    // hooks and tokens are only ever added or taken whole, so a poisoned lock is still valid
+    f(&mut LIFECYCLE.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+/// Cancelled on shutdown, background tasks should stop once it is
+pub fn shutdown_token() -> CancellationToken {
+    with_lifecycle(|lifecycle| {
+        lifecycle
+            .token
+            .get_or_insert_with(CancellationToken::new)
+            .clone()
+    })
+}
+
+/// Registers a store to be flushed on shutdown
+pub fn register_flush(hook: Arc<dyn Flush>) {
+    with_lifecycle(|lifecycle| lifecycle.flush_hooks.push(hook));
+}
+
+/// Stops background tasks, waits for running crypto operations and flushes registered stores.
+/// Must be called from within a tokio runtime.
+pub async fn shutdown() -> Result<(), CryptoPoolError> {
+    let (token, flush_hooks) = with_lifecycle(|lifecycle| {
+        (
+            lifecycle.token.take(),
+            std::mem::take(&mut lifecycle.flush_hooks),
+        )
+    });
+    if let Some(token) = token {
+        token.cancel();
+    }
+    drain_crypto_pool().await?;
+    for hook in flush_hooks {
+        hook.flush().await;
+    }
+    Ok(())
+}
+
+/// Shuts the library down gracefully, see `PrivacyPass::shutdown`
+#[no_mangle]
+pub extern "C" fn pp_shutdown() -> *const i8 {
+    // NOTE: the value of result below would not be *const i8
+    //       if the begin_panic_handling and end_panic_handling macros where not there
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        ffi_runtime()?.block_on(shutdown())?;
+
+        let rv = JSONRetVal {
+            retval: "".to_string(),
+            error: "".to_string(),
+        };
+        let out = encode_json_for_crystal(&rv)?;
+
+        // always end like this
+        Ok::<*const i8, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingFlush(AtomicUsize);
+
+    #[async_trait]
+    impl Flush for CountingFlush {
+        async fn flush(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_tasks_and_flushes() {
+        let flushes = Arc::new(CountingFlush(AtomicUsize::new(0)));
+        register_flush(flushes.clone());
+        let token = shutdown_token();
+        let task = tokio::spawn(async move { token.cancelled().await });
+
+        shutdown().await.unwrap();
+        task.await.unwrap();
+        assert_eq!(flushes.0.load(Ordering::SeqCst), 1);
+
+        // hooks are only run once
+        shutdown().await.unwrap();
+        assert_eq!(flushes.0.load(Ordering::SeqCst), 1);
+        assert!(!shutdown_token().is_cancelled());
+    }
+}
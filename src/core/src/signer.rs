@@ -0,0 +1,119 @@
+// -----------------------------------------------------------------------------
+// ----------------------------  VOPRF signers  --------------------------------
+// -----------------------------------------------------------------------------
+//
+// Issuance and redemption only use the issuer secret key for two VOPRF operations:
+// evaluating blinded elements (proving they were evaluated with the published key), and
+// evaluating token inputs to check token authenticators. `VoprfSigner` abstracts over both,
+// so that the secret key doesn't have to be in the issuer's memory.
+// `VoprfServer` is the software signer, used by every FFI call taking a secret key and by
+// `pp_server_new`. With the `pkcs11` feature, `Pkcs11Signer` forwards both operations to a
+// PKCS#11 module holding the key, see `pp_server_new_from_pkcs11`.
+
+use crate::config::{batched_tokens_mod, VoprfGroup};
+use batched_tokens_mod::PublicKey;
+use rand::rngs::OsRng;
+use thiserror::Error;
+use voprf::{BlindedElement, VoprfServer, VoprfServerBatchEvaluateResult};
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("VOPRF evaluation failed")]
+    Voprf(#[from] voprf::Error),
+    #[cfg(feature = "pkcs11")]
+    #[error("PKCS#11 call failed")]
+    Pkcs11(#[from] cryptoki::error::Error),
+    #[cfg(feature = "pkcs11")]
+    #[error("no PKCS#11 token in slot {0}")]
+    SlotNotFound(usize),
+    #[cfg(feature = "pkcs11")]
+    #[error("no PKCS#11 key labelled {0:?}")]
+    KeyNotFound(String),
+    #[cfg(feature = "pkcs11")]
+    #[error("malformed signer response")]
+    InvalidResponse,
+    #[cfg(feature = "pkcs11")]
+    #[error("signer evaluation proof doesn't verify against its public key")]
+    InvalidProof,
+}
+
+/// VOPRF operations needing the issuer secret key
+pub trait VoprfSigner: Send + Sync {
+    fn public_key(&self) -> PublicKey;
+
+    /// Evaluates `blinded_elements`, with a DLEQ proof covering all of them
+    fn blind_evaluate(
+        &self,
+        blinded_elements: &[BlindedElement<VoprfGroup>],
+    ) -> Result<VoprfServerBatchEvaluateResult<VoprfGroup>, SignerError>;
+
+    /// VOPRF output for `input`, i.e. the authenticator of tokens whose token input it is
+    fn evaluate(&self, input: &[u8]) -> Result<Vec<u8>, SignerError>;
+}
+
+impl VoprfSigner for VoprfServer<VoprfGroup> {
+    fn public_key(&self) -> PublicKey {
+        self.get_public_key()
+    }
+
+    fn blind_evaluate(
+        &self,
+        blinded_elements: &[BlindedElement<VoprfGroup>],
+    ) -> Result<VoprfServerBatchEvaluateResult<VoprfGroup>, SignerError> {
+        // for a single element the batched proof is the one of `VoprfServer::blind_evaluate`,
+        // which skips the batch machinery
+        if let [blinded_element] = blinded_elements {
+            let evaluation = VoprfServer::blind_evaluate(self, &mut OsRng, blinded_element);
+            return Ok(VoprfServerBatchEvaluateResult {
+                messages: vec![evaluation.message],
+                proof: evaluation.proof,
+            });
+        }
+        // batch_blind_evaluate takes a sized collection
+        let blinded_elements = blinded_elements.to_vec();
+        Ok(self.batch_blind_evaluate(&mut OsRng, &blinded_elements)?)
+    }
+
+    fn evaluate(&self, input: &[u8]) -> Result<Vec<u8>, SignerError> {
+        Ok(VoprfServer::evaluate(self, input)?.to_vec())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voprf::VoprfClient;
+
+    #[test]
+    fn test_software_signer() {
+        let server = VoprfServer::<VoprfGroup>::new_from_seed(&[5u8; 32], b"signer").unwrap();
+        let signer: &dyn VoprfSigner = &server;
+        let input = b"token input";
+        let blind = VoprfClient::<VoprfGroup>::blind(input, &mut OsRng).unwrap();
+        let evaluation = signer.blind_evaluate(&[blind.message]).unwrap();
+        let output = blind
+            .state
+            .finalize(
+                input,
+                &evaluation.messages[0],
+                &evaluation.proof,
+                signer.public_key(),
+            )
+            .unwrap();
+        assert_eq!(signer.evaluate(input).unwrap(), output.to_vec());
+
+        let blinded_elements: Vec<_> = (0..3u8)
+            .map(|i| {
+                VoprfClient::<VoprfGroup>::blind(&[i], &mut OsRng)
+                    .unwrap()
+                    .message
+            })
+            .collect();
+        let evaluation = signer.blind_evaluate(&blinded_elements).unwrap();
+        assert_eq!(evaluation.messages.len(), 3);
+    }
+}
@@ -0,0 +1,31 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  test fixtures  ---------------------------------
+// -----------------------------------------------------------------------------
+//
+// Keys shared by the unit tests of the key format modules. Generating an RSA-2048 key takes
+// longer than the rest of those tests, so the blind RSA keypairs are generated once per test
+// binary.
+
+use crate::public_tokens::{gen_rsa_keys, RsaKeypair};
+use p384::NistP384;
+use std::sync::OnceLock;
+use voprf::{derive_key, Group, Mode, VoprfServer};
+
+/// Serialized P-384 secret and public keys derived from `seed`
+pub(crate) fn p384_keypair(seed: u8) -> (Vec<u8>, Vec<u8>) {
+    let secret_key = derive_key::<NistP384>(&[seed; 48], b"PrivacyPass", Mode::Voprf).unwrap();
+    let secret_key = NistP384::serialize_scalar(secret_key).to_vec();
+    let server = VoprfServer::<NistP384>::new_with_key(&secret_key).unwrap();
+    let public_key = NistP384::serialize_elem(server.get_public_key()).to_vec();
+    (secret_key, public_key)
+}
+
+/// Two distinct blind RSA keypairs
+pub(crate) fn rsa_keypairs() -> &'static [RsaKeypair; 2] {
+    static KEYPAIRS: OnceLock<[RsaKeypair; 2]> = OnceLock::new();
+    KEYPAIRS.get_or_init(|| [(); 2].map(|_| gen_rsa_keys().unwrap()))
+}
+
+pub(crate) fn rsa_keypair() -> &'static RsaKeypair {
+    &rsa_keypairs()[0]
+}
@@ -0,0 +1,226 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  token store  -----------------------------------
+// -----------------------------------------------------------------------------
+//
+// Client side cache of unspent tokens, kept per (issuer name, origin) pair so that a token is
+// only ever presented to the origins of the challenge it was issued for. Tokens are the
+// serialized tokens of `gen_token` / `PrivacyPassClient::finalize`, stored as opaque bytes and
+// taken back in the order they were added.
+// The store serializes to bytes, for callers persisting it between sessions (IndexedDB or
+// extension storage from WebAssembly):
+//   struct {
+//       uint8 version;
+//       struct {
+//           opaque issuer_name<0..2^16-1>;
+//           opaque origin_info<0..2^16-1>;
+//           opaque tokens<0..2^32-1>;    /* list of opaque token<0..2^16-1> */
+//       } entries<0..2^32-1>;
+//   }
+// NOTE: tokens are bearer credentials, whoever reads a serialized store can spend them.
+
+use std::collections::{BTreeMap, VecDeque};
+use thiserror::Error;
+use tls_codec::{
+    Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait, TlsByteVecU16, TlsVecU32,
+};
+use tls_codec_derive::{TlsDeserialize, TlsSerialize, TlsSize};
+
+const TOKEN_STORE_VERSION: u8 = 1;
+
+#[derive(Error, Debug)]
+pub enum TokenStoreError {
+    #[error("malformed token store")]
+    Malformed(#[from] tls_codec::Error),
+    #[error("unsupported token store version {0}")]
+    UnsupportedVersion(u8),
+    #[error("issuer name, origin or token longer than 65535 bytes")]
+    TooLong,
+    #[error("issuer name or origin is not UTF-8")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+}
+
+#[derive(TlsDeserialize, TlsSerialize, TlsSize)]
+struct StoredEntry {
+    issuer_name: TlsByteVecU16,
+    origin_info: TlsByteVecU16,
+    tokens: TlsVecU32<TlsByteVecU16>,
+}
+
+#[derive(TlsDeserialize, TlsSerialize, TlsSize)]
+struct StoredTokens {
+    version: u8,
+    entries: TlsVecU32<StoredEntry>,
+}
+
+/// Unspent tokens per (issuer name, origin) pair
+#[derive(Default)]
+pub struct TokenStore {
+    tokens: BTreeMap<(String, String), VecDeque<Vec<u8>>>,
+}
+
+impl TokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds tokens issued for the challenge of `issuer_name` and `origin`, to be taken after
+    /// those already stored for the pair
+    pub fn add_tokens<I: IntoIterator<Item = Vec<u8>>>(
+        &mut self,
+        issuer_name: &str,
+        origin: &str,
+        tokens: I,
+    ) -> Result<(), TokenStoreError> {
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        if issuer_name.len() > usize::from(u16::MAX)
+            || origin.len() > usize::from(u16::MAX)
+            || tokens
+                .iter()
+                .any(|token| token.len() > usize::from(u16::MAX))
+        {
+            return Err(TokenStoreError::TooLong);
+        }
+        if !tokens.is_empty() {
+            self.tokens
+                .entry((issuer_name.to_string(), origin.to_string()))
+                .or_default()
+                .extend(tokens);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the oldest token of the pair, if any is left
+    pub fn take_token(&mut self, issuer_name: &str, origin: &str) -> Option<Vec<u8>> {
+        let key = (issuer_name.to_string(), origin.to_string());
+        let tokens = self.tokens.get_mut(&key)?;
+        let token = tokens.pop_front();
+        if tokens.is_empty() {
+            self.tokens.remove(&key);
+        }
+        token
+    }
+
+    /// Number of tokens left for the pair
+    pub fn count(&self, issuer_name: &str, origin: &str) -> usize {
+        self.tokens
+            .get(&(issuer_name.to_string(), origin.to_string()))
+            .map_or(0, VecDeque::len)
+    }
+
+    /// Number of tokens left for all pairs
+    pub fn len(&self) -> usize {
+        self.tokens.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Drops the tokens of the pair, e.g. once the issuer rotated the key they were issued with
+    pub fn clear(&mut self, issuer_name: &str, origin: &str) {
+        self.tokens
+            .remove(&(issuer_name.to_string(), origin.to_string()));
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TokenStoreError> {
+        let entries = self
+            .tokens
+            .iter()
+            .map(|((issuer_name, origin), tokens)| StoredEntry {
+                issuer_name: TlsByteVecU16::new(issuer_name.as_bytes().to_vec()),
+                origin_info: TlsByteVecU16::new(origin.as_bytes().to_vec()),
+                tokens: TlsVecU32::new(
+                    tokens
+                        .iter()
+                        .map(|token| TlsByteVecU16::new(token.clone()))
+                        .collect(),
+                ),
+            })
+            .collect();
+        Ok(StoredTokens {
+            version: TOKEN_STORE_VERSION,
+            entries: TlsVecU32::new(entries),
+        }
+        .tls_serialize_detached()?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TokenStoreError> {
+        let mut bytes = bytes;
+        let stored = StoredTokens::tls_deserialize(&mut bytes)?;
+        if stored.version != TOKEN_STORE_VERSION {
+            return Err(TokenStoreError::UnsupportedVersion(stored.version));
+        }
+        if !bytes.is_empty() {
+            return Err(TokenStoreError::Malformed(tls_codec::Error::TrailingData));
+        }
+        let mut store = TokenStore::new();
+        for entry in stored.entries.into_vec() {
+            let issuer_name = std::str::from_utf8(entry.issuer_name.as_slice())?;
+            let origin = std::str::from_utf8(entry.origin_info.as_slice())?;
+            store.add_tokens(
+                issuer_name,
+                origin,
+                entry
+                    .tokens
+                    .into_vec()
+                    .into_iter()
+                    .map(|token| token.as_slice().to_vec()),
+            )?;
+        }
+        Ok(store)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_store() {
+        let mut store = TokenStore::new();
+        store
+            .add_tokens("issuer.example", "a.example", vec![vec![1], vec![2]])
+            .unwrap();
+        store
+            .add_tokens("issuer.example", "b.example", vec![vec![3]])
+            .unwrap();
+        assert_eq!(store.count("issuer.example", "a.example"), 2);
+        assert_eq!(store.count("issuer.example", "c.example"), 0);
+        assert_eq!(store.len(), 3);
+
+        let mut restored = TokenStore::from_bytes(&store.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            restored.take_token("issuer.example", "a.example"),
+            Some(vec![1])
+        );
+        assert_eq!(
+            restored.take_token("issuer.example", "a.example"),
+            Some(vec![2])
+        );
+        assert_eq!(restored.take_token("issuer.example", "a.example"), None);
+        assert_eq!(restored.count("issuer.example", "b.example"), 1);
+
+        let mut bytes = store.to_bytes().unwrap();
+        bytes[0] = 2;
+        assert!(matches!(
+            TokenStore::from_bytes(&bytes),
+            Err(TokenStoreError::UnsupportedVersion(2))
+        ));
+        assert!(TokenStore::from_bytes(&bytes[1..]).is_err());
+
+        // names are kept as they are, invalid UTF-8 is refused rather than replaced
+        let mut store = TokenStore::new();
+        store.add_tokens("issuer", "o", vec![vec![1]]).unwrap();
+        let mut bytes = store.to_bytes().unwrap();
+        let origin_at = bytes.iter().rposition(|byte| *byte == b'o').unwrap();
+        bytes[origin_at] = 0xff;
+        assert!(matches!(
+            TokenStore::from_bytes(&bytes),
+            Err(TokenStoreError::InvalidUtf8(_))
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+// -----------------------------------------------------------------------------
+// ---------------------------  tower origin  ----------------------------------
+// -----------------------------------------------------------------------------
+//
+// RFC 9577 origin side as a tower middleware: `PrivateTokenLayer` wraps the services of
+// protected routes (e.g. with axum's `Router::route_layer`) and lets requests through only
+// if the `Origin` of the layer redeems the token of their `Authorization` header. Other
+// requests get a 401 with the `WWW-Authenticate` challenge of the origin, so that clients
+// can fetch a token and retry.
+
+use crate::origin::Origin;
+use http::{header, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+fn unauthorized<B: Default>(origin: &Origin) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    if let Some(challenge) = origin.challenge() {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+/// Layer admitting only requests with a valid, unspent token of the issuer of its `Origin`
+#[derive(Clone)]
+pub struct PrivateTokenLayer {
+    origin: Arc<Origin>,
+}
+
+impl PrivateTokenLayer {
+    pub fn new(origin: Arc<Origin>) -> Self {
+        PrivateTokenLayer { origin }
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+}
+
+impl<S> Layer<S> for PrivateTokenLayer {
+    type Service = PrivateTokenService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PrivateTokenService {
+            inner,
+            origin: Arc::clone(&self.origin),
+        }
+    }
+}
+
+/// Service of `PrivateTokenLayer`, calling the inner service for authorized requests only
+#[derive(Clone)]
+pub struct PrivateTokenService<S> {
+    inner: S,
+    origin: Arc<Origin>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for PrivateTokenService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        // the clone is not ready, so keep the service polled ready and hand over the clone
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let origin = Arc::clone(&self.origin);
+        Box::pin(async move {
+            let authorization = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .map(|value| value.to_str().unwrap_or_default());
+            match origin.redeem(authorization).await {
+                Ok(()) => inner.call(request).await,
+                Err(_) => Ok(unauthorized(&origin)),
+            }
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batched_memory_stores::{MemoryKeyStoreRistretto255, MemoryNonceStore};
+    use crate::config::{batched_tokens_mod, GroupTokenType, VoprfGroup};
+    use crate::origin::OriginConfig;
+    use crate::NONCE_BYTES;
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use batched_tokens_mod::client::Client;
+    use batched_tokens_mod::server::{serialize_public_key, Server};
+    use privacypass::{auth::authenticate::TokenChallenge, Nonce};
+    use rand::{rngs::OsRng, RngCore};
+    use tls_codec::Serialize as TlsSerializeTrait;
+    use tower::{service_fn, ServiceExt};
+    use voprf::Group;
+
+    fn authorization(token: &[u8]) -> Request<()> {
+        Request::builder()
+            .header(
+                header::AUTHORIZATION,
+                format!("PrivateToken token={}", URL_SAFE.encode(token)),
+            )
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_private_token_layer() {
+        let key_store = Arc::new(MemoryKeyStoreRistretto255::default());
+        let public_key = Server::new()
+            .create_keypair_with_params(&*key_store, &[6u8; 32], b"tower origin")
+            .await
+            .unwrap();
+        let config = OriginConfig {
+            issuer_name: "issuer.example".to_string(),
+            origin_info: vec!["origin.example".to_string()],
+            token_key: serialize_public_key(public_key).to_vec(),
+        };
+        let token_challenge = TokenChallenge::new(
+            GroupTokenType,
+            &config.issuer_name,
+            None,
+            &config.origin_info,
+        );
+        let mut nonce: Nonce = [0u8; NONCE_BYTES];
+        OsRng.fill_bytes(&mut nonce);
+        let client = Client::new(public_key);
+        let (token_request, token_states) = client
+            .issue_token_request_with_params(
+                &token_challenge,
+                vec![nonce],
+                vec![<VoprfGroup as Group>::Scalar::random(&mut OsRng)],
+            )
+            .unwrap();
+        let token_response = Server::new()
+            .issue_token_response(&*key_store, token_request)
+            .await
+            .unwrap();
+        let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+        let token = tokens[0].tls_serialize_detached().unwrap();
+
+        let origin = Origin::new(config, key_store, Arc::new(MemoryNonceStore::default())).unwrap();
+        let layer = PrivateTokenLayer::new(Arc::new(origin));
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, std::convert::Infallible>(Response::new("protected".to_string()))
+        }));
+
+        let challenged = service.clone().oneshot(Request::new(())).await.unwrap();
+        assert_eq!(challenged.status(), StatusCode::UNAUTHORIZED);
+        assert!(challenged.headers()[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .starts_with("PrivateToken"));
+
+        let admitted = service
+            .clone()
+            .oneshot(authorization(&token))
+            .await
+            .unwrap();
+        assert_eq!(admitted.status(), StatusCode::OK);
+        assert_eq!(admitted.body(), "protected");
+
+        let replayed = service
+            .clone()
+            .oneshot(authorization(&token))
+            .await
+            .unwrap();
+        assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+
+        let mut tampered = token.clone();
+        tampered[token.len() - 1] ^= 1;
+        let rejected = service.oneshot(authorization(&tampered)).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,456 @@
+// -----------------------------------------------------------------------------
+// ---------------------  key transparency log  --------------------------------
+// -----------------------------------------------------------------------------
+//
+// Append-only, hash-chained log of every public key the issuer has published. Each entry
+// commits to the previous one, so an issuer handing some clients a different key (e.g. to
+// tag them) has to either log it, where anyone can spot it, or fork the log, which clients
+// remembering the last log they saw detect with `verify_log_extends`.
+// Issuers log every key they publish in the process-wide log: the key manager logs the keys
+// it installs and issuer directories the keys they list, as does Crystal with
+// `log_published_key` for the keys it serves otherwise. The log is served to clients (see
+// `get_transparency_log` and the axum issuer), and kept across restarts either by the host
+// (`get_transparency_log` / `load_transparency_log`) or in a file, with
+// `persist_transparency_log`.
+// The verification functions are shared by server and client builds (and exported by the
+// wasm and mobile FFI crates), while the process-wide log is server only.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+pub type LogHash = [u8; 32];
+
+/// prev_hash of the first entry
+pub const GENESIS_HASH: LogHash = [0u8; 32];
+
+const ENTRY_DOMAIN: &[u8] = b"kagipp transparency log entry";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub index: u64,
+    /// unix time (in seconds) at which the key was published
+    pub timestamp: u64,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex")]
+    pub prev_hash: LogHash,
+    #[serde(with = "hex")]
+    pub entry_hash: LogHash,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransparencyError {
+    #[error("entry at position {0} has index {1}")]
+    WrongIndex(u64, u64),
+    #[error("entry {0} does not chain to the previous entry")]
+    BrokenChain(u64),
+    #[error("entry {0} has a wrong hash")]
+    WrongHash(u64),
+    #[error("entry {0} is older than the previous entry")]
+    TimestampRegression(u64),
+    #[error("log does not extend the previously seen log")]
+    NotAnExtension,
+    #[error("public key is not in the log")]
+    KeyNotLogged,
+}
+
+pub fn entry_hash(index: u64, timestamp: u64, public_key: &[u8], prev_hash: &LogHash) -> LogHash {
+    let mut hasher = Sha256::new();
+    hasher.update(ENTRY_DOMAIN);
+    hasher.update(prev_hash);
+    hasher.update(index.to_be_bytes());
+    hasher.update(timestamp.to_be_bytes());
+    hasher.update((public_key.len() as u64).to_be_bytes());
+    hasher.update(public_key);
+    hasher.finalize().into()
+}
+
+/// Checks the whole chain, returning the hash of its last entry
+pub fn verify_log(entries: &[LogEntry]) -> Result<LogHash, TransparencyError> {
+    let mut head = GENESIS_HASH;
+    let mut last_timestamp = 0;
+    for (position, entry) in (0u64..).zip(entries) {
+        if entry.index != position {
+            return Err(TransparencyError::WrongIndex(position, entry.index));
+        }
+        if entry.prev_hash != head {
+            return Err(TransparencyError::BrokenChain(entry.index));
+        }
+        if entry.timestamp < last_timestamp {
+            return Err(TransparencyError::TimestampRegression(entry.index));
+        }
+        if entry_hash(
+            entry.index,
+            entry.timestamp,
+            &entry.public_key,
+            &entry.prev_hash,
+        ) != entry.entry_hash
+        {
+            return Err(TransparencyError::WrongHash(entry.index));
+        }
+        head = entry.entry_hash;
+        last_timestamp = entry.timestamp;
+    }
+    Ok(head)
+}
+
+/// Checks `entries` is a valid log extending a log previously seen by the client, given as
+/// its number of entries and head hash
+pub fn verify_log_extends(
+    entries: &[LogEntry],
+    seen_len: u64,
+    seen_head: &LogHash,
+) -> Result<LogHash, TransparencyError> {
+    let head = verify_log(entries)?;
+    let seen_head_now = match seen_len {
+        0 => Some(&GENESIS_HASH),
+        _ => usize::try_from(seen_len - 1)
+            .ok()
+            .and_then(|last| entries.get(last))
+            .map(|entry| &entry.entry_hash),
+    };
+    match seen_head_now == Some(seen_head) {
+        true => Ok(head),
+        false => Err(TransparencyError::NotAnExtension),
+    }
+}
+
+/// Client-side hook: checks `entries` is a valid log extending the one the client saw last, as
+/// by `verify_log_extends`, and that it contains `public_key`. Returns the new head and the
+/// entry logging the key.
+pub fn verify_key_in_log<'a>(
+    entries: &'a [LogEntry],
+    public_key: &[u8],
+    seen_len: u64,
+    seen_head: &LogHash,
+) -> Result<(LogHash, &'a LogEntry), TransparencyError> {
+    let head = verify_log_extends(entries, seen_len, seen_head)?;
+    let entry = entries
+        .iter()
+        .find(|entry| entry.public_key == public_key)
+        .ok_or(TransparencyError::KeyNotLogged)?;
+    Ok((head, entry))
+}
+
+#[derive(Default)]
+pub struct TransparencyLog {
+    entries: Vec<LogEntry>,
+}
+
+impl TransparencyLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a previously exported log, refusing it if its chain is broken
+    pub fn from_entries(entries: Vec<LogEntry>) -> Result<Self, TransparencyError> {
+        verify_log(&entries)?;
+        Ok(TransparencyLog { entries })
+    }
+
+    /// Logs `public_key` as published at `timestamp`. Publishing a logged key again returns
+    /// its existing entry, as the log records keys rather than publication events.
+    pub fn append(&mut self, public_key: &[u8], timestamp: u64) -> LogEntry {
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.public_key == public_key)
+        {
+            return entry.clone();
+        }
+        let (prev_hash, last_timestamp) = match self.entries.last() {
+            Some(last) => (last.entry_hash, last.timestamp),
+            None => (GENESIS_HASH, 0),
+        };
+        // timestamps never go backwards, even if the system clock does
+        let timestamp = timestamp.max(last_timestamp);
+        let index = self.entries.len() as u64;
+        let entry = LogEntry {
+            index,
+            timestamp,
+            public_key: public_key.to_vec(),
+            prev_hash,
+            entry_hash: entry_hash(index, timestamp, public_key, &prev_hash),
+        };
+        self.entries.push(entry.clone());
+        entry
+    }
+
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    pub fn head(&self) -> LogHash {
+        self.entries
+            .last()
+            .map_or(GENESIS_HASH, |entry| entry.entry_hash)
+    }
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use global::*;
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod global {
+    use super::{verify_log_extends, LogEntry, TransparencyError, TransparencyLog};
+    use crate::clock::{global_clock, Clock};
+    use crate::crystal::{
+        crystal_error, decode_string_from_crystal, decode_untrusted_bytes_from_crystal,
+        encode_json_for_crystal, error_chain_json_retval, error_json_retval, JSONRetVal,
+    };
+    use crate::limits::InputKind;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    pub enum TransparencyLogFileError {
+        #[error("failed to read or write the transparency log file")]
+        Io(#[from] std::io::Error),
+        #[error("malformed transparency log file")]
+        Json(#[from] serde_json::Error),
+        #[error("invalid transparency log")]
+        Log(#[from] TransparencyError),
+    }
+
+    struct GlobalLog {
+        log: TransparencyLog,
+        /// file the log is written to whenever it changes, see `persist_transparency_log`
+        file: Option<PathBuf>,
+        /// whether entries were logged since the log was last written to its file
+        unsaved: bool,
+    }
+
+    impl GlobalLog {
+        /// Replaces the log with `entries`, refusing logs whose chain is broken or that don't
+        /// extend the current log
+        fn replace(&mut self, entries: Vec<LogEntry>) -> Result<(), TransparencyError> {
+            // the empty log of a freshly started process is extended by any valid log
+            let seen_len = self.log.entries().len() as u64;
+            verify_log_extends(&entries, seen_len, &self.log.head())?;
+            self.log = TransparencyLog { entries };
+            self.unsaved = true;
+            Ok(())
+        }
+
+        fn save(&mut self) -> Result<(), TransparencyLogFileError> {
+            if let Some(path) = &self.file {
+                write_log_file(path, &serde_json::to_vec(self.log.entries())?)?;
+            }
+            self.unsaved = false;
+            Ok(())
+        }
+    }
+
+    static GLOBAL_LOG: Mutex<GlobalLog> = Mutex::new(GlobalLog {
+        log: TransparencyLog {
+            entries: Vec::new(),
+        },
+        file: None,
+        unsaved: false,
+    });
+
+    fn with_global_log<T>(f: impl FnOnce(&mut GlobalLog) -> T) -> T {
+        // entries are only ever pushed whole, so a poisoned log is still a valid chain
+        f(&mut GLOBAL_LOG.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Writes `bytes` to a temporary file, then renames it to `path`, so that a crash
+    /// mid-write never leaves a truncated log behind
+    fn write_log_file(path: &Path, bytes: &[u8]) -> Result<(), TransparencyLogFileError> {
+        use std::io::Write;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Keeps the process-wide log in the file at `path`: the log the file holds, if it
+    /// exists, is loaded as by `load_transparency_log`, and the log is written to it whenever
+    /// a key gets logged from then on. To be called at startup, before keys are published.
+    pub fn persist_transparency_log(
+        path: impl AsRef<Path>,
+    ) -> Result<(), TransparencyLogFileError> {
+        let path = path.as_ref();
+        with_global_log(|global_log| {
+            match std::fs::read(path) {
+                Ok(bytes) => global_log.replace(serde_json::from_slice(&bytes)?)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            global_log.file = Some(path.to_path_buf());
+            global_log.save()
+        })
+    }
+
+    /// Logs `public_key` in the process-wide log as published now
+    pub fn publish_key(public_key: &[u8]) -> Result<LogEntry, TransparencyLogFileError> {
+        publish_key_at(public_key, global_clock().unix_seconds())
+    }
+
+    /// Logs `public_key` in the process-wide log as published at `timestamp` (unix time, in
+    /// seconds), then writes the log to its file if it changed. A key that failed to be
+    /// written stays logged, the log being written again on the next publication.
+    pub fn publish_key_at(
+        public_key: &[u8],
+        timestamp: u64,
+    ) -> Result<LogEntry, TransparencyLogFileError> {
+        with_global_log(|global_log| {
+            let len = global_log.log.entries().len();
+            let entry = global_log.log.append(public_key, timestamp);
+            global_log.unsaved |= global_log.log.entries().len() != len;
+            if global_log.unsaved {
+                global_log.save()?;
+            }
+            Ok(entry)
+        })
+    }
+
+    pub fn global_log_entries() -> Vec<LogEntry> {
+        with_global_log(|global_log| global_log.log.entries().to_vec())
+    }
+
+    /// Logs a (base64 encoded) public key as published, to be called before serving it to
+    /// clients. Returns the JSON encoded log entry.
+    #[no_mangle]
+    pub extern "C" fn log_published_key(public_key_cstr: *const i8) -> *const i8 {
+        // NOTE: the value of result below would not be *const i8
+        //       if the begin_panic_handling and end_panic_handling macros where not there
+        begin_panic_handling!();
+        let result = panic::catch_unwind(|| {
+            let public_key =
+                unsafe { decode_untrusted_bytes_from_crystal(public_key_cstr, InputKind::Key)? };
+            let entry = publish_key(&public_key)?;
+
+            let rv = JSONRetVal {
+                retval: serde_json::to_string(&entry)?,
+                error: "".to_string(),
+            };
+            let out = encode_json_for_crystal(&rv)?;
+
+            // always end like this
+            Ok::<*const i8, Box<dyn std::error::Error>>(out)
+        });
+        end_panic_handling!();
+        result
+    }
+
+    /// Returns the process-wide log as a JSON array of entries, to be served to clients
+    #[no_mangle]
+    pub extern "C" fn get_transparency_log() -> *const i8 {
+        // NOTE: the value of result below would not be *const i8
+        //       if the begin_panic_handling and end_panic_handling macros where not there
+        begin_panic_handling!();
+        let result = panic::catch_unwind(|| {
+            let rv = JSONRetVal {
+                retval: serde_json::to_string(&global_log_entries())?,
+                error: "".to_string(),
+            };
+            let out = encode_json_for_crystal(&rv)?;
+
+            // always end like this
+            Ok::<*const i8, Box<dyn std::error::Error>>(out)
+        });
+        end_panic_handling!();
+        result
+    }
+
+    /// Replaces the process-wide log with a previously exported one (e.g. at startup),
+    /// refusing logs whose chain is broken or that don't extend the current log. The log
+    /// is written to its file, if persisted, see `persist_transparency_log`.
+    #[no_mangle]
+    pub extern "C" fn load_transparency_log(log_cstr: *const i8) -> *const i8 {
+        // NOTE: the value of result below would not be *const i8
+        //       if the begin_panic_handling and end_panic_handling macros where not there
+        begin_panic_handling!();
+        let result = panic::catch_unwind(|| {
+            let log_s = unsafe { decode_string_from_crystal(log_cstr)? };
+            let entries: Vec<LogEntry> = match serde_json::from_str(&log_s) {
+                Ok(entries) => Ok(entries),
+                Err(_) => Err(crystal_error("expected a JSON array of log entries")),
+            }?;
+            with_global_log(|global_log| {
+                global_log.replace(entries)?;
+                global_log.save()
+            })?;
+
+            let rv = JSONRetVal {
+                retval: "".to_string(),
+                error: "".to_string(),
+            };
+            let out = encode_json_for_crystal(&rv)?;
+
+            // always end like this
+            Ok::<*const i8, Box<dyn std::error::Error>>(out)
+        });
+        end_panic_handling!();
+        result
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_chain() {
+        let mut log = TransparencyLog::new();
+        log.append(b"key 1", 100);
+        let seen_len = log.entries().len() as u64;
+        let seen_head = log.head();
+        log.append(b"key 2", 200);
+        assert_eq!(log.append(b"key 1", 300).index, 0);
+        assert_eq!(log.entries().len(), 2);
+
+        assert_eq!(verify_log(log.entries()), Ok(log.head()));
+        assert_eq!(
+            verify_key_in_log(log.entries(), b"key 2", seen_len, &seen_head)
+                .unwrap()
+                .1
+                .index,
+            1
+        );
+        assert_eq!(
+            verify_key_in_log(log.entries(), b"key 3", seen_len, &seen_head).err(),
+            Some(TransparencyError::KeyNotLogged)
+        );
+        assert_eq!(
+            verify_log_extends(log.entries(), seen_len, &seen_head),
+            Ok(log.head())
+        );
+    }
+
+    #[test]
+    fn test_log_detects_tampering() {
+        let mut log = TransparencyLog::new();
+        log.append(b"key 1", 100);
+        log.append(b"key 2", 200);
+
+        let mut swapped = log.entries().to_vec();
+        swapped[1].public_key = b"evil key".to_vec();
+        assert_eq!(verify_log(&swapped), Err(TransparencyError::WrongHash(1)));
+
+        // a fork rewriting history does not extend the log clients saw before
+        let mut fork = TransparencyLog::new();
+        fork.append(b"evil key", 100);
+        fork.append(b"key 2", 200);
+        assert_eq!(
+            verify_log_extends(fork.entries(), 2, &log.head()),
+            Err(TransparencyError::NotAnExtension)
+        );
+        // nor is a key logged only in the fork accepted
+        assert_eq!(
+            verify_key_in_log(fork.entries(), b"evil key", 2, &log.head()).err(),
+            Some(TransparencyError::NotAnExtension)
+        );
+        assert!(TransparencyLog::from_entries(swapped).is_err());
+    }
+}
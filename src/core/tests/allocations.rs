@@ -0,0 +1,52 @@
+// Counts heap allocations performed while encoding FFI return values
+
+use kagippcore::crystal::{encode_json_for_crystal, free_string, JSONRetVal};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_encode_json_for_crystal_allocates_once() {
+    let rv = JSONRetVal {
+        retval: "A".repeat(64 * 1024),
+        error: "".to_string(),
+    };
+
+    // the first call on a thread grows the pooled scratch buffer
+    free_string(encode_json_for_crystal(&rv).unwrap());
+
+    let mut out = std::ptr::null();
+    let allocations = allocations_during(|| out = encode_json_for_crystal(&rv).unwrap());
+    free_string(out);
+    assert_eq!(allocations, 1);
+}
@@ -0,0 +1,67 @@
+// Validation of batched P-384 tokens in bulk, in a test binary of its own as redeemed nonces
+// are recorded in the process-wide nonce store
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+use kagippcore::metrics::metrics_snapshot;
+use kagippcore::server::validate_tokens;
+use kagippcore::PrivacyPass;
+use p384::NistP384;
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::TokenType;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use voprf::VoprfServer;
+
+/// JSON return value of an FFI function
+fn retval(out: *const i8) -> serde_json::Value {
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    serde_json::from_str(&out_s).unwrap()
+}
+
+#[tokio::test]
+async fn test_validate_tokens_of_the_batch_group() {
+    let keypair = PrivacyPass::new().gen_keys_batched_p384().await.unwrap();
+    let server = VoprfServer::<NistP384>::new_with_key(keypair.secret_key.expose_secret()).unwrap();
+    let token_challenge = TokenChallenge::new(
+        TokenType::BatchedTokenP384,
+        "issuer.example",
+        None,
+        &["origin.example".to_string()],
+    );
+
+    // token_type || nonce || challenge_digest || token_key_id || authenticator
+    let mut token = (TokenType::BatchedTokenP384 as u16).to_be_bytes().to_vec();
+    token.extend([7u8; 32]);
+    token.extend(token_challenge.digest().unwrap());
+    token.extend(Sha256::digest(&keypair.public_key));
+    let authenticator = server.evaluate(&token).unwrap();
+    token.extend(authenticator);
+    let mut ristretto255_token = token.clone();
+    ristretto255_token[..2].copy_from_slice(&5u16.to_be_bytes());
+    let tokens = [
+        URL_SAFE.encode(&token),
+        URL_SAFE.encode(&token),
+        URL_SAFE.encode(&ristretto255_token),
+    ];
+
+    let inputs = [
+        URL_SAFE.encode(keypair.secret_key.expose_secret()),
+        serde_json::to_string(&tokens).unwrap(),
+        token_challenge.to_base64().unwrap(),
+    ]
+    .map(|input| encode_string_for_crystal(input).unwrap());
+    let redemptions = metrics_snapshot().redemption_latency.count;
+    let rv = retval(validate_tokens(inputs[0], inputs[1], inputs[2]));
+    inputs.into_iter().for_each(free_string);
+    let verdicts: Vec<serde_json::Value> =
+        serde_json::from_str(rv["retval"].as_str().unwrap()).unwrap();
+
+    // the second copy of the token is a replay of the first
+    let retvals: Vec<_> = verdicts.iter().map(|verdict| &verdict["retval"]).collect();
+    assert_eq!(retvals, ["1", "0", ""]);
+    assert_eq!(verdicts[2]["code"], "unsupported_token_type");
+    // every token is timed as a redemption of its own
+    assert_eq!(metrics_snapshot().redemption_latency.count, redemptions + 3);
+}
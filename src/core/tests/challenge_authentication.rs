@@ -0,0 +1,101 @@
+// Redemptions while challenges are authenticated, in a test binary of its own as the MAC key
+// is process-wide and would refuse the unauthenticated challenges of the other tests
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::challenge_authentication::{
+    authenticated_token_challenge, gen_authenticated_token_challenge, set_challenge_mac_key,
+    verify_challenge_mac, ChallengeAuthenticationError,
+};
+use kagippcore::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+use kagippcore::{GroupTokenType, PrivacyPass, PrivacyPassSync, RustKeypair, ValidateTokenError};
+use kagippverify::token::TOKEN_TYPE_PRIVATE_P384;
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::batched_tokens_ristretto255::{client::Client, server::deserialize_public_key};
+use rand::{rngs::OsRng, RngCore};
+use secrecy::ExposeSecret;
+use tls_codec::Serialize as TlsSerializeTrait;
+use voprf::{Group, Ristretto255};
+
+const MAC_KEY: [u8; 32] = [7u8; 32];
+
+/// Token of `keypair` for `token_challenge`
+async fn token_for_challenge(
+    privacy_pass: &PrivacyPass,
+    keypair: &RustKeypair,
+    token_challenge: &TokenChallenge,
+) -> Vec<u8> {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let client = Client::new(deserialize_public_key(&keypair.public_key).unwrap());
+    let (token_request, token_states) = client
+        .issue_token_request_with_params(
+            token_challenge,
+            vec![nonce],
+            vec![<Ristretto255 as Group>::Scalar::random(&mut OsRng)],
+        )
+        .unwrap();
+    let token_response = privacy_pass
+        .gen_token_response(keypair.secret_key.expose_secret(), token_request, 1)
+        .await
+        .unwrap();
+    let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+    tokens[0].tls_serialize_detached().unwrap()
+}
+
+#[tokio::test]
+async fn test_redemptions_are_refused_without_an_authenticated_challenge() {
+    set_challenge_mac_key(Some(&MAC_KEY)).unwrap();
+    let privacy_pass = PrivacyPass::new();
+    let keypair = privacy_pass.gen_keys().await.unwrap();
+    let sk = keypair.secret_key.expose_secret();
+    let origin_info = ["origin.example".to_string()];
+
+    let token_challenge = TokenChallenge::new(GroupTokenType, "issuer.example", None, &origin_info);
+    let token = token_for_challenge(&privacy_pass, &keypair, &token_challenge).await;
+    let token_challenge_s = token_challenge.to_base64().unwrap();
+    // without their challenge, tokens can't be checked for a MAC
+    for result in [
+        privacy_pass.redeem_token(&token, sk, None).await,
+        privacy_pass.validate_token(&token, sk).await,
+        PrivacyPassSync::new().validate_token(&token, sk),
+        privacy_pass
+            .redeem_token(&token, sk, Some(&token_challenge_s))
+            .await,
+    ] {
+        assert!(matches!(
+            result,
+            Err(ValidateTokenError::ChallengeAuthentication(
+                ChallengeAuthenticationError::NotAuthenticated
+            ))
+        ));
+    }
+
+    let token_challenge =
+        authenticated_token_challenge(GroupTokenType, "issuer.example", &origin_info).unwrap();
+    let token = token_for_challenge(&privacy_pass, &keypair, &token_challenge).await;
+    assert!(privacy_pass
+        .redeem_token(&token, sk, Some(&token_challenge.to_base64().unwrap()))
+        .await
+        .unwrap());
+}
+
+#[test]
+fn test_authenticated_challenges_carry_their_token_type() {
+    set_challenge_mac_key(Some(&MAC_KEY)).unwrap();
+    let inputs = ["issuer.example", "origin.example"]
+        .map(|input| encode_string_for_crystal(input.to_string()).unwrap());
+    let out = gen_authenticated_token_challenge(TOKEN_TYPE_PRIVATE_P384, inputs[0], inputs[1]);
+    inputs.into_iter().for_each(free_string);
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    let retval: serde_json::Value = serde_json::from_str(&out_s).unwrap();
+
+    let token_challenge = URL_SAFE.decode(retval["retval"].as_str().unwrap()).unwrap();
+    assert_eq!(
+        kagippverify::TokenChallenge::parse(&token_challenge)
+            .unwrap()
+            .token_type,
+        TOKEN_TYPE_PRIVATE_P384
+    );
+    assert_eq!(verify_challenge_mac(&MAC_KEY, &token_challenge), Ok(()));
+}
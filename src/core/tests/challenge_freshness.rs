@@ -0,0 +1,110 @@
+// Redemptions while challenges are checked for freshness, in a test binary of its own as the
+// check is process-wide and would refuse the un-timestamped challenges of the other tests
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::challenge_freshness::{
+    set_challenge_max_age, timestamped_redemption_context, ChallengeFreshnessError,
+};
+use kagippcore::clock::{global_clock, Clock};
+use kagippcore::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+use kagippcore::{GroupTokenType, PrivacyPass, PrivacyPassSync, RustKeypair, ValidateTokenError};
+use privacypass::auth::authenticate::TokenChallenge;
+use privacypass::batched_tokens_ristretto255::{client::Client, server::deserialize_public_key};
+use rand::{rngs::OsRng, RngCore};
+use secrecy::ExposeSecret;
+use tls_codec::Serialize as TlsSerializeTrait;
+use voprf::{Group, Ristretto255};
+
+const MAX_AGE: u64 = 60;
+
+/// Token of `keypair` for a challenge issued at `issued_at`, along with the base64 challenge
+async fn token_for_challenge(
+    privacy_pass: &PrivacyPass,
+    keypair: &RustKeypair,
+    issued_at: u64,
+) -> (Vec<u8>, String) {
+    let token_challenge = TokenChallenge::new(
+        GroupTokenType,
+        "issuer.example",
+        Some(timestamped_redemption_context(issued_at)),
+        &["origin.example".to_string()],
+    );
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let client = Client::new(deserialize_public_key(&keypair.public_key).unwrap());
+    let (token_request, token_states) = client
+        .issue_token_request_with_params(
+            &token_challenge,
+            vec![nonce],
+            vec![<Ristretto255 as Group>::Scalar::random(&mut OsRng)],
+        )
+        .unwrap();
+    let token_response = privacy_pass
+        .gen_token_response(keypair.secret_key.expose_secret(), token_request, 1)
+        .await
+        .unwrap();
+    let tokens = client.issue_tokens(&token_response, &token_states).unwrap();
+    (
+        tokens[0].tls_serialize_detached().unwrap(),
+        token_challenge.to_base64().unwrap(),
+    )
+}
+
+/// Error code of the validate_token FFI function for `token` and `token_challenge`
+fn ffi_validation_code(keypair: &RustKeypair, token: &[u8], token_challenge: &str) -> String {
+    let inputs = [
+        URL_SAFE.encode(keypair.secret_key.expose_secret()),
+        URL_SAFE.encode(token),
+        token_challenge.to_string(),
+    ]
+    .map(|input| encode_string_for_crystal(input).unwrap());
+    let out = kagippcore::server::validate_token(inputs[0], inputs[1], inputs[2]);
+    inputs.into_iter().for_each(free_string);
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    let retval: serde_json::Value = serde_json::from_str(&out_s).unwrap();
+    retval["code"].as_str().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn test_redemptions_are_refused_without_a_fresh_challenge() {
+    set_challenge_max_age(MAX_AGE);
+    let privacy_pass = PrivacyPass::new();
+    let keypair = privacy_pass.gen_keys().await.unwrap();
+    let sk = keypair.secret_key.expose_secret();
+    let now = global_clock().unix_seconds();
+
+    // without their challenge, tokens can't be checked for freshness
+    let (token, token_challenge) = token_for_challenge(&privacy_pass, &keypair, now).await;
+    for result in [
+        privacy_pass.redeem_token(&token, sk, None).await,
+        privacy_pass.validate_token(&token, sk).await,
+        PrivacyPassSync::new().validate_token(&token, sk),
+    ] {
+        assert!(matches!(
+            result,
+            Err(ValidateTokenError::ChallengeFreshness(
+                ChallengeFreshnessError::NotTimestamped
+            ))
+        ));
+    }
+    assert!(privacy_pass
+        .redeem_token(&token, sk, Some(&token_challenge))
+        .await
+        .unwrap());
+
+    let (stale_token, stale_challenge) =
+        token_for_challenge(&privacy_pass, &keypair, now - 2 * MAX_AGE).await;
+    assert!(matches!(
+        privacy_pass
+            .redeem_token(&stale_token, sk, Some(&stale_challenge))
+            .await,
+        Err(ValidateTokenError::ChallengeFreshness(
+            ChallengeFreshnessError::Stale { .. }
+        ))
+    ));
+    assert_eq!(
+        ffi_validation_code(&keypair, &stale_token, &stale_challenge),
+        "challenge_stale"
+    );
+}
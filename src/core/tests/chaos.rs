@@ -0,0 +1,47 @@
+// Soak test: sustained issuance and redemption under injected store faults and restarts
+
+use kagippcore::chaos::{run_soak, FaultConfig, SoakConfig};
+use std::time::Duration;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn soak_accepts_no_double_spends_and_loses_no_keys() {
+    for seed in 0..4 {
+        let report = run_soak(SoakConfig {
+            faults: FaultConfig {
+                failure_rate: 0.1,
+                max_latency: Duration::from_millis(1),
+            },
+            seed,
+            ..SoakConfig::default()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.double_spends_accepted, 0, "{report:?}");
+        assert_eq!(report.keys_lost, 0, "{report:?}");
+        assert!(report.accepted > 0, "{report:?}");
+        assert!(report.restarts > 0, "{report:?}");
+        assert!(report.injected_failures > 0, "{report:?}");
+        // failed key lookups fail the redemption instead of being treated as spent tokens
+        assert!(report.failed > 0, "{report:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn soak_without_faults_fails_nothing() {
+    let report = run_soak(SoakConfig {
+        faults: FaultConfig {
+            failure_rate: 0.0,
+            max_latency: Duration::ZERO,
+        },
+        ..SoakConfig::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(report.injected_failures, 0, "{report:?}");
+    assert_eq!(report.failed, 0, "{report:?}");
+    assert_eq!(report.failed_issuances, 0, "{report:?}");
+    assert_eq!(report.keys_lost, 0, "{report:?}");
+    assert_eq!(report.accepted, report.issued, "{report:?}");
+}
@@ -0,0 +1,99 @@
+// Issuance of TokenRequests repeating a blinded element while they are deduplicated, in a
+// test binary of its own as the policy is process-wide
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::client::{PrivacyPassClient, TokenRequestState, TokenResponse};
+use kagippcore::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+use kagippcore::server::{
+    gen_token_response, set_duplicate_element_policy, DuplicateElementPolicy,
+};
+use kagippcore::PrivacyPass;
+use p384::NistP384;
+use privacypass::batched_tokens_ristretto255::{client::Client, server::deserialize_public_key};
+use privacypass::TokenType;
+use rand::rngs::OsRng;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use voprf::{derive_key, Group, Mode, Ristretto255, VoprfServer};
+
+#[tokio::test]
+async fn test_deduplicated_token_requests_round_trip() {
+    set_duplicate_element_policy(DuplicateElementPolicy::Deduplicate);
+    let privacy_pass = PrivacyPass::new();
+    let keypair = privacy_pass.gen_keys().await.unwrap();
+    let sk = keypair.secret_key.expose_secret();
+    let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+    let client = PrivacyPassClient::from_www_authenticate_header(header.to_str().unwrap()).unwrap();
+
+    // the first and last tokens share their nonce and blind, and so their blinded element
+    let nonces = [[1u8; 32], [2u8; 32], [1u8; 32], [3u8; 32]];
+    let [a, b, c] = [(); 3].map(|_| <Ristretto255 as Group>::Scalar::random(&mut OsRng));
+    let blinds = [a, b, a, c];
+    let (token_request, _) = Client::new(deserialize_public_key(&keypair.public_key).unwrap())
+        .issue_token_request_with_params(client.token_challenge(), nonces.to_vec(), blinds.to_vec())
+        .unwrap();
+    let state = TokenRequestState::from_json(
+        &serde_json::json!({
+            "nonces_s": nonces.map(hex::encode),
+            "blinds_s": blinds.map(|blind| hex::encode(blind.to_bytes())),
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let inputs = [
+        URL_SAFE.encode(sk),
+        URL_SAFE.encode(token_request.tls_serialize_detached().unwrap()),
+    ]
+    .map(|input| encode_string_for_crystal(input).unwrap());
+    let out = gen_token_response(inputs[0], inputs[1], 4);
+    inputs.into_iter().for_each(free_string);
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    let retval: serde_json::Value = serde_json::from_str(&out_s).unwrap();
+    assert_eq!(retval["dropped"], 1);
+    assert_eq!(retval["kept"], serde_json::json!([0, 1, 3]));
+
+    let token_response = URL_SAFE.decode(retval["retval"].as_str().unwrap()).unwrap();
+    let token_response = TokenResponse::tls_deserialize(&mut token_response.as_slice()).unwrap();
+    let tokens = client.finalize(&state, &token_response).unwrap();
+    assert_eq!(tokens.len(), 3);
+    for token in tokens {
+        let token = token.tls_serialize_detached().unwrap();
+        assert!(privacy_pass.validate_token(&token, sk).await.unwrap());
+    }
+}
+
+#[test]
+fn test_dropped_batched_p384_blinded_elements() {
+    set_duplicate_element_policy(DuplicateElementPolicy::Deduplicate);
+    let [secret_key, other_secret_key] =
+        [[4u8; 48], [5u8; 48]].map(|seed| derive_key::<NistP384>(&seed, b"", Mode::Voprf).unwrap());
+    let [a, b] = [secret_key, other_secret_key].map(|secret_key| {
+        let server =
+            VoprfServer::<NistP384>::new_with_key(&NistP384::serialize_scalar(secret_key)).unwrap();
+        NistP384::serialize_elem(server.get_public_key()).to_vec()
+    });
+
+    // public keys are valid blinded elements, the first one being repeated
+    let mut token_request = (TokenType::BatchedTokenP384 as u16).to_be_bytes().to_vec();
+    token_request.push(*Sha256::digest(&a).last().unwrap());
+    token_request.extend(((3 * a.len()) as u16).to_be_bytes());
+    token_request.extend([a.as_slice(), a.as_slice(), b.as_slice()].concat());
+
+    let inputs = [
+        URL_SAFE.encode(NistP384::serialize_scalar(secret_key)),
+        URL_SAFE.encode(&token_request),
+    ]
+    .map(|input| encode_string_for_crystal(input).unwrap());
+    let out = gen_token_response(inputs[0], inputs[1], 2);
+    inputs.into_iter().for_each(free_string);
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    let retval: serde_json::Value = serde_json::from_str(&out_s).unwrap();
+    // the last element is past the limit and the second repeats the first
+    assert_eq!(retval["error"], "");
+    assert_eq!(retval["dropped"], 2);
+    assert_eq!(retval["kept"], serde_json::json!([0]));
+}
@@ -0,0 +1,92 @@
+// Feeds arbitrary inputs to the FFI functions, checking none of them reaches the panic handler
+
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::crystal::{decode_string_from_crystal, encode_string_for_crystal, free_string};
+use proptest::prelude::*;
+
+/// Reads and frees an FFI return value, returning its error message
+fn retval_error(out: *const i8) -> String {
+    let out_s = unsafe { decode_string_from_crystal(out) }.unwrap();
+    free_string(out);
+    let retval: serde_json::Value = serde_json::from_str(&out_s).unwrap();
+    retval["error"].as_str().unwrap_or_default().to_string()
+}
+
+fn call_with_strings(inputs: &[String], f: impl FnOnce(&[*const i8]) -> *const i8) -> String {
+    let cstrs: Vec<_> = inputs
+        .iter()
+        .map(|input| encode_string_for_crystal(input.clone()).unwrap())
+        .collect();
+    let error = retval_error(f(&cstrs));
+    cstrs.into_iter().for_each(free_string);
+    error
+}
+
+/// Printable strings, and base64 encoded bytes, which get past the decoding step
+fn ffi_input() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[ -~]{0,128}",
+        prop::collection::vec(any::<u8>(), 0..256).prop_map(|bytes| URL_SAFE.encode(bytes)),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn server_ffi_never_panics(
+        sk in ffi_input(),
+        token in ffi_input(),
+        token_request in ffi_input(),
+        token_challenge in ffi_input(),
+        max_nr in any::<u16>(),
+        max_age in any::<u32>(),
+    ) {
+        let inputs = [sk, token, token_request, token_challenge];
+        let errors = [
+            call_with_strings(&inputs, |c| kagippcore::server::validate_token(c[0], c[1], c[3])),
+            call_with_strings(&inputs, |c| {
+                kagippcore::server::gen_token_response(c[0], c[2], max_nr)
+            }),
+            call_with_strings(&inputs, |c| kagippcore::server::gen_token_challenge(c[1], c[2])),
+            call_with_strings(&inputs, |c| {
+                kagippcore::server::gen_www_authenticate_header(c[3], c[0], max_age)
+            }),
+        ];
+        for error in errors {
+            prop_assert_ne!(error, "panic");
+        }
+    }
+
+    #[test]
+    fn client_ffi_never_panics(
+        header in ffi_input(),
+        client_state in ffi_input(),
+        token_response in ffi_input(),
+        nr in any::<u16>(),
+    ) {
+        let inputs = [header, client_state, token_response];
+        let errors = [
+            call_with_strings(&inputs, |c| unsafe {
+                kagippcore::client::gen_token_request(c[0], nr)
+            }),
+            call_with_strings(&inputs, |c| unsafe {
+                kagippcore::client::gen_token(c[0], c[1], c[2])
+            }),
+        ];
+        for error in errors {
+            prop_assert_ne!(error, "panic");
+        }
+    }
+}
+
+#[test]
+fn null_pointers_are_errors() {
+    let null = std::ptr::null();
+    let error = retval_error(kagippcore::server::validate_token(null, null, null));
+    assert!(!error.is_empty());
+    assert_ne!(error, "panic");
+    let error = retval_error(unsafe { kagippcore::client::gen_token_request(null, 1) });
+    assert!(!error.is_empty());
+    assert_ne!(error, "panic");
+}
@@ -0,0 +1,42 @@
+// Shutdown flushes the shared nonce store registered for replay protection
+
+use async_trait::async_trait;
+use kagippcore::nonce_store::{AtomicNonceStore, Nonce, NonceStore};
+use kagippcore::replay::set_shared_nonce_store;
+use kagippcore::shutdown::shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct CountingNonceStore {
+    flushes: AtomicUsize,
+}
+
+#[async_trait]
+impl NonceStore for CountingNonceStore {
+    async fn exists(&self, _nonce: &Nonce) -> bool {
+        false
+    }
+
+    async fn insert(&self, _nonce: Nonce) {}
+
+    async fn flush(&self) {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl AtomicNonceStore for CountingNonceStore {
+    async fn insert_if_absent(&self, _nonce: Nonce) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn shutdown_flushes_the_shared_nonce_store() {
+    let nonce_store = Arc::new(CountingNonceStore::default());
+    set_shared_nonce_store(nonce_store.clone());
+
+    shutdown().await.unwrap();
+    assert_eq!(nonce_store.flushes.load(Ordering::SeqCst), 1);
+}
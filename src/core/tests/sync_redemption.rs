@@ -0,0 +1,44 @@
+// Synchronous redemptions while a shared nonce store is set, in a test binary of its own as
+// the store is process-wide
+
+use kagippcore::batched_memory_stores::MemoryNonceStore;
+use kagippcore::client::PrivacyPassClient;
+use kagippcore::replay::set_shared_nonce_store;
+use kagippcore::{PrivacyPass, PrivacyPassSync, ValidateTokenError};
+use secrecy::ExposeSecret;
+use std::sync::Arc;
+use tls_codec::Serialize as TlsSerializeTrait;
+
+#[tokio::test]
+async fn test_sync_redemption_refuses_shared_nonce_stores() {
+    let privacy_pass = PrivacyPass::new();
+    let keypair = privacy_pass.gen_keys().await.unwrap();
+    let sk = keypair.secret_key.expose_secret();
+    let (_, header) = PrivacyPass::gen_www_authenticate_header(&keypair.public_key).unwrap();
+    let client = PrivacyPassClient::from_www_authenticate_header(header.to_str().unwrap()).unwrap();
+    let (token_request, state) = client.token_request(1).unwrap();
+    let token_response = privacy_pass
+        .gen_token_response(sk, token_request, 1)
+        .await
+        .unwrap();
+    let tokens = client.finalize(&state, &token_response).unwrap();
+    let token = tokens[0].tls_serialize_detached().unwrap();
+
+    set_shared_nonce_store(Arc::new(MemoryNonceStore::default()));
+
+    // refused rather than blocking on the shared store from within the runtime
+    let privacy_pass_sync = PrivacyPassSync::new();
+    assert!(matches!(
+        privacy_pass_sync.redeem_token(&token, sk, None),
+        Err(ValidateTokenError::SharedNonceStore(_))
+    ));
+    assert!(matches!(
+        privacy_pass_sync.redeem_token(&[0u8; 4], sk, None),
+        Err(ValidateTokenError::SharedNonceStore(_))
+    ));
+    assert!(privacy_pass_sync.validate_token(&token, sk).unwrap());
+
+    // async redemptions record the nonce in the shared store
+    assert!(privacy_pass.redeem_token(&token, sk, None).await.unwrap());
+    assert!(!privacy_pass.redeem_token(&token, sk, None).await.unwrap());
+}
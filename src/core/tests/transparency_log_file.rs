@@ -0,0 +1,35 @@
+// Keeping the transparency log in a file, in a test binary of its own as the log is
+// process-wide
+
+use kagippcore::transparency::{
+    global_log_entries, persist_transparency_log, verify_key_in_log, LogEntry, TransparencyLog,
+};
+use kagippcore::{KeyManager, KeyManagerConfig};
+
+fn write_log(path: &std::path::Path, log: &TransparencyLog) {
+    std::fs::write(path, serde_json::to_vec(log.entries()).unwrap()).unwrap();
+}
+
+#[test]
+fn test_transparency_log_file() {
+    let path = std::env::temp_dir().join(format!("kagipp-transparency-{}", std::process::id()));
+    let mut previous = TransparencyLog::new();
+    previous.append(b"key of a previous run", 100);
+    write_log(&path, &previous);
+
+    // the log of the previous run is loaded, then extended with the keys installed
+    persist_transparency_log(&path).unwrap();
+    let key_manager = KeyManager::new(KeyManagerConfig::default());
+    let public_key = key_manager.rotate().unwrap().public_key;
+    let entries: Vec<LogEntry> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    let (_, entry) = verify_key_in_log(&entries, &public_key, 1, &previous.head()).unwrap();
+    assert_eq!(entry.index, 1);
+    assert_eq!(entries, global_log_entries());
+
+    // a file holding a fork of the log is refused
+    let mut fork = TransparencyLog::new();
+    fork.append(b"evil key", 100);
+    write_log(&path, &fork);
+    assert!(persist_transparency_log(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
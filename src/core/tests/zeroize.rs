@@ -0,0 +1,96 @@
+// Checks that secrets are wiped before their memory is handed back to the allocator
+
+use kagippcore::crystal::{
+    decode_secret_bytes_from_crystal, encode_string_for_crystal, free_string,
+};
+use kagippcore::limits::InputKind;
+use kagippcore::PrivacyPass;
+use secrecy::{ExposeSecret, SecretSlice};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+struct InspectingAllocator;
+
+const NOT_FREED: u8 = 0;
+const FREED_WIPED: u8 = 1;
+const FREED_DIRTY: u8 = 2;
+
+// address of the allocation to inspect when freed, and what it held at that point
+static WATCHED: AtomicUsize = AtomicUsize::new(0);
+static WATCHED_STATE: AtomicU8 = AtomicU8::new(NOT_FREED);
+// tests run in parallel, but only one allocation can be watched at a time
+static WATCH_LOCK: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for InspectingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if ptr as usize == WATCHED.load(Ordering::SeqCst) {
+            // the allocation is still live here, so reading it is fine
+            let bytes = std::slice::from_raw_parts(ptr, layout.size());
+            let state = match bytes.iter().all(|byte| *byte == 0) {
+                true => FREED_WIPED,
+                false => FREED_DIRTY,
+            };
+            WATCHED_STATE.store(state, Ordering::SeqCst);
+            WATCHED.store(0, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: InspectingAllocator = InspectingAllocator;
+
+/// Drops `value` and reports whether the heap allocation at `ptr` was wiped beforehand
+fn wiped_on_free<T>(value: T, ptr: *const u8) -> bool {
+    let _guard = WATCH_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    WATCHED_STATE.store(NOT_FREED, Ordering::SeqCst);
+    WATCHED.store(ptr as usize, Ordering::SeqCst);
+    drop(value);
+    let state = WATCHED_STATE.load(Ordering::SeqCst);
+    assert_ne!(state, NOT_FREED, "watched allocation was not freed");
+    state == FREED_WIPED
+}
+
+#[test]
+fn test_plain_vec_is_not_wiped() {
+    // makes sure the allocator actually sees leftover secrets
+    let secret = vec![0xAAu8; 32];
+    let ptr = secret.as_ptr();
+    assert!(!wiped_on_free(secret, ptr));
+}
+
+#[test]
+fn test_secret_slice_is_wiped() {
+    let secret = SecretSlice::from(vec![0xAAu8; 32]);
+    let ptr = secret.expose_secret().as_ptr();
+    assert!(wiped_on_free(secret, ptr));
+}
+
+#[test]
+fn test_decoded_secret_key_is_wiped() {
+    let sk_cstr =
+        encode_string_for_crystal("qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqo=".to_string())
+            .expect("failed to encode secret key");
+    let secret = unsafe { decode_secret_bytes_from_crystal(sk_cstr, InputKind::Key) }
+        .expect("failed to decode secret key");
+    free_string(sk_cstr);
+
+    assert_eq!(secret.expose_secret(), [0xAAu8; 32].as_slice());
+    let ptr = secret.expose_secret().as_ptr();
+    assert!(wiped_on_free(secret, ptr));
+}
+
+#[tokio::test]
+async fn test_generated_secret_key_is_wiped() {
+    let keypair = PrivacyPass::new()
+        .gen_keys()
+        .await
+        .expect("failed to generate keys");
+    let ptr = keypair.secret_key.expose_secret().as_ptr();
+    assert!(wiped_on_free(keypair, ptr));
+}
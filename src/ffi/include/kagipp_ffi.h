@@ -49,6 +49,82 @@ int8_t *privacy_pass_token_finalization(const int8_t *www_authenticate_header,
                                         const int8_t *client_state,
                                         const int8_t *token_response);
 
+/**
+ * Build the Authorization header presenting a finalized token
+ *
+ * # Parameters
+ * - `token`: one of the base64 tokens returned by privacy_pass_token_finalization
+ *
+ * # Returns
+ * JSON string containing the header value, to be sent as `Authorization: <header>`:
+ * {
+ *   "header": "PrivateToken token=\"<base64-token>\"",
+ *   "error": ""
+ * }
+ *
+ * # Safety
+ * - Caller must pass a valid null-terminated C string for token
+ * - Caller MUST call privacy_pass_free_string on the returned pointer
+ * - Returns null pointer on catastrophic failure
+ */
+int8_t *privacy_pass_authorization_header(const int8_t *token);
+
+/**
+ * Check the issuer's key is in its transparency log, and that the log extends the one
+ * seen last time, so that an issuer handing this client its own key gets caught
+ *
+ * # Parameters
+ * - `log`: the JSON transparency log served by the issuer
+ * - `public_key`: the issuer's key, base64url encoded as in the token-key of the
+ *   WWW-Authenticate header
+ * - `seen_len`: `len` of the previous check, 0 on the first check
+ * - `seen_head`: `head` of the previous check, empty on the first check
+ *
+ * # Returns
+ * JSON string containing the entry logging the key, and the log to remember:
+ * {
+ *   "index": 3,
+ *   "len": 4,
+ *   "head": "<hex-encoded-hash>",
+ *   "error": ""
+ * }
+ *
+ * # Safety
+ * - `log`, `public_key` and `seen_head` must be valid null-terminated C strings
+ * - Caller MUST call privacy_pass_free_string on the returned pointer
+ * - Returns null pointer on catastrophic failure
+ */
+int8_t *privacy_pass_verify_key_transparency(const int8_t *log,
+                                             const int8_t *public_key,
+                                             uint64_t seen_len,
+                                             const int8_t *seen_head);
+
+/**
+ * Check a transparency log is valid and extends the one seen last time, e.g. when
+ * refreshing the remembered log without checking a key
+ *
+ * # Parameters
+ * - `log`: the JSON transparency log served by the issuer
+ * - `seen_len`: `len` of the previous check, 0 on the first check
+ * - `seen_head`: `head` of the previous check, empty on the first check
+ *
+ * # Returns
+ * JSON string containing the log to remember:
+ * {
+ *   "len": 4,
+ *   "head": "<hex-encoded-hash>",
+ *   "error": ""
+ * }
+ *
+ * # Safety
+ * - `log` and `seen_head` must be valid null-terminated C strings
+ * - Caller MUST call privacy_pass_free_string on the returned pointer
+ * - Returns null pointer on catastrophic failure
+ */
+int8_t *privacy_pass_verify_log_extends(const int8_t *log,
+                                        uint64_t seen_len,
+                                        const int8_t *seen_head);
+
 /**
  * Free a string allocated by Rust
  *
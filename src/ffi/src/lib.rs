@@ -7,9 +7,11 @@ use std::os::raw::c_char;
 mod types;
 pub use types::*;
 
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use kagippcore::crystal::{
     decode_string_from_crystal, encode_string_for_crystal, JSONRetVal,
 };
+use kagippcore::transparency::{verify_key_in_log, verify_log_extends, LogEntry, LogHash};
 
 // -----------------------------------------------------------------------------
 // --------------------------  Helper Functions  -------------------------------
@@ -56,6 +58,21 @@ fn create_error_response(error_msg: &str) -> String {
     .to_string()
 }
 
+/// Parse a JSON transparency log, as served by the issuer
+fn parse_log(log_json: &str) -> Result<Vec<LogEntry>, String> {
+    serde_json::from_str(log_json).map_err(|e| format!("Failed to parse log JSON: {}", e))
+}
+
+/// Parse the hex head of the log seen last time, ignored when none was seen yet
+fn parse_seen_head(seen_len: u64, seen_head: &str) -> Result<LogHash, String> {
+    let mut head = LogHash::default();
+    if seen_len > 0 {
+        hex::decode_to_slice(seen_head, &mut head)
+            .map_err(|e| format!("Invalid seen log head: {}", e))?;
+    }
+    Ok(head)
+}
+
 // -----------------------------------------------------------------------------
 // --------------------------  Public FFI API  ---------------------------------
 // -----------------------------------------------------------------------------
@@ -259,6 +276,191 @@ pub unsafe extern "C" fn privacy_pass_token_finalization(
     }
 }
 
+/// Build the Authorization header presenting a finalized token
+///
+/// # Parameters
+/// - `token`: one of the base64 tokens returned by privacy_pass_token_finalization
+///
+/// # Returns
+/// JSON string containing the header value, to be sent as `Authorization: <header>`:
+/// {
+///   "header": "PrivateToken token=\"<base64-token>\"",
+///   "error": ""
+/// }
+///
+/// # Safety
+/// - Caller must pass a valid null-terminated C string for token
+/// - Caller MUST call privacy_pass_free_string on the returned pointer
+/// - Returns null pointer on catastrophic failure
+#[no_mangle]
+pub unsafe extern "C" fn privacy_pass_authorization_header(token: *const i8) -> *mut i8 {
+    begin_panic_handling!();
+
+    let result = panic::catch_unwind(|| {
+        // Convert to format expected by core library
+        let token_s = unsafe { c_char_to_string(token)? };
+        let token_cstr = encode_string_for_crystal(token_s)
+            .map_err(|e| format!("Failed to encode token: {}", e))?;
+
+        // Call core library function
+        let header_cstr = unsafe { kagippcore::client::gen_authorization_header(token_cstr) };
+
+        // Free intermediate C string (already consumed by gen_authorization_header)
+        unsafe { free_rust_cstr(token_cstr); }
+
+        // Parse result from core library
+        let result_json = unsafe { decode_string_from_crystal(header_cstr) };
+        // Free intermediate C string (already read by decode_string_from_crystal)
+        unsafe { free_rust_cstr(header_cstr); }
+        let result_json = result_json
+            .map_err(|e| format!("Failed to decode core library response: {}", e))?;
+        let result_obj: JSONRetVal = serde_json::from_str(&result_json)
+            .map_err(|e| format!("Failed to parse core library result: {}", e))?;
+
+        if !result_obj.error.is_empty() {
+            return Ok(string_to_c_char(create_error_response(&result_obj.error)));
+        }
+
+        let response = AuthorizationHeader {
+            header: result_obj.retval,
+            error: String::new(),
+        };
+        let response_json = serde_json::to_string(&response)
+            .map_err(|e| format!("Failed to serialize response: {}", e))?;
+
+        Ok::<*mut i8, String>(string_to_c_char(response_json))
+    });
+
+    match result {
+        Ok(Ok(ptr)) => ptr,
+        Ok(Err(e)) => string_to_c_char(create_error_response(&e)),
+        Err(_) => string_to_c_char(create_error_response("Panic occurred in authorization_header")),
+    }
+}
+
+/// Check the issuer's key is in its transparency log, and that the log extends the one
+/// seen last time, so that an issuer handing this client its own key gets caught
+///
+/// # Parameters
+/// - `log`: the JSON transparency log served by the issuer
+/// - `public_key`: the issuer's key, base64url encoded as in the token-key of the
+///   WWW-Authenticate header
+/// - `seen_len`: `len` of the previous check, 0 on the first check
+/// - `seen_head`: `head` of the previous check, empty on the first check
+///
+/// # Returns
+/// JSON string containing the entry logging the key, and the log to remember:
+/// {
+///   "index": 3,
+///   "len": 4,
+///   "head": "<hex-encoded-hash>",
+///   "error": ""
+/// }
+///
+/// # Safety
+/// - `log`, `public_key` and `seen_head` must be valid null-terminated C strings
+/// - Caller MUST call privacy_pass_free_string on the returned pointer
+/// - Returns null pointer on catastrophic failure
+#[no_mangle]
+pub unsafe extern "C" fn privacy_pass_verify_key_transparency(
+    log: *const i8,
+    public_key: *const i8,
+    seen_len: u64,
+    seen_head: *const i8,
+) -> *mut i8 {
+    begin_panic_handling!();
+
+    let result = panic::catch_unwind(|| {
+        // Parse inputs
+        let log_json = unsafe { c_char_to_string(log)? };
+        let public_key_s = unsafe { c_char_to_string(public_key)? };
+        let seen_head_s = unsafe { c_char_to_string(seen_head)? };
+
+        let entries = parse_log(&log_json)?;
+        let public_key = URL_SAFE
+            .decode(public_key_s)
+            .map_err(|e| format!("Invalid public key: {}", e))?;
+        let seen_head = parse_seen_head(seen_len, &seen_head_s)?;
+
+        let (head, entry) = verify_key_in_log(&entries, &public_key, seen_len, &seen_head)
+            .map_err(|e| format!("Key transparency check failed: {}", e))?;
+
+        let response = KeyTransparencyCheck {
+            index: entry.index,
+            len: entries.len() as u64,
+            head: hex::encode(head),
+            error: String::new(),
+        };
+        let response_json = serde_json::to_string(&response)
+            .map_err(|e| format!("Failed to serialize response: {}", e))?;
+
+        Ok::<*mut i8, String>(string_to_c_char(response_json))
+    });
+
+    match result {
+        Ok(Ok(ptr)) => ptr,
+        Ok(Err(e)) => string_to_c_char(create_error_response(&e)),
+        Err(_) => string_to_c_char(create_error_response("Panic occurred in verify_key_transparency")),
+    }
+}
+
+/// Check a transparency log is valid and extends the one seen last time, e.g. when
+/// refreshing the remembered log without checking a key
+///
+/// # Parameters
+/// - `log`: the JSON transparency log served by the issuer
+/// - `seen_len`: `len` of the previous check, 0 on the first check
+/// - `seen_head`: `head` of the previous check, empty on the first check
+///
+/// # Returns
+/// JSON string containing the log to remember:
+/// {
+///   "len": 4,
+///   "head": "<hex-encoded-hash>",
+///   "error": ""
+/// }
+///
+/// # Safety
+/// - `log` and `seen_head` must be valid null-terminated C strings
+/// - Caller MUST call privacy_pass_free_string on the returned pointer
+/// - Returns null pointer on catastrophic failure
+#[no_mangle]
+pub unsafe extern "C" fn privacy_pass_verify_log_extends(
+    log: *const i8,
+    seen_len: u64,
+    seen_head: *const i8,
+) -> *mut i8 {
+    begin_panic_handling!();
+
+    let result = panic::catch_unwind(|| {
+        // Parse inputs
+        let log_json = unsafe { c_char_to_string(log)? };
+        let seen_head_s = unsafe { c_char_to_string(seen_head)? };
+
+        let entries = parse_log(&log_json)?;
+        let seen_head = parse_seen_head(seen_len, &seen_head_s)?;
+
+        let head = verify_log_extends(&entries, seen_len, &seen_head)
+            .map_err(|e| format!("Log check failed: {}", e))?;
+
+        let response = LogExtensionCheck {
+            len: entries.len() as u64,
+            head: hex::encode(head),
+            error: String::new(),
+        };
+        let response_json = serde_json::to_string(&response)
+            .map_err(|e| format!("Failed to serialize response: {}", e))?;
+
+        Ok::<*mut i8, String>(string_to_c_char(response_json))
+    });
+
+    match result {
+        Ok(Ok(ptr)) => ptr,
+        Ok(Err(e)) => string_to_c_char(create_error_response(&e)),
+        Err(_) => string_to_c_char(create_error_response("Panic occurred in verify_log_extends")),
+    }
+}
+
 /// Free a string allocated by Rust
 ///
 /// # Safety
@@ -316,4 +518,46 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_verify_key_transparency() {
+        use kagippcore::transparency::TransparencyLog;
+
+        let mut log = TransparencyLog::new();
+        log.append(b"key 1", 100);
+        log.append(b"key 2", 200);
+        let log_json = CString::new(serde_json::to_string(log.entries()).unwrap()).unwrap();
+        let public_key = CString::new(URL_SAFE.encode(b"key 2")).unwrap();
+        let seen_head = CString::new("").unwrap();
+
+        let check_ptr = unsafe {
+            privacy_pass_verify_key_transparency(
+                log_json.as_ptr() as *const i8,
+                public_key.as_ptr() as *const i8,
+                0,
+                seen_head.as_ptr() as *const i8,
+            )
+        };
+        let check_json = unsafe { c_char_to_string(check_ptr) }.unwrap();
+        unsafe { privacy_pass_free_string(check_ptr); }
+        let check: KeyTransparencyCheck = serde_json::from_str(&check_json).unwrap();
+        assert_eq!((check.index, check.len), (1, 2));
+        assert_eq!(check.head, hex::encode(log.head()));
+
+        // the same log no longer extends the one seen once it forked
+        let mut fork = TransparencyLog::new();
+        fork.append(b"evil key", 100);
+        let fork_json = CString::new(serde_json::to_string(fork.entries()).unwrap()).unwrap();
+        let seen_head = CString::new(check.head).unwrap();
+        let fork_ptr = unsafe {
+            privacy_pass_verify_log_extends(
+                fork_json.as_ptr() as *const i8,
+                check.len,
+                seen_head.as_ptr() as *const i8,
+            )
+        };
+        let fork_check = unsafe { c_char_to_string(fork_ptr) }.unwrap();
+        unsafe { privacy_pass_free_string(fork_ptr); }
+        assert!(fork_check.contains("Log check failed"));
+    }
+
 }
@@ -37,3 +37,30 @@ pub struct TokenResponse {
     pub token_response: String,
     pub error: String,
 }
+
+/// Authorization header value presenting a token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthorizationHeader {
+    pub header: String,
+    pub error: String,
+}
+
+/// Result of checking the issuer's key against its transparency log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyTransparencyCheck {
+    /// index of the entry logging the key
+    pub index: u64,
+    /// to be remembered, and passed back as seen_len and seen_head on the next check
+    pub len: u64,
+    pub head: String,
+    pub error: String,
+}
+
+/// Result of checking a transparency log extends the one seen last time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogExtensionCheck {
+    /// to be remembered, and passed back as seen_len and seen_head on the next check
+    pub len: u64,
+    pub head: String,
+    pub error: String,
+}
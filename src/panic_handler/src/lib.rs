@@ -71,7 +71,7 @@ pub fn end_panic_handling(_: TokenStream) -> TokenStream {
     let result = match result {
         Ok(non_panicky_result) => match non_panicky_result {
             Ok(res_cstr) => res_cstr,
-            Err(err) => error_json_retval(&format!("{:?}", err))
+            Err(err) => error_chain_json_retval(&*err)
         },
         Err(_) => error_json_retval("panic")
     };
@@ -5,6 +5,6 @@
 
 #include <stdint.h>
 
-char * gen_keys();
+char * gen_keys(uint16_t token_type);
 
 #endif
\ No newline at end of file
@@ -6,7 +6,7 @@
 int main(void) {
 
   for (int i = 0; i < 10; i++) {
-    char *keypair = gen_keys();
+    char *keypair = gen_keys(0);
     if (keypair) {
         printf("keypair = %s\n", keypair);
     } else {
@@ -0,0 +1,87 @@
+// Token challenges, see RFC 9577 Section 2.1:
+//   token_type (2) || issuer_name<1..2^16-1> || redemption_context<0..32> || origin_info<0..2^16-1>
+
+use crate::{take, take_u16, ParseError};
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+
+/// A parsed token challenge, borrowing from its serialization
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenChallenge<'a> {
+    pub token_type: u16,
+    pub issuer_name: &'a str,
+    /// empty, or 32 bytes
+    pub redemption_context: &'a [u8],
+    /// origin names, empty if the challenge is not bound to an origin
+    pub origin_info: Vec<&'a str>,
+}
+
+fn take_name<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a str, ParseError> {
+    core::str::from_utf8(take(input, len)?).map_err(|_| ParseError::InvalidName)
+}
+
+impl<'a> TokenChallenge<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        let mut input = bytes;
+        let token_type = take_u16(&mut input)?;
+        let issuer_name_len = usize::from(take_u16(&mut input)?);
+        if issuer_name_len == 0 {
+            return Err(ParseError::Truncated);
+        }
+        let issuer_name = take_name(&mut input, issuer_name_len)?;
+        let redemption_context_len = usize::from(*take(&mut input, 1)?.first().unwrap_or(&0));
+        if redemption_context_len != 0 && redemption_context_len != 32 {
+            return Err(ParseError::InvalidRedemptionContext(redemption_context_len));
+        }
+        let redemption_context = take(&mut input, redemption_context_len)?;
+        let origin_info_len = usize::from(take_u16(&mut input)?);
+        let origin_info = take_name(&mut input, origin_info_len)?;
+        if !input.is_empty() {
+            return Err(ParseError::TrailingBytes);
+        }
+        Ok(TokenChallenge {
+            token_type,
+            issuer_name,
+            redemption_context,
+            origin_info: match origin_info.is_empty() {
+                true => Vec::new(),
+                false => origin_info.split(',').collect(),
+            },
+        })
+    }
+}
+
+/// SHA256 of a serialized challenge, as carried by tokens issued for it
+pub fn challenge_digest(challenge: &[u8]) -> [u8; 32] {
+    Sha256::digest(challenge).into()
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_challenge() {
+        let mut bytes = vec![0x00, 0x02, 0x00, 0x06];
+        bytes.extend(b"issuer");
+        bytes.push(32);
+        bytes.extend([7u8; 32]);
+        bytes.extend([0x00, 0x0f]);
+        bytes.extend(b"a.example,b.com");
+        let challenge = TokenChallenge::parse(&bytes).unwrap();
+        assert_eq!(challenge.token_type, 2);
+        assert_eq!(challenge.issuer_name, "issuer");
+        assert_eq!(challenge.redemption_context, &[7u8; 32]);
+        assert_eq!(challenge.origin_info, vec!["a.example", "b.com"]);
+
+        bytes[10] = 31;
+        assert_eq!(
+            TokenChallenge::parse(&bytes),
+            Err(ParseError::InvalidRedemptionContext(31))
+        );
+    }
+}
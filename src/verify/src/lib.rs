@@ -0,0 +1,56 @@
+// -----------------------------------------------------------------------------
+// -------------------  no_std token parsing and verification  -----------------
+// -----------------------------------------------------------------------------
+//
+// Parsing of tokens and token challenges, and verification of publicly verifiable tokens,
+// without tokio, key or nonce stores, or the FFI layer, so that edge devices and embedded
+// verifiers can check tokens. Only `alloc` is required.
+// NOTE: replay protection is left to the caller, as it needs a nonce store.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod challenge;
+#[cfg(feature = "public")]
+pub mod public;
+pub mod token;
+
+pub use challenge::TokenChallenge;
+pub use token::Token;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("input is too short")]
+    Truncated,
+    #[error("input has trailing bytes")]
+    TrailingBytes,
+    #[error("unknown token type {0:#06x}")]
+    UnknownTokenType(u16),
+    #[error("invalid redemption context length {0}")]
+    InvalidRedemptionContext(usize),
+    #[error("names are not valid UTF-8")]
+    InvalidName,
+}
+
+/// Splits `len` bytes off the front of `input`
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], ParseError> {
+    if input.len() < len {
+        return Err(ParseError::Truncated);
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn take_array<'a, const N: usize>(input: &mut &'a [u8]) -> Result<&'a [u8; N], ParseError> {
+    take(input, N)?
+        .try_into()
+        .map_err(|_| ParseError::Truncated)
+}
+
+fn take_u16(input: &mut &[u8]) -> Result<u16, ParseError> {
+    Ok(u16::from_be_bytes(*take_array(input)?))
+}
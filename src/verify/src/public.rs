@@ -0,0 +1,148 @@
+// Verification of publicly verifiable tokens (token type 0x0002, blind RSA), see
+// RFC 9578 Section 6: the authenticator is a RSASSA-PSS signature of token_input, with
+// SHA-384, MGF1 with SHA-384 and a 48 byte salt, and token_key_id is the SHA256 of the
+// issuer's public key, given as a DER encoded SubjectPublicKeyInfo.
+
+use crate::token::{authenticator_len, Token, TOKEN_TYPE_PUBLIC_RSA};
+use alloc::vec::Vec;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pss, RsaPublicKey};
+use sha2::{Digest, Sha256, Sha384};
+use thiserror::Error;
+
+const SALT_LEN: usize = 48;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("token type {0:#06x} is not publicly verifiable")]
+    NotPubliclyVerifiable(u16),
+    #[error("token was not issued with this public key")]
+    KeyIdMismatch,
+    #[error("invalid public key")]
+    InvalidPublicKey,
+    #[error("invalid token signature")]
+    InvalidSignature,
+}
+
+/// Splits a DER element off the front of `input`, returning its tag and contents
+fn der_element<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = match first {
+        0..=0x7f => usize::from(first),
+        0x81..=0x84 => {
+            let (len_bytes, after) = rest.split_at_checked(usize::from(first & 0x7f))?;
+            rest = after;
+            len_bytes
+                .iter()
+                .fold(0usize, |len, &byte| (len << 8) | usize::from(byte))
+        }
+        _ => return None,
+    };
+    let (contents, rest) = rest.split_at_checked(len)?;
+    *input = rest;
+    Some((tag, contents))
+}
+
+/// Reads the RSA public key out of a SubjectPublicKeyInfo, whichever its algorithm
+/// identifier (rsaEncryption or RSASSA-PSS)
+fn parse_public_key(spki: &[u8]) -> Option<RsaPublicKey> {
+    const SEQUENCE: u8 = 0x30;
+    const BIT_STRING: u8 = 0x03;
+
+    let mut input = spki;
+    let (SEQUENCE, mut spki_contents) = der_element(&mut input)? else {
+        return None;
+    };
+    if !input.is_empty() {
+        return None;
+    }
+    let (SEQUENCE, _algorithm) = der_element(&mut spki_contents)? else {
+        return None;
+    };
+    let (BIT_STRING, bits) = der_element(&mut spki_contents)? else {
+        return None;
+    };
+    match bits.split_first()? {
+        (0, rsa_public_key) if spki_contents.is_empty() => {
+            RsaPublicKey::from_pkcs1_der(rsa_public_key).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Big-endian modulus and public exponent of a DER encoded SubjectPublicKeyInfo, e.g. to
+/// publish the key as a JWK
+pub fn public_key_components(public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), VerifyError> {
+    let public_key = parse_public_key(public_key).ok_or(VerifyError::InvalidPublicKey)?;
+    Ok((public_key.n().to_bytes_be(), public_key.e().to_bytes_be()))
+}
+
+/// Verifies `token` against the issuer's DER encoded SubjectPublicKeyInfo
+pub fn verify_public_token(token: &Token, public_key: &[u8]) -> Result<(), VerifyError> {
+    if token.token_type != TOKEN_TYPE_PUBLIC_RSA {
+        return Err(VerifyError::NotPubliclyVerifiable(token.token_type));
+    }
+    if Sha256::digest(public_key).as_slice() != token.token_key_id {
+        return Err(VerifyError::KeyIdMismatch);
+    }
+    let public_key = parse_public_key(public_key).ok_or(VerifyError::InvalidPublicKey)?;
+    if Some(public_key.size()) != authenticator_len(TOKEN_TYPE_PUBLIC_RSA) {
+        return Err(VerifyError::InvalidPublicKey);
+    }
+    public_key
+        .verify(
+            Pss::new_with_salt::<Sha384>(SALT_LEN),
+            &Sha384::digest(token.token_input()),
+            token.authenticator,
+        )
+        .map_err(|_| VerifyError::InvalidSignature)
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::EncodePublicKey;
+    use rsa::pss::SigningKey;
+    use rsa::signature::{RandomizedSigner, SignatureEncoding};
+    use rsa::RsaPrivateKey;
+
+    #[test]
+    fn test_verify_public_token() {
+        let mut rng = rand::thread_rng();
+        let secret_key = RsaPrivateKey::new(&mut rng, 2048).unwrap();
+        let spki = secret_key
+            .to_public_key()
+            .to_public_key_der()
+            .unwrap()
+            .into_vec();
+
+        let mut bytes = vec![0x00, 0x02];
+        bytes.extend([1u8; 32]);
+        bytes.extend([2u8; 32]);
+        bytes.extend(Sha256::digest(&spki));
+        let signing_key = SigningKey::<Sha384>::new_with_salt_len(secret_key, SALT_LEN);
+        let signature = signing_key.sign_with_rng(&mut rng, &bytes).to_vec();
+        bytes.extend(signature);
+
+        let token = Token::parse(&bytes).unwrap();
+        assert_eq!(verify_public_token(&token, &spki), Ok(()));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let token = Token::parse(&bytes).unwrap();
+        assert_eq!(
+            verify_public_token(&token, &spki),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_public_token(&token, b"not a key"),
+            Err(VerifyError::KeyIdMismatch)
+        );
+    }
+}
@@ -0,0 +1,128 @@
+// Tokens, see RFC 9577 Section 2.2:
+//   token_type (2) || nonce (32) || challenge_digest (32) || token_key_id (32) || authenticator (Nk)
+
+use crate::{take, take_array, take_u16, ParseError};
+
+pub const NONCE_LEN: usize = 32;
+pub const NONCE_OFFSET: usize = 2;
+pub const CHALLENGE_DIGEST_OFFSET: usize = NONCE_OFFSET + NONCE_LEN;
+pub const TOKEN_KEY_ID_OFFSET: usize = CHALLENGE_DIGEST_OFFSET + 32;
+/// token_input is everything but the authenticator
+pub const TOKEN_INPUT_LEN: usize = TOKEN_KEY_ID_OFFSET + 32;
+
+/// VOPRF(P-384, SHA-384)
+pub const TOKEN_TYPE_PRIVATE_P384: u16 = 0x0001;
+/// Blind RSA (2048-bit), publicly verifiable
+pub const TOKEN_TYPE_PUBLIC_RSA: u16 = 0x0002;
+/// Batched VOPRF(ristretto255, SHA-512)
+pub const TOKEN_TYPE_BATCHED_RISTRETTO255: u16 = 0x0005;
+/// Batched VOPRF(P-384, SHA-384), as numbered by privacypass-rust
+pub const TOKEN_TYPE_BATCHED_P384: u16 = 0xF901;
+
+/// Authenticator length (Nk) of the known token types
+pub fn authenticator_len(token_type: u16) -> Option<usize> {
+    match token_type {
+        TOKEN_TYPE_PRIVATE_P384 | TOKEN_TYPE_BATCHED_P384 => Some(48),
+        TOKEN_TYPE_PUBLIC_RSA => Some(256),
+        TOKEN_TYPE_BATCHED_RISTRETTO255 => Some(64),
+        _ => None,
+    }
+}
+
+/// Reads the nonce of a serialized token, None if it is too short to carry one
+pub fn token_nonce(token: &[u8]) -> Option<[u8; NONCE_LEN]> {
+    token
+        .get(NONCE_OFFSET..NONCE_OFFSET + NONCE_LEN)?
+        .try_into()
+        .ok()
+}
+
+/// A parsed token, borrowing from its serialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub token_type: u16,
+    pub nonce: &'a [u8; NONCE_LEN],
+    pub challenge_digest: &'a [u8; 32],
+    pub token_key_id: &'a [u8; 32],
+    pub authenticator: &'a [u8],
+    token_input: &'a [u8],
+}
+
+impl<'a> Token<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        let mut input = bytes;
+        let token_type = take_u16(&mut input)?;
+        let nk = authenticator_len(token_type).ok_or(ParseError::UnknownTokenType(token_type))?;
+        let nonce = take_array(&mut input)?;
+        let challenge_digest = take_array(&mut input)?;
+        let token_key_id = take_array(&mut input)?;
+        let authenticator = take(&mut input, nk)?;
+        if !input.is_empty() {
+            return Err(ParseError::TrailingBytes);
+        }
+        Ok(Token {
+            token_type,
+            nonce,
+            challenge_digest,
+            token_key_id,
+            authenticator,
+            token_input: &bytes[..TOKEN_INPUT_LEN],
+        })
+    }
+
+    /// The bytes covered by the authenticator
+    pub fn token_input(&self) -> &'a [u8] {
+        self.token_input
+    }
+
+    /// Checks the token was issued for the challenge serialized as `challenge`
+    pub fn matches_challenge(&self, challenge: &[u8]) -> bool {
+        crate::challenge::challenge_digest(challenge) == *self.challenge_digest
+    }
+}
+
+// -----------------------------------------------------------------------------
+// ---------------------------  Unit Tests  ------------------------------------
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_token() {
+        let mut bytes = vec![0x00, 0x05];
+        bytes.extend([1u8; 32]);
+        bytes.extend([2u8; 32]);
+        bytes.extend([3u8; 32]);
+        bytes.extend([4u8; 64]);
+        let token = Token::parse(&bytes).unwrap();
+        assert_eq!(token.token_type, TOKEN_TYPE_BATCHED_RISTRETTO255);
+        assert_eq!(token.nonce, &[1u8; 32]);
+        assert_eq!(token.token_key_id, &[3u8; 32]);
+        assert_eq!(token.authenticator, &[4u8; 64]);
+        assert_eq!(token.token_input(), &bytes[..TOKEN_INPUT_LEN]);
+        assert_eq!(token_nonce(&bytes), Some([1u8; 32]));
+
+        assert_eq!(
+            Token::parse(&bytes[..bytes.len() - 1]),
+            Err(ParseError::Truncated)
+        );
+        bytes.push(0);
+        assert_eq!(Token::parse(&bytes), Err(ParseError::TrailingBytes));
+        bytes[1] = 0x42;
+        assert_eq!(
+            Token::parse(&bytes),
+            Err(ParseError::UnknownTokenType(0x42))
+        );
+
+        // batched P-384 tokens carry a VOPRF(P-384) output, like private P-384 ones
+        let mut bytes = TOKEN_TYPE_BATCHED_P384.to_be_bytes().to_vec();
+        bytes.extend([1u8; TOKEN_INPUT_LEN - 2]);
+        bytes.extend([4u8; 48]);
+        assert_eq!(
+            Token::parse(&bytes).unwrap().token_type,
+            TOKEN_TYPE_BATCHED_P384
+        );
+    }
+}
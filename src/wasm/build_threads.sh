@@ -0,0 +1,6 @@
+# multi-threaded build, needs a nightly toolchain as std has to be rebuilt with atomics.
+# the output only works on cross-origin isolated pages (SharedArrayBuffer available)
+RUSTFLAGS='-C target-feature=+atomics,+bulk-memory,+mutable-globals' \
+    rustup run nightly \
+    wasm-pack build --target web --release --out-dir pkg-threads \
+    -- --features threads -Z build-std=panic_abort,std
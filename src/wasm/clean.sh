@@ -1,2 +1,4 @@
 rm -rf pkg
+rm -rf pkg-threads
 rm -rf example/pkg
+rm -rf example/pkg-threads
@@ -0,0 +1,19 @@
+// Loads the multi-threaded build when the page is cross-origin isolated and it was built,
+// falling back to the single-threaded build otherwise.
+export async function loadKagippjs() {
+  if (self.crossOriginIsolated) {
+    try {
+      const kagippjs = await import("./pkg-threads/kagippjs.js");
+      await kagippjs.default();
+      await kagippjs.initThreadPool(navigator.hardwareConcurrency);
+      kagippjs.enable_parallel_blinding();
+      return kagippjs;
+    } catch (exc) {
+      console.log("threaded build unavailable, falling back to single-threaded build");
+      console.log(exc);
+    }
+  }
+  const kagippjs = await import("./pkg/kagippjs.js");
+  await kagippjs.default();
+  return kagippjs;
+}
@@ -1,3 +1,4 @@
-rm -rf pkg
+rm -rf pkg pkg-threads
 cp -r ../pkg .
-python -m http.server
+if [ -d ../pkg-threads ]; then cp -r ../pkg-threads .; fi
+python serve_isolated.py
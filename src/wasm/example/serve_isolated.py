@@ -0,0 +1,14 @@
+# like `python -m http.server`, but with the headers making the page cross-origin isolated,
+# which browsers require before exposing SharedArrayBuffer to the threaded build
+from http.server import SimpleHTTPRequestHandler, test
+
+
+class IsolatedHandler(SimpleHTTPRequestHandler):
+    def end_headers(self):
+        self.send_header("Cross-Origin-Opener-Policy", "same-origin")
+        self.send_header("Cross-Origin-Embedder-Policy", "require-corp")
+        super().end_headers()
+
+
+if __name__ == "__main__":
+    test(IsolatedHandler)
@@ -0,0 +1,9 @@
+use wasm_bindgen::prelude::*;
+
+/// Returns a JSON description of the crypto backend and build features in use
+#[wasm_bindgen]
+pub fn crypto_capabilities() -> String {
+    let capabilities = kagippcore::capabilities::crypto_capabilities();
+    // this should be unable to fail
+    serde_json::to_string(&capabilities).expect("failed to encode CryptoCapabilities into string")
+}
@@ -35,6 +35,12 @@ pub fn error_json_retval(message: &str) -> String {
     serde_json::to_string(&error_obj).expect("failed to encode JSONRetVal into string")
 }
 
+pub fn error_chain_json_retval(err: &(dyn std::error::Error + 'static)) -> String {
+    let error_obj = kagippcore::crystal::JSONErrorRetVal::from_error(err);
+    // this should be unable to fail
+    serde_json::to_string(&error_obj).expect("failed to encode JSONErrorRetVal into string")
+}
+
 #[wasm_bindgen]
 pub fn token_request(header_s: String, nr: u16) -> String {
     begin_panic_handling!();
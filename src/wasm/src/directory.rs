@@ -0,0 +1,38 @@
+use crate::client::{error_chain_json_retval, error_json_retval};
+use kagippcore::issuer_directory::IssuerDirectory;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+#[derive(Serialize)]
+struct KeySelection {
+    // as in the directory, to be resolved against the directory URL if relative
+    issuer_request_uri: String,
+    token_type: u16,
+    token_key: String,
+}
+
+/// Parses and validates an issuer directory (as fetched from the issuer's
+/// /.well-known/private-token-issuer-directory), and selects its key for the TokenChallenge
+/// of a WWW-Authenticate header. `now` is the time in unix seconds, e.g.
+/// Math.floor(Date.now() / 1000).
+#[wasm_bindgen]
+pub fn select_issuer_key(directory_s: String, header_s: String, now: u64) -> String {
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let directory = IssuerDirectory::from_json(&directory_s)?;
+        let token_key = directory.select_key_for_header(&header_s, now)?;
+        let rv = kagippcore::crystal::JSONRetVal {
+            retval: serde_json::to_string(&KeySelection {
+                issuer_request_uri: directory.issuer_request_uri.clone(),
+                token_type: token_key.token_type,
+                token_key: token_key.token_key.clone(),
+            })?,
+            error: "".to_string(),
+        };
+        let out = serde_json::to_string(&rv)?;
+        // always end like this
+        Ok::<String, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}
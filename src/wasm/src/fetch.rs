@@ -0,0 +1,96 @@
+use crate::issuance::Challenge;
+use js_sys::{Function, Object, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Response};
+
+// Whole issuance flow behind a single call, for callers that only want to retry a request
+// the origin answered with 401:
+//   const response = await fetch(url);
+//   if (response.status == 401) {
+//       const authorization = await fetch_authorization_header(response.headers, fetch,
+//                                                              issuerRequestUri);
+//       await fetch(url, { headers: { Authorization: authorization } });
+//   }
+// The issuer is called through the given fetch-like function, so that callers can route it
+// through their own transport (e.g. the extension's background script).
+
+#[wasm_bindgen]
+extern "C" {
+    /// Headers of the 401 response, or the value of its WWW-Authenticate header
+    #[wasm_bindgen(typescript_type = "Headers | string")]
+    pub type ChallengeHeaders;
+
+    /// fetch, or any function taking the same arguments and resolving to a Response
+    #[wasm_bindgen(typescript_type = "typeof fetch")]
+    pub type FetchFunction;
+}
+
+fn www_authenticate_header(headers: &JsValue) -> Result<String, JsValue> {
+    if let Some(header) = headers.as_string() {
+        return Ok(header);
+    }
+    let headers: &Headers = headers
+        .dyn_ref()
+        .ok_or_else(|| JsError::new("expected Headers or a WWW-Authenticate header value"))?;
+    headers
+        .get("WWW-Authenticate")?
+        .ok_or_else(|| JsError::new("no WWW-Authenticate header").into())
+}
+
+fn set(object: &Object, key: &str, value: &JsValue) -> Result<(), JsValue> {
+    Reflect::set(object, &JsValue::from_str(key), value).map(|_| ())
+}
+
+/// Runs the issuance flow for the challenge of a 401 response: requests a token from the
+/// issuer at `issuer_request_uri` through `fetch`, and returns the value of the
+/// Authorization header to retry the request with
+#[wasm_bindgen]
+pub async fn fetch_authorization_header(
+    headers: ChallengeHeaders,
+    fetch: FetchFunction,
+    issuer_request_uri: String,
+) -> Result<String, JsValue> {
+    let challenge = Challenge::new(&www_authenticate_header(&headers)?)?;
+    let state = challenge.create_token_request(1)?;
+
+    // POST application/private-token-request, see RFC 9578 section 5
+    let request_headers = Object::new();
+    set(
+        &request_headers,
+        "Content-Type",
+        &"application/private-token-request".into(),
+    )?;
+    set(
+        &request_headers,
+        "Accept",
+        &"application/private-token-response".into(),
+    )?;
+    let init = Object::new();
+    set(&init, "method", &"POST".into())?;
+    set(&init, "headers", &request_headers)?;
+    set(
+        &init,
+        "body",
+        &Uint8Array::from(state.token_request().as_slice()),
+    )?;
+    let fetch: &Function = fetch
+        .dyn_ref()
+        .ok_or_else(|| JsError::new("fetch is not a function"))?;
+    let promise = fetch.call2(&JsValue::NULL, &issuer_request_uri.into(), &init)?;
+    let response: Response = JsFuture::from(js_sys::Promise::resolve(&promise))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsError::new(&format!("issuer answered {}", response.status())).into());
+    }
+    let token_response = JsFuture::from(response.array_buffer()?).await?;
+    let token_response = Uint8Array::new(&token_response);
+
+    let tokens = challenge.finalize_tokens(&state, token_response.unchecked_into())?;
+    let token = tokens
+        .first()
+        .ok_or_else(|| JsError::new("issuer answered with no token"))?;
+    Ok(token.authorization_header()?)
+}
@@ -0,0 +1,327 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::client as pp_client;
+use pp_client::{
+    parse_challenges, BatchedToken, ChallengeFields, PrivacyPassClient, TokenResponse,
+};
+use serde::{Serialize, Serializer};
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+// Typed JS-facing issuance flow, for which wasm-bindgen emits complete TypeScript types:
+//   const challenge = new Challenge(response.headers.get("WWW-Authenticate"));
+//   const state = challenge.create_token_request(nr);
+//   // POST state.token_request to the issuer
+//   const tokens = challenge.finalize_tokens(state, tokenResponse);
+//   // Authorization: tokens[0].authorization_header()
+// Errors are thrown as JS Errors instead of being returned in JSON strings, and binary
+// inputs are taken as Uint8Array or base64 strings. The create_token_request,
+// finalize_tokens and parse_www_authenticate functions run the same flow on header values
+// and plain objects, with their shapes as TypeScript types.
+// NOTE: unlike the JSON string API of the client module, panics are not turned into errors:
+//       wasm32 builds abort on panic, so they couldn't be caught there anyway.
+
+#[wasm_bindgen]
+extern "C" {
+    /// Uint8Array, or its URL safe base64 encoding
+    #[wasm_bindgen(typescript_type = "Uint8Array | string")]
+    pub type Bytes;
+
+    #[wasm_bindgen(typescript_type = "{ token_request: string, state: string, nr: number }")]
+    pub type CreatedTokenRequest;
+
+    #[wasm_bindgen(typescript_type = "{ tokens: string[] }")]
+    pub type FinalizedTokens;
+
+    #[wasm_bindgen(typescript_type = "Array<{ token_type: number, issuer_name: string, \
+        origin_info: string[], max_age?: number, token_key: Uint8Array, \
+        token_challenge: string }>")]
+    pub type ChallengeObjects;
+}
+
+/// Bytes of a Uint8Array, or of a (URL safe) base64 string
+fn bytes_from_js(value: &JsValue) -> Result<Vec<u8>, JsError> {
+    match value.as_string() {
+        Some(encoded) => Ok(URL_SAFE.decode(encoded.trim())?),
+        None if value.is_instance_of::<js_sys::Uint8Array>() => {
+            Ok(js_sys::Uint8Array::new(value).to_vec())
+        }
+        None => Err(JsError::new("expected a Uint8Array or a base64 string")),
+    }
+}
+
+/// One challenge of a `WWW-Authenticate: PrivateToken` header value, with the issuer key it
+/// names
+#[wasm_bindgen]
+pub struct Challenge {
+    fields: ChallengeFields,
+    client: PrivacyPassClient,
+}
+
+impl TryFrom<ChallengeFields> for Challenge {
+    type Error = JsError;
+
+    fn try_from(fields: ChallengeFields) -> Result<Self, JsError> {
+        let client = fields.client()?;
+        Ok(Challenge { fields, client })
+    }
+}
+
+#[wasm_bindgen]
+impl Challenge {
+    /// The challenge of a header value holding a single one
+    #[wasm_bindgen(constructor)]
+    pub fn new(www_authenticate_header: &str) -> Result<Challenge, JsError> {
+        let mut challenges = parse_challenges(www_authenticate_header)?;
+        match challenges.len() {
+            1 => challenges.remove(0).try_into(),
+            n => Err(pp_client::ClientError::ChallengeCount(n).into()),
+        }
+    }
+
+    /// Every challenge of a header value, in header order
+    pub fn parse_all(www_authenticate_header: &str) -> Result<Vec<Challenge>, JsError> {
+        parse_challenges(www_authenticate_header)?
+            .into_iter()
+            .map(Challenge::try_from)
+            .collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn token_type(&self) -> u16 {
+        self.fields.token_type
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn issuer_name(&self) -> String {
+        self.fields.issuer_name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn origin_info(&self) -> Vec<String> {
+        self.fields.origin_info.clone()
+    }
+
+    /// Seconds the challenge may be cached for, undefined if the header sets no max-age
+    #[wasm_bindgen(getter)]
+    pub fn max_age(&self) -> Option<usize> {
+        self.fields.max_age
+    }
+
+    /// Serialized issuer public key
+    #[wasm_bindgen(getter)]
+    pub fn token_key(&self) -> Vec<u8> {
+        self.fields.token_key.clone()
+    }
+
+    /// Base64 TokenChallenge, as origins validate tokens against
+    #[wasm_bindgen(getter)]
+    pub fn token_challenge(&self) -> Result<String, JsError> {
+        Ok(self.fields.token_challenge.to_base64()?)
+    }
+
+    /// Builds a TokenRequest for `nr` tokens of this challenge
+    pub fn create_token_request(&self, nr: u16) -> Result<TokenRequestState, JsError> {
+        let (token_request, state) = self.client.token_request(nr)?;
+        Ok(TokenRequestState {
+            token_request: token_request.tls_serialize_detached()?,
+            state,
+        })
+    }
+
+    /// Finalizes the tokens of the issuer's TokenResponse to the token request of `state`,
+    /// made from this challenge. Issuers may answer with fewer tokens than requested.
+    pub fn finalize_tokens(
+        &self,
+        state: &TokenRequestState,
+        token_response: Bytes,
+    ) -> Result<Vec<Token>, JsError> {
+        let token_response_bytes = bytes_from_js(&token_response)?;
+        let token_response = TokenResponse::tls_deserialize(&mut token_response_bytes.as_slice())?;
+        let tokens = self.client.finalize(&state.state, &token_response)?;
+        Ok(tokens.into_iter().map(|token| Token { token }).collect())
+    }
+}
+
+/// A TokenRequest, along with the nonces and blinding factors needed to finalize its tokens.
+/// NOTE: the blinding factors are secret until the tokens are finalized, see to_json.
+#[wasm_bindgen]
+pub struct TokenRequestState {
+    token_request: Vec<u8>,
+    state: pp_client::TokenRequestState,
+}
+
+#[wasm_bindgen]
+impl TokenRequestState {
+    /// Serialized TokenRequest, the body of the application/private-token-request sent to the
+    /// issuer
+    #[wasm_bindgen(getter)]
+    pub fn token_request(&self) -> Vec<u8> {
+        self.token_request.clone()
+    }
+
+    pub fn token_request_base64(&self) -> String {
+        URL_SAFE.encode(&self.token_request)
+    }
+
+    /// Number of tokens requested
+    #[wasm_bindgen(getter)]
+    pub fn nr(&self) -> usize {
+        self.state.nr()
+    }
+
+    /// JSON of the request and its state, for finalizing its tokens in another context, e.g.
+    /// once the extension's service worker restarted. It holds the blinding factors.
+    pub fn to_json(&self) -> Result<String, JsError> {
+        let json = serde_json::json!({
+            "token_request": self.token_request_base64(),
+            "state": &*self.state.to_json()?,
+        });
+        Ok(json.to_string())
+    }
+
+    pub fn from_json(json: &str) -> Result<TokenRequestState, JsError> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let (Some(token_request), Some(state)) =
+            (value["token_request"].as_str(), value["state"].as_str())
+        else {
+            return Err(JsError::new("invalid token request state"));
+        };
+        Ok(TokenRequestState {
+            token_request: URL_SAFE.decode(token_request)?,
+            state: pp_client::TokenRequestState::from_json(state)?,
+        })
+    }
+}
+
+/// A finalized token, to be presented to the origins of its challenge
+#[wasm_bindgen]
+pub struct Token {
+    token: BatchedToken,
+}
+
+#[wasm_bindgen]
+impl Token {
+    pub fn from_bytes(token: Bytes) -> Result<Token, JsError> {
+        let token_bytes = bytes_from_js(&token)?;
+        Ok(Token {
+            token: BatchedToken::tls_deserialize(&mut token_bytes.as_slice())?,
+        })
+    }
+
+    /// Serialized token
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsError> {
+        Ok(self.token.tls_serialize_detached()?)
+    }
+
+    /// Base64 token, as the TokenStore and the JSON string API take them
+    pub fn to_base64(&self) -> Result<String, JsError> {
+        Ok(URL_SAFE.encode(self.to_bytes()?))
+    }
+
+    /// Value of the Authorization header redeeming this token
+    pub fn authorization_header(&self) -> Result<String, JsError> {
+        Ok(pp_client::build_authorization_header(&self.to_bytes()?)?)
+    }
+}
+
+#[derive(Serialize)]
+struct CreatedTokenRequestObject {
+    // base64, to be sent to the issuer (as application/private-token-request once decoded)
+    token_request: String,
+    // to be passed back to finalize_tokens, secret until then
+    state: String,
+    nr: usize,
+}
+
+#[derive(Serialize)]
+struct FinalizedTokensObject {
+    // base64, to be sent as Authorization: PrivateToken token=<token>
+    tokens: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChallengeObject {
+    token_type: u16,
+    issuer_name: String,
+    origin_info: Vec<String>,
+    max_age: Option<usize>,
+    // Uint8Array
+    #[serde(serialize_with = "serialize_bytes")]
+    token_key: Vec<u8>,
+    // base64
+    token_challenge: String,
+}
+
+// serde_wasm_bindgen turns byte slices into Uint8Arrays, and Vec<u8> into arrays otherwise
+fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
+}
+
+fn to_js<T: Serialize, U: JsCast>(value: &T) -> Result<U, JsError> {
+    Ok(serde_wasm_bindgen::to_value(value)?.unchecked_into())
+}
+
+/// Builds a TokenRequest for `nr` tokens of the challenge of a WWW-Authenticate header value
+/// (`PrivateToken challenge=..., token-key=...`), see Challenge.create_token_request
+#[wasm_bindgen]
+pub fn create_token_request(
+    www_authenticate_header: &str,
+    nr: u16,
+) -> Result<CreatedTokenRequest, JsError> {
+    let state = Challenge::new(www_authenticate_header)?.create_token_request(nr)?;
+    to_js(&CreatedTokenRequestObject {
+        token_request: state.token_request_base64(),
+        state: state.state.to_json()?.to_string(),
+        nr: state.nr(),
+    })
+}
+
+/// Finalizes the tokens of the issuer's TokenResponse to the token request of `state`,
+/// returned by create_token_request for the same WWW-Authenticate header value, see
+/// Challenge.finalize_tokens
+#[wasm_bindgen]
+pub fn finalize_tokens(
+    www_authenticate_header: &str,
+    state: &str,
+    token_response: Bytes,
+) -> Result<FinalizedTokens, JsError> {
+    let challenge = Challenge::new(www_authenticate_header)?;
+    let state = pp_client::TokenRequestState::from_json(state)?;
+    let token_response_bytes = bytes_from_js(&token_response)?;
+    let token_response = TokenResponse::tls_deserialize(&mut token_response_bytes.as_slice())?;
+    let tokens = challenge
+        .client
+        .finalize(&state, &token_response)?
+        .into_iter()
+        .map(|token| Token { token }.to_base64())
+        .collect::<Result<_, JsError>>()?;
+    to_js(&FinalizedTokensObject { tokens })
+}
+
+/// Parses the challenges of a WWW-Authenticate header value, see Challenge.parse_all
+#[wasm_bindgen]
+pub fn parse_www_authenticate(www_authenticate_header: &str) -> Result<ChallengeObjects, JsError> {
+    let challenges = Challenge::parse_all(www_authenticate_header)?
+        .iter()
+        .map(|challenge| {
+            Ok(ChallengeObject {
+                token_type: challenge.token_type(),
+                issuer_name: challenge.issuer_name(),
+                origin_info: challenge.origin_info(),
+                max_age: challenge.max_age(),
+                token_key: challenge.token_key(),
+                token_challenge: challenge.token_challenge()?,
+            })
+        })
+        .collect::<Result<Vec<_>, JsError>>()?;
+    to_js(&challenges)
+}
+
+/// RFC 9577 Authorization header value, `PrivateToken token="<base64url>"`, presenting a
+/// serialized token, e.g. one taken from a TokenStore
+#[wasm_bindgen]
+pub fn build_authorization_header(token: Bytes) -> Result<String, JsError> {
+    let token = bytes_from_js(&token)?;
+    Ok(pp_client::build_authorization_header(&token)?)
+}
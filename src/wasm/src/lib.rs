@@ -1,4 +1,12 @@
 #[macro_use]
 extern crate panic_handler;
 
-mod client;
\ No newline at end of file
+mod capabilities;
+mod client;
+mod directory;
+mod fetch;
+mod issuance;
+#[cfg(feature = "threads")]
+mod threads;
+mod token_store;
+mod transparency;
\ No newline at end of file
@@ -0,0 +1,11 @@
+use wasm_bindgen::prelude::*;
+
+// exported to JS as `initThreadPool(num_threads)`, spawning the rayon workers
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+/// Makes token_request and token_finalization split large batches across the rayon workers.
+/// Only call this once the promise returned by `initThreadPool` resolved.
+#[wasm_bindgen]
+pub fn enable_parallel_blinding() {
+    kagippcore::client::set_parallel_blinding(true);
+}
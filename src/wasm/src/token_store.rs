@@ -0,0 +1,60 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use wasm_bindgen::prelude::*;
+
+// Unspent tokens per (issuer name, origin) pair, see kagippcore::token_store.
+// Tokens are taken and returned in base64, as finalize_tokens returns them, and the store is
+// persisted with to_bytes / TokenStore.from_bytes, e.g. to IndexedDB or extension storage.
+
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct TokenStore {
+    inner: kagippcore::token_store::TokenStore,
+}
+
+#[wasm_bindgen]
+impl TokenStore {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TokenStore {
+        TokenStore::default()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<TokenStore, JsError> {
+        Ok(TokenStore {
+            inner: kagippcore::token_store::TokenStore::from_bytes(bytes)?,
+        })
+    }
+
+    /// Uint8Array of the serialized store
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.to_bytes()?)
+    }
+
+    /// Adds base64 tokens issued for the challenge of `issuer_name` and `origin`
+    pub fn add_tokens(
+        &mut self,
+        issuer_name: &str,
+        origin: &str,
+        tokens: Vec<String>,
+    ) -> Result<(), JsError> {
+        let tokens = tokens
+            .iter()
+            .map(|token| URL_SAFE.decode(token))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.inner.add_tokens(issuer_name, origin, tokens)?)
+    }
+
+    /// Removes and returns the oldest base64 token of the pair, undefined if none is left
+    pub fn take_token(&mut self, issuer_name: &str, origin: &str) -> Option<String> {
+        self.inner
+            .take_token(issuer_name, origin)
+            .map(|token| URL_SAFE.encode(token))
+    }
+
+    pub fn count(&self, issuer_name: &str, origin: &str) -> usize {
+        self.inner.count(issuer_name, origin)
+    }
+
+    pub fn clear(&mut self, issuer_name: &str, origin: &str) {
+        self.inner.clear(issuer_name, origin)
+    }
+}
@@ -0,0 +1,50 @@
+use crate::client::{error_chain_json_retval, error_json_retval};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use kagippcore::transparency::{verify_key_in_log, LogEntry, LogHash};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+#[derive(Serialize)]
+struct LogCheck {
+    // index of the entry logging the key
+    index: u64,
+    // to be remembered, and passed back as seen_len and seen_head on the next check
+    len: u64,
+    head: String,
+}
+
+/// Checks the issuer's public key (base64 encoded, as served in the WWW-Authenticate header)
+/// is in the issuer's transparency log, and that the log extends the one seen last time.
+/// Pass `seen_len` 0 and an empty `seen_head` on the first check.
+#[wasm_bindgen]
+pub fn verify_key_transparency(
+    log_s: String,
+    public_key_s: String,
+    seen_len: u64,
+    seen_head_s: String,
+) -> String {
+    begin_panic_handling!();
+    let result = panic::catch_unwind(|| {
+        let entries: Vec<LogEntry> = serde_json::from_str(&log_s)?;
+        let public_key = URL_SAFE.decode(public_key_s)?;
+        let mut seen_head = LogHash::default();
+        if seen_len > 0 {
+            hex::decode_to_slice(seen_head_s, &mut seen_head)?;
+        }
+
+        let (head, entry) = verify_key_in_log(&entries, &public_key, seen_len, &seen_head)?;
+        let rv = kagippcore::crystal::JSONRetVal {
+            retval: serde_json::to_string(&LogCheck {
+                index: entry.index,
+                len: entries.len() as u64,
+                head: hex::encode(head),
+            })?,
+            error: "".to_string(),
+        };
+        let out = serde_json::to_string(&rv)?;
+        // always end like this
+        Ok::<String, Box<dyn std::error::Error>>(out)
+    });
+    end_panic_handling!();
+    result
+}